@@ -0,0 +1,32 @@
+use std::process::Command;
+
+// Passes what the boot banner shows about the build to the kernel (see src/banner.rs):
+// the commit it was built from and the compiler.
+fn main() {
+    let git = |args: &[&str]| {
+        let out = Command::new("git").args(args).output().ok()?;
+        out.status
+            .success()
+            .then(|| String::from_utf8(out.stdout).ok())?
+    };
+
+    // Built outside a checkout, e.g. from a release tarball, the commit is unknown.
+    let commit = git(&["describe", "--always", "--dirty"]);
+    println!(
+        "cargo:rustc-env=OS1K_COMMIT={}",
+        commit.as_deref().map_or("unknown", str::trim)
+    );
+    if let Some(dir) = git(&["rev-parse", "--git-dir"]) {
+        let dir = dir.trim();
+        println!("cargo:rerun-if-changed={dir}/HEAD");
+        println!("cargo:rerun-if-changed={dir}/index");
+    }
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let version = Command::new(rustc).arg("--version").output();
+    let version = version.map_or(String::new(), |out| {
+        String::from_utf8_lossy(&out.stdout).into()
+    });
+    println!("cargo:rustc-env=OS1K_RUSTC={}", version.trim());
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,256 @@
+use crate::buddy::{self, Block, Buddy};
+
+const PAGE_SIZE: usize = 4096;
+
+/// A small xorshift generator, so failures are reproducible from the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+fn buffers(mem_size: usize) -> Vec<Block> {
+    vec![Block::EMPTY; buddy::meta_len(mem_size, PAGE_SIZE)]
+}
+
+/// Returns `true` if no block is allocated and all blocks are merged back into one.
+fn is_fully_merged(buddy: &Buddy) -> bool {
+    let order = (buddy.size() / PAGE_SIZE).ilog2() as usize;
+    buddy.free_blocks()[order] == 1
+}
+
+fn assert_no_overlap(live: &[(usize, usize)]) {
+    let mut sorted = live.to_vec();
+    sorted.sort();
+    for pair in sorted.windows(2) {
+        let (a_off, a_size) = pair[0];
+        let (b_off, _) = pair[1];
+        assert!(a_off + a_size <= b_off, "blocks overlap: {pair:x?}");
+    }
+}
+
+fn random_alloc_free(seed: u64, mem_size: usize) {
+    let mut meta = buffers(mem_size);
+    let mut buddy = Buddy::new(mem_size, PAGE_SIZE, &mut meta);
+    let mut rng = Rng(seed);
+    let mut live: Vec<(usize, usize)> = Vec::new();
+
+    for _ in 0..2000 {
+        if !live.is_empty() && rng.below(4) == 0 {
+            let i = rng.below(live.len());
+            let (offset, size) = live[i];
+            let n = 1 + rng.below(mem_size / 4);
+            match buddy.resize(offset, n) {
+                Some(new_size) => {
+                    assert!(new_size >= n && new_size.is_power_of_two());
+                    assert_eq!(
+                        offset % new_size,
+                        0,
+                        "resized block is not aligned to its size"
+                    );
+                    live[i] = (offset, new_size);
+                    assert_no_overlap(&live);
+                }
+                // Only growing fails.
+                None => assert!(n > size),
+            }
+            assert_eq!(buddy.block_size(offset), Some(live[i].1));
+        } else if live.is_empty() || rng.below(3) != 0 {
+            let n = 1 + rng.below(mem_size / 4);
+            if let Some((offset, size)) = buddy.alloc(n) {
+                assert!(size >= n && size.is_power_of_two());
+                assert_eq!(offset % size, 0, "block is not aligned to its size");
+                assert!(offset + size <= mem_size);
+                live.push((offset, size));
+                assert_no_overlap(&live);
+            }
+        } else {
+            let (offset, size) = live.swap_remove(rng.below(live.len()));
+            assert_eq!(buddy.block_size(offset), Some(size));
+            buddy.free(offset);
+        }
+    }
+
+    while let Some((offset, _)) = live.pop() {
+        buddy.free(offset);
+    }
+
+    assert!(
+        is_fully_merged(&buddy),
+        "seed {seed}: blocks did not merge back"
+    );
+    assert_eq!(buddy.alloc(mem_size), Some((0, mem_size)));
+}
+
+#[test]
+fn random_sequences_never_overlap_and_fully_merge() {
+    for seed in 1..200 {
+        random_alloc_free(seed, 64 * PAGE_SIZE);
+    }
+}
+
+#[test]
+fn page_sized_allocations_fill_memory_exactly() {
+    let mem_size = 32 * PAGE_SIZE;
+    let mut meta = buffers(mem_size);
+    let mut buddy = Buddy::new(mem_size, PAGE_SIZE, &mut meta);
+
+    let blocks: Vec<_> = (0..32).map(|_| buddy.alloc(PAGE_SIZE).unwrap()).collect();
+    assert_eq!(buddy.alloc(1), None);
+
+    for (offset, _) in blocks {
+        buddy.free(offset);
+    }
+    assert!(is_fully_merged(&buddy));
+}
+
+#[test]
+fn whole_region_can_be_allocated_and_freed() {
+    let mem_size = 16 * PAGE_SIZE;
+    let mut meta = buffers(mem_size);
+    let mut buddy = Buddy::new(mem_size, PAGE_SIZE, &mut meta);
+
+    let (offset, size) = buddy.alloc(mem_size).unwrap();
+    assert_eq!((offset, size), (0, mem_size));
+    buddy.free(offset);
+    assert!(is_fully_merged(&buddy));
+}
+
+#[test]
+fn block_sizes_are_kept_until_freed() {
+    let mem_size = 8 * PAGE_SIZE;
+    let mut meta = buffers(mem_size);
+    let mut buddy = Buddy::new(mem_size, PAGE_SIZE, &mut meta);
+
+    let (a, _) = buddy.alloc(PAGE_SIZE).unwrap();
+    let (b, _) = buddy.alloc(3 * PAGE_SIZE).unwrap();
+    assert_eq!(buddy.block_size(a), Some(PAGE_SIZE));
+    assert_eq!(buddy.block_size(b), Some(4 * PAGE_SIZE));
+    // Inside a block, in a free one, or not at the start of a page.
+    assert_eq!(buddy.block_size(b + PAGE_SIZE), None);
+    assert_eq!(buddy.block_size(a + PAGE_SIZE), None);
+    assert_eq!(buddy.block_size(a + 8), None);
+    assert_eq!(buddy.block_size(mem_size), None);
+
+    buddy.free(b);
+    assert_eq!(buddy.block_size(b), None);
+    buddy.free(a);
+    assert!(is_fully_merged(&buddy));
+}
+
+#[test]
+fn blocks_grow_into_free_buddies_above_them() {
+    let mem_size = 8 * PAGE_SIZE;
+    let mut meta = buffers(mem_size);
+    let mut buddy = Buddy::new(mem_size, PAGE_SIZE, &mut meta);
+
+    let (a, _) = buddy.alloc(PAGE_SIZE).unwrap();
+    assert_eq!(buddy.resize(a, 3 * PAGE_SIZE), Some(4 * PAGE_SIZE));
+    assert_eq!(buddy.block_size(a), Some(4 * PAGE_SIZE));
+    assert_eq!(buddy.stats().used, 4 * PAGE_SIZE);
+    // The upper half of the memory is taken, and nothing is above the whole of it.
+    let (b, _) = buddy.alloc(4 * PAGE_SIZE).unwrap();
+    assert_eq!(buddy.resize(a, 5 * PAGE_SIZE), None);
+    assert_eq!(buddy.resize(b, 5 * PAGE_SIZE), None);
+    assert_eq!(buddy.resize(a, 0), None);
+    assert_eq!(buddy.resize(a + PAGE_SIZE, PAGE_SIZE), None);
+
+    // Shrinking gives back the upper halves, the lower one can't grow into a taken buddy.
+    assert_eq!(buddy.resize(b, PAGE_SIZE), Some(PAGE_SIZE));
+    assert_eq!(buddy.free_blocks()[0..2], [1, 1]);
+    let (c, _) = buddy.alloc(PAGE_SIZE).unwrap();
+    assert_eq!(c, b + PAGE_SIZE);
+    assert_eq!(buddy.resize(b, 2 * PAGE_SIZE), None);
+    buddy.free(c);
+    assert_eq!(buddy.resize(b, 2 * PAGE_SIZE), Some(2 * PAGE_SIZE));
+
+    buddy.free(a);
+    buddy.free(b);
+    assert!(is_fully_merged(&buddy));
+    assert_eq!(buddy.stats().used, 0);
+}
+
+#[test]
+fn requests_larger_than_the_managed_region_fail() {
+    // Only the largest power of two (4 pages) of a 6-page region is managed.
+    let mem_size = 6 * PAGE_SIZE;
+    let mut meta = buffers(mem_size);
+    let mut buddy = Buddy::new(mem_size, PAGE_SIZE, &mut meta);
+
+    assert_eq!(buddy.alloc(0), None);
+    assert_eq!(buddy.alloc(5 * PAGE_SIZE), None);
+    assert_eq!(buddy.alloc(7 * PAGE_SIZE), None);
+    assert_eq!(buddy.alloc(4 * PAGE_SIZE), Some((0, 4 * PAGE_SIZE)));
+}
+
+#[test]
+#[should_panic(expected = "was not allocated")]
+fn double_free_is_detected() {
+    let mem_size = 8 * PAGE_SIZE;
+    let mut meta = buffers(mem_size);
+    let mut buddy = Buddy::new(mem_size, PAGE_SIZE, &mut meta);
+
+    let (offset, _) = buddy.alloc(PAGE_SIZE).unwrap();
+    let _keep = buddy.alloc(PAGE_SIZE).unwrap();
+    buddy.free(offset);
+    buddy.free(offset);
+}
+
+#[test]
+fn stats_count_splits_merges_and_failures() {
+    let mem_size = 4 * PAGE_SIZE;
+    let mut meta = buffers(mem_size);
+    let mut buddy = Buddy::new(mem_size, PAGE_SIZE, &mut meta);
+
+    let (offset, _) = buddy.alloc(PAGE_SIZE).unwrap();
+    // The 4-page root and the 2-page block holding the page were split.
+    assert_eq!(buddy.stats().splits, 2);
+    assert_eq!(buddy.free_blocks()[..3], [1, 1, 0]);
+    assert_eq!(buddy.alloc(4 * PAGE_SIZE), None);
+
+    let stats = buddy.stats();
+    assert_eq!((stats.allocs[0], stats.failed), (1, 1));
+    assert_eq!((stats.used, stats.peak), (PAGE_SIZE, PAGE_SIZE));
+
+    buddy.free(offset);
+    assert_eq!(buddy.stats().merges, 2);
+    assert_eq!(buddy.stats().frees[0], 1);
+    assert_eq!(buddy.free_blocks()[..3], [0, 0, 1]);
+
+    buddy.reset_stats();
+    assert_eq!(buddy.stats().allocs[0], 0);
+    assert_eq!(buddy.stats().peak, 0);
+}
+
+#[test]
+fn halves_left_over_by_splits_are_reused_first() {
+    let mem_size = 8 * PAGE_SIZE;
+    let mut meta = buffers(mem_size);
+    let mut buddy = Buddy::new(mem_size, PAGE_SIZE, &mut meta);
+
+    assert_eq!(buddy.alloc(PAGE_SIZE), Some((0, PAGE_SIZE)));
+    assert_eq!(buddy.free_blocks()[..4], [1, 1, 1, 0]);
+    // Served from the free lists of their orders, without splitting again.
+    assert_eq!(
+        buddy.alloc(2 * PAGE_SIZE),
+        Some((2 * PAGE_SIZE, 2 * PAGE_SIZE))
+    );
+    assert_eq!(buddy.alloc(PAGE_SIZE), Some((PAGE_SIZE, PAGE_SIZE)));
+    assert_eq!(buddy.stats().splits, 3);
+    assert_eq!(buddy.free_blocks()[..4], [0, 0, 1, 0]);
+
+    buddy.free(0);
+    buddy.free(2 * PAGE_SIZE);
+    assert_eq!(buddy.free_blocks()[..4], [1, 1, 1, 0]);
+    buddy.free(PAGE_SIZE);
+    assert!(is_fully_merged(&buddy));
+}
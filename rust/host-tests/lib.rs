@@ -0,0 +1,28 @@
+// Kernel code that doesn't depend on the hardware, compiled for the host so it can be
+// tested with `std` and many more iterations than the in-kernel tests can afford.
+//
+// Run with `cargo test` from this directory.
+
+// On the host, kernel assertions always panic so tests see broken invariants.
+#[macro_export]
+macro_rules! kassert {
+    ($($arg:tt)+) => {{
+        assert!($($arg)+);
+        true
+    }};
+}
+
+#[macro_export]
+macro_rules! kassert_eq {
+    ($($arg:tt)+) => {{
+        assert_eq!($($arg)+);
+        true
+    }};
+}
+
+#[allow(dead_code)]
+#[path = "../src/buddy.rs"]
+mod buddy;
+
+#[cfg(test)]
+mod buddy_tests;
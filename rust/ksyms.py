@@ -0,0 +1,57 @@
+#!/usr/bin/env python3
+# Writes the kernel's function symbols into its `.ksyms` section, see `src/ksyms.rs`.
+# Run on every kernel that should print function names, e.g. `./ksyms.py <kernel elf>`.
+# NM and OBJCOPY can be set to use other tools than LLVM's.
+import os
+import re
+import struct
+import subprocess
+import sys
+import tempfile
+
+elf = sys.argv[1]
+nm = os.environ.get("NM", "llvm-nm")
+objcopy = os.environ.get("OBJCOPY", "llvm-objcopy")
+
+output = subprocess.run(
+    [nm, "--defined-only", "--demangle", "--numeric-sort", elf],
+    capture_output=True, text=True, check=True,
+).stdout
+
+addrs = {}
+functions = {}
+for line in output.splitlines():
+    addr, kind, name = line.split(" ", 2)
+    addr = int(addr, 16)
+    addrs[name] = addr
+    # Skips the assembler's mapping symbols and local labels.
+    if kind not in "tTwW" or name.startswith(("$", ".L")):
+        continue
+    # Linker script symbols like `__kernel_base` give way to the function at their address,
+    # otherwise the first name at an address wins.
+    if addr not in functions or functions[addr].startswith("__"):
+        # Drops the hash rustc appends to every path.
+        functions[addr] = re.sub(r"::h[0-9a-f]{16}$", "", name)
+
+base = addrs["__kernel_base"]
+size = addrs["__ksyms_end"] - addrs["__ksyms"]
+
+entries = []
+names = b""
+for addr, name in sorted(functions.items()):
+    encoded = name.encode()
+    entries.append((addr - base, len(names), len(encoded)))
+    names += encoded
+
+section = b"KSYM" + struct.pack("<I", len(entries))
+section += b"".join(struct.pack("<III", *entry) for entry in entries)
+section += names
+if len(section) > size:
+    sys.exit(f"ksyms: {len(section)} bytes of symbols don't fit in the {size} byte .ksyms section")
+
+with tempfile.NamedTemporaryFile() as f:
+    f.write(section.ljust(size, b"\0"))
+    f.flush()
+    subprocess.run([objcopy, "--update-section", f".ksyms={f.name}", elf], check=True)
+
+print(f"ksyms: {len(entries)} symbols, {len(section)} of {size} bytes")
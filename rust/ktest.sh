@@ -0,0 +1,11 @@
+#!/bin/bash
+# Builds the in-kernel `#[test_case]` tests and runs them in QEMU.
+# Exits with QEMU's status: 0 if all tests passed.
+# An optional argument runs only the tests whose name contains it, e.g. `./ktest.sh pipe`.
+set -ue
+
+KERNEL=$(cargo test --release --no-run 2>&1 | grep -o 'target/riscv32imac-unknown-none-elf/release/deps/os1k-[0-9a-f]*')
+./ksyms.py "$KERNEL"
+
+qemu-system-riscv32 -machine virt -bios default -nographic --no-reboot -kernel "$KERNEL" \
+  -append "ktest=${1:-}"
@@ -0,0 +1,31 @@
+use crate::{
+    executor, println,
+    proc::{self, PROC_MAX},
+    sysctl::Tunable,
+};
+
+// Page aging: a kernel task periodically ages the pages of every process by their
+// accessed bits (see `vm::PageTable::age()`), which estimates each one's working set, the
+// pages it has used lately, shown in `/proc/<pid>/status`. Pages that have gone unused the
+// longest are the ones to evict once memory runs short.
+
+/// Milliseconds between scans, `/proc/sys/page_scan_ms`.
+pub static SCAN_MS: Tunable = Tunable::new("page_scan_ms", 1000, 100, 60_000);
+
+crate::register_subsystem!(Late, "aging", init);
+
+fn init() {
+    let scan = async {
+        loop {
+            executor::sleep_ms(SCAN_MS.get()).await;
+            // One process at a time, the other tasks don't wait for a whole scan.
+            for pid in 0..PROC_MAX {
+                proc::age_pages(pid);
+                executor::yield_now().await;
+            }
+        }
+    };
+    if let Err(err) = executor::spawn(scan) {
+        println!("aging: can't start scanning: {err:?}");
+    }
+}
@@ -0,0 +1,162 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    proc::{self, PROC_MAX},
+    signal::{self, SIGALRM},
+    syscall::{self, Errno},
+    timer,
+};
+
+// Per-process alarm timers, like Linux's `ITIMER_REAL`: once it runs out, the process is
+// sent `SIGALRM`, which also wakes it if it is blocked, and an interval timer starts over.
+// Timers are checked on every tick, so they have tick granularity. A forked process starts
+// without one, `exec()` keeps it.
+
+const NO_ALARM: usize = usize::MAX;
+
+/// Time until the alarm goes off and the interval it then restarts with, in milliseconds
+/// (0 for a timer that isn't set, or doesn't restart). Laid out like Linux's
+/// `struct itimerval`, but in milliseconds rather than `timeval`s.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[repr(C)]
+pub struct Itimer {
+    pub value: usize,
+    pub interval: usize,
+}
+
+// Tick at which each process's alarm goes off, and the ticks it then restarts with,
+// indexed by pid.
+static DEADLINE: [AtomicUsize; PROC_MAX] = [const { AtomicUsize::new(NO_ALARM) }; PROC_MAX];
+static INTERVAL: [AtomicUsize; PROC_MAX] = [const { AtomicUsize::new(0) }; PROC_MAX];
+
+fn ticks_to_ms(ticks: usize) -> usize {
+    ticks * 1000 / timer::hz()
+}
+
+/// Returns the alarm timer of `pid`.
+pub fn get(pid: usize) -> Itimer {
+    let deadline = DEADLINE[pid].load(Ordering::Relaxed);
+    let value = match deadline {
+        NO_ALARM => 0,
+        // One that is due but hasn't been sent yet reports as about to go off.
+        _ => ticks_to_ms(deadline.saturating_sub(timer::ticks())).max(1),
+    };
+    Itimer {
+        value,
+        interval: ticks_to_ms(INTERVAL[pid].load(Ordering::Relaxed)),
+    }
+}
+
+/// Sets the alarm timer of `pid`, returning the previous one. A `value` of 0 disarms it.
+pub fn set(pid: usize, timer: Itimer) -> Itimer {
+    let old = get(pid);
+    // Disarmed first, so the tick handler never sees a deadline with the old interval.
+    DEADLINE[pid].store(NO_ALARM, Ordering::Relaxed);
+    INTERVAL[pid].store(timer::ms_to_ticks(timer.interval), Ordering::Relaxed);
+    if timer.value != 0 {
+        let deadline = timer::ticks() + timer::ms_to_ticks(timer.value).max(1);
+        DEADLINE[pid].store(deadline, Ordering::Relaxed);
+    }
+    old
+}
+
+/// Disarms the alarm timer of `pid`, once it has exited.
+pub fn reset(pid: usize) {
+    set(pid, Itimer::default());
+}
+
+/// Returns the earliest tick an alarm goes off at.
+pub fn next_deadline() -> Option<usize> {
+    DEADLINE
+        .iter()
+        .map(|deadline| deadline.load(Ordering::Relaxed))
+        .filter(|&deadline| deadline != NO_ALARM)
+        .min()
+}
+
+/// Sends `SIGALRM` to the processes whose alarm is due at `ticks`, called on every tick.
+pub fn on_tick(ticks: usize) {
+    for pid in 0..PROC_MAX {
+        let deadline = DEADLINE[pid].load(Ordering::Relaxed);
+        if deadline > ticks {
+            continue;
+        }
+        let next = match INTERVAL[pid].load(Ordering::Relaxed) {
+            0 => NO_ALARM,
+            interval => ticks + interval,
+        };
+        // Lost to a concurrent `set()`, which takes precedence.
+        if DEADLINE[pid]
+            .compare_exchange(deadline, next, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            signal::kill_deferred(pid, SIGALRM);
+        }
+    }
+}
+
+/// Stores the calling process's alarm timer as an `Itimer` at `addr`.
+pub fn sys_getitimer(addr: usize) -> Result<usize, Errno> {
+    let timer = get(proc::current());
+    let out = syscall::user_slice_mut(addr, size_of::<Itimer>())?;
+    out[..size_of::<usize>()].copy_from_slice(&timer.value.to_ne_bytes());
+    out[size_of::<usize>()..].copy_from_slice(&timer.interval.to_ne_bytes());
+    Ok(0)
+}
+
+/// Sets the calling process's alarm timer to the `Itimer` at `addr`, storing the previous
+/// one at `old_addr` unless it is null.
+pub fn sys_setitimer(addr: usize, old_addr: usize) -> Result<usize, Errno> {
+    let bytes = syscall::user_slice(addr, size_of::<Itimer>())?;
+    let (value, interval) = bytes.split_at(size_of::<usize>());
+    let timer = Itimer {
+        value: usize::from_ne_bytes(value.try_into().unwrap()),
+        interval: usize::from_ne_bytes(interval.try_into().unwrap()),
+    };
+    let old = set(proc::current(), timer);
+    if old_addr != 0 {
+        let out = syscall::user_slice_mut(old_addr, size_of::<Itimer>())?;
+        out[..size_of::<usize>()].copy_from_slice(&old.value.to_ne_bytes());
+        out[size_of::<usize>()..].copy_from_slice(&old.interval.to_ne_bytes());
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn alarms_fire_once_or_restart() {
+        // A pid no process uses while tests run, whose signals go nowhere.
+        let pid = PROC_MAX - 1;
+        let tick_ms = 1000 / timer::hz();
+        let now = timer::ticks();
+
+        let once = Itimer {
+            value: 3 * tick_ms,
+            interval: 0,
+        };
+        assert_eq!(set(pid, once), Itimer::default());
+        on_tick(now + 2);
+        assert_eq!(signal::masks(pid).0, 0);
+        on_tick(now + 3);
+        assert_eq!(signal::masks(pid).0, 1 << SIGALRM);
+        assert_eq!(get(pid), Itimer::default());
+
+        signal::reset(pid);
+        let every = Itimer {
+            value: tick_ms,
+            interval: 2 * tick_ms,
+        };
+        set(pid, every);
+        on_tick(now + 1);
+        assert_eq!(DEADLINE[pid].load(Ordering::Relaxed), now + 3);
+        assert_eq!(get(pid).interval, 2 * tick_ms);
+
+        reset(pid);
+        signal::reset(pid);
+        on_tick(usize::MAX - 1);
+        assert_eq!(signal::masks(pid).0, 0);
+    }
+}
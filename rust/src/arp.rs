@@ -0,0 +1,249 @@
+use crate::{
+    eth::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4},
+    net::{self, Ipv4Addr, MacAddr},
+    proc,
+    sync::Mutex,
+    syscall::Errno,
+    timer,
+};
+
+// Address Resolution Protocol (RFC 826) for IPv4 over Ethernet.
+
+const HTYPE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+
+const PACKET_LEN: usize = 28;
+
+const CACHE_SIZE: usize = 16;
+// How long a learned address is trusted.
+const ENTRY_TIMEOUT_MS: usize = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ArpPacket {
+    op: u16,
+    sender_mac: MacAddr,
+    sender_ip: Ipv4Addr,
+    target_mac: MacAddr,
+    target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let packet = packet.get(..PACKET_LEN)?;
+        let htype = u16::from_be_bytes([packet[0], packet[1]]);
+        let ptype = u16::from_be_bytes([packet[2], packet[3]]);
+        if htype != HTYPE_ETHERNET || ptype != ETHERTYPE_IPV4 || packet[4] != 6 || packet[5] != 4 {
+            return None;
+        }
+
+        Some(Self {
+            op: u16::from_be_bytes([packet[6], packet[7]]),
+            sender_mac: MacAddr(packet[8..14].try_into().unwrap()),
+            sender_ip: Ipv4Addr(packet[14..18].try_into().unwrap()),
+            target_mac: MacAddr(packet[18..24].try_into().unwrap()),
+            target_ip: Ipv4Addr(packet[24..28].try_into().unwrap()),
+        })
+    }
+
+    fn to_bytes(self) -> [u8; PACKET_LEN] {
+        let mut packet = [0; PACKET_LEN];
+        packet[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        packet[4] = 6;
+        packet[5] = 4;
+        packet[6..8].copy_from_slice(&self.op.to_be_bytes());
+        packet[8..14].copy_from_slice(&self.sender_mac.0);
+        packet[14..18].copy_from_slice(&self.sender_ip.0);
+        packet[18..24].copy_from_slice(&self.target_mac.0);
+        packet[24..28].copy_from_slice(&self.target_ip.0);
+        packet
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    iface: usize,
+    ip: Ipv4Addr,
+    mac: MacAddr,
+    // Tick count at which the entry was learned.
+    updated: usize,
+}
+
+static CACHE: Mutex<[Option<Entry>; CACHE_SIZE]> = Mutex::new([None; CACHE_SIZE]);
+
+fn is_fresh(entry: &Entry) -> bool {
+    timer::ticks().wrapping_sub(entry.updated) < timer::ms_to_ticks(ENTRY_TIMEOUT_MS)
+}
+
+/// Returns the cached hardware address of `ip` on `iface`.
+pub fn lookup(iface: usize, ip: Ipv4Addr) -> Option<MacAddr> {
+    CACHE
+        .lock()
+        .iter()
+        .flatten()
+        .find(|e| e.iface == iface && e.ip == ip && is_fresh(e))
+        .map(|e| e.mac)
+}
+
+/// Records that `ip` is at `mac` on `iface`, only adding a new entry if `create` is set.
+///
+/// The least recently updated entry makes room if the cache is full.
+fn update(iface: usize, ip: Ipv4Addr, mac: MacAddr, create: bool) {
+    let mut cache = CACHE.lock();
+    let updated = timer::ticks();
+
+    if let Some(entry) = cache
+        .iter_mut()
+        .flatten()
+        .find(|e| e.iface == iface && e.ip == ip)
+    {
+        entry.mac = mac;
+        entry.updated = updated;
+        return;
+    }
+    if !create {
+        return;
+    }
+
+    let slot = match cache.iter().position(|e| e.is_none()) {
+        Some(slot) => slot,
+        None => (0..CACHE_SIZE)
+            .min_by_key(|&i| cache[i].map_or(0, |e| updated.wrapping_sub(e.updated)))
+            .unwrap(),
+    };
+    cache[slot] = Some(Entry {
+        iface,
+        ip,
+        mac,
+        updated,
+    });
+}
+
+/// Asks everyone on `iface`'s link for the hardware address of `ip`.
+pub fn request(iface: usize, ip: Ipv4Addr) -> Result<(), Errno> {
+    let packet = ArpPacket {
+        op: OP_REQUEST,
+        sender_mac: net::mac(iface)?,
+        sender_ip: net::ip_config(iface)?.addr,
+        target_mac: MacAddr::ZERO,
+        target_ip: ip,
+    };
+    eth::send(iface, MacAddr::BROADCAST, ETHERTYPE_ARP, &packet.to_bytes())
+}
+
+/// Returns the hardware address of `ip` on `iface`.
+///
+/// Fails with `EAGAIN` if it isn't known yet, after sending a request for it.
+pub fn resolve(iface: usize, ip: Ipv4Addr) -> Result<MacAddr, Errno> {
+    if ip == Ipv4Addr::BROADCAST {
+        return Ok(MacAddr::BROADCAST);
+    }
+    if ip == net::ip_config(iface)?.addr {
+        return net::mac(iface);
+    }
+    if let Some(mac) = lookup(iface, ip) {
+        return Ok(mac);
+    }
+    request(iface, ip)?;
+    Err(Errno::EAGAIN)
+}
+
+/// Returns the hardware address of `ip` on `iface`, waiting up to `timeout_ms`
+/// milliseconds for an answer if it isn't known yet.
+///
+/// Fails with `EHOSTUNREACH` if nobody answered.
+pub fn resolve_wait(iface: usize, ip: Ipv4Addr, timeout_ms: usize) -> Result<MacAddr, Errno> {
+    match resolve(iface, ip) {
+        Err(Errno::EAGAIN) => {}
+        result => return result,
+    }
+
+    let deadline = timer::ticks() + timer::ms_to_ticks(timeout_ms);
+    while timer::ticks() < deadline {
+        proc::sleep(Some(timer::ticks() + 1));
+        net::poll();
+        if let Some(mac) = lookup(iface, ip) {
+            return Ok(mac);
+        }
+    }
+
+    Err(Errno::EHOSTUNREACH)
+}
+
+/// Handles an ARP packet received on `iface`, learning the sender and answering requests
+/// for the interface's address.
+pub fn receive(iface: usize, packet: &[u8]) {
+    let Some(packet) = ArpPacket::parse(packet) else {
+        return;
+    };
+    let (Ok(mac), Ok(ip)) = (net::mac(iface), net::ip_config(iface)) else {
+        return;
+    };
+
+    let for_us = ip.addr != Ipv4Addr::UNSPECIFIED && packet.target_ip == ip.addr;
+    // As RFC 826 says, only hosts that talk to us get a new entry.
+    update(iface, packet.sender_ip, packet.sender_mac, for_us);
+
+    if for_us && packet.op == OP_REQUEST {
+        let reply = ArpPacket {
+            op: OP_REPLY,
+            sender_mac: mac,
+            sender_ip: ip.addr,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        let _ = eth::send(iface, packet.sender_mac, ETHERTYPE_ARP, &reply.to_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        eth::{EthHeader, HEADER_LEN},
+        net::tests::{MockDevice, mock_interface},
+    };
+
+    #[test_case]
+    fn answers_requests_and_learns_sender() {
+        let (iface, dev) = mock_interface();
+        let peer_mac = MacAddr([2, 0, 0, 0, 0, 1]);
+        let peer_ip = Ipv4Addr([10, 0, 0, 1]);
+
+        let request = ArpPacket {
+            op: OP_REQUEST,
+            sender_mac: peer_mac,
+            sender_ip: peer_ip,
+            target_mac: MacAddr::ZERO,
+            target_ip: Ipv4Addr([10, 0, 0, 2]),
+        };
+        let mut frame = [0; HEADER_LEN + PACKET_LEN];
+        eth::write_header(
+            &mut frame,
+            &EthHeader {
+                dst: MacAddr::BROADCAST,
+                src: peer_mac,
+                ethertype: ETHERTYPE_ARP,
+            },
+        );
+        frame[HEADER_LEN..].copy_from_slice(&request.to_bytes());
+        eth::receive(iface, &frame);
+
+        assert_eq!(lookup(iface, peer_ip), Some(peer_mac));
+
+        let sent = dev.sent.lock();
+        let (header, payload) = eth::parse(&sent.0[..sent.1]).unwrap();
+        assert_eq!(header.dst, peer_mac);
+        assert_eq!(
+            ArpPacket::parse(payload),
+            Some(ArpPacket {
+                op: OP_REPLY,
+                sender_mac: MockDevice::MAC,
+                sender_ip: Ipv4Addr([10, 0, 0, 2]),
+                target_mac: peer_mac,
+                target_ip: peer_ip,
+            })
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{
+    block, cmdline, cpu, dtb, macros,
+    mem::{self, RegionKind},
+    net, print, println, timer,
+};
+
+// The boot report, printed once the subsystems are up: what kernel this is, what it found
+// to run on, and how it was told to run. Later lines in the log come from the processes.
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Set by `build.rs`.
+const COMMIT: &str = env!("OS1K_COMMIT");
+const RUSTC: &str = env!("OS1K_RUSTC");
+
+// Cargo features compiled in, see `Cargo.toml`.
+const FEATURES: [(&str, bool); 7] = [
+    ("smp", cfg!(feature = "smp")),
+    ("virtio-net", cfg!(feature = "virtio-net")),
+    ("fs-fat", cfg!(feature = "fs-fat")),
+    ("log-trace", cfg!(feature = "log-trace")),
+    ("fault-inject", cfg!(feature = "fault-inject")),
+    ("gdb", cfg!(feature = "gdb")),
+    ("kassert-log", cfg!(feature = "kassert-log")),
+];
+
+/// Prints the boot report.
+pub fn print() {
+    let profile = if cfg!(debug_assertions) {
+        "debug"
+    } else {
+        "release"
+    };
+    println!("OS1K {VERSION} ({COMMIT}, {profile}, {RUSTC})");
+
+    println!("  cpu:      {} x {}", cpu::count(), cpu::current());
+
+    let usage = mem::usage();
+    println!(
+        "  memory:   {} KiB free of {} KiB",
+        (usage.total - usage.used) / 1024,
+        usage.total / 1024
+    );
+    mem::for_each_region(|kind, start, end| {
+        let kind = match kind {
+            RegionKind::Ram => "ram",
+            RegionKind::Mmio => "mmio",
+        };
+        println!("  {kind:9} 0x{start:08x}-0x{end:08x}");
+    });
+    if let Some((start, end)) = dtb::initrd() {
+        println!("  initrd    0x{start:08x}-0x{end:08x}");
+    }
+
+    for iface in 0..net::MAX_INTERFACES {
+        if let (Ok(name), Ok(mac)) = (net::name(iface), net::mac(iface)) {
+            println!("  net:      {name} {mac:?}");
+        }
+    }
+    for index in 0..block::MAX_DEVICES {
+        if let (Ok(name), Ok(dev)) = (block::name(index), block::device(index)) {
+            let kib = dev.sectors() * block::SECTOR_SIZE as u64 / 1024;
+            println!("  block:    {name} {kib} KiB");
+        }
+    }
+
+    println!(
+        "  config:   hz={} loglevel={}",
+        timer::hz(),
+        macros::loglevel()
+    );
+    let mut features = FEATURES.iter().filter(|(_, on)| *on).map(|(name, _)| name);
+    if let Some(first) = features.next() {
+        print!("  features: {first}");
+        features.for_each(|name| print!(" {name}"));
+        print!("\n");
+    }
+    let cmdline = cmdline::as_str();
+    if !cmdline.is_empty() {
+        println!("  cmdline:  {cmdline}");
+    }
+}
@@ -0,0 +1,558 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    partition,
+    stdlib::{SgList, phalloc_sg},
+    sync::Mutex,
+    syscall::Errno,
+    sysctl::Tunable,
+};
+
+// The block layer: disks, and the partitions on them, read and written in whole sectors.
+// A driver registers each disk it finds, whose partition table is read right away, every
+// partition becoming a device of its own (see `partition`). Filesystems then find the one
+// they are mounted from by name.
+//
+// Devices are used through `read()`, `write()` and `submit()` here rather than directly:
+// batches of requests go to the driver sorted by sector, adjacent ones merged into one
+// vectored request (one virtio descriptor chain), and sequential reads are followed by
+// reading ahead into a few windows of sectors kept here, so the next read is already
+// there. `stats()` counts how well both work, shown in `/proc/diskstats`. Windows belong
+// to a device index: writes to a disk don't drop those of its partitions, or the other way
+// round, so a disk is used either whole or through its partitions. A window's memory is
+// allocated when it is first filled, in pieces if RAM is fragmented, each one a segment of
+// the vectored read that fills it.
+
+pub const SECTOR_SIZE: usize = 512;
+pub const MAX_DEVICES: usize = 16;
+/// Most requests merged into one request to the driver.
+pub const MAX_SEGMENTS: usize = 16;
+
+// Read-ahead windows, shared by all devices, and the most sectors each holds.
+const WINDOWS: usize = 4;
+const WINDOW_SECTORS: usize = 16;
+
+/// Sectors read ahead of a sequential read, `/proc/sys/readahead_sectors`, 0 to not read
+/// ahead at all.
+pub static READAHEAD_SECTORS: Tunable =
+    Tunable::new("readahead_sectors", WINDOW_SECTORS, 0, WINDOW_SECTORS).on_change(|_| {
+        // What was read ahead before still holds what the disk does, but the windows are
+        // dropped so that turning read-ahead off takes effect right away.
+        let mut readahead = READAHEAD.lock();
+        for window in &mut readahead.windows {
+            window.dev = None;
+        }
+    });
+
+/// A disk driver, or a part of a disk.
+pub trait BlockDevice: Sync {
+    /// Returns the size of the device in sectors.
+    fn sectors(&self) -> u64;
+
+    /// Reads the sectors starting at `sector` into `buf`, whose length is a multiple of
+    /// `SECTOR_SIZE`.
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno>;
+
+    /// Writes `buf`, whose length is a multiple of `SECTOR_SIZE`, to the sectors starting at
+    /// `sector`.
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), Errno>;
+
+    /// Reads consecutive sectors starting at `sector` into each of `bufs` in turn, in one
+    /// request to the hardware if the driver can.
+    fn read_vectored(&self, sector: u64, bufs: &mut [&mut [u8]]) -> Result<(), Errno> {
+        let mut sector = sector;
+        for buf in bufs {
+            self.read(sector, buf)?;
+            sector += (buf.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes each of `bufs` in turn to consecutive sectors starting at `sector`, in one
+    /// request to the hardware if the driver can.
+    fn write_vectored(&self, sector: u64, bufs: &[&[u8]]) -> Result<(), Errno> {
+        let mut sector = sector;
+        for buf in bufs {
+            self.write(sector, buf)?;
+            sector += (buf.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    /// Waits until everything written so far is on stable storage, like virtio-blk's FLUSH
+    /// command. A barrier too: writes made after it returns can't be stored before those
+    /// made earlier, which is how filesystems order their updates.
+    ///
+    /// Devices without a volatile write cache have nothing to do.
+    fn flush(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Device {
+    name: &'static str,
+    dev: &'static dyn BlockDevice,
+}
+
+static DEVICES: Mutex<[Option<Device>; MAX_DEVICES]> = Mutex::new([None; MAX_DEVICES]);
+
+/// Adds a disk called `name` for `dev`, and then its partitions, returning its index.
+pub fn register(name: &'static str, dev: &'static dyn BlockDevice) -> Result<usize, Errno> {
+    let index = add(name, dev)?;
+    partition::scan(index, name, dev);
+    Ok(index)
+}
+
+/// Adds a device called `name` for `dev` without looking for partitions on it, returning
+/// its index.
+pub fn add(name: &'static str, dev: &'static dyn BlockDevice) -> Result<usize, Errno> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().flatten().any(|d| d.name == name) {
+        return Err(Errno::EEXIST);
+    }
+    let index = devices
+        .iter()
+        .position(|d| d.is_none())
+        .ok_or(Errno::ENOSPC)?;
+
+    devices[index] = Some(Device { name, dev });
+    Ok(index)
+}
+
+/// Returns the index of the device called `name`.
+pub fn find(name: &str) -> Option<usize> {
+    DEVICES
+        .lock()
+        .iter()
+        .position(|d| d.is_some_and(|d| d.name == name))
+}
+
+pub fn name(index: usize) -> Result<&'static str, Errno> {
+    DEVICES
+        .lock()
+        .get(index)
+        .copied()
+        .flatten()
+        .map(|d| d.name)
+        .ok_or(Errno::ENODEV)
+}
+
+pub fn device(index: usize) -> Result<&'static dyn BlockDevice, Errno> {
+    DEVICES
+        .lock()
+        .get(index)
+        .copied()
+        .flatten()
+        .map(|d| d.dev)
+        .ok_or(Errno::ENODEV)
+}
+
+/// Flushes the device at `index`, see `BlockDevice::flush()`.
+pub fn flush(index: usize) -> Result<(), Errno> {
+    device(index)?.flush()
+}
+
+/// Flushes every device, returning the last error but going on past it.
+pub fn flush_all() -> Result<(), Errno> {
+    let registered = DEVICES.lock().map(|d| d.is_some());
+    let mut result = Ok(());
+    for index in (0..MAX_DEVICES).filter(|&index| registered[index]) {
+        if let Err(errno) = flush(index) {
+            result = Err(errno);
+        }
+    }
+    result
+}
+
+// MARK - REQUESTS
+
+/// What a request does, with the buffer it reads into or writes from.
+pub enum Op<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+/// A request for `submit()`, the buffer being a whole number of sectors long.
+pub struct Request<'a> {
+    pub sector: u64,
+    pub op: Op<'a>,
+}
+
+impl Request<'_> {
+    fn sectors(&self) -> u64 {
+        let len = match &self.op {
+            Op::Read(buf) => buf.len(),
+            Op::Write(buf) => buf.len(),
+        };
+        (len / SECTOR_SIZE) as u64
+    }
+
+    fn is_read(&self) -> bool {
+        matches!(self.op, Op::Read(_))
+    }
+}
+
+/// How requests went since boot, see `stats()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Requests made, and how many of them were merged into the one before.
+    pub requests: usize,
+    pub merged: usize,
+    /// Reads found in a read-ahead window, and those that went to the device.
+    pub readahead_hits: usize,
+    pub readahead_misses: usize,
+}
+
+static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+static MERGED: AtomicUsize = AtomicUsize::new(0);
+static READAHEAD_HITS: AtomicUsize = AtomicUsize::new(0);
+static READAHEAD_MISSES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn stats() -> Stats {
+    Stats {
+        requests: REQUESTS.load(Ordering::Relaxed),
+        merged: MERGED.load(Ordering::Relaxed),
+        readahead_hits: READAHEAD_HITS.load(Ordering::Relaxed),
+        readahead_misses: READAHEAD_MISSES.load(Ordering::Relaxed),
+    }
+}
+
+/// Carries out `requests` on the device at `index`, in one pass over the disk: sorted by
+/// sector, requests of the same kind on adjacent sectors merged. Requests on the same
+/// sector keep their order.
+///
+/// Writes drop the read-ahead windows they overlap. Reads don't read ahead, the caller
+/// knows what it needs already.
+pub fn submit(index: usize, requests: &mut [Request]) -> Result<(), Errno> {
+    let dev = device(index)?;
+    for request in requests.iter() {
+        let len = (request.sectors() as usize) * SECTOR_SIZE;
+        let whole = match &request.op {
+            Op::Read(buf) => buf.len() == len,
+            Op::Write(buf) => buf.len() == len,
+        };
+        if !whole {
+            return Err(Errno::EINVAL);
+        }
+        check_range(dev.sectors(), request.sector, len)?;
+    }
+    REQUESTS.fetch_add(requests.len(), Ordering::Relaxed);
+
+    // An insertion sort is stable, and batches are small.
+    for i in 1..requests.len() {
+        let mut j = i;
+        while j > 0 && requests[j - 1].sector > requests[j].sector {
+            requests.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+
+    let mut rest = requests;
+    while !rest.is_empty() {
+        let mut run = 1;
+        let mut end = rest[0].sector + rest[0].sectors();
+        while run < rest.len().min(MAX_SEGMENTS)
+            && rest[run].sector == end
+            && rest[run].is_read() == rest[0].is_read()
+        {
+            end += rest[run].sectors();
+            run += 1;
+        }
+        MERGED.fetch_add(run - 1, Ordering::Relaxed);
+
+        let (batch, next) = rest.split_at_mut(run);
+        let sector = batch[0].sector;
+        if batch[0].is_read() {
+            let mut bufs: [&mut [u8]; MAX_SEGMENTS] = core::array::from_fn(|_| &mut [][..]);
+            for (buf, request) in bufs.iter_mut().zip(batch.iter_mut()) {
+                if let Op::Read(data) = &mut request.op {
+                    *buf = data;
+                }
+            }
+            dev.read_vectored(sector, &mut bufs[..run])?;
+        } else {
+            let mut bufs: [&[u8]; MAX_SEGMENTS] = [&[]; MAX_SEGMENTS];
+            for (buf, request) in bufs.iter_mut().zip(batch.iter()) {
+                if let Op::Write(data) = &request.op {
+                    *buf = data;
+                }
+            }
+            READAHEAD.lock().invalidate(index, sector, end);
+            dev.write_vectored(sector, &bufs[..run])?;
+        }
+        rest = next;
+    }
+    Ok(())
+}
+
+/// Writes `buf` to the sectors of the device at `index` starting at `sector`.
+pub fn write(index: usize, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+    submit(
+        index,
+        &mut [Request {
+            sector,
+            op: Op::Write(buf),
+        }],
+    )
+}
+
+// MARK - READ-AHEAD
+
+struct Window {
+    // Device the window holds sectors of, `None` while unused.
+    dev: Option<usize>,
+    start: u64,
+    sectors: u64,
+    // When the window was last used, the least recently used one is reused first.
+    used: usize,
+    data: Option<SgList>,
+}
+
+struct ReadAhead {
+    windows: [Window; WINDOWS],
+    // Sector after the last one read from each device: a read starting there is sequential.
+    next: [u64; MAX_DEVICES],
+    clock: usize,
+}
+
+impl ReadAhead {
+    /// Returns the window holding sectors `start..end` of `dev`.
+    fn find(&mut self, dev: usize, start: u64, end: u64) -> Option<&mut Window> {
+        self.clock += 1;
+        let clock = self.clock;
+        let window = self
+            .windows
+            .iter_mut()
+            .find(|w| w.dev == Some(dev) && w.start <= start && end <= w.start + w.sectors)?;
+        window.used = clock;
+        Some(window)
+    }
+
+    /// Drops the windows holding any of sectors `start..end` of `dev`.
+    fn invalidate(&mut self, dev: usize, start: u64, end: u64) {
+        for window in &mut self.windows {
+            if window.dev == Some(dev)
+                && window.start < end
+                && start < window.start + window.sectors
+            {
+                window.dev = None;
+            }
+        }
+    }
+}
+
+// FIXME: Held while a window is filled, fine while drivers poll for completion rather
+// than sleep.
+static READAHEAD: Mutex<ReadAhead> = Mutex::new(ReadAhead {
+    windows: [const {
+        Window {
+            dev: None,
+            start: 0,
+            sectors: 0,
+            used: 0,
+            data: None,
+        }
+    }; WINDOWS],
+    next: [0; MAX_DEVICES],
+    clock: 0,
+});
+
+/// Reads the sectors of the device at `index` starting at `sector` into `buf`. Continuing
+/// a sequential read fills a read-ahead window with the sectors that follow too.
+pub fn read(index: usize, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+    let dev = device(index)?;
+    check_range(dev.sectors(), sector, buf.len())?;
+    let sectors = (buf.len() / SECTOR_SIZE) as u64;
+    let end = sector + sectors;
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+
+    let mut readahead = READAHEAD.lock();
+    let sequential = readahead.next[index] == sector;
+    readahead.next[index] = end;
+    if let Some(window) = readahead.find(index, sector, end) {
+        let offset = (sector - window.start) as usize * SECTOR_SIZE;
+        if let Some(data) = &window.data {
+            data.read_at(offset, buf);
+        }
+        READAHEAD_HITS.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+    READAHEAD_MISSES.fetch_add(1, Ordering::Relaxed);
+    let window_sectors = READAHEAD_SECTORS.get() as u64;
+    if !sequential || sectors >= window_sectors {
+        drop(readahead);
+        return dev.read(sector, buf);
+    }
+
+    // What was asked for and what follows it, up to the end of the device.
+    let clock = readahead.clock;
+    let window = readahead
+        .windows
+        .iter_mut()
+        .min_by_key(|w| (w.dev.is_some(), w.used))
+        .unwrap();
+    let window_sectors = window_sectors.min(dev.sectors() - sector);
+    window.dev = None;
+    let data = match &mut window.data {
+        Some(data) => data,
+        // Without the memory, the read goes to the device as it is.
+        None => match phalloc_sg(WINDOW_SECTORS * SECTOR_SIZE) {
+            Ok(data) => window.data.insert(data),
+            Err(_) => {
+                drop(readahead);
+                return dev.read(sector, buf);
+            }
+        },
+    };
+    let mut left = window_sectors as usize * SECTOR_SIZE;
+    let mut bufs: [&mut [u8]; MAX_SEGMENTS] = core::array::from_fn(|_| &mut [][..]);
+    let mut segments = 0;
+    for slice in data.slices_mut() {
+        if left == 0 {
+            break;
+        }
+        let len = slice.len().min(left);
+        bufs[segments] = &mut slice[..len];
+        segments += 1;
+        left -= len;
+    }
+    dev.read_vectored(sector, &mut bufs[..segments])?;
+    data.read_at(0, buf);
+    window.dev = Some(index);
+    window.start = sector;
+    window.sectors = window_sectors;
+    window.used = clock;
+    Ok(())
+}
+
+/// Checks that `len` bytes starting at `sector` are whole sectors on a device of `sectors`
+/// sectors, for drivers to call before going to the hardware.
+pub fn check_range(sectors: u64, sector: u64, len: usize) -> Result<(), Errno> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(Errno::EINVAL);
+    }
+    let end = sector
+        .checked_add((len / SECTOR_SIZE) as u64)
+        .ok_or(Errno::ENXIO)?;
+    if end > sectors {
+        return Err(Errno::ENXIO);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ranges_must_be_whole_sectors_on_the_device() {
+        assert_eq!(check_range(8, 0, 8 * SECTOR_SIZE), Ok(()));
+        assert_eq!(check_range(8, 7, SECTOR_SIZE), Ok(()));
+        assert_eq!(check_range(8, 7, 2 * SECTOR_SIZE), Err(Errno::ENXIO));
+        assert_eq!(check_range(8, u64::MAX, SECTOR_SIZE), Err(Errno::ENXIO));
+        assert_eq!(check_range(8, 0, 100), Err(Errno::EINVAL));
+    }
+
+    const DISK_SECTORS: usize = 64;
+
+    // Counts the requests that reach it.
+    struct RamDisk {
+        data: Mutex<[u8; DISK_SECTORS * SECTOR_SIZE]>,
+        reads: AtomicUsize,
+        writes: AtomicUsize,
+    }
+
+    impl BlockDevice for RamDisk {
+        fn sectors(&self) -> u64 {
+            DISK_SECTORS as u64
+        }
+
+        fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+            let start = sector as usize * SECTOR_SIZE;
+            buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn write(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+            let start = sector as usize * SECTOR_SIZE;
+            self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn write_vectored(&self, sector: u64, bufs: &[&[u8]]) -> Result<(), Errno> {
+            let mut start = sector as usize * SECTOR_SIZE;
+            for buf in bufs {
+                self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+                start += buf.len();
+            }
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    static DISK: RamDisk = RamDisk {
+        data: Mutex::new([0; DISK_SECTORS * SECTOR_SIZE]),
+        reads: AtomicUsize::new(0),
+        writes: AtomicUsize::new(0),
+    };
+
+    fn disk() -> usize {
+        find("ramtest").unwrap_or_else(|| add("ramtest", &DISK).unwrap())
+    }
+
+    #[test_case]
+    fn adjacent_requests_are_merged() {
+        let index = disk();
+        let before = stats();
+        let writes = DISK.writes.load(Ordering::Relaxed);
+        let data = [0, 1, 2, 5].map(|value| [value; SECTOR_SIZE]);
+        let mut requests = [(42, 2), (40, 0), (41, 1), (45, 3)].map(|(sector, i)| Request {
+            sector,
+            op: Op::Write(&data[i]),
+        });
+        assert_eq!(submit(index, &mut requests), Ok(()));
+
+        // Sectors 40 to 42 in one request, 45 in another.
+        assert_eq!(DISK.writes.load(Ordering::Relaxed), writes + 2);
+        assert_eq!(stats().merged, before.merged + 2);
+        assert_eq!(stats().requests, before.requests + 4);
+        let data = DISK.data.lock();
+        for (sector, value) in [(40, 0), (41, 1), (42, 2), (45, 5)] {
+            assert_eq!(data[sector * SECTOR_SIZE], value);
+        }
+        drop(data);
+
+        let mut buf = [0; 100];
+        let mut bad = [Request {
+            sector: 0,
+            op: Op::Read(&mut buf),
+        }];
+        assert_eq!(submit(index, &mut bad), Err(Errno::EINVAL));
+    }
+
+    #[test_case]
+    fn sequential_reads_are_read_ahead() {
+        let index = disk();
+        DISK.data.lock()[..SECTOR_SIZE * 8].fill(7);
+        let before = stats();
+        let reads = DISK.reads.load(Ordering::Relaxed);
+        let mut buf = [0; SECTOR_SIZE];
+
+        // Starts where the last read from the disk ended, at 0 as nothing was read yet.
+        for sector in 0..4 {
+            assert_eq!(read(index, sector, &mut buf), Ok(()));
+            assert_eq!(buf[0], 7);
+        }
+        assert_eq!(DISK.reads.load(Ordering::Relaxed), reads + 1);
+        assert_eq!(stats().readahead_hits, before.readahead_hits + 3);
+        assert_eq!(stats().readahead_misses, before.readahead_misses + 1);
+
+        // Writing drops what was read ahead.
+        assert_eq!(write(index, 4, &[9; SECTOR_SIZE]), Ok(()));
+        assert_eq!(read(index, 4, &mut buf), Ok(()));
+        assert_eq!(buf[0], 9);
+        assert_eq!(DISK.reads.load(Ordering::Relaxed), reads + 2);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::{perf, println, sync::Mutex, timer};
+
+const MAX_PHASES: usize = 32;
+
+/// A measured step of the boot, with `time` CSR values at its start and end.
+#[derive(Clone, Copy)]
+struct Phase {
+    name: &'static str,
+    start: u64,
+    end: u64,
+    counts: perf::Counters,
+}
+
+static PHASES: Mutex<[Option<Phase>; MAX_PHASES]> = Mutex::new([None; MAX_PHASES]);
+
+fn to_us(time: u64) -> u64 {
+    time * 1_000_000 / timer::TIMEBASE_FREQ
+}
+
+/// Runs `f` as boot phase `name`, recording how long it took for `report()`.
+///
+/// Phases beyond `MAX_PHASES` still run, but aren't recorded.
+pub fn measure<R>(name: &'static str, f: impl FnOnce() -> R) -> R {
+    let start = timer::now();
+    let (ret, counts) = perf::measure(f);
+    let end = timer::now();
+
+    if let Some(slot) = PHASES.lock().iter_mut().find(|p| p.is_none()) {
+        *slot = Some(Phase {
+            name,
+            start,
+            end,
+            counts,
+        });
+    }
+
+    ret
+}
+
+/// Prints the duration of every recorded phase and the time since reset, called right
+/// before the first process is scheduled.
+pub fn report() {
+    println!("boot: phase          time (us)       cycles instructions");
+    for phase in PHASES.lock().iter().flatten() {
+        println!(
+            "boot: {:<14} {:>9} {:>12} {:>12}",
+            phase.name,
+            to_us(phase.end - phase.start),
+            phase.counts.cycles,
+            phase.counts.instret
+        );
+    }
+    // `time` starts counting at reset, so this includes the firmware.
+    println!("boot: {} us to the first process", to_us(timer::now()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PHASES, measure};
+
+    #[test_case]
+    fn measure_records_phase() {
+        assert_eq!(measure("bootprof-test", || 42), 42);
+
+        let phases = PHASES.lock();
+        let phase = phases.iter().flatten().find(|p| p.name == "bootprof-test");
+        assert!(phase.is_some_and(|p| p.start <= p.end));
+    }
+}
@@ -0,0 +1,326 @@
+// The buddy allocator's bookkeeping, independent of where the managed memory lives.
+//
+// Blocks are identified by their byte offset from the start of the managed region,
+// and all metadata lives in caller-provided buffers, so this file doesn't depend on
+// the rest of the kernel and is also compiled and tested on the host (see `host-tests/`).
+//
+// Free blocks are kept in a list per order, so an allocation takes the first block of the
+// smallest order that fits and splits it down, and a free merges the block with its buddy
+// while the buddy is free: both take a step per order. The metadata has an entry per
+// `min_block` bytes, the one of a block's first `min_block` saying whether it is free or
+// allocated, of what order, and linking it into its free list.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum BlockState {
+    /// Not the start of a block, but inside one, or of one being split or merged.
+    Inside = 0,
+    Free = 1,
+    Allocated = 2,
+}
+
+// Ends a free list.
+const NONE: u32 = u32::MAX;
+
+/// The metadata of `min_block` bytes of managed memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Block {
+    state: BlockState,
+    order: u8,
+    // Neighbours in the free list of the order, as indexes into the metadata.
+    prev: u32,
+    next: u32,
+}
+
+impl Block {
+    pub const EMPTY: Self = Self {
+        state: BlockState::Inside,
+        order: 0,
+        prev: NONE,
+        next: NONE,
+    };
+}
+
+/// Returns the number of `Block`s needed to manage `mem_size` bytes in blocks of at least
+/// `min_block` bytes.
+pub fn meta_len(mem_size: usize, min_block: usize) -> usize {
+    // Only the largest power of two that fits in `mem_size` is managed.
+    1 << (mem_size / min_block).ilog2()
+}
+
+/// Number of block orders `Stats` keeps counters for, order `k` being blocks of
+/// `min_block << k` bytes.
+pub const MAX_ORDERS: usize = 32;
+
+/// Counters of what the allocator did since it was created or `reset_stats()` was called.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Blocks handed out and freed, indexed by order.
+    pub allocs: [usize; MAX_ORDERS],
+    pub frees: [usize; MAX_ORDERS],
+    /// Blocks split in two to serve an allocation, and pairs of buddies merged when freeing.
+    pub splits: usize,
+    pub merges: usize,
+    /// Allocations of a nonzero size that couldn't be served.
+    pub failed: usize,
+    /// Bytes allocated now, and the most that were allocated at once.
+    pub used: usize,
+    pub peak: usize,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            allocs: [0; MAX_ORDERS],
+            frees: [0; MAX_ORDERS],
+            splits: 0,
+            merges: 0,
+            failed: 0,
+            used: 0,
+            peak: 0,
+        }
+    }
+}
+
+pub struct Buddy<'a> {
+    min_block: usize,
+    // Order of the whole managed region.
+    high_order: usize,
+    meta: &'a mut [Block],
+    // First free block of each order, and how many there are.
+    free_lists: [u32; MAX_ORDERS],
+    free_counts: [usize; MAX_ORDERS],
+    stats: Stats,
+}
+
+impl<'a> Buddy<'a> {
+    /// Creates a buddy allocator managing `mem_size` bytes, handing out blocks of at least `min_block` bytes.
+    ///
+    /// `meta` must hold at least `meta_len()` elements.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `min_block` is not a power of two or the buffer is too small.
+    pub fn new(mem_size: usize, min_block: usize, meta: &'a mut [Block]) -> Self {
+        assert!(
+            min_block.is_power_of_two(),
+            "min_block must be a power of two."
+        );
+        let len = meta_len(mem_size, min_block);
+        assert!(meta.len() >= len, "buddy metadata buffer is too small.");
+        assert!(len <= NONE as usize, "buddy can't index that many blocks.");
+        assert!(
+            len.ilog2() < MAX_ORDERS as u32,
+            "buddy can't manage that many blocks."
+        );
+
+        meta.fill(Block::EMPTY);
+        let mut buddy = Self {
+            min_block,
+            high_order: len.ilog2() as usize,
+            meta,
+            free_lists: [NONE; MAX_ORDERS],
+            free_counts: [0; MAX_ORDERS],
+            stats: Stats::new(),
+        };
+        buddy.push(0, buddy.high_order);
+        buddy
+    }
+
+    /// Makes the block at metadata index `i` a free block of `order`, first in its list.
+    fn push(&mut self, i: usize, order: usize) {
+        let next = self.free_lists[order];
+        if next != NONE {
+            self.meta[next as usize].prev = i as u32;
+        }
+        self.meta[i] = Block {
+            state: BlockState::Free,
+            order: order as u8,
+            prev: NONE,
+            next,
+        };
+        self.free_lists[order] = i as u32;
+        self.free_counts[order] += 1;
+    }
+
+    /// Takes the free block at metadata index `i` out of its list.
+    fn unlink(&mut self, i: usize) {
+        let Block {
+            order, prev, next, ..
+        } = self.meta[i];
+        match prev {
+            NONE => self.free_lists[order as usize] = next,
+            prev => self.meta[prev as usize].next = next,
+        }
+        if next != NONE {
+            self.meta[next as usize].prev = prev;
+        }
+        self.meta[i] = Block::EMPTY;
+        self.free_counts[order as usize] -= 1;
+    }
+
+    /// Allocates a block of at least `n` bytes.
+    ///
+    /// Returns the offset and size of the block, or `None` if `n` is zero or no block is free.
+    /// The size is a power of two and the offset is aligned to it.
+    pub fn alloc(&mut self, n: usize) -> Option<(usize, usize)> {
+        if n == 0 {
+            return None;
+        }
+
+        let block = self.take_block(n);
+        match block {
+            Some((_, size)) => {
+                let order = (size / self.min_block).trailing_zeros() as usize;
+                self.stats.allocs[order] += 1;
+                self.stats.used += size;
+                self.stats.peak = self.stats.peak.max(self.stats.used);
+            }
+            None => self.stats.failed += 1,
+        }
+        block
+    }
+
+    /// Takes a block for `alloc()` from the free lists, splitting a larger one as needed.
+    fn take_block(&mut self, n: usize) -> Option<(usize, usize)> {
+        let blocks = n.div_ceil(self.min_block).checked_next_power_of_two()?;
+        let order = blocks.trailing_zeros() as usize;
+        let mut from = (order..=self.high_order).find(|&k| self.free_lists[k] != NONE)?;
+
+        let i = self.free_lists[from] as usize;
+        self.unlink(i);
+        // Give back the upper halves until the block is as small as it can be.
+        while from > order {
+            from -= 1;
+            self.push(i + (1 << from), from);
+            self.stats.splits += 1;
+        }
+        self.meta[i] = Block {
+            state: BlockState::Allocated,
+            order: order as u8,
+            ..Block::EMPTY
+        };
+        Some((i * self.min_block, self.min_block << order))
+    }
+
+    /// Returns the size of the block `alloc()` returned at `offset`, `None` if no allocated
+    /// block starts there.
+    pub fn block_size(&self, offset: usize) -> Option<usize> {
+        if !offset.is_multiple_of(self.min_block) {
+            return None;
+        }
+        self.meta
+            .get(offset / self.min_block)
+            .filter(|block| block.state == BlockState::Allocated)
+            .map(|block| self.min_block << block.order)
+    }
+
+    /// Resizes the block at `offset` that `alloc()` returned to hold `n` bytes without moving
+    /// it, growing it by taking its buddies while they are free and above it, or shrinking it
+    /// by giving back its upper halves.
+    ///
+    /// Returns the new size, `None` if no block starts there, `n` is zero or the block
+    /// can't grow where it is.
+    pub fn resize(&mut self, offset: usize, n: usize) -> Option<usize> {
+        let size = self.block_size(offset)?;
+        let i = offset / self.min_block;
+        let from = self.meta[i].order as usize;
+        let order = match n {
+            0 => return None,
+            n => n
+                .div_ceil(self.min_block)
+                .checked_next_power_of_two()?
+                .trailing_zeros() as usize,
+        };
+        if order > self.high_order {
+            return None;
+        }
+
+        // Each buddy on the way up must be the free upper half of the larger block.
+        let growable = (from..order).all(|k| {
+            let buddy = i ^ (1 << k);
+            let block = self.meta[buddy];
+            buddy > i && block.state == BlockState::Free && block.order as usize == k
+        });
+        if !growable {
+            return None;
+        }
+        for k in from..order {
+            self.unlink(i ^ (1 << k));
+            self.stats.merges += 1;
+        }
+        for k in (order..from).rev() {
+            self.push(i + (1 << k), k);
+            self.stats.splits += 1;
+        }
+        self.meta[i] = Block {
+            state: BlockState::Allocated,
+            order: order as u8,
+            ..Block::EMPTY
+        };
+
+        let new_size = self.min_block << order;
+        self.stats.used = self.stats.used - size + new_size;
+        self.stats.peak = self.stats.peak.max(self.stats.used);
+        Some(new_size)
+    }
+
+    /// Frees the block at `offset` previously returned by `alloc()`, merging it with its
+    /// buddies as far as possible. Its size is known from the metadata.
+    ///
+    /// A block state that is not what this function expects indicates a bug in the
+    /// allocation logic and fails a `kassert!`. If that doesn't panic, the block is
+    /// left as it is.
+    pub fn free(&mut self, offset: usize) {
+        let mut i = offset / self.min_block;
+
+        let Some(size) = self.block_size(offset) else {
+            crate::kassert!(
+                false,
+                "buddy_free(): Memory at index {i} was not allocated, something is wrong."
+            );
+            return;
+        };
+        let mut order = self.meta[i].order as usize;
+        self.meta[i] = Block::EMPTY;
+        self.stats.frees[order] += 1;
+        self.stats.used -= size;
+
+        // The whole region has no buddy to merge with.
+        while order < self.high_order {
+            let buddy = i ^ (1 << order);
+            let block = self.meta[buddy];
+            if block.state != BlockState::Free || block.order as usize != order {
+                break;
+            }
+            self.unlink(buddy);
+            i = i.min(buddy);
+            order += 1;
+            self.stats.merges += 1;
+        }
+        self.push(i, order);
+    }
+
+    /// Returns the bytes managed, a power of two.
+    pub fn size(&self) -> usize {
+        self.min_block << self.high_order
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Zeroes the counters, except for the bytes in use, which also become the peak.
+    pub fn reset_stats(&mut self) {
+        let used = self.stats.used;
+        self.stats = Stats::new();
+        self.stats.used = used;
+        self.stats.peak = used;
+    }
+
+    /// Returns the number of free blocks of each order, which can be handed out without
+    /// splitting a larger one.
+    pub fn free_blocks(&self) -> [usize; MAX_ORDERS] {
+        self.free_counts
+    }
+}
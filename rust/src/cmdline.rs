@@ -0,0 +1,92 @@
+use crate::{dtb, sync::OnceCell};
+
+// The kernel command line, `/chosen/bootargs` in the device tree (QEMU's `-append`).
+// Options are separated by whitespace and are either `key=value` or a bare `key`.
+
+const MAX_CMDLINE: usize = 256;
+
+struct Cmdline {
+    buf: [u8; MAX_CMDLINE],
+    len: usize,
+}
+
+// Copied out of the device tree, whose memory the kernel doesn't reserve.
+static CMDLINE: OnceCell<Cmdline> = OnceCell::new();
+
+/// Reads the command line from the device tree, called once at boot after `dtb::init`.
+///
+/// A command line longer than `MAX_CMDLINE` bytes is cut off at the last whole option.
+pub fn init() {
+    CMDLINE.get_or_init(|| {
+        let mut cmdline = Cmdline {
+            buf: [0; MAX_CMDLINE],
+            len: 0,
+        };
+
+        let bootargs = dtb::property("/chosen", "bootargs").unwrap_or(&[]);
+        let bootargs = bootargs.split(|&b| b == 0).next().unwrap_or(&[]);
+        let mut len = bootargs.len();
+        if len > MAX_CMDLINE {
+            len = bootargs[..MAX_CMDLINE]
+                .iter()
+                .rposition(|b| b.is_ascii_whitespace())
+                .unwrap_or(0);
+        }
+
+        // Anything that isn't UTF-8 is dropped as a whole.
+        if core::str::from_utf8(&bootargs[..len]).is_ok() {
+            cmdline.buf[..len].copy_from_slice(&bootargs[..len]);
+            cmdline.len = len;
+        }
+        cmdline
+    });
+}
+
+/// Returns the whole command line, empty if there was none.
+pub fn as_str() -> &'static str {
+    let cmdline = CMDLINE.get_or_init(|| Cmdline {
+        buf: [0; MAX_CMDLINE],
+        len: 0,
+    });
+    // Checked in `init()`.
+    unsafe { core::str::from_utf8_unchecked(&cmdline.buf[..cmdline.len]) }
+}
+
+/// Returns the value of option `key`, `""` if it is given without a value.
+///
+/// If an option is given more than once, the last one wins.
+pub fn get(key: &str) -> Option<&'static str> {
+    find(as_str(), key)
+}
+
+/// Returns the value of option `key` parsed as a number, `None` if it is missing or invalid.
+pub fn get_usize(key: &str) -> Option<usize> {
+    get(key)?.parse().ok()
+}
+
+fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_ascii_whitespace()
+        .rev()
+        .find_map(|option| match option.split_once('=') {
+            Some((k, value)) if k == key => Some(value),
+            None if option == key => Some(""),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find;
+
+    #[test_case]
+    fn finds_options() {
+        let cmdline = "console=ttyS0  hz=250 quiet ktest=pipe hz=50";
+
+        assert_eq!(find(cmdline, "console"), Some("ttyS0"));
+        assert_eq!(find(cmdline, "quiet"), Some(""));
+        assert_eq!(find(cmdline, "hz"), Some("50"));
+        assert_eq!(find(cmdline, "root"), None);
+        assert_eq!(find(cmdline, "ktest=pipe"), None);
+    }
+}
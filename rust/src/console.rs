@@ -0,0 +1,55 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{cmdline, fbcon, netconsole, syscall::Errno, tty};
+
+// The console: the tty the first process runs on, and the one kernel messages are printed
+// on. Both are the SBI console (tty 0) unless the command line names another driver's tty
+// (see `tty::register()`): `console=<name>` picks the console, and where kernel messages
+// go too unless `log=<name>` picks another for them.
+//
+// Everything printed on the console, kernel messages and program output alike, is mirrored
+// to the network console and the framebuffer console, and what they take as input is
+// typed on the console.
+
+static CONSOLE: AtomicUsize = AtomicUsize::new(0);
+static LOG: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes `tty` the console or the log if the command line names its driver, `name`.
+pub fn select(tty: usize, name: &str) {
+    let console = cmdline::get("console");
+    if console == Some(name) {
+        CONSOLE.store(tty, Ordering::Relaxed);
+    }
+    if cmdline::get("log").or(console) == Some(name) {
+        LOG.store(tty, Ordering::Relaxed);
+    }
+}
+
+/// Returns the tty of the console.
+pub fn tty() -> usize {
+    CONSOLE.load(Ordering::Relaxed)
+}
+
+/// Copies console output to the network and framebuffer consoles.
+pub fn mirror(buf: &[u8]) {
+    netconsole::capture(buf);
+    fbcon::write(buf);
+}
+
+/// Writes kernel messages, see `print!`.
+pub fn log(buf: &[u8]) {
+    mirror(buf);
+    tty::output(LOG.load(Ordering::Relaxed), buf);
+}
+
+/// Takes `bytes` as if they were typed on the console, for input arriving some other way
+/// (see `netconsole` and `virtio_input`).
+pub fn feed(bytes: &[u8]) {
+    tty::feed(tty(), bytes);
+}
+
+/// Opens the console as fds 0, 1 and 2 of the calling process, which has no fds yet, see
+/// `tty::open_stdio()`.
+pub fn open_stdio() -> Result<(), Errno> {
+    tty::open_stdio(tty())
+}
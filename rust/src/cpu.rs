@@ -0,0 +1,243 @@
+use core::fmt;
+
+use crate::{MAX_HARTS, dtb, hart_id, panic, println, sbi, sync::Mutex};
+
+/// Paging modes a hart's MMU supports, from `mmu-type` in the device tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MmuType {
+    Bare,
+    Sv32,
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+/// Multi-letter extensions the kernel looks for, others aren't recorded.
+const NAMED_EXTENSIONS: &[&str] = &["sstc", "svpbmt", "zicsr", "zifencei"];
+
+/// What a hart implements, detected at boot.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuFeatures {
+    // Bit `n` is set for single-letter extension `'a' + n`.
+    extensions: u32,
+    // Bit `n` is set for `NAMED_EXTENSIONS[n]`.
+    named: u32,
+    xlen: usize,
+    mmu: MmuType,
+}
+
+impl CpuFeatures {
+    const fn empty() -> Self {
+        Self {
+            extensions: 0,
+            named: 0,
+            xlen: 0,
+            mmu: MmuType::Bare,
+        }
+    }
+
+    /// Returns whether single-letter extension `ext` (e.g. `'c'`) is implemented.
+    pub fn has(&self, ext: char) -> bool {
+        ext.is_ascii_lowercase() && self.extensions & (1 << (ext as u32 - 'a' as u32)) != 0
+    }
+
+    /// Returns whether multi-letter extension `name` (e.g. `"sstc"`), one of
+    /// `NAMED_EXTENSIONS`, is implemented.
+    pub fn has_named(&self, name: &str) -> bool {
+        NAMED_EXTENSIONS
+            .iter()
+            .position(|&n| n == name)
+            .is_some_and(|n| self.named & (1 << n) != 0)
+    }
+
+    /// Returns whether the hart has floating-point registers that need saving.
+    pub fn has_fpu(&self) -> bool {
+        self.has('f') || self.has('d')
+    }
+
+    /// Returns whether the MMU supports the paging mode the kernel is built for, `vm::SATP_MODE`.
+    pub fn supports_kernel_paging(&self) -> bool {
+        #[cfg(target_arch = "riscv32")]
+        let needed = MmuType::Sv32;
+        #[cfg(target_arch = "riscv64")]
+        let needed = MmuType::Sv39;
+
+        // `mmu-type` names the largest mode, which implies the smaller ones.
+        self.mmu >= needed
+    }
+}
+
+/// Prints the features as an ISA string and paging mode, e.g. `rv32imac (Sv32)`.
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The usual order of the base and common extensions, then the rest.
+        const ORDER: &str = "imafdqcbvh";
+        write!(f, "rv{}", self.xlen)?;
+        let rest = ('a'..='z').filter(|&ext| !ORDER.contains(ext));
+        for ext in ORDER.chars().chain(rest).filter(|&ext| self.has(ext)) {
+            write!(f, "{ext}")?;
+        }
+        write!(f, " ({:?})", self.mmu)
+    }
+}
+
+// Filled in by `init()` for every hart in the device tree.
+static FEATURES: Mutex<[Option<CpuFeatures>; MAX_HARTS]> = Mutex::new([None; MAX_HARTS]);
+
+/// Returns the features of `hart`, or `None` if it wasn't found at boot.
+pub fn features(hart: usize) -> Option<CpuFeatures> {
+    FEATURES.lock()[hart]
+}
+
+/// Returns the features of the calling hart.
+pub fn current() -> CpuFeatures {
+    features(hart_id()).unwrap_or(CpuFeatures::empty())
+}
+
+/// Returns how many harts were found at boot, at least the one running.
+pub fn count() -> usize {
+    FEATURES.lock().iter().flatten().count().max(1)
+}
+
+/// Parses a `riscv,isa` string such as `rv64imafdc_zicsr_zifencei`.
+///
+/// Multi-letter extensions after the first `_` are ignored unless in `NAMED_EXTENSIONS`.
+fn parse_isa(isa: &str) -> CpuFeatures {
+    let mut features = CpuFeatures::empty();
+
+    features.xlen = match isa.get(..4) {
+        Some("rv32") => 32,
+        Some("rv64") => 64,
+        _ => return features,
+    };
+
+    let mut parts = isa[4..].split('_');
+    let letters = parts.next().unwrap_or("");
+    for ext in letters.chars().filter(char::is_ascii_lowercase) {
+        // `g` stands for the general-purpose set.
+        let exts = if ext == 'g' { "imafd" } else { "" };
+        for ext in exts.chars().chain([ext]) {
+            features.extensions |= 1 << (ext as u32 - 'a' as u32);
+        }
+    }
+    for name in parts {
+        if let Some(n) = NAMED_EXTENSIONS.iter().position(|&n| n == name) {
+            features.named |= 1 << n;
+        }
+    }
+
+    features
+}
+
+fn parse_mmu(mmu_type: &str) -> MmuType {
+    match mmu_type {
+        "riscv,sv32" => MmuType::Sv32,
+        "riscv,sv39" => MmuType::Sv39,
+        "riscv,sv48" => MmuType::Sv48,
+        "riscv,sv57" => MmuType::Sv57,
+        _ => MmuType::Bare,
+    }
+}
+
+/// Returns the string value of `name` of the node for `hart`, `/cpus/cpu@<hart>`.
+fn cpu_property(hart: usize, name: &str) -> Option<&'static str> {
+    const PREFIX: &[u8] = b"/cpus/cpu@";
+
+    // Hart ids are small, a few digits are plenty.
+    let mut path = [0_u8; PREFIX.len() + 4];
+    path[..PREFIX.len()].copy_from_slice(PREFIX);
+    let mut len = PREFIX.len();
+    let mut digits = [0_u8; 4];
+    let mut n = hart;
+    let mut count = 0;
+    loop {
+        digits[count] = b'0' + (n % 10) as u8;
+        count += 1;
+        n /= 10;
+        if n == 0 || count == digits.len() {
+            break;
+        }
+    }
+    for &digit in digits[..count].iter().rev() {
+        path[len] = digit;
+        len += 1;
+    }
+
+    let value = dtb::property(core::str::from_utf8(&path[..len]).ok()?, name)?;
+    core::str::from_utf8(value.split(|&b| b == 0).next()?).ok()
+}
+
+crate::register_subsystem!(Core, "cpu", init, after("dtb"));
+
+/// Detects the features of every hart and checks the boot hart can run the kernel.
+fn init() {
+    let mut features = FEATURES.lock();
+
+    for (hart, slot) in features.iter_mut().enumerate() {
+        let Some(isa) = cpu_property(hart, "riscv,isa") else {
+            continue;
+        };
+        let mut f = parse_isa(isa);
+        f.mmu = cpu_property(hart, "mmu-type").map_or(MmuType::Bare, parse_mmu);
+        *slot = Some(f);
+
+        println!("cpu{hart}: {isa} ({:?})", f.mmu);
+    }
+
+    let (vendor, arch, imp) = sbi::machine_ids();
+    println!(
+        "cpu{}: mvendorid=0x{vendor:x} marchid=0x{arch:x} mimpid=0x{imp:x}, sbi: time={} hsm={} srst={}",
+        hart_id(),
+        sbi::probe_extension(sbi::EID_TIME),
+        sbi::probe_extension(sbi::EID_HSM),
+        sbi::probe_extension(sbi::EID_SRST),
+    );
+
+    // Without a device tree entry there's nothing to check against, assume the best.
+    if let Some(boot) = features[hart_id()] {
+        if boot.xlen != usize::BITS as usize {
+            panic!(
+                "kernel built for rv{} runs on an rv{} hart",
+                usize::BITS,
+                boot.xlen
+            );
+        }
+        if !boot.supports_kernel_paging() {
+            panic!(
+                "the boot hart's MMU ({:?}) lacks the kernel's paging mode",
+                boot.mmu
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use super::*;
+    use crate::vfs::BufWriter;
+
+    #[test_case]
+    fn parses_isa_strings() {
+        let f = parse_isa("rv64gc_zicsr_zifencei");
+        assert_eq!(f.xlen, 64);
+        assert!(f.has('i') && f.has('m') && f.has('d') && f.has('c'));
+        assert!(f.has_fpu());
+        assert!(!f.has('v') && !f.has('z'));
+        assert!(f.has_named("zifencei") && !f.has_named("sstc"));
+        assert!(parse_isa("rv64imac_sstc").has_named("sstc"));
+
+        let f = parse_isa("rv32imac");
+        assert_eq!(f.xlen, 32);
+        assert!(f.has('a') && !f.has_fpu());
+
+        let mut buf = [0; 32];
+        let mut out = BufWriter::new(&mut buf);
+        write!(out, "{f}").unwrap();
+        let len = out.len();
+        assert_eq!(&buf[..len], b"rv32imac (Bare)");
+        assert_eq!(parse_isa("x86").xlen, 0);
+        assert_eq!(parse_mmu("riscv,sv39"), MmuType::Sv39);
+    }
+}
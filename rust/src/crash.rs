@@ -0,0 +1,247 @@
+use core::fmt::{self, Write};
+
+use crate::{
+    csr::{self, Satp, Scause, Scounteren, Sie, Sstatus},
+    file, macros,
+    mem::PAGE_SIZE,
+    println, proc, signal,
+    sync::Mutex,
+    syscall::Errno,
+    trap::TrapFrame,
+    vfs::{self, BufWriter},
+};
+
+// Crash reports for processes killed by a fault: everything needed to find out what went
+// wrong, since that's all a dead process leaves behind. They are printed on the console
+// and kept in `/tmp/crash.<pid>`, as much of them as fits in a page.
+
+// ABI names of `x1` to `x31`.
+const REG_NAMES: [&str; 31] = [
+    "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6",
+    "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+// Words of the stack shown, starting at `sp`.
+const STACK_WORDS: usize = 16;
+
+// Reports are put together here, not on the small kernel stacks.
+static REPORT: Mutex<[u8; PAGE_SIZE]> = Mutex::new([0; PAGE_SIZE]);
+
+/// Returns the name of synchronous exception `scause`.
+pub fn cause_name(scause: usize) -> &'static str {
+    match scause {
+        0 => "instruction address misaligned",
+        1 => "instruction access fault",
+        2 => "illegal instruction",
+        3 => "breakpoint",
+        4 => "load address misaligned",
+        5 => "load access fault",
+        6 => "store address misaligned",
+        7 => "store access fault",
+        12 => "instruction page fault",
+        13 => "load page fault",
+        15 => "store page fault",
+        _ => "unknown exception",
+    }
+}
+
+/// Returns the signal a process gets for causing exception `scause`, `None` for exceptions
+/// that aren't the process's fault.
+pub fn fault_signal(scause: usize) -> Option<usize> {
+    match scause {
+        0 | 4 | 6 => Some(signal::SIGBUS),
+        1 | 5 | 7 | 12 | 13 | 15 => Some(signal::SIGSEGV),
+        2 => Some(signal::SIGILL),
+        3 => Some(signal::SIGTRAP),
+        _ => None,
+    }
+}
+
+/// Prints the crash report of `pid`, which caused exception `scause` at `pc` with the
+/// registers in `tf`, and keeps it in `/tmp/crash.<pid>`.
+pub fn report(pid: usize, tf: &TrapFrame, scause: usize, stval: usize, pc: usize) {
+    let mut buf = REPORT.lock();
+    let mut out = Tee {
+        file: BufWriter::new(&mut buf[..]),
+        full: false,
+    };
+    let _ = write_report(&mut out, pid, tf, scause, stval, pc);
+    let len = out.file.len();
+    if let Err(err) = save(pid, &buf[..len]) {
+        println!("crash: can't save the report of pid {pid}: {err:?}");
+    }
+}
+
+fn write_report(
+    out: &mut impl Write,
+    pid: usize,
+    tf: &TrapFrame,
+    scause: usize,
+    stval: usize,
+    pc: usize,
+) -> fmt::Result {
+    writeln!(out, "---- crash report: pid {pid} ----")?;
+    writeln!(
+        out,
+        "{} at pc 0x{pc:x}, stval 0x{stval:x} (scause {scause})",
+        cause_name(scause)
+    )?;
+
+    writeln!(out, "registers:")?;
+    let width = 2 * size_of::<usize>();
+    for (row, names) in REG_NAMES.chunks(4).enumerate() {
+        write!(out, " ")?;
+        for (col, name) in names.iter().enumerate() {
+            write!(out, " {name:>3}=0x{:0width$x}", tf.reg(1 + row * 4 + col))?;
+        }
+        writeln!(out)?;
+    }
+
+    write_csrs(out)?;
+
+    writeln!(out, "memory map:")?;
+    let mut result = Ok(());
+    proc::for_each_region(pid, |r| {
+        result = result.and_then(|_| writeln!(out, "  {r}"));
+    });
+    result?;
+
+    writeln!(out, "stack:")?;
+    let sp = tf.reg(2);
+    for i in 0..STACK_WORDS {
+        let addr = sp + i * size_of::<usize>();
+        // Stops at the first unmapped word, which the stack may well run into.
+        let Some(paddr) = proc::translate(addr) else {
+            writeln!(out, "  0x{addr:08x}: <unmapped>")?;
+            break;
+        };
+        let word = unsafe { (paddr as *const usize).read_volatile() };
+        writeln!(out, "  0x{addr:08x}: 0x{word:0width$x}")?;
+    }
+    writeln!(out, "---- end of crash report ----")
+}
+
+/// Writes `report` to `/tmp/crash.<pid>`, replacing the report of an earlier process with
+/// the same pid.
+fn save(pid: usize, report: &[u8]) -> Result<(), Errno> {
+    let mut buf = [0; 32];
+    let mut path = BufWriter::new(&mut buf);
+    write!(path, "/tmp/crash.{pid}").map_err(|_| Errno::ENAMETOOLONG)?;
+    let len = path.len();
+    let path = core::str::from_utf8(&buf[..len]).map_err(|_| Errno::EINVAL)?;
+
+    match vfs::unlink(path) {
+        Ok(()) | Err(Errno::ENOENT) => {}
+        Err(err) => return Err(err),
+    }
+    let fd = vfs::open(path, vfs::O_WRONLY | vfs::O_CREAT, 0o644)?;
+    let written = file::write(fd, report);
+    file::close(fd)?;
+    match written? == report.len() {
+        true => Ok(()),
+        false => Err(Errno::ENOSPC),
+    }
+}
+
+/// Prints the supervisor CSRs of the calling hart, decoded.
+pub fn print_csrs() {
+    let _ = write_csrs(&mut macros::Writer);
+}
+
+fn write_csrs(out: &mut impl Write) -> fmt::Result {
+    let sstatus = Sstatus::read();
+    let sie = Sie::read();
+    let satp = Satp::read();
+    let scause = Scause::read();
+    let scounteren = Scounteren::read();
+    let (stvec, mode) = csr::stvec();
+    writeln!(out, "csrs:")?;
+    writeln!(
+        out,
+        "  sstatus: spp={:?} sie={} spie={} sum={} fs={:?}",
+        sstatus.spp(),
+        sstatus.sie(),
+        sstatus.spie(),
+        sstatus.sum(),
+        sstatus.fs()
+    )?;
+    writeln!(
+        out,
+        "  sie: ssie={} stie={} seie={}",
+        sie.ssie(),
+        sie.stie(),
+        sie.seie()
+    )?;
+    writeln!(
+        out,
+        "  satp: mode={:?} asid={} ppn=0x{:x}",
+        satp.mode(),
+        satp.asid(),
+        satp.ppn()
+    )?;
+    writeln!(
+        out,
+        "  scause: interrupt={} code={}",
+        scause.is_interrupt(),
+        scause.code()
+    )?;
+    writeln!(
+        out,
+        "  scounteren: cy={} tm={} ir={}",
+        scounteren.cy(),
+        scounteren.tm(),
+        scounteren.ir()
+    )?;
+    writeln!(out, "  stvec: 0x{stvec:x} ({mode:?})")
+}
+
+/// Writes to the console, and to the report's buffer while it has room.
+struct Tee<'a> {
+    file: BufWriter<'a>,
+    // Set once something didn't fit, the file then ends before it.
+    full: bool,
+}
+
+impl Write for Tee<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        macros::Writer.write_str(s)?;
+        if !self.full && self.file.write_str(s).is_err() {
+            self.full = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn maps_exceptions_to_signals() {
+        assert_eq!(fault_signal(13), Some(signal::SIGSEGV));
+        assert_eq!(fault_signal(6), Some(signal::SIGBUS));
+        assert_eq!(fault_signal(8), None);
+        assert_eq!(cause_name(2), "illegal instruction");
+    }
+
+    #[test_case]
+    fn user_breakpoints_trap() {
+        // An `ebreak` the debugger didn't put there is the process's own.
+        #[cfg(feature = "gdb")]
+        assert!(crate::gdb::on_breakpoint(&mut TrapFrame::zeroed(), 0x1000).is_none());
+        assert_eq!(fault_signal(3), Some(signal::SIGTRAP));
+        assert_eq!(cause_name(3), "breakpoint");
+    }
+
+    #[test_case]
+    fn reports_are_kept_in_tmp() {
+        assert_eq!(save(0, b"first report"), Ok(()));
+        assert_eq!(save(0, b"second"), Ok(()));
+
+        let (mount, ino) = vfs::resolve("/tmp/crash.0").unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(vfs::read(mount, ino, 0, &mut buf), Ok(6));
+        assert_eq!(&buf[..6], b"second");
+        assert_eq!(vfs::unlink("/tmp/crash.0"), Ok(()));
+    }
+}
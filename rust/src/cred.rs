@@ -0,0 +1,155 @@
+use crate::{
+    proc::{self, PROC_MAX},
+    sync::Mutex,
+    syscall::Errno,
+};
+
+// Credentials: the user and group each process runs as. User 0 is root, which may do
+// anything; everyone else may only touch files their mode lets them (see `permission()`),
+// signal their own processes, and never raise a hard resource limit or mount anything.
+//
+// Processes start as root and keep the credentials of the process that spawned them, until
+// they give up root with `setuid()`/`setgid()`.
+
+pub const ROOT: usize = 0;
+
+/// What a process may do with a file, as in the bits of its mode.
+pub const MAY_READ: usize = 4;
+pub const MAY_WRITE: usize = 2;
+pub const MAY_EXEC: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cred {
+    pub uid: usize,
+    pub gid: usize,
+}
+
+const DEFAULT_CRED: Cred = Cred {
+    uid: ROOT,
+    gid: ROOT,
+};
+
+// Credentials of each process, indexed by pid.
+static CREDS: Mutex<[Cred; PROC_MAX]> = Mutex::new([DEFAULT_CRED; PROC_MAX]);
+
+pub fn get(pid: usize) -> Cred {
+    CREDS.lock()[pid]
+}
+
+/// Returns whether `pid` runs as root.
+pub fn is_root(pid: usize) -> bool {
+    get(pid).uid == ROOT
+}
+
+/// Gives `child` the credentials of `parent`, when the former spawns the latter.
+pub fn inherit(parent: usize, child: usize) {
+    let mut creds = CREDS.lock();
+    creds[child] = creds[parent];
+}
+
+/// Restores the default credentials of `pid`, called when it exits.
+pub fn reset(pid: usize) {
+    CREDS.lock()[pid] = DEFAULT_CRED;
+}
+
+/// Checks that `pid` may access a file owned by `uid` and `gid` with permission bits
+/// `mode` in the ways `want`, a combination of `MAY_*`.
+///
+/// Fails with `EACCES` if it may not. Root may do anything, except execute a file nobody
+/// may execute.
+pub fn permission(
+    pid: usize,
+    uid: usize,
+    gid: usize,
+    mode: usize,
+    want: usize,
+) -> Result<(), Errno> {
+    let cred = get(pid);
+    let allowed = if cred.uid == ROOT {
+        // Any execute bit will do.
+        let exec = (mode | mode >> 3 | mode >> 6) & MAY_EXEC;
+        MAY_READ | MAY_WRITE | exec
+    } else if cred.uid == uid {
+        mode >> 6 & 0o7
+    } else if cred.gid == gid {
+        mode >> 3 & 0o7
+    } else {
+        mode & 0o7
+    };
+    match want & !allowed {
+        0 => Ok(()),
+        _ => Err(Errno::EACCES),
+    }
+}
+
+/// Checks that `sender` may signal `pid`: root may signal anyone, everyone else only
+/// processes of the same user.
+///
+/// Fails with `EPERM` if it may not.
+pub fn may_signal(sender: usize, pid: usize) -> Result<(), Errno> {
+    let sender = get(sender).uid;
+    if sender == ROOT || sender == get(pid).uid || !proc::exists(pid) {
+        return Ok(());
+    }
+    Err(Errno::EPERM)
+}
+
+/// Checks that the calling process is root.
+///
+/// Fails with `EPERM` if it isn't.
+pub fn require_root() -> Result<(), Errno> {
+    match is_root(proc::current()) {
+        true => Ok(()),
+        false => Err(Errno::EPERM),
+    }
+}
+
+/// Makes `pid` run as user `uid`, which only root may change to another user than its
+/// own.
+pub fn setuid(pid: usize, uid: usize) -> Result<(), Errno> {
+    let mut creds = CREDS.lock();
+    let cred = &mut creds[pid];
+    if cred.uid != ROOT && cred.uid != uid {
+        return Err(Errno::EPERM);
+    }
+    cred.uid = uid;
+    Ok(())
+}
+
+/// Makes `pid` run as group `gid`, which only root may change to another group than its
+/// own.
+pub fn setgid(pid: usize, gid: usize) -> Result<(), Errno> {
+    let mut creds = CREDS.lock();
+    let cred = &mut creds[pid];
+    if cred.uid != ROOT && cred.gid != gid {
+        return Err(Errno::EPERM);
+    }
+    cred.gid = gid;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn users_are_confined_by_modes() {
+        // A pid no process uses while tests run.
+        let pid = PROC_MAX - 1;
+        assert_eq!(permission(pid, 5, 5, 0o000, MAY_READ | MAY_WRITE), Ok(()));
+        assert_eq!(permission(pid, 5, 5, 0o644, MAY_EXEC), Err(Errno::EACCES));
+
+        assert_eq!(setgid(pid, 7), Ok(()));
+        assert_eq!(setuid(pid, 5), Ok(()));
+        assert_eq!(setuid(pid, ROOT), Err(Errno::EPERM));
+        assert_eq!(setgid(pid, ROOT), Err(Errno::EPERM));
+        assert_eq!(permission(pid, 5, 1, 0o640, MAY_READ | MAY_WRITE), Ok(()));
+        assert_eq!(permission(pid, 6, 7, 0o640, MAY_READ), Ok(()));
+        assert_eq!(permission(pid, 6, 7, 0o640, MAY_WRITE), Err(Errno::EACCES));
+        assert_eq!(permission(pid, 6, 8, 0o604, MAY_READ), Ok(()));
+        assert_eq!(permission(pid, 6, 8, 0o640, MAY_READ), Err(Errno::EACCES));
+
+        reset(pid);
+        assert!(is_root(pid));
+    }
+}
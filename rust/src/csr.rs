@@ -0,0 +1,523 @@
+use core::arch::asm;
+
+// Typed access to the supervisor CSRs the kernel uses. Registers made of fields are
+// structs read and written whole, with a getter and a `with_*` builder per field; single
+// bits the kernel flips on their own are also set and cleared in place (`csrs`/`csrc`),
+// which an interrupt can't tear. Fields are declared once with their bit range, which is
+// checked against the register width at compile time. Registers holding a plain value
+// get functions to read or write them.
+
+/// A range of bits in a register.
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    shift: u32,
+    width: u32,
+}
+
+impl Field {
+    const fn new(shift: u32, width: u32) -> Self {
+        assert!(
+            width > 0 && shift + width <= usize::BITS,
+            "field outside the register"
+        );
+        Self { shift, width }
+    }
+
+    const fn bit(shift: u32) -> Self {
+        Self::new(shift, 1)
+    }
+
+    const fn mask(self) -> usize {
+        (usize::MAX >> (usize::BITS - self.width)) << self.shift
+    }
+
+    const fn get(self, bits: usize) -> usize {
+        (bits & self.mask()) >> self.shift
+    }
+
+    fn set(self, bits: usize, value: usize) -> usize {
+        crate::kassert!(
+            value & !(self.mask() >> self.shift) == 0,
+            "csr: value 0x{value:x} doesn't fit the field"
+        );
+        (bits & !self.mask()) | ((value << self.shift) & self.mask())
+    }
+}
+
+macro_rules! csr_read {
+    ($csr:literal) => {{
+        let value: usize;
+        unsafe { asm!(concat!("csrr {0}, ", $csr), out(reg) value) };
+        value
+    }};
+}
+
+macro_rules! csr_write {
+    ($csr:literal, $value:expr) => {
+        unsafe { asm!(concat!("csrw ", $csr, ", {0}"), in(reg) $value) }
+    };
+}
+
+/// Defines a struct for the register `$csr`, with `read()` and private helpers to write
+/// it and to set or clear fields in place.
+macro_rules! field_csr {
+    ($(#[$meta:meta])* $name:ident, $csr:literal) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub struct $name(usize);
+
+        impl $name {
+            pub fn read() -> Self {
+                Self(csr_read!($csr))
+            }
+
+            #[allow(dead_code)]
+            pub const fn bits(self) -> usize {
+                self.0
+            }
+
+            #[allow(dead_code)]
+            fn write_bits(bits: usize) {
+                csr_write!($csr, bits);
+            }
+
+            #[allow(dead_code)]
+            fn set_in_place(field: Field) {
+                unsafe { asm!(concat!("csrs ", $csr, ", {0}"), in(reg) field.mask()) }
+            }
+
+            #[allow(dead_code)]
+            fn clear_in_place(field: Field) {
+                unsafe { asm!(concat!("csrc ", $csr, ", {0}"), in(reg) field.mask()) }
+            }
+        }
+    };
+}
+
+// MARK - SSTATUS
+
+/// The privilege mode a trap was taken from, `sstatus.SPP`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Privilege {
+    User,
+    Supervisor,
+}
+
+/// The state of the floating-point registers, `sstatus.FS`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FpState {
+    /// Any floating-point instruction traps.
+    Off,
+    Initial,
+    /// Unchanged since last saved or restored.
+    Clean,
+    /// Written since, so they need saving.
+    Dirty,
+}
+
+field_csr!(
+    /// Supervisor status: interrupt enable and the state saved by the last trap.
+    Sstatus,
+    "sstatus"
+);
+
+impl Sstatus {
+    const SIE: Field = Field::bit(1);
+    const SPIE: Field = Field::bit(5);
+    const SPP: Field = Field::bit(8);
+    const FS: Field = Field::new(13, 2);
+    const SUM: Field = Field::bit(18);
+
+    pub fn write(self) {
+        Self::write_bits(self.0);
+    }
+
+    /// Whether interrupts are enabled.
+    pub fn sie(self) -> bool {
+        Self::SIE.get(self.0) != 0
+    }
+
+    /// Whether interrupts were enabled before the last trap, restored by `sret`.
+    pub fn spie(self) -> bool {
+        Self::SPIE.get(self.0) != 0
+    }
+
+    /// The mode the last trap came from, returned to by `sret`.
+    pub fn spp(self) -> Privilege {
+        match Self::SPP.get(self.0) {
+            0 => Privilege::User,
+            _ => Privilege::Supervisor,
+        }
+    }
+
+    pub fn fs(self) -> FpState {
+        match Self::FS.get(self.0) {
+            0 => FpState::Off,
+            1 => FpState::Initial,
+            2 => FpState::Clean,
+            _ => FpState::Dirty,
+        }
+    }
+
+    /// Whether the kernel may access user pages, see `uaccess`.
+    pub fn sum(self) -> bool {
+        Self::SUM.get(self.0) != 0
+    }
+
+    pub fn with_sie(self, on: bool) -> Self {
+        Self(Self::SIE.set(self.0, on as usize))
+    }
+
+    pub fn with_spie(self, on: bool) -> Self {
+        Self(Self::SPIE.set(self.0, on as usize))
+    }
+
+    pub fn with_spp(self, mode: Privilege) -> Self {
+        Self(Self::SPP.set(self.0, (mode == Privilege::Supervisor) as usize))
+    }
+
+    pub fn with_sum(self, on: bool) -> Self {
+        Self(Self::SUM.set(self.0, on as usize))
+    }
+
+    pub fn with_fs(self, state: FpState) -> Self {
+        Self(Self::FS.set(self.0, state as usize))
+    }
+
+    /// Enables interrupts.
+    pub fn set_sie() {
+        Self::set_in_place(Self::SIE);
+    }
+
+    /// Disables interrupts.
+    pub fn clear_sie() {
+        Self::clear_in_place(Self::SIE);
+    }
+
+    pub fn set_sum() {
+        Self::set_in_place(Self::SUM);
+    }
+
+    pub fn clear_sum() {
+        Self::clear_in_place(Self::SUM);
+    }
+}
+
+// MARK - SIE
+
+field_csr!(
+    /// Supervisor interrupt enable, a bit per interrupt cause.
+    Sie,
+    "sie"
+);
+
+impl Sie {
+    const SSIE: Field = Field::bit(1);
+    const STIE: Field = Field::bit(5);
+    const SEIE: Field = Field::bit(9);
+
+    pub fn write(self) {
+        Self::write_bits(self.0);
+    }
+
+    pub fn ssie(self) -> bool {
+        Self::SSIE.get(self.0) != 0
+    }
+
+    pub fn stie(self) -> bool {
+        Self::STIE.get(self.0) != 0
+    }
+
+    pub fn seie(self) -> bool {
+        Self::SEIE.get(self.0) != 0
+    }
+
+    pub fn with_ssie(self, on: bool) -> Self {
+        Self(Self::SSIE.set(self.0, on as usize))
+    }
+
+    pub fn with_stie(self, on: bool) -> Self {
+        Self(Self::STIE.set(self.0, on as usize))
+    }
+
+    pub fn with_seie(self, on: bool) -> Self {
+        Self(Self::SEIE.set(self.0, on as usize))
+    }
+
+    /// Enables the timer interrupt.
+    pub fn set_stie() {
+        Self::set_in_place(Self::STIE);
+    }
+
+    /// Enables external interrupts.
+    pub fn set_seie() {
+        Self::set_in_place(Self::SEIE);
+    }
+}
+
+// MARK - SCAUSE
+
+field_csr!(
+    /// Supervisor trap cause, read-only as far as the kernel is concerned.
+    Scause,
+    "scause"
+);
+
+impl Scause {
+    const INTERRUPT: Field = Field::bit(usize::BITS - 1);
+    const CODE: Field = Field::new(0, usize::BITS - 1);
+
+    /// Whether the trap is an interrupt rather than an exception.
+    pub fn is_interrupt(self) -> bool {
+        Self::INTERRUPT.get(self.0) != 0
+    }
+
+    /// The interrupt or exception number.
+    pub fn code(self) -> usize {
+        Self::CODE.get(self.0)
+    }
+}
+
+// MARK - SATP
+
+/// Paging mode, `satp.MODE`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SatpMode {
+    Bare,
+    #[cfg(target_arch = "riscv32")]
+    Sv32,
+    #[cfg(target_arch = "riscv64")]
+    Sv39,
+}
+
+field_csr!(
+    /// Supervisor address translation and protection: the paging mode and root table.
+    Satp,
+    "satp"
+);
+
+impl Satp {
+    #[cfg(target_arch = "riscv32")]
+    const MODE: Field = Field::bit(31);
+    #[cfg(target_arch = "riscv32")]
+    const ASID: Field = Field::new(22, 9);
+    #[cfg(target_arch = "riscv32")]
+    const PPN: Field = Field::new(0, 22);
+    #[cfg(target_arch = "riscv64")]
+    const MODE: Field = Field::new(60, 4);
+    #[cfg(target_arch = "riscv64")]
+    const ASID: Field = Field::new(44, 16);
+    #[cfg(target_arch = "riscv64")]
+    const PPN: Field = Field::new(0, 44);
+
+    pub fn new(mode: SatpMode, asid: usize, ppn: usize) -> Self {
+        let mode = match mode {
+            SatpMode::Bare => 0,
+            #[cfg(target_arch = "riscv32")]
+            SatpMode::Sv32 => 1,
+            #[cfg(target_arch = "riscv64")]
+            SatpMode::Sv39 => 8,
+        };
+        let bits = Self::MODE.set(0, mode);
+        let bits = Self::ASID.set(bits, asid);
+        Self(Self::PPN.set(bits, ppn))
+    }
+
+    /// Switches to the table at page number `ppn`. Stale translations are the caller's to
+    /// flush.
+    pub fn write(mode: SatpMode, asid: usize, ppn: usize) {
+        Self::write_bits(Self::new(mode, asid, ppn).0);
+    }
+
+    pub fn mode(self) -> SatpMode {
+        match Self::MODE.get(self.0) {
+            #[cfg(target_arch = "riscv32")]
+            1 => SatpMode::Sv32,
+            #[cfg(target_arch = "riscv64")]
+            8 => SatpMode::Sv39,
+            _ => SatpMode::Bare,
+        }
+    }
+
+    pub fn asid(self) -> usize {
+        Self::ASID.get(self.0)
+    }
+
+    pub fn ppn(self) -> usize {
+        Self::PPN.get(self.0)
+    }
+}
+
+// MARK - SCOUNTEREN
+
+field_csr!(
+    /// Counter enable: which counters user mode may read, a bit per counter.
+    Scounteren,
+    "scounteren"
+);
+
+impl Scounteren {
+    const CY: Field = Field::bit(0);
+    const TM: Field = Field::bit(1);
+    const IR: Field = Field::bit(2);
+
+    pub fn write(self) {
+        Self::write_bits(self.0);
+    }
+
+    /// Whether user mode may read `cycle`.
+    pub fn cy(self) -> bool {
+        Self::CY.get(self.0) != 0
+    }
+
+    /// Whether user mode may read `time`.
+    pub fn tm(self) -> bool {
+        Self::TM.get(self.0) != 0
+    }
+
+    /// Whether user mode may read `instret`.
+    pub fn ir(self) -> bool {
+        Self::IR.get(self.0) != 0
+    }
+
+    pub fn with_cy(self, on: bool) -> Self {
+        Self(Self::CY.set(self.0, on as usize))
+    }
+
+    pub fn with_tm(self, on: bool) -> Self {
+        Self(Self::TM.set(self.0, on as usize))
+    }
+
+    pub fn with_ir(self, on: bool) -> Self {
+        Self(Self::IR.set(self.0, on as usize))
+    }
+}
+
+// MARK - STVEC
+
+/// How traps find their handler, `stvec.MODE`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrapMode {
+    /// All traps enter at the base.
+    Direct = 0,
+    /// Interrupts enter at the base plus 4 times their cause, exceptions at the base.
+    Vectored = 1,
+}
+
+/// Sets where traps enter the kernel, `base` must be 4-byte aligned.
+pub fn set_stvec(base: usize, mode: TrapMode) {
+    crate::kassert!(base.is_multiple_of(4), "csr: unaligned trap vector");
+    csr_write!("stvec", base | mode as usize);
+}
+
+/// Returns where traps enter the kernel and how.
+pub fn stvec() -> (usize, TrapMode) {
+    let bits: usize = csr_read!("stvec");
+    let mode = match bits & 0b11 {
+        0 => TrapMode::Direct,
+        _ => TrapMode::Vectored,
+    };
+    (bits & !0b11, mode)
+}
+
+// MARK - VALUES
+
+/// Returns the pc a trap was taken at.
+pub fn sepc() -> usize {
+    csr_read!("sepc")
+}
+
+/// Sets the pc `sret` returns to.
+pub fn set_sepc(pc: usize) {
+    csr_write!("sepc", pc);
+}
+
+/// Returns the address or instruction the last exception was about, 0 if none.
+pub fn stval() -> usize {
+    csr_read!("stval")
+}
+
+/// Sets the scratch register, see `trap_entry`.
+pub fn set_sscratch(value: usize) {
+    csr_write!("sscratch", value);
+}
+
+/// Returns the `time` CSR, only its low half on rv32.
+pub fn time() -> usize {
+    csr_read!("time")
+}
+
+/// Returns the high half of the `time` CSR.
+#[cfg(target_arch = "riscv32")]
+pub fn timeh() -> usize {
+    csr_read!("timeh")
+}
+
+/// Returns the `cycle` CSR, only its low half on rv32.
+pub fn cycle() -> usize {
+    csr_read!("cycle")
+}
+
+/// Returns the high half of the `cycle` CSR.
+#[cfg(target_arch = "riscv32")]
+pub fn cycleh() -> usize {
+    csr_read!("cycleh")
+}
+
+/// Returns the `instret` CSR, only its low half on rv32.
+pub fn instret() -> usize {
+    csr_read!("instret")
+}
+
+/// Returns the high half of the `instret` CSR.
+#[cfg(target_arch = "riscv32")]
+pub fn instreth() -> usize {
+    csr_read!("instreth")
+}
+
+/// Sets the `time` the calling hart's timer interrupt is pending from, on harts with the
+/// Sstc extension. Writing it clears a pending interrupt until then.
+pub fn set_stimecmp(value: u64) {
+    // By number, the assembler only knows the names with Sstc enabled.
+    #[cfg(target_arch = "riscv32")]
+    {
+        // The low half is maxed first, so no value in between is earlier than both the old
+        // and the new one.
+        csr_write!("0x14d", usize::MAX);
+        csr_write!("0x15d", (value >> 32) as usize);
+        csr_write!("0x14d", value as usize);
+    }
+    #[cfg(target_arch = "riscv64")]
+    csr_write!("0x14d", value as usize);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn fields_pack_and_unpack() {
+        let satp = Satp::new(SatpMode::Bare, 3, 0x1234);
+        assert_eq!(
+            (satp.mode(), satp.asid(), satp.ppn()),
+            (SatpMode::Bare, 3, 0x1234)
+        );
+
+        let sstatus = Sstatus(0).with_spp(Privilege::Supervisor).with_sum(true);
+        assert_eq!(sstatus.bits(), 1 << 8 | 1 << 18);
+        assert_eq!(sstatus.with_sum(false).spp(), Privilege::Supervisor);
+        assert_eq!(
+            sstatus.with_fs(FpState::Clean).bits(),
+            sstatus.bits() | 2 << 13
+        );
+        assert_eq!(sstatus.with_fs(FpState::Dirty).fs(), FpState::Dirty);
+        assert!(Sie(0).with_stie(true).stie());
+        assert_eq!(Field::new(4, 4).set(0xff, 0x3), 0x3f);
+
+        let sum = Sstatus::read().sum();
+        Sstatus::set_sum();
+        assert!(Sstatus::read().sum());
+        Sstatus::read().with_sum(sum).write();
+        assert_eq!(Sstatus::read().sum(), sum);
+    }
+}
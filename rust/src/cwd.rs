@@ -0,0 +1,151 @@
+use crate::{
+    cred::MAY_EXEC,
+    proc::{self, PROC_MAX},
+    sync::Mutex,
+    syscall::Errno,
+    vfs::{self, PATH_MAX},
+};
+
+// Working directories: the directory each process resolves relative paths from (see
+// `vfs::resolve()`), changed with `chdir()`. Processes start in the root, and keep the
+// working directory of the process that spawned them.
+//
+// A working directory is kept as the absolute path it was changed to, with `.` and `..`
+// taken out without looking at the filesystems, as a shell's `cd` does: `..` goes back
+// where the path came from, even through a symbolic link. Relative paths get theirs taken
+// out the same way, the path walker doesn't know `..`.
+//
+// FIXME: Renaming the directory, or one above it, leaves processes in it behind, with a
+// path that no longer resolves.
+
+#[derive(Clone, Copy)]
+struct Cwd {
+    path: [u8; PATH_MAX],
+    len: usize,
+}
+
+impl Cwd {
+    fn as_str(&self) -> &str {
+        // Only ever built from `str`s.
+        unsafe { core::str::from_utf8_unchecked(&self.path[..self.len]) }
+    }
+}
+
+const ROOT: Cwd = {
+    let mut path = [0; PATH_MAX];
+    path[0] = b'/';
+    Cwd { path, len: 1 }
+};
+
+// Working directory of each process, indexed by pid.
+static CWDS: Mutex<[Cwd; PROC_MAX]> = Mutex::new([ROOT; PROC_MAX]);
+
+/// Gives `child` the working directory of `parent`, when the former spawns the latter.
+pub fn inherit(parent: usize, child: usize) {
+    let mut cwds = CWDS.lock();
+    cwds[child] = cwds[parent];
+}
+
+/// Moves `pid` back to the root, called when it exits.
+pub fn reset(pid: usize) {
+    CWDS.lock()[pid] = ROOT;
+}
+
+/// Writes `path` taken from directory `base`, an absolute path, into `out`, with `.` and
+/// `..` taken out, returning its length.
+///
+/// Fails with `ENOENT` if `path` is empty, and with `ENAMETOOLONG` if it doesn't fit.
+fn join(base: &str, path: &str, out: &mut [u8; PATH_MAX]) -> Result<usize, Errno> {
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    let mut len = 0;
+    for name in base.split('/').chain(path.split('/')) {
+        match name {
+            "" | "." => {}
+            ".." => len = out[..len].iter().rposition(|&c| c == b'/').unwrap_or(0),
+            _ => {
+                let end = len + 1 + name.len();
+                if end > PATH_MAX {
+                    return Err(Errno::ENAMETOOLONG);
+                }
+                out[len] = b'/';
+                out[len + 1..end].copy_from_slice(name.as_bytes());
+                len = end;
+            }
+        }
+    }
+    if len == 0 {
+        out[0] = b'/';
+        len = 1;
+    }
+    Ok(len)
+}
+
+/// Returns `path` as is if it is absolute, or written into `buf` taken from the calling
+/// process's working directory if it is relative.
+///
+/// Fails with `ENOENT` if `path` is empty, and with `ENAMETOOLONG` if it doesn't fit.
+pub fn absolute<'a>(path: &'a str, buf: &'a mut [u8; PATH_MAX]) -> Result<&'a str, Errno> {
+    if path.starts_with('/') {
+        return Ok(path);
+    }
+    let cwd = CWDS.lock()[proc::current()];
+    let len = join(cwd.as_str(), path, buf)?;
+    Ok(core::str::from_utf8(&buf[..len]).unwrap())
+}
+
+/// Makes the directory at `path` the working directory of the calling process.
+///
+/// Fails with `ENOTDIR` if it isn't a directory, and with `EACCES` if the process may not
+/// search it.
+pub fn chdir(path: &str) -> Result<(), Errno> {
+    let pid = proc::current();
+    let current = CWDS.lock()[pid];
+    let base = match path.starts_with('/') {
+        true => "/",
+        false => current.as_str(),
+    };
+    let mut cwd = ROOT;
+    cwd.len = join(base, path, &mut cwd.path)?;
+    let (mount, ino) = vfs::resolve(cwd.as_str())?;
+    if !vfs::is_dir(mount, ino) {
+        return Err(Errno::ENOTDIR);
+    }
+    vfs::access(mount, ino, MAY_EXEC)?;
+    CWDS.lock()[pid] = cwd;
+    Ok(())
+}
+
+/// Stores the working directory of the calling process into `buf`, returning its length.
+///
+/// Fails with `ERANGE` if `buf` can't hold it.
+pub fn getcwd(buf: &mut [u8]) -> Result<usize, Errno> {
+    let cwd = CWDS.lock()[proc::current()];
+    let path = cwd.as_str().as_bytes();
+    buf.get_mut(..path.len())
+        .ok_or(Errno::ERANGE)?
+        .copy_from_slice(path);
+    Ok(path.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn relative_paths_start_from_the_working_directory() {
+        let mut buf = [0; PATH_MAX];
+        assert_eq!(absolute("/proc/./x", &mut buf), Ok("/proc/./x"));
+        assert_eq!(chdir("/tmp/../proc"), Ok(()));
+        assert_eq!(absolute("self/../x/.", &mut buf), Ok("/proc/x"));
+        assert_eq!(chdir(".."), Ok(()));
+        let mut cwd = [0; 1];
+        assert_eq!(getcwd(&mut cwd), Ok(1));
+        assert_eq!(&cwd, b"/");
+        assert_eq!(chdir("proc/meminfo"), Err(Errno::ENOTDIR));
+        assert_eq!(chdir("tmp"), Ok(()));
+        assert_eq!(getcwd(&mut cwd), Err(Errno::ERANGE));
+        reset(proc::current());
+    }
+}
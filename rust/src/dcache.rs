@@ -0,0 +1,308 @@
+use crate::{
+    mem::{self, OwnedRegion, PAGE_SIZE},
+    sync::Mutex,
+    syscall::Errno,
+    vfs::{FileSystem, NAME_MAX},
+};
+
+// The VFS's caches: the dentry cache, remembering the inode a name in a directory was
+// found to be, and with it the inode cache, remembering what kind of file that inode is.
+// Resolving a path walks the cached components without asking the filesystem, which for
+// `tarfs` means scanning the whole archive per component.
+//
+// Entries of open files are referenced and stay cached. Others are evicted when room is
+// needed, least recently used first, or all at once when memory runs out. They live in
+// pages allocated as the cache grows, up to `MAX_PAGES`, which the shrinker gives back
+// once emptied. Filesystems whose names come and go on their own, like `/proc`'s pids,
+// aren't cached (`FileSystem::cacheable()`), and those that rename or remove files must
+// call `forget()`.
+
+const MAX_PAGES: usize = 4;
+const PER_PAGE: usize = PAGE_SIZE / size_of::<Dentry>();
+
+#[derive(Clone, Copy)]
+struct Dentry {
+    // Mount the entry is on, `None` for a free entry.
+    mount: Option<usize>,
+    dir: usize,
+    name: [u8; NAME_MAX],
+    name_len: usize,
+    ino: usize,
+    is_dir: bool,
+    // Open files of the inode.
+    refs: usize,
+    // When the entry was last used, the least recently used one is evicted first.
+    used: usize,
+}
+
+const FREE: Dentry = Dentry {
+    mount: None,
+    dir: 0,
+    name: [0; NAME_MAX],
+    name_len: 0,
+    ino: 0,
+    is_dir: false,
+    refs: 0,
+    used: 0,
+};
+
+impl Dentry {
+    fn is(&self, mount: usize, dir: usize, name: &str) -> bool {
+        self.mount == Some(mount)
+            && self.dir == dir
+            && &self.name[..self.name_len] == name.as_bytes()
+    }
+
+    fn is_inode(&self, mount: usize, ino: usize) -> bool {
+        self.mount == Some(mount) && self.ino == ino
+    }
+}
+
+struct Cache {
+    pages: [Option<mem::Lease<Dentry>>; MAX_PAGES],
+    clock: usize,
+}
+
+impl Cache {
+    fn entries(&mut self) -> impl Iterator<Item = &mut Dentry> {
+        self.pages
+            .iter_mut()
+            .flatten()
+            .flat_map(|page| page.iter_mut())
+    }
+
+    fn tick(&mut self) -> usize {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Returns an entry to fill in: a free one, one in a new page, or the least recently
+    /// used one that isn't referenced, in that order. `None` if all are referenced.
+    fn slot(&mut self) -> Option<&mut Dentry> {
+        if !self.entries().any(|e| e.mount.is_none())
+            && let Some(page) = self.pages.iter_mut().find(|p| p.is_none())
+        {
+            // Caching is worth no more than this: a failed allocation evicts instead.
+            *page = OwnedRegion::alloc(PAGE_SIZE)
+                .ok()
+                .map(|region| region.lease(PER_PAGE, FREE));
+        }
+        self.entries()
+            .filter(|e| e.refs == 0)
+            .min_by_key(|e| (e.mount.is_some(), e.used))
+    }
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    pages: [const { None }; MAX_PAGES],
+    clock: 0,
+});
+
+/// Returns the inode of `name` in directory `dir` of `fs`, mounted at `mount`, looking it
+/// up in the filesystem only if it isn't cached.
+pub fn lookup(mount: usize, fs: &dyn FileSystem, dir: usize, name: &str) -> Result<usize, Errno> {
+    if !fs.cacheable() || name.len() > NAME_MAX {
+        return fs.lookup(dir, name);
+    }
+
+    let mut cache = CACHE.lock();
+    let clock = cache.tick();
+    if let Some(entry) = cache.entries().find(|e| e.is(mount, dir, name)) {
+        entry.used = clock;
+        return Ok(entry.ino);
+    }
+    // The filesystem may take a while, and may allocate.
+    drop(cache);
+
+    let ino = fs.lookup(dir, name)?;
+    insert(mount, fs, dir, name, ino);
+    Ok(ino)
+}
+
+/// Caches `name` in directory `dir` of `fs`, mounted at `mount`, as inode `ino`, found
+/// without a lookup, like by listing the directory.
+pub fn insert(mount: usize, fs: &dyn FileSystem, dir: usize, name: &str, ino: usize) {
+    if !fs.cacheable() || name.len() > NAME_MAX {
+        return;
+    }
+    let is_dir = fs.is_dir(ino);
+    let mut cache = CACHE.lock();
+    let clock = cache.tick();
+    // Another hart may have looked it up meanwhile.
+    if cache.entries().any(|e| e.is(mount, dir, name)) {
+        return;
+    }
+    if let Some(entry) = cache.slot() {
+        *entry = Dentry {
+            mount: Some(mount),
+            dir,
+            ino,
+            is_dir,
+            used: clock,
+            ..FREE
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        entry.name_len = name.len();
+    }
+}
+
+/// Returns whether inode `ino` of `fs`, mounted at `mount`, is a directory.
+pub fn is_dir(mount: usize, fs: &dyn FileSystem, ino: usize) -> bool {
+    let cached = CACHE
+        .lock()
+        .entries()
+        .find(|e| e.is_inode(mount, ino))
+        .map(|e| e.is_dir);
+    cached.unwrap_or_else(|| fs.is_dir(ino))
+}
+
+/// Keeps the entry of inode `ino` on `mount` cached while a file has it open, until `put()`.
+/// Inodes that aren't cached, like roots, need nothing.
+pub fn get(mount: usize, ino: usize) {
+    if let Some(entry) = CACHE.lock().entries().find(|e| e.is_inode(mount, ino)) {
+        entry.refs += 1;
+    }
+}
+
+/// Drops a reference taken by `get()`.
+pub fn put(mount: usize, ino: usize) {
+    let mut cache = CACHE.lock();
+    if let Some(entry) = cache
+        .entries()
+        .find(|e| e.is_inode(mount, ino) && e.refs > 0)
+    {
+        entry.refs -= 1;
+    }
+}
+
+/// Drops the entry of `name` in directory `dir` on `mount`, called by filesystems that
+/// rename or remove it.
+pub fn forget(mount: usize, dir: usize, name: &str) {
+    if let Some(entry) = CACHE.lock().entries().find(|e| e.is(mount, dir, name)) {
+        entry.mount = None;
+        entry.refs = 0;
+    }
+}
+
+/// Evicts every entry that isn't referenced and gives back the pages left empty, returning
+/// how many. Called by the memory allocator when it runs out.
+fn shrink() -> usize {
+    // Whoever holds it may be allocating.
+    let Some(mut cache) = CACHE.try_lock() else {
+        return 0;
+    };
+    cache
+        .entries()
+        .filter(|e| e.refs == 0)
+        .for_each(|e| e.mount = None);
+
+    let mut freed = 0;
+    for page in &mut cache.pages {
+        if page
+            .as_ref()
+            .is_some_and(|p| p.iter().all(|e| e.mount.is_none()))
+        {
+            *page = None;
+            freed += 1;
+        }
+    }
+    freed
+}
+
+crate::register_subsystem!(Subsys, "dcache", init);
+
+fn init() {
+    mem::register_shrinker(shrink);
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::vfs::DirEntry;
+
+    // Far from the real mounts.
+    const MOUNT: usize = usize::MAX;
+
+    // A directory of files named `a`, `bb`, `ccc`..., inode `n` being the one `n` letters
+    // long, counting lookups.
+    struct Letters(AtomicUsize);
+
+    impl FileSystem for Letters {
+        fn root(&self) -> usize {
+            0
+        }
+
+        fn lookup(&self, _dir: usize, name: &str) -> Result<usize, Errno> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            match name.bytes().next() {
+                Some(c) if name.bytes().all(|b| b == c) => Ok(name.len()),
+                _ => Err(Errno::ENOENT),
+            }
+        }
+
+        fn is_dir(&self, ino: usize) -> bool {
+            ino == 0
+        }
+
+        fn read(&self, _ino: usize, _offset: usize, _buf: &mut [u8]) -> Result<usize, Errno> {
+            Ok(0)
+        }
+
+        fn readdir(&self, _dir: usize, _index: usize) -> Result<Option<DirEntry>, Errno> {
+            Ok(None)
+        }
+    }
+
+    static LETTERS: Letters = Letters(AtomicUsize::new(0));
+
+    fn lookups() -> usize {
+        LETTERS.0.load(Ordering::Relaxed)
+    }
+
+    #[test_case]
+    fn lookups_are_cached() {
+        let before = lookups();
+        assert_eq!(lookup(MOUNT, &LETTERS, 0, "bb"), Ok(2));
+        assert_eq!(lookup(MOUNT, &LETTERS, 0, "bb"), Ok(2));
+        assert_eq!(lookups(), before + 1);
+        assert!(!is_dir(MOUNT, &LETTERS, 2));
+
+        // Failures aren't cached.
+        assert_eq!(lookup(MOUNT, &LETTERS, 0, "ab"), Err(Errno::ENOENT));
+        assert_eq!(lookup(MOUNT, &LETTERS, 0, "ab"), Err(Errno::ENOENT));
+        assert_eq!(lookups(), before + 3);
+
+        forget(MOUNT, 0, "bb");
+        assert_eq!(lookup(MOUNT, &LETTERS, 0, "bb"), Ok(2));
+        assert_eq!(lookups(), before + 4);
+
+        // Listed names are found without a lookup.
+        insert(MOUNT, &LETTERS, 0, "eeeee", 5);
+        assert_eq!(lookup(MOUNT, &LETTERS, 0, "eeeee"), Ok(5));
+        assert_eq!(lookups(), before + 4);
+        forget(MOUNT, 0, "eeeee");
+    }
+
+    #[test_case]
+    fn open_inodes_survive_shrinking() {
+        assert_eq!(lookup(MOUNT, &LETTERS, 0, "ccc"), Ok(3));
+        assert_eq!(lookup(MOUNT, &LETTERS, 0, "dddd"), Ok(4));
+        get(MOUNT, 3);
+        shrink();
+
+        let before = lookups();
+        assert_eq!(lookup(MOUNT, &LETTERS, 0, "ccc"), Ok(3));
+        assert_eq!(lookups(), before);
+        assert_eq!(lookup(MOUNT, &LETTERS, 0, "dddd"), Ok(4));
+        assert_eq!(lookups(), before + 1);
+
+        put(MOUNT, 3);
+        shrink();
+        assert_eq!(lookup(MOUNT, &LETTERS, 0, "ccc"), Ok(3));
+        assert_eq!(lookups(), before + 2);
+        forget(MOUNT, 0, "ccc");
+        forget(MOUNT, 0, "dddd");
+    }
+}
@@ -0,0 +1,467 @@
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    cmdline,
+    net::{self, IpConfig, Ipv4Addr, MacAddr},
+    println,
+    sync::Mutex,
+    syscall::Errno,
+    timer, udp,
+};
+
+// Dynamic Host Configuration Protocol (RFC 2131) client, configuring an interface from the
+// network's DHCP server when the kernel command line has `ip=dhcp`.
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+// Asks servers to broadcast replies, an unconfigured interface drops anything else.
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+// Fixed part of a message, up to and including the magic cookie.
+const FIXED_LEN: usize = 240;
+const MAX_MESSAGE: usize = 576;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+// How long to wait for a reply before sending again.
+const RETRANSMIT_MS: usize = 2000;
+// How long booting waits for a lease before carrying on unconfigured.
+const BOOT_TIMEOUT_MS: usize = 10_000;
+
+// Transaction ids tell our exchanges apart from other clients'.
+static NEXT_XID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    // Sent a DHCPDISCOVER, waiting for offers.
+    Selecting,
+    // Sent a DHCPREQUEST for an offer, waiting for the server to acknowledge it.
+    Requesting { offered: Ipv4Addr, server: Ipv4Addr },
+    // Configured, until the lease needs renewing.
+    Bound,
+    // Past the renewal time, asking the server to extend the lease.
+    Renewing,
+}
+
+/// A lease the server granted, with deadlines in timer ticks.
+#[derive(Debug, Clone, Copy)]
+struct Lease {
+    config: IpConfig,
+    server: Ipv4Addr,
+    renew_at: usize,
+    expires_at: usize,
+}
+
+struct Client {
+    iface: usize,
+    sock: usize,
+    state: State,
+    xid: u32,
+    // When to send the last message again.
+    retransmit_at: usize,
+    lease: Option<Lease>,
+}
+
+// FIXME: Only one interface is configured over DHCP.
+static CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+
+/// The parts of a server's reply the client looks at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Reply {
+    xid: u32,
+    msg_type: u8,
+    yiaddr: Ipv4Addr,
+    server: Ipv4Addr,
+    netmask: Ipv4Addr,
+    router: Ipv4Addr,
+    lease_secs: u32,
+    renewal_secs: Option<u32>,
+}
+
+/// Writes a client message of `msg_type` to `buf`, returning its length.
+///
+/// `ciaddr` is our current address when renewing, `requested` the offer being accepted.
+fn write_message(
+    buf: &mut [u8],
+    msg_type: u8,
+    xid: u32,
+    mac: MacAddr,
+    ciaddr: Ipv4Addr,
+    requested: Option<(Ipv4Addr, Ipv4Addr)>,
+) -> usize {
+    buf[..FIXED_LEN].fill(0);
+    buf[0] = OP_REQUEST;
+    buf[1] = HTYPE_ETHERNET;
+    buf[2] = 6;
+    buf[4..8].copy_from_slice(&xid.to_be_bytes());
+    buf[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    buf[12..16].copy_from_slice(&ciaddr.0);
+    buf[28..34].copy_from_slice(&mac.0);
+    buf[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+    let mut len = FIXED_LEN;
+    let mut option = |code: u8, value: &[u8]| {
+        buf[len] = code;
+        buf[len + 1] = value.len() as u8;
+        buf[len + 2..len + 2 + value.len()].copy_from_slice(value);
+        len += 2 + value.len();
+    };
+
+    option(OPT_MESSAGE_TYPE, &[msg_type]);
+    if let Some((addr, server)) = requested {
+        option(OPT_REQUESTED_IP, &addr.0);
+        option(OPT_SERVER_ID, &server.0);
+    }
+    option(
+        OPT_PARAMETER_LIST,
+        &[
+            OPT_SUBNET_MASK,
+            OPT_ROUTER,
+            OPT_LEASE_TIME,
+            OPT_RENEWAL_TIME,
+        ],
+    );
+
+    buf[len] = OPT_END;
+    len + 1
+}
+
+/// Parses a server's reply, `None` if it isn't one.
+fn parse_reply(msg: &[u8]) -> Option<Reply> {
+    if msg.len() < FIXED_LEN || msg[0] != OP_REPLY || msg[236..240] != MAGIC_COOKIE {
+        return None;
+    }
+
+    let mut reply = Reply {
+        xid: u32::from_be_bytes(msg[4..8].try_into().unwrap()),
+        msg_type: 0,
+        yiaddr: Ipv4Addr(msg[16..20].try_into().unwrap()),
+        server: Ipv4Addr::UNSPECIFIED,
+        netmask: Ipv4Addr::UNSPECIFIED,
+        router: Ipv4Addr::UNSPECIFIED,
+        lease_secs: 0,
+        renewal_secs: None,
+    };
+
+    let mut options = &msg[FIXED_LEN..];
+    while let [code, rest @ ..] = options {
+        match *code {
+            OPT_PAD => {
+                options = rest;
+                continue;
+            }
+            OPT_END => break,
+            _ => {}
+        }
+        let (&len, rest) = rest.split_first()?;
+        let (value, rest) = rest.split_at_checked(len as usize)?;
+        options = rest;
+
+        let addr = || Some(Ipv4Addr(value.get(..4)?.try_into().unwrap()));
+        let secs = || Some(u32::from_be_bytes(value.get(..4)?.try_into().unwrap()));
+        match *code {
+            OPT_MESSAGE_TYPE => reply.msg_type = *value.first()?,
+            OPT_SUBNET_MASK => reply.netmask = addr()?,
+            OPT_ROUTER => reply.router = addr()?,
+            OPT_SERVER_ID => reply.server = addr()?,
+            OPT_LEASE_TIME => reply.lease_secs = secs()?,
+            OPT_RENEWAL_TIME => reply.renewal_secs = Some(secs()?),
+            // Rebinding isn't implemented, renewal broadcasts anyway.
+            OPT_REBINDING_TIME => {}
+            _ => {}
+        }
+    }
+
+    Some(reply)
+}
+
+fn secs_to_ticks(secs: u32) -> usize {
+    (secs as usize).saturating_mul(timer::hz())
+}
+
+impl Client {
+    /// Sends the message for the current state, and schedules sending it again.
+    fn send(&mut self) {
+        let Ok(mac) = net::mac(self.iface) else {
+            return;
+        };
+        let (msg_type, ciaddr, requested) = match self.state {
+            State::Selecting => (DHCPDISCOVER, Ipv4Addr::UNSPECIFIED, None),
+            State::Requesting { offered, server } => {
+                (DHCPREQUEST, Ipv4Addr::UNSPECIFIED, Some((offered, server)))
+            }
+            State::Renewing => {
+                let addr = self.lease.map_or(Ipv4Addr::UNSPECIFIED, |l| l.config.addr);
+                (DHCPREQUEST, addr, None)
+            }
+            State::Bound => return,
+        };
+
+        let mut msg = [0; MAX_MESSAGE];
+        let len = write_message(&mut msg, msg_type, self.xid, mac, ciaddr, requested);
+        // FIXME: Renewals should be unicast to the server, but replies to broadcasts
+        // arrive just the same.
+        let _ = udp::send_broadcast(self.sock, self.iface, SERVER_PORT, &msg[..len]);
+        self.retransmit_at = timer::ticks() + timer::ms_to_ticks(RETRANSMIT_MS);
+    }
+
+    /// Starts over with a new transaction, dropping any lease.
+    fn restart(&mut self) {
+        if self.lease.take().is_some() {
+            let _ = net::set_ip_config(
+                self.iface,
+                IpConfig {
+                    addr: Ipv4Addr::UNSPECIFIED,
+                    netmask: Ipv4Addr::UNSPECIFIED,
+                    gateway: Ipv4Addr::UNSPECIFIED,
+                },
+            );
+            println!("dhcp: lease expired");
+        }
+        self.xid = new_xid(self.iface);
+        self.state = State::Selecting;
+        self.send();
+    }
+
+    fn handle(&mut self, reply: &Reply) {
+        if reply.xid != self.xid {
+            return;
+        }
+
+        match (self.state, reply.msg_type) {
+            (State::Selecting, DHCPOFFER) => {
+                // The first offer wins.
+                self.state = State::Requesting {
+                    offered: reply.yiaddr,
+                    server: reply.server,
+                };
+                self.send();
+            }
+            (State::Requesting { .. } | State::Renewing, DHCPACK) => self.bind(reply),
+            (State::Requesting { .. } | State::Renewing, DHCPNAK) => self.restart(),
+            _ => {}
+        }
+    }
+
+    /// Configures the interface with the lease in `ack`.
+    fn bind(&mut self, ack: &Reply) {
+        let now = timer::ticks();
+        // The renewal time defaults to half the lease.
+        let renewal_secs = ack.renewal_secs.unwrap_or(ack.lease_secs / 2);
+        let lease = Lease {
+            config: IpConfig {
+                addr: ack.yiaddr,
+                netmask: ack.netmask,
+                gateway: ack.router,
+            },
+            server: ack.server,
+            renew_at: now.saturating_add(secs_to_ticks(renewal_secs)),
+            expires_at: now.saturating_add(secs_to_ticks(ack.lease_secs)),
+        };
+
+        if net::set_ip_config(self.iface, lease.config).is_err() {
+            return;
+        }
+        if self.state != State::Renewing {
+            println!(
+                "dhcp: bound to {:?}/{} gw {:?} from {:?}, lease {} s",
+                lease.config.addr,
+                lease.config.netmask.to_u32().count_ones(),
+                lease.config.gateway,
+                lease.server,
+                ack.lease_secs,
+            );
+        }
+
+        self.lease = Some(lease);
+        self.state = State::Bound;
+    }
+
+    /// Retransmits, renews and expires as deadlines pass.
+    fn check_timers(&mut self) {
+        let now = timer::ticks();
+
+        match (self.state, self.lease) {
+            (State::Bound, Some(lease)) if now >= lease.renew_at => {
+                self.xid = new_xid(self.iface);
+                self.state = State::Renewing;
+                self.send();
+            }
+            (State::Renewing, Some(lease)) if now >= lease.expires_at => self.restart(),
+            (State::Bound, _) => {}
+            _ if now >= self.retransmit_at => self.send(),
+            _ => {}
+        }
+    }
+}
+
+fn new_xid(iface: usize) -> u32 {
+    // Unique enough: the low bytes of the MAC address tell clients apart, the counter our
+    // own transactions.
+    let mac = net::mac(iface).map_or([0; 6], |mac| mac.0);
+    let count = NEXT_XID.fetch_add(1, Ordering::Relaxed) as u32;
+    u32::from_be_bytes([mac[4], mac[5], 0, 0]) ^ count ^ timer::now() as u32
+}
+
+/// Starts configuring `iface` over DHCP, `poll()` carries on from there.
+pub fn start(iface: usize) -> Result<(), Errno> {
+    let mut client = CLIENT.lock();
+    if client.is_some() {
+        return Err(Errno::EEXIST);
+    }
+
+    let sock = udp::create()?;
+    if let Err(err) = udp::bind(
+        sock,
+        net::SocketAddr {
+            ip: Ipv4Addr::UNSPECIFIED,
+            port: CLIENT_PORT,
+        },
+    ) {
+        udp::close(sock);
+        return Err(err);
+    }
+
+    let c = client.insert(Client {
+        iface,
+        sock,
+        state: State::Selecting,
+        xid: new_xid(iface),
+        retransmit_at: 0,
+        lease: None,
+    });
+    c.send();
+    Ok(())
+}
+
+/// Handles the server's replies and the lease's deadlines, called by `net::poll()`.
+pub fn poll() {
+    // Sending may end up polling again, which has nothing left to do.
+    let Some(mut client) = CLIENT.try_lock() else {
+        return;
+    };
+    let Some(client) = client.as_mut() else {
+        return;
+    };
+
+    let mut msg = [0; MAX_MESSAGE];
+    while let Some((len, _)) = udp::try_recv_from(client.sock, &mut msg) {
+        if let Some(reply) = parse_reply(&msg[..len]) {
+            client.handle(&reply);
+        }
+    }
+
+    client.check_timers();
+}
+
+/// Returns whether the client holds a lease.
+pub fn is_bound() -> bool {
+    CLIENT.lock().as_ref().is_some_and(|c| c.lease.is_some())
+}
+
+crate::register_subsystem!(Late, "dhcp", init);
+
+/// Configures the first network card's interface over DHCP if the command line says `ip=dhcp`, waiting
+/// for a lease so the rest of the system starts with a working network.
+fn init() {
+    if cmdline::get("ip") != Some("dhcp") {
+        return;
+    }
+    // FIXME: Should pick the interface from `ip=`, as Linux does.
+    let Some(iface) =
+        (0..net::MAX_INTERFACES).find(|&i| net::mac(i).is_ok() && !net::is_loopback(i))
+    else {
+        println!("dhcp: no network interface");
+        return;
+    };
+    if let Err(err) = start(iface) {
+        println!("dhcp: failed to start: {err:?}");
+        return;
+    }
+
+    let deadline = timer::ticks() + timer::ms_to_ticks(BOOT_TIMEOUT_MS);
+    while !is_bound() && timer::ticks() < deadline {
+        net::poll();
+        spin_loop();
+    }
+    if !is_bound() {
+        println!("dhcp: no lease yet, carrying on");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn builds_requests_and_parses_replies() {
+        let mac = MacAddr([2, 0, 0, 0, 0, 1]);
+        let offered = Ipv4Addr([10, 0, 0, 15]);
+        let server = Ipv4Addr([10, 0, 0, 1]);
+        let mut msg = [0; MAX_MESSAGE];
+        let len = write_message(
+            &mut msg,
+            DHCPREQUEST,
+            0x1234,
+            mac,
+            Ipv4Addr::UNSPECIFIED,
+            Some((offered, server)),
+        );
+        assert_eq!(&msg[28..34], &mac.0);
+        assert_eq!(
+            &msg[FIXED_LEN..FIXED_LEN + 3],
+            &[OPT_MESSAGE_TYPE, 1, DHCPREQUEST]
+        );
+        assert_eq!(msg[len - 1], OPT_END);
+
+        // Turn it into the server's acknowledgement.
+        msg[0] = OP_REPLY;
+        msg[16..20].copy_from_slice(&offered.0);
+        msg[FIXED_LEN + 2] = DHCPACK;
+        let mut end = len - 1;
+        for option in [
+            &[OPT_PAD][..],
+            &[OPT_SUBNET_MASK, 4, 255, 255, 255, 0],
+            &[OPT_LEASE_TIME, 4, 0, 0, 0x0e, 0x10],
+            &[OPT_END],
+        ] {
+            msg[end..end + option.len()].copy_from_slice(option);
+            end += option.len();
+        }
+
+        let reply = parse_reply(&msg[..end]).unwrap();
+        assert_eq!(reply.xid, 0x1234);
+        assert_eq!(reply.msg_type, DHCPACK);
+        assert_eq!(reply.yiaddr, offered);
+        // The requested address and server id the client sent are read back as well.
+        assert_eq!(reply.server, server);
+        assert_eq!(reply.netmask, Ipv4Addr([255, 255, 255, 0]));
+        assert_eq!(reply.lease_secs, 3600);
+        assert_eq!(reply.renewal_secs, None);
+
+        assert_eq!(parse_reply(&msg[..FIXED_LEN - 1]), None);
+    }
+}
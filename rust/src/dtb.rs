@@ -0,0 +1,450 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    extable,
+    mem::{self, PAGE_SIZE},
+};
+
+// A minimal reader for the flattened device tree (FDT) the firmware passes in `a1`.
+// See the devicetree specification, chapter 5. All fields are big-endian.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// Largest blob copied into kernel memory, a larger one is read where the firmware left it.
+const COPY_MAX: usize = 64 * 1024;
+
+// Deepest nesting of nodes whose interrupt parent is tracked, deeper nodes have none.
+const MAX_DEPTH: usize = 16;
+
+/// A device node, as `for_each_compatible()` finds it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Node {
+    /// Start and end address of the first `reg` range.
+    pub start: usize,
+    pub end: usize,
+    /// What other nodes refer to it by, if any do.
+    pub phandle: Option<u32>,
+    /// The first interrupt the node raises, if any.
+    pub interrupt: Option<Interrupt>,
+}
+
+/// An interrupt a node raises: the first cell of its `interrupts` specifier, and the
+/// phandle of the interrupt controller it is a specifier of, from `interrupt-parent` on the
+/// node or the nearest node above it. Only the controller's driver knows what it means.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interrupt {
+    pub parent: u32,
+    pub cell: u32,
+}
+
+// Address of the blob handed over at boot, or of the kernel's copy, zero if there was none.
+static DTB_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+/// Copies the device tree blob at `addr` out of the RAM the firmware left it in, which
+/// isn't reserved for it, called once at boot before `mem::init()`.
+pub fn init(addr: usize) {
+    DTB_ADDR.store(addr, Ordering::Relaxed);
+    let Some(len) = total_size(addr) else {
+        return;
+    };
+    if len > COPY_MAX {
+        return;
+    }
+    let copy = mem::boot_alloc(len.div_ceil(PAGE_SIZE)).leak();
+    unsafe {
+        copy.as_mut_ptr()
+            .copy_from_nonoverlapping(addr as *const u8, len)
+    };
+    DTB_ADDR.store(copy.as_usize(), Ordering::Relaxed);
+}
+
+/// Returns the `totalsize` from the header of the blob at `addr`, `None` if there is no
+/// blob there.
+fn total_size(addr: usize) -> Option<usize> {
+    if addr == 0 {
+        return None;
+    }
+    // Whatever the firmware passed, which may not even be memory.
+    let mut header = [0; 8];
+    unsafe { extable::copy(header.as_mut_ptr(), addr as *const u8, header.len()) }.ok()?;
+    if be32(&header, 0)? != FDT_MAGIC {
+        return None;
+    }
+    Some(be32(&header, 4)? as usize)
+}
+
+/// Returns the value of property `name` of the node at `path` (e.g. `/chosen`) in the
+/// boot device tree, or `None` if there is no such property or no device tree.
+pub fn property(path: &str, name: &str) -> Option<&'static [u8]> {
+    let addr = DTB_ADDR.load(Ordering::Relaxed);
+    // The header's `totalsize` bounds the rest of the blob.
+    let len = total_size(addr)?;
+    let blob = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+
+    find_property(blob, path, name)
+}
+
+/// Calls `f` with every node with a `reg` range in the boot device tree whose `compatible`
+/// list has `compatible` (e.g. `virtio,mmio`).
+pub fn for_each_compatible(compatible: &str, mut f: impl FnMut(Node)) {
+    let addr = DTB_ADDR.load(Ordering::Relaxed);
+    let Some(len) = total_size(addr) else {
+        return;
+    };
+    let blob = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+
+    find_compatible(blob, compatible, &mut f);
+}
+
+/// Returns the start and end address of the initial ramdisk the boot loader placed in
+/// memory (QEMU's `-initrd`), `None` if there is none.
+pub fn initrd() -> Option<(usize, usize)> {
+    let address = |name| {
+        let value = property("/chosen", name)?;
+        match value.len() {
+            4 => Some(be32(value, 0)? as usize),
+            8 => Some(u64::from_be_bytes(value.try_into().unwrap()) as usize),
+            _ => None,
+        }
+    };
+    Some((address("linux,initrd-start")?, address("linux,initrd-end")?))
+}
+
+/// Returns the start and end address of the first range of RAM, `None` if there is no
+/// device tree.
+pub fn memory() -> Option<(usize, usize)> {
+    reg_range(property("/memory", "reg")?)
+}
+
+/// Reads the first `<address size>` pair of a `reg` property, with both as one or two
+/// cells, as QEMU's virt machine has them.
+fn reg_range(reg: &[u8]) -> Option<(usize, usize)> {
+    let (start, size) = match reg.len() {
+        8 => (be32(reg, 0)? as u64, be32(reg, 4)? as u64),
+        16.. => (
+            u64::from_be_bytes(reg[..8].try_into().unwrap()),
+            u64::from_be_bytes(reg[8..16].try_into().unwrap()),
+        ),
+        _ => return None,
+    };
+    let start = usize::try_from(start).ok()?;
+    Some((start, start.checked_add(usize::try_from(size).ok()?)?))
+}
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Returns the nul-terminated string at `offset`, without the nul.
+fn c_str(bytes: &[u8], offset: usize) -> Option<&[u8]> {
+    let bytes = bytes.get(offset..)?;
+    let len = bytes.iter().position(|&b| b == 0)?;
+    Some(&bytes[..len])
+}
+
+/// Returns whether a node called `node` (e.g. `uart@10000000`) is meant by path component
+/// `component`, which may leave out the unit address.
+fn node_matches(node: &[u8], component: &str) -> bool {
+    let component = component.as_bytes();
+    node == component
+        || (!component.contains(&b'@') && node.split(|&b| b == b'@').next() == Some(component))
+}
+
+/// Looks up property `name` of the node at `path` in the device tree `blob`.
+fn find_property<'a>(blob: &'a [u8], path: &str, name: &str) -> Option<&'a [u8]> {
+    if be32(blob, 0)? != FDT_MAGIC {
+        return None;
+    }
+    let structs = blob.get(be32(blob, 8)? as usize..)?;
+    let strings = blob.get(be32(blob, 12)? as usize..)?;
+
+    let components = path.split('/').filter(|c| !c.is_empty());
+    let path_depth = components.clone().count();
+
+    // Nesting depth of the current node, the root node being at depth 1, and how many of
+    // the enclosing nodes (counting the root) lie on `path`.
+    let mut depth = 0;
+    let mut matched = 0;
+    let mut offset = 0;
+
+    loop {
+        let token = be32(structs, offset)?;
+        offset += 4;
+
+        match token {
+            FDT_BEGIN_NODE => {
+                let node = c_str(structs, offset)?;
+                offset += (node.len() + 1).next_multiple_of(4);
+                depth += 1;
+
+                if matched == depth - 1 {
+                    if depth == 1 {
+                        matched = 1;
+                    } else if components
+                        .clone()
+                        .nth(depth - 2)
+                        .is_some_and(|c| node_matches(node, c))
+                    {
+                        matched = depth;
+                    }
+                }
+            }
+            FDT_END_NODE => {
+                if matched == depth {
+                    matched -= 1;
+                }
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = be32(structs, offset)? as usize;
+                let name_offset = be32(structs, offset + 4)? as usize;
+                let value = structs.get(offset + 8..offset + 8 + len)?;
+                offset += 8 + len.next_multiple_of(4);
+
+                if depth == path_depth + 1
+                    && matched == depth
+                    && c_str(strings, name_offset)? == name.as_bytes()
+                {
+                    return Some(value);
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => return None,
+            // A corrupt blob.
+            _ => return None,
+        }
+    }
+}
+
+/// Calls `f` with every node with a `reg` range of the device tree `blob` whose
+/// `compatible` list has `compatible`, in the order they appear.
+fn find_compatible(blob: &[u8], compatible: &str, f: &mut impl FnMut(Node)) -> Option<()> {
+    if be32(blob, 0)? != FDT_MAGIC {
+        return None;
+    }
+    let structs = blob.get(be32(blob, 8)? as usize..)?;
+    let strings = blob.get(be32(blob, 12)? as usize..)?;
+
+    // Of the node whose properties are being read. They come before its children, so they
+    // are all known by the next node token.
+    let mut matched = false;
+    let mut reg = None;
+    let mut phandle = None;
+    let mut interrupt = None;
+    // Interrupt parent of the nodes at each depth, inherited from the node above.
+    let mut parents = [None; MAX_DEPTH + 1];
+    let mut depth = 0;
+    let mut offset = 0;
+
+    loop {
+        let token = be32(structs, offset)?;
+        offset += 4;
+
+        if matches!(token, FDT_BEGIN_NODE | FDT_END_NODE)
+            && let Some((start, end)) = reg.take().filter(|_| matched)
+        {
+            let parent = parents.get(depth).copied().flatten();
+            f(Node {
+                start,
+                end,
+                phandle,
+                interrupt: interrupt
+                    .zip(parent)
+                    .map(|(cell, parent)| Interrupt { parent, cell }),
+            });
+        }
+        match token {
+            FDT_BEGIN_NODE => {
+                let node = c_str(structs, offset)?;
+                offset += (node.len() + 1).next_multiple_of(4);
+                depth += 1;
+                if depth <= MAX_DEPTH {
+                    parents[depth] = parents[depth - 1];
+                }
+                matched = false;
+                reg = None;
+                phandle = None;
+                interrupt = None;
+            }
+            FDT_END_NODE => {
+                matched = false;
+                depth = depth.checked_sub(1)?;
+            }
+            FDT_PROP => {
+                let len = be32(structs, offset)? as usize;
+                let name_offset = be32(structs, offset + 4)? as usize;
+                let value = structs.get(offset + 8..offset + 8 + len)?;
+                offset += 8 + len.next_multiple_of(4);
+
+                match c_str(strings, name_offset)? {
+                    b"compatible" => {
+                        matched = value.split(|&b| b == 0).any(|c| c == compatible.as_bytes());
+                    }
+                    b"reg" => reg = reg_range(value),
+                    b"phandle" => phandle = be32(value, 0),
+                    b"interrupts" => interrupt = be32(value, 0),
+                    b"interrupt-parent" => {
+                        if let Some(parent) = parents.get_mut(depth) {
+                            *parent = be32(value, 0);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => return Some(()),
+            // A corrupt blob.
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Appends `bytes` to `blob` at `*len`, padded to 4 bytes.
+    fn push(blob: &mut [u8], len: &mut usize, bytes: &[u8]) {
+        blob[*len..*len + bytes.len()].copy_from_slice(bytes);
+        *len += bytes.len().next_multiple_of(4);
+    }
+
+    #[test_case]
+    fn finds_property_by_path() {
+        let mut blob = [0_u8; 256];
+        let mut len = 40;
+
+        // Strings block first, so the structure block can refer to it.
+        let strings = len;
+        push(&mut blob, &mut len, b"bootargs\0reg\0");
+        let structs = len;
+
+        for (token, payload) in [
+            (FDT_BEGIN_NODE, &b"\0"[..]),
+            (FDT_BEGIN_NODE, b"memory@80000000\0"),
+            (FDT_PROP, &[0, 0, 0, 4, 0, 0, 0, 9, 0x80, 0, 0, 0]),
+            (FDT_END_NODE, b""),
+            (FDT_BEGIN_NODE, b"chosen\0"),
+            (
+                FDT_PROP,
+                &[0, 0, 0, 6, 0, 0, 0, 0, b'h', b'z', b'=', b'5', b'0', 0],
+            ),
+            (FDT_END_NODE, b""),
+            (FDT_END_NODE, b""),
+            (FDT_END, b""),
+        ] {
+            push(&mut blob, &mut len, &token.to_be_bytes());
+            push(&mut blob, &mut len, payload);
+        }
+
+        blob[0..4].copy_from_slice(&FDT_MAGIC.to_be_bytes());
+        blob[4..8].copy_from_slice(&(len as u32).to_be_bytes());
+        blob[8..12].copy_from_slice(&(structs as u32).to_be_bytes());
+        blob[12..16].copy_from_slice(&(strings as u32).to_be_bytes());
+
+        assert_eq!(
+            find_property(&blob, "/chosen", "bootargs"),
+            Some(&b"hz=50\0"[..])
+        );
+        assert_eq!(
+            find_property(&blob, "/memory", "reg"),
+            Some(&[0x80, 0, 0, 0][..])
+        );
+        assert_eq!(
+            find_property(&blob, "/memory@80000000", "reg"),
+            Some(&[0x80, 0, 0, 0][..])
+        );
+        assert_eq!(find_property(&blob, "/chosen", "reg"), None);
+        assert_eq!(find_property(&blob, "/", "bootargs"), None);
+    }
+
+    #[test_case]
+    fn finds_nodes_by_compatible() {
+        let mut blob = [0_u8; 512];
+        let mut len = 40;
+
+        let strings = len;
+        push(
+            &mut blob,
+            &mut len,
+            b"compatible\0reg\0interrupts\0interrupt-parent\0phandle\0",
+        );
+        let structs = len;
+
+        let compatible = |name: &[u8]| {
+            let mut prop = [0; 32];
+            prop[3] = name.len() as u8;
+            prop[8..8 + name.len()].copy_from_slice(name);
+            (prop, 8 + name.len())
+        };
+        let (virtio, virtio_len) = compatible(b"virtio,mmio\0");
+        let (uart, uart_len) = compatible(b"ns16550a\0");
+        let reg = |addr: u8| [0, 0, 0, 8, 0, 0, 0, 11, addr, 0, 0, 0, 0, 0, 0x10, 0];
+        // A one-cell property, by the offset of its name.
+        let cell = |name: u8, value: u8| [0, 0, 0, 4, 0, 0, 0, name, 0, 0, 0, value];
+        for (token, payload) in [
+            (FDT_BEGIN_NODE, &b"\0"[..]),
+            (FDT_PROP, &cell(26, 3)),
+            (FDT_BEGIN_NODE, b"virtio_mmio@10001000\0"),
+            (FDT_PROP, &reg(0x10)),
+            (FDT_PROP, &virtio[..virtio_len]),
+            (FDT_PROP, &cell(15, 8)),
+            (FDT_END_NODE, b""),
+            (FDT_BEGIN_NODE, b"uart@20000000\0"),
+            (FDT_PROP, &uart[..uart_len]),
+            (FDT_PROP, &reg(0x20)),
+            (FDT_PROP, &cell(43, 3)),
+            (FDT_END_NODE, b""),
+            (FDT_BEGIN_NODE, b"virtio_mmio@30000000\0"),
+            (FDT_PROP, &virtio[..virtio_len]),
+            (FDT_PROP, &reg(0x30)),
+            (FDT_END_NODE, b""),
+            (FDT_END_NODE, b""),
+            (FDT_END, b""),
+        ] {
+            push(&mut blob, &mut len, &token.to_be_bytes());
+            push(&mut blob, &mut len, payload);
+        }
+
+        blob[0..4].copy_from_slice(&FDT_MAGIC.to_be_bytes());
+        blob[4..8].copy_from_slice(&(len as u32).to_be_bytes());
+        blob[8..12].copy_from_slice(&(structs as u32).to_be_bytes());
+        blob[12..16].copy_from_slice(&(strings as u32).to_be_bytes());
+
+        let mut found = [None; 3];
+        let mut count = 0;
+        find_compatible(&blob, "virtio,mmio", &mut |node| {
+            found[count] = Some(node);
+            count += 1;
+        });
+        assert_eq!(count, 2);
+        let [Some(first), Some(second), _] = found else {
+            unreachable!()
+        };
+        assert_eq!((first.start, first.end), (0x1000_0000, 0x1000_1000));
+        assert_eq!((second.start, second.end), (0x3000_0000, 0x3000_1000));
+        // The interrupt parent is inherited from the root.
+        let interrupt = Interrupt { parent: 3, cell: 8 };
+        assert_eq!(first.interrupt, Some(interrupt));
+        assert_eq!(second.interrupt, None);
+
+        let mut uart = None;
+        find_compatible(&blob, "ns16550a", &mut |node| uart = Some(node));
+        assert_eq!(uart.map(|node| node.phandle), Some(Some(3)));
+    }
+
+    #[test_case]
+    fn reads_reg_ranges() {
+        let reg = [0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x08, 0, 0, 0];
+        assert_eq!(reg_range(&reg), Some((0x8000_0000, 0x8800_0000)));
+        assert_eq!(reg_range(&reg[4..12]), Some((0x8000_0000, 0)));
+        assert_eq!(reg_range(&reg[..4]), None);
+    }
+}
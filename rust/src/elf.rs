@@ -0,0 +1,416 @@
+use crate::{
+    mem::PAGE_SIZE,
+    pagecache, proc, rlimit,
+    stdlib::phalloc,
+    syscall::Errno,
+    vm::{self, PAGE_R, PAGE_U, PAGE_W, PAGE_X, PageTable},
+};
+
+// Loads ELF executables (see the System V ABI, chapter 4) into an address space. Only
+// statically linked executables for the kernel's own architecture and word size are
+// supported, like the ones the `os1k-user` crate builds.
+//
+// A program's stack is at the top of user space, below the kernel at 0x80200000. It starts
+// out with the program's arguments and environment, laid out as the System V ABI has it:
+//
+//   sp -> argc
+//         argv[0] .. argv[argc - 1], NULL
+//         envp[0] .. envp[n - 1], NULL
+//         the strings they point to, each ending with a NUL
+
+/// Top of a program's stack.
+pub const STACK_TOP: usize = 0x8000_0000;
+pub const STACK_SIZE: usize = 64 * 1024;
+/// Most bytes of arguments and environment a program is started with, counting their
+/// pointers: they all go on the top page of its stack.
+pub const ARG_MAX: usize = PAGE_SIZE;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+// Part of a writable segment that is only written while loading, made read-only after.
+const PT_GNU_RELRO: u32 = 0x6474_e552;
+
+// Segment permissions.
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+// Where the fields are in the file header and program headers, which differ in the width
+// of addresses and offsets only.
+#[cfg(target_pointer_width = "32")]
+mod layout {
+    pub const CLASS: u8 = 1;
+    pub const HEADER_LEN: usize = 52;
+    pub const E_ENTRY: usize = 24;
+    pub const E_PHOFF: usize = 28;
+    pub const E_PHENTSIZE: usize = 42;
+    pub const E_PHNUM: usize = 44;
+    pub const PHDR_LEN: usize = 32;
+    pub const P_OFFSET: usize = 4;
+    pub const P_VADDR: usize = 8;
+    pub const P_FILESZ: usize = 16;
+    pub const P_MEMSZ: usize = 20;
+    pub const P_FLAGS: usize = 24;
+}
+
+#[cfg(target_pointer_width = "64")]
+mod layout {
+    pub const CLASS: u8 = 2;
+    pub const HEADER_LEN: usize = 64;
+    pub const E_ENTRY: usize = 24;
+    pub const E_PHOFF: usize = 32;
+    pub const E_PHENTSIZE: usize = 54;
+    pub const E_PHNUM: usize = 56;
+    pub const PHDR_LEN: usize = 56;
+    pub const P_OFFSET: usize = 8;
+    pub const P_VADDR: usize = 16;
+    pub const P_FILESZ: usize = 32;
+    pub const P_MEMSZ: usize = 40;
+    pub const P_FLAGS: usize = 4;
+}
+
+use layout::*;
+
+// Program headers looked at, executables with more are rejected.
+const MAX_PHDRS: usize = 16;
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn word_at(bytes: &[u8], offset: usize) -> usize {
+    let len = size_of::<usize>();
+    usize::from_le_bytes(bytes[offset..offset + len].try_into().unwrap())
+}
+
+/// Fills `buf` from `offset` of the file, failing with `ENOEXEC` if it ends first.
+fn read_exact(
+    read: &impl Fn(usize, &mut [u8]) -> Result<usize, Errno>,
+    mut offset: usize,
+    mut buf: &mut [u8],
+) -> Result<(), Errno> {
+    while !buf.is_empty() {
+        match read(offset, buf)? {
+            0 => return Err(Errno::ENOEXEC),
+            n => {
+                offset += n;
+                buf = &mut buf[n..];
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the file header, returning the entry point and where and how many program
+/// headers there are.
+fn parse_header(header: &[u8; HEADER_LEN]) -> Result<(usize, usize, usize), Errno> {
+    if header[..4] != MAGIC
+        || header[4] != CLASS
+        || header[5] != ELFDATA2LSB
+        || u16_at(header, 16) != ET_EXEC
+        || u16_at(header, 18) != EM_RISCV
+        || u16_at(header, E_PHENTSIZE) as usize != PHDR_LEN
+    {
+        return Err(Errno::ENOEXEC);
+    }
+    let phnum = u16_at(header, E_PHNUM) as usize;
+    if phnum > MAX_PHDRS {
+        return Err(Errno::ENOEXEC);
+    }
+    Ok((word_at(header, E_ENTRY), word_at(header, E_PHOFF), phnum))
+}
+
+/// Maps a zeroed user page at `page` that `page_table` then owns, returning its frame.
+fn map_zeroed(page_table: &mut PageTable, page: usize, flags: usize) -> Result<usize, Errno> {
+    rlimit::check_rss(proc::current(), page_table.usage().resident)?;
+    let frame = phalloc(PAGE_SIZE).map_err(|_| Errno::ENOMEM)?;
+    unsafe { frame.as_mut_ptr().write_bytes(0, PAGE_SIZE) };
+    page_table.map_page(page, frame.as_usize(), flags | PAGE_U);
+    Ok(frame.as_usize())
+}
+
+/// Returns the pages `phdr` spans.
+fn pages(phdr: &[u8]) -> core::ops::Range<usize> {
+    let vaddr = word_at(phdr, P_VADDR);
+    let end = vaddr + word_at(phdr, P_MEMSZ);
+    vaddr - vaddr % PAGE_SIZE..end.next_multiple_of(PAGE_SIZE)
+}
+
+/// Returns the access the loadable segments on `page` ask for, all of them as they may
+/// share it.
+fn page_prot(phdrs: &[u8], page: usize) -> usize {
+    let mut prot = 0;
+    for phdr in phdrs.chunks_exact(PHDR_LEN) {
+        if u32_at(phdr, 0) != PT_LOAD || !pages(phdr).contains(&page) {
+            continue;
+        }
+        let flags = u32_at(phdr, P_FLAGS);
+        for (flag, page_flag) in [(PF_R, PAGE_R), (PF_W, PAGE_W), (PF_X, PAGE_X)] {
+            if flags & flag != 0 {
+                prot |= page_flag;
+            }
+        }
+    }
+    prot
+}
+
+/// Maps the loadable segments of the executable that `read` reads from (at an offset, into
+/// a buffer) into `page_table`, as user pages it then owns, and returns the entry point.
+/// Pages get the access their segments ask for, so code is read-only and a `PT_GNU_RELRO`
+/// segment is made read-only once loaded; a process writing there gets `SIGSEGV`.
+///
+/// Pages that are never written and hold a whole page of the file, at an offset `map`
+/// returns a frame of the page cache for, map that frame instead of a copy, see `mmap`.
+///
+/// Fails with `ENOEXEC` if the file isn't an executable this kernel runs, and with
+/// `ENOMEM` if there is no memory for its segments.
+pub fn load(
+    page_table: &mut PageTable,
+    read: impl Fn(usize, &mut [u8]) -> Result<usize, Errno>,
+    map: impl Fn(usize) -> Option<usize>,
+) -> Result<usize, Errno> {
+    let mut header = [0; HEADER_LEN];
+    read_exact(&read, 0, &mut header)?;
+    let (entry, phoff, phnum) = parse_header(&header)?;
+
+    let mut phdrs = [0; MAX_PHDRS * PHDR_LEN];
+    let phdrs = &mut phdrs[..phnum * PHDR_LEN];
+    read_exact(&read, phoff, phdrs)?;
+
+    for phdr in phdrs.chunks_exact(PHDR_LEN) {
+        if u32_at(phdr, 0) != PT_LOAD {
+            continue;
+        }
+        let offset = word_at(phdr, P_OFFSET);
+        let vaddr = word_at(phdr, P_VADDR);
+        let file_size = word_at(phdr, P_FILESZ);
+        let mem_size = word_at(phdr, P_MEMSZ);
+        let end = vaddr.checked_add(mem_size).ok_or(Errno::ENOEXEC)?;
+        if file_size > mem_size || vaddr < PAGE_SIZE || end > STACK_TOP - STACK_SIZE {
+            return Err(Errno::ENOEXEC);
+        }
+
+        let mut page = vaddr - vaddr % PAGE_SIZE;
+        while page < end {
+            // The part of the segment's file contents that lands in this page, the rest
+            // stays zero.
+            let start = page.max(vaddr);
+            let stop = (page + PAGE_SIZE).min(vaddr + file_size);
+
+            // Segments may share a page, the first one maps it. Pages with nothing from
+            // the file map the zero page until they are written.
+            let mapped = page_table.translate(page);
+            if start >= stop && mapped.is_none() {
+                page_table.map_zero_page(page, PAGE_R | PAGE_W);
+            }
+            let file_offset = offset + (start - vaddr);
+            let whole = start == page && stop == page + PAGE_SIZE;
+            if whole
+                && mapped.is_none()
+                && file_offset.is_multiple_of(PAGE_SIZE)
+                && page_prot(phdrs, page) & PAGE_W == 0
+                && let Some(frame) = map(file_offset)
+            {
+                page_table.map_cache_page(page, frame, PAGE_R, false);
+            } else if start < stop {
+                let frame = match mapped {
+                    // Shared with the cache, which must not see the other segment.
+                    Some(frame) if pagecache::is_cached(frame) => {
+                        page_table.copy_cache_page(page, PAGE_U | PAGE_R | PAGE_W)?
+                    }
+                    Some(frame) if !vm::is_zero_page(frame) => frame,
+                    _ => map_zeroed(page_table, page, PAGE_R | PAGE_W)?,
+                };
+                let dst = unsafe {
+                    core::slice::from_raw_parts_mut((frame + start - page) as *mut u8, stop - start)
+                };
+                read_exact(&read, file_offset, dst)?;
+            }
+            page += PAGE_SIZE;
+        }
+        page_table.set_vma(vaddr - vaddr % PAGE_SIZE, page, PAGE_R | PAGE_W)?;
+    }
+
+    // Loaded through the frames, the protections don't matter until the program runs.
+    for phdr in phdrs.chunks_exact(PHDR_LEN) {
+        if u32_at(phdr, 0) != PT_LOAD {
+            continue;
+        }
+        for page in pages(phdr).step_by(PAGE_SIZE) {
+            page_table.mprotect(page, page + PAGE_SIZE, page_prot(phdrs, page))?;
+        }
+    }
+    for phdr in phdrs.chunks_exact(PHDR_LEN) {
+        if u32_at(phdr, 0) != PT_GNU_RELRO {
+            continue;
+        }
+        // Only whole pages, the last one may hold other writable data.
+        let start = pages(phdr).start;
+        let end = word_at(phdr, P_VADDR).saturating_add(word_at(phdr, P_MEMSZ));
+        let end = end - end % PAGE_SIZE;
+        if start < end {
+            page_table.mprotect(start, end, PAGE_R)?;
+        }
+    }
+
+    Ok(entry)
+}
+
+/// Maps a stack into `page_table` with `argv` and `envp` on it, and returns the stack
+/// pointer to start the program with.
+///
+/// Fails with `E2BIG` if the strings don't fit in `ARG_MAX`, and with `ENOMEM` if there is
+/// no memory for the stack.
+pub fn map_stack(
+    page_table: &mut PageTable,
+    argv: &[&[u8]],
+    envp: &[&[u8]],
+) -> Result<usize, Errno> {
+    let word = size_of::<usize>();
+    let strings: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let pointers = (argv.len() + envp.len() + 3) * word;
+    // The calling convention keeps `sp` 16-byte aligned.
+    let sp = STACK_TOP.saturating_sub(strings + pointers) & !15;
+    if STACK_TOP - sp > ARG_MAX {
+        return Err(Errno::E2BIG);
+    }
+
+    // Everything goes on the top page, written through its frame, the rest of the stack
+    // is the zero page until it grows there.
+    let top_page = STACK_TOP - PAGE_SIZE;
+    for page in (STACK_TOP - STACK_SIZE..top_page).step_by(PAGE_SIZE) {
+        page_table.map_zero_page(page, PAGE_R | PAGE_W);
+    }
+    let top = map_zeroed(page_table, top_page, PAGE_R | PAGE_W)?;
+    page_table.set_vma(STACK_TOP - STACK_SIZE, STACK_TOP, PAGE_R | PAGE_W)?;
+    let top = unsafe { core::slice::from_raw_parts_mut(top as *mut u8, PAGE_SIZE) };
+    let mut put = |addr: usize, bytes: &[u8]| {
+        top[addr - top_page..addr - top_page + bytes.len()].copy_from_slice(bytes);
+    };
+
+    put(sp, &argv.len().to_ne_bytes());
+    let mut pointer = sp + word;
+    let mut string = sp + pointers;
+    for array in [argv, envp] {
+        for s in array {
+            put(pointer, &string.to_ne_bytes());
+            put(string, s);
+            // The NUL is already there, the page is zeroed.
+            pointer += word;
+            string += s.len() + 1;
+        }
+        // So is the NULL ending the array.
+        pointer += word;
+    }
+    Ok(sp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4] = CLASS;
+        header[5] = ELFDATA2LSB;
+        header[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        header[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        header[E_ENTRY..E_ENTRY + size_of::<usize>()]
+            .copy_from_slice(&0x1000_0000usize.to_le_bytes());
+        header[E_PHENTSIZE..E_PHENTSIZE + 2].copy_from_slice(&(PHDR_LEN as u16).to_le_bytes());
+        header
+    }
+
+    #[test_case]
+    fn accepts_only_executables_for_this_kernel() {
+        assert_eq!(parse_header(&header()), Ok((0x1000_0000, 0, 0)));
+
+        let mut other_machine = header();
+        other_machine[18] = 62; // x86-64
+        assert_eq!(parse_header(&other_machine), Err(Errno::ENOEXEC));
+
+        let mut not_elf = header();
+        not_elf[0] = b'#';
+        assert_eq!(parse_header(&not_elf), Err(Errno::ENOEXEC));
+    }
+
+    #[test_case]
+    fn loads_segments_and_zeroes_the_rest() {
+        // One segment with 4 bytes of contents right after the headers, spanning two pages.
+        let mut file = [0; HEADER_LEN + PHDR_LEN + 4];
+        file[..HEADER_LEN].copy_from_slice(&header());
+        file[E_PHOFF..E_PHOFF + size_of::<usize>()].copy_from_slice(&HEADER_LEN.to_le_bytes());
+        file[E_PHNUM] = 1;
+        let phdr = &mut file[HEADER_LEN..HEADER_LEN + PHDR_LEN];
+        phdr[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        let field = |phdr: &mut [u8], at: usize, value: usize| {
+            phdr[at..at + size_of::<usize>()].copy_from_slice(&value.to_le_bytes())
+        };
+        field(phdr, P_OFFSET, HEADER_LEN + PHDR_LEN);
+        field(phdr, P_VADDR, 0x1000_0ffe);
+        field(phdr, P_FILESZ, 4);
+        field(phdr, P_MEMSZ, 8);
+        phdr[P_FLAGS..P_FLAGS + 4].copy_from_slice(&(PF_R | PF_X).to_le_bytes());
+        file[HEADER_LEN + PHDR_LEN..].copy_from_slice(b"abcd");
+
+        let mut page_table = PageTable::new();
+        let read = |offset: usize, buf: &mut [u8]| {
+            let src = file.get(offset..).unwrap_or(&[]);
+            let n = buf.len().min(src.len());
+            buf[..n].copy_from_slice(&src[..n]);
+            Ok(n)
+        };
+        assert_eq!(load(&mut page_table, read, |_| None), Ok(0x1000_0000));
+
+        let byte = |vaddr| unsafe { *(page_table.translate(vaddr).unwrap() as *const u8) };
+        assert_eq!([byte(0x1000_0ffe), byte(0x1000_0fff)], *b"ab");
+        assert_eq!([byte(0x1000_1000), byte(0x1000_1001)], *b"cd");
+        assert_eq!(byte(0x1000_1002), 0);
+
+        // Code, so read-only once loaded.
+        let vma = page_table.vmas()[0];
+        assert_eq!((vma.start, vma.end), (0x1000_0000, 0x1000_2000));
+        assert_eq!(vma.prot, PAGE_R | PAGE_X);
+        page_table.for_each_region(|r| assert_eq!(r.flags, PAGE_R | PAGE_X | PAGE_U));
+    }
+
+    #[test_case]
+    fn passes_arguments_on_the_stack() {
+        let mut page_table = PageTable::new();
+        let sp = map_stack(&mut page_table, &[b"sh", b"-c"], &[b"A=1"]).unwrap();
+        assert_eq!(sp % 16, 0);
+        assert!(page_table.translate(STACK_TOP - STACK_SIZE).is_some());
+        // Only the top page, with the arguments, has a frame of its own.
+        assert_eq!(page_table.usage().resident, 1);
+
+        let word_at =
+            |vaddr: usize| unsafe { *(page_table.translate(vaddr).unwrap() as *const usize) };
+        let string_at = |vaddr: usize| unsafe {
+            let s = page_table.translate(vaddr).unwrap() as *const u8;
+            let len = (0..).find(|&i| *s.add(i) == 0).unwrap();
+            core::slice::from_raw_parts(s, len)
+        };
+        let word = size_of::<usize>();
+        assert_eq!(word_at(sp), 2);
+        assert_eq!(string_at(word_at(sp + word)), b"sh");
+        assert_eq!(string_at(word_at(sp + 2 * word)), b"-c");
+        assert_eq!(word_at(sp + 3 * word), 0);
+        assert_eq!(string_at(word_at(sp + 4 * word)), b"A=1");
+        assert_eq!(word_at(sp + 5 * word), 0);
+
+        let too_long = [b'x'; ARG_MAX];
+        let mut page_table = PageTable::new();
+        assert_eq!(
+            map_stack(&mut page_table, &[&too_long], &[]),
+            Err(Errno::E2BIG)
+        );
+    }
+}
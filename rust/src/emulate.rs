@@ -0,0 +1,270 @@
+use core::ptr;
+
+use crate::{
+    sync::Mutex,
+    timer,
+    trap::TrapFrame,
+    uaccess::{self, Access, UserAccess},
+};
+
+// Emulation of instructions the hardware doesn't implement, run from the illegal
+// instruction trap. An emulator claims the instructions matching its mask and value,
+// performs them on the trapped registers, and execution resumes after the instruction.
+
+const MAX_EMULATORS: usize = 8;
+
+const OPCODE_SYSTEM: u32 = 0x73;
+const OPCODE_OP: u32 = 0x33;
+const FUNCT7_MULDIV: u32 = 1;
+
+const CSR_TIME: u32 = 0xc01;
+#[cfg(target_arch = "riscv32")]
+const CSR_TIMEH: u32 = 0xc81;
+
+/// Performs `insn` on `tf`, returning `false` if it turns out not to handle it after all.
+pub type EmulateFn = fn(tf: &mut TrapFrame, insn: u32) -> bool;
+
+#[derive(Clone, Copy)]
+struct Emulator {
+    name: &'static str,
+    mask: u32,
+    value: u32,
+    emulate: EmulateFn,
+}
+
+static EMULATORS: Mutex<[Option<Emulator>; MAX_EMULATORS]> = Mutex::new([None; MAX_EMULATORS]);
+
+/// Registers `emulate` for the instructions whose bits under `mask` equal `value`.
+///
+/// # Panics
+///
+/// This function panics if all emulator slots are in use.
+pub fn register(name: &'static str, mask: u32, value: u32, emulate: EmulateFn) {
+    let mut emulators = EMULATORS.lock();
+    let slot = emulators
+        .iter_mut()
+        .find(|e| e.is_none())
+        .expect("no free emulator slots.");
+
+    *slot = Some(Emulator {
+        name,
+        mask,
+        value,
+        emulate,
+    });
+}
+
+/// Returns the instruction at `pc` that trapped, taken from `stval` if the hardware put it there.
+pub fn fetch(pc: usize, stval: usize) -> u32 {
+    if stval != 0 {
+        return stval as u32;
+    }
+
+    // A user instruction has to be fetched from the process's memory.
+    let _user = UserAccess::new();
+    if uaccess::is_user(pc) && uaccess::check(pc, 2, Access::Exec).is_err() {
+        return 0;
+    }
+    // Instructions are only 2-byte aligned with the C extension.
+    let low = unsafe { ptr::read_volatile(pc as *const u16) } as u32;
+    if low & 0b11 != 0b11 {
+        return low;
+    }
+    if uaccess::is_user(pc) && uaccess::check(pc + 2, 2, Access::Exec).is_err() {
+        return 0;
+    }
+    low | (unsafe { ptr::read_volatile((pc + 2) as *const u16) } as u32) << 16
+}
+
+/// Returns the length in bytes of `insn`, 2 for compressed instructions.
+pub fn insn_len(insn: u32) -> usize {
+    if insn & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+/// Handles an illegal instruction exception at `pc`.
+///
+/// Returns the pc to resume at if an emulator performed the instruction.
+pub fn on_illegal_insn(tf: &mut TrapFrame, pc: usize, stval: usize) -> Option<usize> {
+    let insn = fetch(pc, stval);
+
+    // Emulators only register at boot, so the lock is never held by the trapped context.
+    let emulators = *EMULATORS.lock();
+    let emulator = emulators
+        .iter()
+        .flatten()
+        .find(|e| insn & e.mask == e.value && (e.emulate)(tf, insn))?;
+    crate::trace!("emulate: {} 0x{insn:08x} at 0x{pc:x}", emulator.name);
+
+    Some(pc + insn_len(insn))
+}
+
+pub fn rd(insn: u32) -> usize {
+    (insn >> 7 & 0x1f) as usize
+}
+
+pub fn rs1(insn: u32) -> usize {
+    (insn >> 15 & 0x1f) as usize
+}
+
+pub fn rs2(insn: u32) -> usize {
+    (insn >> 20 & 0x1f) as usize
+}
+
+pub fn funct3(insn: u32) -> u32 {
+    insn >> 12 & 0x7
+}
+
+// MARK: - rdtime
+
+/// Emulates `rdtime` (and `rdtimeh` on rv32), i.e. `csrrs rd, time, x0`, for user code
+/// on platforms where `scounteren` keeps it from reading the counter itself.
+fn emulate_rdtime(tf: &mut TrapFrame, insn: u32) -> bool {
+    let value = match insn >> 20 {
+        CSR_TIME => timer::now() as usize,
+        #[cfg(target_arch = "riscv32")]
+        CSR_TIMEH => (timer::now() >> 32) as usize,
+        _ => return false,
+    };
+    tf.set_reg(rd(insn), value);
+    true
+}
+
+// MARK: - M extension
+
+// The arithmetic below uses only shifts and adds, so it works on cores without the
+// M extension as long as the kernel itself is built without it.
+
+/// Returns the low and high halves of the full product of `a` and `b`.
+fn mul_wide(a: usize, b: usize) -> (usize, usize) {
+    let (mut low, mut high) = (0_usize, 0_usize);
+
+    for i in 0..usize::BITS {
+        if b >> i & 1 != 0 {
+            let (sum, carry) = low.overflowing_add(a << i);
+            let shifted_out = if i == 0 { 0 } else { a >> (usize::BITS - i) };
+            low = sum;
+            high = high.wrapping_add(shifted_out).wrapping_add(carry as usize);
+        }
+    }
+
+    (low, high)
+}
+
+/// Returns the quotient and remainder of `n / d`, `d` must not be zero.
+fn div_rem(n: usize, d: usize) -> (usize, usize) {
+    let (mut quotient, mut remainder) = (0_usize, 0_usize);
+
+    for i in (0..usize::BITS).rev() {
+        let carry = remainder >> (usize::BITS - 1);
+        remainder = remainder << 1 | (n >> i & 1);
+        if carry != 0 || remainder >= d {
+            remainder = remainder.wrapping_sub(d);
+            quotient |= 1 << i;
+        }
+    }
+
+    (quotient, remainder)
+}
+
+fn is_negative(x: usize) -> bool {
+    (x as isize) < 0
+}
+
+fn negate_if(x: usize, negate: bool) -> usize {
+    if negate { x.wrapping_neg() } else { x }
+}
+
+/// Returns the result of M extension operation `funct3` on `a` and `b`, with the
+/// results the spec gives for division by zero and overflow.
+fn muldiv(funct3: u32, a: usize, b: usize) -> usize {
+    let (a_neg, b_neg) = (is_negative(a), is_negative(b));
+    let (a_abs, b_abs) = (negate_if(a, a_neg), negate_if(b, b_neg));
+
+    match funct3 {
+        // mul
+        0 => mul_wide(a, b).0,
+        // mulh, corrected from the unsigned product for negative operands
+        1 => mul_wide(a, b)
+            .1
+            .wrapping_sub(if a_neg { b } else { 0 })
+            .wrapping_sub(if b_neg { a } else { 0 }),
+        // mulhsu
+        2 => mul_wide(a, b).1.wrapping_sub(if a_neg { b } else { 0 }),
+        // mulhu
+        3 => mul_wide(a, b).1,
+        // div, the overflowing `MIN / -1` wraps to `MIN` by itself
+        4 if b == 0 => usize::MAX,
+        4 => negate_if(div_rem(a_abs, b_abs).0, a_neg != b_neg),
+        // divu
+        5 if b == 0 => usize::MAX,
+        5 => div_rem(a, b).0,
+        // rem, takes the sign of the dividend
+        6 if b == 0 => a,
+        6 => negate_if(div_rem(a_abs, b_abs).1, a_neg),
+        // remu
+        7 if b == 0 => a,
+        _ => div_rem(a, b).1,
+    }
+}
+
+/// Emulates the M extension's multiplication and division instructions.
+///
+/// FIXME: rv64's `*w` variants aren't emulated.
+fn emulate_muldiv(tf: &mut TrapFrame, insn: u32) -> bool {
+    let result = muldiv(funct3(insn), tf.reg(rs1(insn)), tf.reg(rs2(insn)));
+    tf.set_reg(rd(insn), result);
+    true
+}
+
+crate::register_subsystem!(Arch, "emulate", init);
+
+fn init() {
+    register(
+        "rdtime",
+        0x000f_f07f,
+        (0b010 << 12) | OPCODE_SYSTEM,
+        emulate_rdtime,
+    );
+    register(
+        "muldiv",
+        0xfe00_007f,
+        (FUNCT7_MULDIV << 25) | OPCODE_OP,
+        emulate_muldiv,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `op rd, rs1, rs2` of the M extension.
+    fn muldiv_insn(funct3: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+        (FUNCT7_MULDIV << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | OPCODE_OP
+    }
+
+    #[test_case]
+    fn emulates_mul_and_div() {
+        let mut tf = TrapFrame::zeroed();
+        let cases: [(u32, isize, isize, isize); 8] = [
+            (0, -7, 6, -42),
+            (1, -1, 1, -1),
+            (3, -1, 2, 1),
+            (4, -7, 2, -3),
+            (4, isize::MIN, -1, isize::MIN),
+            (5, 7, 0, -1),
+            (6, -7, 2, -1),
+            (7, 7, 0, 7),
+        ];
+
+        for (funct3, a, b, expected) in cases {
+            tf.set_reg(11, a as usize);
+            tf.set_reg(12, b as usize);
+            let insn = muldiv_insn(funct3, 10, 11, 12);
+            assert_eq!(
+                on_illegal_insn(&mut tf, 0x1000, insn as usize),
+                Some(0x1004)
+            );
+            assert_eq!(tf.reg(10) as isize, expected, "funct3 {funct3}");
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{
+    arp, ipv4,
+    net::{self, MAX_FRAME, MacAddr},
+    syscall::Errno,
+    trace,
+};
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub const HEADER_LEN: usize = 14;
+
+/// The header of an Ethernet II frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EthHeader {
+    pub dst: MacAddr,
+    pub src: MacAddr,
+    pub ethertype: u16,
+}
+
+/// Splits `frame` into its header and payload.
+pub fn parse(frame: &[u8]) -> Option<(EthHeader, &[u8])> {
+    let (header, payload) = frame.split_at_checked(HEADER_LEN)?;
+    Some((
+        EthHeader {
+            dst: MacAddr(header[0..6].try_into().unwrap()),
+            src: MacAddr(header[6..12].try_into().unwrap()),
+            ethertype: u16::from_be_bytes([header[12], header[13]]),
+        },
+        payload,
+    ))
+}
+
+/// Writes `header` to the start of `frame`, which must hold at least `HEADER_LEN` bytes.
+pub fn write_header(frame: &mut [u8], header: &EthHeader) {
+    frame[0..6].copy_from_slice(&header.dst.0);
+    frame[6..12].copy_from_slice(&header.src.0);
+    frame[12..14].copy_from_slice(&header.ethertype.to_be_bytes());
+}
+
+/// Sends `payload` in a frame of type `ethertype` to `dst` out of `iface`.
+pub fn send(iface: usize, dst: MacAddr, ethertype: u16, payload: &[u8]) -> Result<(), Errno> {
+    let len = HEADER_LEN + payload.len();
+    if len > MAX_FRAME {
+        return Err(Errno::EMSGSIZE);
+    }
+
+    let mut frame = [0; MAX_FRAME];
+    let header = EthHeader {
+        dst,
+        src: net::mac(iface)?,
+        ethertype,
+    };
+    write_header(&mut frame, &header);
+    frame[HEADER_LEN..len].copy_from_slice(payload);
+
+    net::transmit(iface, &frame[..len])
+}
+
+/// Handles a frame received on `iface`, passing its payload to the protocol it carries.
+pub fn receive(iface: usize, frame: &[u8]) {
+    let Some((header, payload)) = parse(frame) else {
+        return;
+    };
+
+    // Devices may deliver frames for other hosts, e.g. in promiscuous mode.
+    let Ok(mac) = net::mac(iface) else {
+        return;
+    };
+    if header.dst != mac && header.dst != MacAddr::BROADCAST {
+        return;
+    }
+
+    match header.ethertype {
+        ETHERTYPE_ARP => arp::receive(iface, payload),
+        ETHERTYPE_IPV4 => ipv4::receive(iface, header.src, payload),
+        ethertype => trace!("eth: dropped frame of type 0x{ethertype:04x}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn header_round_trips() {
+        let header = EthHeader {
+            dst: MacAddr::BROADCAST,
+            src: MacAddr([2, 0, 0, 0, 0, 1]),
+            ethertype: ETHERTYPE_ARP,
+        };
+        let mut frame = [0xaa; HEADER_LEN + 2];
+        write_header(&mut frame, &header);
+
+        assert_eq!(parse(&frame), Some((header, &[0xaa, 0xaa][..])));
+        assert_eq!(parse(&frame[..HEADER_LEN - 1]), None);
+    }
+}
@@ -0,0 +1,81 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{proc, timer};
+
+/// A flag a driver's interrupt handler sets to wake up processes waiting for it.
+///
+/// Signalling never takes a lock, so it is safe from interrupt context. The event resets
+/// automatically when a waiter consumes it.
+pub struct Event {
+    signaled: AtomicBool,
+    // Bit mask of waiting pids.
+    waiters: AtomicUsize,
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Self {
+            signaled: AtomicBool::new(false),
+            waiters: AtomicUsize::new(0),
+        }
+    }
+
+    /// Signals the event, waking up all waiting processes.
+    pub fn signal(&self) {
+        self.signaled.store(true, Ordering::Release);
+
+        let waiters = self.waiters.swap(0, Ordering::AcqRel);
+        if waiters != 0 {
+            proc::wake_deferred(waiters);
+        }
+    }
+
+    /// Clears the event without waking anyone.
+    pub fn reset(&self) {
+        self.signaled.store(false, Ordering::Release);
+    }
+
+    /// Waits until the event is signaled, for at most `timeout_ms` milliseconds if given.
+    ///
+    /// Returns `true` if the event was signaled, consuming it, or `false` on timeout.
+    pub fn wait(&self, timeout_ms: Option<usize>) -> bool {
+        let deadline = timeout_ms.map(|ms| timer::ticks() + timer::ms_to_ticks(ms));
+        let pid = proc::current();
+
+        loop {
+            if self.signaled.swap(false, Ordering::AcqRel) {
+                return true;
+            }
+            if deadline.is_some_and(|deadline| timer::ticks() >= deadline) {
+                return false;
+            }
+
+            self.waiters.fetch_or(1 << pid, Ordering::AcqRel);
+
+            // A signal between the check above and registering as a waiter would be missed.
+            if !self.signaled.load(Ordering::Acquire) {
+                proc::sleep(deadline);
+            }
+
+            self.waiters.fetch_and(!(1 << pid), Ordering::AcqRel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Event;
+
+    #[test_case]
+    fn signaled_event_is_consumed_once() {
+        let event = Event::new();
+
+        event.signal();
+        assert!(event.wait(Some(0)));
+        assert!(!event.wait(Some(0)));
+
+        event.signal();
+        event.reset();
+        assert!(!event.wait(Some(0)));
+    }
+}
@@ -0,0 +1,293 @@
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+use crate::{event::Event, sync::Mutex, syscall::Errno, timer};
+
+// A tiny executor running `async fn` state machines, e.g. driver completions and protocol
+// retransmits, as tasks of a single kernel process instead of a process each.
+//
+// Futures are stored in fixed slots, there is no heap. Waking a task sets its bit in
+// `READY` and signals `WAKEUP`, which the executor process sleeps on, so wakers may be
+// called from interrupt context.
+
+const MAX_TASKS: usize = 8;
+// Largest future a task can hold, `spawn()` doesn't compile for larger ones.
+const TASK_SIZE: usize = 512;
+
+// `u128` gives the slot the strictest alignment futures need.
+struct Slot(UnsafeCell<[u128; TASK_SIZE / size_of::<u128>()]>);
+
+// Only the executor touches the future in a slot once it is spawned.
+unsafe impl Sync for Slot {}
+
+/// Type-erased operations on the future in a slot.
+#[derive(Clone, Copy)]
+struct Task {
+    poll: unsafe fn(*mut u8, &mut Context) -> Poll<()>,
+    drop: unsafe fn(*mut u8),
+}
+
+static SLOTS: [Slot; MAX_TASKS] =
+    [const { Slot(UnsafeCell::new([0; TASK_SIZE / size_of::<u128>()])) }; MAX_TASKS];
+static TASKS: Mutex<[Option<Task>; MAX_TASKS]> = Mutex::new([None; MAX_TASKS]);
+
+// Tasks to poll, as a bit mask of slots.
+static READY: AtomicUsize = AtomicUsize::new(0);
+static WAKEUP: Event = Event::new();
+
+// Tasks waiting in `sleep_ms()`, and the earliest tick one of them wakes up at.
+static SLEEPING: AtomicUsize = AtomicUsize::new(0);
+static NEXT_DEADLINE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+unsafe fn poll_future<F: Future<Output = ()>>(ptr: *mut u8, cx: &mut Context) -> Poll<()> {
+    unsafe { Pin::new_unchecked(&mut *(ptr as *mut F)).poll(cx) }
+}
+
+unsafe fn drop_future<F>(ptr: *mut u8) {
+    unsafe { (ptr as *mut F).drop_in_place() };
+}
+
+/// Starts running `future` as a task, returning its id.
+///
+/// Fails with `ENOSPC` if all task slots are taken.
+pub fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) -> Result<usize, Errno> {
+    const {
+        assert!(
+            size_of::<F>() <= TASK_SIZE,
+            "future too large for a task slot"
+        );
+        assert!(align_of::<F>() <= align_of::<Slot>());
+    }
+
+    let mut tasks = TASKS.lock();
+    let id = tasks
+        .iter()
+        .position(|t| t.is_none())
+        .ok_or(Errno::ENOSPC)?;
+
+    unsafe { (SLOTS[id].0.get() as *mut F).write(future) };
+    tasks[id] = Some(Task {
+        poll: poll_future::<F>,
+        drop: drop_future::<F>,
+    });
+    drop(tasks);
+
+    wake_task(id);
+    Ok(id)
+}
+
+// MARK: - Wakers
+
+fn wake_task(id: usize) {
+    READY.fetch_or(1 << id, Ordering::AcqRel);
+    WAKEUP.signal();
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(
+    |data| RawWaker::new(data, &VTABLE),
+    |data| wake_task(data as usize),
+    |data| wake_task(data as usize),
+    |_| {},
+);
+
+fn waker(id: usize) -> Waker {
+    // The task id is the whole state, no reference counting needed.
+    unsafe { Waker::from_raw(RawWaker::new(id as *const (), &VTABLE)) }
+}
+
+/// Returns the id of the task `cx` belongs to.
+///
+/// # Panics
+///
+/// Panics if the future isn't polled by this executor.
+fn task_id(cx: &Context) -> usize {
+    let waker = cx.waker();
+    assert!(
+        core::ptr::eq(waker.vtable(), &VTABLE),
+        "future polled outside the executor."
+    );
+    waker.data() as usize
+}
+
+// MARK: - Running tasks
+
+/// Polls every task that was woken up once, returning how many were polled.
+pub fn poll_ready() -> usize {
+    let now = timer::ticks();
+    if now >= NEXT_DEADLINE.load(Ordering::Acquire) {
+        // Sleepers check their own deadline and go back to sleep if it isn't theirs.
+        NEXT_DEADLINE.store(usize::MAX, Ordering::Release);
+        READY.fetch_or(SLEEPING.swap(0, Ordering::AcqRel), Ordering::AcqRel);
+    }
+
+    let ready = READY.swap(0, Ordering::AcqRel);
+    for id in (0..MAX_TASKS).filter(|id| ready & (1 << id) != 0) {
+        let Some(task) = TASKS.lock()[id] else {
+            continue;
+        };
+
+        // Not holding `TASKS`, so the task can spawn others.
+        let ptr = SLOTS[id].0.get() as *mut u8;
+        let waker = waker(id);
+        let mut cx = Context::from_waker(&waker);
+        if unsafe { (task.poll)(ptr, &mut cx) }.is_ready() {
+            unsafe { (task.drop)(ptr) };
+            TASKS.lock()[id] = None;
+        }
+    }
+
+    ready.count_ones() as usize
+}
+
+/// Runs tasks forever, the body of the executor's kernel process.
+pub fn run() -> ! {
+    loop {
+        // Tasks woken until now are polled next, a wakeup left over would only spin once
+        // more.
+        WAKEUP.reset();
+        poll_ready();
+
+        if READY.load(Ordering::Acquire) == 0 {
+            let timeout = match NEXT_DEADLINE.load(Ordering::Acquire) {
+                usize::MAX => None,
+                deadline => {
+                    let ticks = deadline.saturating_sub(timer::ticks());
+                    Some(ticks * 1000 / timer::hz())
+                }
+            };
+            WAKEUP.wait(timeout);
+        }
+    }
+}
+
+// MARK: - Futures
+
+/// An event tasks can wait for, signaled e.g. by an interrupt handler.
+///
+/// Like `Event`, it resets when a waiting task consumes it, and signaling it never takes
+/// a lock.
+pub struct Notify {
+    signaled: AtomicBool,
+    // Waiting tasks, as a bit mask of slots.
+    waiters: AtomicUsize,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Self {
+            signaled: AtomicBool::new(false),
+            waiters: AtomicUsize::new(0),
+        }
+    }
+
+    /// Signals the event, waking up all waiting tasks.
+    pub fn notify(&self) {
+        self.signaled.store(true, Ordering::Release);
+
+        let waiters = self.waiters.swap(0, Ordering::AcqRel);
+        for id in (0..MAX_TASKS).filter(|id| waiters & (1 << id) != 0) {
+            wake_task(id);
+        }
+    }
+
+    /// Waits until the event is signaled, consuming it.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified(self)
+    }
+}
+
+pub struct Notified<'a>(&'a Notify);
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let notify = self.0;
+        if notify.signaled.swap(false, Ordering::AcqRel) {
+            return Poll::Ready(());
+        }
+
+        notify.waiters.fetch_or(1 << task_id(cx), Ordering::AcqRel);
+        // A signal between the check above and registering would be missed.
+        if notify.signaled.swap(false, Ordering::AcqRel) {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+pub struct Sleep {
+    deadline: usize,
+}
+
+/// Waits for at least `ms` milliseconds.
+pub fn sleep_ms(ms: usize) -> Sleep {
+    Sleep {
+        deadline: timer::ticks() + timer::ms_to_ticks(ms),
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if timer::ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        SLEEPING.fetch_or(1 << task_id(cx), Ordering::AcqRel);
+        NEXT_DEADLINE.fetch_min(self.deadline, Ordering::AcqRel);
+        Poll::Pending
+    }
+}
+
+/// Lets the other ready tasks run before continuing.
+pub async fn yield_now() {
+    let mut yielded = false;
+    core::future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static NOTIFY: Notify = Notify::new();
+    static STEPS: AtomicUsize = AtomicUsize::new(0);
+
+    async fn waits_twice() {
+        NOTIFY.notified().await;
+        STEPS.fetch_add(1, Ordering::Relaxed);
+        yield_now().await;
+        STEPS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn tasks_run_when_woken() {
+        let id = spawn(waits_twice()).unwrap();
+
+        assert_eq!(poll_ready(), 1);
+        assert_eq!(poll_ready(), 0);
+        assert_eq!(STEPS.load(Ordering::Relaxed), 0);
+
+        NOTIFY.notify();
+        poll_ready();
+        assert_eq!(STEPS.load(Ordering::Relaxed), 1);
+        // The task yielded, so it's ready right away.
+        poll_ready();
+        assert_eq!(STEPS.load(Ordering::Relaxed), 2);
+        assert!(TASKS.lock()[id].is_none());
+    }
+}
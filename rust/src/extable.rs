@@ -0,0 +1,92 @@
+use core::arch::asm;
+
+use crate::syscall::Errno;
+
+// The exception table, for kernel code that touches addresses which may fault on purpose:
+// memory a debugger asks for, a device tree pointer from the firmware. Each instruction
+// that may fault has an entry in the `__ex_table` section pairing its address with that of
+// a fixup, where the trap handler resumes instead of panicking (see `fixup()`), and the
+// code there returns an error. Only `copy()` has entries for now, anything else that may
+// fault goes through it.
+
+#[repr(C)]
+struct Entry {
+    insn: usize,
+    fixup: usize,
+}
+
+unsafe extern "C" {
+    // Bounds of the `__ex_table` section, see kernel.ld.
+    static __ex_table: Entry;
+    static __ex_table_end: Entry;
+}
+
+fn entries() -> &'static [Entry] {
+    let start = unsafe { &__ex_table } as *const Entry;
+    let end = unsafe { &__ex_table_end } as *const Entry;
+    unsafe { core::slice::from_raw_parts(start, end.offset_from(start) as usize) }
+}
+
+/// Returns where to resume after the kernel faulted at `pc`, `None` if the fault wasn't
+/// expected there.
+pub fn fixup(pc: usize) -> Option<usize> {
+    entries().iter().find(|e| e.insn == pc).map(|e| e.fixup)
+}
+
+/// Copies `len` bytes from `src` to `dst` a byte at a time, failing with `EFAULT` if
+/// either faults, with what was copied until then left in place.
+///
+/// # Safety
+///
+/// Whatever `dst` points to may be overwritten: it mustn't be memory the kernel relies on.
+pub unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), Errno> {
+    let left: usize;
+    unsafe {
+        asm!(
+            "beqz {len}, 3f",
+            "1: lbu {byte}, 0({src})",
+            "2: sb {byte}, 0({dst})",
+            "addi {src}, {src}, 1",
+            "addi {dst}, {dst}, 1",
+            "addi {len}, {len}, -1",
+            "bnez {len}, 1b",
+            // A fault at 1 or 2 lands here with bytes left.
+            "3:",
+            ".pushsection __ex_table, \"a\"",
+            concat!(".balign ", crate::reg_bytes!()),
+            concat!(crate::reg_bytes!(ptr), " 1b, 3b"),
+            concat!(crate::reg_bytes!(ptr), " 2b, 3b"),
+            ".popsection",
+            len = inout(reg) len => left,
+            src = inout(reg) src => _,
+            dst = inout(reg) dst => _,
+            byte = out(reg) _,
+            options(nostack),
+        );
+    }
+    match left {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn faults_in_copy_return_an_error() {
+        let src = [1_u8, 2, 3, 4];
+        let mut dst = [0; 4];
+        assert_eq!(unsafe { copy(dst.as_mut_ptr(), src.as_ptr(), 4) }, Ok(()));
+        assert_eq!(dst, src);
+
+        // Nothing is at the top of the address space, paging or not.
+        let bad = (usize::MAX - 3) as *const u8;
+        assert_eq!(
+            unsafe { copy(dst.as_mut_ptr(), bad, 4) },
+            Err(Errno::EFAULT)
+        );
+        assert_eq!(fixup(0), None);
+    }
+}
@@ -0,0 +1,234 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{cmdline, println, sync::Mutex, syscall::Errno, timer};
+
+// Fault injection, for testing how call sites of `faultpoint!` cope with failures. Faults
+// are injected by tests, or at boot with `fault=<spec,...>` on the command line, each spec
+// being one of `<name>:fail:<skip>:<count>`, `<name>:every:<n>` or `<name>:delay:<us>`.
+
+const MAX_FAULTS: usize = 8;
+
+/// How a fault point misbehaves once a fault is injected into it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Lets the first `skip` hits through, then fails the following `count` hits.
+    Fail { skip: usize, count: usize },
+    /// Fails every `n`th hit.
+    FailEveryNth(usize),
+    /// Busy-waits `us` microseconds on every hit without failing.
+    Delay { us: usize },
+}
+
+struct FaultPoint {
+    name: &'static str,
+    fault: Fault,
+    hits: usize,
+    failed: usize,
+}
+
+static FAULTS: Mutex<[Option<FaultPoint>; MAX_FAULTS]> = Mutex::new([const { None }; MAX_FAULTS]);
+
+// Number of injected faults, so fault points stay a single atomic load while none are configured.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Injects `fault` into the fault point `name`, replacing any fault already injected there.
+///
+/// Fails with `ENOSPC` if faults are already injected into `MAX_FAULTS` other points.
+pub fn inject(name: &'static str, fault: Fault) -> Result<(), Errno> {
+    let mut faults = FAULTS.lock();
+
+    let slot = match faults
+        .iter()
+        .position(|f| f.as_ref().is_some_and(|f| f.name == name))
+    {
+        Some(slot) => slot,
+        None => {
+            let slot = faults
+                .iter()
+                .position(|f| f.is_none())
+                .ok_or(Errno::ENOSPC)?;
+            ACTIVE.fetch_add(1, Ordering::Relaxed);
+            slot
+        }
+    };
+
+    faults[slot] = Some(FaultPoint {
+        name,
+        fault,
+        hits: 0,
+        failed: 0,
+    });
+    Ok(())
+}
+
+/// Removes the fault injected into `name`, returning how many times it made the call site fail.
+///
+/// Faults injected from the command line stay for as long as the kernel runs.
+#[cfg(test)]
+pub fn clear(name: &str) -> usize {
+    let mut faults = FAULTS.lock();
+
+    for slot in faults.iter_mut() {
+        if slot.as_ref().is_some_and(|f| f.name == name) {
+            ACTIVE.fetch_sub(1, Ordering::Relaxed);
+            return slot.take().map_or(0, |f| f.failed);
+        }
+    }
+
+    0
+}
+
+/// Called by `faultpoint!`, returns `true` if the hit should fail.
+///
+/// Safe to call from interrupt context: if the fault table is locked, the hit passes.
+pub fn hit(name: &str) -> bool {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+
+    let Some(mut faults) = FAULTS.try_lock() else {
+        return false;
+    };
+
+    let Some(point) = faults.iter_mut().flatten().find(|f| f.name == name) else {
+        return false;
+    };
+
+    point.hits += 1;
+
+    let fail = match point.fault {
+        Fault::Fail { skip, count } => point.hits > skip && point.hits <= skip + count,
+        Fault::FailEveryNth(n) => n != 0 && point.hits % n == 0,
+        Fault::Delay { us } => {
+            drop(faults);
+            delay(us);
+            return false;
+        }
+    };
+
+    if fail {
+        point.failed += 1;
+    }
+
+    fail
+}
+
+/// Parses a fault spec of the command line, see the top of this file.
+fn parse(spec: &'static str) -> Option<(&'static str, Fault)> {
+    let mut fields = spec.split(':');
+    let name = fields.next().filter(|name| !name.is_empty())?;
+    let kind = fields.next()?;
+    let mut arg = || fields.next()?.parse().ok();
+    let fault = match kind {
+        "fail" => Fault::Fail {
+            skip: arg()?,
+            count: arg()?,
+        },
+        "every" => Fault::FailEveryNth(arg()?),
+        "delay" => Fault::Delay { us: arg()? },
+        _ => return None,
+    };
+    match fields.next() {
+        Some(_) => None,
+        None => Some((name, fault)),
+    }
+}
+
+crate::register_subsystem!(Core, "fault", init, after("cmdline"));
+
+fn init() {
+    let Some(list) = cmdline::get("fault") else {
+        return;
+    };
+
+    for spec in list.split(',') {
+        match parse(spec) {
+            Some((name, fault)) => {
+                if inject(name, fault).is_err() {
+                    println!("fault: too many faults");
+                    return;
+                }
+            }
+            None => println!("fault: bad fault {spec}"),
+        }
+    }
+}
+
+fn delay(us: usize) {
+    let deadline = timer::now() + (us as u64 * timer::TIMEBASE_FREQ) / 1_000_000;
+    while timer::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fault, MAX_FAULTS, clear, inject, parse};
+    use crate::syscall::Errno;
+    use crate::{mem::Error, stdlib::phalloc};
+
+    #[test_case]
+    fn injected_allocation_failure() {
+        inject("buddy-alloc", Fault::Fail { skip: 1, count: 1 }).unwrap();
+
+        let first = phalloc(1);
+        let second = phalloc(1);
+        let third = phalloc(1);
+
+        assert_eq!(clear("buddy-alloc"), 1);
+        assert!(first.is_ok());
+        assert!(matches!(second, Err(Error::OutOfMemory)));
+        assert!(third.is_ok());
+
+        crate::stdlib::phree(first.unwrap());
+        crate::stdlib::phree(third.unwrap());
+    }
+
+    #[test_case]
+    fn every_nth_fault() {
+        inject("test-point", Fault::FailEveryNth(3)).unwrap();
+        let fails = (0..9).filter(|_| crate::faultpoint!("test-point")).count();
+        assert_eq!(clear("test-point"), 3);
+        assert_eq!(fails, 3);
+        assert!(!crate::faultpoint!("test-point"));
+    }
+
+    #[test_case]
+    fn fault_slots_run_out() {
+        const NAMES: [&str; MAX_FAULTS + 1] =
+            ["f0", "f1", "f2", "f3", "f4", "f5", "f6", "f7", "f8"];
+        for name in &NAMES[..MAX_FAULTS] {
+            inject(name, Fault::FailEveryNth(1)).unwrap();
+        }
+        assert_eq!(
+            inject(NAMES[MAX_FAULTS], Fault::FailEveryNth(1)),
+            Err(Errno::ENOSPC)
+        );
+        // Replacing one doesn't take another slot.
+        assert_eq!(inject(NAMES[0], Fault::FailEveryNth(2)), Ok(()));
+        for name in &NAMES[..MAX_FAULTS] {
+            clear(name);
+        }
+        assert!(!crate::faultpoint!("f0"));
+    }
+
+    #[test_case]
+    fn parses_command_line_faults() {
+        assert_eq!(
+            parse("buddy-alloc:fail:2:1"),
+            Some(("buddy-alloc", Fault::Fail { skip: 2, count: 1 }))
+        );
+        assert_eq!(
+            parse("virtio-tx:every:4"),
+            Some(("virtio-tx", Fault::FailEveryNth(4)))
+        );
+        assert_eq!(
+            parse("block-read:delay:500"),
+            Some(("block-read", Fault::Delay { us: 500 }))
+        );
+        assert_eq!(parse("buddy-alloc:fail:2"), None);
+        assert_eq!(parse("buddy-alloc:every:4:1"), None);
+        assert_eq!(parse(":every:4"), None);
+        assert_eq!(parse("buddy-alloc:crash:1"), None);
+    }
+}
@@ -0,0 +1,362 @@
+use core::sync::atomic::{Ordering, fence};
+
+use crate::{
+    dtb, font,
+    mem::{self, RegionKind},
+    println,
+    stdlib::phalloc,
+    sync::Mutex,
+};
+
+// A text console on a framebuffer, for machines with a display but no serial line: what
+// is printed, kernel messages and program output alike, is also drawn there in an 8x8
+// font, scrolling up once the screen is full.
+//
+// The framebuffer is the one the firmware describes in the device tree (a `simple-
+// framebuffer` node under `/chosen`), or else one this sets up in RAM for QEMU's `ramfb`
+// device (`-device ramfb`), which QEMU's fw_cfg interface is told about.
+//
+// Drawing happens with any lock held, from interrupt handlers too, so output is dropped
+// while the framebuffer is being drawn to by someone else on the hart.
+
+// Size of the framebuffer set up for `ramfb`.
+const RAMFB_WIDTH: usize = 640;
+const RAMFB_HEIGHT: usize = 480;
+
+const FOREGROUND: u32 = 0x00aa_aaaa;
+const FOREGROUND_565: u16 = 0xad55;
+
+struct Fbcon {
+    base: usize,
+    width: usize,
+    height: usize,
+    stride: usize,
+    // Bytes per pixel, 4 for `x8r8g8b8` or 2 for `r5g6b5`.
+    bpp: usize,
+    // Where the next character goes, in characters.
+    col: usize,
+    row: usize,
+}
+
+static FBCON: Mutex<Option<Fbcon>> = Mutex::new(None);
+
+impl Fbcon {
+    fn cols(&self) -> usize {
+        self.width / font::WIDTH
+    }
+
+    fn rows(&self) -> usize {
+        self.height / font::HEIGHT
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
+        let addr = self.base + y * self.stride + x * self.bpp;
+        unsafe {
+            match self.bpp {
+                2 => (addr as *mut u16).write_volatile(if on { FOREGROUND_565 } else { 0 }),
+                _ => (addr as *mut u32).write_volatile(if on { FOREGROUND } else { 0 }),
+            }
+        }
+    }
+
+    fn draw(&mut self, c: u8) {
+        let (x, y) = (self.col * font::WIDTH, self.row * font::HEIGHT);
+        for (dy, bits) in font::glyph(c).iter().enumerate() {
+            for dx in 0..font::WIDTH {
+                self.set_pixel(x + dx, y + dy, bits & (1 << dx) != 0);
+            }
+        }
+    }
+
+    /// Moves everything up a line, blanking the last one.
+    fn scroll(&mut self) {
+        let line = font::HEIGHT * self.stride;
+        let screen = self.rows() * line;
+        unsafe {
+            core::ptr::copy(
+                (self.base + line) as *const u8,
+                self.base as *mut u8,
+                screen - line,
+            );
+            ((self.base + screen - line) as *mut u8).write_bytes(0, line);
+        }
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows() {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    fn put(&mut self, c: u8) {
+        match c {
+            b'\n' => self.newline(),
+            b'\r' => self.col = 0,
+            b'\t' => {
+                for _ in 0..8 - self.col % 8 {
+                    self.put(b' ');
+                }
+            }
+            0x08 => self.col = self.col.saturating_sub(1),
+            // Other control characters, and escape sequences, are not drawn.
+            ..b' ' | 0x7f.. => {}
+            _ => {
+                if self.col == self.cols() {
+                    self.newline();
+                }
+                self.draw(c);
+                self.col += 1;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        unsafe { (self.base as *mut u8).write_bytes(0, self.height * self.stride) };
+        self.col = 0;
+        self.row = 0;
+    }
+}
+
+/// Draws `buf` on the framebuffer console, if there is one.
+pub fn write(buf: &[u8]) {
+    let Some(mut fbcon) = FBCON.try_lock() else {
+        return;
+    };
+    if let Some(fbcon) = fbcon.as_mut() {
+        for &c in buf {
+            fbcon.put(c);
+        }
+    }
+}
+
+/// Returns the framebuffer `/chosen/framebuffer` describes, as its address, width, height,
+/// stride and bytes per pixel.
+fn simple_framebuffer() -> Option<(usize, usize, usize, usize, usize)> {
+    let u32_property = |name| {
+        let value = dtb::property("/chosen/framebuffer", name)?;
+        Some(u32::from_be_bytes(value.get(..4)?.try_into().unwrap()) as usize)
+    };
+    let reg = dtb::property("/chosen/framebuffer", "reg")?;
+    let base = match reg.len() {
+        8 => u32::from_be_bytes(reg[..4].try_into().unwrap()) as u64,
+        16.. => u64::from_be_bytes(reg[..8].try_into().unwrap()),
+        _ => return None,
+    };
+    let bpp = match dtb::property("/chosen/framebuffer", "format")? {
+        b"a8r8g8b8\0" | b"x8r8g8b8\0" => 4,
+        b"r5g6b5\0" => 2,
+        _ => return None,
+    };
+    let (width, height) = (u32_property("width")?, u32_property("height")?);
+    let stride = u32_property("stride")?;
+    Some((usize::try_from(base).ok()?, width, height, stride, bpp))
+}
+
+// MARK - RAMFB
+
+// fw_cfg registers, as offsets from the base.
+const FW_CFG_DATA: usize = 0x0;
+const FW_CFG_SELECTOR: usize = 0x8;
+const FW_CFG_DMA: usize = 0x10;
+
+const FW_CFG_SIGNATURE: u16 = 0x0;
+const FW_CFG_ID: u16 = 0x1;
+const FW_CFG_FILE_DIR: u16 = 0x19;
+const FW_CFG_ID_DMA: u32 = 1 << 1;
+
+// DMA control bits.
+const DMA_ERROR: u32 = 1 << 0;
+const DMA_SELECT: u32 = 1 << 3;
+const DMA_WRITE: u32 = 1 << 4;
+
+/// `DRM_FORMAT_XRGB8888`, "XR24".
+const FOURCC_XRGB8888: u32 = 0x3432_5258;
+
+/// A fw_cfg DMA request, all big-endian.
+#[repr(C)]
+struct DmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+/// What `etc/ramfb` is set to, all big-endian.
+#[repr(C, packed)]
+struct RamfbConfig {
+    addr: u64,
+    fourcc: u32,
+    flags: u32,
+    width: u32,
+    height: u32,
+    stride: u32,
+}
+
+fn fw_cfg_select(base: usize, key: u16) {
+    unsafe { ((base + FW_CFG_SELECTOR) as *mut u16).write_volatile(key.to_be()) };
+}
+
+fn fw_cfg_read(base: usize, buf: &mut [u8]) {
+    for b in buf {
+        *b = unsafe { ((base + FW_CFG_DATA) as *const u8).read_volatile() };
+    }
+}
+
+/// Returns the selector of the fw_cfg file called `name`.
+fn fw_cfg_find(base: usize, name: &[u8]) -> Option<u16> {
+    fw_cfg_select(base, FW_CFG_FILE_DIR);
+    let mut count = [0; 4];
+    fw_cfg_read(base, &mut count);
+    // Size, selector, reserved, then the nul-padded name.
+    let mut entry = [0; 64];
+    for _ in 0..u32::from_be_bytes(count) {
+        fw_cfg_read(base, &mut entry);
+        let entry_name = &entry[8..];
+        let len = entry_name.iter().position(|&b| b == 0).unwrap_or(56);
+        if &entry_name[..len] == name {
+            return Some(u16::from_be_bytes([entry[4], entry[5]]));
+        }
+    }
+    None
+}
+
+/// Writes `len` bytes at `addr` to the fw_cfg file `key`, returning whether it took them.
+fn fw_cfg_dma_write(base: usize, key: u16, addr: usize, len: usize) -> bool {
+    let access = DmaAccess {
+        control: ((key as u32) << 16 | DMA_SELECT | DMA_WRITE).to_be(),
+        length: (len as u32).to_be(),
+        address: (addr as u64).to_be(),
+    };
+    let access_addr = &access as *const DmaAccess as u64;
+    fence(Ordering::SeqCst);
+    unsafe {
+        // Writing the low half starts the transfer.
+        ((base + FW_CFG_DMA) as *mut u32).write_volatile(((access_addr >> 32) as u32).to_be());
+        ((base + FW_CFG_DMA + 4) as *mut u32).write_volatile((access_addr as u32).to_be());
+    }
+    loop {
+        let control = u32::from_be(unsafe { (&raw const access.control).read_volatile() });
+        if control & DMA_ERROR != 0 {
+            return false;
+        }
+        if control == 0 {
+            return true;
+        }
+    }
+}
+
+/// Sets up a framebuffer in RAM for QEMU's `ramfb` device, returning it like
+/// `simple_framebuffer()`.
+fn ramfb() -> Option<(usize, usize, usize, usize, usize)> {
+    let mut fw_cfg = None;
+    dtb::for_each_compatible("qemu,fw-cfg-mmio", |node| {
+        fw_cfg.get_or_insert((node.start, node.end));
+    });
+    let (base, end) = fw_cfg?;
+    mem::add_region(RegionKind::Mmio, base, end);
+
+    let mut signature = [0; 4];
+    fw_cfg_select(base, FW_CFG_SIGNATURE);
+    fw_cfg_read(base, &mut signature);
+    let mut id = [0; 4];
+    fw_cfg_select(base, FW_CFG_ID);
+    fw_cfg_read(base, &mut id);
+    // `etc/ramfb` only takes writes by DMA.
+    if &signature != b"QEMU" || u32::from_le_bytes(id) & FW_CFG_ID_DMA == 0 {
+        return None;
+    }
+    let key = fw_cfg_find(base, b"etc/ramfb")?;
+
+    let stride = RAMFB_WIDTH * 4;
+    let fb = phalloc(RAMFB_HEIGHT * stride).ok()?.as_usize();
+    let config = RamfbConfig {
+        addr: (fb as u64).to_be(),
+        fourcc: FOURCC_XRGB8888.to_be(),
+        flags: 0,
+        width: (RAMFB_WIDTH as u32).to_be(),
+        height: (RAMFB_HEIGHT as u32).to_be(),
+        stride: (stride as u32).to_be(),
+    };
+    let config_addr = &config as *const RamfbConfig as usize;
+    if !fw_cfg_dma_write(base, key, config_addr, size_of::<RamfbConfig>()) {
+        println!("fbcon: ramfb didn't take its configuration");
+        return None;
+    }
+    Some((fb, RAMFB_WIDTH, RAMFB_HEIGHT, stride, 4))
+}
+
+crate::register_subsystem!(Device, "fbcon", init);
+
+fn init() {
+    let framebuffer = match simple_framebuffer() {
+        Some(fb @ (base, _, height, stride, _)) => {
+            mem::add_region(RegionKind::Mmio, base, base + height * stride);
+            Some(fb)
+        }
+        None => ramfb(),
+    };
+    let Some((base, width, height, stride, bpp)) = framebuffer else {
+        return;
+    };
+    let mut fbcon = Fbcon {
+        base,
+        width,
+        height,
+        stride,
+        bpp,
+        col: 0,
+        row: 0,
+    };
+    fbcon.clear();
+    *FBCON.lock() = Some(fbcon);
+    println!(
+        "fbcon: {width}x{height} framebuffer at {base:#x}, {}x{} characters",
+        width / font::WIDTH,
+        height / font::HEIGHT
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn text_wraps_and_scrolls() {
+        // Two lines of two characters.
+        const W: usize = 2 * font::WIDTH;
+        const H: usize = 2 * font::HEIGHT;
+        let mut pixels = [0_u32; W * H];
+        let mut fbcon = Fbcon {
+            base: pixels.as_mut_ptr() as usize,
+            width: W,
+            height: H,
+            stride: W * 4,
+            bpp: 4,
+            col: 0,
+            row: 0,
+        };
+        let lit = |pixels: &[u32], col: usize, row: usize, glyph: &[u8; font::HEIGHT]| {
+            (0..font::HEIGHT).all(|dy| {
+                (0..font::WIDTH).all(|dx| {
+                    let pixel = pixels[(row * font::HEIGHT + dy) * W + col * font::WIDTH + dx];
+                    (pixel == FOREGROUND) == (glyph[dy] & (1 << dx) != 0)
+                })
+            })
+        };
+
+        // "c" wraps to the second line, "d" scrolls "ab" away.
+        for &c in b"abc\nd" {
+            fbcon.put(c);
+        }
+        assert_eq!((fbcon.col, fbcon.row), (1, 1));
+        assert!(lit(&pixels, 0, 0, font::glyph(b'c')));
+        assert!(lit(&pixels, 0, 1, font::glyph(b'd')));
+        assert!(lit(&pixels, 1, 1, font::glyph(b' ')));
+
+        fbcon.put(0x08);
+        fbcon.put(b'e');
+        assert!(lit(&pixels, 0, 1, font::glyph(b'e')));
+    }
+}
@@ -0,0 +1,576 @@
+use core::slice;
+
+use crate::{
+    faultpoint,
+    mem::PhysAddr,
+    pipe,
+    poll::{self, PollTable},
+    proc::{self, PROC_MAX},
+    rlimit,
+    stdlib::{phalloc, phree},
+    sync::Mutex,
+    syscall::Errno,
+    tty, udp, vfs,
+};
+
+/// Most fds a process can have open, however high its `RLIMIT_NOFILE`.
+pub const MAX_FDS: usize = 1024;
+const MAX_FILES: usize = 128;
+
+// `fcntl()` commands and fd flags, with the values Linux uses.
+pub const F_GETFD: usize = 1;
+pub const F_SETFD: usize = 2;
+pub const FD_CLOEXEC: usize = 1;
+
+/// What an open file refers to, each kind implements `read()`/`write()`/`close()` its own way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileKind {
+    /// A terminal, see `tty`.
+    Tty(usize),
+    PipeRead(usize),
+    PipeWrite(usize),
+    Socket(usize),
+    /// A file or directory of the filesystem mounted at `mount`.
+    Inode {
+        mount: usize,
+        ino: usize,
+    },
+}
+
+struct OpenFile {
+    kind: FileKind,
+    // Number of fds referring to this file.
+    refs: usize,
+    // Where the next read or write starts, in bytes or, for directories, entries.
+    offset: usize,
+    // `vfs::O_RDONLY`, `O_WRONLY` or `O_RDWR`, as opened.
+    access: usize,
+}
+
+// System-wide open files, shared by the fds referring to them.
+static FILES: Mutex<[Option<OpenFile>; MAX_FILES]> = Mutex::new([const { None }; MAX_FILES]);
+
+#[derive(Clone, Copy)]
+struct Fd {
+    // Slot of `FILES`.
+    file: usize,
+    // Closed when the process execs another program.
+    cloexec: bool,
+}
+
+// Fds a table holds before it first grows.
+const INLINE_FDS: usize = 16;
+const WORD_BITS: usize = usize::BITS as usize;
+// Words of `FdTable::used`, one bit of `FdTable::full` each.
+const WORDS: usize = MAX_FDS / WORD_BITS;
+const _: () = assert!(WORDS <= WORD_BITS);
+
+// The fds of a process. Its first fds are kept inline, and once one above them is opened
+// the table moves to a block of its own, twice as large every time it grows again, up to
+// `MAX_FDS`. The block is only freed when the process exits.
+//
+// The lowest free fd is found in two steps: the first word of `used` that `full` doesn't
+// flag, and the first clear bit of that word.
+struct FdTable {
+    inline: [Option<Fd>; INLINE_FDS],
+    // Holds all `cap` fds once the table grew, `inline` is unused then.
+    block: Option<PhysAddr>,
+    cap: usize,
+    // Bit `fd % WORD_BITS` of word `fd / WORD_BITS` is set while `fd` is open.
+    used: [usize; WORDS],
+    // Bit `i` is set while all the fds of `used[i]` are open.
+    full: usize,
+}
+
+impl FdTable {
+    const EMPTY: Self = Self {
+        inline: [None; INLINE_FDS],
+        block: None,
+        cap: INLINE_FDS,
+        used: [0; WORDS],
+        full: 0,
+    };
+
+    fn slots(&self) -> &[Option<Fd>] {
+        match self.block {
+            // Allocated for `cap` fds, see `grow()`.
+            Some(block) => unsafe { slice::from_raw_parts(block.as_ptr().cast(), self.cap) },
+            None => &self.inline,
+        }
+    }
+
+    fn slots_mut(&mut self) -> &mut [Option<Fd>] {
+        match self.block {
+            Some(block) => unsafe {
+                slice::from_raw_parts_mut(block.as_mut_ptr().cast(), self.cap)
+            },
+            None => &mut self.inline,
+        }
+    }
+
+    fn get(&self, fd: usize) -> Option<Fd> {
+        self.slots().get(fd).copied().flatten()
+    }
+
+    fn get_mut(&mut self, fd: usize) -> Option<&mut Fd> {
+        self.slots_mut().get_mut(fd)?.as_mut()
+    }
+
+    /// Returns the lowest fd that isn't open, if any is below `MAX_FDS`.
+    fn lowest_free(&self) -> Option<usize> {
+        let word = (!self.full).trailing_zeros() as usize;
+        let bit = (!*self.used.get(word)?).trailing_zeros() as usize;
+        Some(word * WORD_BITS + bit)
+    }
+
+    /// Grows the table to hold `fd`, which must be below `MAX_FDS`.
+    ///
+    /// Fails with `ENOMEM` if there is no memory for it.
+    fn reserve(&mut self, fd: usize) -> Result<(), Errno> {
+        if fd < self.cap {
+            return Ok(());
+        }
+        let cap = (fd + 1).next_power_of_two().max(self.cap * 2).min(MAX_FDS);
+        let block = phalloc(cap * size_of::<Option<Fd>>()).map_err(|_| Errno::ENOMEM)?;
+        let slots = unsafe { slice::from_raw_parts_mut(block.as_mut_ptr().cast(), cap) };
+        slots[..self.cap].copy_from_slice(self.slots());
+        slots[self.cap..].fill(None);
+        if let Some(old) = self.block.replace(block) {
+            phree(old);
+        }
+        self.cap = cap;
+        Ok(())
+    }
+
+    /// Makes `fd` refer to `entry`, growing the table to hold it.
+    ///
+    /// Fails with `ENOMEM` if there is no memory for it.
+    fn set(&mut self, fd: usize, entry: Fd) -> Result<(), Errno> {
+        self.reserve(fd)?;
+        self.slots_mut()[fd] = Some(entry);
+        let word = fd / WORD_BITS;
+        self.used[word] |= 1 << (fd % WORD_BITS);
+        if self.used[word] == usize::MAX {
+            self.full |= 1 << word;
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, fd: usize) -> Option<Fd> {
+        let entry = self.slots_mut().get_mut(fd)?.take()?;
+        let word = fd / WORD_BITS;
+        self.used[word] &= !(1 << (fd % WORD_BITS));
+        self.full &= !(1 << word);
+        Some(entry)
+    }
+
+    fn count(&self) -> usize {
+        self.used
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+}
+
+// Per-process fd tables, indexed by pid.
+// Lock order: `FDS` before `FILES`.
+static FDS: Mutex<[FdTable; PROC_MAX]> = Mutex::new([const { FdTable::EMPTY }; PROC_MAX]);
+
+/// Opens a new file referring to `kind` and returns the lowest free fd of the calling process.
+///
+/// Fails with `EMFILE` if no fd below the process's `RLIMIT_NOFILE` is free, and with
+/// `ENOMEM` if the fd table can't grow to hold it.
+pub fn open(kind: FileKind) -> Result<usize, Errno> {
+    open_as(kind, vfs::O_RDWR)
+}
+
+/// Like `open()`, the file only readable or writable as `access` says, one of
+/// `vfs::O_RDONLY`, `O_WRONLY` and `O_RDWR`.
+pub fn open_as(kind: FileKind, access: usize) -> Result<usize, Errno> {
+    let pid = proc::current();
+    let limit = rlimit::current(pid, rlimit::RLIMIT_NOFILE);
+    let mut fds = FDS.lock();
+    let fds = &mut fds[pid];
+    let fd = fds
+        .lowest_free()
+        .filter(|&fd| fd < limit)
+        .ok_or(Errno::EMFILE)?;
+
+    if faultpoint!("file-alloc") {
+        return Err(Errno::ENFILE);
+    }
+    let mut files = FILES.lock();
+    let file = files
+        .iter()
+        .position(|f| f.is_none())
+        .ok_or(Errno::ENFILE)?;
+
+    fds.set(
+        fd,
+        Fd {
+            file,
+            cloexec: false,
+        },
+    )?;
+    files[file] = Some(OpenFile {
+        kind,
+        refs: 1,
+        offset: 0,
+        access,
+    });
+
+    Ok(fd)
+}
+
+/// Makes the lowest free fd of the calling process refer to the same file as `fd`, which
+/// it returns. The new fd stays open across exec.
+///
+/// Fails with `EMFILE` if no fd below the process's `RLIMIT_NOFILE` is free, and with
+/// `ENOMEM` if the fd table can't grow to hold it.
+pub fn dup(fd: usize) -> Result<usize, Errno> {
+    let pid = proc::current();
+    let limit = rlimit::current(pid, rlimit::RLIMIT_NOFILE);
+    let mut fds = FDS.lock();
+    let fds = &mut fds[pid];
+    let file = fds.get(fd).ok_or(Errno::EBADF)?.file;
+    let new_fd = fds
+        .lowest_free()
+        .filter(|&fd| fd < limit)
+        .ok_or(Errno::EMFILE)?;
+
+    fds.set(
+        new_fd,
+        Fd {
+            file,
+            cloexec: false,
+        },
+    )?;
+    FILES.lock()[file].as_mut().unwrap().refs += 1;
+    Ok(new_fd)
+}
+
+/// Makes `new_fd` of the calling process refer to the same file as `fd`, closing what it
+/// referred to before, and returns it. The new fd stays open across exec.
+///
+/// Fails with `EBADF` if `new_fd` is at or above the process's `RLIMIT_NOFILE`, and with
+/// `ENOMEM` if the fd table can't grow to hold it.
+pub fn dup2(fd: usize, new_fd: usize) -> Result<usize, Errno> {
+    let pid = proc::current();
+    let limit = rlimit::current(pid, rlimit::RLIMIT_NOFILE);
+    let file = file_of(fd)?;
+    if new_fd >= limit.min(MAX_FDS) {
+        return Err(Errno::EBADF);
+    }
+    if new_fd == fd {
+        return Ok(fd);
+    }
+    // Grown first, so that nothing is closed when it can't.
+    FDS.lock()[pid].reserve(new_fd)?;
+
+    // Closing first may release a file, which must not happen while holding the locks.
+    let _ = close_as(pid, new_fd);
+
+    let mut fds = FDS.lock();
+    let mut files = FILES.lock();
+    // Still open, `fd` refers to it.
+    files[file].as_mut().unwrap().refs += 1;
+    fds[pid].set(
+        new_fd,
+        Fd {
+            file,
+            cloexec: false,
+        },
+    )?;
+    Ok(new_fd)
+}
+
+/// Gets (`F_GETFD`) or sets (`F_SETFD`) the `FD_CLOEXEC` flag of `fd`.
+pub fn fcntl(fd: usize, cmd: usize, arg: usize) -> Result<usize, Errno> {
+    let mut fds = FDS.lock();
+    let entry = fds[proc::current()].get_mut(fd).ok_or(Errno::EBADF)?;
+
+    match cmd {
+        F_GETFD => Ok(if entry.cloexec { FD_CLOEXEC } else { 0 }),
+        F_SETFD => {
+            entry.cloexec = arg & FD_CLOEXEC != 0;
+            Ok(0)
+        }
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// Gives `child`, whose table is empty, copies of the fds of `parent`, referring to the
+/// same files, when the former spawns the latter.
+///
+/// Fails with `ENOMEM` if the table of `child` can't grow as large as that of `parent`.
+pub fn inherit(parent: usize, child: usize) -> Result<(), Errno> {
+    let mut fds = FDS.lock();
+    let [parent, child] = fds.get_disjoint_mut([parent, child]).unwrap();
+    child.reserve(parent.cap - 1)?;
+    let mut files = FILES.lock();
+    for fd in parent.slots().iter().flatten() {
+        files[fd.file].as_mut().unwrap().refs += 1;
+    }
+    child.slots_mut()[..parent.cap].copy_from_slice(parent.slots());
+    child.used = parent.used;
+    child.full = parent.full;
+    Ok(())
+}
+
+/// Closes the fds of `pid` flagged with `FD_CLOEXEC`, called when it execs another program.
+pub fn close_on_exec(pid: usize) {
+    let cap = FDS.lock()[pid].cap;
+    for fd in 0..cap {
+        let cloexec = FDS.lock()[pid].get(fd).is_some_and(|f| f.cloexec);
+        if cloexec {
+            let _ = close_as(pid, fd);
+        }
+    }
+}
+
+/// Closes `fd` of the calling process, releasing the file once no fd refers to it.
+pub fn close(fd: usize) -> Result<(), Errno> {
+    close_as(proc::current(), fd)
+}
+
+/// Returns the number of open fds of `pid`.
+pub fn count(pid: usize) -> usize {
+    FDS.lock()[pid].count()
+}
+
+/// Closes all fds of `pid`, and frees its table, called when it exits.
+pub fn close_all(pid: usize) {
+    let cap = FDS.lock()[pid].cap;
+    for fd in 0..cap {
+        let _ = close_as(pid, fd);
+    }
+    let mut fds = FDS.lock();
+    if let Some(block) = fds[pid].block {
+        phree(block);
+    }
+    fds[pid] = FdTable::EMPTY;
+}
+
+fn close_as(pid: usize, fd: usize) -> Result<(), Errno> {
+    let mut fds = FDS.lock();
+    let file = fds[pid].take(fd).ok_or(Errno::EBADF)?.file;
+
+    let mut files = FILES.lock();
+    let open_file = files[file].as_mut().unwrap();
+    open_file.refs -= 1;
+    if open_file.refs > 0 {
+        return Ok(());
+    }
+    let kind = open_file.kind;
+    files[file] = None;
+    drop(files);
+    drop(fds);
+
+    match kind {
+        FileKind::PipeRead(pipe) => pipe::close_read(pipe),
+        FileKind::PipeWrite(pipe) => pipe::close_write(pipe),
+        FileKind::Socket(sock) => udp::close(sock),
+        FileKind::Inode { mount, ino } => vfs::release(mount, ino),
+        FileKind::Tty(_) => {}
+    }
+
+    Ok(())
+}
+
+// Returns the slot of `FILES` that `fd` of the calling process refers to.
+fn file_of(fd: usize) -> Result<usize, Errno> {
+    FDS.lock()[proc::current()]
+        .get(fd)
+        .map(|f| f.file)
+        .ok_or(Errno::EBADF)
+}
+
+/// Returns what `fd` of the calling process refers to.
+pub fn kind(fd: usize) -> Result<FileKind, Errno> {
+    let file = file_of(fd)?;
+    Ok(FILES.lock()[file].as_ref().unwrap().kind)
+}
+
+/// Returns the offset of `fd`, where the next read or write starts.
+pub fn offset(fd: usize) -> Result<usize, Errno> {
+    let file = file_of(fd)?;
+    Ok(FILES.lock()[file].as_ref().unwrap().offset)
+}
+
+pub fn set_offset(fd: usize, offset: usize) -> Result<(), Errno> {
+    let file = file_of(fd)?;
+    FILES.lock()[file].as_mut().unwrap().offset = offset;
+    Ok(())
+}
+
+/// Returns how `fd` was opened, `vfs::O_RDONLY`, `vfs::O_WRONLY` or `vfs::O_RDWR`.
+pub fn access(fd: usize) -> Result<usize, Errno> {
+    let file = file_of(fd)?;
+    Ok(FILES.lock()[file].as_ref().unwrap().access)
+}
+
+/// Reads up to `buf.len()` bytes from `fd`, returning how many were read (0 at end of file).
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+    match kind(fd)? {
+        FileKind::Tty(tty) => tty::read(tty, buf),
+        FileKind::PipeRead(pipe) => pipe::read(pipe, buf),
+        FileKind::PipeWrite(_) => Err(Errno::EBADF),
+        FileKind::Socket(sock) => udp::recv_from(sock, buf).map(|(n, _)| n),
+        FileKind::Inode { mount, ino } => {
+            if access(fd)? == vfs::O_WRONLY {
+                return Err(Errno::EBADF);
+            }
+            let offset = offset(fd)?;
+            let n = vfs::read(mount, ino, offset, buf)?;
+            set_offset(fd, offset + n)?;
+            Ok(n)
+        }
+    }
+}
+
+/// Writes `buf` to `fd`, returning how many bytes were written.
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, Errno> {
+    match kind(fd)? {
+        FileKind::Tty(tty) => Ok(tty::write(tty, buf)),
+        FileKind::PipeWrite(pipe) => pipe::write(pipe, buf),
+        FileKind::PipeRead(_) => Err(Errno::EBADF),
+        // Sockets aren't connected, every datagram needs `sendto`.
+        FileKind::Socket(_) => Err(Errno::EDESTADDRREQ),
+        FileKind::Inode { mount, ino } => {
+            if access(fd)? == vfs::O_RDONLY {
+                return Err(Errno::EBADF);
+            }
+            let offset = offset(fd)?;
+            let n = vfs::write(mount, ino, offset, buf)?;
+            set_offset(fd, offset + n)?;
+            Ok(n)
+        }
+    }
+}
+
+/// Writes back the file `fd` refers to, see `FileSystem::fsync()`.
+///
+/// Fails with `EINVAL` if it isn't a file of a filesystem, like Linux for pipes and sockets.
+pub fn fsync(fd: usize) -> Result<(), Errno> {
+    match kind(fd)? {
+        FileKind::Inode { mount, ino } => vfs::fsync(mount, ino),
+        FileKind::Tty(_) | FileKind::PipeRead(_) | FileKind::PipeWrite(_) | FileKind::Socket(_) => {
+            Err(Errno::EINVAL)
+        }
+    }
+}
+
+/// Returns the readiness of `fd` as `poll::POLL*` flags, queueing the caller on the file
+/// through `table` if given.
+pub fn poll(fd: usize, table: Option<&mut PollTable>) -> Result<u16, Errno> {
+    Ok(match kind(fd)? {
+        // FIXME: Doesn't queue the caller, console input is only noticed when polled again.
+        FileKind::Tty(tty) => tty::poll(tty),
+        FileKind::PipeRead(pipe) => pipe::poll(pipe, false, table),
+        FileKind::PipeWrite(pipe) => pipe::poll(pipe, true, table),
+        FileKind::Socket(sock) => udp::poll(sock, table),
+        // Files never block.
+        FileKind::Inode { .. } => poll::POLLIN | poll::POLLOUT,
+    })
+}
+
+/// Returns the socket `fd` refers to.
+pub fn socket(fd: usize) -> Result<usize, Errno> {
+    match kind(fd)? {
+        FileKind::Socket(sock) => Ok(sock),
+        _ => Err(Errno::ENOTSOCK),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault::{self, Fault};
+
+    #[test_case]
+    fn duplicates_share_the_file() {
+        let (r, w) = pipe::open().unwrap();
+        let w2 = dup(w).unwrap();
+        close(w).unwrap();
+        assert_eq!(write(w2, b"x"), Ok(1));
+
+        assert_eq!(dup2(r, 10), Ok(10));
+        let mut buf = [0; 1];
+        assert_eq!(read(10, &mut buf), Ok(1));
+        assert_eq!(dup2(r, MAX_FDS), Err(Errno::EBADF));
+
+        // The pipe has no writers once the last duplicate is closed.
+        close(w2).unwrap();
+        assert_eq!(read(r, &mut buf), Ok(0));
+
+        close(10).unwrap();
+        close(r).unwrap();
+    }
+
+    #[test_case]
+    fn tables_grow_and_hand_out_the_lowest_fd() {
+        let pid = proc::current();
+        let (r, w) = pipe::open().unwrap();
+        let open = count(pid);
+        let mut dups = [0; INLINE_FDS * 2];
+        for fd in dups.iter_mut() {
+            *fd = dup(r).unwrap();
+        }
+        assert!(dups.iter().any(|&fd| fd >= INLINE_FDS));
+        assert!(FDS.lock()[pid].cap > INLINE_FDS);
+
+        // A hole is filled before the fds above it.
+        close(dups[3]).unwrap();
+        assert_eq!(dup(r), Ok(dups[3]));
+        assert_eq!(write(w, b"x"), Ok(1));
+        assert_eq!(read(dups[INLINE_FDS + 1], &mut [0; 1]), Ok(1));
+
+        for fd in dups {
+            close(fd).unwrap();
+        }
+        assert_eq!(count(pid), open);
+        close(r).unwrap();
+        close(w).unwrap();
+    }
+
+    #[test_case]
+    fn failed_opens_leave_nothing_open() {
+        let pid = proc::current();
+        let open = count(pid);
+        // Either end of the pipe.
+        for skip in 0..2 {
+            fault::inject("file-alloc", Fault::Fail { skip, count: 1 }).unwrap();
+            assert_eq!(pipe::open(), Err(Errno::ENFILE));
+            assert_eq!(fault::clear("file-alloc"), 1);
+            assert_eq!(count(pid), open);
+        }
+    }
+
+    #[test_case]
+    fn only_files_can_be_synced() {
+        let (r, w) = pipe::open().unwrap();
+        assert_eq!(fsync(w), Err(Errno::EINVAL));
+        assert_eq!(fsync(MAX_FDS), Err(Errno::EBADF));
+        close(r).unwrap();
+        close(w).unwrap();
+    }
+
+    #[test_case]
+    fn cloexec_fds_are_closed_on_exec() {
+        let pid = proc::current();
+        let (r, w) = pipe::open().unwrap();
+        assert_eq!(fcntl(w, F_SETFD, FD_CLOEXEC), Ok(0));
+        assert_eq!(fcntl(w, F_GETFD, 0), Ok(FD_CLOEXEC));
+
+        // A pid no process uses while tests run.
+        let child = PROC_MAX - 1;
+        inherit(pid, child).unwrap();
+        assert_eq!(count(child), count(pid));
+        close_all(child);
+
+        close_on_exec(pid);
+        assert_eq!(write(w, b"x"), Err(Errno::EBADF));
+        assert_eq!(read(r, &mut [0; 1]), Ok(0));
+        close(r).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    cpu,
+    csr::{FpState, Sstatus},
+    proc::{self, PROC_MAX},
+    sync::Mutex,
+};
+
+// Floating-point registers of user processes, on harts with the F or D extension. The
+// kernel itself is built without them, so they are left alone on traps and swapped at
+// context switches only: saved if `sstatus.FS` says the process has written them since, and
+// restored for the next one, which leaves them clean. Without an FPU `sstatus.FS` stays
+// off, and floating-point instructions trap as illegal.
+
+/// The registers of a process while it isn't running.
+#[derive(Debug, Clone, Copy)]
+struct FpRegs {
+    // Wide enough for D, the low half holds an F register.
+    f: [u64; 32],
+    fcsr: usize,
+}
+
+impl FpRegs {
+    const fn new() -> Self {
+        Self {
+            f: [0; 32],
+            fcsr: 0,
+        }
+    }
+}
+
+static REGS: Mutex<[FpRegs; PROC_MAX]> = Mutex::new([const { FpRegs::new() }; PROC_MAX]);
+
+// Bytes in a register: 8 with D, 4 with only F, 0 without an FPU. Taken from the boot hart,
+// the others are assumed to match.
+static WIDTH: AtomicUsize = AtomicUsize::new(0);
+
+crate::register_subsystem!(Core, "fpu", init, after("cpu"));
+
+fn init() {
+    let features = cpu::current();
+    let width = match (features.has('d'), features.has_fpu()) {
+        (true, _) => 8,
+        (false, true) => 4,
+        (false, false) => 0,
+    };
+    WIDTH.store(width, Ordering::Relaxed);
+}
+
+fn save(regs: &mut FpRegs) {
+    let f = regs.f.as_mut_ptr();
+    match WIDTH.load(Ordering::Relaxed) {
+        8 => unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +d",
+                ".irp i, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+                "fsd f\\i, 8 * \\i({f})",
+                ".endr",
+                "frcsr {fcsr}",
+                ".option pop",
+                f = in(reg) f,
+                fcsr = out(reg) regs.fcsr,
+            )
+        },
+        4 => unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +f",
+                ".irp i, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+                "fsw f\\i, 8 * \\i({f})",
+                ".endr",
+                "frcsr {fcsr}",
+                ".option pop",
+                f = in(reg) f,
+                fcsr = out(reg) regs.fcsr,
+            )
+        },
+        _ => {}
+    }
+}
+
+fn restore(regs: &FpRegs) {
+    let f = regs.f.as_ptr();
+    match WIDTH.load(Ordering::Relaxed) {
+        8 => unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +d",
+                ".irp i, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+                "fld f\\i, 8 * \\i({f})",
+                ".endr",
+                "fscsr {fcsr}",
+                ".option pop",
+                f = in(reg) f,
+                fcsr = in(reg) regs.fcsr,
+            )
+        },
+        4 => unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +f",
+                ".irp i, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+                "flw f\\i, 8 * \\i({f})",
+                ".endr",
+                "fscsr {fcsr}",
+                ".option pop",
+                f = in(reg) f,
+                fcsr = in(reg) regs.fcsr,
+            )
+        },
+        _ => {}
+    }
+}
+
+// Leaves the registers enabled but clean, they match the saved state.
+fn set_clean() {
+    Sstatus::read().with_fs(FpState::Clean).write();
+}
+
+// Makes `regs` the live registers. They need to be enabled to be written, which marks them
+// dirty again.
+fn load(regs: &FpRegs) {
+    set_clean();
+    restore(regs);
+    set_clean();
+}
+
+/// Saves the registers of `prev` if it wrote them, and loads those of `next`, before
+/// switching from one to the other.
+pub fn switch(prev: usize, next: usize) {
+    if WIDTH.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let mut regs = REGS.lock();
+    if Sstatus::read().fs() == FpState::Dirty {
+        save(&mut regs[prev]);
+    }
+    load(&regs[next]);
+}
+
+/// Gives the forked `child` a copy of the registers of `parent`, the calling process.
+pub fn inherit(parent: usize, child: usize) {
+    if WIDTH.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let mut regs = REGS.lock();
+    if Sstatus::read().fs() == FpState::Dirty {
+        save(&mut regs[parent]);
+        set_clean();
+    }
+    regs[child] = regs[parent];
+}
+
+/// Zeroes the registers of `pid`, e.g. on `exec()`, and the live ones if it is running.
+pub fn reset(pid: usize) {
+    let mut regs = REGS.lock();
+    regs[pid] = FpRegs::new();
+    if WIDTH.load(Ordering::Relaxed) != 0 && pid == proc::current() {
+        load(&regs[pid]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn registers_survive_a_save_and_restore() {
+        if WIDTH.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let sstatus = Sstatus::read();
+        set_clean();
+        let mut regs = FpRegs::new();
+        regs.f[1] = 0x4000_0000;
+        regs.f[31] = 0x3f80_0000;
+        regs.fcsr = 0x20;
+        restore(&regs);
+        let mut saved = FpRegs::new();
+        save(&mut saved);
+        sstatus.write();
+        assert_eq!(saved.f[1] as u32, 0x4000_0000);
+        assert_eq!(saved.f[31] as u32, 0x3f80_0000);
+        assert_eq!(saved.fcsr, 0x20);
+    }
+}
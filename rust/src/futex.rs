@@ -0,0 +1,86 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{
+    proc::{self, PROC_MAX, WaitQueue},
+    signal,
+    sync::Mutex,
+    syscall::Errno,
+    uaccess::{self, Access},
+};
+
+// Physical address of the word each process waits on, indexed by pid. Keying by physical
+// address lets processes sharing memory at different virtual addresses wake each other.
+static WAITING_ON: Mutex<[Option<usize>; PROC_MAX]> = Mutex::new([None; PROC_MAX]);
+static WAITERS: [WaitQueue; PROC_MAX] = [const { WaitQueue::new() }; PROC_MAX];
+
+/// Returns the key of the word at `addr`, its physical address.
+///
+/// The page is faulted in for writing first: a swapped out or not yet faulted in page has
+/// no address, and an untouched one is the zero page every process shares.
+fn key(addr: usize) -> Result<usize, Errno> {
+    if !addr.is_multiple_of(size_of::<u32>()) {
+        return Err(Errno::EINVAL);
+    }
+    uaccess::check(addr, size_of::<u32>(), Access::Write)?;
+    proc::translate(addr).ok_or(Errno::EFAULT)
+}
+
+/// Blocks the calling process on the `u32` at `addr` if it still holds `expected`.
+///
+/// Fails with `EAGAIN` if the value changed. May return without a matching `wake()`,
+/// callers must check the value again.
+pub fn wait(addr: usize, expected: u32) -> Result<(), Errno> {
+    let key = key(addr)?;
+    let pid = proc::current();
+
+    let mut waiting_on = WAITING_ON.lock();
+
+    // Compared under the lock, so a `wake()` after the value changed can't be missed.
+    let word = unsafe { AtomicU32::from_ptr(addr as *mut u32) };
+    if word.load(Ordering::Acquire) != expected {
+        return Err(Errno::EAGAIN);
+    }
+
+    waiting_on[pid] = Some(key);
+    WAITERS[pid].wait(waiting_on);
+
+    WAITING_ON.lock()[pid] = None;
+
+    signal::interrupted()
+}
+
+/// Wakes up to `n` processes waiting on the word at `addr`, returning how many were woken.
+pub fn wake(addr: usize, n: usize) -> Result<usize, Errno> {
+    let key = key(addr)?;
+    let mut waiting_on = WAITING_ON.lock();
+
+    let mut woken = 0;
+    for (pid, waiting) in waiting_on.iter_mut().enumerate() {
+        if woken == n {
+            break;
+        }
+        if *waiting == Some(key) {
+            *waiting = None;
+            WAITERS[pid].wake_one();
+            woken += 1;
+        }
+    }
+
+    Ok(woken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wait, wake};
+    use crate::syscall::Errno;
+
+    #[test_case]
+    fn wait_on_changed_value_fails() {
+        let word = 1_u32;
+        let addr = &word as *const u32 as usize;
+
+        assert_eq!(wait(addr, 0), Err(Errno::EAGAIN));
+        assert_eq!(wait(addr + 1, 1), Err(Errno::EINVAL));
+        assert_eq!(wake(addr, 1), Ok(0));
+    }
+}
@@ -0,0 +1,449 @@
+use core::{arch::asm, hint::spin_loop, ptr};
+
+use crate::{extable, sbi, sync::Mutex, trap::TrapFrame, uaccess::UserAccess};
+
+// A minimal GDB remote serial protocol stub on the SBI debug console.
+//
+// The kernel stops for the debugger when it hits a breakpoint set by GDB, when GDB sends
+// an interrupt (Ctrl-C) while the kernel runs, or when it panics. Connect with e.g.
+// `target remote /dev/ttyUSB0`, or with QEMU's `-serial tcp::1234,server` and `target remote :1234`.
+//
+// FIXME: Only the hart that stopped is halted, other harts keep running.
+
+const PACKET_SIZE: usize = 512;
+const MAX_BREAKPOINTS: usize = 8;
+
+// Byte GDB sends on the serial line to interrupt the running target.
+const INTERRUPT: u8 = 0x03;
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u32 = 0x9002;
+
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+
+// General-purpose registers followed by pc, in GDB's riscv register numbering.
+const NUM_REGS: usize = 33;
+const REG_PC: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: usize,
+    orig: u32,
+    len: usize,
+    // Set by single-stepping, removed once hit.
+    temporary: bool,
+}
+
+static BREAKPOINTS: Mutex<[Option<Breakpoint>; MAX_BREAKPOINTS]> =
+    Mutex::new([None; MAX_BREAKPOINTS]);
+
+crate::register_subsystem!(Late, "gdb", init);
+
+fn init() {
+    crate::println!("gdb: remote stub on the console, interrupt with Ctrl-C from GDB");
+}
+
+struct Packet {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    fn new() -> Self {
+        Self {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|b| self.push(b));
+    }
+
+    fn push_hex_byte(&mut self, byte: u8) {
+        self.push(hex_char(byte >> 4));
+        self.push(hex_char(byte & 0xf));
+    }
+
+    /// Pushes `value` in target byte order, as GDB expects register contents.
+    fn push_hex_le(&mut self, value: usize) {
+        value
+            .to_le_bytes()
+            .iter()
+            .for_each(|&b| self.push_hex_byte(b));
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+fn hex_char(nibble: u8) -> u8 {
+    b"0123456789abcdef"[nibble as usize & 0xf]
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() {
+        return None;
+    }
+    s.iter()
+        .try_fold(0_usize, |acc, &c| Some((acc << 4) | hex_digit(c)? as usize))
+}
+
+/// Parses a value sent in target byte order (two hex digits per byte, least significant first).
+fn parse_hex_le(s: &[u8]) -> Option<usize> {
+    let mut bytes = [0_u8; size_of::<usize>()];
+    if s.len() != 2 * bytes.len() {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = parse_hex(&s[2 * i..2 * i + 2])? as u8;
+    }
+    Some(usize::from_le_bytes(bytes))
+}
+
+/// Splits "addr,len" (optionally followed by `terminator` and more data).
+fn parse_addr_len(s: &[u8], terminator: u8) -> Option<(usize, usize, &[u8])> {
+    let comma = s.iter().position(|&c| c == b',')?;
+    let end = s.iter().position(|&c| c == terminator).unwrap_or(s.len());
+    let addr = parse_hex(&s[..comma])?;
+    let len = parse_hex(s.get(comma + 1..end)?)?;
+    Some((addr, len, s.get(end + 1..).unwrap_or(&[])))
+}
+
+fn getc() -> u8 {
+    loop {
+        if let Some(c) = sbi::getchar() {
+            return c;
+        }
+        spin_loop();
+    }
+}
+
+fn putc(c: u8) {
+    sbi::putchar(c as char);
+}
+
+/// Receives a packet into `buf`, acknowledging it, and returns its length.
+fn receive(buf: &mut [u8; PACKET_SIZE]) -> usize {
+    loop {
+        // Anything between packets (acks, stray interrupts) is dropped.
+        while getc() != b'$' {}
+
+        let mut len = 0;
+        let mut sum = 0_u8;
+        loop {
+            let c = getc();
+            if c == b'#' {
+                break;
+            }
+            if len < PACKET_SIZE {
+                buf[len] = c;
+                len += 1;
+            }
+            sum = sum.wrapping_add(c);
+        }
+
+        let checksum = [getc(), getc()];
+        if parse_hex(&checksum) == Some(sum as usize) {
+            putc(b'+');
+            return len;
+        }
+        putc(b'-');
+    }
+}
+
+/// Sends `packet`, retransmitting until GDB acknowledges it.
+fn send(packet: &Packet) {
+    loop {
+        putc(b'$');
+        let mut sum = 0_u8;
+        for &c in packet.as_bytes() {
+            putc(c);
+            sum = sum.wrapping_add(c);
+        }
+        putc(b'#');
+        putc(hex_char(sum >> 4));
+        putc(hex_char(sum & 0xf));
+
+        loop {
+            match getc() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+fn read_insn(addr: usize, len: usize) -> u32 {
+    // Breakpoints are as often in user programs as in the kernel.
+    let _user = UserAccess::new();
+    (0..len).fold(0, |insn, i| {
+        insn | (unsafe { ptr::read_volatile((addr + i) as *const u8) } as u32) << (8 * i)
+    })
+}
+
+fn write_insn(addr: usize, insn: u32, len: usize) {
+    let _user = UserAccess::new();
+    for i in 0..len {
+        unsafe { ptr::write_volatile((addr + i) as *mut u8, (insn >> (8 * i)) as u8) };
+    }
+    unsafe { asm!("fence.i") };
+}
+
+/// Returns the length of the instruction at `addr`, 2 for compressed instructions.
+fn insn_len(addr: usize) -> usize {
+    if read_insn(addr, 2) & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+fn insert_breakpoint(addr: usize, len: usize, temporary: bool) -> bool {
+    let mut breakpoints = BREAKPOINTS.lock();
+
+    if breakpoints.iter().flatten().any(|b| b.addr == addr) {
+        return true;
+    }
+
+    let Some(slot) = breakpoints.iter_mut().find(|b| b.is_none()) else {
+        return false;
+    };
+
+    *slot = Some(Breakpoint {
+        addr,
+        orig: read_insn(addr, len),
+        len,
+        temporary,
+    });
+    write_insn(addr, if len == 2 { C_EBREAK } else { EBREAK }, len);
+
+    true
+}
+
+fn remove_breakpoint(addr: usize) -> bool {
+    let mut breakpoints = BREAKPOINTS.lock();
+
+    for slot in breakpoints.iter_mut() {
+        if let Some(b) = slot.filter(|b| b.addr == addr) {
+            write_insn(b.addr, b.orig, b.len);
+            *slot = None;
+            return true;
+        }
+    }
+
+    false
+}
+
+fn remove_all_breakpoints() {
+    let mut breakpoints = BREAKPOINTS.lock();
+    for b in breakpoints.iter_mut().filter_map(|b| b.take()) {
+        write_insn(b.addr, b.orig, b.len);
+    }
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+/// Serves GDB requests for the stopped context in `tf`/`pc` until it resumes it.
+fn serve(tf: &mut TrapFrame, pc: &mut usize, signal: u8) -> Resume {
+    let mut buf = [0_u8; PACKET_SIZE];
+
+    let mut reply = Packet::new();
+    reply.push(b'S');
+    reply.push_hex_byte(signal);
+    send(&reply);
+
+    loop {
+        let len = receive(&mut buf);
+        let cmd = &buf[..len];
+        let args = cmd.get(1..).unwrap_or(&[]);
+        let mut reply = Packet::new();
+
+        match cmd.first() {
+            Some(b'?') => {
+                reply.push(b'S');
+                reply.push_hex_byte(signal);
+            }
+            Some(b'g') => {
+                (0..REG_PC).for_each(|n| reply.push_hex_le(tf.reg(n)));
+                reply.push_hex_le(*pc);
+            }
+            Some(b'G') => {
+                let width = 2 * size_of::<usize>();
+                for (n, value) in args.chunks(width).take(NUM_REGS).enumerate() {
+                    let value = parse_hex_le(value).unwrap_or(0);
+                    if n == REG_PC {
+                        *pc = value
+                    } else {
+                        tf.set_reg(n, value)
+                    }
+                }
+                reply.push_str("OK");
+            }
+            Some(b'p') => match parse_hex(args) {
+                Some(REG_PC) => reply.push_hex_le(*pc),
+                Some(n) if n < REG_PC => reply.push_hex_le(tf.reg(n)),
+                _ => reply.push_str("E01"),
+            },
+            Some(b'P') => {
+                let eq = args.iter().position(|&c| c == b'=').unwrap_or(args.len());
+                match (
+                    parse_hex(&args[..eq]),
+                    args.get(eq + 1..).and_then(parse_hex_le),
+                ) {
+                    (Some(REG_PC), Some(value)) => *pc = value,
+                    (Some(n), Some(value)) if n < REG_PC => tf.set_reg(n, value),
+                    _ => {}
+                }
+                reply.push_str("OK");
+            }
+            Some(b'm') => match parse_addr_len(args, b':') {
+                Some((addr, len, _)) => {
+                    let _user = UserAccess::new();
+                    for i in 0..len.min(PACKET_SIZE / 2) {
+                        let mut byte = 0;
+                        // GDB asks for unmapped memory too, e.g. following stale pointers.
+                        match unsafe { extable::copy(&mut byte, (addr + i) as *const u8, 1) } {
+                            Ok(()) => reply.push_hex_byte(byte),
+                            Err(_) => {
+                                reply.len = 0;
+                                reply.push_str("E14");
+                                break;
+                            }
+                        }
+                    }
+                }
+                None => reply.push_str("E01"),
+            },
+            Some(b'M') => match parse_addr_len(args, b':') {
+                Some((addr, len, data)) if data.len() >= 2 * len => {
+                    let _user = UserAccess::new();
+                    let written = (0..len).try_for_each(|i| {
+                        let byte = parse_hex(&data[2 * i..2 * i + 2]).unwrap_or(0) as u8;
+                        unsafe { extable::copy((addr + i) as *mut u8, &byte, 1) }
+                    });
+                    unsafe { asm!("fence.i") };
+                    reply.push_str(if written.is_ok() { "OK" } else { "E14" });
+                }
+                _ => reply.push_str("E01"),
+            },
+            Some(b'c') => {
+                if let Some(addr) = parse_hex(args) {
+                    *pc = addr;
+                }
+                return Resume::Continue;
+            }
+            Some(b's') => {
+                if let Some(addr) = parse_hex(args) {
+                    *pc = addr;
+                }
+                return Resume::Step;
+            }
+            Some(b'D') => {
+                remove_all_breakpoints();
+                reply.push_str("OK");
+                send(&reply);
+                return Resume::Continue;
+            }
+            Some(b'k') => {
+                remove_all_breakpoints();
+                return Resume::Continue;
+            }
+            Some(op @ (b'Z' | b'z')) if args.first() == Some(&b'0') => {
+                let ok = match parse_addr_len(args.get(2..).unwrap_or(&[]), b';') {
+                    Some((addr, kind, _)) if *op == b'Z' => insert_breakpoint(addr, kind, false),
+                    Some((addr, _, _)) => remove_breakpoint(addr),
+                    None => false,
+                };
+                reply.push_str(if ok { "OK" } else { "E01" });
+            }
+            // Empty reply: command not supported.
+            _ => {}
+        }
+
+        send(&reply);
+    }
+}
+
+/// Stops in the debugger and returns the pc to resume at.
+fn stop(tf: &mut TrapFrame, pc: usize, signal: u8) -> usize {
+    let mut pc = pc;
+
+    if let Resume::Step = serve(tf, &mut pc, signal) {
+        // FIXME: Doesn't follow jumps and taken branches.
+        let next = pc + insn_len(pc);
+        insert_breakpoint(next, insn_len(next), true);
+    }
+
+    pc
+}
+
+/// Handles an `ebreak` at `sepc`.
+///
+/// Returns the pc to resume at, or `None` if the breakpoint wasn't set by the debugger.
+pub fn on_breakpoint(tf: &mut TrapFrame, sepc: usize) -> Option<usize> {
+    let breakpoint = BREAKPOINTS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|b| b.addr == sepc)
+        .copied()?;
+
+    if breakpoint.temporary {
+        remove_breakpoint(sepc);
+    }
+
+    Some(stop(tf, sepc, SIGTRAP))
+}
+
+/// Checks the console for GDB's interrupt request, called on every timer tick.
+///
+/// Returns the pc to resume at if the debugger stopped the interrupted context.
+pub fn poll_interrupt(tf: &mut TrapFrame, sepc: usize) -> Option<usize> {
+    // FIXME: Other console input is dropped.
+    match sbi::getchar() {
+        Some(INTERRUPT) => Some(stop(tf, sepc, SIGTRAP)),
+        _ => None,
+    }
+}
+
+/// Waits for a debugger after a panic. The panicked context can be inspected but not resumed.
+pub fn on_panic() -> ! {
+    // Keep the timer (and with it the watchdog) quiet while waiting.
+    crate::csr::Sstatus::clear_sie();
+
+    let mut tf = TrapFrame::zeroed();
+    let (ra, sp, pc): (usize, usize, usize);
+    unsafe {
+        asm!("mv {0}, ra", "mv {1}, sp", "auipc {2}, 0", out(reg) ra, out(reg) sp, out(reg) pc)
+    };
+    tf.set_reg(1, ra);
+    tf.set_reg(2, sp);
+
+    crate::println!("\ngdb: waiting for a debugger on the console");
+
+    loop {
+        stop(&mut tf, pc, SIGABRT);
+    }
+}
@@ -0,0 +1,131 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    mem::{PAGE_SIZE, PhysAddr},
+    slab,
+    stdlib::{kfree, kmalloc, phalloc_uninit, phrealloc, phree},
+};
+
+// The kernel heap, behind the `alloc` crate's `Box`, `Vec`, `BTreeMap` and the rest. Small
+// allocations are objects of the slab caches, larger ones a block of the buddy allocator
+// of their own. Both are aligned to their size, so any alignment up to the size of memory
+// is honored.
+//
+// Allocating before `mem::init()` panics, like `phalloc()`.
+// With `slab_debug` set, a heap object found overflowed is reported as allocated here:
+// `GlobalAlloc` doesn't pass on where `Box::new()` and the rest were called.
+
+pub struct KernelHeap;
+
+#[global_allocator]
+static HEAP: KernelHeap = KernelHeap;
+
+/// Returns the bytes an allocation of `layout` takes: a slab object or a block, as large as
+/// the alignment.
+fn block_size(layout: Layout) -> usize {
+    let size = layout.size().max(layout.align());
+    slab::size_of_object(size).unwrap_or_else(|| size.max(PAGE_SIZE).next_power_of_two())
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let size = block_size(layout);
+        let allocated = match size <= slab::MAX_SIZE {
+            true => kmalloc(size),
+            false => phalloc_uninit(size).map(|block| NonNull::new(block.as_mut_ptr()).unwrap()),
+        };
+        allocated.map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let size = block_size(layout);
+        match NonNull::new(ptr) {
+            Some(ptr) if size <= slab::MAX_SIZE => kfree(ptr),
+            _ => phree(PhysAddr::new(ptr as usize, Some(size))),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let (old, new) = (block_size(layout), block_size(new_layout));
+        // Growing within the object or block, or shrinking to half of it or more, keeps it.
+        if new == old {
+            return ptr;
+        }
+        // Blocks grow into their free buddies when they can, and move only otherwise.
+        if old > slab::MAX_SIZE && new > slab::MAX_SIZE {
+            return phrealloc(PhysAddr::new(ptr as usize, Some(old)), new)
+                .map_or(ptr::null_mut(), |block| block.as_mut_ptr());
+        }
+        let new = unsafe { self.alloc(new_layout) };
+        if !new.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem;
+    use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+
+    #[test_case]
+    fn collections_are_freed_back_to_the_buddy_allocator() {
+        let used = mem::usage().used;
+        let mut map = BTreeMap::new();
+        for i in 0..32 {
+            map.insert(i, Box::new(i * 2));
+        }
+        let mut doubled: Vec<usize> = map.values().map(|n| **n).collect();
+        doubled.extend(0..PAGE_SIZE);
+        assert_eq!(doubled[31], 62);
+        assert_eq!(doubled.len(), 32 + PAGE_SIZE);
+        drop(map);
+        drop(doubled);
+        assert_eq!(mem::usage().used, used);
+    }
+
+    #[test_case]
+    fn small_allocations_come_from_slabs() {
+        let boxed = Box::new([0u8; 24]);
+        let addr = &*boxed as *const [u8; 24] as usize;
+        let page = crate::page::get(addr).unwrap();
+        assert_ne!(page.flags() & crate::page::SLAB, 0);
+    }
+
+    #[test_case]
+    fn large_blocks_are_reallocated() {
+        let used = mem::usage().used;
+        let layout = Layout::from_size_align(2 * PAGE_SIZE, 8).unwrap();
+        let ptr = unsafe { HEAP.alloc(layout) };
+        unsafe { ptr.write_bytes(0xa5, 2 * PAGE_SIZE) };
+
+        let grown = unsafe { HEAP.realloc(ptr, layout, 3 * PAGE_SIZE) };
+        assert!(!grown.is_null());
+        assert_eq!(unsafe { *grown.add(2 * PAGE_SIZE - 1) }, 0xa5);
+        assert_eq!(mem::usage().used, used + 4 * PAGE_SIZE);
+        let layout = Layout::from_size_align(3 * PAGE_SIZE, 8).unwrap();
+        let shrunk = unsafe { HEAP.realloc(grown, layout, PAGE_SIZE + 1) };
+        // Shrinking keeps the block where it is.
+        assert_eq!(shrunk, grown);
+        unsafe { HEAP.dealloc(shrunk, Layout::from_size_align(PAGE_SIZE + 1, 8).unwrap()) };
+        assert_eq!(mem::usage().used, used);
+    }
+
+    #[test_case]
+    fn allocations_are_aligned() {
+        let layout = Layout::from_size_align(8, 4 * PAGE_SIZE).unwrap();
+        let ptr = unsafe { HEAP.alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % (4 * PAGE_SIZE), 0);
+        unsafe { HEAP.dealloc(ptr, layout) };
+    }
+}
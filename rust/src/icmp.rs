@@ -0,0 +1,173 @@
+use crate::{
+    ipv4::{self, Ipv4Header, MAX_PAYLOAD, PROTO_ICMP},
+    net::{self, Ipv4Addr, MacAddr},
+    proc,
+    sync::Mutex,
+    syscall::{self, Errno},
+    timer, trace,
+};
+
+// Internet Control Message Protocol (RFC 792), only echo for now: the kernel answers pings
+// and can send its own, which the shell's `ping` asks for with the `ping()` syscall.
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+
+const HEADER_LEN: usize = 8;
+
+// Identifier of echo requests sent by `ping()`.
+const PING_ID: u16 = 0x4f53;
+const PING_PAYLOAD: &[u8] = b"os1k ping";
+
+/// An echo reply `ping()` received.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PingReply {
+    pub from: Ipv4Addr,
+    pub seq: u16,
+    pub ttl: u8,
+    pub rtt_us: u64,
+}
+
+// The last echo reply to one of our requests, with the `time` it arrived at.
+static LAST_REPLY: Mutex<Option<(PingReply, u64)>> = Mutex::new(None);
+
+/// Writes an echo message of `ty` with `payload` to `buf`, returning its length.
+fn write_echo(buf: &mut [u8], ty: u8, id: u16, seq: u16, payload: &[u8]) -> usize {
+    let len = HEADER_LEN + payload.len();
+    buf[0] = ty;
+    buf[1] = 0;
+    buf[2..4].fill(0);
+    buf[4..6].copy_from_slice(&id.to_be_bytes());
+    buf[6..8].copy_from_slice(&seq.to_be_bytes());
+    buf[HEADER_LEN..len].copy_from_slice(payload);
+
+    let sum = ipv4::checksum(&buf[..len]);
+    buf[2..4].copy_from_slice(&sum.to_be_bytes());
+    len
+}
+
+/// Handles an ICMP message in a datagram described by `header`, received on `iface` in a
+/// frame from `src_mac`.
+pub fn receive(iface: usize, src_mac: MacAddr, header: &Ipv4Header, msg: &[u8]) {
+    if msg.len() < HEADER_LEN || ipv4::checksum(msg) != 0 {
+        return;
+    }
+    let id = u16::from_be_bytes([msg[4], msg[5]]);
+    let seq = u16::from_be_bytes([msg[6], msg[7]]);
+
+    match msg[0] {
+        TYPE_ECHO_REQUEST => {
+            // Answered straight to the sender's hardware address, without asking ARP.
+            let mut reply = [0; MAX_PAYLOAD];
+            let len = write_echo(&mut reply, TYPE_ECHO_REPLY, id, seq, &msg[HEADER_LEN..]);
+            let _ = ipv4::send_via(iface, src_mac, header.src, PROTO_ICMP, &reply[..len]);
+        }
+        TYPE_ECHO_REPLY if id == PING_ID => {
+            let reply = PingReply {
+                from: header.src,
+                seq,
+                ttl: header.ttl,
+                rtt_us: 0,
+            };
+            *LAST_REPLY.lock() = Some((reply, timer::now()));
+        }
+        ty => trace!("icmp: dropped message of type {ty}"),
+    }
+}
+
+/// Sends an echo request with sequence number `seq` to `dst` and waits up to `timeout_ms`
+/// milliseconds for the reply, as the `ping` command does.
+///
+/// Fails with `ETIMEDOUT` if no reply arrived, including when `dst`'s next hop never
+/// answered ARP.
+pub fn ping(dst: Ipv4Addr, seq: u16, timeout_ms: usize) -> Result<PingReply, Errno> {
+    let mut request = [0; HEADER_LEN + PING_PAYLOAD.len()];
+    write_echo(&mut request, TYPE_ECHO_REQUEST, PING_ID, seq, PING_PAYLOAD);
+
+    *LAST_REPLY.lock() = None;
+    let start = timer::now();
+    let deadline = timer::ticks() + timer::ms_to_ticks(timeout_ms);
+    match ipv4::send(dst, PROTO_ICMP, &request) {
+        Err(Errno::EHOSTUNREACH) => return Err(Errno::ETIMEDOUT),
+        result => result?,
+    }
+
+    while timer::ticks() < deadline {
+        net::poll();
+
+        if let Some((mut reply, arrived)) = *LAST_REPLY.lock()
+            && reply.from == dst
+            && reply.seq == seq
+        {
+            reply.rtt_us = (arrived - start) * 1_000_000 / timer::TIMEBASE_FREQ;
+            return Ok(reply);
+        }
+
+        proc::sleep(Some(timer::ticks() + 1));
+    }
+
+    Err(Errno::ETIMEDOUT)
+}
+
+/// Pings `addr`, an IPv4 address as a number, like `ping()`, storing the TTL of the reply
+/// and its round trip time in microseconds at `reply`.
+pub fn sys_ping(addr: usize, seq: usize, timeout_ms: usize, reply: usize) -> Result<usize, Errno> {
+    let pong = ping(Ipv4Addr::from_u32(addr as u32), seq as u16, timeout_ms)?;
+    let out = syscall::user_slice_mut(reply, 2 * size_of::<usize>())?;
+    out[..size_of::<usize>()].copy_from_slice(&(pong.ttl as usize).to_ne_bytes());
+    out[size_of::<usize>()..].copy_from_slice(&(pong.rtt_us as usize).to_ne_bytes());
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        eth::{self, ETHERTYPE_IPV4, EthHeader},
+        net::tests::{MockDevice, mock_interface},
+    };
+
+    #[test_case]
+    fn answers_echo_requests() {
+        let (iface, dev) = mock_interface();
+        let peer_mac = MacAddr([2, 0, 0, 0, 0, 1]);
+        let peer_ip = Ipv4Addr([10, 0, 0, 1]);
+
+        let mut frame = [0; eth::HEADER_LEN + ipv4::HEADER_LEN + HEADER_LEN + 4];
+        eth::write_header(
+            &mut frame,
+            &EthHeader {
+                dst: MockDevice::MAC,
+                src: peer_mac,
+                ethertype: ETHERTYPE_IPV4,
+            },
+        );
+        let (_, datagram) = frame.split_at_mut(eth::HEADER_LEN);
+        let header = Ipv4Header {
+            src: peer_ip,
+            dst: Ipv4Addr([10, 0, 0, 2]),
+            protocol: PROTO_ICMP,
+            ttl: 64,
+        };
+        ipv4::write_header(datagram, &header, HEADER_LEN + 4);
+        write_echo(
+            &mut datagram[ipv4::HEADER_LEN..],
+            TYPE_ECHO_REQUEST,
+            7,
+            1,
+            b"abcd",
+        );
+        eth::receive(iface, &frame);
+
+        let sent = dev.sent.lock();
+        let (eth_header, datagram) = eth::parse(&sent.0[..sent.1]).unwrap();
+        assert_eq!(eth_header.dst, peer_mac);
+        let (header, msg) = ipv4::parse(datagram).unwrap();
+        assert_eq!(header.dst, peer_ip);
+        assert_eq!(header.protocol, PROTO_ICMP);
+
+        let mut expected = [0; HEADER_LEN + 4];
+        write_echo(&mut expected, TYPE_ECHO_REPLY, 7, 1, b"abcd");
+        assert_eq!(msg, &expected[..]);
+    }
+}
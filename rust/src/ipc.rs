@@ -0,0 +1,228 @@
+use crate::{
+    proc::{self, WaitQueue},
+    signal,
+    sync::Mutex,
+    syscall::Errno,
+};
+
+const MAX_QUEUES: usize = 16;
+const QUEUE_DEPTH: usize = 8;
+pub const MSG_SIZE: usize = 64;
+
+// Permissions of processes other than the owner, which can always send and receive.
+pub const MQ_OTHERS_SEND: usize = 1 << 0;
+pub const MQ_OTHERS_RECV: usize = 1 << 1;
+
+// Flags of `SYS_MQ_SEND`/`SYS_MQ_RECV`.
+pub const MQ_NONBLOCK: usize = 1 << 0;
+
+#[derive(Clone, Copy)]
+struct Message {
+    sender: usize,
+    len: usize,
+    data: [u8; MSG_SIZE],
+}
+
+/// A bounded FIFO of messages, identified by an id chosen by its creator.
+struct Queue {
+    id: usize,
+    owner: usize,
+    perms: usize,
+    msgs: [Message; QUEUE_DEPTH],
+    head: usize,
+    len: usize,
+}
+
+impl Queue {
+    fn allows(&self, pid: usize, perm: usize) -> bool {
+        pid == self.owner || self.perms & perm != 0
+    }
+}
+
+static QUEUES: Mutex<[Option<Queue>; MAX_QUEUES]> = Mutex::new([const { None }; MAX_QUEUES]);
+
+// Processes waiting for room in, or a message on, the queue in the same slot of `QUEUES`.
+static SENDERS: [WaitQueue; MAX_QUEUES] = [const { WaitQueue::new() }; MAX_QUEUES];
+static RECEIVERS: [WaitQueue; MAX_QUEUES] = [const { WaitQueue::new() }; MAX_QUEUES];
+
+fn slot_of(queues: &[Option<Queue>; MAX_QUEUES], id: usize) -> Result<usize, Errno> {
+    queues
+        .iter()
+        .position(|q| q.as_ref().is_some_and(|q| q.id == id))
+        .ok_or(Errno::ENOENT)
+}
+
+/// Creates a message queue named `id`, owned by the calling process.
+///
+/// `perms` is a combination of `MQ_OTHERS_SEND` and `MQ_OTHERS_RECV`.
+pub fn create(id: usize, perms: usize) -> Result<(), Errno> {
+    let mut queues = QUEUES.lock();
+
+    if slot_of(&queues, id).is_ok() {
+        return Err(Errno::EEXIST);
+    }
+
+    let slot = queues
+        .iter()
+        .position(|q| q.is_none())
+        .ok_or(Errno::ENOSPC)?;
+
+    queues[slot] = Some(Queue {
+        id,
+        owner: proc::current(),
+        perms,
+        msgs: [Message {
+            sender: 0,
+            len: 0,
+            data: [0; MSG_SIZE],
+        }; QUEUE_DEPTH],
+        head: 0,
+        len: 0,
+    });
+
+    Ok(())
+}
+
+/// Destroys the queue `id`, processes blocked on it fail with `ENOENT`.
+pub fn destroy(id: usize) -> Result<(), Errno> {
+    let mut queues = QUEUES.lock();
+    let slot = slot_of(&queues, id)?;
+
+    if queues[slot]
+        .as_ref()
+        .is_some_and(|q| q.owner != proc::current())
+    {
+        return Err(Errno::EPERM);
+    }
+    queues[slot] = None;
+    drop(queues);
+
+    SENDERS[slot].wake_all();
+    RECEIVERS[slot].wake_all();
+
+    Ok(())
+}
+
+/// Appends `msg` to the queue `id`, blocking while it is full unless `nonblock` is set.
+pub fn send(id: usize, msg: &[u8], nonblock: bool) -> Result<(), Errno> {
+    send_as(proc::current(), id, msg, nonblock)
+}
+
+fn send_as(pid: usize, id: usize, msg: &[u8], nonblock: bool) -> Result<(), Errno> {
+    if msg.len() > MSG_SIZE {
+        return Err(Errno::EMSGSIZE);
+    }
+
+    loop {
+        let mut queues = QUEUES.lock();
+        let slot = slot_of(&queues, id)?;
+        let queue = queues[slot].as_mut().unwrap();
+
+        if !queue.allows(pid, MQ_OTHERS_SEND) {
+            return Err(Errno::EACCES);
+        }
+
+        if queue.len == QUEUE_DEPTH {
+            if nonblock {
+                return Err(Errno::EAGAIN);
+            }
+            SENDERS[slot].wait(queues);
+            signal::interrupted()?;
+            continue;
+        }
+
+        let tail = &mut queue.msgs[(queue.head + queue.len) % QUEUE_DEPTH];
+        tail.sender = pid;
+        tail.len = msg.len();
+        tail.data[..msg.len()].copy_from_slice(msg);
+        queue.len += 1;
+        drop(queues);
+
+        RECEIVERS[slot].wake_one();
+
+        return Ok(());
+    }
+}
+
+/// Removes the oldest message from the queue `id` into `buf`, blocking while the queue
+/// is empty unless `nonblock` is set.
+///
+/// Returns the length of the message and the pid of its sender. Messages longer than
+/// `buf` are truncated.
+pub fn recv(id: usize, buf: &mut [u8], nonblock: bool) -> Result<(usize, usize), Errno> {
+    recv_as(proc::current(), id, buf, nonblock)
+}
+
+fn recv_as(pid: usize, id: usize, buf: &mut [u8], nonblock: bool) -> Result<(usize, usize), Errno> {
+    loop {
+        let mut queues = QUEUES.lock();
+        let slot = slot_of(&queues, id)?;
+        let queue = queues[slot].as_mut().unwrap();
+
+        if !queue.allows(pid, MQ_OTHERS_RECV) {
+            return Err(Errno::EACCES);
+        }
+
+        if queue.len == 0 {
+            if nonblock {
+                return Err(Errno::EAGAIN);
+            }
+            RECEIVERS[slot].wait(queues);
+            signal::interrupted()?;
+            continue;
+        }
+
+        let msg = queue.msgs[queue.head];
+        queue.head = (queue.head + 1) % QUEUE_DEPTH;
+        queue.len -= 1;
+        drop(queues);
+
+        SENDERS[slot].wake_one();
+
+        let len = msg.len.min(buf.len());
+        buf[..len].copy_from_slice(&msg.data[..len]);
+
+        return Ok((len, msg.sender));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn messages_are_received_in_order() {
+        create(0x100, 0).unwrap();
+
+        send(0x100, b"first", true).unwrap();
+        send(0x100, b"second", true).unwrap();
+
+        let mut buf = [0; MSG_SIZE];
+        assert_eq!(recv(0x100, &mut buf, true), Ok((5, proc::current())));
+        assert_eq!(&buf[..5], b"first");
+        assert_eq!(recv(0x100, &mut buf, true), Ok((6, proc::current())));
+        assert_eq!(&buf[..6], b"second");
+        assert_eq!(recv(0x100, &mut buf, true), Err(Errno::EAGAIN));
+
+        destroy(0x100).unwrap();
+        assert_eq!(recv(0x100, &mut buf, true), Err(Errno::ENOENT));
+    }
+
+    #[test_case]
+    fn full_queue_and_permissions() {
+        create(0x101, MQ_OTHERS_SEND).unwrap();
+        assert_eq!(create(0x101, 0), Err(Errno::EEXIST));
+
+        for _ in 0..QUEUE_DEPTH {
+            send_as(5, 0x101, b"x", true).unwrap();
+        }
+        assert_eq!(send(0x101, b"x", true), Err(Errno::EAGAIN));
+        assert_eq!(send(0x101, &[0; MSG_SIZE + 1], true), Err(Errno::EMSGSIZE));
+
+        let mut buf = [0; 1];
+        assert_eq!(recv_as(5, 0x101, &mut buf, true), Err(Errno::EACCES));
+        assert_eq!(recv(0x101, &mut buf, true), Ok((1, 5)));
+
+        destroy(0x101).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    arp,
+    eth::{self, ETHERTYPE_IPV4},
+    icmp,
+    net::{self, Ipv4Addr, MAX_FRAME, MAX_INTERFACES, MacAddr},
+    syscall::Errno,
+    trace, udp,
+};
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_UDP: u8 = 17;
+
+pub const HEADER_LEN: usize = 20;
+/// Largest payload that fits in a single Ethernet frame, nothing is fragmented on sending.
+pub const MAX_PAYLOAD: usize = MAX_FRAME - eth::HEADER_LEN - HEADER_LEN;
+
+const DEFAULT_TTL: u8 = 64;
+// How long sending waits for the next hop to answer ARP.
+const ARP_TIMEOUT_MS: usize = 1000;
+// The "more fragments" flag and the fragment offset of `flags_fragment`.
+const FLAG_MF: u16 = 1 << 13;
+const FRAGMENT_OFFSET: u16 = 0x1fff;
+
+// Identification of the next datagram sent.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// The fields of an IPv4 header the stack looks at, options are skipped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ipv4Header {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+}
+
+/// Returns the Internet checksum (RFC 1071) of `data`, starting from the partial sum `sum`.
+pub fn checksum_with(mut sum: u32, data: &[u8]) -> u16 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Returns the partial checksum of the pseudo header TCP and UDP checksums cover.
+pub fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let words = [src.0, dst.0]
+        .map(|a| u16::from_be_bytes([a[0], a[1]]) as u32 + u16::from_be_bytes([a[2], a[3]]) as u32);
+    words[0] + words[1] + protocol as u32 + len as u32
+}
+
+/// Returns the Internet checksum of `data`, which is zero for data including a valid checksum.
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_with(0, data)
+}
+
+/// Splits `packet` into its header and payload, dropping invalid packets and fragments.
+pub fn parse(packet: &[u8]) -> Option<(Ipv4Header, &[u8])> {
+    let version = packet.first()? >> 4;
+    let header_len = (packet[0] & 0xf) as usize * 4;
+    if version != 4 || header_len < HEADER_LEN || packet.len() < header_len {
+        return None;
+    }
+    if checksum(&packet[..header_len]) != 0 {
+        return None;
+    }
+
+    // Frames may be padded beyond the datagram.
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if total_len < header_len || total_len > packet.len() {
+        return None;
+    }
+
+    // FIXME: Fragments aren't reassembled, they are dropped.
+    let flags_fragment = u16::from_be_bytes([packet[6], packet[7]]);
+    if flags_fragment & (FLAG_MF | FRAGMENT_OFFSET) != 0 {
+        return None;
+    }
+
+    Some((
+        Ipv4Header {
+            src: Ipv4Addr(packet[12..16].try_into().unwrap()),
+            dst: Ipv4Addr(packet[16..20].try_into().unwrap()),
+            protocol: packet[9],
+            ttl: packet[8],
+        },
+        &packet[header_len..total_len],
+    ))
+}
+
+/// Writes a header without options for a datagram carrying `payload_len` bytes to the
+/// start of `buf`.
+pub fn write_header(buf: &mut [u8], header: &Ipv4Header, payload_len: usize) {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed) as u16;
+    let header_bytes = &mut buf[..HEADER_LEN];
+
+    header_bytes.fill(0);
+    header_bytes[0] = 0x45;
+    header_bytes[2..4].copy_from_slice(&((HEADER_LEN + payload_len) as u16).to_be_bytes());
+    header_bytes[4..6].copy_from_slice(&id.to_be_bytes());
+    header_bytes[8] = header.ttl;
+    header_bytes[9] = header.protocol;
+    header_bytes[12..16].copy_from_slice(&header.src.0);
+    header_bytes[16..20].copy_from_slice(&header.dst.0);
+
+    let sum = checksum(header_bytes);
+    header_bytes[10..12].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// Returns the interface to send to `dst` out of, and the next hop on its link: `dst`
+/// itself on a directly connected network, the interface's gateway otherwise.
+pub fn route(dst: Ipv4Addr) -> Result<(usize, Ipv4Addr), Errno> {
+    let configs =
+        (0..MAX_INTERFACES).filter_map(|iface| Some((iface, net::ip_config(iface).ok()?)));
+    let mut gateway = None;
+
+    for (iface, ip) in configs.filter(|(_, ip)| ip.addr != Ipv4Addr::UNSPECIFIED) {
+        let mask = ip.netmask.to_u32();
+        let on_link = match dst {
+            // Broadcasts are for the network, not the loopback interface.
+            Ipv4Addr::BROADCAST => !net::is_loopback(iface),
+            _ => dst.to_u32() & mask == ip.addr.to_u32() & mask,
+        };
+        if on_link {
+            return Ok((iface, dst));
+        }
+        if gateway.is_none() && ip.gateway != Ipv4Addr::UNSPECIFIED {
+            gateway = Some((iface, ip.gateway));
+        }
+    }
+
+    gateway.ok_or(Errno::ENETUNREACH)
+}
+
+/// Sends `payload` as a datagram of `protocol` to `dst` on the link of `iface`, whose
+/// hardware address is `dst_mac`.
+pub fn send_via(
+    iface: usize,
+    dst_mac: MacAddr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), Errno> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(Errno::EMSGSIZE);
+    }
+
+    let mut packet = [0; HEADER_LEN + MAX_PAYLOAD];
+    let header = Ipv4Header {
+        src: net::ip_config(iface)?.addr,
+        dst,
+        protocol,
+        ttl: DEFAULT_TTL,
+    };
+    write_header(&mut packet, &header, payload.len());
+    packet[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+
+    eth::send(
+        iface,
+        dst_mac,
+        ETHERTYPE_IPV4,
+        &packet[..HEADER_LEN + payload.len()],
+    )
+}
+
+/// Sends `payload` as a datagram of `protocol` to `dst`.
+///
+/// Blocks while the next hop's hardware address is resolved, failing with `EHOSTUNREACH`
+/// if it doesn't answer within `ARP_TIMEOUT_MS`.
+pub fn send(dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), Errno> {
+    let (iface, next_hop) = route(dst)?;
+    let dst_mac = arp::resolve_wait(iface, next_hop, ARP_TIMEOUT_MS)?;
+    send_via(iface, dst_mac, dst, protocol, payload)
+}
+
+/// Handles a datagram received on `iface` in a frame from `src_mac`.
+pub fn receive(iface: usize, src_mac: MacAddr, packet: &[u8]) {
+    let Some((header, payload)) = parse(packet) else {
+        return;
+    };
+    let Ok(ip) = net::ip_config(iface) else {
+        return;
+    };
+    if header.dst != ip.addr && header.dst != Ipv4Addr::BROADCAST {
+        return;
+    }
+
+    match header.protocol {
+        PROTO_ICMP => icmp::receive(iface, src_mac, &header, payload),
+        PROTO_UDP => udp::receive(&header, payload),
+        protocol => trace!("ipv4: dropped datagram of protocol {protocol}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn header_round_trips() {
+        let header = Ipv4Header {
+            src: Ipv4Addr([10, 0, 0, 2]),
+            dst: Ipv4Addr([10, 0, 0, 1]),
+            protocol: PROTO_ICMP,
+            ttl: DEFAULT_TTL,
+        };
+        let mut packet = [0; HEADER_LEN + 3];
+        write_header(&mut packet, &header, 2);
+        packet[HEADER_LEN..].copy_from_slice(&[1, 2, 0xee]);
+
+        // The trailing byte is frame padding, not payload.
+        assert_eq!(parse(&packet), Some((header, &[1, 2][..])));
+
+        packet[8] -= 1;
+        assert_eq!(parse(&packet), None);
+    }
+
+    #[test_case]
+    fn checksum_matches_rfc_1071_example() {
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&data), !0xddf2);
+    }
+}
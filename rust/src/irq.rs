@@ -0,0 +1,373 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{
+    MAX_HARTS,
+    csr::Sie,
+    dtb, hart_id,
+    mem::{self, PAGE_SIZE, RegionKind},
+    println, proc,
+    sync::{Mutex, OnceCell},
+    syscall::Errno,
+    timer,
+};
+
+// Device interrupts, which arrive through the PLIC (RISC-V's platform-level interrupt
+// controller) as supervisor external interrupts. A driver asks for its interrupt line with
+// `request_irq()`, and the PLIC passes it on to the harts of the line's affinity (all of
+// them unless `set_affinity()` says otherwise), where the handler runs in interrupt
+// context.
+//
+// Drivers don't know line numbers: `of_node()` translates the interrupt a device's node in
+// the device tree raises into an `Irq` of the PLIC, if the PLIC is the node's interrupt
+// parent.
+//
+// Work that takes long shouldn't hold up the hart with interrupts off, so a handler can be
+// threaded (`request_threaded_irq()`): its hard part only acknowledges the device and asks
+// for the thread part to run, which it then does in the `irqd` kernel process, scheduled
+// like any other. The line stays masked until then, so a level-triggered device doesn't
+// interrupt again before it was dealt with. Kernel code isn't preempted, a thread part
+// that runs long should give up the hart now and then.
+//
+// FIXME: Only the boot hart enables external interrupts, the others are parked.
+
+pub const MAX_IRQS: usize = 64;
+
+// PLIC registers, as offsets from the base.
+const PRIORITY: usize = 0x0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4;
+
+/// An interrupt line of the PLIC, as `of_node()` finds it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Irq(usize);
+
+impl Irq {
+    /// Returns the PLIC's number of the line.
+    pub fn line(&self) -> usize {
+        self.0
+    }
+}
+
+/// What a hard handler did about its interrupt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrqReturn {
+    /// Not from this device.
+    None,
+    Handled,
+    /// Acknowledged, the thread part has the rest to do.
+    WakeThread,
+}
+
+/// Handles interrupt `irq`.
+pub type Handler = fn(usize) -> IrqReturn;
+
+#[derive(Clone, Copy)]
+struct Action {
+    name: &'static str,
+    handler: Handler,
+    thread: Option<fn(usize)>,
+}
+
+static PLIC: OnceCell<usize> = OnceCell::new();
+// What the PLIC's node is referred to by as an interrupt parent.
+static PLIC_PHANDLE: OnceCell<u32> = OnceCell::new();
+// Never removed, so they can be used without a lock in interrupt handlers.
+static ACTIONS: [OnceCell<Action>; MAX_IRQS] = [const { OnceCell::new() }; MAX_IRQS];
+// Taken by `request_irq()`, before `ACTIONS` is set.
+static CLAIMED: Mutex<[bool; MAX_IRQS]> = Mutex::new([false; MAX_IRQS]);
+// Harts each line goes to, as a bit mask.
+static AFFINITY: [AtomicUsize; MAX_IRQS] = [const { AtomicUsize::new(usize::MAX) }; MAX_IRQS];
+static COUNTS: [AtomicUsize; MAX_IRQS] = [const { AtomicUsize::new(0) }; MAX_IRQS];
+// Lines whose thread part is due.
+static THREAD_PENDING: [AtomicBool; MAX_IRQS] = [const { AtomicBool::new(false) }; MAX_IRQS];
+// Pid of `irqd` as a bit mask, 0 until it runs.
+static IRQD: AtomicUsize = AtomicUsize::new(0);
+
+/// The PLIC context of supervisor mode on `hart`, as QEMU's virt machine numbers them.
+fn context(hart: usize) -> usize {
+    2 * hart + 1
+}
+
+fn reg(offset: usize) -> Option<*mut u32> {
+    PLIC.get().map(|&base| (base + offset) as *mut u32)
+}
+
+/// Routes `irq` to the harts in its affinity, or to none if `masked`.
+fn route(irq: usize, masked: bool) {
+    let affinity = if masked {
+        0
+    } else {
+        AFFINITY[irq].load(Ordering::Relaxed)
+    };
+    for hart in 0..MAX_HARTS {
+        let Some(enable) = reg(ENABLE + ENABLE_STRIDE * context(hart) + 4 * (irq / 32)) else {
+            return;
+        };
+        unsafe {
+            let bits = enable.read_volatile();
+            let bit = 1 << (irq % 32);
+            let bits = if affinity & (1 << hart) != 0 {
+                bits | bit
+            } else {
+                bits & !bit
+            };
+            enable.write_volatile(bits);
+        }
+    }
+}
+
+/// Returns the line of the interrupt `node` raises, `None` if it raises none, or not
+/// through the PLIC, or on a line past `MAX_IRQS`.
+pub fn of_node(node: &dtb::Node) -> Option<Irq> {
+    let interrupt = node.interrupt?;
+    if Some(&interrupt.parent) != PLIC_PHANDLE.get() {
+        return None;
+    }
+    line(interrupt.cell as usize)
+}
+
+/// Returns line `irq` if there is such a line, 0 being no interrupt.
+fn line(irq: usize) -> Option<Irq> {
+    (1..MAX_IRQS).contains(&irq).then_some(Irq(irq))
+}
+
+fn request(irq: Irq, action: Action) -> Result<(), Errno> {
+    let irq = irq.0;
+    let mut claimed = CLAIMED.lock();
+    if claimed[irq] {
+        return Err(Errno::EBUSY);
+    }
+    claimed[irq] = true;
+    drop(claimed);
+
+    ACTIONS[irq].get_or_init(|| action);
+    if let Some(priority) = reg(PRIORITY + 4 * irq) {
+        unsafe { priority.write_volatile(1) };
+    }
+    route(irq, false);
+    Ok(())
+}
+
+/// Calls `handler` in interrupt context whenever interrupt line `irq` is raised.
+///
+/// Fails with `EBUSY` if it has a handler already.
+pub fn request_irq(irq: Irq, name: &'static str, handler: Handler) -> Result<(), Errno> {
+    request(
+        irq,
+        Action {
+            name,
+            handler,
+            thread: None,
+        },
+    )
+}
+
+/// Like `request_irq()`, and runs `thread` in `irqd` whenever `handler` returns
+/// `IrqReturn::WakeThread`, the line masked until it did.
+pub fn request_threaded_irq(
+    irq: Irq,
+    name: &'static str,
+    handler: Handler,
+    thread: fn(usize),
+) -> Result<(), Errno> {
+    request(
+        irq,
+        Action {
+            name,
+            handler,
+            thread: Some(thread),
+        },
+    )
+}
+
+/// Sends interrupt line `irq` to the harts in the bit mask `harts` only.
+///
+/// Fails with `EINVAL` if none of the harts exists.
+pub fn set_affinity(irq: Irq, harts: usize) -> Result<(), Errno> {
+    let irq = irq.0;
+    let all = usize::MAX >> (usize::BITS as usize - MAX_HARTS);
+    if harts & all == 0 {
+        return Err(Errno::EINVAL);
+    }
+    AFFINITY[irq].store(harts & all, Ordering::Relaxed);
+    if ACTIONS[irq].get().is_some() && !THREAD_PENDING[irq].load(Ordering::Relaxed) {
+        route(irq, false);
+    }
+    Ok(())
+}
+
+/// Calls `f` with every requested line, its name, how many times it was raised, its
+/// affinity, and whether it is threaded.
+pub fn for_each(mut f: impl FnMut(usize, &'static str, usize, usize, bool)) {
+    for (irq, action) in ACTIONS.iter().enumerate() {
+        if let Some(action) = action.get() {
+            let count = COUNTS[irq].load(Ordering::Relaxed);
+            let affinity = AFFINITY[irq].load(Ordering::Relaxed);
+            f(irq, action.name, count, affinity, action.thread.is_some());
+        }
+    }
+}
+
+/// Runs the hard handler of `irq`, leaving the thread part for `irqd` if it asks.
+fn dispatch(irq: usize) {
+    let Some(action) = ACTIONS.get(irq).and_then(OnceCell::get) else {
+        return;
+    };
+    COUNTS[irq].fetch_add(1, Ordering::Relaxed);
+    if (action.handler)(irq) == IrqReturn::WakeThread && action.thread.is_some() {
+        route(irq, true);
+        THREAD_PENDING[irq].store(true, Ordering::Release);
+        proc::wake_deferred(IRQD.load(Ordering::Relaxed));
+    }
+}
+
+/// Handles a supervisor external interrupt, every line the PLIC has pending for this hart.
+pub fn on_external() {
+    let Some(claim) = reg(CONTEXT + CONTEXT_STRIDE * context(hart_id()) + CLAIM) else {
+        return;
+    };
+    loop {
+        let irq = unsafe { claim.read_volatile() } as usize;
+        if irq == 0 {
+            break;
+        }
+        dispatch(irq);
+        unsafe { claim.write_volatile(irq as u32) };
+    }
+}
+
+/// Runs the thread parts that are due, returning whether there were any.
+fn run_threads() -> bool {
+    let mut ran = false;
+    for (irq, pending) in THREAD_PENDING.iter().enumerate() {
+        if !pending.swap(false, Ordering::Acquire) {
+            continue;
+        }
+        if let Some(thread) = ACTIONS[irq].get().and_then(|action| action.thread) {
+            thread(irq);
+        }
+        route(irq, false);
+        ran = true;
+    }
+    ran
+}
+
+/// `irqd`, the kernel process running the thread parts of threaded handlers.
+fn irqd() -> ! {
+    IRQD.store(1 << proc::current(), Ordering::Relaxed);
+    loop {
+        if !run_threads() {
+            // A wakeup just before it blocks may be missed, it then looks again in a second.
+            proc::sleep(Some(timer::ticks() + timer::hz()));
+        }
+    }
+}
+
+/// Starts `irqd` if a handler is threaded, once there are processes.
+pub fn start_irqd() {
+    let threaded = ACTIONS
+        .iter()
+        .any(|action| action.get().is_some_and(|action| action.thread.is_some()));
+    if threaded {
+        proc::new(irqd as *const () as usize);
+    }
+}
+
+crate::register_subsystem!(Arch, "irq", init);
+
+fn init() {
+    let mut plic = None;
+    for compatible in ["riscv,plic0", "sifive,plic-1.0.0"] {
+        dtb::for_each_compatible(compatible, |node| {
+            plic.get_or_insert(node);
+        });
+    }
+    let Some(dtb::Node {
+        start: base,
+        phandle,
+        ..
+    }) = plic
+    else {
+        return;
+    };
+    // Only what is used: priorities and enables, and the contexts of the harts.
+    mem::add_region(RegionKind::Mmio, base, base + PAGE_SIZE);
+    mem::add_region(RegionKind::Mmio, base + ENABLE, base + ENABLE + PAGE_SIZE);
+    let contexts = base + CONTEXT;
+    mem::add_region(
+        RegionKind::Mmio,
+        contexts,
+        contexts + CONTEXT_STRIDE * (context(MAX_HARTS - 1) + 1),
+    );
+    PLIC.get_or_init(|| base);
+    if let Some(phandle) = phandle {
+        PLIC_PHANDLE.get_or_init(|| phandle);
+    }
+
+    for hart in 0..MAX_HARTS {
+        if let Some(threshold) = reg(CONTEXT + CONTEXT_STRIDE * context(hart) + THRESHOLD) {
+            unsafe { threshold.write_volatile(0) };
+        }
+    }
+    Sie::set_seie();
+    println!("irq: PLIC at {base:#x}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static THREAD_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    #[test_case]
+    fn threaded_handlers_defer_to_irqd() {
+        // No PLIC to route lines with, the handlers are called as if it had.
+        assert_eq!(line(0), None);
+        assert_eq!(line(MAX_IRQS), None);
+        let irq = MAX_IRQS - 1;
+        let line = line(irq).unwrap();
+        let hard = |_| IrqReturn::WakeThread;
+        let thread = |_| {
+            THREAD_RUNS.fetch_add(1, Ordering::Relaxed);
+        };
+        assert_eq!(request_threaded_irq(line, "test", hard, thread), Ok(()));
+        assert_eq!(request_irq(line, "test", hard), Err(Errno::EBUSY));
+        assert_eq!(set_affinity(line, 0), Err(Errno::EINVAL));
+        assert_eq!(set_affinity(line, 1), Ok(()));
+
+        dispatch(irq);
+        dispatch(irq);
+        assert_eq!(THREAD_RUNS.load(Ordering::Relaxed), 0);
+        assert!(run_threads());
+        assert_eq!(THREAD_RUNS.load(Ordering::Relaxed), 1);
+        assert!(!run_threads());
+
+        let mut found = None;
+        for_each(|line, name, count, affinity, threaded| {
+            if line == irq {
+                found = Some((name, count, affinity, threaded));
+            }
+        });
+        assert_eq!(found, Some(("test", 2, 1, true)));
+    }
+
+    #[test_case]
+    fn only_lines_of_the_plic_are_translated() {
+        let node = |parent, cell| dtb::Node {
+            start: 0x1000_1000,
+            end: 0x1000_2000,
+            phandle: None,
+            interrupt: Some(dtb::Interrupt { parent, cell }),
+        };
+        let plic = PLIC_PHANDLE.get().copied();
+        assert_eq!(of_node(&node(plic.unwrap_or(0) + 1, 1)), None);
+        if let Some(plic) = plic {
+            assert_eq!(of_node(&node(plic, 10)).map(|irq| irq.line()), Some(10));
+            assert_eq!(of_node(&node(plic, MAX_IRQS as u32)), None);
+        }
+    }
+}
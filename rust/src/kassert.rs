@@ -0,0 +1,84 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{print, println, sysctl::Tunable};
+
+/// What a failed `kassert!` does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Mode {
+    /// Panics, like `assert!`.
+    Panic = 0,
+    /// Prints a warning and lets the caller continue.
+    Log = 1,
+    /// Only counts the failure, see `failures()`.
+    Count = 2,
+}
+
+// The `kassert-log` feature makes release images log broken invariants instead of halting.
+const DEFAULT_MODE: Mode = if cfg!(feature = "kassert-log") {
+    Mode::Log
+} else {
+    Mode::Panic
+};
+
+/// What failed assertions do, a `Mode` by number, `/proc/sys/kassert_mode`: e.g.
+/// `sysctl.kassert_mode=2` on the command line only counts them.
+pub static MODE: Tunable = Tunable::new(
+    "kassert_mode",
+    DEFAULT_MODE as usize,
+    0,
+    Mode::Count as usize,
+);
+
+static FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+fn mode() -> Mode {
+    match MODE.get() {
+        1 => Mode::Log,
+        2 => Mode::Count,
+        _ => Mode::Panic,
+    }
+}
+
+/// Returns the number of failed assertions since boot, in any mode.
+pub fn failures() -> usize {
+    FAILURES.load(Ordering::Relaxed)
+}
+
+/// Called by `kassert!` when an assertion fails.
+pub fn fail(file: &str, line: u32, args: fmt::Arguments) {
+    FAILURES.fetch_add(1, Ordering::Relaxed);
+
+    match mode() {
+        Mode::Log => println!("WARNING: {file}:{line}: assertion failed: {args}"),
+        Mode::Count => {}
+        Mode::Panic => {
+            print!("PANIC: {file}:{line}: assertion failed: {args}");
+            crate::macros::halt();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MODE, Mode, failures};
+
+    #[test_case]
+    fn count_mode_continues() {
+        let before = failures();
+        let previous = MODE.get();
+        MODE.set(Mode::Count as usize).unwrap();
+
+        let two = core::hint::black_box(2);
+        let held = crate::kassert!(two > 1);
+        let broken = crate::kassert_eq!(two, 3, "arithmetic is broken");
+
+        MODE.set(previous).unwrap();
+        assert!(held);
+        assert!(!broken);
+        assert_eq!(failures(), before + 1);
+    }
+}
@@ -1,19 +1,118 @@
 #![feature(naked_functions)]
+#![cfg_attr(test, feature(custom_test_frameworks))]
+#![cfg_attr(test, test_runner(crate::ktest::runner))]
+#![cfg_attr(test, reexport_test_harness_main = "test_main")]
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+
+mod aging;
+mod alarm;
+mod arp;
+mod banner;
+mod block;
+mod bootprof;
+mod buddy;
+mod cmdline;
+mod console;
+mod cpu;
+mod crash;
+mod cred;
+mod csr;
+mod cwd;
+mod dcache;
+mod dhcp;
+mod dtb;
+mod elf;
+mod emulate;
+mod eth;
+mod event;
+mod executor;
+mod extable;
+#[cfg(any(test, feature = "fault-inject"))]
+mod fault;
+mod fbcon;
+mod file;
+mod font;
+mod fpu;
+mod futex;
+#[cfg(feature = "gdb")]
+mod gdb;
+mod heap;
+mod icmp;
+mod ipc;
+mod ipv4;
+mod irq;
+mod kassert;
+mod ksyms;
+#[cfg(test)]
+mod ktest;
+mod loadavg;
+mod loopback;
 mod macros;
 mod mem;
+mod misaligned;
+mod mmap;
+mod net;
+mod netconsole;
+mod page;
+mod pagecache;
+mod partition;
+mod perf;
+mod pipe;
+mod poll;
 mod proc;
+mod procfs;
+mod profile;
+mod ramfs;
+mod random;
+mod rcu;
+mod reboot;
+mod rlimit;
 mod sbi;
+mod signal;
+mod slab;
 mod stdlib;
+mod subsys;
+mod swap;
 mod sync;
+mod syscall;
+mod sysctl;
+mod tarfs;
+mod timer;
+mod tracepoint;
 mod trap;
+mod tty;
+mod uaccess;
+mod udp;
+mod vfs;
+mod virtio;
+mod virtio_blk;
+mod virtio_console;
+mod virtio_input;
 mod vm;
+mod watchdog;
+mod workqueue;
 
-use core::{arch::asm, hint::spin_loop, panic::PanicInfo};
+use core::{
+    arch::{asm, naked_asm},
+    hint::spin_loop,
+    panic::PanicInfo,
+};
+use sync::{Barrier, OnceCell};
 use trap::trap_entry;
 
+/// Maximum number of harts the kernel keeps per-hart state for (QEMU virt supports up to 8).
+#[cfg(feature = "smp")]
+pub const MAX_HARTS: usize = 8;
+#[cfg(not(feature = "smp"))]
+pub const MAX_HARTS: usize = 1;
+
+/// Bytes of the stack each hart boots on, `kernel.ld` reserves 8 of them at `__stacks`.
+pub const BOOT_STACK_SIZE: usize = 128 * 1024;
+const _: () = assert!(MAX_HARTS <= 8, "kernel.ld only reserves 8 boot stacks.");
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     panic!("{info}")
@@ -22,70 +121,149 @@ fn panic(info: &PanicInfo) -> ! {
 unsafe extern "C" {
     static __bss: u8;
     static __bss_end: u8;
-    static __stack_top: u8;
+    static __stacks: u8;
     static __free_ram: u8;
     static __free_ram_end: u8;
     static __allocator_mem: u8;
     static __allocator_mem_end: u8;
     static __kernel_base: u8;
+    static __ksyms: u8;
+    static __ksyms_end: u8;
+}
+
+// Where the harts meet before the scheduler starts, for as many as came up.
+static HARTS_UP: OnceCell<Barrier> = OnceCell::new();
+
+/// Returns the id of the calling hart, which `boot` keeps in `tp`.
+pub fn hart_id() -> usize {
+    let id: usize;
+    unsafe { asm!("mv {0}, tp", out(reg) id) };
+    id
 }
 
-unsafe fn kernel_init(hart_id: usize, _dtb_addr: usize) {
-    write_csr!("stvec", trap_entry as *const ());
+unsafe fn kernel_init(hart_id: usize, dtb_addr: usize) {
+    // Interrupts are off until the subsystems handling them turn them on, whatever the
+    // firmware left enabled, and user pages are only accessed through `uaccess`.
+    csr::Sstatus::read()
+        .with_sie(false)
+        .with_spie(false)
+        .with_spp(csr::Privilege::Supervisor)
+        .with_sum(false)
+        .write();
+    csr::Sie::read()
+        .with_ssie(false)
+        .with_stie(false)
+        .with_seie(false)
+        .write();
+    csr::set_stvec(trap_entry as *const () as usize, csr::TrapMode::Vectored);
+    // Running in the kernel, see `trap_entry`.
+    csr::set_sscratch(0);
+    // Programs read the counters themselves, for timing and benchmarks. Reads the
+    // platform doesn't allow still trap, and `time` is emulated then.
+    csr::Scounteren::read()
+        .with_cy(true)
+        .with_tm(true)
+        .with_ir(true)
+        .write();
 
     if hart_id != 0 {
         // FIXME: when running in debug mode, value is not zero
+        #[cfg(feature = "smp")]
         println!("hart_id:{}", hart_id);
-        loop {
-            spin_loop();
-        }
+        // The boot hart sets the barrier up once it knows how many harts there are.
+        let harts_up = loop {
+            match HARTS_UP.get() {
+                Some(barrier) => break barrier,
+                None => spin_loop(),
+            }
+        };
+        harts_up.wait();
+        reboot::park();
     }
 
     let bss_start = unsafe { &__bss } as *const u8 as *mut u8;
     let bss_end = unsafe { &__bss_end } as *const u8;
     unsafe { bss_start.write_bytes(0, bss_end.offset_from(bss_start) as usize) };
 
-    let alloc_mem_start = unsafe { &__allocator_mem } as *const u8 as *mut u8;
-    let alloc_mem_end = unsafe { &__allocator_mem_end } as *const u8;
-    unsafe { alloc_mem_start.write_bytes(0, alloc_mem_end.offset_from(alloc_mem_start) as usize) };
+    // Memory for anything needed before `mem::init()`, see `mem::boot_alloc()`.
+    let alloc_mem_start = unsafe { &__allocator_mem } as *const u8 as usize;
+    let alloc_mem_end = unsafe { &__allocator_mem_end } as *const u8 as usize;
+    mem::boot_init(alloc_mem_start, alloc_mem_end);
+
+    dtb::init(dtb_addr);
 
-    // FIXME: Either this or zeroing during the allocation
     // FIXME: Should be replaced with the actual memory addresses acquired by parsing dtb
     let ram_start = unsafe { &__free_ram } as *const u8 as *mut u8;
-    let ram_end = unsafe { &__free_ram_end } as *const u8;
-    unsafe { ram_start.write_bytes(0, ram_end.offset_from(ram_start) as usize) };
-
-    mem::init(
-        ram_start as usize,
-        ram_end as usize,
-        alloc_mem_start as usize,
-        alloc_mem_end as usize,
-    );
+    let mut ram_end = unsafe { &__free_ram_end } as *const u8;
+    // The initrd may have been loaded into free RAM, which then ends where it starts.
+    if let Some((initrd, _)) = dtb::initrd()
+        && (ram_start as usize..ram_end as usize).contains(&initrd)
+    {
+        ram_end = initrd as *const u8;
+    }
+    // Free RAM isn't zeroed here, the allocators zero what they hand out unless asked not
+    // to (see `phalloc_uninit()`).
 
-    proc::init()
-}
+    let kernel_base = unsafe { &__kernel_base } as *const u8 as usize;
+    bootprof::measure("mem", || {
+        // The kernel image, the boot allocator's memory and the initrd are reserved.
+        let ram_top = unsafe { &__free_ram_end } as *const u8 as usize;
+        page::init(kernel_base, ram_top, ram_start as usize..ram_end as usize);
+        mem::init(ram_start as usize, ram_end as usize)
+    });
+    // All of RAM, or at least what the kernel knows it has if there is no device tree.
+    let (ram_base, ram_top) = dtb::memory().unwrap_or((kernel_base, ram_end as usize));
+    mem::add_region(mem::RegionKind::Ram, ram_base, ram_top);
 
-fn delay() {
-    for _ in 0..10000000 {
-        core::hint::spin_loop();
+    bootprof::measure("cmdline", cmdline::init);
+    if let Some(level) = cmdline::get_usize("loglevel") {
+        // Levels past the most verbose one print everything too.
+        let _ = macros::LOGLEVEL.set(level.min(macros::LOGLEVEL_TRACE));
     }
+
+    // Everything else registers itself with `register_subsystem!`.
+    subsys::init_all();
+
+    #[cfg(test)]
+    test_main();
 }
 
-#[unsafe(no_mangle)]
-fn proc_a_entry() {
-    loop {
-        print!("a");
-        proc::give_up();
-        delay();
-    }
+/// Starts the other harts the kernel keeps state for that the firmware left stopped, and
+/// waits for all that came up at `HARTS_UP`.
+fn start_harts(dtb_addr: usize) {
+    let hsm = sbi::probe_extension(sbi::EID_HSM);
+    let others = (0..MAX_HARTS).filter(|&hart| hart != hart_id() && cpu::features(hart).is_some());
+    // Without HSM, the firmware started every hart.
+    let started = others
+        .filter(|&hart| match hsm {
+            true => match sbi::hart_get_status(hart) {
+                Ok(sbi::HART_STOPPED) => {
+                    sbi::hart_start(hart, boot as *const () as usize, dtb_addr) == 0
+                }
+                status => status.is_ok(),
+            },
+            false => true,
+        })
+        .count();
+    HARTS_UP.get_or_init(|| Barrier::new(started + 1)).wait();
 }
 
-#[unsafe(no_mangle)]
-fn proc_b_entry() {
-    loop {
-        print!("b");
-        proc::give_up();
-        delay();
+/// The first user process, running the shell from the initrd, or the program `init=` names.
+fn init_entry() {
+    let path = cmdline::get("init").unwrap_or("/bin/sh");
+    if let Err(err) = console::open_stdio() {
+        println!("init: can't open the console: {err:?}");
+    }
+    match proc::exec(path, &[path.as_bytes()], &[b"PATH=/bin"]) {
+        Ok((entry, sp)) => {
+            let mut frame = trap::TrapFrame::zeroed();
+            frame.set_sp(sp);
+            proc::enter_user(entry, frame)
+        }
+        Err(err) => {
+            println!("init: can't run {path}: {err:?}");
+            proc::exit(proc::exit_status(1));
+        }
     }
 }
 
@@ -94,33 +272,67 @@ unsafe fn kernel_main(hart_id: usize, dtb_addr: usize) -> ! {
         kernel_init(hart_id, dtb_addr);
     }
 
-    println!("Hello, World!");
+    banner::print();
+    start_harts(dtb_addr);
 
     // creating idle proc
     proc::new(0);
 
-    proc::new(proc_a_entry as usize);
-    proc::new(proc_b_entry as usize);
+    // Runs the kernel's async tasks.
+    proc::new(executor::run as *const () as usize);
+    irq::start_irqd();
+    workqueue::start_workers();
+
+    // On behalf of the idle process, whose limits and credentials it starts with.
+    if let Err(err) = proc::spawn(init_entry as *const () as usize) {
+        panic!("can't start init: {err:?}");
+    }
 
-    proc::give_up();
+    bootprof::report();
+    if profile::is_running() {
+        profile::stop();
+        profile::report();
+    }
 
-    panic!("switched to idle proc");
+    // The idle process, scheduled whenever no other process is runnable.
+    loop {
+        proc::give_up();
+        // Delivers what arrived to processes blocked on sockets.
+        net::poll();
+        timer::idle();
+    }
 }
 
+/// Entered by every hart the firmware starts, with the hart id in `a0` and the device tree
+/// in `a1`. Each hart gets a stack of its own, indexed by hart id, and harts the kernel
+/// keeps no per-hart state for are parked before they touch any memory.
+///
+/// # Safety
+///
+/// Only the firmware may jump here, once per hart, it never returns.
 #[unsafe(no_mangle)]
 #[unsafe(link_section = ".text.boot")]
+#[naked]
 pub unsafe extern "C" fn boot(hart_id: usize, dtb_addr: usize) -> ! {
     unsafe {
-        asm!(
-            "mv a0, {0}",
-            "mv a1, {1}",
-            "mv sp, {2}",
-            "j {3}",
-            in(reg) hart_id,
-            in(reg) dtb_addr,
-            in(reg) &__stack_top,
-            sym kernel_main,
-            options(noreturn)
+        naked_asm!(
+            "li t0, {max_harts}",
+            "bgeu a0, t0, 2f",
+            "mv tp, a0",
+            // The top of stack `hart_id`, stacks grow down.
+            "addi t0, a0, 1",
+            "li t1, {stack_size}",
+            "mul t0, t0, t1",
+            "la sp, {stacks}",
+            "add sp, sp, t0",
+            "j {kernel_main}",
+            "2:",
+            "wfi",
+            "j 2b",
+            max_harts = const MAX_HARTS,
+            stack_size = const BOOT_STACK_SIZE,
+            stacks = sym __stacks,
+            kernel_main = sym kernel_main,
         );
     }
 }
@@ -0,0 +1,176 @@
+use core::{arch::asm, fmt};
+
+use crate::{__free_ram_end, __kernel_base, __ksyms, __ksyms_end, println};
+
+// The kernel's symbol table, so addresses can be printed as function names without
+// external tools. `ksyms.py` writes it into the `.ksyms` section of the linked kernel, in
+// this format (little endian, like RISC-V):
+//
+//   b"KSYM", count: u32
+//   count * [offset from __kernel_base: u32, name offset: u32, name length: u32]
+//   names, offsets relative to the end of the entries
+//
+// Entries are sorted by address. A kernel that wasn't run through `ksyms.py` has an empty
+// section, and every lookup fails.
+//
+// Addresses in reports go through `resolve()`: panics print a backtrace of them, found by
+// following the frame pointers the kernel is built with (`-Cforce-frame-pointers`, see
+// `.cargo/config.toml`), and so do the profiler and tracepoints.
+
+const MAGIC: [u8; 4] = *b"KSYM";
+const HEADER_LEN: usize = 8;
+// Most frames a backtrace shows.
+const MAX_FRAMES: usize = 32;
+
+// One entry, as laid out in the section.
+type Entry = [u32; 3];
+
+struct Table {
+    entries: &'static [Entry],
+    names: &'static [u8],
+}
+
+fn table() -> Option<Table> {
+    let start = unsafe { &__ksyms } as *const u8;
+    let end = unsafe { &__ksyms_end } as *const u8;
+    let section = unsafe { core::slice::from_raw_parts(start, end.offset_from(start) as usize) };
+
+    if section[..4] != MAGIC {
+        return None;
+    }
+    let count = u32::from_ne_bytes(section[4..HEADER_LEN].try_into().unwrap()) as usize;
+    let names_start = HEADER_LEN + count * size_of::<Entry>();
+    if names_start > section.len() {
+        return None;
+    }
+
+    // The section is 8-byte aligned, so the entries after the header are 4-byte aligned.
+    let entries =
+        unsafe { core::slice::from_raw_parts(start.add(HEADER_LEN) as *const Entry, count) };
+    Some(Table {
+        entries,
+        names: &section[names_start..],
+    })
+}
+
+/// Returns the name and start address of the function containing `addr`, `None` if there
+/// is no symbol table or `addr` isn't in the kernel.
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    let base = unsafe { &__kernel_base } as *const u8 as usize;
+    let offset = u32::try_from(addr.checked_sub(base)?).ok()?;
+
+    let table = table()?;
+    let index = table
+        .entries
+        .partition_point(|e| e[0] <= offset)
+        .checked_sub(1)?;
+    let [start, name_offset, name_len] = table.entries[index];
+
+    let name = table
+        .names
+        .get(name_offset as usize..(name_offset + name_len) as usize)?;
+    Some((core::str::from_utf8(name).ok()?, base + start as usize))
+}
+
+/// Returns whether `ksyms.py` filled in the symbol table.
+pub fn is_present() -> bool {
+    table().is_some()
+}
+
+/// An address in the kernel, printed as `function+0xoffset` if there is a symbol for it,
+/// as the address otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub addr: usize,
+    /// The name and start address of the function containing `addr`.
+    pub function: Option<(&'static str, usize)>,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.function {
+            Some((name, start)) if self.addr == start => write!(f, "{name}"),
+            Some((name, start)) => write!(f, "{name}+{:#x}", self.addr - start),
+            None => write!(f, "{:#x}", self.addr),
+        }
+    }
+}
+
+/// Returns the symbol for `addr`, to print it.
+pub fn resolve(addr: usize) -> Symbol {
+    Symbol {
+        addr,
+        function: lookup(addr),
+    }
+}
+
+/// Prints the calls that led to the caller, innermost first.
+///
+/// Each frame keeps the return address and the caller's frame pointer in the two words
+/// below the address `s0` points to. The walk stops at the first frame pointer that isn't
+/// in kernel memory, or that doesn't lead up the stack.
+pub fn print_backtrace() {
+    let word = size_of::<usize>();
+    let low = unsafe { &__kernel_base } as *const u8 as usize;
+    let high = unsafe { &__free_ram_end } as *const u8 as usize;
+    let mut fp: usize;
+    unsafe { asm!("mv {0}, s0", out(reg) fp) };
+
+    println!("backtrace:");
+    for _ in 0..MAX_FRAMES {
+        if fp < low + 2 * word || fp > high || !fp.is_multiple_of(word) {
+            break;
+        }
+        // Safety: Checked to be kernel memory, which is always mapped.
+        let (ra, caller_fp) = unsafe {
+            (
+                ((fp - word) as *const usize).read(),
+                ((fp - 2 * word) as *const usize).read(),
+            )
+        };
+        if ra == 0 {
+            break;
+        }
+        println!("  {}", resolve(ra));
+        if caller_fp <= fp {
+            break;
+        }
+        fp = caller_fp;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::BufWriter;
+    use core::fmt::Write;
+
+    fn format(symbol: Symbol) -> ([u8; 64], usize) {
+        let mut buf = [0; 64];
+        let mut out = BufWriter::new(&mut buf);
+        write!(out, "{symbol}").unwrap();
+        let len = out.len();
+        (buf, len)
+    }
+
+    #[test_case]
+    fn symbols_print_as_function_and_offset() {
+        let (buf, len) = format(resolve(0x10));
+        assert_eq!(&buf[..len], b"0x10");
+
+        let symbol = Symbol {
+            addr: 0x1010,
+            function: Some(("kernel_main", 0x1000)),
+        };
+        let (buf, len) = format(symbol);
+        assert_eq!(&buf[..len], b"kernel_main+0x10");
+
+        // Only filled in when `ktest.sh` ran `ksyms.py` on the test kernel.
+        let addr = resolve as *const () as usize;
+        if is_present() {
+            let (name, start) = resolve(addr + 2).function.unwrap();
+            assert_eq!(start, addr);
+            assert!(name.ends_with("resolve"));
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use core::{
+    arch::asm,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{cmdline, print, println, sync::OnceCell};
+
+/// MMIO address of QEMU virt's SiFive test device, writing to it powers off the machine.
+pub const TEST_FINISHER: usize = 0x10_0000;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_FAIL: u32 = 0x3333;
+
+const NO_TEST: usize = usize::MAX;
+
+pub enum ExitCode {
+    Success,
+    Failure(u16),
+}
+
+/// A function collected by `#[test_case]`.
+pub trait Testable: Sync {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+}
+
+impl<T: Fn() + Sync> Testable for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        self()
+    }
+}
+
+// Test cases handed over by the generated `test_main()`.
+static TESTS: OnceCell<&'static [&'static dyn Testable]> = OnceCell::new();
+
+// Index of the running test, or `NO_TEST` outside of the test loop.
+static CURRENT: AtomicUsize = AtomicUsize::new(NO_TEST);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+static SKIPPED: AtomicUsize = AtomicUsize::new(0);
+
+// Stack pointer `resume` starts from after a test panicked.
+static RESUME_SP: AtomicUsize = AtomicUsize::new(0);
+
+/// Powers off QEMU, which then exits with a status derived from `code`.
+pub fn exit(code: ExitCode) -> ! {
+    let value = match code {
+        ExitCode::Success => FINISHER_PASS,
+        ExitCode::Failure(code) => ((code as u32) << 16) | FINISHER_FAIL,
+    };
+
+    unsafe { ptr::write_volatile(TEST_FINISHER as *mut u32, value) };
+
+    loop {
+        unsafe { asm!("wfi") }
+    }
+}
+
+/// Test runner passed to `#![test_runner]`, runs all `#[test_case]`s and exits QEMU with the result.
+pub fn runner(tests: &'static [&'static dyn Testable]) -> ! {
+    TESTS.get_or_init(|| tests);
+
+    println!("ktest: running {} tests", tests.len());
+
+    let sp: usize;
+    unsafe { asm!("mv {0}, sp", out(reg) sp) };
+    RESUME_SP.store(sp, Ordering::Relaxed);
+
+    run_from(0)
+}
+
+fn run_from(first: usize) -> ! {
+    let tests = *TESTS.get_or_init(|| &[]);
+
+    // `ktest=<pattern>` on the command line runs only tests whose name contains it.
+    let filter = cmdline::get("ktest").unwrap_or("");
+
+    for (i, test) in tests.iter().enumerate().skip(first) {
+        if !test.name().contains(filter) {
+            SKIPPED.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        CURRENT.store(i, Ordering::Relaxed);
+        print!("ktest: test {} ... ", test.name());
+        test.run();
+        println!("ok");
+    }
+
+    CURRENT.store(NO_TEST, Ordering::Relaxed);
+
+    let failed = FAILED.load(Ordering::Relaxed);
+    let skipped = SKIPPED.load(Ordering::Relaxed);
+    let passed = tests.len() - failed - skipped;
+
+    if failed == 0 {
+        println!("ktest: result: ok. {passed} passed; 0 failed; {skipped} filtered out");
+        exit(ExitCode::Success)
+    } else {
+        println!("ktest: result: FAILED. {passed} passed; {failed} failed; {skipped} filtered out");
+        exit(ExitCode::Failure(failed as u16))
+    }
+}
+
+extern "C" fn resume() -> ! {
+    run_from(CURRENT.load(Ordering::Relaxed) + 1)
+}
+
+/// Called by `panic!` once the message is printed.
+///
+/// Outside of a test this exits QEMU with a failure. Inside a test, the test is marked
+/// as failed and the runner continues with the next one on a fresh stack.
+/// Locks held by the failed test stay locked.
+pub fn on_panic() -> ! {
+    if CURRENT.load(Ordering::Relaxed) == NO_TEST {
+        exit(ExitCode::Failure(1));
+    }
+
+    println!("\nktest: FAILED");
+    FAILED.fetch_add(1, Ordering::Relaxed);
+
+    let sp = RESUME_SP.load(Ordering::Relaxed);
+    unsafe {
+        asm!(
+            "mv sp, {0}",
+            "j {1}",
+            in(reg) sp,
+            sym resume,
+            options(noreturn)
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{MAX_HARTS, proc, timer};
+
+// Load averages of each hart: its run queue length (the runnable processes that last ran
+// on it, see `proc::stats()`) sampled every 5 seconds and decayed exponentially over 1, 5
+// and 15 minutes, in the fixed point Linux uses. The system's load is their sum.
+
+/// Bits of a load average below the binary point.
+pub const FSHIFT: usize = 11;
+pub const FIXED_1: usize = 1 << FSHIFT;
+
+const SAMPLE_SECS: usize = 5;
+// `FIXED_1 / e^(5s / 1min)`, and over 5 and 15 minutes.
+const EXP: [usize; 3] = [1884, 2014, 2037];
+
+static LOAD: [[AtomicUsize; 3]; MAX_HARTS] =
+    [const { [const { AtomicUsize::new(0) }; 3] }; MAX_HARTS];
+// Tick at which the next sample is due.
+static NEXT_SAMPLE: AtomicUsize = AtomicUsize::new(0);
+
+/// Decays `load` towards `active` runnable processes over one sample.
+fn decay(load: usize, exp: usize, active: usize) -> usize {
+    let active = active * FIXED_1;
+    let mut new = load * exp + active * (FIXED_1 - exp);
+    // Rounds up while growing, so a constant load is eventually reached.
+    if active >= load {
+        new += FIXED_1 - 1;
+    }
+    new / FIXED_1
+}
+
+/// Returns the tick the next sample is due at.
+pub fn next_sample() -> usize {
+    NEXT_SAMPLE.load(Ordering::Relaxed)
+}
+
+/// Samples the run queues when due, called on every tick of hart 0.
+pub fn on_tick(ticks: usize) {
+    let next = NEXT_SAMPLE.load(Ordering::Relaxed);
+    if ticks < next {
+        return;
+    }
+    // Tried again on the next tick if the process table is busy.
+    let Some(run_queues) = proc::try_run_queues() else {
+        return;
+    };
+    NEXT_SAMPLE.store(ticks + SAMPLE_SECS * timer::hz(), Ordering::Relaxed);
+    // The first sample only sets the clock.
+    if next == 0 {
+        return;
+    }
+
+    for (load, active) in LOAD.iter().zip(run_queues) {
+        for (load, exp) in load.iter().zip(EXP) {
+            load.store(
+                decay(load.load(Ordering::Relaxed), exp, active),
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+/// Returns the 1, 5 and 15 minute load averages of each hart, in `FIXED_1` fixed point.
+pub fn get() -> [[usize; 3]; MAX_HARTS] {
+    LOAD.each_ref()
+        .map(|load| load.each_ref().map(|load| load.load(Ordering::Relaxed)))
+}
+
+/// Formats a load average with two decimals, like `/proc/loadavg`.
+pub struct Fixed(pub usize);
+
+impl core::fmt::Display for Fixed {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        // Rounded to the nearest hundredth.
+        let hundredths = (self.0 * 100 + FIXED_1 / 2) >> FSHIFT;
+        write!(f, "{}.{:02}", hundredths / 100, hundredths % 100)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use super::*;
+    use crate::vfs::BufWriter;
+
+    #[test_case]
+    fn load_decays_towards_the_run_queue_length() {
+        let mut load = 0;
+        // A minute of one runnable process brings the 1 minute average to 1 - 1/e.
+        for _ in 0..60 / SAMPLE_SECS {
+            load = decay(load, EXP[0], 1);
+        }
+        let mut buf = [0; 8];
+        let mut out = BufWriter::new(&mut buf);
+        write!(out, "{}", Fixed(load)).unwrap();
+        let len = out.len();
+        assert_eq!(&buf[..len], b"0.63");
+
+        for _ in 0..1000 {
+            load = decay(load, EXP[0], 1);
+        }
+        assert_eq!(load, FIXED_1);
+        for _ in 0..1000 {
+            load = decay(load, EXP[0], 0);
+        }
+        assert_eq!(load, 0);
+    }
+}
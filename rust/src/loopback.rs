@@ -0,0 +1,120 @@
+use crate::{
+    net::{self, IpConfig, Ipv4Addr, MAX_FRAME, MacAddr, NetDevice},
+    sync::Mutex,
+    syscall::Errno,
+};
+
+// The `lo` interface: frames sent on it are received on it by the next `net::poll()`, so
+// the stack can talk to itself without a network card.
+
+// Frames in flight before sending fails.
+const QUEUE_LEN: usize = 4;
+
+struct Queue {
+    frames: [([u8; MAX_FRAME], usize); QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+struct Loopback {
+    queue: Mutex<Queue>,
+}
+
+impl NetDevice for Loopback {
+    fn mac(&self) -> MacAddr {
+        MacAddr::ZERO
+    }
+
+    fn is_loopback(&self) -> bool {
+        true
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), Errno> {
+        let mut q = self.queue.lock();
+        if q.len == QUEUE_LEN {
+            return Err(Errno::ENOBUFS);
+        }
+
+        let tail = (q.head + q.len) % QUEUE_LEN;
+        q.frames[tail].0[..frame.len()].copy_from_slice(frame);
+        q.frames[tail].1 = frame.len();
+        q.len += 1;
+        Ok(())
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut q = self.queue.lock();
+        if q.len == 0 {
+            return None;
+        }
+
+        let (frame, len) = &q.frames[q.head];
+        let len = *len;
+        buf[..len].copy_from_slice(&frame[..len]);
+        q.head = (q.head + 1) % QUEUE_LEN;
+        q.len -= 1;
+        Some(len)
+    }
+}
+
+static LOOPBACK: Loopback = Loopback {
+    queue: Mutex::new(Queue {
+        frames: [([0; MAX_FRAME], 0); QUEUE_LEN],
+        head: 0,
+        len: 0,
+    }),
+};
+
+crate::register_subsystem!(Device, "loopback", init);
+
+/// Adds `lo` with address 127.0.0.1/8.
+fn init() {
+    let iface = net::register("lo", &LOOPBACK).expect("no free interface slots.");
+    net::set_ip_config(
+        iface,
+        IpConfig {
+            addr: Ipv4Addr([127, 0, 0, 1]),
+            netmask: Ipv4Addr([255, 0, 0, 0]),
+            gateway: Ipv4Addr::UNSPECIFIED,
+        },
+    )
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        net::{self, Ipv4Addr, SocketAddr},
+        udp,
+    };
+
+    #[test_case]
+    fn udp_datagrams_loop_back() {
+        let addr = |port| SocketAddr {
+            ip: Ipv4Addr([127, 0, 0, 1]),
+            port,
+        };
+        let receiver = udp::create().unwrap();
+        let sender = udp::create().unwrap();
+        udp::bind(
+            receiver,
+            SocketAddr {
+                ip: Ipv4Addr::UNSPECIFIED,
+                port: 7000,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(udp::send_to(sender, addr(7000), b"hello"), Ok(5));
+        net::poll();
+
+        let mut buf = [0; 8];
+        let (n, from) = udp::try_recv_from(receiver, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        assert_eq!(from.ip, Ipv4Addr([127, 0, 0, 1]));
+        assert!(from.port != 0);
+
+        udp::close(sender);
+        udp::close(receiver);
+    }
+}
@@ -1,28 +1,54 @@
-use crate::sbi::putchar;
+use crate::sysctl::Tunable;
+
+/// Console log level at which `trace!` messages are printed, the most verbose one.
+pub const LOGLEVEL_TRACE: usize = 8;
+
+/// Set with `loglevel=` on the command line or `/proc/sys/loglevel`, everything compiled
+/// in is printed by default.
+pub static LOGLEVEL: Tunable = Tunable::new("loglevel", LOGLEVEL_TRACE, 0, LOGLEVEL_TRACE);
+
+pub fn loglevel() -> usize {
+    LOGLEVEL.get()
+}
 
 pub struct Writer;
 
 impl core::fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for ch in s.chars() {
-            putchar(ch);
-        }
+        crate::console::log(s.as_bytes());
         Ok(())
     }
 }
 
+/// Stops the calling hart after a panic message has been printed.
+#[allow(unreachable_code)]
+pub fn halt() -> ! {
+    crate::ksyms::print_backtrace();
+    crate::tracepoint::dump_on_panic();
+
+    #[cfg(test)]
+    crate::ktest::on_panic();
+
+    #[cfg(feature = "gdb")]
+    crate::gdb::on_panic();
+
+    loop {
+        unsafe { core::arch::asm!("wfi") }
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ({
         use core::fmt::Write;
-        let _ = write!(crate::macros::Writer, $($arg)*);
+        let _ = write!($crate::macros::Writer, $($arg)*);
     });
 }
 
 #[macro_export]
 macro_rules! println {
     ($($arg:tt)*) => ({
-        use crate::print;
+        use $crate::print;
         print!("{}\n", format_args!($($arg)*));
     });
 }
@@ -30,36 +56,188 @@ macro_rules! println {
 #[macro_export]
 macro_rules! panic {
     ($($arg:tt)*) => ({
-        use crate::print;
+        use $crate::print;
         print!("PANIC: {}:{}: {}", file!(), line!(), format_args!($($arg)*));
-        loop {
-            unsafe { core::arch::asm!("wfi") }
-        }
+        $crate::macros::halt();
     });
 }
 
+/// Marks a fault point named `$name`.
+///
+/// Evaluates to `true` if the call site should take its failure path. Without the
+/// `fault-inject` feature (or `cargo test`) this is always `false` and costs nothing.
+#[macro_export]
+macro_rules! faultpoint {
+    ($name:literal) => {{
+        #[cfg(any(test, feature = "fault-inject"))]
+        let fail = $crate::fault::hit($name);
+        #[cfg(not(any(test, feature = "fault-inject")))]
+        let fail = false;
+        fail
+    }};
+}
+
+/// Checks a kernel invariant, evaluating to whether it holds.
+///
+/// What a failure does depends on `kassert::MODE`: it panics by default (or logs with the
+/// `kassert-log` feature), so callers must handle `false` sensibly, e.g. by bailing out.
 #[macro_export]
-macro_rules! read_csr {
-    ($reg:literal) => {{
-        let value: usize;
-        unsafe {
-            core::arch::asm!(
-                concat!("csrr {0}, ", $reg),
-                out(reg) value,
-            );
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        $crate::kassert!($cond, "{}", stringify!($cond))
+    };
+    ($cond:expr, $($arg:tt)+) => {{
+        let ok: bool = $cond;
+        if !ok {
+            $crate::kassert::fail(file!(), line!(), format_args!($($arg)+));
         }
-        value
+        ok
     }};
 }
 
+/// Like `kassert!`, checks that two expressions are equal.
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => $crate::kassert!(
+                *left == *right,
+                "`{} == {}` ({:?} != {:?})",
+                stringify!($left),
+                stringify!($right),
+                left,
+                right
+            ),
+        }
+    };
+    ($left:expr, $right:expr, $($arg:tt)+) => {
+        match (&$left, &$right) {
+            (left, right) => $crate::kassert!(
+                *left == *right,
+                "{} ({:?} != {:?})",
+                format_args!($($arg)+),
+                left,
+                right
+            ),
+        }
+    };
+}
+
+// Register width dependent parts of asm templates: the size of a register in bytes, the
+// mnemonics that store and load a whole register, and the directive emitting a pointer.
+#[cfg(target_arch = "riscv32")]
+#[macro_export]
+macro_rules! reg_bytes {
+    () => {
+        "4"
+    };
+    (store) => {
+        "sw"
+    };
+    (ptr) => {
+        ".word"
+    };
+    (load) => {
+        "lw"
+    };
+}
+
+#[cfg(target_arch = "riscv64")]
+#[macro_export]
+macro_rules! reg_bytes {
+    () => {
+        "8"
+    };
+    (store) => {
+        "sd"
+    };
+    (ptr) => {
+        ".dword"
+    };
+    (load) => {
+        "ld"
+    };
+}
+
+/// Expands to an asm line storing `$reg` into the `$slot`th register-sized word above
+/// `$base`, `sp` if omitted.
+#[macro_export]
+macro_rules! store_reg {
+    ($reg:ident, $slot:literal) => {
+        $crate::store_reg!($reg, $slot, sp)
+    };
+    ($reg:ident, $slot:literal, $base:ident) => {
+        concat!(
+            $crate::reg_bytes!(store),
+            " ",
+            stringify!($reg),
+            ", ",
+            $crate::reg_bytes!(),
+            " * ",
+            $slot,
+            "(",
+            stringify!($base),
+            ")"
+        )
+    };
+}
+
+/// Expands to an asm line loading `$reg` from the `$slot`th register-sized word above
+/// `$base`, `sp` if omitted.
+#[macro_export]
+macro_rules! load_reg {
+    ($reg:ident, $slot:literal) => {
+        $crate::load_reg!($reg, $slot, sp)
+    };
+    ($reg:ident, $slot:literal, $base:ident) => {
+        concat!(
+            $crate::reg_bytes!(load),
+            " ",
+            stringify!($reg),
+            ", ",
+            $crate::reg_bytes!(),
+            " * ",
+            $slot,
+            "(",
+            stringify!($base),
+            ")"
+        )
+    };
+}
+
+/// Adds a subsystem with the given `subsys::InitLevel`, name, and init function to the
+/// kernel's subsystem table, initialized after the subsystems named by `after(...)`, if
+/// given, e.g. `register_subsystem!(Subsys, "foo", init, after("dtb", "bar"))`.
+///
+/// The entry is placed in the `.subsystems` linker section, so registering takes no code
+/// in `kernel_init` and a subsystem that isn't compiled in costs nothing.
+#[macro_export]
+macro_rules! register_subsystem {
+    ($level:ident, $name:literal, $init:path $(, after($($after:literal),+ $(,)?))?) => {
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = ".subsystems")]
+            static SUBSYSTEM: $crate::subsys::Subsystem = $crate::subsys::Subsystem {
+                level: $crate::subsys::InitLevel::$level,
+                name: $name,
+                after: &[$($($after),+)?],
+                init: $init,
+            };
+        };
+    };
+}
+
+/// Prints a trace message when built with the `log-trace` feature and the console log level
+/// is at least `LOGLEVEL_TRACE`, and nothing otherwise.
+///
+/// The arguments are still type-checked without the feature, but never evaluated.
 #[macro_export]
-macro_rules! write_csr {
-    ($reg:literal, $value:expr) => {
-        unsafe {
-            core::arch::asm!(
-                concat!("csrw ", $reg, ", {0}"),
-                in(reg) $value,
-            );
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "log-trace")
+            && $crate::macros::loglevel() >= $crate::macros::LOGLEVEL_TRACE
+        {
+            $crate::println!("[trace] {}", format_args!($($arg)*));
         }
     };
 }
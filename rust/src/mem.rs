@@ -1,12 +1,14 @@
 use core::{
     fmt::LowerHex,
-    ops::{Add, Sub},
-    slice,
-    str::Utf8Error,
+    marker::PhantomData,
+    num::NonZeroUsize,
+    ops::{Deref, DerefMut},
+    ptr, slice,
 };
 
 use crate::{
-    panic,
+    buddy::{self, Block, Buddy},
+    faultpoint, page, panic,
     sync::{Mutex, OnceCell},
 };
 
@@ -17,54 +19,344 @@ pub const PAGE_SIZE: usize = 4096;
 // Global static instance of Memory, safely wrapped in a OnceCell.
 static MEMORY: OnceCell<Mutex<Memory>> = OnceCell::new();
 
-/// Initializes the global static instance of Memory
+// Hands out the reserved allocator region until `init()` takes it over, `None` before
+// `boot_init()` and after `init()`.
+static BOOT_ALLOC: Mutex<Option<InitialAlloc>> = Mutex::new(None);
+
+/// Hands the reserved region `alloc_mem_start..alloc_mem_end` to the boot allocator.
+///
+/// Must be called first thing at boot, before any call to `boot_alloc()`.
+pub fn boot_init(alloc_mem_start: usize, alloc_mem_end: usize) {
+    let mut boot_alloc = BOOT_ALLOC.lock();
+    assert!(boot_alloc.is_none(), "boot_init(): called twice.");
+    *boot_alloc = Some(InitialAlloc::new(alloc_mem_start, alloc_mem_end));
+}
+
+/// Allocates `n` zeroed pages for boot-time structures that are needed before `init()`.
+/// They are never freed.
+///
+/// # Panics
+///
+/// This function panics if the reserved region runs out, or once `init()` has handed what
+/// is left of it to the buddy allocator: `phalloc()` takes over from then on.
+pub fn boot_alloc(n: usize) -> OwnedRegion {
+    BOOT_ALLOC
+        .lock()
+        .as_mut()
+        .expect("boot_alloc(): the boot allocator is closed.")
+        .page_alloc(n)
+}
+
+/// Initializes the global static instance of Memory, closing the boot allocator.
 ///
 /// Must be called early in the boot process before any call to buddy_alloc().
-pub fn init(ram_start: usize, ram_end: usize, alloc_mem_start: usize, alloc_mem_end: usize) {
+pub fn init(ram_start: usize, ram_end: usize) {
+    let mut sc_alloc = BOOT_ALLOC
+        .lock()
+        .take()
+        .expect("mem::init(): called before boot_init(), or twice.");
     MEMORY.get_or_init(|| {
         Mutex::new(Memory::new(
             Some(ram_start),
             Some(ram_end),
-            Some(alloc_mem_start),
-            Some(alloc_mem_end),
+            Some(&mut sc_alloc),
         ))
     });
 }
 
+/// Allocates a block of at least `n` bytes, zeroed.
 pub fn buddy_alloc(n: usize) -> Result<PhysAddr, Error> {
+    let addr = buddy_alloc_uninit(n)?;
+    // Free RAM is neither zeroed at boot nor when freed, only as it is handed out.
+    let size = addr.size().expect("buddy_alloc(): block without a size.");
+    unsafe { addr.as_mut_ptr().write_bytes(0, size) };
+    Ok(addr)
+}
+
+/// Allocates a block of at least `n` bytes like `buddy_alloc()`, but leaves whatever it
+/// held before in it, for callers about to overwrite all of it.
+pub fn buddy_alloc_uninit(n: usize) -> Result<PhysAddr, Error> {
+    if faultpoint!("buddy-alloc") {
+        return Err(Error::OutOfMemory);
+    }
+
     // It's safe to call Memory::new() with None values since
     // init_mem() has already initialized the OnceCell and Mutex.
-    let mem = MEMORY.get_or_init(|| Mutex::new(Memory::new(None, None, None, None)));
+    let mem = MEMORY.get_or_init(|| Mutex::new(Memory::new(None, None, None)));
     // FIXME: Giant lock on all available memory
-    mem.lock().buddy_alloc(n)
+    let mut locked = mem.lock();
+    let addr = match locked.buddy_alloc(n) {
+        // Caches give back what they can, which must not happen holding the lock.
+        Err(Error::OutOfMemory) => {
+            drop(locked);
+            if reclaim() == 0 {
+                return Err(Error::OutOfMemory);
+            }
+            locked = mem.lock();
+            locked.buddy_alloc(n)?
+        }
+        result => result?,
+    };
+    // Under the lock, so the block is never seen allocated with its last owner's page
+    // descriptors.
+    page::on_alloc(addr);
+    Ok(addr)
+}
+
+/// Allocates at least `n` bytes aligned to `align`, a power of two, with `buddy_alloc()`.
+///
+/// Blocks are aligned to their size, so this takes one of at least `align` bytes. Fails with
+/// `BadAlignment` if `align` is larger than `max_alignment()`.
+pub fn buddy_alloc_aligned(n: usize, align: usize) -> Result<PhysAddr, Error> {
+    if n == 0 {
+        return Err(Error::ZeroSize);
+    }
+    if !align.is_power_of_two() || align > max_alignment() {
+        return Err(Error::BadAlignment);
+    }
+    let addr = buddy_alloc(n.max(align))?;
+    crate::kassert!(
+        addr.is_aligned(align),
+        "buddy_alloc_aligned(): {addr:?} isn't aligned to {align:#x}."
+    );
+    Ok(addr)
 }
 
+/// Returns the largest alignment `buddy_alloc_aligned()` can guarantee: blocks are aligned
+/// to their size from the start of the memory managed, which is only so aligned itself.
+pub fn max_alignment() -> usize {
+    let mem = MEMORY.get_or_init(|| Mutex::new(Memory::new(None, None, None)));
+    let mem = mem.lock();
+    let start = mem.start.as_usize();
+    (1 << start.trailing_zeros()).min(mem.buddy.size())
+}
+
+/// Bytes of memory the buddy allocator manages and has handed out, including what rounding
+/// up to a block added.
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub total: usize,
+    pub used: usize,
+}
+
+pub fn usage() -> Usage {
+    let mem = MEMORY.get_or_init(|| Mutex::new(Memory::new(None, None, None)));
+    let mem = mem.lock();
+    Usage {
+        total: mem.buddy.size(),
+        used: mem.buddy.stats().used,
+    }
+}
+
+/// Pages of memory the buddy allocator manages, as reported by `stats()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Bytes of RAM managed.
+    pub total: usize,
+    pub free_pages: usize,
+    pub used_pages: usize,
+    /// Bytes of the largest free block, the most a single allocation can get.
+    pub largest_free: usize,
+    /// Free blocks of each order, `PAGE_SIZE << order` bytes.
+    pub free_blocks: [usize; buddy::MAX_ORDERS],
+}
+
+/// Returns how much memory is free and in what blocks, as one snapshot.
+pub fn stats() -> Stats {
+    let mem = MEMORY.get_or_init(|| Mutex::new(Memory::new(None, None, None)));
+    let mem = mem.lock();
+    let total = mem.buddy.size();
+    let free_blocks = mem.buddy.free_blocks();
+    let free_pages = free_blocks
+        .iter()
+        .enumerate()
+        .map(|(order, &count)| count << order)
+        .sum();
+    let largest = free_blocks.iter().rposition(|&count| count > 0);
+    Stats {
+        total,
+        free_pages,
+        used_pages: total / PAGE_SIZE - free_pages,
+        largest_free: largest.map_or(0, |order| PAGE_SIZE << order),
+        free_blocks,
+    }
+}
+
+/// Returns the buddy allocator's counters and its free blocks of each order.
+pub fn buddy_stats() -> (buddy::Stats, [usize; buddy::MAX_ORDERS]) {
+    let mem = MEMORY.get_or_init(|| Mutex::new(Memory::new(None, None, None)));
+    let mem = mem.lock();
+    (*mem.buddy.stats(), mem.buddy.free_blocks())
+}
+
+pub fn reset_buddy_stats() {
+    let mem = MEMORY.get_or_init(|| Mutex::new(Memory::new(None, None, None)));
+    mem.lock().buddy.reset_stats();
+}
+
+/// Frees the block at `addr`, which `buddy_alloc()` returned. The size of `addr` isn't
+/// needed, the allocator knows it; if there is one, it must be the block's.
 pub fn buddy_free(addr: PhysAddr) {
     // It's safe to call Memory::new() with None values since
     // init_mem() has already initialized the OnceCell and Mutex.
-    let mem = MEMORY.get_or_init(|| Mutex::new(Memory::new(None, None, None, None)));
+    let mem = MEMORY.get_or_init(|| Mutex::new(Memory::new(None, None, None)));
     // FIXME: Giant lock on all available memory
     mem.lock().buddy_free(addr);
 }
 
+/// Resizes the block at `addr` to hold at least `n` bytes, keeping what it holds up to the
+/// smaller of both sizes, and returns it.
+///
+/// The block stays where it is when it shrinks, or when it grows into free buddies above
+/// it; otherwise a new block is allocated, the bytes copied and the old block freed. What it
+/// grew by isn't zeroed. Fails with `ZeroSize` if `n` is 0 and with `OutOfMemory` if no
+/// block is large enough, leaving the block as it was.
+pub fn buddy_realloc(addr: PhysAddr, n: usize) -> Result<PhysAddr, Error> {
+    if n == 0 {
+        return Err(Error::ZeroSize);
+    }
+    let mem = MEMORY.get_or_init(|| Mutex::new(Memory::new(None, None, None)));
+    let mut locked = mem.lock();
+    let Some(size) = locked.allocated_size(addr) else {
+        crate::kassert!(
+            false,
+            "buddy_realloc(): {addr:?} was not allocated by this allocator."
+        );
+        return Err(Error::OutOfMemory);
+    };
+    if let Some(resized) = locked.buddy_resize(addr, size, n) {
+        return Ok(resized);
+    }
+    drop(locked);
+
+    // Shrinking never fails, so the new block is the larger one.
+    let new = buddy_alloc_uninit(n)?;
+    unsafe { ptr::copy_nonoverlapping(addr.as_ptr(), new.as_mut_ptr(), size) };
+    page::on_realloc(PhysAddr::new(addr.as_usize(), Some(size)), new);
+    buddy_free(addr);
+    Ok(new)
+}
+
+// MARK - END
+
+// MARK - RECLAIM
+
+// Caches holding memory they can give back, asked to when an allocation runs out of it.
+const MAX_SHRINKERS: usize = 4;
+
+/// Frees what memory a cache can, returning the number of blocks it freed.
+type Shrinker = fn() -> usize;
+
+static SHRINKERS: Mutex<[Option<Shrinker>; MAX_SHRINKERS]> = Mutex::new([None; MAX_SHRINKERS]);
+
+/// Registers `shrink`, called when memory runs out to free what it can and return the
+/// number of blocks it freed. It may be called with any lock held, so it must only
+/// `try_lock()` its own.
+///
+/// # Panics
+///
+/// Panics if `MAX_SHRINKERS` are registered already.
+pub fn register_shrinker(shrink: Shrinker) {
+    let mut shrinkers = SHRINKERS.lock();
+    let slot = shrinkers
+        .iter_mut()
+        .find(|s| s.is_none())
+        .expect("register_shrinker(): no free slots.");
+    *slot = Some(shrink);
+}
+
+/// Asks every shrinker to give memory back, returning the number of blocks they freed.
+pub fn reclaim() -> usize {
+    let shrinkers = *SHRINKERS.lock();
+    shrinkers.iter().flatten().map(|shrink| shrink()).sum()
+}
+
 // MARK - END
 
 #[derive(Debug)]
 pub enum Error {
     OutOfMemory,
     ZeroSize,
+    /// An alignment that isn't a power of two, or larger than blocks can be aligned to.
+    BadAlignment,
+}
+
+// MARK - MEMORY MAP
+
+// The physical address ranges known to hold something, which `PhysAddr::new_checked()`
+// accepts: RAM, from the device tree, and the registers of devices whose drivers
+// registered them.
+
+const REGIONS_MAX: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    Ram,
+    Mmio,
+}
+
+/// Why an address was refused by `PhysAddr::new_checked()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddrError {
+    Null,
+    /// The range wraps around the end of the address space.
+    Overflow,
+    /// The range isn't all in one region of the memory map.
+    Unmapped,
+}
+
+struct MemoryMap {
+    regions: [(RegionKind, usize, usize); REGIONS_MAX],
+    len: usize,
+}
+
+static MEMORY_MAP: Mutex<MemoryMap> = Mutex::new(MemoryMap {
+    regions: [(RegionKind::Ram, 0, 0); REGIONS_MAX],
+    len: 0,
+});
+
+/// Adds `start..end` to the memory map, called at boot for RAM and by drivers for their
+/// registers.
+///
+/// # Panics
+///
+/// This function panics if the memory map is full.
+pub fn add_region(kind: RegionKind, start: usize, end: usize) {
+    let mut map = MEMORY_MAP.lock();
+    let len = map.len;
+    assert!(len < REGIONS_MAX, "memory map is full.");
+    map.regions[len] = (kind, start, end);
+    map.len += 1;
+}
+
+/// Calls `f` with the kind, start and end of every region of the memory map, in the order
+/// they were added.
+pub fn for_each_region(mut f: impl FnMut(RegionKind, usize, usize)) {
+    let map = MEMORY_MAP.lock();
+    for &(kind, start, end) in &map.regions[..map.len] {
+        f(kind, start, end);
+    }
+}
+
+/// Returns the kind of region `start..end` lies in, `None` if it isn't all in one.
+pub fn region_kind(start: usize, end: usize) -> Option<RegionKind> {
+    let map = MEMORY_MAP.lock();
+    map.regions[..map.len]
+        .iter()
+        .find(|&&(_, r_start, r_end)| r_start <= start && end <= r_end)
+        .map(|&(kind, ..)| kind)
 }
 
 // MARK - INITIAL ALLOCATOR
 
 struct InitialAlloc {
-    start: usize,
     end: usize,
     next: usize,
 }
 
 impl InitialAlloc {
-    /// Returns a new instance of InitialAlloc, that controls a zeroed memory region of `end - start` size.
+    /// Returns a new instance of InitialAlloc, that controls a memory region of `end - start`
+    /// size, zeroing the pages it hands out.
     ///
     /// # Safety
     ///
@@ -73,15 +365,7 @@ impl InitialAlloc {
     ///
     /// The caller must ensure that these assumptions hold, as violating them may lead to undefined behavior.
     fn new(start: usize, end: usize) -> Self {
-        let _start = start as *const u8 as *mut u8;
-        let _end = end as *const u8 as *mut u8;
-        unsafe { _start.write_bytes(0, _end.offset_from(_start) as usize) };
-
-        Self {
-            start,
-            end,
-            next: start,
-        }
+        Self { end, next: start }
     }
 
     /// Allocates `n` pages of memory.
@@ -95,7 +379,7 @@ impl InitialAlloc {
     /// # Panics
     ///
     /// This function panics if there is not enough available memory.
-    fn page_alloc(&mut self, n: usize) -> PhysAddr {
+    fn page_alloc(&mut self, n: usize) -> OwnedRegion {
         let size = n * PAGE_SIZE;
 
         let addr = self.next;
@@ -105,8 +389,13 @@ impl InitialAlloc {
         } else {
             panic!("{:?}", Error::OutOfMemory);
         }
+        unsafe { (addr as *mut u8).write_bytes(0, size) };
 
-        PhysAddr::new(addr, Some(size))
+        // Never given back, there is nothing to return the pages to.
+        OwnedRegion {
+            addr: PhysAddr::new(addr, Some(size)),
+            release: None,
+        }
     }
 }
 
@@ -114,61 +403,11 @@ impl InitialAlloc {
 
 // MARK - BUDDY ALLOCATOR
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-#[repr(u8)]
-enum BlockState {
-    Free = 1,
-    Allocated = 2,
-    Split = 3,
-}
-
-/// Returns the level where a given memory block would sit
-/// in the binary tree that buddy allocator internally uses.
-fn find_order(n: usize) -> usize {
-    if n == usize::MAX {
-        size_of::<usize>() * 8
-    } else {
-        usize::ilog2(n + 1) as usize
-    }
-}
-
-/// Returns the next powers of two that comes after `n`,
-/// or `None` if `n` is grater than `(usize::MAX / 2) + 1`
-fn next_power_of_two(n: usize) -> Option<usize> {
-    if n == 0 {
-        return Some(1);
-    }
-
-    if n > (usize::MAX / 2 + 1) {
-        return None; // Cannot represent next power of two within usize
-    }
-
-    let mut x = n - 1;
-    x |= x >> 1;
-    x |= x >> 2;
-    x |= x >> 4;
-    x |= x >> 8;
-    x |= x >> 16;
-
-    #[cfg(target_pointer_width = "64")]
-    {
-        x |= x >> 32;
-    }
-
-    Some(x + 1)
-}
-
 #[repr(C)]
 struct Memory<'a> {
     start: PhysAddr,
     end: PhysAddr,
-    mem_size: usize,
-    buddy_node_count: usize,
-    buddy_high_order: usize,
-    buddy_low_order: usize,
-    buddy_stack_size: usize,
-    buddy_stack: &'a mut [usize], // FIXME: change to function-local
-    buddy_meta: &'a mut [BlockState],
+    buddy: Buddy<'a>,
 }
 
 impl<'a> Memory<'a> {
@@ -176,7 +415,7 @@ impl<'a> Memory<'a> {
     ///
     /// # Safety
     ///
-    /// - `ram_start`, `ram_end`, `alloc_mem_start`, and `alloc_mem_end` must be valid addresses.
+    /// - `ram_start` and `ram_end` must be valid addresses.
     /// - This function must not be called for a second time on the same memory regions.
     ///
     /// The caller must ensure that these assumptions hold, as violating them may lead to undefined behavior.
@@ -187,20 +426,11 @@ impl<'a> Memory<'a> {
     fn new(
         ram_start: Option<usize>,
         ram_end: Option<usize>,
-        alloc_mem_start: Option<usize>,
-        alloc_mem_end: Option<usize>,
+        sc_alloc: Option<&mut InitialAlloc>,
     ) -> Self {
-        // Initializing the first allocator that will be used
-        // to allocate memory to initialize the buddy allocator.
-        // This allocator uses a special reserved memory region
-        // defined in the linker script.
-        let alloc_mem_start = alloc_mem_start.expect(
-            "expected the start address of the reserved allocator memory region, found None.",
-        );
-        let alloc_mem_end = alloc_mem_end.expect(
-            "expected the end address of the reserved allocator memory region, found None.",
-        );
-        let mut sc_alloc = InitialAlloc::new(alloc_mem_start, alloc_mem_end);
+        // The boot allocator, over a special reserved memory region defined in the linker
+        // script, provides the memory to initialize the buddy allocator.
+        let sc_alloc = sc_alloc.expect("expected the boot allocator, found None.");
 
         let start = ram_start.expect("expected the start address of RAM, found None.");
         let end = ram_end.expect("expected the end address of RAM, found None.");
@@ -209,44 +439,17 @@ impl<'a> Memory<'a> {
         // i.e. previous power of two of the actual size.
         let mem_size = end - start;
 
-        // Initialize metadata memory
-        let buddy_node_count = 2 * (mem_size / PAGE_SIZE) - 1;
-        let buddy_meta_size = buddy_node_count * size_of::<BlockState>();
-        let buddy_meta = unsafe {
-            let addr = sc_alloc
-                .page_alloc(buddy_meta_size.div_ceil(PAGE_SIZE))
-                .as_mut_slice_leak::<BlockState>(buddy_node_count);
-
-            addr.as_mut_ptr()
-                .write_bytes(BlockState::Free as u8, buddy_node_count);
-            addr
-        };
-
-        // Initialize stack memory for DFS on metadata
-        let buddy_high_order = find_order(mem_size);
-        let buddy_low_order = find_order(PAGE_SIZE);
-        let buddy_stack_len = buddy_high_order - buddy_low_order + 1;
-        // FIXME: rethink the stack size
-        let buddy_stack_size = buddy_meta_size * size_of::<usize>();
-        let buddy_stack = unsafe {
-            let addr = sc_alloc
-                .page_alloc(buddy_stack_size.div_ceil(PAGE_SIZE))
-                .as_mut_slice_leak::<usize>(buddy_stack_len);
-
-            addr.as_mut_ptr().write_bytes(0, buddy_meta_size);
-            addr
-        };
+        // Metadata memory, one `Block` per page
+        let meta_len = buddy::meta_len(mem_size, PAGE_SIZE);
+        let buddy_meta = sc_alloc
+            .page_alloc((meta_len * size_of::<Block>()).div_ceil(PAGE_SIZE))
+            .lease(meta_len, Block::EMPTY)
+            .leak();
 
         Self {
             start: PhysAddr::new(start, None),
             end: PhysAddr::new(end, None),
-            mem_size,
-            buddy_node_count,
-            buddy_high_order,
-            buddy_low_order,
-            buddy_meta,
-            buddy_stack,
-            buddy_stack_size,
+            buddy: Buddy::new(mem_size, PAGE_SIZE, buddy_meta),
         }
     }
 
@@ -255,111 +458,56 @@ impl<'a> Memory<'a> {
     /// Returns the beginning address of the allocated region if successful,
     /// or an error of type `mem::Error` if the allocation fails.
     /// The returned address is guaranteed to be page-aligned.
-    ///
-    /// This function uses a binary tree represented as an array of `BlockState`s.
     fn buddy_alloc(&mut self, n: usize) -> Result<PhysAddr, Error> {
-        if n > self.mem_size {
-            return Err(Error::OutOfMemory);
-        }
-
         if n == 0 {
             return Err(Error::ZeroSize);
         }
 
-        let n: usize = if n < PAGE_SIZE { PAGE_SIZE } else { n };
-        let n = next_power_of_two(n).expect("can you really handle that size??");
-
-        let req_order = self.buddy_high_order - find_order(n);
-
-        let mut sp = 0_isize;
-        self.buddy_stack[sp as usize] = 0; // index of the first node
-
-        while sp >= 0 {
-            let i = self.buddy_stack[sp as usize];
-            sp -= 1;
-            let level = find_order(i);
-
-            if req_order == level {
-                if self.buddy_meta[i] == BlockState::Free {
-                    self.buddy_meta[i] = BlockState::Allocated;
-
-                    let addr = unsafe {
-                        (self.start.as_usize() as *const u8).add(
-                            ((1 + i) - 2_usize.pow(level as u32))
-                                * 2_usize.pow((self.buddy_high_order - level) as u32),
-                        )
-                    };
-                    return Ok(PhysAddr::new(addr as usize, Some(n)));
-                }
-            } else {
-                match self.buddy_meta[i] {
-                    BlockState::Free => {
-                        self.buddy_meta[i] = BlockState::Split;
-                        sp += 1;
-                        self.buddy_stack[sp as usize] = 2 * i + 2;
-                        sp += 1;
-                        self.buddy_stack[sp as usize] = 2 * i + 1;
-                    }
-                    BlockState::Allocated => continue,
-                    BlockState::Split => {
-                        sp += 1;
-                        self.buddy_stack[sp as usize] = 2 * i + 2;
-                        sp += 1;
-                        self.buddy_stack[sp as usize] = 2 * i + 1;
-                    }
-                }
-            };
-        }
+        let (offset, size) = self.buddy.alloc(n).ok_or(Error::OutOfMemory)?;
 
-        return Err(Error::OutOfMemory);
+        Ok(PhysAddr::new(self.start.as_usize() + offset, Some(size)))
     }
 
-    fn buddy_free(&mut self, addr: PhysAddr) {
-        if let None = addr.size {
-            // If address doesn't have a size,
-            // then it was not allocated by this allocator.
-            return;
+    /// Returns the size of the block allocated at `addr`, `None` if none was or its size
+    /// isn't the one `addr` has.
+    fn allocated_size(&self, addr: PhysAddr) -> Option<usize> {
+        let offset = addr.as_usize().wrapping_sub(self.start.as_usize());
+        match addr.as_usize() < self.end.as_usize() {
+            true => self.buddy.block_size(offset),
+            false => None,
         }
+        .filter(|&size| addr.size.is_none_or(|given| given == size))
+    }
 
-        let size = addr.size.expect("buddy_free(): size is None.");
-        let offset = addr.as_usize() - self.start.as_usize();
-
-        let level = self.buddy_high_order - size.trailing_zeros() as usize; // Size is power of 2
-        let position = offset / size;
-        let i = (1 << level) - 1 + position;
-        let i_at_level = (1 + i) - 2_usize.pow(level as u32);
-
-        if self.buddy_meta[i] == BlockState::Allocated {
-            self.buddy_meta[i] = BlockState::Free;
-        } else {
-            panic!("buddy_free(): Memory at index {i} was not allocated, something is wrong.")
+    fn buddy_free(&mut self, addr: PhysAddr) {
+        let size = self.allocated_size(addr);
+        if !crate::kassert!(
+            size.is_some(),
+            "buddy_free(): {addr:?} was not allocated by this allocator."
+        ) {
+            return;
         }
 
-        // Merge with buddy logic
-
-        let buddy_i_at_level = i_at_level ^ 1;
-        let mut buddy_i = buddy_i_at_level + 2_usize.pow(level as u32) - 1;
-        let mut level = level;
-        let mut i = i;
-        while self.buddy_meta[buddy_i] == BlockState::Free {
-            // in each iteration, i is the parent of the i in previous iterations.
-            i = (i - 1) / 2;
-
-            if self.buddy_meta[i] == BlockState::Split {
-                self.buddy_meta[i] = BlockState::Free;
-            } else {
-                panic!("buddy_free(): Memory at index {i} was not split, something is wrong.")
-            }
-
-            if i == 0 {
-                break;
-            }
+        // Under the lock, so the frames can't be handed out again before they are reset.
+        page::on_free(PhysAddr::new(addr.as_usize(), size));
+        self.buddy.free(addr.as_usize() - self.start.as_usize());
+    }
 
-            level = level - 1;
-            let i_at_level = (1 + i) - 2_usize.pow(level as u32);
-            let buddy_i_at_level = i_at_level ^ 1;
-            buddy_i = buddy_i_at_level + 2_usize.pow(level as u32) - 1;
+    /// Resizes the block of `size` bytes at `addr` to hold `n` bytes without moving it,
+    /// returning it with its new size, `None` if it can't grow where it is.
+    fn buddy_resize(&mut self, addr: PhysAddr, size: usize, n: usize) -> Option<PhysAddr> {
+        let start = addr.as_usize();
+        let new_size = self.buddy.resize(start - self.start.as_usize(), n)?;
+        if new_size > size {
+            page::on_alloc(PhysAddr::new(start + size, Some(new_size - size)));
+            page::on_realloc(
+                PhysAddr::new(start, Some(size)),
+                PhysAddr::new(start, Some(new_size)),
+            );
+        } else if new_size < size {
+            page::on_free(PhysAddr::new(start + new_size, Some(size - new_size)));
         }
+        Some(PhysAddr::new(start, Some(new_size)))
     }
 }
 
@@ -367,26 +515,46 @@ impl<'a> Memory<'a> {
 
 // MARK - PHYSICAL-ADDRESS TYPE DEFINITION
 
-/// `PhysAddr` represents a physical memory address.
+/// `PhysAddr` represents a physical memory address, which is never zero.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(C)]
 pub struct PhysAddr {
-    addr: usize,
+    addr: NonZeroUsize,
     size: Option<usize>,
 }
 
 impl PhysAddr {
+    /// Returns the address `addr` of a region of `size` bytes, if known, without checking
+    /// it. Only for addresses the kernel made itself, such as the allocators' and the page
+    /// tables'; see `new_checked()` for the others.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `addr` is zero.
     pub fn new(addr: usize, size: Option<usize>) -> Self {
-        // FIXME: Should not allow 0
+        let addr = NonZeroUsize::new(addr).expect("PhysAddr::new(): address is zero.");
         Self { addr, size }
     }
 
+    /// Returns the address `addr` of a region of `size` bytes, if known, after checking
+    /// that the region lies in RAM or in a device's registers, in one region of the memory
+    /// map.
+    pub fn new_checked(addr: usize, size: Option<usize>) -> Result<Self, AddrError> {
+        let addr = NonZeroUsize::new(addr).ok_or(AddrError::Null)?;
+        let end = addr
+            .get()
+            .checked_add(size.unwrap_or(1))
+            .ok_or(AddrError::Overflow)?;
+        region_kind(addr.get(), end).ok_or(AddrError::Unmapped)?;
+        Ok(Self { addr, size })
+    }
+
     pub fn size(&self) -> Option<usize> {
         self.size
     }
 
     pub const fn as_usize(self) -> usize {
-        self.addr
+        self.addr.get()
     }
 
     /// Checks if the internal value is aligned to the specified `alignment`.
@@ -394,11 +562,8 @@ impl PhysAddr {
     /// Returns `true` if the internal `usize` value is evenly divisible by `alignment`, indicating
     /// that it is aligned to that boundary. For meaningful alignment checks, `alignment` should
     /// typically be a power of two (e.g., 1, 2, 4, 8).
-    ///
-    /// # Panics
-    /// If `alignment` is zero, this function will panic due to division by zero.
     pub fn is_aligned(&self, alignment: usize) -> bool {
-        self.addr % alignment == 0
+        self.addr.get().is_multiple_of(alignment)
     }
 
     /// Returns a `*const u8` pointer derived from the internal `usize` value.
@@ -407,7 +572,7 @@ impl PhysAddr {
     /// is not dereferenced by this function, so it is safe to call. The caller is responsible
     /// for ensuring the pointer is valid and properly aligned if they choose to dereference it.
     pub fn as_ptr(&self) -> *const u8 {
-        self.addr as *const u8
+        self.addr.get() as *const u8
     }
 
     /// Returns a `*mut u8` pointer derived from the internal `usize` value.
@@ -417,65 +582,7 @@ impl PhysAddr {
     /// that dereferencing or writing to it does not violate Rust's aliasing rules (e.g., no
     /// concurrent mutable access without proper synchronization).
     pub fn as_mut_ptr(&self) -> *mut u8 {
-        self.addr as *const u8 as *mut u8
-    }
-
-    /// # Safety
-    ///
-    /// - `self.addr as *const T` must be a valid, non-null pointer to a readable memory region.
-    /// - The memory region must contain at least `len` initialized elements of type `T`.
-    /// - The pointer must be properly aligned for type `T`.
-    /// - The memory must remain allocated and immutable for the entire duration of the program.
-    pub unsafe fn as_slice<T>(&self, len: usize) -> &[T] {
-        unsafe { slice::from_raw_parts(self.addr as *const T, len) }
-    }
-
-    /// # Safety
-    ///
-    /// - `self.addr as *mut T` must be a valid, non-null pointer to a readable and writable memory region.
-    /// - The memory region must contain at least `len` elements of type `T`.
-    /// - The pointer must be properly aligned for type `T`.
-    /// - The memory must remain allocated for the entire duration of the program.
-    /// - No other references (mutable or immutable) to the memory should exist while the mutable slice is in use.
-    pub unsafe fn as_mut_slice<T>(&mut self, len: usize) -> &mut [T] {
-        unsafe { slice::from_raw_parts_mut(self.addr as *mut T, len) }
-    }
-
-    pub unsafe fn as_mut_slice_leak<T>(self, len: usize) -> &'static mut [T] {
-        unsafe { slice::from_raw_parts_mut(self.addr as *mut T, len) }
-    }
-
-    /// # Safety
-    ///
-    /// - `self.addr as *const T` must be a valid, non-null pointer to a readable memory region containing an initialized value of type `T`.
-    /// - The memory region must be at least `size_of::<T>()` bytes.
-    /// - The pointer must be properly aligned for type `T`.
-    /// - The memory must remain allocated and immutable for the entire duration of the program.
-    pub unsafe fn as_struct<T>(&self) -> &T {
-        unsafe { &*(self.addr as *const T) }
-    }
-
-    /// # Safety
-    ///
-    /// - `self.addr as *mut T` must be a valid, non-null pointer to a readable and writable memory region containing a value of type `T`.
-    /// - The memory region must be at least `size_of::<T>()` bytes.
-    /// - The pointer must be properly aligned for type `T`.
-    /// - The memory must remain allocated for the entire duration of the program.
-    /// - If the reference is used to read, the memory must be initialized.
-    /// - No other references (mutable or immutable) to the memory should exist while the mutable reference is in use.
-    pub unsafe fn as_mut_struct<T>(&mut self) -> &mut T {
-        unsafe { &mut *(self.addr as *const T as *mut T) }
-    }
-
-    /// # Safety
-    ///
-    /// The caller must ensure that:
-    /// - `self.addr as *const u8` is a valid pointer to a readable, initialized memory region of at least `len` bytes.
-    /// - The memory region remains allocated and is not deallocated for the entire duration of the program.
-    /// - The memory region is not mutated for the entire duration of the program, as the returned `&str` references it immutably.
-    pub unsafe fn as_str(&self, len: usize) -> Result<&str, Utf8Error> {
-        let byte_slice = unsafe { slice::from_raw_parts(self.addr as *const u8, len) };
-        core::str::from_utf8(byte_slice)
+        self.addr.get() as *const u8 as *mut u8
     }
 }
 
@@ -519,63 +626,330 @@ impl LowerHex for PhysAddr {
 
 // MARK - END
 
-// MARK - VIRTUAL-ADDRESS TYPE DEFINITION
+// MARK - LEASED MEMORY
 
-/// `VirtAddr` represents a virtual memory address.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-#[repr(transparent)]
-pub struct VirtAddr(usize);
+/// Physical memory owned by a value, given back to the allocator it came from when the
+/// value is dropped, unless it is leaked with `leak()`.
+#[derive(Debug)]
+pub struct OwnedRegion {
+    addr: PhysAddr,
+    // `None` for memory that can't be given back.
+    release: Option<fn(PhysAddr)>,
+}
 
-impl VirtAddr {
-    pub fn new(addr: usize) -> Self {
-        Self(addr)
+impl OwnedRegion {
+    /// Allocates at least `n` bytes from the buddy allocator, like `phalloc()`.
+    pub fn alloc(n: usize) -> Result<Self, Error> {
+        Ok(Self {
+            addr: buddy_alloc(n)?,
+            release: Some(buddy_free),
+        })
     }
 
-    pub const fn as_usize(self) -> usize {
-        self.0
+    /// Turns the region into a slice of `len` copies of `value`, owning the memory.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the region is too small or not aligned for `T`, or has no
+    /// known size.
+    pub fn lease<T: Copy>(self, len: usize, value: T) -> Lease<T> {
+        self.lease_with(len, |_| value)
     }
 
-    pub fn is_aligned(&self, alignment: usize) -> bool {
-        self.0 % alignment == 0
+    /// Turns the region into a slice of `len` values, `f(i)` being the `i`th, owning the
+    /// memory.
+    ///
+    /// # Panics
+    ///
+    /// This function panics like `lease()`.
+    pub fn lease_with<T>(self, len: usize, mut f: impl FnMut(usize) -> T) -> Lease<T> {
+        let size = self.addr.size().expect("lease(): region has no size.");
+        assert!(
+            len * size_of::<T>() <= size && self.addr.is_aligned(align_of::<T>()),
+            "lease(): {len} values don't fit at {:?}.",
+            self.addr
+        );
+        let ptr = self.addr.as_mut_ptr() as *mut T;
+        for i in 0..len {
+            // Safety: The region is ours, large enough and aligned for `len` values.
+            unsafe { ptr.add(i).write(f(i)) };
+        }
+        Lease {
+            region: self,
+            len,
+            _marker: PhantomData,
+        }
     }
-}
-
-impl Add<usize> for VirtAddr {
-    type Output = Self;
 
-    fn add(self, rhs: usize) -> Self::Output {
-        Self(self.0 + rhs)
+    /// Gives up ownership of the memory, which is never returned, and returns its address.
+    pub fn leak(self) -> PhysAddr {
+        let addr = self.addr;
+        core::mem::forget(self);
+        addr
     }
 }
 
-impl Add for VirtAddr {
-    type Output = Self;
-
-    fn add(self, rhs: Self) -> Self::Output {
-        Self(self.0 + rhs.0)
+impl Drop for OwnedRegion {
+    fn drop(&mut self) {
+        if let Some(release) = self.release {
+            release(self.addr);
+        }
     }
 }
 
-impl Sub<usize> for VirtAddr {
-    type Output = Self;
+/// `len` values of `T` in an `OwnedRegion`, which is freed with them.
+#[derive(Debug)]
+pub struct Lease<T> {
+    region: OwnedRegion,
+    len: usize,
+    _marker: PhantomData<T>,
+}
 
-    fn sub(self, rhs: usize) -> Self::Output {
-        Self(self.0 - rhs)
+impl<T> Lease<T> {
+    /// Gives up ownership of the memory, which then stays allocated, for good.
+    pub fn leak(self) -> &'static mut [T] {
+        let len = self.len;
+        let addr = self.region.leak();
+        // Safety: Nothing else owns the memory any more, so nothing frees or reuses it.
+        unsafe { slice::from_raw_parts_mut(addr.as_mut_ptr() as *mut T, len) }
     }
 }
 
-impl Sub for VirtAddr {
-    type Output = Self;
+impl<T> Deref for Lease<T> {
+    type Target = [T];
 
-    fn sub(self, rhs: Self) -> Self::Output {
-        Self(self.0 - rhs.0)
+    fn deref(&self) -> &[T] {
+        // Safety: Initialized by `OwnedRegion::lease()` and owned by `self`.
+        unsafe { slice::from_raw_parts(self.region.addr.as_ptr() as *const T, self.len) }
     }
 }
 
-impl LowerHex for VirtAddr {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        LowerHex::fmt(&self.0, f)
+impl<T> DerefMut for Lease<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        // Safety: As in `deref()`, and borrowed mutably through `self`.
+        unsafe { slice::from_raw_parts_mut(self.region.addr.as_mut_ptr() as *mut T, self.len) }
     }
 }
 
 // MARK - END
+
+// MARK - TESTS
+
+#[cfg(test)]
+mod tests {
+    use core::slice;
+
+    use super::{AddrError, Error, InitialAlloc, OwnedRegion, PAGE_SIZE, PhysAddr};
+    use crate::stdlib::{phalloc, phalloc_uninit, phalloc_zeroed, phrealloc, phree};
+
+    #[test_case]
+    fn alloc_is_page_aligned() {
+        let addr = phalloc(1).unwrap();
+        assert!(addr.is_aligned(PAGE_SIZE));
+        assert_eq!(addr.size(), Some(PAGE_SIZE));
+        phree(addr);
+    }
+
+    #[test_case]
+    fn alloc_rounds_up_to_power_of_two() {
+        let addr = phalloc(3 * PAGE_SIZE).unwrap();
+        assert_eq!(addr.size(), Some(4 * PAGE_SIZE));
+        assert!(addr.is_aligned(4 * PAGE_SIZE));
+        phree(addr);
+    }
+
+    #[test_case]
+    fn blocks_are_zeroed_when_handed_out() {
+        let addr = phalloc(2 * PAGE_SIZE).unwrap();
+        unsafe { addr.as_mut_ptr().write_bytes(0xa5, 2 * PAGE_SIZE) };
+        phree(addr);
+        // Likely the same block, written to, but zeroed again.
+        let again = phalloc(2 * PAGE_SIZE).unwrap();
+        let block = unsafe { slice::from_raw_parts(again.as_ptr(), 2 * PAGE_SIZE) };
+        assert!(block.iter().all(|&b| b == 0));
+        phree(again);
+    }
+
+    #[test_case]
+    fn uninit_blocks_are_left_as_they_were() {
+        let addr = phalloc_uninit(PAGE_SIZE).unwrap();
+        unsafe { addr.as_mut_ptr().write_bytes(0xa5, PAGE_SIZE) };
+        phree(addr);
+        // Likely the same block, unless freeing it merged it into one another page was
+        // split from ahead of it.
+        let again = phalloc_uninit(PAGE_SIZE).unwrap();
+        if again == addr {
+            assert!(
+                unsafe { slice::from_raw_parts(again.as_ptr(), PAGE_SIZE) }
+                    .iter()
+                    .all(|&b| b == 0xa5)
+            );
+        }
+        phree(again);
+        let zeroed = phalloc_zeroed(PAGE_SIZE).unwrap();
+        assert!(
+            unsafe { slice::from_raw_parts(zeroed.as_ptr(), PAGE_SIZE) }
+                .iter()
+                .all(|&b| b == 0)
+        );
+        phree(zeroed);
+    }
+
+    #[test_case]
+    fn blocks_are_freed_by_address_alone() {
+        let used = super::usage().used;
+        let addr = phalloc(3 * PAGE_SIZE).unwrap();
+        assert_eq!(super::usage().used, used + 4 * PAGE_SIZE);
+        phree(PhysAddr::new(addr.as_usize(), None));
+        assert_eq!(super::usage().used, used);
+    }
+
+    #[test_case]
+    fn blocks_are_resized_in_place_when_they_can() {
+        let used = super::usage().used;
+        let addr = phalloc(PAGE_SIZE).unwrap();
+        unsafe { addr.as_mut_ptr().write_bytes(0xa5, PAGE_SIZE) };
+
+        let grown = phrealloc(addr, 4 * PAGE_SIZE).unwrap();
+        assert_eq!(grown.size(), Some(4 * PAGE_SIZE));
+        assert_eq!(super::usage().used, used + 4 * PAGE_SIZE);
+        assert!(
+            unsafe { slice::from_raw_parts(grown.as_ptr(), PAGE_SIZE) }
+                .iter()
+                .all(|&b| b == 0xa5)
+        );
+        let shrunk = phrealloc(grown, PAGE_SIZE).unwrap();
+        assert_eq!(shrunk, PhysAddr::new(grown.as_usize(), Some(PAGE_SIZE)));
+        assert_eq!(super::usage().used, used + PAGE_SIZE);
+
+        // Kept in place, or moved if the buddy above it is taken.
+        let taken = phalloc(PAGE_SIZE).unwrap();
+        let moved = phrealloc(shrunk, 2 * PAGE_SIZE).unwrap();
+        if taken.as_usize() == shrunk.as_usize() + PAGE_SIZE {
+            assert_ne!(moved.as_usize(), shrunk.as_usize());
+        }
+        assert_eq!(unsafe { *moved.as_ptr().add(PAGE_SIZE - 1) }, 0xa5);
+        assert!(matches!(phrealloc(moved, 0), Err(Error::ZeroSize)));
+        phree(moved);
+        phree(taken);
+        assert_eq!(super::usage().used, used);
+    }
+
+    #[test_case]
+    fn alloc_zero_size_fails() {
+        assert!(matches!(phalloc(0), Err(Error::ZeroSize)));
+    }
+
+    #[test_case]
+    fn allocations_do_not_overlap() {
+        let a = phalloc(PAGE_SIZE).unwrap();
+        let b = phalloc(PAGE_SIZE).unwrap();
+        assert!(
+            a.as_usize() + PAGE_SIZE <= b.as_usize() || b.as_usize() + PAGE_SIZE <= a.as_usize()
+        );
+        phree(a);
+        phree(b);
+    }
+
+    #[test_case]
+    fn checked_addresses_are_in_the_memory_map() {
+        let ram = phalloc(PAGE_SIZE).unwrap();
+        let addr = ram.as_usize();
+        assert_eq!(PhysAddr::new_checked(addr, ram.size()), Ok(ram));
+        assert_eq!(PhysAddr::new_checked(0, None), Err(AddrError::Null));
+        assert_eq!(
+            PhysAddr::new_checked(addr, Some(usize::MAX)),
+            Err(AddrError::Overflow)
+        );
+        // Below RAM, where QEMU's virt machine has nothing any driver registered.
+        assert_eq!(
+            PhysAddr::new_checked(PAGE_SIZE, None),
+            Err(AddrError::Unmapped)
+        );
+        phree(ram);
+    }
+
+    #[test_case]
+    fn leases_return_their_pages() {
+        let used = super::usage().used;
+        let mut lease = OwnedRegion::alloc(PAGE_SIZE).unwrap().lease(16, 7_u32);
+        assert_eq!(super::usage().used, used + PAGE_SIZE);
+        assert!(lease.iter().all(|&v| v == 7));
+        lease[15] = 8;
+        assert_eq!(lease.iter().sum::<u32>(), 15 * 7 + 8);
+        drop(lease);
+        assert_eq!(super::usage().used, used);
+    }
+
+    #[test_case]
+    fn boot_allocator_hands_out_zeroed_pages() {
+        #[repr(align(4096))]
+        struct Pages([u8; 3 * PAGE_SIZE]);
+        static mut PAGES: Pages = Pages([0xff; 3 * PAGE_SIZE]);
+
+        let start = unsafe { &raw mut PAGES.0 } as usize;
+        let mut boot_alloc = InitialAlloc::new(start, start + 3 * PAGE_SIZE);
+        let first = boot_alloc.page_alloc(1).leak();
+        let second = boot_alloc.page_alloc(2).leak();
+        assert_eq!(first.as_usize(), start);
+        assert_eq!(second.as_usize(), start + PAGE_SIZE);
+        assert_eq!(second.size(), Some(2 * PAGE_SIZE));
+        assert!(
+            unsafe { slice::from_raw_parts(second.as_ptr(), 2 * PAGE_SIZE) }
+                .iter()
+                .all(|&b| b == 0)
+        );
+
+        // Closed once `init()` ran before the tests.
+        assert!(super::BOOT_ALLOC.lock().is_none());
+    }
+
+    #[test_case]
+    fn aligned_allocations_are_aligned() {
+        use crate::stdlib::phalloc_aligned;
+
+        let align = 16 * PAGE_SIZE;
+        assert!(super::max_alignment() >= align);
+        let addr = phalloc_aligned(PAGE_SIZE, align).unwrap();
+        assert!(addr.is_aligned(align));
+        phree(addr);
+        assert!(matches!(
+            phalloc_aligned(PAGE_SIZE, 3 * PAGE_SIZE),
+            Err(Error::BadAlignment)
+        ));
+        assert!(matches!(
+            phalloc_aligned(PAGE_SIZE, 2 * super::max_alignment()),
+            Err(Error::BadAlignment)
+        ));
+    }
+
+    #[test_case]
+    fn stats_add_up() {
+        let before = super::stats();
+        assert_eq!(
+            before.free_pages + before.used_pages,
+            before.total / PAGE_SIZE
+        );
+        assert!(before.largest_free.is_power_of_two());
+
+        let a = phalloc(2 * PAGE_SIZE).unwrap();
+        let during = super::stats();
+        assert_eq!(during.used_pages, before.used_pages + 2);
+        assert!(during.largest_free <= before.largest_free);
+        phree(a);
+        assert_eq!(super::stats(), before);
+    }
+
+    #[test_case]
+    fn freed_buddies_merge() {
+        let a = phalloc(PAGE_SIZE).unwrap();
+        let b = phalloc(PAGE_SIZE).unwrap();
+        phree(a);
+        phree(b);
+
+        // Both pages merged back, so the same two-page block is handed out again.
+        let c = phalloc(2 * PAGE_SIZE).unwrap();
+        assert_eq!(c.as_usize(), a.as_usize().min(b.as_usize()));
+        phree(c);
+    }
+}
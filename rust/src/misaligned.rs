@@ -0,0 +1,191 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    cpu,
+    emulate::{self, funct3, rd, rs2},
+    hart_id,
+    proc::{self, PROC_MAX},
+    trace,
+    trap::TrapFrame,
+    uaccess::{self, UserAccess},
+};
+
+// Emulation of misaligned loads and stores for cores that trap on them instead of
+// performing them in hardware. The access is done byte by byte on behalf of the trapped
+// context, which then resumes after the instruction.
+
+const OPCODE_LOAD: u32 = 0x03;
+const OPCODE_STORE: u32 = 0x23;
+
+// Misaligned accesses emulated in total and for each pid, to find frequent offenders.
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+static BY_PID: [AtomicUsize; PROC_MAX] = [const { AtomicUsize::new(0) }; PROC_MAX];
+
+/// A load or store decoded from the trapped instruction.
+struct Access {
+    store: bool,
+    width: usize,
+    // Loads narrower than a register are sign-extended unless unsigned.
+    signed: bool,
+    // Register loaded into or stored from.
+    reg: usize,
+}
+
+// Register `x8` to `x15` as named by the 3-bit fields of compressed instructions.
+fn creg(field: u32) -> usize {
+    (field & 0x7) as usize + 8
+}
+
+fn decode(insn: u32) -> Option<Access> {
+    let load = |width, signed| {
+        Some(Access {
+            store: false,
+            width,
+            signed,
+            reg: rd(insn),
+        })
+    };
+    let store = |width, reg| {
+        Some(Access {
+            store: true,
+            width,
+            signed: false,
+            reg,
+        })
+    };
+
+    if emulate::insn_len(insn) == 4 {
+        return match (insn & 0x7f, funct3(insn)) {
+            (OPCODE_LOAD, 1) => load(2, true),
+            (OPCODE_LOAD, 2) => load(4, true),
+            #[cfg(target_arch = "riscv64")]
+            (OPCODE_LOAD, 3) => load(8, true),
+            (OPCODE_LOAD, 5) => load(2, false),
+            #[cfg(target_arch = "riscv64")]
+            (OPCODE_LOAD, 6) => load(4, false),
+            (OPCODE_STORE, 1) => store(2, rs2(insn)),
+            (OPCODE_STORE, 2) => store(4, rs2(insn)),
+            #[cfg(target_arch = "riscv64")]
+            (OPCODE_STORE, 3) => store(8, rs2(insn)),
+            _ => None,
+        };
+    }
+
+    // Compressed instructions, by quadrant and funct3.
+    match (insn & 0b11, insn >> 13 & 0x7) {
+        // c.lw, c.sw
+        (0b00, 0b010) => load(4, true).map(|a| Access {
+            reg: creg(insn >> 2),
+            ..a
+        }),
+        (0b00, 0b110) => store(4, creg(insn >> 2)),
+        // c.lwsp, c.swsp
+        (0b10, 0b010) => load(4, true),
+        (0b10, 0b110) => store(4, (insn >> 2 & 0x1f) as usize),
+        // c.ld, c.sd, c.ldsp, c.sdsp
+        #[cfg(target_arch = "riscv64")]
+        (0b00, 0b011) => load(8, true).map(|a| Access {
+            reg: creg(insn >> 2),
+            ..a
+        }),
+        #[cfg(target_arch = "riscv64")]
+        (0b00, 0b111) => store(8, creg(insn >> 2)),
+        #[cfg(target_arch = "riscv64")]
+        (0b10, 0b011) => load(8, true),
+        #[cfg(target_arch = "riscv64")]
+        (0b10, 0b111) => store(8, (insn >> 2 & 0x1f) as usize),
+        _ => None,
+    }
+}
+
+/// Handles a misaligned load or store at `pc` of address `addr`.
+///
+/// Returns the pc to resume at, or `None` if the instruction isn't one that is emulated.
+pub fn on_misaligned(tf: &mut TrapFrame, pc: usize, addr: usize) -> Option<usize> {
+    let insn = emulate::fetch(pc, 0);
+    // A compressed encoding on a hart without C can't be what trapped. Harts the device tree
+    // didn't describe are assumed to have it, like the kernel is built for.
+    let compressed = emulate::insn_len(insn) == 2;
+    if compressed && !cpu::features(hart_id()).is_none_or(|f| f.has('c')) {
+        return None;
+    }
+    let access = decode(insn)?;
+
+    let _user = UserAccess::new();
+    let needed = match access.store {
+        true => uaccess::Access::Write,
+        false => uaccess::Access::Read,
+    };
+    if uaccess::is_user(addr) && uaccess::check(addr, access.width, needed).is_err() {
+        return None;
+    }
+
+    if access.store {
+        let bytes = tf.reg(access.reg).to_le_bytes();
+        for (i, &byte) in bytes[..access.width].iter().enumerate() {
+            unsafe { ptr::write_volatile((addr + i) as *mut u8, byte) };
+        }
+    } else {
+        let mut bytes = [0; size_of::<usize>()];
+        for (i, byte) in bytes[..access.width].iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile((addr + i) as *const u8) };
+        }
+        let mut value = usize::from_le_bytes(bytes);
+        if access.signed && access.width < size_of::<usize>() {
+            let shift = usize::BITS as usize - 8 * access.width;
+            value = (((value << shift) as isize) >> shift) as usize;
+        }
+        tf.set_reg(access.reg, value);
+    }
+
+    TOTAL.fetch_add(1, Ordering::Relaxed);
+    BY_PID[proc::current()].fetch_add(1, Ordering::Relaxed);
+    trace!(
+        "misaligned: {}-byte access to 0x{addr:x} at 0x{pc:x}",
+        access.width
+    );
+
+    Some(pc + emulate::insn_len(insn))
+}
+
+/// Returns the number of misaligned accesses emulated in total and for `pid`.
+pub fn count(pid: usize) -> (usize, usize) {
+    (
+        TOTAL.load(Ordering::Relaxed),
+        BY_PID[pid].load(Ordering::Relaxed),
+    )
+}
+
+/// Clears the count of `pid`, called when it exits.
+pub fn reset(pid: usize) {
+    BY_PID[pid].store(0, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn emulates_misaligned_load_and_store() {
+        let mut tf = TrapFrame::zeroed();
+        let mut buf = [0_u8; 8];
+        let addr = buf.as_mut_ptr() as usize + 1;
+        let (_, before) = count(proc::current());
+
+        // sw a2, 0(a1)
+        tf.set_reg(12, 0x8899_aabb);
+        let pc = &0x00c5_a023_u32 as *const u32 as usize;
+        assert_eq!(on_misaligned(&mut tf, pc, addr), Some(pc + 4));
+        assert_eq!(buf[1..5], [0xbb, 0xaa, 0x99, 0x88]);
+
+        // lh a0, 0(a1), sign-extended
+        let pc = &0x0005_9503_u32 as *const u32 as usize;
+        assert_eq!(on_misaligned(&mut tf, pc, addr + 2), Some(pc + 4));
+        assert_eq!(tf.reg(10), 0x8899_u16 as i16 as isize as usize);
+
+        assert_eq!(count(proc::current()).1, before + 2);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{
+    elf::{STACK_SIZE, STACK_TOP},
+    file::{self, FileKind},
+    mem::PAGE_SIZE,
+    pagecache, proc,
+    syscall::Errno,
+    vfs,
+    vm::{self, Backing, PAGE_U, PAGE_W, PageTable, VMA_MAX, Vma},
+};
+
+// Memory mappings a process asks for with `mmap()`: zeroed memory, or a file. Pages of a
+// file come from the page cache (see `pagecache`) on the first access, which faults: a
+// private mapping maps the cached page read-only and gets a copy of its own on the first
+// write, while a shared mapping writes to the cached page, which goes back to the file.
+//
+// Mappings are placed below the stack, the highest gap that fits first, unless the process
+// asks for an address with `MAP_FIXED`.
+//
+// FIXME: A mapping doesn't keep its file open. Once the file is removed and closed, its
+// pages the cache let go of read as whatever file reuses the inode.
+
+// Flags of `mmap()`, with the values Linux uses.
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+/// Maps `len` bytes of the file `fd` refers to, from `offset`, or of zeroed memory with
+/// `MAP_ANONYMOUS`, into the calling process with `prot`, made of `PROT_READ`,
+/// `PROT_WRITE` and `PROT_EXEC`, like Linux's `mmap()`. Returns where it is mapped: at
+/// `addr` with `MAP_FIXED`, replacing what was mapped there, anywhere else without.
+///
+/// Fails with `EINVAL` if the flags aren't one of `MAP_SHARED` and `MAP_PRIVATE` and what
+/// goes with them, or `len`, `offset` or a fixed `addr` aren't usable, with `ENODEV` if
+/// `fd` isn't a file that can be mapped, with `EACCES` if it wasn't opened for the access
+/// asked for, and with `ENOMEM` if there is no room for the mapping.
+pub fn mmap(
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> Result<usize, Errno> {
+    let prot = vm::prot_flags(prot).ok_or(Errno::EINVAL)?;
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(Errno::EINVAL),
+    };
+    if flags & !(MAP_SHARED | MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS) != 0
+        || len == 0
+        || !offset.is_multiple_of(PAGE_SIZE)
+    {
+        return Err(Errno::EINVAL);
+    }
+    let len = len
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(Errno::ENOMEM)?;
+
+    let backing = if flags & MAP_ANONYMOUS != 0 {
+        // FIXME: Shared anonymous memory isn't shared with children, they get a copy.
+        Backing::Anon
+    } else {
+        let FileKind::Inode { mount, ino } = file::kind(fd)? else {
+            return Err(Errno::ENODEV);
+        };
+        if vfs::is_dir(mount, ino) {
+            return Err(Errno::ENODEV);
+        }
+        // Pages are read in, and those of a shared mapping written back.
+        let access = file::access(fd)?;
+        let writes_back = shared && prot & PAGE_W != 0;
+        if access == vfs::O_WRONLY || (writes_back && access != vfs::O_RDWR) {
+            return Err(Errno::EACCES);
+        }
+        Backing::File {
+            mount,
+            ino,
+            offset,
+            shared,
+        }
+    };
+
+    proc::with_page_table(|table| {
+        let start = match flags & MAP_FIXED {
+            0 => table
+                .find_gap(len, STACK_TOP - STACK_SIZE)
+                .ok_or(Errno::ENOMEM)?,
+            _ => {
+                let end = addr.checked_add(len).ok_or(Errno::EINVAL)?;
+                if !addr.is_multiple_of(PAGE_SIZE) || addr < PAGE_SIZE || end > STACK_TOP {
+                    return Err(Errno::EINVAL);
+                }
+                unmap(table, addr, end)?;
+                addr
+            }
+        };
+        let end = start + len;
+        table.insert_vma(Vma {
+            start,
+            end,
+            prot,
+            backing,
+        })?;
+        // Files are mapped as pages fault.
+        if backing == Backing::Anon {
+            for page in (start..end).step_by(PAGE_SIZE) {
+                table.map_zero_page(page, prot);
+            }
+        }
+        Ok(start)
+    })
+}
+
+/// Unmaps `addr..addr + len` from the calling process, writing back what was written to
+/// shared mappings of files, like Linux's `munmap()`.
+///
+/// Fails with `EINVAL` if `addr` isn't page-aligned or `len` is 0, and with `ENOMEM` if
+/// that takes too many VMAs.
+pub fn munmap(addr: usize, len: usize) -> Result<(), Errno> {
+    let end = addr
+        .checked_add(len)
+        .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE))
+        .ok_or(Errno::EINVAL)?;
+    if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    proc::with_page_table(|table| unmap(table, addr, end))
+}
+
+fn unmap(table: &mut PageTable, start: usize, end: usize) -> Result<(), Errno> {
+    let mut files = [None; VMA_MAX];
+    for (file, vma) in files.iter_mut().zip(table.vmas()) {
+        if let Backing::File {
+            mount,
+            ino,
+            shared: true,
+            ..
+        } = vma.backing
+            && vma.start < end
+            && start < vma.end
+        {
+            *file = Some((mount, ino));
+        }
+    }
+    table.remove_vmas(start, end)?;
+    table.unmap_range(start, end);
+    for (mount, ino) in files.into_iter().flatten() {
+        pagecache::writeback(mount, ino)?;
+    }
+    Ok(())
+}
+
+/// Handles a fault at `vaddr` in `table`, on a write if `write`, in a mapping of a file,
+/// returning whether the page is there now: the file's page, from the page cache, or a
+/// copy of it written to in a private mapping. Pages of the cache are also mapped in VMAs
+/// that aren't the file's, see `elf::load()`, and get copied the same way.
+///
+/// Fails with `ENOMEM` if there is no memory for the page, or an error reading it.
+pub fn fault(table: &mut PageTable, vaddr: usize, write: bool) -> Result<bool, Errno> {
+    let vaddr = vaddr & !(PAGE_SIZE - 1);
+    let Some(vma) = table.vma_at(vaddr) else {
+        return Ok(false);
+    };
+    if vma.prot == 0 || (write && vma.prot & PAGE_W == 0) || table.swapped(vaddr).is_some() {
+        return Ok(false);
+    }
+    let shared = matches!(vma.backing, Backing::File { shared: true, .. });
+
+    match table.user_page(vaddr) {
+        Some((frame, false)) if write && pagecache::is_cached(frame) => {
+            if shared {
+                // The reference the read-only entry took carries over.
+                table.map_cache_page(vaddr, frame, vma.prot, true);
+            } else {
+                table.copy_cache_page(vaddr, PAGE_U | vma.prot)?;
+            }
+            Ok(true)
+        }
+        Some(_) => Ok(false),
+        None => {
+            let Backing::File {
+                mount, ino, offset, ..
+            } = vma.backing
+            else {
+                return Ok(false);
+            };
+            let frame = vfs::map_page(mount, ino, (offset + vaddr - vma.start) / PAGE_SIZE)?;
+            table.map_cache_page(vaddr, frame, vma.prot, write && shared);
+            if write && !shared {
+                table.copy_cache_page(vaddr, PAGE_U | vma.prot)?;
+            }
+            Ok(true)
+        }
+    }
+}
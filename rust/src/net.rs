@@ -0,0 +1,305 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    dhcp, eth, netconsole, println,
+    rcu::Rcu,
+    syscall::Errno,
+    workqueue::{self, NET_RX_SOFTIRQ},
+};
+
+// The bottom of the network stack: devices, and the interfaces configured on top of them.
+// Received frames travel up through `eth`, which hands them to the protocol layers.
+//
+// Devices are polled for frames: by the idle loop, by senders waiting for a reply, and
+// on every tick, which raises `NET_RX_SOFTIRQ`. Its handler can't poll itself, with
+// interrupts off and maybe the locks of the stack held by the code it returns to, so it
+// queues the poll on the worker.
+
+pub const MAX_INTERFACES: usize = 4;
+
+/// The IPv4 address family, with the value Linux uses.
+pub const AF_INET: u16 = 2;
+
+/// Largest Ethernet frame without the frame check sequence, which devices handle themselves.
+pub const MAX_FRAME: usize = 1514;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+    pub const ZERO: MacAddr = MacAddr([0; 6]);
+}
+
+impl fmt::Debug for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([0xff; 4]);
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_u32(addr: u32) -> Self {
+        Ipv4Addr(addr.to_be_bytes())
+    }
+}
+
+impl fmt::Debug for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+/// An IPv4 address and port, as passed to the socket syscalls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketAddr {
+    pub ip: Ipv4Addr,
+    pub port: u16,
+}
+
+impl SocketAddr {
+    /// Size of Linux's `struct sockaddr_in`, the layout user space passes addresses in.
+    pub const LEN: usize = 16;
+
+    /// Parses a `struct sockaddr_in`.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Errno> {
+        let bytes = bytes.get(..Self::LEN).ok_or(Errno::EINVAL)?;
+        if u16::from_ne_bytes([bytes[0], bytes[1]]) != AF_INET {
+            return Err(Errno::EAFNOSUPPORT);
+        }
+        Ok(Self {
+            port: u16::from_be_bytes([bytes[2], bytes[3]]),
+            ip: Ipv4Addr(bytes[4..8].try_into().unwrap()),
+        })
+    }
+
+    /// Returns the address as a `struct sockaddr_in`.
+    pub fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0..2].copy_from_slice(&AF_INET.to_ne_bytes());
+        bytes[2..4].copy_from_slice(&self.port.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.ip.0);
+        bytes
+    }
+}
+
+/// A network card driver, moving whole Ethernet frames.
+pub trait NetDevice: Sync {
+    fn mac(&self) -> MacAddr;
+
+    /// Returns whether frames sent on the device come back to it, see `loopback`.
+    fn is_loopback(&self) -> bool {
+        false
+    }
+
+    /// Queues `frame` for sending.
+    fn transmit(&self, frame: &[u8]) -> Result<(), Errno>;
+
+    /// Copies the next received frame into `buf`, returning its length, or `None` if
+    /// nothing was received.
+    fn receive(&self, buf: &mut [u8]) -> Option<usize>;
+}
+
+/// Addresses of an interface, all unspecified until configured.
+#[derive(Debug, Clone, Copy)]
+pub struct IpConfig {
+    pub addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+}
+
+#[derive(Clone, Copy)]
+struct Interface {
+    name: &'static str,
+    dev: &'static dyn NetDevice,
+    mac: MacAddr,
+    ip: IpConfig,
+}
+
+// Read for every packet sent or received, routing included.
+static INTERFACES: Rcu<[Option<Interface>; MAX_INTERFACES]> = Rcu::new([None; MAX_INTERFACES]);
+
+/// Adds an interface called `name` for `dev`, returning its index.
+pub fn register(name: &'static str, dev: &'static dyn NetDevice) -> Result<usize, Errno> {
+    INTERFACES.update(|interfaces| {
+        let index = interfaces
+            .iter()
+            .position(|i| i.is_none())
+            .ok_or(Errno::ENOSPC)?;
+
+        interfaces[index] = Some(Interface {
+            name,
+            dev,
+            mac: dev.mac(),
+            ip: IpConfig {
+                addr: Ipv4Addr::UNSPECIFIED,
+                netmask: Ipv4Addr::UNSPECIFIED,
+                gateway: Ipv4Addr::UNSPECIFIED,
+            },
+        });
+
+        Ok(index)
+    })
+}
+
+fn interface(iface: usize) -> Result<Interface, Errno> {
+    INTERFACES
+        .read(|interfaces| interfaces.get(iface).copied().flatten())
+        .ok_or(Errno::ENODEV)
+}
+
+pub fn name(iface: usize) -> Result<&'static str, Errno> {
+    Ok(interface(iface)?.name)
+}
+
+pub fn mac(iface: usize) -> Result<MacAddr, Errno> {
+    Ok(interface(iface)?.mac)
+}
+
+pub fn is_loopback(iface: usize) -> bool {
+    interface(iface).is_ok_and(|i| i.dev.is_loopback())
+}
+
+pub fn ip_config(iface: usize) -> Result<IpConfig, Errno> {
+    Ok(interface(iface)?.ip)
+}
+
+/// Sets the IPv4 addresses of `iface`.
+pub fn set_ip_config(iface: usize, ip: IpConfig) -> Result<(), Errno> {
+    INTERFACES.update(|interfaces| {
+        let interface = interfaces
+            .get_mut(iface)
+            .and_then(|i| i.as_mut())
+            .ok_or(Errno::ENODEV)?;
+        interface.ip = ip;
+        Ok(())
+    })
+}
+
+/// Sends a raw Ethernet frame out of `iface`.
+pub fn transmit(iface: usize, frame: &[u8]) -> Result<(), Errno> {
+    // The device is called without holding the lock, it may take a while.
+    interface(iface)?.dev.transmit(frame)
+}
+
+/// Processes all frames received on every interface so far.
+pub fn poll() {
+    let mut frame = [0; MAX_FRAME];
+
+    for iface in 0..MAX_INTERFACES {
+        let Ok(interface) = interface(iface) else {
+            continue;
+        };
+        while let Some(len) = interface.dev.receive(&mut frame) {
+            eth::receive(iface, &frame[..len]);
+        }
+    }
+
+    dhcp::poll();
+    netconsole::poll();
+}
+
+// Set from queueing a poll on the worker until it starts, so the queue holds one at most.
+static POLL_QUEUED: AtomicBool = AtomicBool::new(false);
+
+/// Has the network interfaces polled soon, called on every tick.
+pub fn on_tick() {
+    workqueue::raise_softirq(NET_RX_SOFTIRQ);
+}
+
+fn net_rx_softirq() {
+    if !POLL_QUEUED.swap(true, Ordering::Relaxed) && workqueue::queue_work(poll_work, 0).is_err() {
+        POLL_QUEUED.store(false, Ordering::Relaxed);
+    }
+}
+
+fn poll_work(_: usize) {
+    POLL_QUEUED.store(false, Ordering::Relaxed);
+    poll();
+}
+
+crate::register_subsystem!(Subsys, "net", init);
+
+fn init() {
+    if let Err(err) = workqueue::open_softirq(NET_RX_SOFTIRQ, net_rx_softirq) {
+        println!("net: can't poll on ticks: {err:?}");
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::sync::{Mutex, OnceCell};
+
+    /// A device that records the last frame sent, for testing the layers above.
+    pub struct MockDevice {
+        pub sent: Mutex<([u8; MAX_FRAME], usize)>,
+    }
+
+    impl MockDevice {
+        pub const MAC: MacAddr = MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+
+        pub const fn new() -> Self {
+            Self {
+                sent: Mutex::new(([0; MAX_FRAME], 0)),
+            }
+        }
+    }
+
+    impl NetDevice for MockDevice {
+        fn mac(&self) -> MacAddr {
+            Self::MAC
+        }
+
+        fn transmit(&self, frame: &[u8]) -> Result<(), Errno> {
+            let mut sent = self.sent.lock();
+            sent.0[..frame.len()].copy_from_slice(frame);
+            sent.1 = frame.len();
+            Ok(())
+        }
+
+        fn receive(&self, _buf: &mut [u8]) -> Option<usize> {
+            None
+        }
+    }
+
+    static MOCK: MockDevice = MockDevice::new();
+    static MOCK_IFACE: OnceCell<usize> = OnceCell::new();
+
+    /// Returns the index of an interface backed by a `MockDevice` with address 10.0.0.2/24.
+    pub fn mock_interface() -> (usize, &'static MockDevice) {
+        let iface = *MOCK_IFACE.get_or_init(|| register("mock0", &MOCK).unwrap());
+        set_ip_config(
+            iface,
+            IpConfig {
+                addr: Ipv4Addr([10, 0, 0, 2]),
+                netmask: Ipv4Addr([255, 255, 255, 0]),
+                gateway: Ipv4Addr([10, 0, 0, 1]),
+            },
+        )
+        .unwrap();
+        (iface, &MOCK)
+    }
+
+    #[test_case]
+    fn configures_interfaces() {
+        let (iface, _) = mock_interface();
+        assert_eq!(mac(iface), Ok(MockDevice::MAC));
+        assert_eq!(ip_config(iface).unwrap().addr, Ipv4Addr([10, 0, 0, 2]));
+        assert_eq!(mac(MAX_INTERFACES), Err(Errno::ENODEV));
+    }
+}
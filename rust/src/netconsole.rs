@@ -0,0 +1,225 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    arp, cmdline, console, ipv4,
+    net::{Ipv4Addr, SocketAddr},
+    println,
+    sync::{Mutex, OnceCell},
+    udp,
+};
+
+// A network console, like Linux's netconsole: everything printed on the console, kernel
+// messages and program output alike, is also sent to a remote host in UDP datagrams, for
+// boards without a serial line. `netconsole=<ip>:<port>` on the command line names the
+// host, which can listen with `nc -u -l <port>`. With `netconsole=<ip>:<port>,input`,
+// datagrams that host sends to port `INPUT_PORT` are taken as typed console input too, so
+// the shell can be used over the network. There is no TCP, so nothing is retransmitted.
+//
+// Printing happens with any lock held, those of the network stack included, so output is
+// only buffered then, and sent from `net::poll()`. Output from before the stack can reach
+// the host is kept until it can, as much as fits: the oldest is dropped first.
+
+const BUF_LEN: usize = 4096;
+// Bytes sent per datagram.
+const CHUNK: usize = 512;
+/// Port console input is taken on, the source port of Linux's netconsole.
+pub const INPUT_PORT: u16 = 6665;
+
+/// Output not sent yet. Positions count every byte ever buffered, so what was sent can be
+/// dropped even if older bytes were overwritten meanwhile.
+struct Ring {
+    buf: [u8; BUF_LEN],
+    head: usize,
+    tail: usize,
+}
+
+impl Ring {
+    fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.buf[self.tail % BUF_LEN] = b;
+            self.tail = self.tail.wrapping_add(1);
+        }
+        if self.tail.wrapping_sub(self.head) > BUF_LEN {
+            self.head = self.tail.wrapping_sub(BUF_LEN);
+        }
+    }
+
+    /// Copies the oldest bytes into `out`, returning where they start and how many there are.
+    fn peek(&self, out: &mut [u8]) -> (usize, usize) {
+        let len = self.tail.wrapping_sub(self.head).min(out.len());
+        for (i, b) in out[..len].iter_mut().enumerate() {
+            *b = self.buf[self.head.wrapping_add(i) % BUF_LEN];
+        }
+        (self.head, len)
+    }
+
+    /// Drops the bytes before position `end`, unless they already were.
+    fn consume(&mut self, end: usize) {
+        if end.wrapping_sub(self.head) <= self.tail.wrapping_sub(self.head) {
+            self.head = end;
+        }
+    }
+}
+
+struct Config {
+    sock: usize,
+    remote: SocketAddr,
+    input: bool,
+}
+
+static OUTPUT: Mutex<Ring> = Mutex::new(Ring {
+    buf: [0; BUF_LEN],
+    head: 0,
+    tail: 0,
+});
+// Output is buffered until `init()` finds out whether there is a host to send it to.
+static CAPTURE: AtomicBool = AtomicBool::new(true);
+static CONFIG: OnceCell<Config> = OnceCell::new();
+// Held while sending, which may print or poll the network again.
+static SENDING: Mutex<()> = Mutex::new(());
+
+/// Buffers console output for the remote host. Safe to call from anywhere, output is dropped
+/// rather than waited for if the buffer is busy.
+pub fn capture(bytes: &[u8]) {
+    if !CAPTURE.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(mut output) = OUTPUT.try_lock() {
+        output.push(bytes);
+    }
+}
+
+/// Sends buffered output and takes console input, called by `net::poll()`.
+pub fn poll() {
+    let Some(config) = CONFIG.get() else {
+        return;
+    };
+    let Some(_sending) = SENDING.try_lock() else {
+        return;
+    };
+
+    let mut chunk = [0; CHUNK];
+    if config.input {
+        while let Some((len, from)) = udp::try_recv_from(config.sock, &mut chunk) {
+            if from.ip == config.remote.ip {
+                console::feed(&chunk[..len]);
+            }
+        }
+    }
+
+    // Sending blocks until the next hop is resolved, which the idle loop can't, so output
+    // waits for the answer to the request this sends instead.
+    let resolved =
+        ipv4::route(config.remote.ip).and_then(|(iface, next_hop)| arp::resolve(iface, next_hop));
+    if resolved.is_err() {
+        return;
+    }
+    loop {
+        let (start, len) = OUTPUT.lock().peek(&mut chunk);
+        // Kept for later if the host can't be reached yet.
+        if len == 0 || udp::send_to(config.sock, config.remote, &chunk[..len]).is_err() {
+            break;
+        }
+        OUTPUT.lock().consume(start.wrapping_add(len));
+    }
+}
+
+/// Parses `<ip>:<port>[,input]`.
+fn parse(arg: &str) -> Option<(SocketAddr, bool)> {
+    let (addr, input) = match arg.split_once(',') {
+        Some((addr, "input")) => (addr, true),
+        Some(_) => return None,
+        None => (arg, false),
+    };
+    let (ip, port) = addr.split_once(':')?;
+    let mut octets = [0; 4];
+    let mut parts = ip.split('.');
+    for octet in &mut octets {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    let remote = SocketAddr {
+        ip: Ipv4Addr(octets),
+        port: port.parse().ok()?,
+    };
+    Some((remote, input))
+}
+
+crate::register_subsystem!(Late, "netconsole", init);
+
+fn init() {
+    let Some(arg) = cmdline::get("netconsole") else {
+        CAPTURE.store(false, Ordering::Relaxed);
+        let mut output = OUTPUT.lock();
+        output.head = output.tail;
+        return;
+    };
+    let Some((remote, input)) = parse(arg) else {
+        CAPTURE.store(false, Ordering::Relaxed);
+        println!("netconsole: expected <ip>:<port>[,input], got '{arg}'");
+        return;
+    };
+    let sock = match udp::create() {
+        Ok(sock) => sock,
+        Err(err) => {
+            CAPTURE.store(false, Ordering::Relaxed);
+            println!("netconsole: can't create a socket: {err:?}");
+            return;
+        }
+    };
+    let local = SocketAddr {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: if input { INPUT_PORT } else { 0 },
+    };
+    if let Err(err) = udp::bind(sock, local) {
+        println!("netconsole: can't bind port {}: {err:?}", local.port);
+    }
+    CONFIG.get_or_init(|| Config {
+        sock,
+        remote,
+        input,
+    });
+    println!("netconsole: sending to {:?}:{}", remote.ip, remote.port);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ring_keeps_the_newest_output() {
+        let mut ring = Ring {
+            buf: [0; BUF_LEN],
+            head: 0,
+            tail: 0,
+        };
+        let mut out = [0; 4];
+
+        ring.push(b"hello");
+        assert_eq!(ring.peek(&mut out), (0, 4));
+        assert_eq!(&out, b"hell");
+        ring.consume(4);
+        assert_eq!(ring.peek(&mut out), (4, 1));
+
+        // What was peeked is overwritten before it is sent, which then drops nothing more.
+        ring.push(&[b'x'; BUF_LEN]);
+        ring.consume(5);
+        assert_eq!(ring.peek(&mut out), (5, 4));
+        assert_eq!(&out, b"xxxx");
+    }
+
+    #[test_case]
+    fn parses_the_remote_host() {
+        let remote = SocketAddr {
+            ip: Ipv4Addr([10, 0, 2, 2]),
+            port: 6666,
+        };
+        assert_eq!(parse("10.0.2.2:6666"), Some((remote, false)));
+        assert_eq!(parse("10.0.2.2:6666,input"), Some((remote, true)));
+        assert_eq!(parse("10.0.2:6666"), None);
+        assert_eq!(parse("10.0.2.2.1:6666"), None);
+        assert_eq!(parse("10.0.2.2"), None);
+    }
+}
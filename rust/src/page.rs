@@ -0,0 +1,244 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU8, AtomicU16, Ordering},
+};
+
+use crate::{
+    mem::{self, PAGE_SIZE, PhysAddr},
+    sync::OnceCell,
+};
+
+// The page descriptor table: a `Page` for every frame of the kernel's RAM, indexed by page
+// frame number (PFN), with what the kernel knows about the frame beyond whether it is
+// free. Frames the buddy allocator doesn't manage, like those of the kernel image and the
+// initrd, are reserved. The allocator marks frames as kernel memory when it hands them out
+// and clears them when they come back; the code they are handed to says what it uses them
+// for.
+
+/// Never handed out.
+pub const RESERVED: u8 = 1 << 0;
+/// Holds slab objects.
+pub const SLAB: u8 = 1 << 1;
+/// Caches file contents.
+pub const PAGE_CACHE: u8 = 1 << 2;
+/// Anonymous memory of a user process.
+pub const USER_ANON: u8 = 1 << 3;
+
+/// Who a frame was handed out to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Owner {
+    Free = 0,
+    Kernel = 1,
+    PageTable = 2,
+    User = 3,
+}
+
+/// The descriptor of a frame.
+#[derive(Debug)]
+pub struct Page {
+    flags: AtomicU8,
+    owner: AtomicU8,
+    // Page aging scans since a user page was last found accessed, see `vm::PageTable::age()`.
+    age: AtomicU8,
+    refcount: AtomicU16,
+}
+
+impl Page {
+    const fn new() -> Self {
+        Self {
+            flags: AtomicU8::new(0),
+            owner: AtomicU8::new(Owner::Free as u8),
+            age: AtomicU8::new(0),
+            refcount: AtomicU16::new(0),
+        }
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.flags.load(Ordering::Relaxed)
+    }
+
+    pub fn set_flags(&self, flags: u8) {
+        self.flags.fetch_or(flags, Ordering::Relaxed);
+    }
+
+    pub fn clear_flags(&self, flags: u8) {
+        self.flags.fetch_and(!flags, Ordering::Relaxed);
+    }
+
+    pub fn owner(&self) -> Owner {
+        match self.owner.load(Ordering::Relaxed) {
+            1 => Owner::Kernel,
+            2 => Owner::PageTable,
+            3 => Owner::User,
+            _ => Owner::Free,
+        }
+    }
+
+    pub fn set_owner(&self, owner: Owner) {
+        self.owner.store(owner as u8, Ordering::Relaxed);
+    }
+
+    /// Returns how many aging scans in a row found the page unused, up to `u8::MAX`.
+    pub fn age(&self) -> usize {
+        self.age.load(Ordering::Relaxed) as usize
+    }
+
+    /// Records an aging scan, which found the page used or not, returning its new age.
+    pub fn on_scan(&self, accessed: bool) -> usize {
+        let age = match accessed {
+            true => 0,
+            false => self.age.load(Ordering::Relaxed).saturating_add(1),
+        };
+        self.age.store(age, Ordering::Relaxed);
+        age as usize
+    }
+
+    /// Returns how many users the frame has, 0 once it is free.
+    pub fn refcount(&self) -> usize {
+        self.refcount.load(Ordering::Relaxed) as usize
+    }
+
+    /// Adds a user of the frame, returning how many it has now.
+    pub fn get(&self) -> usize {
+        self.refcount.fetch_add(1, Ordering::Relaxed) as usize + 1
+    }
+
+    /// Drops a user of the frame, returning how many are left.
+    pub fn put(&self) -> usize {
+        let old = self.refcount.fetch_sub(1, Ordering::Relaxed) as usize;
+        crate::kassert!(old > 0, "page::put(): refcount of a free page dropped.");
+        old.saturating_sub(1)
+    }
+
+    fn reset(&self, owner: Owner, refcount: u16) {
+        // Whether the frame is reserved never changes.
+        self.clear_flags(!RESERVED);
+        self.age.store(0, Ordering::Relaxed);
+        self.set_owner(owner);
+        self.refcount.store(refcount, Ordering::Relaxed);
+    }
+}
+
+/// The descriptors, and the PFN of the first one.
+struct Table {
+    pages: &'static [Page],
+    base: usize,
+}
+
+static TABLE: OnceCell<Table> = OnceCell::new();
+
+pub fn pfn(addr: usize) -> usize {
+    addr / PAGE_SIZE
+}
+
+/// Sets up descriptors for the RAM at `start..end`, from the boot allocator, so it must be
+/// called before `mem::init()`. Frames outside of `managed`, the RAM the buddy allocator
+/// is given, are reserved for the kernel.
+pub fn init(start: usize, end: usize, managed: Range<usize>) {
+    let len = pfn(end) - pfn(start);
+    let region = mem::boot_alloc((len * size_of::<Page>()).div_ceil(PAGE_SIZE));
+    let pages = region
+        .lease_with(len, |i| {
+            let page = Page::new();
+            if !managed.contains(&((pfn(start) + i) * PAGE_SIZE)) {
+                page.set_owner(Owner::Kernel);
+                page.set_flags(RESERVED);
+            }
+            page
+        })
+        .leak();
+    TABLE.get_or_init(|| Table {
+        pages,
+        base: pfn(start),
+    });
+}
+
+/// Returns the descriptor of the frame `addr` is in, `None` if it isn't managed RAM.
+pub fn get(addr: usize) -> Option<&'static Page> {
+    let table = TABLE.get()?;
+    table.pages.get(pfn(addr).checked_sub(table.base)?)
+}
+
+/// Calls `f` with the descriptor of every frame of the block at `addr`.
+fn for_block(addr: PhysAddr, mut f: impl FnMut(&Page)) {
+    let pages = addr.size().unwrap_or(PAGE_SIZE) / PAGE_SIZE;
+    for page in 0..pages {
+        if let Some(page) = get(addr.as_usize() + page * PAGE_SIZE) {
+            f(page);
+        }
+    }
+}
+
+/// Marks the frames of a block the buddy allocator handed out as the kernel's.
+pub fn on_alloc(addr: PhysAddr) {
+    for_block(addr, |page| page.reset(Owner::Kernel, 1));
+}
+
+/// Marks the frames of a block given back to the buddy allocator as free.
+pub fn on_free(addr: PhysAddr) {
+    for_block(addr, |page| {
+        crate::kassert!(
+            page.flags() & RESERVED == 0,
+            "page::on_free(): {addr:?} has reserved frames."
+        );
+        page.reset(Owner::Free, 0)
+    });
+}
+
+/// Gives the frames of the block `to`, which the block `from` grew or was moved into, the
+/// owner and flags of those of `from`, the frames it grew by those of its first frame. Must
+/// be called before `from` is freed.
+pub fn on_realloc(from: PhysAddr, to: PhysAddr) {
+    let Some(first) = get(from.as_usize()) else {
+        return;
+    };
+    let mut frame = 0;
+    for_block(to, |page| {
+        let old = match frame * PAGE_SIZE < from.size().unwrap_or(PAGE_SIZE) {
+            true => get(from.as_usize() + frame * PAGE_SIZE).unwrap_or(first),
+            false => first,
+        };
+        frame += 1;
+        page.flags.store(old.flags(), Ordering::Relaxed);
+        page.set_owner(old.owner());
+    });
+}
+
+/// Returns how many frames `owner` has.
+pub fn count(owner: Owner) -> usize {
+    TABLE.get().map_or(0, |table| {
+        table.pages.iter().filter(|p| p.owner() == owner).count()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stdlib::{phalloc, phree};
+
+    #[test_case]
+    fn descriptors_follow_allocations() {
+        let addr = phalloc(2 * PAGE_SIZE).unwrap();
+        let second = get(addr.as_usize() + PAGE_SIZE).unwrap();
+        assert_eq!(second.owner(), Owner::Kernel);
+        assert_eq!(second.refcount(), 1);
+
+        let user = count(Owner::User);
+        second.set_owner(Owner::User);
+        second.set_flags(USER_ANON);
+        assert_eq!(count(Owner::User), user + 1);
+        assert_eq!(second.get(), 2);
+        assert_eq!(second.put(), 1);
+
+        phree(addr);
+        assert_eq!(second.owner(), Owner::Free);
+        assert_eq!((second.flags(), second.refcount()), (0, 0));
+        assert!(get(0).is_none());
+
+        // The kernel's own code.
+        let text = get(init as *const () as usize).unwrap();
+        assert_eq!(text.flags(), RESERVED);
+        assert_eq!(text.owner(), Owner::Kernel);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::{
+    mem::{PAGE_SIZE, PhysAddr},
+    page,
+    stdlib::{phalloc, phree},
+    sync::Mutex,
+    syscall::Errno,
+    vfs::FileSystem,
+};
+
+// The page cache: pages of files, read once and then shared by every mapping of them (see
+// `mmap`), a frame each. A frame is referenced once by the cache and once by every page
+// table entry mapping it, and evicted, least recently used first, only when the cache is
+// full and nothing maps it any more.
+//
+// Pages written through a shared mapping are dirty, and so are those mapped writable, which
+// may be written at any time. `writeback()` writes them back to their file: before the file
+// is read or synced, when a shared mapping goes away, and when evicted. `vfs::write()`
+// copies what it writes into cached pages, so they never go stale.
+//
+// Files stay opened (`FileSystem::opened()`) while the cache has pages of them, so their
+// inodes aren't freed or reused under it.
+//
+// FIXME: Only what the cache holds when it is full is evicted, it has no shrinker: giving
+// back a page calls into the filesystem, which may be what is allocating.
+
+const MAX_PAGES: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Cached {
+    fs: &'static dyn FileSystem,
+    mount: usize,
+    ino: usize,
+    // Page of the file, in pages.
+    index: usize,
+    frame: usize,
+    // Bytes of the page that are file contents, the rest is past its end.
+    len: usize,
+    dirty: bool,
+    // Page table entries mapping the frame writable.
+    writers: usize,
+    used: usize,
+}
+
+impl Cached {
+    fn is(&self, mount: usize, ino: usize, index: usize) -> bool {
+        self.mount == mount && self.ino == ino && self.index == index
+    }
+
+    fn refs(&self) -> usize {
+        page::get(self.frame).map_or(1, |page| page.refcount())
+    }
+
+    /// Writes the page back to its file.
+    fn write_back(&self) -> Result<(), Errno> {
+        let data = unsafe { core::slice::from_raw_parts(self.frame as *const u8, self.len) };
+        self.fs.write(self.ino, self.index * PAGE_SIZE, data)?;
+        Ok(())
+    }
+
+    /// Frees the frame, which nothing maps any more.
+    fn free(self) {
+        phree(PhysAddr::new(self.frame, Some(PAGE_SIZE)));
+        self.fs.released(self.ino);
+    }
+}
+
+struct Cache {
+    pages: [Option<Cached>; MAX_PAGES],
+    clock: usize,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    pages: [None; MAX_PAGES],
+    clock: 0,
+});
+
+/// Returns the frame holding page `index` of file `ino` of `fs`, mounted at `mount`, reading
+/// it in if it isn't cached, with a reference taken for the page table entry the caller maps
+/// it with (see `unmap()`).
+///
+/// Fails with `ENOMEM` if there is no memory for it or the cache is full of mapped pages.
+pub fn map(
+    mount: usize,
+    fs: &'static dyn FileSystem,
+    ino: usize,
+    index: usize,
+) -> Result<usize, Errno> {
+    let mut cache = CACHE.lock();
+    cache.clock += 1;
+    let clock = cache.clock;
+    if let Some(cached) = cache
+        .pages
+        .iter_mut()
+        .flatten()
+        .find(|c| c.is(mount, ino, index))
+    {
+        cached.used = clock;
+        page::get(cached.frame).map(|page| page.get());
+        return Ok(cached.frame);
+    }
+    // The filesystem may take a while, and may allocate.
+    drop(cache);
+
+    let frame = phalloc(PAGE_SIZE).map_err(|_| Errno::ENOMEM)?;
+    let page = unsafe { core::slice::from_raw_parts_mut(frame.as_mut_ptr(), PAGE_SIZE) };
+    let len = match fs.read(ino, index * PAGE_SIZE, page) {
+        Ok(len) => len,
+        Err(errno) => {
+            phree(frame);
+            return Err(errno);
+        }
+    };
+    page[len..].fill(0);
+    let frame = frame.as_usize();
+    if let Some(page) = page::get(frame) {
+        page.set_flags(page::PAGE_CACHE);
+    }
+
+    let mut cache = CACHE.lock();
+    // Another hart may have read it meanwhile.
+    if let Some(cached) = cache
+        .pages
+        .iter()
+        .flatten()
+        .find(|c| c.is(mount, ino, index))
+    {
+        let cached = cached.frame;
+        page::get(cached).map(|page| page.get());
+        drop(cache);
+        phree(PhysAddr::new(frame, Some(PAGE_SIZE)));
+        return Ok(cached);
+    }
+    let slot = match cache.pages.iter().position(Option::is_none) {
+        Some(slot) => slot,
+        None => {
+            let Some(slot) = cache
+                .pages
+                .iter()
+                .enumerate()
+                .filter_map(|(slot, c)| Some((slot, c.as_ref()?)))
+                .filter(|(_, c)| c.refs() == 1)
+                .min_by_key(|(_, c)| c.used)
+                .map(|(slot, _)| slot)
+            else {
+                drop(cache);
+                phree(PhysAddr::new(frame, Some(PAGE_SIZE)));
+                return Err(Errno::ENOMEM);
+            };
+            let evicted = cache.pages[slot].take();
+            cache.pages[slot] = Some(placeholder(fs, mount, ino, index, frame, len, clock));
+            drop(cache);
+            if let Some(evicted) = evicted {
+                if evicted.dirty || evicted.writers > 0 {
+                    // Nowhere else to keep it, it is lost if the file can't take it.
+                    let _ = evicted.write_back();
+                }
+                evicted.free();
+            }
+            fs.opened(ino);
+            page::get(frame).map(|page| page.get());
+            return Ok(frame);
+        }
+    };
+    cache.pages[slot] = Some(placeholder(fs, mount, ino, index, frame, len, clock));
+    page::get(frame).map(|page| page.get());
+    drop(cache);
+    fs.opened(ino);
+    Ok(frame)
+}
+
+fn placeholder(
+    fs: &'static dyn FileSystem,
+    mount: usize,
+    ino: usize,
+    index: usize,
+    frame: usize,
+    len: usize,
+    used: usize,
+) -> Cached {
+    Cached {
+        fs,
+        mount,
+        ino,
+        index,
+        frame,
+        len,
+        dirty: false,
+        writers: 0,
+        used,
+    }
+}
+
+/// Returns whether `frame` is a page of the cache.
+pub fn is_cached(frame: usize) -> bool {
+    page::get(frame).is_some_and(|page| page.flags() & page::PAGE_CACHE != 0)
+}
+
+/// Calls `f` with the entry of `frame`, if it is cached.
+fn with_frame(frame: usize, f: impl FnOnce(&mut Cached)) {
+    if let Some(cached) = CACHE
+        .lock()
+        .pages
+        .iter_mut()
+        .flatten()
+        .find(|c| c.frame == frame)
+    {
+        f(cached);
+    }
+}
+
+/// Notes that a page table entry maps `frame` writable from now on, in a shared mapping.
+pub fn make_writable(frame: usize) {
+    with_frame(frame, |cached| {
+        cached.writers += 1;
+        cached.dirty = true;
+    });
+}
+
+/// Notes that a page table entry mapping `frame` writable is now read-only.
+pub fn make_read_only(frame: usize) {
+    with_frame(frame, |cached| {
+        cached.writers = cached.writers.saturating_sub(1)
+    });
+}
+
+/// Takes a reference to `frame` for another page table entry mapping it, writable if
+/// `writable`, when a process forks.
+pub fn dup(frame: usize, writable: bool) {
+    if let Some(page) = page::get(frame) {
+        page.get();
+    }
+    if writable {
+        make_writable(frame);
+    }
+}
+
+/// Drops the reference of a page table entry that mapped `frame`, writable if `writable`.
+pub fn unmap(frame: usize, writable: bool) {
+    if writable {
+        make_read_only(frame);
+    }
+    if let Some(page) = page::get(frame) {
+        page.put();
+    }
+}
+
+/// Writes back the dirty pages of file `ino` on `mount`, and those mapped writable, which
+/// may be written any time.
+pub fn writeback(mount: usize, ino: usize) -> Result<(), Errno> {
+    writeback_where(|c| c.mount == mount && c.ino == ino)
+}
+
+/// Writes back the dirty pages of every file, like `writeback()`.
+pub fn writeback_all() -> Result<(), Errno> {
+    writeback_where(|_| true)
+}
+
+/// Writes back the pages `which` picks that are dirty or mapped writable, returning the
+/// last error but going on past it.
+fn writeback_where(which: impl Fn(&Cached) -> bool) -> Result<(), Errno> {
+    let mut result = Ok(());
+    for slot in 0..MAX_PAGES {
+        let cached = {
+            let mut cache = CACHE.lock();
+            let Some(cached) = cache.pages[slot]
+                .as_mut()
+                .filter(|c| which(c) && (c.dirty || c.writers > 0))
+            else {
+                continue;
+            };
+            cached.dirty = false;
+            // Kept from being evicted while it is written.
+            page::get(cached.frame).map(|page| page.get());
+            *cached
+        };
+        if let Err(errno) = cached.write_back() {
+            with_frame(cached.frame, |c| c.dirty = true);
+            result = Err(errno);
+        }
+        page::get(cached.frame).map(|page| page.put());
+    }
+    result
+}
+
+/// Copies `buf`, just written to file `ino` on `mount` at `offset`, into the pages cached of
+/// it.
+pub fn write_through(mount: usize, ino: usize, offset: usize, buf: &[u8]) {
+    let end = offset + buf.len();
+    for cached in CACHE.lock().pages.iter_mut().flatten() {
+        let start = cached.index * PAGE_SIZE;
+        if cached.mount != mount || cached.ino != ino || end <= start {
+            continue;
+        }
+        let (from, to) = (offset.max(start), end.min(start + PAGE_SIZE));
+        if from >= to {
+            continue;
+        }
+        unsafe {
+            core::slice::from_raw_parts_mut((cached.frame + from - start) as *mut u8, to - from)
+                .copy_from_slice(&buf[from - offset..to - offset]);
+        }
+        cached.len = cached.len.max(to - start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ramfs::RamFs;
+    use crate::vfs::Metadata;
+
+    #[test_case]
+    fn mapped_pages_are_shared_and_written_back() {
+        static FS: RamFs = RamFs::new();
+        let meta = Metadata {
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+        };
+        let ino = FS.create(FS.root(), "f", meta).unwrap();
+        FS.write(ino, PAGE_SIZE, b"abc").unwrap();
+        // Not a mount number, nothing else in the cache uses it.
+        let mount = usize::MAX;
+
+        let frame = map(mount, &FS, ino, 1).unwrap();
+        assert_eq!(map(mount, &FS, ino, 1), Ok(frame));
+        assert!(is_cached(frame));
+        let page = page::get(frame).unwrap();
+        assert_eq!(page.refcount(), 3);
+        assert_eq!(unsafe { *((frame + 2) as *const u8) }, b'c');
+
+        make_writable(frame);
+        unsafe { *(frame as *mut u8) = b'x' };
+        write_through(mount, ino, PAGE_SIZE + 1, b"y");
+        assert_eq!(writeback(mount, ino), Ok(()));
+        let mut buf = [0; 4];
+        assert_eq!(FS.read(ino, PAGE_SIZE, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"xyc");
+
+        unmap(frame, true);
+        unmap(frame, false);
+        assert_eq!(page.refcount(), 1);
+        FS.unlink(FS.root(), "f").unwrap();
+    }
+}
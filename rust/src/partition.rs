@@ -0,0 +1,425 @@
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    block::{self, BlockDevice, SECTOR_SIZE},
+    println,
+    sync::OnceCell,
+    syscall::Errno,
+    vfs::BufWriter,
+};
+
+// Partition tables, read off every disk `block::register()` is given: the four primary
+// entries of an MBR, or, when the MBR is GPT's protective one, the entries of the GPT.
+// Each partition is added as a block device of its own, named after the disk followed by
+// its number in the table, counting from 1 as Linux does: `vda1` is the first entry.
+//
+// Tables are read through `block::read()`, the GPT's entries filling a read-ahead window.
+// Extended MBR partitions aren't followed, and GPT's backup copy at the end of the disk
+// isn't read: a corrupt primary GPT leaves the disk without partitions.
+
+// Partitions on all disks together.
+const MAX_PARTITIONS: usize = 8;
+// Longest partition name.
+const NAME_MAX: usize = 16;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_LEN: usize = 16;
+const MBR_EMPTY: u8 = 0x00;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const MBR_GPT_PROTECTIVE: u8 = 0xee;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_LEN: usize = 92;
+const GPT_ENTRY_LEN: usize = 128;
+
+/// A partition's place in its table and on its disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entry {
+    pub number: usize,
+    pub start: u64,
+    pub sectors: u64,
+}
+
+/// A partition, the sectors of a disk it covers being a block device of their own.
+pub struct Partition {
+    disk: &'static dyn BlockDevice,
+    start: u64,
+    sectors: u64,
+    name: [u8; NAME_MAX],
+    name_len: usize,
+}
+
+impl Partition {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap()
+    }
+}
+
+impl BlockDevice for Partition {
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        block::check_range(self.sectors, sector, buf.len())?;
+        self.disk.read(self.start + sector, buf)
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        block::check_range(self.sectors, sector, buf.len())?;
+        self.disk.write(self.start + sector, buf)
+    }
+
+    fn read_vectored(&self, sector: u64, bufs: &mut [&mut [u8]]) -> Result<(), Errno> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        block::check_range(self.sectors, sector, len)?;
+        self.disk.read_vectored(self.start + sector, bufs)
+    }
+
+    fn write_vectored(&self, sector: u64, bufs: &[&[u8]]) -> Result<(), Errno> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        block::check_range(self.sectors, sector, len)?;
+        self.disk.write_vectored(self.start + sector, bufs)
+    }
+
+    // The disk's cache holds the writes of all its partitions.
+    fn flush(&self) -> Result<(), Errno> {
+        self.disk.flush()
+    }
+}
+
+static PARTITIONS: [OnceCell<Partition>; MAX_PARTITIONS] =
+    [const { OnceCell::new() }; MAX_PARTITIONS];
+static NEXT_PARTITION: AtomicUsize = AtomicUsize::new(0);
+
+/// Reads sectors of a disk, starting at the one given, into a buffer a whole number of
+/// sectors long.
+pub type ReadFn<'a> = &'a dyn Fn(u64, &mut [u8]) -> Result<(), Errno>;
+
+/// Adds the partitions on `disk`, the block device at `index`, as block devices, called by
+/// `block::register()`.
+pub fn scan(index: usize, name: &str, disk: &'static dyn BlockDevice) {
+    let mut entries = [Entry {
+        number: 0,
+        start: 0,
+        sectors: 0,
+    }; MAX_PARTITIONS];
+    let read = |sector, buf: &mut [u8]| block::read(index, sector, buf);
+    let count = match parse(disk.sectors(), &read, &mut entries) {
+        Ok(count) => count,
+        Err(err) => {
+            println!("{name}: bad partition table: {err:?}");
+            return;
+        }
+    };
+
+    for entry in &entries[..count] {
+        let mut part_name = [0; NAME_MAX];
+        let mut out = BufWriter::new(&mut part_name);
+        let name_len = match write!(out, "{name}{}", entry.number) {
+            Ok(()) => out.len(),
+            Err(_) => continue,
+        };
+        let slot = NEXT_PARTITION.fetch_add(1, Ordering::Relaxed);
+        let Some(cell) = PARTITIONS.get(slot) else {
+            println!("{name}: too many partitions, the rest are left out");
+            return;
+        };
+
+        let part = cell.get_or_init(|| Partition {
+            disk,
+            start: entry.start,
+            sectors: entry.sectors,
+            name: part_name,
+            name_len,
+        });
+        match block::add(part.name(), part) {
+            Ok(_) => println!(
+                "{name}: {} at sector {}, {} sectors",
+                part.name(),
+                part.start,
+                part.sectors
+            ),
+            Err(err) => println!("{name}: can't add {}: {err:?}", part.name()),
+        }
+    }
+}
+
+/// Reads the partition table of a disk of `sectors` sectors through `read` into `out`,
+/// returning the number of partitions.
+///
+/// A disk without a table has none. Partitions that don't fit on the disk, or in `out`,
+/// are left out.
+pub fn parse(sectors: u64, read: ReadFn, out: &mut [Entry]) -> Result<usize, Errno> {
+    let mut mbr = [0; SECTOR_SIZE];
+    read(0, &mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(0);
+    }
+
+    let mut count = 0;
+    for slot in 0..4 {
+        let entry = &mbr[MBR_ENTRIES + slot * MBR_ENTRY_LEN..][..MBR_ENTRY_LEN];
+        match entry[4] {
+            MBR_GPT_PROTECTIVE => return parse_gpt(sectors, read, out),
+            MBR_EMPTY => continue,
+            kind if MBR_EXTENDED.contains(&kind) => continue,
+            _ => {}
+        }
+        let entry = Entry {
+            number: slot + 1,
+            start: le32(entry, 8) as u64,
+            sectors: le32(entry, 12) as u64,
+        };
+        push(sectors, out, &mut count, entry);
+    }
+    Ok(count)
+}
+
+/// Reads the GPT, whose header is in sector 1, after a protective MBR.
+fn parse_gpt(sectors: u64, read: ReadFn, out: &mut [Entry]) -> Result<usize, Errno> {
+    let mut sector = [0; SECTOR_SIZE];
+    read(1, &mut sector)?;
+
+    let header_len = le32(&sector, 12) as usize;
+    if &sector[..8] != GPT_SIGNATURE || !(GPT_HEADER_LEN..=SECTOR_SIZE).contains(&header_len) {
+        return Err(Errno::EINVAL);
+    }
+    let header_crc = le32(&sector, 16);
+    sector[16..20].fill(0);
+    if crc32_finish(crc32(crc32_init(), &sector[..header_len])) != header_crc {
+        return Err(Errno::EINVAL);
+    }
+
+    let entries_start = le64(&sector, 72);
+    let entries = le32(&sector, 80) as usize;
+    let entry_len = le32(&sector, 84) as usize;
+    let entries_crc = le32(&sector, 88);
+    if entry_len < GPT_ENTRY_LEN || !SECTOR_SIZE.is_multiple_of(entry_len) {
+        return Err(Errno::EINVAL);
+    }
+    let per_sector = SECTOR_SIZE / entry_len;
+    let entry_sectors = entries.div_ceil(per_sector) as u64;
+    if entries_start.saturating_add(entry_sectors) > sectors {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut count = 0;
+    let mut crc = crc32_init();
+    for i in 0..entries {
+        if i % per_sector == 0 {
+            read(entries_start + (i / per_sector) as u64, &mut sector)?;
+        }
+        let entry = &sector[i % per_sector * entry_len..][..entry_len];
+        crc = crc32(crc, entry);
+
+        // An unused entry has no type.
+        let (first, last) = (le64(entry, 32), le64(entry, 40));
+        if entry[..16].iter().all(|&b| b == 0) || last < first {
+            continue;
+        }
+        let entry = Entry {
+            number: i + 1,
+            start: first,
+            sectors: last - first + 1,
+        };
+        push(sectors, out, &mut count, entry);
+    }
+    if crc32_finish(crc) != entries_crc {
+        return Err(Errno::EINVAL);
+    }
+    Ok(count)
+}
+
+/// Adds `entry` to the `count` partitions in `out`, unless it doesn't fit on a disk of
+/// `sectors` sectors.
+fn push(sectors: u64, out: &mut [Entry], count: &mut usize, entry: Entry) {
+    let fits = entry.sectors > 0
+        && entry
+            .start
+            .checked_add(entry.sectors)
+            .is_some_and(|end| end <= sectors);
+    if fits && *count < out.len() {
+        out[*count] = entry;
+        *count += 1;
+    }
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// The CRC-32 GPT checksums its header and entries with, the one of Ethernet and zlib,
+// computed a bit at a time: tables are read once per disk.
+fn crc32_init() -> u32 {
+    !0
+}
+
+fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+fn crc32_finish(crc: u32) -> u32 {
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::Mutex;
+
+    const DISK_SECTORS: usize = 64;
+
+    struct RamDisk(Mutex<[u8; DISK_SECTORS * SECTOR_SIZE]>, AtomicUsize);
+
+    impl BlockDevice for RamDisk {
+        fn sectors(&self) -> u64 {
+            DISK_SECTORS as u64
+        }
+
+        fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+            block::check_range(self.sectors(), sector, buf.len())?;
+            let start = sector as usize * SECTOR_SIZE;
+            buf.copy_from_slice(&self.0.lock()[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+            block::check_range(self.sectors(), sector, buf.len())?;
+            let start = sector as usize * SECTOR_SIZE;
+            self.0.lock()[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), Errno> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    // Disk images are built here, not on the small kernel stacks.
+    static DISK: RamDisk = RamDisk(
+        Mutex::new([0; DISK_SECTORS * SECTOR_SIZE]),
+        AtomicUsize::new(0),
+    );
+
+    fn mbr_entry(image: &mut [u8], slot: usize, kind: u8, start: u32, sectors: u32) {
+        let entry = &mut image[MBR_ENTRIES + slot * MBR_ENTRY_LEN..][..MBR_ENTRY_LEN];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    }
+
+    fn parse_disk(out: &mut [Entry]) -> Result<usize, Errno> {
+        parse(DISK.sectors(), &|sector, buf| DISK.read(sector, buf), out)
+    }
+
+    fn entry(number: usize, start: u64, sectors: u64) -> Entry {
+        Entry {
+            number,
+            start,
+            sectors,
+        }
+    }
+
+    #[test_case]
+    fn mbr_partitions_are_found() {
+        {
+            let mut image = DISK.0.lock();
+            image.fill(0);
+            image[510..512].copy_from_slice(&MBR_SIGNATURE);
+            mbr_entry(&mut *image, 0, 0x0c, 8, 16);
+            mbr_entry(&mut *image, 1, MBR_EXTENDED[0], 24, 8);
+            mbr_entry(&mut *image, 2, 0x83, 24, 40);
+            // Runs past the end of the disk.
+            mbr_entry(&mut *image, 3, 0x83, 60, 10);
+        }
+
+        let mut entries = [entry(0, 0, 0); 4];
+        assert_eq!(parse_disk(&mut entries), Ok(2));
+        assert_eq!(entries[..2], [entry(1, 8, 16), entry(3, 24, 40)]);
+
+        // Partitions are offset into the disk, and can't reach past their end.
+        let part = Partition {
+            disk: &DISK,
+            start: 8,
+            sectors: 16,
+            name: [0; NAME_MAX],
+            name_len: 0,
+        };
+        let data = [0x5a; SECTOR_SIZE];
+        assert_eq!(part.write(1, &data), Ok(()));
+        assert_eq!(DISK.0.lock()[9 * SECTOR_SIZE], 0x5a);
+        let mut buf = [0; SECTOR_SIZE];
+        assert_eq!(part.read(16, &mut buf), Err(Errno::ENXIO));
+        let flushes = DISK.1.load(Ordering::Relaxed);
+        assert_eq!(part.flush(), Ok(()));
+        assert_eq!(DISK.1.load(Ordering::Relaxed), flushes + 1);
+
+        DISK.0.lock()[510] = 0;
+        assert_eq!(parse_disk(&mut entries), Ok(0));
+    }
+
+    #[test_case]
+    fn gpt_partitions_are_found() {
+        {
+            let mut image = DISK.0.lock();
+            image.fill(0);
+            image[510..512].copy_from_slice(&MBR_SIGNATURE);
+            mbr_entry(
+                &mut *image,
+                0,
+                MBR_GPT_PROTECTIVE,
+                1,
+                DISK_SECTORS as u32 - 1,
+            );
+
+            // Eight entries in sectors 2 and 3, two of them used.
+            let entries = &mut image[2 * SECTOR_SIZE..4 * SECTOR_SIZE];
+            for (i, first, last) in [(0, 34u64, 49u64), (5, 50, 63)] {
+                let entry = &mut entries[i * GPT_ENTRY_LEN..][..GPT_ENTRY_LEN];
+                entry[..16].fill(0xaf);
+                entry[32..40].copy_from_slice(&first.to_le_bytes());
+                entry[40..48].copy_from_slice(&last.to_le_bytes());
+            }
+            let entries_crc = crc32_finish(crc32(crc32_init(), entries));
+
+            let header = &mut image[SECTOR_SIZE..2 * SECTOR_SIZE];
+            header[..8].copy_from_slice(GPT_SIGNATURE);
+            header[12..16].copy_from_slice(&(GPT_HEADER_LEN as u32).to_le_bytes());
+            header[72..80].copy_from_slice(&2u64.to_le_bytes());
+            header[80..84].copy_from_slice(&8u32.to_le_bytes());
+            header[84..88].copy_from_slice(&(GPT_ENTRY_LEN as u32).to_le_bytes());
+            header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+            let header_crc = crc32_finish(crc32(crc32_init(), &header[..GPT_HEADER_LEN]));
+            header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        }
+
+        let mut entries = [entry(0, 0, 0); 4];
+        assert_eq!(parse_disk(&mut entries), Ok(2));
+        assert_eq!(entries[..2], [entry(1, 34, 16), entry(6, 50, 14)]);
+
+        // A corrupt entry fails the checksum.
+        DISK.0.lock()[2 * SECTOR_SIZE + 32] = 35;
+        assert_eq!(parse_disk(&mut entries), Err(Errno::EINVAL));
+    }
+
+    #[test_case]
+    fn crc32_matches_the_usual_check_value() {
+        assert_eq!(crc32_finish(crc32(crc32_init(), b"123456789")), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,125 @@
+use core::ops::Sub;
+
+use crate::csr;
+
+// The hart's performance counters: `cycle`, the clock cycles it ran, and `instret`, the
+// instructions it retired. Code is measured by reading both before and after it, with a
+// `Span` or with `measure()`.
+//
+// The counters belong to the hart, not to the code: whatever else runs on it meanwhile,
+// interrupt handlers or other processes if the code blocks, is counted too.
+
+/// Values of the counters, or the difference between two readings.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Counters {
+    pub cycles: u64,
+    pub instret: u64,
+}
+
+impl Sub for Counters {
+    type Output = Counters;
+
+    fn sub(self, earlier: Counters) -> Counters {
+        Counters {
+            cycles: self.cycles.wrapping_sub(earlier.cycles),
+            instret: self.instret.wrapping_sub(earlier.instret),
+        }
+    }
+}
+
+/// Reads a 64-bit counter from its halves on rv32, retrying if the low half wrapped in
+/// between.
+#[cfg(target_arch = "riscv32")]
+fn read_counter(low: fn() -> usize, high: fn() -> usize) -> u64 {
+    loop {
+        let hi = high();
+        let lo = low();
+        if hi == high() {
+            return ((hi as u64) << 32) | lo as u64;
+        }
+    }
+}
+
+/// Returns the counters of the calling hart.
+pub fn read() -> Counters {
+    #[cfg(target_arch = "riscv32")]
+    return Counters {
+        cycles: read_counter(csr::cycle, csr::cycleh),
+        instret: read_counter(csr::instret, csr::instreth),
+    };
+    #[cfg(target_arch = "riscv64")]
+    return Counters {
+        cycles: csr::cycle() as u64,
+        instret: csr::instret() as u64,
+    };
+}
+
+/// Counts from its creation until `elapsed()` is called or, if it has a name, it is dropped
+/// and the counts are printed.
+pub struct Span {
+    name: Option<&'static str>,
+    start: Counters,
+}
+
+impl Span {
+    /// Starts counting.
+    pub fn start() -> Self {
+        Self {
+            name: None,
+            start: read(),
+        }
+    }
+
+    /// Starts counting, printing the counts as `name`'s once dropped.
+    pub fn named(name: &'static str) -> Self {
+        Self {
+            name: Some(name),
+            start: read(),
+        }
+    }
+
+    /// Returns the counts since the span started.
+    pub fn elapsed(&self) -> Counters {
+        read() - self.start
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(name) = self.name {
+            let counts = self.elapsed();
+            crate::println!(
+                "perf: {name}: {} cycles, {} instructions",
+                counts.cycles,
+                counts.instret
+            );
+        }
+    }
+}
+
+/// Runs `f`, returning what it returned and what it counted.
+pub fn measure<R>(f: impl FnOnce() -> R) -> (R, Counters) {
+    let span = Span::start();
+    let result = f();
+    (result, span.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stdlib::{phalloc, phree};
+
+    #[test_case]
+    fn counters_count_what_runs() {
+        let (sum, counts) = measure(|| (0..1000).map(core::hint::black_box).sum::<usize>());
+        assert_eq!(sum, 499_500);
+        // At least an instruction per number added.
+        assert!(counts.instret >= 1000);
+        assert!(counts.cycles > 0);
+
+        let span = Span::start();
+        phree(phalloc(1).unwrap());
+        let first = span.elapsed();
+        assert!(span.elapsed().instret > first.instret);
+    }
+}
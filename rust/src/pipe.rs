@@ -0,0 +1,225 @@
+use crate::{
+    file::{self, FileKind},
+    poll::{POLLERR, POLLHUP, POLLIN, POLLOUT, PollTable},
+    proc::{self, WaitQueue},
+    signal::{self, SIGPIPE},
+    sync::Mutex,
+    syscall::Errno,
+};
+
+const MAX_PIPES: usize = 8;
+const PIPE_SIZE: usize = 512;
+
+/// A ring buffer connecting the write end of a pipe to its read end.
+struct Pipe {
+    buf: [u8; PIPE_SIZE],
+    head: usize,
+    len: usize,
+    // Open files for each end, the pipe is freed once both reach zero.
+    readers: usize,
+    writers: usize,
+}
+
+static PIPES: Mutex<[Option<Pipe>; MAX_PIPES]> = Mutex::new([const { None }; MAX_PIPES]);
+
+// Processes waiting for data in, or room in, the pipe in the same slot of `PIPES`.
+static READERS: [WaitQueue; MAX_PIPES] = [const { WaitQueue::new() }; MAX_PIPES];
+static WRITERS: [WaitQueue; MAX_PIPES] = [const { WaitQueue::new() }; MAX_PIPES];
+
+/// Creates a pipe, returning fds for its read and write ends.
+pub fn open() -> Result<(usize, usize), Errno> {
+    let pipe = {
+        let mut pipes = PIPES.lock();
+        let pipe = pipes
+            .iter()
+            .position(|p| p.is_none())
+            .ok_or(Errno::ENFILE)?;
+
+        pipes[pipe] = Some(Pipe {
+            buf: [0; PIPE_SIZE],
+            head: 0,
+            len: 0,
+            readers: 1,
+            writers: 1,
+        });
+        pipe
+    };
+
+    let read_fd = match file::open(FileKind::PipeRead(pipe)) {
+        Ok(fd) => fd,
+        Err(err) => {
+            close_read(pipe);
+            close_write(pipe);
+            return Err(err);
+        }
+    };
+
+    match file::open(FileKind::PipeWrite(pipe)) {
+        Ok(write_fd) => Ok((read_fd, write_fd)),
+        Err(err) => {
+            close_write(pipe);
+            let _ = file::close(read_fd);
+            Err(err)
+        }
+    }
+}
+
+/// Reads up to `buf.len()` bytes, blocking while the pipe is empty and still has writers.
+///
+/// Returns 0 at end of file, i.e. once the pipe is empty and all write ends are closed.
+pub fn read(pipe: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+    loop {
+        let mut pipes = PIPES.lock();
+        let p = pipes[pipe].as_mut().unwrap();
+
+        if p.len == 0 {
+            if p.writers == 0 || buf.is_empty() {
+                return Ok(0);
+            }
+            READERS[pipe].wait(pipes);
+            signal::interrupted()?;
+            continue;
+        }
+
+        let n = p.len.min(buf.len());
+        for (i, byte) in buf[..n].iter_mut().enumerate() {
+            *byte = p.buf[(p.head + i) % PIPE_SIZE];
+        }
+        p.head = (p.head + n) % PIPE_SIZE;
+        p.len -= n;
+        drop(pipes);
+
+        WRITERS[pipe].wake_all();
+
+        return Ok(n);
+    }
+}
+
+/// Writes all of `buf`, blocking while the pipe is full.
+///
+/// Fails with `EPIPE` if all read ends are closed before anything was written, sending the
+/// caller `SIGPIPE`.
+pub fn write(pipe: usize, buf: &[u8]) -> Result<usize, Errno> {
+    let mut written = 0;
+
+    loop {
+        let mut pipes = PIPES.lock();
+        let p = pipes[pipe].as_mut().unwrap();
+
+        if p.readers == 0 {
+            return if written > 0 {
+                Ok(written)
+            } else {
+                signal::send(SIGPIPE);
+                Err(Errno::EPIPE)
+            };
+        }
+
+        let n = (PIPE_SIZE - p.len).min(buf.len() - written);
+        for (i, &byte) in buf[written..written + n].iter().enumerate() {
+            p.buf[(p.head + p.len + i) % PIPE_SIZE] = byte;
+        }
+        p.len += n;
+        written += n;
+
+        if written == buf.len() {
+            drop(pipes);
+            READERS[pipe].wake_all();
+            return Ok(written);
+        }
+
+        READERS[pipe].wake_all();
+        WRITERS[pipe].wait(pipes);
+        // What was written stays written, only a write that wrote nothing runs again.
+        if written == 0 {
+            signal::interrupted()?;
+        } else if signal::pending(proc::current()) {
+            return Ok(written);
+        }
+    }
+}
+
+/// Returns the readiness of a pipe end as `poll::POLL*` flags, queueing the caller on it
+/// through `table` if given.
+pub fn poll(pipe: usize, write_end: bool, table: Option<&mut PollTable>) -> u16 {
+    let pipes = PIPES.lock();
+    let p = pipes[pipe].as_ref().unwrap();
+
+    if let Some(table) = table {
+        table.add(if write_end {
+            &WRITERS[pipe]
+        } else {
+            &READERS[pipe]
+        });
+    }
+
+    match write_end {
+        false if p.len > 0 => POLLIN,
+        false if p.writers == 0 => POLLHUP,
+        true if p.readers == 0 => POLLERR,
+        true if p.len < PIPE_SIZE => POLLOUT,
+        _ => 0,
+    }
+}
+
+/// Releases a read end, called once the last file referring to it is closed.
+pub fn close_read(pipe: usize) {
+    release(pipe, |p| p.readers -= 1);
+    WRITERS[pipe].wake_all();
+}
+
+/// Releases a write end, called once the last file referring to it is closed.
+pub fn close_write(pipe: usize) {
+    release(pipe, |p| p.writers -= 1);
+    READERS[pipe].wake_all();
+}
+
+fn release(pipe: usize, f: impl FnOnce(&mut Pipe)) {
+    let mut pipes = PIPES.lock();
+    let p = pipes[pipe].as_mut().unwrap();
+    f(p);
+    if p.readers == 0 && p.writers == 0 {
+        pipes[pipe] = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::open;
+    use crate::{
+        file, proc,
+        signal::{self, SIGPIPE},
+        syscall::Errno,
+    };
+
+    #[test_case]
+    fn pipe_read_write_and_eof() {
+        let (r, w) = open().unwrap();
+
+        assert_eq!(file::write(w, b"hello"), Ok(5));
+        assert_eq!(file::read(w, &mut [0; 1]), Err(Errno::EBADF));
+
+        let mut buf = [0; 3];
+        assert_eq!(file::read(r, &mut buf), Ok(3));
+        assert_eq!(&buf, b"hel");
+
+        file::close(w).unwrap();
+        assert_eq!(file::read(r, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(file::read(r, &mut buf), Ok(0));
+
+        file::close(r).unwrap();
+        assert_eq!(file::close(r), Err(Errno::EBADF));
+    }
+
+    #[test_case]
+    fn write_to_closed_pipe_fails() {
+        let (r, w) = open().unwrap();
+        file::close(r).unwrap();
+        assert_eq!(file::write(w, b"x"), Err(Errno::EPIPE));
+        let pid = proc::current();
+        assert_ne!(signal::masks(pid).0 & (1 << SIGPIPE), 0);
+        signal::reset(pid);
+        file::close(w).unwrap();
+    }
+}
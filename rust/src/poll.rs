@@ -0,0 +1,155 @@
+use crate::{
+    file,
+    proc::{self, WaitQueue},
+    signal,
+    syscall::{self, Errno},
+    timer,
+};
+
+// Readiness flags, with the values Linux uses.
+pub const POLLIN: u16 = 0x01;
+pub const POLLOUT: u16 = 0x04;
+pub const POLLERR: u16 = 0x08;
+pub const POLLHUP: u16 = 0x10;
+pub const POLLNVAL: u16 = 0x20;
+
+const MAX_POLL_FDS: usize = 16;
+// Each polled file may queue the caller on a few wait queues.
+const MAX_POLL_QUEUES: usize = 2 * MAX_POLL_FDS;
+
+/// An entry of the array passed to `SYS_POLL`, laid out like Linux's `struct pollfd`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: u16,
+    pub revents: u16,
+}
+
+/// The wait queues a polling process is queued on, left when the table is dropped.
+pub struct PollTable {
+    pid: usize,
+    queues: [Option<&'static WaitQueue>; MAX_POLL_QUEUES],
+}
+
+impl PollTable {
+    fn new() -> Self {
+        Self {
+            pid: proc::current(),
+            queues: [None; MAX_POLL_QUEUES],
+        }
+    }
+
+    /// Queues the polling process on `queue` until the table is dropped.
+    pub fn add(&mut self, queue: &'static WaitQueue) {
+        // Without a free slot the process may sleep through the event until its timeout.
+        if let Some(slot) = self.queues.iter_mut().find(|q| q.is_none()) {
+            queue.add(self.pid);
+            *slot = Some(queue);
+        }
+    }
+}
+
+impl Drop for PollTable {
+    fn drop(&mut self) {
+        for queue in self.queues.iter().flatten() {
+            queue.remove(self.pid);
+        }
+    }
+}
+
+/// Waits until at least one of `fds` is ready for the events it asks for, for at most
+/// `timeout_ms` milliseconds if given.
+///
+/// Sets `revents` of every entry and returns the number of ready entries, 0 on timeout.
+/// Errors and hang-ups are always reported, negative fds are ignored.
+pub fn poll(fds: &mut [PollFd], timeout_ms: Option<usize>) -> Result<usize, Errno> {
+    if fds.len() > MAX_POLL_FDS {
+        return Err(Errno::EINVAL);
+    }
+
+    let deadline = timeout_ms.map(|ms| timer::ticks() + timer::ms_to_ticks(ms));
+
+    loop {
+        // Queued before checking readiness, so nothing that happens in between is missed.
+        let mut table = PollTable::new();
+        let mut ready = 0;
+
+        for pfd in fds.iter_mut() {
+            pfd.revents = match pfd.fd {
+                ..0 => 0,
+                fd => match file::poll(fd as usize, Some(&mut table)) {
+                    Ok(revents) => revents & (pfd.events | POLLERR | POLLHUP),
+                    Err(_) => POLLNVAL,
+                },
+            };
+            if pfd.revents != 0 {
+                ready += 1;
+            }
+        }
+
+        if ready > 0 || deadline.is_some_and(|deadline| timer::ticks() >= deadline) {
+            return Ok(ready);
+        }
+
+        proc::sleep(deadline);
+        // Never restarted, like on Linux: the timeout would start over.
+        if signal::pending(proc::current()) {
+            return Err(Errno::EINTR);
+        }
+    }
+}
+
+/// `SYS_POLL`: polls `nfds` `PollFd`s at `addr`, waiting forever if `timeout_ms` is negative.
+pub fn sys_poll(addr: usize, nfds: usize, timeout_ms: isize) -> Result<usize, Errno> {
+    if !addr.is_multiple_of(align_of::<PollFd>()) {
+        return Err(Errno::EFAULT);
+    }
+    let len = nfds.checked_mul(size_of::<PollFd>()).ok_or(Errno::EINVAL)?;
+    let bytes = syscall::user_slice_mut(addr, len)?;
+    let fds = unsafe { core::slice::from_raw_parts_mut(bytes.as_mut_ptr() as *mut PollFd, nfds) };
+
+    poll(fds, usize::try_from(timeout_ms).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipe;
+
+    #[test_case]
+    fn poll_reports_pipe_readiness() {
+        let (r, w) = pipe::open().unwrap();
+        let mut fds = [
+            PollFd {
+                fd: r as i32,
+                events: POLLIN,
+                revents: 0,
+            },
+            PollFd {
+                fd: w as i32,
+                events: POLLOUT,
+                revents: 0,
+            },
+            PollFd {
+                fd: -1,
+                events: POLLIN,
+                revents: 0,
+            },
+        ];
+
+        assert_eq!(poll(&mut fds, Some(0)), Ok(1));
+        assert_eq!(fds[0].revents, 0);
+        assert_eq!(fds[1].revents, POLLOUT);
+
+        file::write(w, b"x").unwrap();
+        file::close(w).unwrap();
+        assert_eq!(poll(&mut fds[..1], Some(0)), Ok(1));
+        assert_eq!(fds[0].revents, POLLIN);
+
+        assert_eq!(poll(&mut fds[1..2], Some(0)), Ok(1));
+        assert_eq!(fds[1].revents, POLLNVAL);
+
+        file::close(r).unwrap();
+    }
+}
@@ -1,26 +1,72 @@
 use core::{
     arch::{asm, naked_asm},
     ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
 use crate::{
-    __free_ram_end, __kernel_base,
-    mem::PAGE_SIZE,
-    stdlib::FixedVec,
-    sync::{Mutex, OnceCell},
-    vm::{PAGE_R, PAGE_W, PAGE_X, PageTable, SATP_SV32},
+    __free_ram_end, __kernel_base, MAX_HARTS, alarm, cred,
+    csr::{Satp, SatpMode, Sstatus},
+    cwd, elf, file, fpu, hart_id, load_reg, loadavg,
+    mem::{self, PAGE_SIZE, RegionKind},
+    misaligned, mmap, pagecache, rcu, reg_bytes, rlimit, signal,
+    stdlib::{FixedVec, phalloc_uninit},
+    store_reg, swap,
+    sync::{Mutex, MutexGuard, OnceCell},
+    syscall::Errno,
+    sysctl::Tunable,
+    trap::{self, TrapFrame},
+    tty, vfs,
+    vm::{self, PAGE_R, PAGE_U, PAGE_W, PAGE_X, PageTable, Region, SATP_MODE},
+    watchdog,
 };
 
-const PROC_STACK_SIZE: usize = 8 * 1024 / size_of::<usize>();
-const PROC_MAX: usize = 8;
+const PROC_STACK_SIZE: usize = 8 * 1024;
+pub const PROC_MAX: usize = 8;
+
+// Written at the bottom of every process's kernel stack, running past the stack
+// overwrites it first.
+const STACK_CANARY: usize = usize::from_ne_bytes([0x5a; size_of::<usize>()]);
 
 static PROC_TABLE: OnceCell<Mutex<ProcTable>> = OnceCell::new();
 
+// Address of the canary of each process, zero for slots never used, readable from
+// interrupt context without taking `PROC_TABLE`.
+static STACK_CANARIES: [AtomicUsize; PROC_MAX] = [const { AtomicUsize::new(0) }; PROC_MAX];
+
+// Pid running on each hart, readable from interrupt context without taking `PROC_TABLE`.
+static CURRENT_PID: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+/// Timer ticks a process runs before being preempted, `/proc/sys/sched_timeslice`.
+pub static TIMESLICE: Tunable = Tunable::new("sched_timeslice", 1, 1, 100);
+
+// Ticks the process running on each hart has run since it was switched to.
+static SLICE_USED: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+// Set once the boot hart switched to a process for the first time, blocking works then.
+static SCHEDULING: AtomicBool = AtomicBool::new(false);
+
+// Hart each process last ran on, whose run queue it counts towards, indexed by pid.
+static LAST_HART: [AtomicUsize; PROC_MAX] = [const { AtomicUsize::new(0) }; PROC_MAX];
+
+// Priority of each process, indexed by pid: the runnable process with the highest one
+// runs next, the lowest pid among equals. Boosted for as long as it holds a lock a
+// process of a higher priority waits for, see `sync::PiMutex`.
+static PRIORITY: [AtomicUsize; PROC_MAX] = [const { AtomicUsize::new(0) }; PROC_MAX];
+static BOOST: [AtomicUsize; PROC_MAX] = [const { AtomicUsize::new(0) }; PROC_MAX];
+/// Highest priority `setpriority()` gives a process.
+pub const MAX_PRIORITY: usize = 39;
+
 #[derive(Debug, PartialEq)]
 #[repr(u8)]
 enum ProcState {
     Unused = 0,
     Runnable = 1,
+    Blocked = 2,
+    /// Exited, until its parent collects the exit status with `wait()`.
+    Zombie = 3,
+    /// Stopped by a signal, until it is sent `SIGCONT`.
+    Stopped = 4,
 }
 
 #[derive(Debug)]
@@ -30,12 +76,21 @@ pub struct Process {
     pid: usize,
     page_table: PageTable,
     state: ProcState,
+    // Where a forked process enters user mode when it first runs.
+    user_pc: usize,
 }
 
 impl Process {
     fn sp_as_mut_ptr(&mut self) -> *mut usize {
         &mut self.sp as *mut usize
     }
+
+    /// Returns where the registers of the process's user mode are saved, at the top of its
+    /// kernel stack, see `trap_entry`.
+    fn trap_frame(&mut self) -> *mut TrapFrame {
+        let stack_top = (self.stack.as_mut_ptr() as usize + PROC_STACK_SIZE) & !0xf;
+        (stack_top - size_of::<TrapFrame>()) as *mut TrapFrame
+    }
 }
 
 struct ProcTable {
@@ -52,12 +107,7 @@ impl ProcTable {
     }
 
     fn next_unused(&self) -> Option<usize> {
-        for i in 0..self.table.cap() {
-            if self.table[i].state == ProcState::Unused {
-                return Some(i);
-            }
-        }
-        None
+        (0..self.table.cap()).find(|&i| self.table[i].state == ProcState::Unused)
     }
 
     fn get_proc(&mut self, index: usize) -> &mut Process {
@@ -71,10 +121,14 @@ impl ProcTable {
         proc.pid = proc_index;
 
         proc.state = ProcState::Runnable;
-        let mut sp = &mut proc.stack[PROC_STACK_SIZE - 4] as *mut u8 as *mut usize;
+        // The top of the stack is kept for the trap frame of user mode, the ABI wants a
+        // 16-byte aligned stack below it.
+        let stack_top = proc.trap_frame() as usize & !0xf;
+        let mut sp = stack_top as *mut usize;
 
+        // Frame popped by `switch_context`: ra, then s0 to s11.
         unsafe {
-            sp = sp.offset(-12);
+            sp = sp.offset(-13);
             ptr::write(sp, pc);
         }
         for i in 1..13 {
@@ -85,22 +139,85 @@ impl ProcTable {
 
         proc.sp = sp as usize;
 
-        proc.page_table = PageTable::new();
+        let canary = (proc.stack.as_mut_ptr() as usize).next_multiple_of(size_of::<usize>());
+        unsafe { ptr::write_volatile(canary as *mut usize, STACK_CANARY) };
+        STACK_CANARIES[proc_index].store(canary, Ordering::Relaxed);
 
-        let mut base = unsafe { &__kernel_base } as *const u8 as usize;
-        let end = unsafe { &__free_ram_end } as *const u8 as usize;
-
-        while base < end {
-            proc.page_table
-                .map_page(base, base, PAGE_R | PAGE_W | PAGE_X);
-            base += PAGE_SIZE;
-        }
+        proc.page_table = kernel_page_table();
+        PRIORITY[proc_index].store(0, Ordering::Relaxed);
+        BOOST[proc_index].store(0, Ordering::Relaxed);
+        // Leads a group of its own unless it inherits its creator's.
+        PGID[proc_index].store(proc_index, Ordering::Relaxed);
 
         proc_index
     }
+
+    /// Returns the slot a new process of `parent` goes in.
+    ///
+    /// Fails with `EAGAIN` if the `RLIMIT_NPROC` of `parent` is reached or all slots are
+    /// taken.
+    fn reserve(&mut self, parent: usize) -> Result<usize, Errno> {
+        let limit = rlimit::current(parent, rlimit::RLIMIT_NPROC);
+        // Processes of the same user count, taking `cred`'s lock within this one.
+        let uid = cred::get(parent).uid;
+        let live = (0..PROC_MAX)
+            .filter(|&pid| self.get_proc(pid).state != ProcState::Unused)
+            .filter(|&pid| cred::get(pid).uid == uid)
+            .count();
+        self.next_unused()
+            .filter(|_| live < limit)
+            .ok_or(Errno::EAGAIN)
+    }
+}
+
+/// Returns a page table mapping the kernel and free RAM, which every address space shares.
+fn kernel_page_table() -> PageTable {
+    let mut page_table = PageTable::new();
+
+    let mut base = unsafe { &__kernel_base } as *const u8 as usize;
+    let end = unsafe { &__free_ram_end } as *const u8 as usize;
+
+    while base < end {
+        page_table.map_page(base, base, PAGE_R | PAGE_W | PAGE_X);
+        base += PAGE_SIZE;
+    }
+
+    // Registers of devices drivers found, collected first as mapping may allocate.
+    let mut mmio = [(0, 0); 8];
+    let mut count = 0;
+    mem::for_each_region(|kind, start, end| {
+        if kind == RegionKind::Mmio && count < mmio.len() {
+            mmio[count] = (start, end);
+            count += 1;
+        }
+    });
+    for &(start, end) in &mmio[..count] {
+        for addr in (start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+            page_table.map_page(addr, addr, PAGE_R | PAGE_W);
+        }
+    }
+
+    // Lets a failing test power off QEMU from any process.
+    #[cfg(test)]
+    page_table.map_page(
+        crate::ktest::TEST_FINISHER,
+        crate::ktest::TEST_FINISHER,
+        PAGE_R | PAGE_W,
+    );
+
+    page_table
 }
 
-pub fn init() {
+/// Switches the calling hart to `page_table`.
+fn activate(page_table: &PageTable) {
+    unsafe { asm!("sfence.vma") };
+    Satp::write(SATP_MODE, 0, page_table.root_pt_addr() / PAGE_SIZE);
+    unsafe { asm!("sfence.vma") };
+}
+
+crate::register_subsystem!(Core, "proc", init);
+
+fn init() {
     PROC_TABLE.get_or_init(|| Mutex::new(ProcTable::new()));
 }
 
@@ -111,43 +228,887 @@ pub fn new(pc: usize) {
         .create_process(pc);
 }
 
+/// Creates a process starting at `pc` on behalf of the calling process, which passes its
+/// resource limits, credentials, fds, working directory, terminal and process group on to
+/// it, and returns its pid.
+///
+/// Fails with `EAGAIN` if the caller's `RLIMIT_NPROC` is reached or all slots are taken, and
+/// with `ENOMEM` if its fds can't be copied.
+pub fn spawn(pc: usize) -> Result<usize, Errno> {
+    let parent = current();
+    let mut proc_table = PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock();
+    let pid = proc_table.reserve(parent)?;
+    // The fd table may need memory, taken before there is a process to undo.
+    file::inherit(parent, pid)?;
+    proc_table.create_process(pc);
+    drop(proc_table);
+
+    rlimit::inherit(parent, pid);
+    cred::inherit(parent, pid);
+    cwd::inherit(parent, pid);
+    tty::inherit(parent, pid);
+    PGID[pid].store(pgid(parent), Ordering::Relaxed);
+    Ok(pid)
+}
+
+// Process without a parent, whose exit status nobody collects.
+const NO_PARENT: usize = usize::MAX;
+
+/// A process's parent and, once it exited, what `wait()` reports about it.
+#[derive(Clone, Copy)]
+struct Exit {
+    parent: usize,
+    status: Option<usize>,
+    // The signal that stopped the process, until `wait()` reported it or it continued.
+    stopped: Option<usize>,
+}
+
+// Indexed by pid.
+// Lock order: `EXITS` before `PROC_TABLE`.
+static EXITS: Mutex<[Exit; PROC_MAX]> = Mutex::new(
+    [Exit {
+        parent: NO_PARENT,
+        status: None,
+        stopped: None,
+    }; PROC_MAX],
+);
+
+// Parents waiting for a child to exit or stop.
+static CHILD_EXITED: WaitQueue = WaitQueue::new();
+
+/// Returns the wait status of a process that exited with `code`, as Linux encodes it.
+pub fn exit_status(code: usize) -> usize {
+    (code & 0xff) << 8
+}
+
+/// Returns the wait status of a process terminated by signal `sig`, as Linux encodes it.
+pub fn signal_status(sig: usize) -> usize {
+    sig
+}
+
+/// Returns the wait status of a process stopped by signal `sig`, as Linux encodes it.
+pub fn stopped_status(sig: usize) -> usize {
+    (sig << 8) | 0x7f
+}
+
+// Process group of each process, indexed by pid. 0 for a free slot, or the idle process,
+// which isn't in any.
+static PGID: [AtomicUsize; PROC_MAX] = [const { AtomicUsize::new(0) }; PROC_MAX];
+
+/// Returns the process group of `pid`.
+pub fn pgid(pid: usize) -> usize {
+    PGID[pid].load(Ordering::Relaxed)
+}
+
+/// Returns the processes in group `pgid`, as a bit mask of pids. Safe to call from
+/// interrupt handlers.
+pub fn group_members(pgid: usize) -> usize {
+    if pgid == 0 {
+        return 0;
+    }
+    (0..PROC_MAX)
+        .filter(|&pid| PGID[pid].load(Ordering::Relaxed) == pgid)
+        .fold(0, |pids, pid| pids | 1 << pid)
+}
+
+/// Moves `pid` (the caller if 0) into process group `pgid`, a new one that `pid` leads if
+/// `pgid` is 0 or `pid`.
+///
+/// Fails with `ESRCH` unless `pid` is the caller or one of its children, and with `EPERM`
+/// if group `pgid` doesn't exist.
+pub fn setpgid(pid: usize, pgid: usize) -> Result<(), Errno> {
+    let caller = current();
+    let pid = if pid == 0 { caller } else { pid };
+    let pgid = if pgid == 0 { pid } else { pgid };
+    if pid >= PROC_MAX {
+        return Err(Errno::ESRCH);
+    }
+
+    let exits = EXITS.lock();
+    let is_caller_or_child = pid == caller || exits[pid].parent == caller;
+    // Zombies have left their group already.
+    if !is_caller_or_child || exits[pid].status.is_some() || !exists(pid) {
+        return Err(Errno::ESRCH);
+    }
+    if pgid != pid && group_members(pgid) == 0 {
+        return Err(Errno::EPERM);
+    }
+    PGID[pid].store(pgid, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Creates a copy of the calling user process, which resumes at `pc` with the registers in
+/// `tf` except for `a0`, where it finds 0. Returns the copy's pid.
+///
+/// Fails with `EAGAIN` like `spawn()`, and with `ENOMEM` if its memory or fds can't be copied.
+pub fn fork(tf: &TrapFrame, pc: usize) -> Result<usize, Errno> {
+    let parent = current();
+    let mut proc_table = PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock();
+    let pid = proc_table.reserve(parent)?;
+
+    let mut page_table = kernel_page_table();
+    copy_user_pages(&proc_table.get_proc(parent).page_table, &mut page_table)?;
+    // The fd table may need memory, taken before there is a process to undo.
+    file::inherit(parent, pid)?;
+
+    proc_table.create_process(enter_forked as *const () as usize);
+    let child = proc_table.get_proc(pid);
+    child.page_table = page_table;
+    child.user_pc = pc;
+    let mut frame = *tf;
+    frame.set_reg(10, 0);
+    unsafe { child.trap_frame().write(frame) };
+    drop(proc_table);
+
+    EXITS.lock()[pid] = Exit {
+        parent,
+        status: None,
+        stopped: None,
+    };
+    rlimit::inherit(parent, pid);
+    cred::inherit(parent, pid);
+    cwd::inherit(parent, pid);
+    fpu::inherit(parent, pid);
+    signal::inherit(parent, pid);
+    tty::inherit(parent, pid);
+    PGID[pid].store(pgid(parent), Ordering::Relaxed);
+    Ok(pid)
+}
+
+/// Maps copies of the user pages of `from` into `to`, and the same pages of the page cache.
+fn copy_user_pages(from: &PageTable, to: &mut PageTable) -> Result<(), Errno> {
+    to.copy_vmas(from);
+    let mut result = Ok(());
+    from.for_each_region(|region| {
+        if region.flags & PAGE_U == 0 || result.is_err() {
+            return;
+        }
+        // Still zero, the copy may share it too.
+        if vm::is_zero_page(region.paddr) {
+            for vaddr in (region.start..region.end).step_by(PAGE_SIZE) {
+                to.map_zero_page(vaddr, region.flags);
+            }
+            return;
+        }
+        for offset in (0..region.end - region.start).step_by(PAGE_SIZE) {
+            // The child is given the limits of the parent, calling this.
+            if let Err(err) = rlimit::check_rss(current(), to.usage().resident) {
+                result = Err(err);
+                return;
+            }
+            // Private ones are read-only, and copied once written.
+            let paddr = region.paddr + offset;
+            if pagecache::is_cached(paddr) {
+                pagecache::dup(paddr, region.flags & PAGE_W != 0);
+                to.map_page(region.start + offset, paddr, region.flags);
+                continue;
+            }
+            let Ok(frame) = phalloc_uninit(PAGE_SIZE) else {
+                // `to` owns the pages copied so far, they are freed along with it.
+                result = Err(Errno::ENOMEM);
+                return;
+            };
+            unsafe {
+                let src = paddr as *const u8;
+                frame.as_mut_ptr().copy_from_nonoverlapping(src, PAGE_SIZE);
+            }
+            to.map_page(region.start + offset, frame.as_usize(), region.flags);
+        }
+    });
+    // Pages swapped out are read back into the copies, the parent's stay in swap.
+    from.for_each_swapped(|vaddr, slot, flags| {
+        if result.is_err() {
+            return;
+        }
+        let Ok(frame) = phalloc_uninit(PAGE_SIZE) else {
+            result = Err(Errno::ENOMEM);
+            return;
+        };
+        result = swap::read(slot, frame.as_usize());
+        to.map_page(vaddr, frame.as_usize(), flags);
+    });
+    result
+}
+
+/// Where a forked process starts, in the kernel, before entering user mode like its parent.
+extern "C" fn enter_forked() -> ! {
+    let mut proc_table = PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock();
+    let proc = proc_table.get_proc(current());
+    let (frame, pc) = (proc.trap_frame(), proc.user_pc);
+    drop(proc_table);
+
+    unsafe { trap::return_to_user(frame, pc) }
+}
+
+/// Replaces the program of the calling process with the ELF executable at `path`, in a
+/// fresh address space with `argv` and `envp` on its stack, and returns the pc and stack
+/// pointer it starts with.
+///
+/// Its fds flagged with `FD_CLOEXEC` are closed and caught signals get their default
+/// action back. On failure the process keeps running its current program.
+pub fn exec(path: &str, argv: &[&[u8]], envp: &[&[u8]]) -> Result<(usize, usize), Errno> {
+    let (mount, ino) = vfs::resolve(path)?;
+    if vfs::is_dir(mount, ino) {
+        return Err(Errno::EACCES);
+    }
+    vfs::access(mount, ino, cred::MAY_EXEC)?;
+
+    let mut page_table = kernel_page_table();
+    let entry = elf::load(
+        &mut page_table,
+        |offset, buf| vfs::read(mount, ino, offset, buf),
+        |offset| vfs::map_page(mount, ino, offset / PAGE_SIZE).ok(),
+    )?;
+    // Copied from the old address space, which is still the active one.
+    let sp = elf::map_stack(&mut page_table, argv, envp)?;
+
+    let pid = current();
+    let mut proc_table = PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock();
+    let proc = proc_table.get_proc(pid);
+    // The old table is still in use until the switch, only then can it be freed.
+    let old = core::mem::replace(&mut proc.page_table, page_table);
+    activate(&proc.page_table);
+    drop(old);
+    drop(proc_table);
+
+    file::close_on_exec(pid);
+    fpu::reset(pid);
+    signal::reset_on_exec(pid);
+    Ok((entry, sp))
+}
+
+/// Enters user mode at `pc` with the registers in `frame`, e.g. once a process started in
+/// the kernel has `exec()`ed a program.
+pub fn enter_user(pc: usize, frame: TrapFrame) -> ! {
+    let mut proc_table = PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock();
+    let top = proc_table.get_proc(current()).trap_frame();
+    drop(proc_table);
+
+    // Nothing below the frame is needed anymore.
+    unsafe {
+        top.write(frame);
+        trap::return_to_user(top, pc)
+    }
+}
+
+/// Waits for a child of the calling process to exit, `pid` or any if `None`, and returns
+/// its pid and wait status. With `stopped`, a child stopping is reported too, once.
+///
+/// Fails with `ECHILD` if there is no such child.
+pub fn wait(pid: Option<usize>, stopped: bool) -> Result<(usize, usize), Errno> {
+    let parent = current();
+    let is_child = |exits: &[Exit; PROC_MAX], child: usize| {
+        exits[child].parent == parent && pid.is_none_or(|p| p == child)
+    };
+    loop {
+        let mut exits = EXITS.lock();
+        if !(0..PROC_MAX).any(|child| is_child(&exits, child)) {
+            return Err(Errno::ECHILD);
+        }
+
+        let exited = (0..PROC_MAX).find(|&c| is_child(&exits, c) && exits[c].status.is_some());
+        if let Some(child) = exited {
+            let status = exits[child].status.take().unwrap();
+            exits[child].parent = NO_PARENT;
+            set_state(child, ProcState::Unused);
+            return Ok((child, status));
+        }
+        let stop = (0..PROC_MAX)
+            .filter(|_| stopped)
+            .find(|&c| is_child(&exits, c) && exits[c].stopped.is_some());
+        if let Some(child) = stop {
+            let sig = exits[child].stopped.take().unwrap();
+            return Ok((child, stopped_status(sig)));
+        }
+
+        CHILD_EXITED.wait(exits);
+        signal::interrupted()?;
+    }
+}
+
+// Pids (as a bit mask) woken up from interrupt context, made runnable by the next `give_up()`.
+static DEFERRED_WAKEUPS: AtomicUsize = AtomicUsize::new(0);
+
+// Tick at which each sleeping process times out, indexed by pid.
+static WAKE_AT: [AtomicUsize; PROC_MAX] = [const { AtomicUsize::new(NO_TIMEOUT) }; PROC_MAX];
+const NO_TIMEOUT: usize = usize::MAX;
+
+/// Returns the priority `pid` is scheduled with, boost included.
+pub fn priority(pid: usize) -> usize {
+    PRIORITY[pid]
+        .load(Ordering::Relaxed)
+        .max(BOOST[pid].load(Ordering::Relaxed))
+}
+
+/// Sets the priority of `pid`, higher ones running first.
+pub fn set_priority(pid: usize, priority: usize) {
+    PRIORITY[pid].store(priority, Ordering::Relaxed);
+}
+
+/// Has `pid` run with at least `priority` until `unboost()`.
+pub fn boost(pid: usize, priority: usize) {
+    BOOST[pid].fetch_max(priority, Ordering::Relaxed);
+}
+
+/// Ends the boost of `pid`.
+pub fn unboost(pid: usize) {
+    BOOST[pid].store(0, Ordering::Relaxed);
+}
+
+/// Returns whether processes are being scheduled, so the caller may block.
+pub fn is_scheduling() -> bool {
+    SCHEDULING.load(Ordering::Relaxed)
+}
+
+/// Returns the pid of the process running on the calling hart.
+pub fn current() -> usize {
+    current_pid(hart_id())
+}
+
+fn set_state(pid: usize, state: ProcState) {
+    PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock()
+        .get_proc(pid)
+        .state = state;
+}
+
+/// Marks `pid`, the calling process, blocked for its next `give_up()`, unless a signal is
+/// pending: `interrupt()` only wakes up processes that are already blocked.
+fn block(pid: usize) {
+    set_state(pid, ProcState::Blocked);
+    if signal::pending(pid) {
+        set_state(pid, ProcState::Runnable);
+    }
+}
+
+/// Returns `true` if `pid` is a live process.
+pub fn exists(pid: usize) -> bool {
+    pid < PROC_MAX
+        && PROC_TABLE
+            .get_or_init(|| Mutex::new(ProcTable::new()))
+            .lock()
+            .get_proc(pid)
+            .state
+            != ProcState::Unused
+}
+
+/// Returns whether `pid` runs a program in user mode: kernel processes have nothing mapped
+/// in their address space, and neither have exited ones.
+pub fn is_user(pid: usize) -> bool {
+    let mut mapped = false;
+    if exists(pid) {
+        for_each_region(pid, |_| mapped = true);
+    }
+    mapped
+}
+
+/// Returns what `pid` is doing, `None` if there is no such process.
+pub fn state_name(pid: usize) -> Option<&'static str> {
+    if pid >= PROC_MAX {
+        return None;
+    }
+    let mut proc_table = PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock();
+    let running = (0..MAX_HARTS).any(|hart| current_pid(hart) == pid);
+    match proc_table.get_proc(pid).state {
+        ProcState::Unused => None,
+        ProcState::Runnable if running => Some("running"),
+        ProcState::Runnable => Some("runnable"),
+        ProcState::Blocked => Some("blocked"),
+        ProcState::Zombie => Some("zombie"),
+        ProcState::Stopped => Some("stopped"),
+    }
+}
+
+/// Numbers of processes by state, and the load averages of the harts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    /// Running or waiting to run, not counting the idle process.
+    pub runnable: usize,
+    pub blocked: usize,
+    pub stopped: usize,
+    pub zombie: usize,
+    /// Runnable processes by the hart they last ran on.
+    pub run_queues: [usize; MAX_HARTS],
+    /// Load averages of each hart over 1, 5 and 15 minutes, see `loadavg`.
+    pub load: [[usize; 3]; MAX_HARTS],
+}
+
+fn count(proc_table: &mut ProcTable) -> Stats {
+    let mut stats = Stats::default();
+    for (pid, last_hart) in LAST_HART.iter().enumerate().skip(1) {
+        match proc_table.get_proc(pid).state {
+            ProcState::Unused => {}
+            ProcState::Runnable => {
+                stats.runnable += 1;
+                stats.run_queues[last_hart.load(Ordering::Relaxed)] += 1;
+            }
+            ProcState::Blocked => stats.blocked += 1,
+            ProcState::Stopped => stats.stopped += 1,
+            ProcState::Zombie => stats.zombie += 1,
+        }
+    }
+    stats.load = loadavg::get();
+    stats
+}
+
+/// Returns the numbers of processes by state and the load averages.
+pub fn stats() -> Stats {
+    count(
+        &mut PROC_TABLE
+            .get_or_init(|| Mutex::new(ProcTable::new()))
+            .lock(),
+    )
+}
+
+/// Returns the length of each hart's run queue, `None` if the process table is in use.
+/// Safe to call from interrupt handlers.
+pub fn try_run_queues() -> Option<[usize; MAX_HARTS]> {
+    let mut proc_table = PROC_TABLE.get()?.try_lock()?;
+    Some(count(&mut proc_table).run_queues)
+}
+
+/// Changes the access to the calling process's pages in `addr..addr + len` to `prot`, made
+/// of `PROT_READ`, `PROT_WRITE` and `PROT_EXEC`, like Linux's `mprotect()`.
+///
+/// Fails with `EINVAL` if `addr` isn't page-aligned or `prot` has other bits, and with
+/// `ENOMEM` if part of the range isn't mapped.
+pub fn mprotect(addr: usize, len: usize, prot: usize) -> Result<(), Errno> {
+    let flags = vm::prot_flags(prot).ok_or(Errno::EINVAL)?;
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(Errno::EINVAL);
+    }
+    let end = addr
+        .checked_add(len)
+        .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE))
+        .ok_or(Errno::ENOMEM)?;
+    if addr == end {
+        return Ok(());
+    }
+
+    PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock()
+        .get_proc(current())
+        .page_table
+        .mprotect(addr, end, flags)
+}
+
+/// Calls `f` with the address space of the calling process.
+pub fn with_page_table<R>(f: impl FnOnce(&mut PageTable) -> R) -> R {
+    f(&mut PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock()
+        .get_proc(current())
+        .page_table)
+}
+
+/// Returns the physical address `vaddr` maps to in the calling process's address space.
+pub fn translate(vaddr: usize) -> Option<usize> {
+    // Paging is still off until the first process runs.
+    if Satp::read().mode() == SatpMode::Bare {
+        return Some(vaddr);
+    }
+
+    PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock()
+        .get_proc(current())
+        .page_table
+        .translate(vaddr)
+}
+
+/// Returns whether `start..end` is mapped with all of `flags` in the user part of the
+/// calling process's address space.
+pub fn user_accessible(start: usize, end: usize, flags: usize) -> bool {
+    // Paging is still off until the first process runs.
+    if Satp::read().mode() == SatpMode::Bare {
+        return true;
+    }
+
+    let mut proc_table = PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock();
+    let page_table = &mut proc_table.get_proc(current()).page_table;
+    // The kernel is about to touch these, they may be swapped out, the zero page it is
+    // about to write to, or a file not mapped yet.
+    for vaddr in (start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+        let write = flags & PAGE_W != 0;
+        if swap::swap_in(page_table, vaddr).is_err()
+            || (write && page_table.unshare_zero_page(vaddr).is_err())
+            || mmap::fault(page_table, vaddr, write).is_err()
+        {
+            return false;
+        }
+    }
+    page_table.allows(start, end, flags)
+}
+
+/// Handles a page fault of the calling process at `addr`, on a write if `write`, returning
+/// whether it can carry on: the page was swapped out and is back, was the zero page
+/// written to and has a frame of its own, or is mapped from a file (see `mmap`).
+pub fn on_page_fault(addr: usize, write: bool) -> bool {
+    let pid = current();
+    if pid == 0 {
+        return false;
+    }
+    let mut proc_table = PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock();
+    let page_table = &mut proc_table.get_proc(pid).page_table;
+    let swapped = swap::swap_in(page_table, addr).unwrap_or(false);
+    let unshared = write && page_table.unshare_zero_page(addr).unwrap_or(false);
+    let mapped = mmap::fault(page_table, addr, write).unwrap_or(false);
+    swapped || unshared || mapped
+}
+
+/// Calls `f` for every region mapped in the address space of `pid`.
+pub fn for_each_region(pid: usize, f: impl FnMut(Region)) {
+    PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock()
+        .get_proc(pid)
+        .page_table
+        .for_each_region(f);
+}
+
+/// Returns the memory the address space of `pid` uses.
+pub fn mem_usage(pid: usize) -> vm::MemUsage {
+    PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock()
+        .get_proc(pid)
+        .page_table
+        .usage()
+}
+
+/// Ages the pages of `pid`, see `vm::PageTable::age()`.
+pub fn age_pages(pid: usize) {
+    let mut proc_table = PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock();
+    let proc = proc_table.get_proc(pid);
+    if proc.state != ProcState::Unused {
+        proc.page_table.age();
+    }
+}
+
+/// Calls `f` with the page table of every process, returning `false` without calling it if
+/// the process table is in use. Safe to call from shrinkers.
+pub fn try_for_each_page_table(mut f: impl FnMut(&mut PageTable)) -> bool {
+    let Some(mut proc_table) = PROC_TABLE.get().and_then(|t| t.try_lock()) else {
+        return false;
+    };
+    for pid in 0..PROC_MAX {
+        let proc = proc_table.get_proc(pid);
+        if proc.state != ProcState::Unused {
+            f(&mut proc.page_table);
+        }
+    }
+    true
+}
+
+/// Makes `pid` runnable if it is blocked, e.g. to handle a signal.
+///
+/// It is woken as if spuriously, blocking syscalls then fail with `ERESTARTSYS` (see
+/// `signal::interrupted()`) and kernel code waits again unless its condition changed.
+pub fn interrupt(pid: usize) {
+    let mut proc_guard = PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock();
+
+    let proc = proc_guard.get_proc(pid);
+    if proc.state == ProcState::Blocked {
+        proc.state = ProcState::Runnable;
+    }
+}
+
+/// Stops the calling process for signal `sig` until it is sent `SIGCONT`, letting its
+/// parent's `wait()` know.
+pub fn stop(sig: usize) {
+    let pid = current();
+
+    let mut exits = EXITS.lock();
+    exits[pid].stopped = Some(sig);
+    set_state(pid, ProcState::Stopped);
+    drop(exits);
+    CHILD_EXITED.wake_all();
+
+    give_up();
+}
+
+/// Makes `pid` runnable if it is stopped.
+pub fn resume(pid: usize) {
+    let mut exits = EXITS.lock();
+    // Only a process that is still stopped gets reported as such.
+    exits[pid].stopped = None;
+    let mut proc_guard = PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock();
+    let proc = proc_guard.get_proc(pid);
+    if proc.state == ProcState::Stopped {
+        proc.state = ProcState::Runnable;
+    }
+}
+
+/// Terminates the calling process, releasing its memory, files and signal state.
+///
+/// Its parent collects `status` (see `exit_status()`) with `wait()`, until then the
+/// process stays a zombie. Its own children are left without a parent.
+///
+/// FIXME: The slot can be reused while its stack is still in use until the switch away
+/// from it, which is only safe as long as a single hart schedules processes.
+pub fn exit(status: usize) -> ! {
+    let pid = current();
+
+    file::close_all(pid);
+    signal::reset(pid);
+    alarm::reset(pid);
+    misaligned::reset(pid);
+    rlimit::reset(pid);
+    cred::reset(pid);
+    cwd::reset(pid);
+    fpu::reset(pid);
+    PGID[pid].store(0, Ordering::Relaxed);
+    tty::reset(pid);
+
+    PROC_TABLE
+        .get_or_init(|| Mutex::new(ProcTable::new()))
+        .lock()
+        .get_proc(pid)
+        .page_table
+        .free_user_pages();
+
+    let mut exits = EXITS.lock();
+    for child in 0..PROC_MAX {
+        if exits[child].parent == pid {
+            exits[child].parent = NO_PARENT;
+            // Nobody is left to wait for it.
+            if exits[child].status.take().is_some() {
+                set_state(child, ProcState::Unused);
+            }
+        }
+    }
+    if exits[pid].parent == NO_PARENT {
+        set_state(pid, ProcState::Unused);
+    } else {
+        exits[pid].status = Some(status);
+        exits[pid].stopped = None;
+        set_state(pid, ProcState::Zombie);
+    }
+    drop(exits);
+    CHILD_EXITED.wake_all();
+
+    // Exiting from a trap handler, interrupts must not stay off for the next process.
+    Sstatus::set_sie();
+
+    give_up();
+
+    panic!("exited process {pid} was scheduled again.");
+}
+
+/// Blocks the calling process until `wake_deferred()` is called for it or the tick count
+/// reaches `deadline`.
+///
+/// Wakeups may be spurious, callers must check what they are waiting for again.
+pub fn sleep(deadline: Option<usize>) {
+    let pid = current();
+
+    WAKE_AT[pid].store(deadline.unwrap_or(NO_TIMEOUT), Ordering::Relaxed);
+    block(pid);
+
+    give_up();
+
+    WAKE_AT[pid].store(NO_TIMEOUT, Ordering::Relaxed);
+}
+
+/// Wakes up the processes in the `pids` bit mask. Safe to call from interrupt handlers.
+pub fn wake_deferred(pids: usize) {
+    crate::tracepoint!(Wakeup, pids);
+    DEFERRED_WAKEUPS.fetch_or(pids, Ordering::Release);
+}
+
+/// Returns the earliest tick a sleeping process waits for.
+pub fn next_wakeup() -> Option<usize> {
+    WAKE_AT
+        .iter()
+        .map(|wake_at| wake_at.load(Ordering::Relaxed))
+        .filter(|&wake_at| wake_at != NO_TIMEOUT)
+        .min()
+}
+
+/// Wakes up sleeping processes whose deadline is at or before `ticks`, called on every tick.
+pub fn wake_sleepers(ticks: usize) {
+    let due = (0..PROC_MAX)
+        .filter(|&pid| WAKE_AT[pid].load(Ordering::Relaxed) <= ticks)
+        .fold(0, |pids, pid| pids | 1 << pid);
+
+    if due != 0 {
+        wake_deferred(due);
+    }
+}
+
+/// Processes blocked until a condition they wait for may have changed.
+pub struct WaitQueue {
+    waiting: Mutex<[bool; PROC_MAX]>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiting: Mutex::new([false; PROC_MAX]),
+        }
+    }
+
+    /// Blocks the calling process until it is woken up.
+    ///
+    /// `guard` protects the condition being waited for and is released only once the
+    /// process is queued, so a wakeup can't be missed. Wakeups may be spurious, callers
+    /// must check the condition again.
+    pub fn wait<T>(&self, guard: MutexGuard<'_, T>) {
+        let pid = current();
+
+        self.waiting.lock()[pid] = true;
+        block(pid);
+        drop(guard);
+
+        give_up();
+    }
+
+    /// Wakes up one waiting process, returning `false` if there was none.
+    pub fn wake_one(&self) -> bool {
+        let mut waiting = self.waiting.lock();
+
+        let Some(pid) = waiting.iter().position(|&w| w) else {
+            return false;
+        };
+        waiting[pid] = false;
+        // Takes effect once the caller gives up the hart, which also covers waiters
+        // that are queued but haven't blocked yet, see `add()`.
+        wake_deferred(1 << pid);
+
+        true
+    }
+
+    /// Queues `pid` without blocking it, for waiting on several queues at once.
+    ///
+    /// Wakeups between this and the process's next `sleep()` aren't lost.
+    pub fn add(&self, pid: usize) {
+        self.waiting.lock()[pid] = true;
+    }
+
+    /// Removes `pid` from the queue if it is still queued.
+    pub fn remove(&self, pid: usize) {
+        self.waiting.lock()[pid] = false;
+    }
+
+    /// Wakes up all waiting processes, returning how many there were.
+    pub fn wake_all(&self) -> usize {
+        let mut woken = 0;
+        while self.wake_one() {
+            woken += 1;
+        }
+        woken
+    }
+}
+
+/// Panics, naming the owner, if the kernel stack of `pid` overflowed its canary.
+///
+/// Checked on every context switch away from a process and on every trap, which catches
+/// an overflow soon after the fact rather than preventing it.
+pub fn check_stack(pid: usize) {
+    let canary = STACK_CANARIES[pid].load(Ordering::Relaxed);
+    if canary != 0 && unsafe { ptr::read_volatile(canary as *const usize) } != STACK_CANARY {
+        panic!("kernel stack overflow: pid {pid} overwrote its stack canary at 0x{canary:x}");
+    }
+}
+
+/// Returns the pid of the process currently running on `hart`.
+pub fn current_pid(hart: usize) -> usize {
+    CURRENT_PID[hart].load(Ordering::Relaxed)
+}
+
+/// Counts a timer tick against the time slice of the process running on this hart,
+/// returning whether it is used up.
+pub fn tick_slice() -> bool {
+    SLICE_USED[hart_id()].fetch_add(1, Ordering::Relaxed) + 1 >= TIMESLICE.get()
+}
+
 pub fn give_up() {
+    watchdog::pet_hart();
+    check_stack(current());
+    rcu::quiescent();
+
     let mut proc_guard = PROC_TABLE
         .get_or_init(|| Mutex::new(ProcTable::new()))
         .lock();
 
+    let mut woken = DEFERRED_WAKEUPS.swap(0, Ordering::Acquire);
+    while woken != 0 {
+        let proc = proc_guard.get_proc(woken.trailing_zeros() as usize);
+        if proc.state == ProcState::Blocked {
+            proc.state = ProcState::Runnable;
+        }
+        woken &= woken - 1;
+    }
+
     let curr_proc_idx = proc_guard.curr_proc_idx;
 
+    // The highest priority wins, ties going round-robin from the process after the current
+    // one, which comes last and keeps the hart if it is still runnable and nothing else is.
+    // The idle process only runs if no other can.
+    let slots = proc_guard.table.cap() - 1;
     let mut next_runnable_idx = 0;
-    for i in 1..proc_guard.table.cap() {
-        if proc_guard.table[i].state == ProcState::Runnable && i != proc_guard.curr_proc_idx {
+    for i in (0..slots).map(|k| 1 + (curr_proc_idx + k) % slots) {
+        if proc_guard.table[i].state == ProcState::Runnable
+            && (next_runnable_idx == 0 || priority(i) > priority(next_runnable_idx))
+        {
             next_runnable_idx = i;
-            break;
         }
     }
 
-    let prev_sp = proc_guard.get_proc(curr_proc_idx).sp_as_mut_ptr();
+    let prev = proc_guard.get_proc(curr_proc_idx);
+    let prev_sp = prev.sp_as_mut_ptr();
+    let prev_pid = prev.pid;
 
     let next = proc_guard.get_proc(next_runnable_idx);
     let next_sp = next.sp_as_mut_ptr();
-    let next_stack = unsafe { (&next.stack[PROC_STACK_SIZE - 1] as *const u8).add(1) };
+    let next_pid = next.pid;
 
-    unsafe {
-        asm!(
-            "sfence.vma",
-            "csrw satp, {0}",
-            "sfence.vma",
-            "csrw sscratch, {1}",
-            in(reg) (SATP_SV32 | (next.page_table.root_pt_addr() / PAGE_SIZE)),
-            in(reg) next_stack, // trap_handler will use this value
-        );
-    }
+    // sscratch stays zero while running in the kernel, see `trap_entry`.
+    activate(&next.page_table);
 
+    crate::tracepoint!(SchedSwitch, current(), next_pid);
     proc_guard.curr_proc_idx = next_runnable_idx;
+    CURRENT_PID[hart_id()].store(next_pid, Ordering::Relaxed);
+    SCHEDULING.store(true, Ordering::Relaxed);
+    SLICE_USED[hart_id()].store(0, Ordering::Relaxed);
+    LAST_HART[next_pid].store(hart_id(), Ordering::Relaxed);
 
     drop(proc_guard);
 
+    fpu::switch(prev_pid, next_pid);
+
+    // Whether interrupts are on, and where a trap handler returns to, belongs to the
+    // process that gives up the hart.
+    let sstatus = Sstatus::read();
     switch_context(prev_sp, next_sp);
+    sstatus.write();
+
+    // Resumed, fatal signals sent meanwhile take effect here.
+    signal::handle_fatal();
 }
 
 #[naked]
@@ -155,38 +1116,38 @@ pub extern "C" fn switch_context(prev_sp: *mut usize, next_sp: *mut usize) {
     unsafe {
         naked_asm!(
             // Save callee-saved registers onto the current process's stack.
-            "addi sp, sp, -13 * 4", // Allocate stack space for 13 4-byte registers
-            "sw ra,  0  * 4(sp)",   // Save callee-saved registers only
-            "sw s0,  1  * 4(sp)",
-            "sw s1,  2  * 4(sp)",
-            "sw s2,  3  * 4(sp)",
-            "sw s3,  4  * 4(sp)",
-            "sw s4,  5  * 4(sp)",
-            "sw s5,  6  * 4(sp)",
-            "sw s6,  7  * 4(sp)",
-            "sw s7,  8  * 4(sp)",
-            "sw s8,  9  * 4(sp)",
-            "sw s9,  10 * 4(sp)",
-            "sw s10, 11 * 4(sp)",
-            "sw s11, 12 * 4(sp)",
+            concat!("addi sp, sp, -13 * ", reg_bytes!()), // Allocate stack space for 13 registers
+            store_reg!(ra, 0),                            // Save callee-saved registers only
+            store_reg!(s0, 1),
+            store_reg!(s1, 2),
+            store_reg!(s2, 3),
+            store_reg!(s3, 4),
+            store_reg!(s4, 5),
+            store_reg!(s5, 6),
+            store_reg!(s6, 7),
+            store_reg!(s7, 8),
+            store_reg!(s8, 9),
+            store_reg!(s9, 10),
+            store_reg!(s10, 11),
+            store_reg!(s11, 12),
             // Switch the stack pointer.
-            "sw sp, (a0)", // *prev_sp = sp;
-            "lw sp, (a1)", // Switch stack pointer (sp) here
+            store_reg!(sp, 0, a0), // *prev_sp = sp;
+            load_reg!(sp, 0, a1),  // Switch stack pointer (sp) here
             // Restore callee-saved registers from the next process's stack.
-            "lw ra,  0  * 4(sp)", // Restore callee-saved registers only
-            "lw s0,  1  * 4(sp)",
-            "lw s1,  2  * 4(sp)",
-            "lw s2,  3  * 4(sp)",
-            "lw s3,  4  * 4(sp)",
-            "lw s4,  5  * 4(sp)",
-            "lw s5,  6  * 4(sp)",
-            "lw s6,  7  * 4(sp)",
-            "lw s7,  8  * 4(sp)",
-            "lw s8,  9  * 4(sp)",
-            "lw s9,  10 * 4(sp)",
-            "lw s10, 11 * 4(sp)",
-            "lw s11, 12 * 4(sp)",
-            "addi sp, sp, 13 * 4", // We've popped 13 4-byte registers from the stack
+            load_reg!(ra, 0), // Restore callee-saved registers only
+            load_reg!(s0, 1),
+            load_reg!(s1, 2),
+            load_reg!(s2, 3),
+            load_reg!(s3, 4),
+            load_reg!(s4, 5),
+            load_reg!(s5, 6),
+            load_reg!(s6, 7),
+            load_reg!(s7, 8),
+            load_reg!(s8, 9),
+            load_reg!(s9, 10),
+            load_reg!(s10, 11),
+            load_reg!(s11, 12),
+            concat!("addi sp, sp, 13 * ", reg_bytes!()), // We've popped 13 registers from the stack
             "ret",
         )
     }
@@ -0,0 +1,426 @@
+use core::fmt::Write;
+
+use crate::{
+    MAX_HARTS, block, file, irq, loadavg,
+    mem::{self, PAGE_SIZE},
+    misaligned, page,
+    proc::{self, PROC_MAX},
+    signal, slab, swap,
+    sync::Mutex,
+    syscall::Errno,
+    sysctl::TUNABLES,
+    timer,
+    trap::{self, IRQ_CAUSES, IRQ_S_EXTERNAL, IRQ_S_SOFT, IRQ_S_TIMER},
+    vfs::{self, BufWriter, DirEntry, FileSystem},
+};
+
+// The filesystem at `/proc`, whose files are generated from kernel state on every read:
+//
+//   /proc/meminfo, /proc/uptime, /proc/interrupts, /proc/buddyinfo, /proc/loadavg,
+//   /proc/diskstats, /proc/slabinfo
+//   /proc/sys/<tunable>
+//   /proc/<pid>/status, /proc/<pid>/maps
+//
+// Writing anything to `/proc/buddyinfo` resets the allocator's counters, and writing a
+// number to a file in `/proc/sys` sets that tunable (see `sysctl`).
+
+// Inodes of the files that always exist.
+const ROOT: usize = 1;
+const MEMINFO: usize = 2;
+const UPTIME: usize = 3;
+const INTERRUPTS: usize = 4;
+const BUDDYINFO: usize = 5;
+const LOADAVG: usize = 6;
+const DISKSTATS: usize = 7;
+const SLABINFO: usize = 8;
+const SYS: usize = 9;
+
+const TOP_FILES: [(&str, usize); 8] = [
+    ("meminfo", MEMINFO),
+    ("uptime", UPTIME),
+    ("interrupts", INTERRUPTS),
+    ("buddyinfo", BUDDYINFO),
+    ("loadavg", LOADAVG),
+    ("diskstats", DISKSTATS),
+    ("slabinfo", SLABINFO),
+    ("sys", SYS),
+];
+
+// Inode of the file of each tunable in `/proc/sys`, in `TUNABLES` order.
+const SYS_BASE: usize = 10;
+
+// Inodes of process `pid` start at `PID_BASE + pid * PID_INODES`: its directory, then the
+// files in `PID_FILES`.
+const PID_BASE: usize = 32;
+const PID_INODES: usize = 4;
+const PID_FILES: [&str; 2] = ["status", "maps"];
+
+const _: () = assert!(SYS_BASE + TUNABLES.len() <= PID_BASE);
+
+// Largest file that can be generated, longer contents are cut off.
+const MAX_CONTENTS: usize = 2048;
+
+// Contents are generated here, not on the small kernel stacks.
+static SCRATCH: Mutex<[u8; MAX_CONTENTS]> = Mutex::new([0; MAX_CONTENTS]);
+
+/// What an inode of the filesystem stands for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Node {
+    Root,
+    File(usize),
+    SysDir,
+    SysFile(usize),
+    PidDir(usize),
+    PidFile(usize, usize),
+}
+
+fn node(ino: usize) -> Option<Node> {
+    match ino {
+        ROOT => Some(Node::Root),
+        MEMINFO | UPTIME | INTERRUPTS | BUDDYINFO | LOADAVG | DISKSTATS | SLABINFO => {
+            Some(Node::File(ino))
+        }
+        SYS => Some(Node::SysDir),
+        _ if (SYS_BASE..SYS_BASE + TUNABLES.len()).contains(&ino) => {
+            Some(Node::SysFile(ino - SYS_BASE))
+        }
+        _ if ino >= PID_BASE => {
+            let pid = (ino - PID_BASE) / PID_INODES;
+            let file = (ino - PID_BASE) % PID_INODES;
+            if !proc::exists(pid) {
+                return None;
+            }
+            match file {
+                0 => Some(Node::PidDir(pid)),
+                _ if file <= PID_FILES.len() => Some(Node::PidFile(pid, file - 1)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn pid_ino(pid: usize) -> usize {
+    PID_BASE + pid * PID_INODES
+}
+
+struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn root(&self) -> usize {
+        ROOT
+    }
+
+    // Pid directories come and go with their processes.
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    fn lookup(&self, dir: usize, name: &str) -> Result<usize, Errno> {
+        match node(dir).ok_or(Errno::ENOENT)? {
+            Node::Root => {
+                if let Some(&(_, ino)) = TOP_FILES.iter().find(|(n, _)| *n == name) {
+                    return Ok(ino);
+                }
+                match name.parse() {
+                    Ok(pid) if proc::exists(pid) => Ok(pid_ino(pid)),
+                    _ => Err(Errno::ENOENT),
+                }
+            }
+            Node::PidDir(pid) => PID_FILES
+                .iter()
+                .position(|&n| n == name)
+                .map(|file| pid_ino(pid) + 1 + file)
+                .ok_or(Errno::ENOENT),
+            Node::SysDir => TUNABLES
+                .iter()
+                .position(|t| t.name == name)
+                .map(|i| SYS_BASE + i)
+                .ok_or(Errno::ENOENT),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn is_dir(&self, ino: usize) -> bool {
+        matches!(node(ino), Some(Node::Root | Node::SysDir | Node::PidDir(_)))
+    }
+
+    fn read(&self, ino: usize, offset: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+        let node = node(ino).ok_or(Errno::ENOENT)?;
+
+        let mut scratch = SCRATCH.lock();
+        let mut out = BufWriter::new(&mut scratch[..]);
+        // A full buffer cuts the file off, which is all that can be done about it.
+        let _ = generate(node, &mut out);
+        let len = out.len();
+
+        let start = offset.min(len);
+        let n = buf.len().min(len - start);
+        buf[..n].copy_from_slice(&scratch[start..start + n]);
+        Ok(n)
+    }
+
+    fn write(&self, ino: usize, _offset: usize, buf: &[u8]) -> Result<usize, Errno> {
+        match node(ino).ok_or(Errno::ENOENT)? {
+            Node::File(BUDDYINFO) => mem::reset_buddy_stats(),
+            Node::SysFile(i) => {
+                let value = core::str::from_utf8(buf).map_err(|_| Errno::EINVAL)?;
+                TUNABLES[i].parse(value)?;
+            }
+            _ => return Err(Errno::EACCES),
+        }
+        Ok(buf.len())
+    }
+
+    fn readdir(&self, dir: usize, index: usize) -> Result<Option<DirEntry>, Errno> {
+        match node(dir).ok_or(Errno::ENOENT)? {
+            Node::Root => {
+                if let Some(&(name, ino)) = TOP_FILES.get(index) {
+                    return Ok(Some(DirEntry::new(ino, format_args!("{name}"))));
+                }
+                let pid = (0..PROC_MAX)
+                    .filter(|&pid| proc::exists(pid))
+                    .nth(index - TOP_FILES.len());
+                Ok(pid.map(|pid| DirEntry::new(pid_ino(pid), format_args!("{pid}"))))
+            }
+            Node::PidDir(pid) => Ok(PID_FILES
+                .get(index)
+                .map(|name| DirEntry::new(pid_ino(pid) + 1 + index, format_args!("{name}")))),
+            Node::SysDir => Ok(TUNABLES
+                .get(index)
+                .map(|t| DirEntry::new(SYS_BASE + index, format_args!("{}", t.name)))),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+}
+
+fn generate(node: Node, out: &mut BufWriter) -> core::fmt::Result {
+    match node {
+        Node::File(MEMINFO) => {
+            let stats = mem::stats();
+            let kb = |pages| pages * PAGE_SIZE / 1024;
+            writeln!(out, "MemTotal: {:8} kB", stats.total / 1024)?;
+            writeln!(out, "MemFree:  {:8} kB", kb(stats.free_pages))?;
+            writeln!(out, "MemUsed:  {:8} kB", kb(stats.used_pages))?;
+            writeln!(out, "MaxBlock: {:8} kB", stats.largest_free / 1024)?;
+            let kb = |owner| kb(page::count(owner));
+            writeln!(out, "AnonPages:{:8} kB", kb(page::Owner::User))?;
+            writeln!(out, "PageTables:{:7} kB", kb(page::Owner::PageTable))?;
+            let swap = swap::stats();
+            writeln!(out, "SwapTotal:{:8} kB", swap.total * PAGE_SIZE / 1024)?;
+            writeln!(
+                out,
+                "SwapFree: {:8} kB",
+                (swap.total - swap.used) * PAGE_SIZE / 1024
+            )
+        }
+        Node::File(UPTIME) => {
+            let centis = |ticks: usize| ticks * 100 / timer::hz();
+            let up = (timer::now() * 100 / timer::TIMEBASE_FREQ) as usize;
+            let idle = centis(timer::idle_ticks());
+            writeln!(
+                out,
+                "{}.{:02} {}.{:02}",
+                up / 100,
+                up % 100,
+                idle / 100,
+                idle % 100
+            )
+        }
+        Node::File(INTERRUPTS) => {
+            write!(out, "    ")?;
+            for hart in 0..MAX_HARTS {
+                write!(out, " {:>6}{hart:<4}", "HART")?;
+            }
+            writeln!(out)?;
+            for cause in 0..IRQ_CAUSES {
+                let name = match cause {
+                    IRQ_S_SOFT => "software",
+                    IRQ_S_TIMER => "timer",
+                    IRQ_S_EXTERNAL => "external",
+                    _ => continue,
+                };
+                write!(out, "{cause:3}:")?;
+                for hart in 0..MAX_HARTS {
+                    write!(out, " {:10}", trap::interrupt_count(hart, cause))?;
+                }
+                writeln!(out, "  {name}")?;
+            }
+            // Device lines, counted for all harts together.
+            let mut result = Ok(());
+            irq::for_each(|line, name, count, affinity, threaded| {
+                if result.is_ok() {
+                    let kind = if threaded { "threaded" } else { "" };
+                    result = writeln!(
+                        out,
+                        "{line:3}: {count:10}  plic {name} affinity {affinity:#x} {kind}"
+                    );
+                }
+            });
+            result
+        }
+        Node::File(BUDDYINFO) => {
+            let (stats, free) = mem::buddy_stats();
+            writeln!(out, "order   size kB     free   allocs    frees")?;
+            let orders = free.iter().zip(stats.allocs.iter().zip(&stats.frees));
+            for (order, (&free, (&allocs, &frees))) in orders.enumerate() {
+                if free + allocs + frees == 0 {
+                    continue;
+                }
+                let size = (PAGE_SIZE << order) / 1024;
+                writeln!(out, "{order:5} {size:9} {free:8} {allocs:8} {frees:8}")?;
+            }
+            writeln!(out, "splits:  {}", stats.splits)?;
+            writeln!(out, "merges:  {}", stats.merges)?;
+            writeln!(out, "failed:  {}", stats.failed)?;
+            writeln!(out, "used:    {} kB", stats.used / 1024)?;
+            writeln!(out, "peak:    {} kB", stats.peak / 1024)
+        }
+        Node::File(LOADAVG) => {
+            // The system's load first, like Linux, then each hart's.
+            let stats = proc::stats();
+            let total = stats.runnable + stats.blocked + stats.stopped + stats.zombie;
+            let mut load = [0; 3];
+            for hart in &stats.load {
+                for (sum, avg) in load.iter_mut().zip(hart) {
+                    *sum += avg;
+                }
+            }
+            let [one, five, fifteen] = load.map(loadavg::Fixed);
+            writeln!(out, "{one} {five} {fifteen} {}/{total}", stats.runnable)?;
+            for (hart, load) in stats.load.iter().enumerate() {
+                let [one, five, fifteen] = load.map(loadavg::Fixed);
+                let queue = stats.run_queues[hart];
+                writeln!(out, "hart{hart} {one} {five} {fifteen} {queue}")?;
+            }
+            Ok(())
+        }
+        Node::File(DISKSTATS) => {
+            let stats = block::stats();
+            writeln!(out, "requests:         {}", stats.requests)?;
+            writeln!(out, "merged:           {}", stats.merged)?;
+            writeln!(out, "readahead_hits:   {}", stats.readahead_hits)?;
+            writeln!(out, "readahead_misses: {}", stats.readahead_misses)
+        }
+        Node::File(SLABINFO) => {
+            writeln!(out, "name            objects   slabs")?;
+            for cache in slab::stats() {
+                let (size, objects, slabs) = (cache.size, cache.objects, cache.slabs);
+                writeln!(out, "kmalloc-{size:<6} {objects:8} {slabs:7}")?;
+            }
+            Ok(())
+        }
+        Node::SysFile(i) => writeln!(out, "{}", TUNABLES[i].get()),
+        Node::PidFile(pid, 0) => {
+            let (pending, blocked) = signal::masks(pid);
+            writeln!(out, "Pid:     {pid}")?;
+            writeln!(
+                out,
+                "State:   {}",
+                proc::state_name(pid).unwrap_or("exited")
+            )?;
+            writeln!(out, "Pgid:    {}", proc::pgid(pid))?;
+            writeln!(out, "SigPnd:  {pending:08x}")?;
+            writeln!(out, "SigBlk:  {blocked:08x}")?;
+            let usage = proc::mem_usage(pid);
+            let kb = |pages| pages * PAGE_SIZE / 1024;
+            writeln!(out, "VmHWM:   {:8} kB", kb(usage.peak))?;
+            writeln!(out, "VmRSS:   {:8} kB", kb(usage.resident))?;
+            writeln!(out, "VmPTE:   {:8} kB", kb(usage.tables))?;
+            writeln!(out, "VmWSS:   {:8} kB", kb(usage.working_set))?;
+            writeln!(out, "VmDirty: {:8} kB", kb(usage.dirty))?;
+            writeln!(out, "VmSwap:  {:8} kB", kb(usage.swapped))?;
+            writeln!(out, "Fds:     {}", file::count(pid))?;
+            writeln!(out, "Misalign: {}", misaligned::count(pid).1)
+        }
+        Node::PidFile(pid, _) => {
+            let mut result = Ok(());
+            proc::for_each_region(pid, |r| {
+                if result.is_ok() {
+                    result = writeln!(out, "{r}");
+                }
+            });
+            result
+        }
+        Node::Root | Node::SysDir | Node::PidDir(_) | Node::File(_) => Ok(()),
+    }
+}
+
+static PROCFS: ProcFs = ProcFs;
+
+crate::register_subsystem!(Subsys, "procfs", init);
+
+fn init() {
+    vfs::mount("/proc", &PROCFS).expect("can't mount /proc.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysctl;
+
+    fn read_all(path: &str, buf: &mut [u8]) -> usize {
+        let fd = vfs::open(path, vfs::O_RDONLY, 0).unwrap();
+        let mut len = 0;
+        loop {
+            let n = file::read(fd, &mut buf[len..]).unwrap();
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+        file::close(fd).unwrap();
+        len
+    }
+
+    #[test_case]
+    fn files_are_generated_on_read() {
+        let mut buf = [0; 512];
+        let len = read_all("/proc/meminfo", &mut buf);
+        assert!(buf[..len].starts_with(b"MemTotal:"));
+        let len = read_all("/proc/interrupts", &mut buf);
+        assert!(buf[..len].windows(5).any(|w| w == b"timer"));
+
+        // Tests run before any process is created, so there are no pid directories.
+        let mut out = BufWriter::new(&mut buf);
+        generate(Node::PidFile(0, 0), &mut out).unwrap();
+        let len = out.len();
+        assert!(buf[..len].starts_with(b"Pid:     0\n"));
+        assert_eq!(
+            vfs::open("/proc/0/status", vfs::O_RDONLY, 0),
+            Err(Errno::ENOENT)
+        );
+    }
+
+    #[test_case]
+    fn lists_top_level_files() {
+        let fd = vfs::open("/proc", vfs::O_DIRECTORY, 0).unwrap();
+        let mut buf = [0; 128];
+        let len = vfs::getdents(fd, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            b"meminfo\0uptime\0interrupts\0buddyinfo\0loadavg\0diskstats\0slabinfo\0sys\0"
+        );
+        assert_eq!(vfs::getdents(fd, &mut buf), Ok(0));
+        assert_eq!(
+            vfs::open("/proc/uptime", vfs::O_DIRECTORY, 0),
+            Err(Errno::ENOTDIR)
+        );
+        file::close(fd).unwrap();
+    }
+
+    #[test_case]
+    fn tunables_are_set_through_sys() {
+        let tunable = sysctl::find("sched_timeslice").unwrap();
+        let before = tunable.get();
+        let fd = vfs::open("/proc/sys/sched_timeslice", vfs::O_WRONLY, 0).unwrap();
+        assert_eq!(file::write(fd, b"3\n"), Ok(2));
+        assert_eq!(file::write(fd, b"1000\n"), Err(Errno::EINVAL));
+        file::close(fd).unwrap();
+
+        let mut buf = [0; 16];
+        let len = read_all("/proc/sys/sched_timeslice", &mut buf);
+        assert_eq!(&buf[..len], b"3\n");
+        tunable.set(before).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+use core::{
+    cell::UnsafeCell,
+    cmp::Reverse,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::{
+    MAX_HARTS, cmdline, hart_id, ksyms, println,
+    proc::{self, PROC_MAX},
+};
+
+// A sampling profiler: while it runs, every timer interrupt records where the interrupted
+// hart was and which process it ran. `report()` then counts samples per function using the
+// embedded symbol table, see `ksyms.rs`.
+//
+// `profile` on the command line starts it before the timer does, and the report is printed
+// once the kernel has booted. Samples are taken at `hz=`, raise it for a finer profile.
+
+// Samples kept per hart, later ones are dropped until the profiler is restarted.
+const SAMPLES_PER_HART: usize = 2048;
+// Distinct functions `report()` tells apart, samples in others are counted together.
+const MAX_FUNCTIONS: usize = 64;
+// Functions listed by `report()`.
+const TOP_FUNCTIONS: usize = 20;
+
+#[derive(Clone, Copy)]
+struct Sample {
+    pc: usize,
+    pid: usize,
+}
+
+struct Buffer {
+    samples: UnsafeCell<[Sample; SAMPLES_PER_HART]>,
+    len: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+// Only the timer interrupt of the buffer's own hart writes to it, and only to slots past
+// `len`.
+unsafe impl Sync for Buffer {}
+
+static BUFFERS: [Buffer; MAX_HARTS] = [const {
+    Buffer {
+        samples: UnsafeCell::new([Sample { pc: 0, pid: 0 }; SAMPLES_PER_HART]),
+        len: AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+    }
+}; MAX_HARTS];
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Discards all samples and starts taking new ones.
+pub fn start() {
+    RUNNING.store(false, Ordering::Relaxed);
+    for buffer in &BUFFERS {
+        buffer.len.store(0, Ordering::Relaxed);
+        buffer.dropped.store(0, Ordering::Relaxed);
+    }
+    RUNNING.store(true, Ordering::Release);
+}
+
+pub fn stop() {
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Records a sample of the calling hart, interrupted at `sepc`. Called on every timer tick.
+pub fn sample(sepc: usize) {
+    if !RUNNING.load(Ordering::Acquire) {
+        return;
+    }
+
+    let buffer = &BUFFERS[hart_id()];
+    let len = buffer.len.load(Ordering::Relaxed);
+    if len == SAMPLES_PER_HART {
+        buffer.dropped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let sample = Sample {
+        pc: sepc,
+        pid: proc::current(),
+    };
+    unsafe { (buffer.samples.get() as *mut Sample).add(len).write(sample) };
+    buffer.len.store(len + 1, Ordering::Release);
+}
+
+crate::register_subsystem!(Core, "profile", init, after("cmdline"));
+
+/// Starts profiling the boot if `profile` is on the command line.
+fn init() {
+    if cmdline::get("profile").is_some() {
+        start();
+    }
+}
+
+/// Calls `f` with every sample taken so far.
+fn for_each_sample(mut f: impl FnMut(Sample)) {
+    for buffer in &BUFFERS {
+        let len = buffer.len.load(Ordering::Acquire);
+        let samples = buffer.samples.get() as *const Sample;
+        for i in 0..len {
+            f(unsafe { samples.add(i).read() });
+        }
+    }
+}
+
+/// Returns the function `pc` is in, its own address if there is no symbol for it.
+fn function_of(pc: usize) -> usize {
+    ksyms::lookup(pc).map_or(pc, |(_, start)| start)
+}
+
+/// Prints the functions most samples were taken in, and the share of each process.
+pub fn report() {
+    let mut functions = [(0, 0); MAX_FUNCTIONS];
+    let mut distinct = 0;
+    let mut other = 0;
+    let mut per_pid = [0; PROC_MAX];
+    let mut total = 0;
+
+    for_each_sample(|sample| {
+        total += 1;
+        per_pid[sample.pid] += 1;
+
+        let function = function_of(sample.pc);
+        match functions[..distinct]
+            .iter_mut()
+            .find(|(f, _)| *f == function)
+        {
+            Some((_, count)) => *count += 1,
+            None if distinct < MAX_FUNCTIONS => {
+                functions[distinct] = (function, 1);
+                distinct += 1;
+            }
+            None => other += 1,
+        }
+    });
+
+    let dropped: usize = BUFFERS
+        .iter()
+        .map(|b| b.dropped.load(Ordering::Relaxed))
+        .sum();
+    println!("profile: {total} samples ({dropped} dropped)");
+    if total == 0 {
+        return;
+    }
+    if !ksyms::is_present() {
+        println!("profile: no symbol table, run ksyms.py on the kernel to see function names");
+    }
+
+    let percent = |count: usize| count * 100 / total;
+    let functions = &mut functions[..distinct];
+    functions.sort_unstable_by_key(|&(_, count)| Reverse(count));
+    for &(function, count) in functions.iter().take(TOP_FUNCTIONS) {
+        let function = ksyms::resolve(function);
+        println!("profile: {count:6} {:3}% {function}", percent(count));
+    }
+    if other > 0 {
+        println!("profile: {other:6} {:3}% (other functions)", percent(other));
+    }
+
+    for (pid, &count) in per_pid.iter().enumerate().filter(|(_, c)| **c > 0) {
+        println!("profile: pid {pid}: {count} samples ({}%)", percent(count));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn samples_only_while_running() {
+        let was_running = is_running();
+        let len = || BUFFERS[hart_id()].len.load(Ordering::Relaxed);
+
+        stop();
+        let before = len();
+        sample(0x1234);
+        assert_eq!(len(), before);
+
+        start();
+        let pc = function_of as *const () as usize;
+        sample(pc);
+        // The timer interrupt may have taken samples of its own meanwhile.
+        let mut found = false;
+        for_each_sample(|s| found |= s.pc == pc && s.pid == proc::current());
+        assert!(found);
+        if ksyms::is_present() {
+            assert_eq!(function_of(pc + 4), pc);
+        }
+
+        if !was_running {
+            stop();
+        }
+    }
+}
@@ -0,0 +1,573 @@
+use crate::{
+    cred, faultpoint,
+    mem::{PAGE_SIZE, PhysAddr},
+    stdlib::{phalloc, phree},
+    sync::PiMutex,
+    syscall::Errno,
+    vfs::{self, DirEntry, FileSystem, Metadata, NAME_MAX},
+};
+
+// A filesystem in memory, mounted at `/tmp`, for files programs create. Everything is lost
+// on reboot.
+//
+// Inodes and directory entries are kept in tables of their own, an inode being its index
+// in the table, 0 the root directory. File contents are in pages allocated as the file
+// grows, up to `MAX_FILE_PAGES`. An inode is freed once no entry names it and no open file
+// refers to it, so a file removed while open can still be read and written until closed.
+
+const MAX_INODES: usize = 64;
+const MAX_ENTRIES: usize = 64;
+const MAX_FILE_PAGES: usize = 16;
+const ROOT: usize = 0;
+
+#[derive(Clone, Copy)]
+struct Inode {
+    used: bool,
+    is_dir: bool,
+    // Its contents are the target.
+    is_symlink: bool,
+    meta: Metadata,
+    size: usize,
+    pages: [Option<PhysAddr>; MAX_FILE_PAGES],
+    // Entries naming the inode.
+    nlink: usize,
+    // Open files referring to it.
+    opens: usize,
+}
+
+const FREE_INODE: Inode = Inode {
+    used: false,
+    is_dir: false,
+    is_symlink: false,
+    meta: Metadata {
+        mode: 0,
+        uid: cred::ROOT,
+        gid: cred::ROOT,
+    },
+    size: 0,
+    pages: [None; MAX_FILE_PAGES],
+    nlink: 0,
+    opens: 0,
+};
+
+impl Inode {
+    /// Reads at `offset` into `buf`, returning how many bytes were read.
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let end = self.size.min(offset.saturating_add(buf.len()));
+        let mut pos = offset;
+        while pos < end {
+            let n = (PAGE_SIZE - pos % PAGE_SIZE).min(end - pos);
+            let out = &mut buf[pos - offset..pos - offset + n];
+            match self.pages[pos / PAGE_SIZE] {
+                Some(page) => unsafe {
+                    out.copy_from_slice(core::slice::from_raw_parts(
+                        page.as_ptr().add(pos % PAGE_SIZE),
+                        n,
+                    ))
+                },
+                // Never written.
+                None => out.fill(0),
+            }
+            pos += n;
+        }
+        end.saturating_sub(offset)
+    }
+
+    /// Writes `buf` at `offset`, allocating the pages it needs.
+    fn write(&mut self, offset: usize, buf: &[u8]) -> Result<usize, Errno> {
+        let end = offset
+            .checked_add(buf.len())
+            .filter(|&end| end <= MAX_FILE_PAGES * PAGE_SIZE)
+            .ok_or(Errno::EFBIG)?;
+        let mut pos = offset;
+        while pos < end {
+            let n = (PAGE_SIZE - pos % PAGE_SIZE).min(end - pos);
+            let page = match self.pages[pos / PAGE_SIZE] {
+                Some(page) => page,
+                None => {
+                    if faultpoint!("ramfs-alloc") {
+                        return Err(Errno::ENOSPC);
+                    }
+                    let page = phalloc(PAGE_SIZE).map_err(|_| Errno::ENOSPC)?;
+                    unsafe { page.as_mut_ptr().write_bytes(0, PAGE_SIZE) };
+                    *self.pages[pos / PAGE_SIZE].insert(page)
+                }
+            };
+            let data = &buf[pos - offset..pos - offset + n];
+            unsafe {
+                core::slice::from_raw_parts_mut(page.as_mut_ptr().add(pos % PAGE_SIZE), n)
+                    .copy_from_slice(data)
+            };
+            pos += n;
+            self.size = self.size.max(pos);
+        }
+        Ok(buf.len())
+    }
+}
+
+/// A name of an inode in a directory.
+#[derive(Clone, Copy)]
+struct Entry {
+    dir: usize,
+    ino: usize,
+    name: [u8; NAME_MAX],
+    len: usize,
+}
+
+impl Entry {
+    fn new(dir: usize, ino: usize, name: &str) -> Self {
+        let mut entry = Entry {
+            dir,
+            ino,
+            name: [0; NAME_MAX],
+            len: name.len(),
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        entry
+    }
+
+    fn name(&self) -> &str {
+        // Only ever copied from a `str`.
+        unsafe { core::str::from_utf8_unchecked(&self.name[..self.len]) }
+    }
+}
+
+struct Tables {
+    inodes: [Inode; MAX_INODES],
+    entries: [Option<Entry>; MAX_ENTRIES],
+}
+
+impl Tables {
+    fn inode(&mut self, ino: usize) -> Result<&mut Inode, Errno> {
+        self.inodes
+            .get_mut(ino)
+            .filter(|inode| inode.used)
+            .ok_or(Errno::ENOENT)
+    }
+
+    fn dir(&mut self, ino: usize) -> Result<&mut Inode, Errno> {
+        let inode = self.inode(ino)?;
+        match inode.is_dir {
+            true => Ok(inode),
+            false => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn entries_of(&self, dir: usize) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().flatten().filter(move |e| e.dir == dir)
+    }
+
+    /// Returns the slot of entry `name` of directory `dir`, and the inode it names.
+    fn find(&mut self, dir: usize, name: &str) -> Result<(usize, usize), Errno> {
+        self.dir(dir)?;
+        self.entries
+            .iter()
+            .enumerate()
+            .find_map(|(slot, e)| {
+                let e = e.as_ref()?;
+                (e.dir == dir && e.name() == name).then_some((slot, e.ino))
+            })
+            .ok_or(Errno::ENOENT)
+    }
+
+    /// Names inode `ino` `name` in directory `dir`.
+    fn link(&mut self, dir: usize, name: &str, ino: usize) -> Result<(), Errno> {
+        if name.len() > NAME_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        if self.find(dir, name).is_ok() {
+            return Err(Errno::EEXIST);
+        }
+        let slot = self
+            .entries
+            .iter_mut()
+            .find(|e| e.is_none())
+            .ok_or(Errno::ENOSPC)?;
+        *slot = Some(Entry::new(dir, ino, name));
+        self.inodes[ino].nlink += 1;
+        Ok(())
+    }
+
+    /// Removes the entry in `slot`, freeing the inode it names if nothing else refers to it.
+    fn unlink(&mut self, slot: usize) {
+        if let Some(entry) = self.entries[slot].take() {
+            self.inodes[entry.ino].nlink -= 1;
+            self.free_unused(entry.ino);
+        }
+    }
+
+    fn free_unused(&mut self, ino: usize) {
+        let inode = &mut self.inodes[ino];
+        if inode.nlink == 0 && inode.opens == 0 {
+            inode.pages.iter().flatten().for_each(|&page| phree(page));
+            *inode = FREE_INODE;
+        }
+    }
+
+    /// Creates an inode named `name` in directory `dir`, returning it.
+    fn add(
+        &mut self,
+        dir: usize,
+        name: &str,
+        meta: Metadata,
+        is_dir: bool,
+    ) -> Result<usize, Errno> {
+        let ino = self
+            .inodes
+            .iter()
+            .position(|inode| !inode.used)
+            .ok_or(Errno::ENOSPC)?;
+        self.inodes[ino] = Inode {
+            used: true,
+            is_dir,
+            meta,
+            ..FREE_INODE
+        };
+        if let Err(errno) = self.link(dir, name, ino) {
+            self.inodes[ino] = FREE_INODE;
+            return Err(errno);
+        }
+        Ok(ino)
+    }
+
+    /// Returns whether directory `dir` is `ancestor` or below it.
+    fn is_below(&self, mut dir: usize, ancestor: usize) -> bool {
+        loop {
+            if dir == ancestor {
+                return true;
+            }
+            // A directory has a single entry, in its parent.
+            match self.entries.iter().flatten().find(|e| e.ino == dir) {
+                Some(entry) => dir = entry.dir,
+                None => return false,
+            }
+        }
+    }
+
+    /// Returns whether directory `ino` has no entries.
+    fn is_empty(&self, ino: usize) -> bool {
+        self.entries_of(ino).next().is_none()
+    }
+}
+
+pub struct RamFs {
+    // Held while copying file contents, which may fault a swapped out user page in, so it
+    // blocks, and lends its priority to whoever holds it.
+    tables: PiMutex<Tables>,
+}
+
+impl RamFs {
+    pub const fn new() -> Self {
+        let mut inodes = [FREE_INODE; MAX_INODES];
+        // Anyone may create files in it, and remove their own.
+        inodes[ROOT] = Inode {
+            used: true,
+            is_dir: true,
+            meta: Metadata {
+                mode: vfs::S_ISVTX | 0o777,
+                uid: cred::ROOT,
+                gid: cred::ROOT,
+            },
+            nlink: 1,
+            ..FREE_INODE
+        };
+        Self {
+            tables: PiMutex::new(Tables {
+                inodes,
+                entries: [None; MAX_ENTRIES],
+            }),
+        }
+    }
+}
+
+impl FileSystem for RamFs {
+    fn root(&self) -> usize {
+        ROOT
+    }
+
+    fn lookup(&self, dir: usize, name: &str) -> Result<usize, Errno> {
+        self.tables.lock().find(dir, name).map(|(_, ino)| ino)
+    }
+
+    fn is_dir(&self, ino: usize) -> bool {
+        self.tables.lock().dir(ino).is_ok()
+    }
+
+    fn read(&self, ino: usize, offset: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+        Ok(self.tables.lock().inode(ino)?.read(offset, buf))
+    }
+
+    fn readdir(&self, dir: usize, index: usize) -> Result<Option<DirEntry>, Errno> {
+        let mut tables = self.tables.lock();
+        tables.dir(dir)?;
+        Ok(tables
+            .entries_of(dir)
+            .nth(index)
+            .map(|e| DirEntry::new(e.ino, format_args!("{}", e.name()))))
+    }
+
+    fn write(&self, ino: usize, offset: usize, buf: &[u8]) -> Result<usize, Errno> {
+        self.tables.lock().inode(ino)?.write(offset, buf)
+    }
+
+    fn metadata(&self, ino: usize) -> Metadata {
+        let mut tables = self.tables.lock();
+        tables
+            .inode(ino)
+            .map_or(FREE_INODE.meta, |inode| inode.meta)
+    }
+
+    fn set_metadata(&self, ino: usize, meta: Metadata) -> Result<(), Errno> {
+        self.tables.lock().inode(ino)?.meta = meta;
+        Ok(())
+    }
+
+    fn create(&self, dir: usize, name: &str, meta: Metadata) -> Result<usize, Errno> {
+        self.tables.lock().add(dir, name, meta, false)
+    }
+
+    fn mkdir(&self, dir: usize, name: &str, meta: Metadata) -> Result<usize, Errno> {
+        self.tables.lock().add(dir, name, meta, true)
+    }
+
+    fn unlink(&self, dir: usize, name: &str) -> Result<(), Errno> {
+        let mut tables = self.tables.lock();
+        let (slot, ino) = tables.find(dir, name)?;
+        if tables.inodes[ino].is_dir {
+            return Err(Errno::EISDIR);
+        }
+        tables.unlink(slot);
+        Ok(())
+    }
+
+    fn rmdir(&self, dir: usize, name: &str) -> Result<(), Errno> {
+        let mut tables = self.tables.lock();
+        let (slot, ino) = tables.find(dir, name)?;
+        tables.dir(ino)?;
+        if !tables.is_empty(ino) {
+            return Err(Errno::ENOTEMPTY);
+        }
+        tables.unlink(slot);
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        old_dir: usize,
+        old_name: &str,
+        new_dir: usize,
+        new_name: &str,
+    ) -> Result<(), Errno> {
+        let mut tables = self.tables.lock();
+        let (slot, ino) = tables.find(old_dir, old_name)?;
+        let is_dir = tables.inodes[ino].is_dir;
+        tables.dir(new_dir)?;
+        if new_name.len() > NAME_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        if is_dir && tables.is_below(new_dir, ino) {
+            return Err(Errno::EINVAL);
+        }
+
+        // Whatever the new name names already is replaced, if it is of the same kind.
+        if let Ok((replaced, target)) = tables.find(new_dir, new_name) {
+            if target == ino {
+                return Ok(());
+            }
+            match (is_dir, tables.inodes[target].is_dir) {
+                (true, false) => return Err(Errno::ENOTDIR),
+                (false, true) => return Err(Errno::EISDIR),
+                (true, true) if !tables.is_empty(target) => return Err(Errno::ENOTEMPTY),
+                _ => tables.unlink(replaced),
+            }
+        }
+        tables.entries[slot] = Some(Entry::new(new_dir, ino, new_name));
+        Ok(())
+    }
+
+    fn link(&self, dir: usize, name: &str, ino: usize) -> Result<(), Errno> {
+        let mut tables = self.tables.lock();
+        if tables.inode(ino)?.is_dir {
+            return Err(Errno::EPERM);
+        }
+        tables.link(dir, name, ino)
+    }
+
+    fn symlink(
+        &self,
+        dir: usize,
+        name: &str,
+        target: &str,
+        meta: Metadata,
+    ) -> Result<usize, Errno> {
+        let mut tables = self.tables.lock();
+        let ino = tables.add(dir, name, meta, false)?;
+        let inode = &mut tables.inodes[ino];
+        inode.is_symlink = true;
+        if let Err(errno) = inode.write(0, target.as_bytes()) {
+            let (slot, _) = tables.find(dir, name)?;
+            tables.unlink(slot);
+            return Err(errno);
+        }
+        Ok(ino)
+    }
+
+    fn is_symlink(&self, ino: usize) -> bool {
+        self.tables
+            .lock()
+            .inode(ino)
+            .is_ok_and(|inode| inode.is_symlink)
+    }
+
+    fn readlink(&self, ino: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+        let mut tables = self.tables.lock();
+        let inode = tables.inode(ino)?;
+        if !inode.is_symlink {
+            return Err(Errno::EINVAL);
+        }
+        if inode.size > buf.len() {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        Ok(inode.read(0, buf))
+    }
+
+    fn opened(&self, ino: usize) {
+        if let Ok(inode) = self.tables.lock().inode(ino) {
+            inode.opens += 1;
+        }
+    }
+
+    fn released(&self, ino: usize) {
+        let mut tables = self.tables.lock();
+        if let Ok(inode) = tables.inode(ino) {
+            inode.opens = inode.opens.saturating_sub(1);
+            tables.free_unused(ino);
+        }
+    }
+}
+
+impl Drop for RamFs {
+    fn drop(&mut self) {
+        for inode in &self.tables.lock().inodes {
+            inode.pages.iter().flatten().for_each(|&page| phree(page));
+        }
+    }
+}
+
+static TMP: RamFs = RamFs::new();
+
+crate::register_subsystem!(Subsys, "ramfs", init);
+
+fn init() {
+    vfs::mount("/tmp", &TMP).expect("can't mount /tmp.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault::{self, Fault};
+
+    const META: Metadata = Metadata {
+        mode: 0o640,
+        uid: 5,
+        gid: 6,
+    };
+
+    #[test_case]
+    fn files_grow_across_pages() {
+        let fs = RamFs::new();
+        let ino = fs.create(ROOT, "a", META).unwrap();
+        assert_eq!(fs.create(ROOT, "a", META), Err(Errno::EEXIST));
+        assert_eq!(fs.lookup(ROOT, "a"), Ok(ino));
+        assert_eq!(fs.metadata(ino), META);
+        assert!(!fs.is_dir(ino));
+
+        let data = [7; 10];
+        assert_eq!(fs.write(ino, PAGE_SIZE - 4, &data), Ok(10));
+        let mut buf = [1; 16];
+        assert_eq!(fs.read(ino, PAGE_SIZE - 6, &mut buf), Ok(12));
+        assert_eq!(buf[..12], [0, 0, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7]);
+        assert_eq!(
+            fs.write(ino, MAX_FILE_PAGES * PAGE_SIZE, &data),
+            Err(Errno::EFBIG)
+        );
+
+        let entry = fs.readdir(ROOT, 0).unwrap().unwrap();
+        assert_eq!((entry.ino, entry.name()), (ino, "a"));
+        assert!(fs.readdir(ROOT, 1).unwrap().is_none());
+    }
+
+    #[test_case]
+    fn writes_fail_when_pages_run_out() {
+        let fs = RamFs::new();
+        let ino = fs.create(ROOT, "a", META).unwrap();
+        fs.write(ino, 0, &[7; 8]).unwrap();
+
+        // Only the second page is needed.
+        fault::inject("ramfs-alloc", Fault::Fail { skip: 0, count: 1 }).unwrap();
+        assert_eq!(fs.write(ino, PAGE_SIZE - 4, &[8; 8]), Err(Errno::ENOSPC));
+        assert_eq!(fault::clear("ramfs-alloc"), 1);
+        let mut buf = [0; 8];
+        assert_eq!(fs.read(ino, PAGE_SIZE - 4, &mut buf), Ok(4));
+        assert_eq!(buf[..4], [8; 4]);
+
+        assert_eq!(fs.write(ino, PAGE_SIZE - 4, &[9; 8]), Ok(8));
+        assert_eq!(fs.read(ino, PAGE_SIZE - 4, &mut buf), Ok(8));
+        assert_eq!(buf, [9; 8]);
+    }
+
+    #[test_case]
+    fn names_move_and_go() {
+        let fs = RamFs::new();
+        let dir = fs.mkdir(ROOT, "d", META).unwrap();
+        let sub = fs.mkdir(dir, "sub", META).unwrap();
+        let file = fs.create(ROOT, "f", META).unwrap();
+
+        assert_eq!(fs.rename(ROOT, "d", sub, "d"), Err(Errno::EINVAL));
+        assert_eq!(fs.rename(ROOT, "f", ROOT, "d"), Err(Errno::EISDIR));
+        assert_eq!(fs.rmdir(ROOT, "d"), Err(Errno::ENOTEMPTY));
+        assert_eq!(fs.unlink(ROOT, "d"), Err(Errno::EISDIR));
+        assert_eq!(fs.rmdir(ROOT, "f"), Err(Errno::ENOTDIR));
+
+        assert_eq!(fs.rename(ROOT, "f", sub, "g"), Ok(()));
+        assert_eq!(fs.lookup(ROOT, "f"), Err(Errno::ENOENT));
+        assert_eq!(fs.lookup(sub, "g"), Ok(file));
+
+        // Removed while open, it lives on until closed.
+        fs.write(file, 0, b"x").unwrap();
+        fs.opened(file);
+        assert_eq!(fs.unlink(sub, "g"), Ok(()));
+        let mut buf = [0; 1];
+        assert_eq!(fs.read(file, 0, &mut buf), Ok(1));
+        fs.released(file);
+        assert_eq!(fs.read(file, 0, &mut buf), Err(Errno::ENOENT));
+
+        // Gone once its last name is.
+        let file = fs.create(dir, "h", META).unwrap();
+        assert_eq!(fs.link(sub, "h2", file), Ok(()));
+        assert_eq!(fs.link(ROOT, "d2", dir), Err(Errno::EPERM));
+        assert_eq!(fs.unlink(dir, "h"), Ok(()));
+        assert_eq!(fs.write(file, 0, b"x"), Ok(1));
+        assert_eq!(fs.unlink(sub, "h2"), Ok(()));
+        assert_eq!(fs.write(file, 0, b"x"), Err(Errno::ENOENT));
+
+        assert_eq!(fs.rmdir(dir, "sub"), Ok(()));
+        assert_eq!(fs.rmdir(ROOT, "d"), Ok(()));
+        assert!(fs.readdir(ROOT, 0).unwrap().is_none());
+    }
+
+    #[test_case]
+    fn symlinks_keep_their_target() {
+        let fs = RamFs::new();
+        let link = fs.symlink(ROOT, "l", "../bin/sh", META).unwrap();
+        let file = fs.create(ROOT, "f", META).unwrap();
+        assert!(fs.is_symlink(link));
+        assert!(!fs.is_symlink(file));
+
+        let mut buf = [0; 16];
+        assert_eq!(fs.readlink(link, &mut buf), Ok(9));
+        assert_eq!(&buf[..9], b"../bin/sh");
+        assert_eq!(fs.readlink(link, &mut buf[..4]), Err(Errno::ENAMETOOLONG));
+        assert_eq!(fs.readlink(file, &mut buf), Err(Errno::EINVAL));
+    }
+}
@@ -0,0 +1,240 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{
+    cmdline, dtb, println, proc,
+    sync::Mutex,
+    syscall::{Errno, user_slice_mut},
+    timer,
+};
+
+// The entropy pool, and `getrandom()` to read from it. What can't be predicted from outside
+// is mixed into a 256-bit key: the seed the boot loader leaves in the device tree
+// (`/chosen/rng-seed`, which QEMU fills), and the time every interrupt arrives at. Each
+// source credits the bits of entropy it is thought to carry, and once `READY_BITS` are in
+// the pool is ready. Output is ChaCha20 (RFC 7539) keyed with the pool, which is rekeyed
+// from the output after every request, so what was handed out can't be worked back from
+// a later state of the pool.
+//
+// Mixing is a plain xor-and-rotate into the key words, good enough as ChaCha20 does the
+// hashing on the way out, but no match for a real kernel's.
+
+/// Bits of entropy credited before the pool is ready and `getrandom()` stops blocking.
+const READY_BITS: usize = 256;
+
+// Flags of `getrandom()`, as Linux numbers them. There is one pool, `GRND_RANDOM` makes no
+// difference.
+pub const GRND_NONBLOCK: usize = 1 << 0;
+pub const GRND_RANDOM: usize = 1 << 1;
+/// Doesn't wait for the pool to be ready.
+pub const GRND_INSECURE: usize = 1 << 2;
+
+struct Pool {
+    key: [u32; 8],
+    // Key word the next input goes into.
+    next: usize,
+    counter: u64,
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    key: [0; 8],
+    next: 0,
+    counter: 0,
+});
+static CREDITED: AtomicUsize = AtomicUsize::new(0);
+static READY: AtomicBool = AtomicBool::new(false);
+// Pids (as a bit mask) blocked in `getrandom()` until the pool is ready.
+static WAITING: AtomicUsize = AtomicUsize::new(0);
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+/// Returns ChaCha20 block `counter` of the stream of `key` and `nonce`, the counter taking
+/// the first nonce word of RFC 7539 as its high half.
+fn chacha20(key: &[u32; 8], counter: u64, nonce: u64) -> [u32; 16] {
+    let mut state = [0; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    state[4..12].copy_from_slice(key);
+    state[12..].copy_from_slice(&[
+        counter as u32,
+        (counter >> 32) as u32,
+        nonce as u32,
+        (nonce >> 32) as u32,
+    ]);
+
+    let mut x = state;
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 1, 5, 9, 13);
+        quarter_round(&mut x, 2, 6, 10, 14);
+        quarter_round(&mut x, 3, 7, 11, 15);
+        quarter_round(&mut x, 0, 5, 10, 15);
+        quarter_round(&mut x, 1, 6, 11, 12);
+        quarter_round(&mut x, 2, 7, 8, 13);
+        quarter_round(&mut x, 3, 4, 9, 14);
+    }
+    for (x, state) in x.iter_mut().zip(state) {
+        *x = x.wrapping_add(state);
+    }
+    x
+}
+
+impl Pool {
+    fn mix(&mut self, word: u32) {
+        let i = self.next;
+        self.key[i] = (self.key[i] ^ word).rotate_left(7);
+        self.next = (i + 1) % self.key.len();
+    }
+
+    /// Returns the next block of output.
+    fn block(&mut self) -> [u32; 16] {
+        self.counter += 1;
+        chacha20(&self.key, self.counter, 0)
+    }
+
+    /// Replaces the key with output, so earlier output can't be worked back from it.
+    fn rekey(&mut self) {
+        let block = self.block();
+        self.key.copy_from_slice(&block[..8]);
+    }
+}
+
+/// Credits `bits` of entropy, making the pool ready once there are enough.
+fn credit(bits: usize) {
+    let before = CREDITED.fetch_add(bits, Ordering::Relaxed);
+    if before < READY_BITS && before + bits >= READY_BITS {
+        READY.store(true, Ordering::Release);
+        proc::wake_deferred(WAITING.swap(0, Ordering::Relaxed));
+    }
+}
+
+/// Mixes `data` into the pool, crediting `bits` of entropy for it.
+pub fn add(data: &[u8], bits: usize) {
+    let mut pool = POOL.lock();
+    for chunk in data.chunks(4) {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        pool.mix(u32::from_le_bytes(word));
+    }
+    drop(pool);
+    credit(bits);
+}
+
+/// Mixes in the time an interrupt of `cause` arrived at `pc`, a bit of entropy for its low
+/// bits. Safe to call from interrupt handlers: dropped if the pool is in use.
+pub fn add_interrupt(cause: usize, pc: usize) {
+    let Some(mut pool) = POOL.try_lock() else {
+        return;
+    };
+    let now = timer::now();
+    pool.mix(now as u32 ^ (cause as u32).rotate_left(16));
+    pool.mix((now >> 32) as u32 ^ pc as u32);
+    drop(pool);
+    credit(1);
+}
+
+/// Returns whether enough entropy was mixed in for the output to be unpredictable.
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Fills `buf` with random bytes, whether the pool is ready or not.
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(64) {
+        let block = POOL.lock().block();
+        for (bytes, word) in chunk.chunks_mut(4).zip(block) {
+            bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+        }
+    }
+    POOL.lock().rekey();
+}
+
+/// Fills the `len` bytes of the calling process's memory at `addr` with random bytes,
+/// like Linux's `getrandom()`, returning `len`. Blocks until the pool is ready, unless
+/// `flags` has `GRND_INSECURE`.
+///
+/// Fails with `EAGAIN` if it isn't ready yet and `flags` has `GRND_NONBLOCK`, and with
+/// `EINVAL` if `flags` has other bits.
+pub fn sys_getrandom(addr: usize, len: usize, flags: usize) -> Result<usize, Errno> {
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0
+        || flags & GRND_RANDOM != 0 && flags & GRND_INSECURE != 0
+    {
+        return Err(Errno::EINVAL);
+    }
+    let pid = proc::current();
+    while flags & GRND_INSECURE == 0 && !is_ready() {
+        if flags & GRND_NONBLOCK != 0 {
+            return Err(Errno::EAGAIN);
+        }
+        WAITING.fetch_or(1 << pid, Ordering::Relaxed);
+        // A wakeup just before it blocks may be missed, it then checks again in a second.
+        if !is_ready() {
+            proc::sleep(Some(timer::ticks() + timer::hz()));
+        }
+    }
+    fill(user_slice_mut(addr, len)?);
+    Ok(len)
+}
+
+crate::register_subsystem!(Core, "random", init, after("dtb", "cmdline"));
+
+fn init() {
+    // Different on every boot with a seed, or a different command line or boot time.
+    match dtb::property("/chosen", "rng-seed") {
+        Some(seed) => add(seed, seed.len() * 8),
+        None => println!("random: no rng-seed, waiting for interrupts to fill the pool"),
+    }
+    add(cmdline::as_str().as_bytes(), 0);
+    add(&timer::now().to_le_bytes(), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn chacha20_matches_the_rfc() {
+        // RFC 7539, section 2.3.2.
+        let key =
+            core::array::from_fn(|i| u32::from_le_bytes([0, 1, 2, 3].map(|b| b + 4 * i as u8)));
+        let block = chacha20(&key, (0x0900_0000 << 32) | 1, 0x4a00_0000);
+        assert_eq!(
+            block,
+            [
+                0xe4e7_f110,
+                0x1559_3bd1,
+                0x1fdd_0f50,
+                0xc471_20a3,
+                0xc7f4_d1c7,
+                0x0368_c033,
+                0x9aaa_2204,
+                0x4e6c_d4c3,
+                0x4664_82d2,
+                0x09aa_9f07,
+                0x05d7_c214,
+                0xa202_8bd9,
+                0xd19c_12b5,
+                0xb94e_16de,
+                0xe883_d0cb,
+                0x4e3c_50a2,
+            ]
+        );
+    }
+
+    #[test_case]
+    fn output_differs_between_requests() {
+        let (mut a, mut b) = ([0; 100], [0; 100]);
+        fill(&mut a);
+        fill(&mut b);
+        assert_ne!(a, b);
+        assert_ne!(a[..64], a[36..]);
+        assert!(a.iter().any(|&byte| byte != 0));
+    }
+}
@@ -0,0 +1,246 @@
+use core::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::{MAX_HARTS, hart_id, proc, sync::Mutex, syscall::Errno};
+
+// Read-copy-update, for data that is read far more often than it changes. Readers take no
+// lock, they only mark the hart as reading (`read_lock()`), and writers never change what
+// a reader may be looking at: they publish a changed copy instead, and reuse or free the
+// old one only after a grace period, once every hart passed a quiescent state.
+//
+// A hart is quiescent when it switches processes, which it never does while reading: a
+// read-side section must not block or give up the hart. Kernel code isn't preempted, so
+// nothing else ends one early.
+//
+// `Rcu` keeps two copies of a value and switches between them on every update. The old
+// copy is only overwritten by the next update once `call_rcu()` said a grace period passed,
+// so an update only waits for one if it follows another closely. Writers that can't wait
+// leave the freeing to `call_rcu()` the same way, which runs it after the next grace period.
+//
+// FIXME: Only the boot hart is online, the others are parked.
+
+pub const MAX_CALLBACKS: usize = 16;
+
+// Incremented whenever a grace period starts, numbering it.
+static GP: AtomicUsize = AtomicUsize::new(0);
+// The last grace period each hart was quiescent in.
+static QUIESCENT: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+// Nesting depth of the read-side sections of each hart.
+static READERS: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+// Harts grace periods wait for, as a bit mask.
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+struct Callback {
+    f: fn(usize),
+    data: usize,
+    gp: usize,
+}
+
+static CALLBACKS: Mutex<[Option<Callback>; MAX_CALLBACKS]> = Mutex::new([None; MAX_CALLBACKS]);
+
+/// A read-side section of the calling hart, until dropped.
+pub struct ReadGuard {
+    // Stays on the hart it was taken on.
+    _hart: PhantomData<*const ()>,
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        READERS[hart_id()].fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Starts a read-side section, which must not block. Sections may nest.
+pub fn read_lock() -> ReadGuard {
+    READERS[hart_id()].fetch_add(1, Ordering::Acquire);
+    ReadGuard { _hart: PhantomData }
+}
+
+/// Notes a quiescent state of the calling hart, then runs the callbacks whose grace period
+/// is over. Called on every context switch.
+pub fn quiescent() {
+    let hart = hart_id();
+    crate::kassert!(
+        READERS[hart].load(Ordering::Relaxed) == 0,
+        "rcu: giving up the hart in a read-side section"
+    );
+    QUIESCENT[hart].store(GP.load(Ordering::Acquire), Ordering::Release);
+    run_callbacks();
+}
+
+/// Returns whether grace period `gp` is over.
+fn completed(gp: usize) -> bool {
+    let mut online = ONLINE.load(Ordering::Relaxed);
+    while online != 0 {
+        let hart = online.trailing_zeros() as usize;
+        if QUIESCENT[hart].load(Ordering::Acquire) < gp {
+            return false;
+        }
+        online &= online - 1;
+    }
+    true
+}
+
+/// Starts a grace period, returning its number.
+fn start_gp() -> usize {
+    GP.fetch_add(1, Ordering::AcqRel) + 1
+}
+
+/// Waits until every read-side section that started before is over, giving up the hart
+/// meanwhile. Not for interrupt context, or from within a read-side section.
+pub fn synchronize() {
+    let gp = start_gp();
+    quiescent();
+    while !completed(gp) {
+        proc::give_up();
+    }
+}
+
+/// Calls `f(data)` once every read-side section that started before is over, to free
+/// what they may still be looking at.
+///
+/// Fails with `ENOSPC` if too many callbacks are waiting already.
+pub fn call_rcu(f: fn(usize), data: usize) -> Result<(), Errno> {
+    let gp = start_gp();
+    let mut callbacks = CALLBACKS.lock();
+    let slot = callbacks
+        .iter_mut()
+        .find(|c| c.is_none())
+        .ok_or(Errno::ENOSPC)?;
+    *slot = Some(Callback { f, data, gp });
+    Ok(())
+}
+
+fn run_callbacks() {
+    let mut due = [None; MAX_CALLBACKS];
+    // Not from interrupt context, a hart taking the lock is never interrupted by itself.
+    let Some(mut callbacks) = CALLBACKS.try_lock() else {
+        return;
+    };
+    for (callback, due) in callbacks.iter_mut().zip(&mut due) {
+        if callback.is_some_and(|c| completed(c.gp)) {
+            *due = callback.take();
+        }
+    }
+    drop(callbacks);
+
+    for callback in due.into_iter().flatten() {
+        (callback.f)(callback.data);
+    }
+}
+
+/// A value readers see without locking, updated by read-copy-update.
+pub struct Rcu<T> {
+    copies: [UnsafeCell<T>; 2],
+    // Index of the copy readers see.
+    current: AtomicUsize,
+    // Set by an update until a grace period later, while readers may see the other copy.
+    stale: AtomicBool,
+    // Held by writers while they update.
+    writer: Mutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+impl<T: Copy> Rcu<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            copies: [UnsafeCell::new(value), UnsafeCell::new(value)],
+            current: AtomicUsize::new(0),
+            stale: AtomicBool::new(false),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Returns the value as of now, valid for as long as the read-side section `_guard`.
+    pub fn get<'a>(&'a self, _guard: &'a ReadGuard) -> &'a T {
+        let current = self.current.load(Ordering::Acquire);
+        // Writers only change the other copy, until a grace period after it was replaced.
+        unsafe { &*self.copies[current].get() }
+    }
+
+    /// Calls `f` with the value in a read-side section of its own.
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let guard = read_lock();
+        f(self.get(&guard))
+    }
+
+    /// Has `f` change a copy of the value, then has readers see the changed copy, and
+    /// returns what `f` returns. Readers that started before may see the old copy until
+    /// the next grace period.
+    ///
+    /// A copy of the value is made whether or not `f` changes it. Not for interrupt
+    /// context, or from within a read-side section.
+    pub fn update<R>(&'static self, f: impl FnOnce(&mut T) -> R) -> R {
+        let _writer = self.writer.lock();
+        // The copy the last update replaced is overwritten, once no reader sees it.
+        while self.stale.load(Ordering::Acquire) {
+            synchronize();
+        }
+        let current = self.current.load(Ordering::Relaxed);
+        let next = 1 - current;
+        let copy = unsafe { &mut *self.copies[next].get() };
+        *copy = unsafe { *self.copies[current].get() };
+        let result = f(copy);
+        self.current.store(next, Ordering::Release);
+
+        self.stale.store(true, Ordering::Relaxed);
+        let stale = &self.stale as *const AtomicBool as usize;
+        let reusable = |stale: usize| {
+            unsafe { &*(stale as *const AtomicBool) }.store(false, Ordering::Release)
+        };
+        if call_rcu(reusable, stale).is_err() {
+            synchronize();
+            reusable(stale);
+        }
+        result
+    }
+}
+
+crate::register_subsystem!(Core, "rcu", init);
+
+fn init() {
+    ONLINE.fetch_or(1 << hart_id(), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static FREED: AtomicUsize = AtomicUsize::new(0);
+
+    #[test_case]
+    fn readers_keep_their_copy_until_done() {
+        static VALUE: Rcu<[usize; 2]> = Rcu::new([1, 2]);
+
+        let guard = read_lock();
+        let old = VALUE.get(&guard);
+        // Waiting for the reader would never end, so publish by hand like `update()`.
+        let next = 1 - VALUE.current.load(Ordering::Relaxed);
+        unsafe { *VALUE.copies[next].get() = [3, 4] };
+        VALUE.current.store(next, Ordering::Release);
+        assert_eq!(*old, [1, 2]);
+        assert_eq!(*VALUE.get(&guard), [3, 4]);
+
+        let gp = start_gp();
+        assert!(!completed(gp));
+        drop(guard);
+        quiescent();
+        assert!(completed(gp));
+
+        VALUE.update(|v| v[0] += 2);
+        assert_eq!(VALUE.read(|v| *v), [5, 4]);
+
+        assert_eq!(
+            call_rcu(|n| _ = FREED.fetch_add(n, Ordering::Relaxed), 1),
+            Ok(())
+        );
+        assert_eq!(FREED.load(Ordering::Relaxed), 0);
+        quiescent();
+        assert_eq!(FREED.load(Ordering::Relaxed), 1);
+    }
+}
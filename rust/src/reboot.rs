@@ -0,0 +1,154 @@
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    MAX_HARTS, block, cpu, cred, hart_id, kassert, perf, println, proc,
+    proc::PROC_MAX,
+    sbi::{self, ResetReason, ResetType},
+    signal::{self, SIGKILL, SIGTERM},
+    swap,
+    syscall::Errno,
+    timer, vfs,
+};
+
+// Shutting down and rebooting, with `reboot()`: the machine goes away only once what is
+// running had a chance to finish and what is in memory was written back.
+//
+// In order: user processes but the caller are sent `SIGTERM`, and `SIGKILL` if they are
+// still around after a grace period; swap is turned off; the page cache and the
+// filesystems are written back and the block devices flushed; the other harts stop
+// themselves through SBI's Hart State Management extension; and the boot hart powers off
+// or resets the machine through the System Reset extension. Failing to write back doesn't
+// stop the shutdown, there is nothing better to do then, but it is reported.
+
+// Commands of `reboot()`, with the values Linux uses.
+pub const REBOOT_CMD_RESTART: usize = 0x0123_4567;
+pub const REBOOT_CMD_POWER_OFF: usize = 0x4321_fedc;
+
+// Time processes get to exit after `SIGTERM`, and after `SIGKILL`.
+const TERM_GRACE_MS: usize = 2000;
+const KILL_GRACE_MS: usize = 500;
+// Time the other harts get to stop.
+const HART_STOP_MS: usize = 100;
+
+// Set once a shutdown started, there is only one.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+// Set when the parked harts should stop.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Powers off the machine for `REBOOT_CMD_POWER_OFF`, or resets it for
+/// `REBOOT_CMD_RESTART`, after shutting everything down. Only returns on failure.
+///
+/// Fails with `EPERM` unless the calling process is root, with `EINVAL` for another
+/// command, with `EBUSY` if a shutdown is under way, and with `ENOSYS` if the SBI
+/// implementation can't reset the machine.
+pub fn reboot(cmd: usize) -> Result<usize, Errno> {
+    cred::require_root()?;
+    let how = match cmd {
+        REBOOT_CMD_POWER_OFF => ResetType::Shutdown,
+        REBOOT_CMD_RESTART => ResetType::ColdReboot,
+        _ => return Err(Errno::EINVAL),
+    };
+    if SHUTTING_DOWN.swap(true, Ordering::Relaxed) {
+        return Err(Errno::EBUSY);
+    }
+
+    println!("reboot: sending SIGTERM to all processes");
+    if !signal_all(SIGTERM, TERM_GRACE_MS) {
+        println!("reboot: sending SIGKILL to all processes");
+        signal_all(SIGKILL, KILL_GRACE_MS);
+    }
+
+    // Like `swapoff -a`, once nothing is left to swap in but the caller's pages.
+    if swap::disable().is_err() {
+        println!("reboot: swap still in use");
+    }
+
+    println!("reboot: syncing filesystems");
+    let span = perf::Span::named("sync");
+    if let Err(errno) = vfs::sync().and_then(|_| block::flush_all()) {
+        println!("reboot: can't write everything back: {errno:?}");
+    }
+    drop(span);
+    // Those only counted are reported nowhere else.
+    let failures = kassert::failures();
+    if failures > 0 {
+        println!("reboot: {failures} kernel assertions failed since boot");
+    }
+
+    stop_harts();
+
+    let err = sbi::system_reset(how, ResetReason::NoReason);
+    println!("reboot: SRST failed with error {err}");
+    SHUTTING_DOWN.store(false, Ordering::Relaxed);
+    Err(Errno::ENOSYS)
+}
+
+/// Returns the user processes other than the caller, as a bit mask.
+fn others() -> usize {
+    (1..PROC_MAX)
+        .filter(|&pid| pid != proc::current() && proc::is_user(pid))
+        .fold(0, |pids, pid| pids | 1 << pid)
+}
+
+/// Sends `sig` to the user processes other than the caller, then waits up to `grace_ms`
+/// for them to exit, returning whether they did.
+fn signal_all(sig: usize, grace_ms: usize) -> bool {
+    let mut pids = others();
+    while pids != 0 {
+        let _ = signal::kill(pids.trailing_zeros() as usize, sig);
+        pids &= pids - 1;
+    }
+    let deadline = timer::ticks() + timer::ms_to_ticks(grace_ms);
+    while others() != 0 {
+        if timer::ticks() >= deadline {
+            return false;
+        }
+        proc::sleep(Some(timer::ticks() + 1));
+    }
+    true
+}
+
+/// Has the other harts the kernel knows stop, waiting a little for them to.
+fn stop_harts() {
+    if !sbi::probe_extension(sbi::EID_HSM) {
+        return;
+    }
+    STOPPING.store(true, Ordering::Release);
+    let others = (0..MAX_HARTS).filter(|&hart| hart != hart_id() && cpu::features(hart).is_some());
+    // Spinning, ticks may not be counted meanwhile.
+    let deadline = timer::now() + timer::TIMEBASE_FREQ * HART_STOP_MS as u64 / 1000;
+    for hart in others {
+        while sbi::hart_get_status(hart) != Ok(sbi::HART_STOPPED) && timer::now() < deadline {
+            spin_loop();
+        }
+    }
+}
+
+/// Keeps a hart the kernel doesn't schedule on spinning until the machine shuts down,
+/// then stops it.
+pub fn park() -> ! {
+    while !STOPPING.load(Ordering::Acquire) {
+        spin_loop();
+    }
+    let err = sbi::hart_stop();
+    println!("reboot: hart {} can't stop: {err}", hart_id());
+    loop {
+        spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn only_known_commands_reboot() {
+        assert_eq!(reboot(0), Err(Errno::EINVAL));
+        assert!(!SHUTTING_DOWN.load(Ordering::Relaxed));
+        // Kernel processes are left alone.
+        assert_eq!(others(), 0);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::{
+    cred,
+    file::MAX_FDS,
+    proc::{self, PROC_MAX},
+    sync::Mutex,
+    syscall::{self, Errno},
+};
+
+// Resource limits, checked where the resources are handed out so that a runaway process
+// fails its own requests instead of exhausting the kernel.
+
+// Resources, with the values Linux uses.
+/// User pages the process may have mapped.
+pub const RLIMIT_RSS: usize = 5;
+/// Processes of its user that may be alive when the process spawns another.
+pub const RLIMIT_NPROC: usize = 6;
+/// One more than the highest fd the process may open.
+pub const RLIMIT_NOFILE: usize = 7;
+
+pub const RLIM_INFINITY: usize = usize::MAX;
+
+const RESOURCES: [usize; 3] = [RLIMIT_RSS, RLIMIT_NPROC, RLIMIT_NOFILE];
+
+/// A soft limit, enforced, and the hard limit it may be raised up to, laid out like
+/// Linux's `struct rlimit`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Rlimit {
+    pub cur: usize,
+    pub max: usize,
+}
+
+const DEFAULT_LIMITS: [Rlimit; RESOURCES.len()] = [
+    Rlimit {
+        cur: RLIM_INFINITY,
+        max: RLIM_INFINITY,
+    },
+    Rlimit {
+        cur: PROC_MAX,
+        max: PROC_MAX,
+    },
+    // Plenty for most programs, and those that need more may raise it.
+    Rlimit {
+        cur: 64,
+        max: MAX_FDS,
+    },
+];
+
+// Limits of each process, indexed by pid and then by position in `RESOURCES`.
+static LIMITS: Mutex<[[Rlimit; RESOURCES.len()]; PROC_MAX]> =
+    Mutex::new([DEFAULT_LIMITS; PROC_MAX]);
+
+fn index(resource: usize) -> Result<usize, Errno> {
+    RESOURCES
+        .iter()
+        .position(|&r| r == resource)
+        .ok_or(Errno::EINVAL)
+}
+
+/// Returns the limits of `pid` for `resource`.
+pub fn get(pid: usize, resource: usize) -> Result<Rlimit, Errno> {
+    Ok(LIMITS.lock()[pid][index(resource)?])
+}
+
+/// Returns the enforced limit of `pid` for `resource`, which must be one of `RLIMIT_*`.
+pub fn current(pid: usize, resource: usize) -> usize {
+    get(pid, resource).unwrap().cur
+}
+
+/// Sets the limits of `pid` for `resource`.
+///
+/// Fails with `EINVAL` if the soft limit exceeds the hard one, and with `EPERM` if the hard
+/// limit would go up and `pid` isn't root.
+pub fn set(pid: usize, resource: usize, new: Rlimit) -> Result<(), Errno> {
+    if new.cur > new.max {
+        return Err(Errno::EINVAL);
+    }
+
+    let root = cred::is_root(pid);
+    let mut limits = LIMITS.lock();
+    let limit = &mut limits[pid][index(resource)?];
+    if new.max > limit.max && !root {
+        return Err(Errno::EPERM);
+    }
+    *limit = new;
+    Ok(())
+}
+
+/// Gives `child` the limits of `parent`, when the former spawns the latter.
+pub fn inherit(parent: usize, child: usize) {
+    let mut limits = LIMITS.lock();
+    limits[child] = limits[parent];
+}
+
+/// Restores the default limits of `pid`, called when it exits.
+pub fn reset(pid: usize) {
+    LIMITS.lock()[pid] = DEFAULT_LIMITS;
+}
+
+/// Checks that `pid` may map another user page into an address space that has `resident`
+/// mapped already (see `vm::MemUsage`), called before allocating it.
+///
+/// Fails with `ENOMEM` if that would go over the process's `RLIMIT_RSS`.
+pub fn check_rss(pid: usize, resident: usize) -> Result<(), Errno> {
+    match resident < current(pid, RLIMIT_RSS) {
+        true => Ok(()),
+        false => Err(Errno::ENOMEM),
+    }
+}
+
+/// Stores the calling process's limits for `resource` in the `Rlimit` at `addr`.
+pub fn sys_getrlimit(resource: usize, addr: usize) -> Result<usize, Errno> {
+    let limit = get(proc::current(), resource)?;
+    let out = syscall::user_slice_mut(addr, size_of::<Rlimit>())?;
+    out[..size_of::<usize>()].copy_from_slice(&limit.cur.to_ne_bytes());
+    out[size_of::<usize>()..].copy_from_slice(&limit.max.to_ne_bytes());
+    Ok(0)
+}
+
+/// Sets the calling process's limits for `resource` to the `Rlimit` at `addr`.
+pub fn sys_setrlimit(resource: usize, addr: usize) -> Result<usize, Errno> {
+    let bytes = syscall::user_slice(addr, size_of::<Rlimit>())?;
+    let (cur, max) = bytes.split_at(size_of::<usize>());
+    let limit = Rlimit {
+        cur: usize::from_ne_bytes(cur.try_into().unwrap()),
+        max: usize::from_ne_bytes(max.try_into().unwrap()),
+    };
+    set(proc::current(), resource, limit).map(|_| 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipe;
+
+    #[test_case]
+    fn only_root_raises_limits() {
+        let pid = proc::current();
+        let old = get(pid, RLIMIT_NOFILE).unwrap();
+        let lowered = Rlimit { cur: 1, max: 4 };
+
+        assert_eq!(set(pid, RLIMIT_NOFILE, lowered), Ok(()));
+        // A pipe needs two fds.
+        assert!(matches!(pipe::open(), Err(Errno::EMFILE)));
+        // Only root may raise it again.
+        cred::setuid(pid, 1).unwrap();
+        assert_eq!(
+            set(pid, RLIMIT_NOFILE, Rlimit { cur: 5, max: 5 }),
+            Err(Errno::EPERM)
+        );
+        cred::reset(pid);
+        assert_eq!(set(pid, RLIMIT_NOFILE, Rlimit { cur: 5, max: 5 }), Ok(()));
+        assert_eq!(
+            set(pid, RLIMIT_NOFILE, Rlimit { cur: 4, max: 3 }),
+            Err(Errno::EINVAL)
+        );
+        assert_eq!(get(pid, 0), Err(Errno::EINVAL));
+
+        // All tests run as the same process, put the limit back for the ones that follow.
+        LIMITS.lock()[pid][index(RLIMIT_NOFILE).unwrap()] = old;
+    }
+
+    #[test_case]
+    fn checks_pages_against_the_limit() {
+        // A pid no process uses while tests run.
+        let pid = PROC_MAX - 1;
+        set(pid, RLIMIT_RSS, Rlimit { cur: 3, max: 3 }).unwrap();
+
+        assert_eq!(check_rss(pid, 2), Ok(()));
+        assert_eq!(check_rss(pid, 3), Err(Errno::ENOMEM));
+
+        reset(pid);
+        assert_eq!(check_rss(pid, 3), Ok(()));
+    }
+}
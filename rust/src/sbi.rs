@@ -2,6 +2,29 @@ use core::arch::asm;
 
 // pub enum SBIErr {}
 
+const EID_BASE: isize = 0x10;
+pub const EID_TIME: isize = 0x54494D45;
+pub const EID_HSM: isize = 0x48534D;
+pub const EID_SRST: isize = 0x53525354;
+
+/// State of a hart in the Hart State Management extension, see `hart_get_status()`.
+pub const HART_STOPPED: isize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(isize)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(isize)]
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub unsafe fn sbi_call(
     arg0: isize,
     arg1: isize,
@@ -34,8 +57,92 @@ pub unsafe fn sbi_call(
     if err == 0 { Ok(val) } else { Err(err) }
 }
 
+/// Returns whether the SBI implementation provides extension `eid`.
+pub fn probe_extension(eid: isize) -> bool {
+    matches!(unsafe { sbi_call(eid, 0, 0, 0, 0, 0, 3, EID_BASE) }, Ok(available) if available != 0)
+}
+
+/// Returns the `mvendorid`, `marchid`, and `mimpid` CSRs of the calling hart, which only
+/// M-mode can read.
+pub fn machine_ids() -> (usize, usize, usize) {
+    let id = |fid| unsafe { sbi_call(0, 0, 0, 0, 0, 0, fid, EID_BASE) }.unwrap_or(0) as usize;
+    (id(4), id(5), id(6))
+}
+
 pub fn putchar(ch: char) {
     unsafe {
         _ = sbi_call(ch as isize, 0, 0, 0, 0, 0, 0, 1);
     }
 }
+
+/// Reads a byte from the debug console, or returns `None` if no input is pending.
+pub fn getchar() -> Option<u8> {
+    // The legacy extension returns the character (or -1) in a0, which `sbi_call` reports as the error.
+    match unsafe { sbi_call(0, 0, 0, 0, 0, 0, 0, 2) } {
+        Ok(_) => Some(0),
+        Err(-1) => None,
+        Err(ch) => Some(ch as u8),
+    }
+}
+
+/// Programs the clock for the next timer event at `stime_value` (absolute `time` value).
+pub fn set_timer(stime_value: u64) {
+    unsafe {
+        // On rv32 the 64-bit value is split across a0 (low) and a1 (high).
+        #[cfg(target_arch = "riscv32")]
+        let high = (stime_value >> 32) as isize;
+        #[cfg(target_arch = "riscv64")]
+        let high = 0;
+        _ = sbi_call(stime_value as isize, high, 0, 0, 0, 0, 0, EID_TIME);
+    }
+}
+
+/// Starts the stopped `hart` at `start_addr` in supervisor mode, with its id in `a0` and
+/// `opaque` in `a1`. Returns the SBI error code, 0 on success.
+pub fn hart_start(hart: usize, start_addr: usize, opaque: usize) -> isize {
+    let ret = unsafe {
+        sbi_call(
+            hart as isize,
+            start_addr as isize,
+            opaque as isize,
+            0,
+            0,
+            0,
+            0,
+            EID_HSM,
+        )
+    };
+    ret.err().unwrap_or(0)
+}
+
+/// Stops the calling hart, giving it back to the SBI implementation.
+///
+/// Only returns if the hart couldn't be stopped, with the SBI error code.
+pub fn hart_stop() -> isize {
+    let ret = unsafe { sbi_call(0, 0, 0, 0, 0, 0, 1, EID_HSM) };
+    ret.err().unwrap_or(0)
+}
+
+/// Returns the HSM state of `hart`, e.g. `HART_STOPPED`.
+pub fn hart_get_status(hart: usize) -> Result<isize, isize> {
+    unsafe { sbi_call(hart as isize, 0, 0, 0, 0, 0, 2, EID_HSM) }
+}
+
+/// Resets or powers off the system using the SBI System Reset extension.
+///
+/// Only returns if the reset request failed, with the SBI error code.
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> isize {
+    let ret = unsafe {
+        sbi_call(
+            reset_type as isize,
+            reason as isize,
+            0,
+            0,
+            0,
+            0,
+            0,
+            EID_SRST,
+        )
+    };
+    ret.err().unwrap_or(0)
+}
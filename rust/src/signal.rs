@@ -0,0 +1,501 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use crate::{
+    cred, println,
+    proc::{self, PROC_MAX},
+    sync::Mutex,
+    syscall::Errno,
+    trap::TrapFrame,
+    uaccess::{self, Access, UserAccess},
+};
+
+pub const NSIG: usize = 32;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGBUS: usize = 7;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+
+// Handlers with a special meaning.
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+// `how` of `sigprocmask()`.
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+// Flags of `sigaction()`, with the values Linux uses.
+pub const SA_RESTART: usize = 0x1000_0000;
+
+#[derive(Clone, Copy)]
+struct Action {
+    handler: usize,
+    // User code the handler returns to, which must call `SYS_SIGRETURN`.
+    trampoline: usize,
+    flags: usize,
+}
+
+const DEFAULT_ACTION: Action = Action {
+    handler: SIG_DFL,
+    trampoline: 0,
+    flags: 0,
+};
+
+// Per-process signal state, indexed by pid. Bit `n` of a mask stands for signal `n`.
+static PENDING: [AtomicU32; PROC_MAX] = [const { AtomicU32::new(0) }; PROC_MAX];
+static BLOCKED: [AtomicU32; PROC_MAX] = [const { AtomicU32::new(0) }; PROC_MAX];
+static ACTIONS: Mutex<[[Action; NSIG]; PROC_MAX]> = Mutex::new([[DEFAULT_ACTION; NSIG]; PROC_MAX]);
+// Whether a signal interrupted the syscall each process returns from, see `deliver()`.
+static RESTARTING: [AtomicBool; PROC_MAX] = [const { AtomicBool::new(false) }; PROC_MAX];
+
+/// Saved on the user stack while a handler runs, restored by `sigreturn()`.
+#[repr(C)]
+struct SignalFrame {
+    tf: TrapFrame,
+    pc: usize,
+    blocked: u32,
+}
+
+fn bit(sig: usize) -> u32 {
+    1 << sig
+}
+
+fn is_valid(sig: usize) -> bool {
+    sig > 0 && sig < NSIG
+}
+
+/// Returns the name of `sig`, like `SIGTERM`, unless it has none.
+fn name(sig: usize) -> Option<&'static str> {
+    Some(match sig {
+        SIGHUP => "SIGHUP",
+        SIGINT => "SIGINT",
+        SIGQUIT => "SIGQUIT",
+        SIGILL => "SIGILL",
+        SIGTRAP => "SIGTRAP",
+        SIGBUS => "SIGBUS",
+        SIGKILL => "SIGKILL",
+        SIGUSR1 => "SIGUSR1",
+        SIGSEGV => "SIGSEGV",
+        SIGUSR2 => "SIGUSR2",
+        SIGPIPE => "SIGPIPE",
+        SIGALRM => "SIGALRM",
+        SIGTERM => "SIGTERM",
+        SIGCHLD => "SIGCHLD",
+        SIGCONT => "SIGCONT",
+        SIGSTOP => "SIGSTOP",
+        SIGTSTP => "SIGTSTP",
+        _ => return None,
+    })
+}
+
+/// Returns `true` if the default disposition of `sig` is to ignore it.
+fn ignored_by_default(sig: usize) -> bool {
+    sig == SIGCHLD || sig == SIGCONT
+}
+
+/// Returns `true` if the default disposition of `sig` is to stop the process. Signals that
+/// neither stop nor are ignored by default terminate.
+fn stops_by_default(sig: usize) -> bool {
+    sig == SIGSTOP || sig == SIGTSTP
+}
+
+/// Returns `true` if `sig` can't be caught, ignored or blocked.
+fn is_unstoppable(sig: usize) -> bool {
+    sig == SIGKILL || sig == SIGSTOP
+}
+
+/// Returns `true` if `sig` does nothing when delivered with `action`.
+fn is_ignored(sig: usize, action: Action) -> bool {
+    action.handler == SIG_IGN || (action.handler == SIG_DFL && ignored_by_default(sig))
+}
+
+/// Returns the pending and the blocked signals of `pid`, as masks.
+pub fn masks(pid: usize) -> (u32, u32) {
+    (
+        PENDING[pid].load(Ordering::Relaxed),
+        BLOCKED[pid].load(Ordering::Relaxed),
+    )
+}
+
+/// Sends `sig` to `pid`, waking it up if it is blocked.
+pub fn kill(pid: usize, sig: usize) -> Result<(), Errno> {
+    if !is_valid(sig) {
+        return Err(Errno::EINVAL);
+    }
+    // The idle process can't be signalled.
+    if pid == 0 {
+        return Err(Errno::EPERM);
+    }
+    if !proc::exists(pid) {
+        return Err(Errno::ESRCH);
+    }
+
+    match sig {
+        // Continuing undoes stops that are still pending, and the other way around.
+        SIGCONT => {
+            PENDING[pid].fetch_and(!(bit(SIGSTOP) | bit(SIGTSTP)), Ordering::Relaxed);
+            proc::resume(pid);
+        }
+        SIGSTOP | SIGTSTP => {
+            PENDING[pid].fetch_and(!bit(SIGCONT), Ordering::Relaxed);
+        }
+        SIGKILL => proc::resume(pid),
+        _ => {}
+    }
+    PENDING[pid].fetch_or(bit(sig), Ordering::Relaxed);
+    proc::interrupt(pid);
+
+    Ok(())
+}
+
+/// Sends `sig` to every process in group `pgid`.
+///
+/// Fails with `ESRCH` if there is none.
+pub fn kill_group(pgid: usize, sig: usize) -> Result<(), Errno> {
+    let mut members = proc::group_members(pgid);
+    if members == 0 {
+        return Err(Errno::ESRCH);
+    }
+    // Only those the caller may signal, failing if that is none of them.
+    let sender = proc::current();
+    for pid in 0..PROC_MAX {
+        if members & (1 << pid) != 0 && cred::may_signal(sender, pid).is_err() {
+            members &= !(1 << pid);
+        }
+    }
+    if members == 0 {
+        return Err(Errno::EPERM);
+    }
+    while members != 0 {
+        kill(members.trailing_zeros() as usize, sig)?;
+        members &= members - 1;
+    }
+    Ok(())
+}
+
+/// Sends `sig` to `pid` like `kill()`, but from an interrupt handler: the process is woken
+/// up on the next schedule.
+pub fn kill_deferred(pid: usize, sig: usize) {
+    PENDING[pid].fetch_or(bit(sig), Ordering::Relaxed);
+    proc::wake_deferred(1 << pid);
+}
+
+/// Sends `sig` to every process in group `pgid` like `kill_deferred()`.
+pub fn kill_group_deferred(pgid: usize, sig: usize) {
+    let mut members = proc::group_members(pgid);
+    while members != 0 {
+        kill_deferred(members.trailing_zeros() as usize, sig);
+        members &= members - 1;
+    }
+}
+
+/// Sets the handler of `sig` for the calling process, returning the previous one.
+///
+/// `handler` is `SIG_DFL`, `SIG_IGN`, or the address of a user function taking the signal
+/// number, which returns to `trampoline`. `SIGKILL` and `SIGSTOP` can't be caught or
+/// ignored. With `SA_RESTART` in `flags`, blocking syscalls the handler interrupts run
+/// again once it returns instead of failing with `EINTR`.
+pub fn sigaction(
+    sig: usize,
+    handler: usize,
+    trampoline: usize,
+    flags: usize,
+) -> Result<usize, Errno> {
+    if !is_valid(sig) || is_unstoppable(sig) {
+        return Err(Errno::EINVAL);
+    }
+    if flags & !SA_RESTART != 0 {
+        return Err(Errno::EINVAL);
+    }
+    if handler > SIG_IGN && trampoline == 0 {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut actions = ACTIONS.lock();
+    let action = &mut actions[proc::current()][sig];
+    let old = action.handler;
+    *action = Action {
+        handler,
+        trampoline,
+        flags,
+    };
+
+    Ok(old)
+}
+
+/// Changes the blocked signals of the calling process as `how` says, returning the previous mask.
+///
+/// `SIGKILL` and `SIGSTOP` can't be blocked.
+pub fn sigprocmask(how: usize, set: u32) -> Result<u32, Errno> {
+    let blocked = &BLOCKED[proc::current()];
+    let set = set & !(bit(SIGKILL) | bit(SIGSTOP));
+
+    let old = match how {
+        SIG_BLOCK => blocked.fetch_or(set, Ordering::Relaxed),
+        SIG_UNBLOCK => blocked.fetch_and(!set, Ordering::Relaxed),
+        SIG_SETMASK => blocked.swap(set, Ordering::Relaxed),
+        _ => return Err(Errno::EINVAL),
+    };
+
+    Ok(old)
+}
+
+/// Gives `child` the handlers and blocked signals of `parent`, when the former forks the latter.
+pub fn inherit(parent: usize, child: usize) {
+    BLOCKED[child].store(BLOCKED[parent].load(Ordering::Relaxed), Ordering::Relaxed);
+    let mut actions = ACTIONS.lock();
+    actions[child] = actions[parent];
+}
+
+/// Restores the default action of caught signals of `pid`, whose handlers are gone once
+/// it execs another program. Ignored signals stay ignored.
+pub fn reset_on_exec(pid: usize) {
+    for action in ACTIONS.lock()[pid].iter_mut() {
+        if action.handler > SIG_IGN {
+            *action = DEFAULT_ACTION;
+        }
+    }
+}
+
+/// Clears the signal state of `pid`, called when it exits.
+pub fn reset(pid: usize) {
+    PENDING[pid].store(0, Ordering::Relaxed);
+    BLOCKED[pid].store(0, Ordering::Relaxed);
+    RESTARTING[pid].store(false, Ordering::Relaxed);
+    ACTIONS.lock()[pid] = [DEFAULT_ACTION; NSIG];
+}
+
+/// Sends `sig` to the calling process for a fault it caused, which it can't ignore or block.
+///
+/// A caught signal is delivered on the next return to user space, otherwise the process is
+/// terminated right away.
+pub fn force(sig: usize) {
+    let pid = proc::current();
+
+    let mut actions = ACTIONS.lock();
+    if actions[pid][sig].handler == SIG_IGN {
+        actions[pid][sig] = DEFAULT_ACTION;
+    }
+    drop(actions);
+
+    BLOCKED[pid].fetch_and(!bit(sig), Ordering::Relaxed);
+    PENDING[pid].fetch_or(bit(sig), Ordering::Relaxed);
+
+    handle_fatal();
+}
+
+/// Sends `sig` to the calling process for what its syscall ran into. Unlike `force()`, it may
+/// be blocked, ignored or caught, and takes effect on the way back to user space.
+pub fn send(sig: usize) {
+    PENDING[proc::current()].fetch_or(bit(sig), Ordering::Relaxed);
+}
+
+/// Returns whether the calling process has a handler for `sig`, so that a fault raising it
+/// doesn't kill the process.
+pub fn is_caught(sig: usize) -> bool {
+    ACTIONS.lock()[proc::current()][sig].handler > SIG_IGN
+}
+
+/// Returns whether `pid` has a signal pending that isn't blocked or ignored, which
+/// interrupts what it is blocked on.
+pub fn pending(pid: usize) -> bool {
+    let deliverable = PENDING[pid].load(Ordering::Relaxed) & !BLOCKED[pid].load(Ordering::Relaxed);
+    if deliverable == 0 {
+        return false;
+    }
+    let actions = ACTIONS.lock();
+    (1..NSIG).any(|sig| deliverable & bit(sig) != 0 && !is_ignored(sig, actions[pid][sig]))
+}
+
+/// Fails with `ERESTARTSYS` if the calling process has a signal to handle, checked by
+/// blocking syscalls whenever they wake up.
+///
+/// Signals that stop or terminate the process took effect when it was woken, what is left
+/// is a handler to run before returning to user space.
+pub fn interrupted() -> Result<(), Errno> {
+    match pending(proc::current()) {
+        true => Err(Errno::ERESTARTSYS),
+        false => Ok(()),
+    }
+}
+
+/// Notes that the syscall the calling process returns from failed with `ERESTARTSYS`, to
+/// be run again or fail with `EINTR` by `deliver()`.
+pub fn interrupted_syscall() {
+    RESTARTING[proc::current()].store(true, Ordering::Relaxed);
+}
+
+/// Removes the lowest pending signal of `pid` that isn't blocked, unless `filter` rejects it.
+fn take_pending(pid: usize, filter: impl Fn(usize, Action) -> bool) -> Option<(usize, Action)> {
+    let deliverable = PENDING[pid].load(Ordering::Relaxed) & !BLOCKED[pid].load(Ordering::Relaxed);
+    let actions = ACTIONS.lock();
+
+    (1..NSIG)
+        .filter(|&sig| deliverable & bit(sig) != 0)
+        .map(|sig| (sig, actions[pid][sig]))
+        .find(|&(sig, action)| filter(sig, action))
+        .inspect(|&(sig, _)| {
+            PENDING[pid].fetch_and(!bit(sig), Ordering::Relaxed);
+        })
+}
+
+fn terminate(pid: usize, sig: usize) -> ! {
+    match name(sig) {
+        Some(name) => println!("pid {pid} terminated by {name}"),
+        None => println!("pid {pid} terminated by signal {sig}"),
+    }
+    proc::exit(proc::signal_status(sig))
+}
+
+/// Applies default dispositions of pending signals, called whenever a process resumes.
+///
+/// Signals with a user handler stay pending until the next return to user space.
+pub fn handle_fatal() {
+    let pid = proc::current();
+
+    while let Some((sig, action)) = take_pending(pid, |_, action| action.handler <= SIG_IGN) {
+        if action.handler != SIG_DFL || ignored_by_default(sig) {
+            continue;
+        }
+        if stops_by_default(sig) {
+            proc::stop(sig);
+        } else {
+            terminate(pid, sig);
+        }
+    }
+}
+
+/// Delivers a pending signal on return to user space at `pc` with the registers in `tf`.
+///
+/// Returns the pc to return at, which is the handler's if a handler is to run. Its frame
+/// is saved on the user stack and the handler returns to its trampoline.
+///
+/// A syscall interrupted by the signal returns to its `ecall`, see `syscall::dispatch()`:
+/// it runs again after the handler if the handler has `SA_RESTART`, or right away if no
+/// handler runs, and otherwise the handler returns past it with `EINTR`.
+pub fn deliver(tf: &mut TrapFrame, pc: usize) -> usize {
+    let pid = proc::current();
+    let restarting = RESTARTING[pid].swap(false, Ordering::Relaxed);
+
+    handle_fatal();
+
+    let Some((sig, action)) = take_pending(pid, |_, _| true) else {
+        return pc;
+    };
+
+    let blocked = BLOCKED[pid].fetch_or(bit(sig), Ordering::Relaxed);
+    let mut frame = SignalFrame {
+        tf: *tf,
+        pc,
+        blocked,
+    };
+    if restarting && action.flags & SA_RESTART == 0 {
+        frame.tf.set_syscall_ret(-(Errno::EINTR as isize));
+        frame.pc = pc + 4;
+    }
+
+    let sp = tf.reg(2).wrapping_sub(size_of::<SignalFrame>()) & !0xf;
+    let _user = UserAccess::new();
+    if uaccess::check(sp, size_of::<SignalFrame>(), Access::Write).is_err() {
+        // Like Linux, a handler that can't be given a frame ends the process instead.
+        terminate(pid, SIGSEGV);
+    }
+    unsafe { ptr::write_unaligned(sp as *mut SignalFrame, frame) };
+
+    tf.set_reg(2, sp);
+    tf.set_reg(1, action.trampoline);
+    tf.set_reg(10, sig);
+
+    action.handler
+}
+
+/// Restores the frame saved by `deliver()` once a handler returns, returning the pc to resume at.
+pub fn sigreturn(tf: &mut TrapFrame) -> usize {
+    if uaccess::check(tf.reg(2), size_of::<SignalFrame>(), Access::Read).is_err() {
+        terminate(proc::current(), SIGSEGV);
+    }
+    let frame = unsafe { ptr::read_unaligned(tf.reg(2) as *const SignalFrame) };
+
+    let unblockable = bit(SIGKILL) | bit(SIGSTOP);
+    BLOCKED[proc::current()].store(frame.blocked & !unblockable, Ordering::Relaxed);
+    *tf = frame.tf;
+
+    frame.pc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn blocked_signals_stay_pending() {
+        let pid = proc::current();
+
+        sigaction(SIGUSR1, 0x1000, 0x2000, 0).unwrap();
+        let old = sigprocmask(SIG_BLOCK, bit(SIGUSR1)).unwrap();
+        PENDING[pid].fetch_or(bit(SIGUSR1), Ordering::Relaxed);
+
+        assert!(take_pending(pid, |_, _| true).is_none());
+
+        sigprocmask(SIG_SETMASK, old).unwrap();
+        let (sig, action) = take_pending(pid, |_, _| true).unwrap();
+        assert_eq!(sig, SIGUSR1);
+        assert_eq!(action.handler, 0x1000);
+        assert_eq!(PENDING[pid].load(Ordering::Relaxed), 0);
+
+        reset(pid);
+    }
+
+    #[test_case]
+    fn only_signals_to_handle_interrupt_syscalls() {
+        let pid = proc::current();
+
+        // Ignored by default, like signals with `SIG_IGN`.
+        PENDING[pid].fetch_or(bit(SIGCHLD), Ordering::Relaxed);
+        assert_eq!(interrupted(), Ok(()));
+        sigaction(SIGUSR2, 0x1000, 0x2000, SA_RESTART).unwrap();
+        PENDING[pid].fetch_or(bit(SIGUSR2), Ordering::Relaxed);
+        assert_eq!(interrupted(), Err(Errno::ERESTARTSYS));
+        sigprocmask(SIG_BLOCK, bit(SIGUSR2)).unwrap();
+        assert!(!pending(pid));
+        assert_eq!(sigaction(SIGUSR2, 0x1000, 0x2000, 1), Err(Errno::EINVAL));
+
+        reset(pid);
+    }
+
+    #[test_case]
+    fn kill_rejects_invalid_targets() {
+        assert_eq!(kill(1, NSIG), Err(Errno::EINVAL));
+        assert_eq!(kill(0, SIGTERM), Err(Errno::EPERM));
+        assert_eq!(kill(PROC_MAX, SIGTERM), Err(Errno::ESRCH));
+        assert_eq!(sigaction(SIGKILL, SIG_IGN, 0, 0), Err(Errno::EINVAL));
+        assert_eq!(sigaction(SIGSTOP, SIG_IGN, 0, 0), Err(Errno::EINVAL));
+        // Tests run before any process exists, so there are no groups either.
+        assert_eq!(kill_group(1, SIGINT), Err(Errno::ESRCH));
+        assert_eq!(kill_group(0, SIGINT), Err(Errno::ESRCH));
+    }
+
+    #[test_case]
+    fn signals_are_named() {
+        assert_eq!(name(SIGQUIT), Some("SIGQUIT"));
+        assert_eq!(name(SIGUSR2), Some("SIGUSR2"));
+        assert_eq!(name(NSIG - 1), None);
+    }
+}
@@ -0,0 +1,484 @@
+use core::{fmt, panic::Location, ptr::NonNull};
+
+use crate::{
+    mem::{self, PAGE_SIZE, PhysAddr},
+    page,
+    stdlib::{phalloc_uninit, phree},
+    sync::Mutex,
+    sysctl::Tunable,
+};
+
+// Slab caches: small objects of a few size classes, packed into slabs of `SLAB_SIZE`
+// bytes from the buddy allocator instead of taking a page each. Each class has its own
+// cache, with the slabs that still have free objects in a list.
+//
+// A slab starts with its header, and its objects follow, aligned to their size, which is a
+// power of two. Slabs are aligned to their size, so an object's slab is found from its
+// address alone, and their frames are flagged `page::SLAB`. Free objects hold the address
+// of the next free object of the slab. A slab is given back as soon as none of its objects
+// is allocated.
+//
+// With `slab_debug` set, objects allocated from then on are debugged, a lightweight KASAN:
+// they come from caches of their own, in slots twice as large. A slot's left half holds a
+// `Track` of the free list link, who allocated the object and how many bytes, between
+// redzones of `REDZONE` bytes; the object is its right half, followed by a redzone too.
+// Freeing an object checks its redzones, failing a `kassert!` that names the allocating
+// call site if one was overwritten, and fills it with `POISON`; allocating the slot again
+// checks the poison is intact, catching writes after a free. A `Track` is only believed
+// while the redzones around it are intact, and a free list link only followed if it
+// points into its slab, so an overflow running into the next slot is reported rather than
+// crashing the allocator.
+
+/// Bytes of the largest object, larger allocations need `phalloc()`.
+pub const MAX_SIZE: usize = 2048;
+const MIN_SIZE: usize = 16;
+const CLASSES: usize = (MAX_SIZE / MIN_SIZE).ilog2() as usize + 1;
+const SLAB_SIZE: usize = 4 * PAGE_SIZE;
+
+/// Whether objects allocated from now on get redzones checked when they are freed,
+/// `/proc/sys/slab_debug`.
+pub static DEBUG: Tunable = Tunable::new("slab_debug", 0, 0, 1);
+
+// Redzones of debugged objects, and what they are filled with once freed.
+const REDZONE: u8 = 0xbb;
+const POISON: u8 = 0x6b;
+// Redzone bytes after a debugged object and before its `Track`, at least.
+const MIN_REDZONE: usize = 8;
+
+struct Slab {
+    class: usize,
+    // Whether the objects are debugged.
+    debug: bool,
+    // First free object, 0 if all are allocated.
+    free: usize,
+    used: usize,
+    // Slabs of the cache with free objects, 0 ends the list.
+    prev: usize,
+    next: usize,
+}
+
+impl Slab {
+    /// Returns the slab `object` is in.
+    fn of(object: usize) -> *mut Slab {
+        (object & !(SLAB_SIZE - 1)) as *mut Slab
+    }
+
+    /// Returns where the free `object` keeps the address of the next free object, past the
+    /// leading redzone of a debugged slot.
+    fn link(&self, object: usize) -> *mut usize {
+        match self.debug {
+            true => (object + MIN_REDZONE) as *mut usize,
+            false => object as *mut usize,
+        }
+    }
+
+    /// Returns whether an object of the slab starts at `addr`.
+    fn holds(&self, addr: usize) -> bool {
+        Slab::of(addr) as *const Slab == self
+            && addr.is_multiple_of(object_size(self.class))
+            && addr & (SLAB_SIZE - 1) >= size_of::<Slab>()
+    }
+}
+
+/// Counts of a cache, as reported by `stats()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Bytes of each object.
+    pub size: usize,
+    pub slabs: usize,
+    /// Objects allocated.
+    pub objects: usize,
+}
+
+struct Cache {
+    // First slab with free objects, 0 if none.
+    partial: usize,
+    slabs: usize,
+    objects: usize,
+}
+
+impl Cache {
+    fn push(&mut self, slab: &mut Slab) {
+        slab.prev = 0;
+        slab.next = self.partial;
+        if let Some(next) = unsafe { (self.partial as *mut Slab).as_mut() } {
+            next.prev = slab as *mut Slab as usize;
+        }
+        self.partial = slab as *mut Slab as usize;
+    }
+
+    fn remove(&mut self, slab: &mut Slab) {
+        match unsafe { (slab.prev as *mut Slab).as_mut() } {
+            Some(prev) => prev.next = slab.next,
+            None => self.partial = slab.next,
+        }
+        if let Some(next) = unsafe { (slab.next as *mut Slab).as_mut() } {
+            next.prev = slab.prev;
+        }
+    }
+}
+
+// Caches of each class, of plain objects, then of debugged ones.
+static CACHES: [[Mutex<Cache>; CLASSES]; 2] = [const {
+    [const {
+        Mutex::new(Cache {
+            partial: 0,
+            slabs: 0,
+            objects: 0,
+        })
+    }; CLASSES]
+}; 2];
+
+/// What a debugged object's slot holds after its leading redzone.
+#[repr(C)]
+struct Track {
+    // The free list link, as in any free object.
+    next_free: usize,
+    // Where the object was last allocated, `None` if it never was.
+    caller: Option<&'static Location<'static>>,
+    size: usize,
+}
+
+impl fmt::Display for Track {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.caller {
+            Some(caller) => write!(f, "{} bytes allocated at {caller}", self.size),
+            None => write!(f, "never allocated"),
+        }
+    }
+}
+
+/// Returns the size class of `n` bytes, `None` if they don't fit in an object.
+fn class_of(n: usize) -> Option<usize> {
+    match n {
+        ..=MAX_SIZE => Some((n.max(MIN_SIZE).next_power_of_two() / MIN_SIZE).ilog2() as usize),
+        _ => None,
+    }
+}
+
+fn object_size(class: usize) -> usize {
+    MIN_SIZE << class
+}
+
+/// Returns the class of the slot of a debugged object of `n` bytes, `None` if it doesn't fit
+/// in an object.
+fn debug_class_of(n: usize) -> Option<usize> {
+    class_of(2 * (n + MIN_REDZONE).max(size_of::<Track>() + 2 * MIN_REDZONE))
+}
+
+/// Returns the bytes of the object an allocation of `n` bytes gets, `None` if they don't
+/// fit in one.
+pub fn size_of_object(n: usize) -> Option<usize> {
+    class_of(n).map(object_size)
+}
+
+/// Allocates an object of at least `n` bytes, aligned to its size. It isn't zeroed.
+///
+/// With `DEBUG` set, the object is debugged if its slot fits in an object, and aligned to
+/// half the slot, which is at least its size.
+///
+/// Fails with `ZeroSize` if `n` is 0, and with `OutOfMemory` if it is over `MAX_SIZE` or
+/// there is no memory for another slab.
+#[track_caller]
+pub fn alloc(n: usize) -> Result<NonNull<u8>, mem::Error> {
+    if n == 0 {
+        return Err(mem::Error::ZeroSize);
+    }
+    let class = class_of(n).ok_or(mem::Error::OutOfMemory)?;
+    let object = match (DEBUG.get() != 0).then(|| debug_class_of(n)).flatten() {
+        Some(class) => track_alloc(take(class, true)?, class, n, Location::caller()),
+        None => take(class, false)?,
+    };
+    Ok(NonNull::new(object as *mut u8).unwrap())
+}
+
+/// Takes a free object of `class` from the plain or debugged cache.
+fn take(class: usize, debug: bool) -> Result<usize, mem::Error> {
+    let mut cache = CACHES[debug as usize][class].lock();
+    if cache.partial == 0 {
+        // The buddy allocator may call shrinkers, which may free objects.
+        drop(cache);
+        let slab = new_slab(class, debug)?;
+        cache = CACHES[debug as usize][class].lock();
+        cache.slabs += 1;
+        cache.push(unsafe { &mut *slab });
+    }
+
+    let slab = unsafe { &mut *(cache.partial as *mut Slab) };
+    let object = slab.free;
+    let mut next = unsafe { *slab.link(object) };
+    if slab.debug
+        && next != 0
+        && !crate::kassert!(
+            slab.holds(next),
+            "slab: free list link in {object:#x} overwritten, by an overflow of the object before it"
+        )
+    {
+        // The objects further down the list are lost.
+        next = 0;
+    }
+    slab.free = next;
+    slab.used += 1;
+    if slab.free == 0 {
+        cache.remove(slab);
+    }
+    cache.objects += 1;
+    Ok(object)
+}
+
+/// Returns whether the `len` bytes at `start` are all `byte`.
+fn is_filled(start: usize, len: usize, byte: u8) -> bool {
+    unsafe { core::slice::from_raw_parts(start as *const u8, len) }
+        .iter()
+        .all(|&b| b == byte)
+}
+
+fn fill(start: usize, len: usize, byte: u8) {
+    unsafe { (start as *mut u8).write_bytes(byte, len) };
+}
+
+/// Returns the `Track` of the debugged `slot`, and whether it can be believed: the redzones
+/// around it are intact.
+fn track_of(slot: usize, half: usize) -> (&'static mut Track, bool) {
+    let redzone = slot + MIN_REDZONE + size_of::<Track>();
+    let intact =
+        is_filled(slot, MIN_REDZONE, REDZONE) && is_filled(redzone, slot + half - redzone, REDZONE);
+    (
+        unsafe { &mut *((slot + MIN_REDZONE) as *mut Track) },
+        intact,
+    )
+}
+
+/// Paints the redzones of the left half of the debugged `slot`, around its `Track`.
+fn paint_header(slot: usize, half: usize) {
+    let redzone = slot + MIN_REDZONE + size_of::<Track>();
+    fill(slot, MIN_REDZONE, REDZONE);
+    fill(redzone, slot + half - redzone, REDZONE);
+}
+
+/// Checks the debugged `slot` of `class` was left alone while free, and makes it hold an
+/// object of `n` bytes allocated at `caller`, returning the object.
+fn track_alloc(slot: usize, class: usize, n: usize, caller: &'static Location<'static>) -> usize {
+    let half = object_size(class) / 2;
+    let object = slot + half;
+    let (track, intact) = track_of(slot, half);
+    match intact {
+        true => crate::kassert!(
+            is_filled(object, half, POISON),
+            "slab: {object:#x} was written to while free, {track}"
+        ),
+        false => crate::kassert!(false, "slab: header of the free {object:#x} overwritten"),
+    };
+
+    *track = Track {
+        next_free: 0,
+        caller: Some(caller),
+        size: n,
+    };
+    // Repainted whole, a broken redzone is reported once.
+    paint_header(slot, half);
+    fill(object + n, half - n, REDZONE);
+    object
+}
+
+/// Checks the redzones of the debugged `object` of `class` and poisons it, returning its slot.
+fn track_free(object: usize, class: usize) -> usize {
+    let half = object_size(class) / 2;
+    let slot = object - half;
+    let (track, intact) = track_of(slot, half);
+    let n = track.size;
+    // A broken header can't be trusted to say where the object came from.
+    match intact && track.caller.is_some() && n <= half - MIN_REDZONE {
+        true => crate::kassert!(
+            is_filled(object + n, half - n, REDZONE),
+            "slab: redzone of {object:#x} overwritten, {track}"
+        ),
+        false => crate::kassert!(false, "slab: header of {object:#x} overwritten"),
+    };
+    paint_header(slot, half);
+    fill(object, half, POISON);
+    slot
+}
+
+/// Allocates a slab of `class` with all its objects free, debugged ones poisoned.
+fn new_slab(class: usize, debug: bool) -> Result<*mut Slab, mem::Error> {
+    // Objects aren't zeroed, and the header and free list are written below.
+    let block = phalloc_uninit(SLAB_SIZE)?;
+    for frame in (0..SLAB_SIZE).step_by(PAGE_SIZE) {
+        if let Some(page) = page::get(block.as_usize() + frame) {
+            page.set_flags(page::SLAB);
+        }
+    }
+
+    let size = object_size(class);
+    let start = block.as_usize();
+    let slab = unsafe {
+        let slab = start as *mut Slab;
+        slab.write(Slab {
+            class,
+            debug,
+            free: 0,
+            used: 0,
+            prev: 0,
+            next: 0,
+        });
+        &mut *slab
+    };
+    // Linked last to first, so the list runs up the slab.
+    for object in (start + size_of::<Slab>().next_multiple_of(size)..start + SLAB_SIZE)
+        .step_by(size)
+        .rev()
+    {
+        if debug {
+            paint_header(object, size / 2);
+            fill(object + size / 2, size / 2, POISON);
+            *track_of(object, size / 2).0 = Track {
+                next_free: 0,
+                caller: None,
+                size: 0,
+            };
+        }
+        unsafe { *slab.link(object) = slab.free };
+        slab.free = object;
+    }
+    Ok(slab as *mut Slab)
+}
+
+/// Frees `object`, which `alloc()` returned.
+pub fn free(object: NonNull<u8>) {
+    let object = object.as_ptr() as usize;
+    let slab = Slab::of(object);
+    if !crate::kassert!(
+        page::get(object).is_some_and(|page| page.flags() & page::SLAB != 0),
+        "slab::free(): {object:#x} isn't a slab object."
+    ) {
+        return;
+    }
+
+    let slab = unsafe { &mut *slab };
+    let object = match slab.debug {
+        true => {
+            let half = object_size(slab.class) / 2;
+            if !crate::kassert!(
+                object % (2 * half) == half,
+                "slab::free(): {object:#x} isn't a slab object."
+            ) {
+                return;
+            }
+            track_free(object, slab.class)
+        }
+        false => object,
+    };
+    let mut cache = CACHES[slab.debug as usize][slab.class].lock();
+    unsafe { *slab.link(object) = slab.free };
+    if slab.free == 0 {
+        cache.push(slab);
+    }
+    slab.free = object;
+    slab.used -= 1;
+    cache.objects -= 1;
+    if slab.used > 0 {
+        return;
+    }
+    cache.remove(slab);
+    cache.slabs -= 1;
+    drop(cache);
+    phree(PhysAddr::new(slab as *mut Slab as usize, Some(SLAB_SIZE)));
+}
+
+/// Returns the counts of each class, smallest objects first, debugged objects counted in
+/// the class of their slot.
+pub fn stats() -> [Stats; CLASSES] {
+    core::array::from_fn(|class| {
+        let [plain, debugged] = [0, 1].map(|debug| {
+            let cache = CACHES[debug][class].lock();
+            (cache.slabs, cache.objects)
+        });
+        Stats {
+            size: object_size(class),
+            slabs: plain.0 + debugged.0,
+            objects: plain.1 + debugged.1,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn objects_are_packed_into_slabs() {
+        assert_eq!(size_of_object(1), Some(MIN_SIZE));
+        assert_eq!(size_of_object(100), Some(128));
+        assert_eq!(size_of_object(MAX_SIZE + 1), None);
+
+        let used = mem::usage().used;
+        let class = class_of(24).unwrap();
+        let before = stats()[class];
+        let mut objects = [NonNull::dangling(); 8];
+        for object in objects.iter_mut() {
+            *object = alloc(24).unwrap();
+            assert_eq!(object.as_ptr() as usize % 32, 0);
+        }
+        objects.sort();
+        assert!(objects.windows(2).all(|w| w[0] != w[1]));
+        let after = stats()[class];
+        assert_eq!(after.objects, before.objects + 8);
+        assert!(after.slabs <= before.slabs + 1);
+
+        for object in objects {
+            free(object);
+        }
+        assert_eq!(stats()[class], before);
+        assert_eq!(mem::usage().used, used);
+    }
+
+    #[test_case]
+    fn large_objects_fill_several_slabs() {
+        let before = stats()[CLASSES - 1];
+        let mut objects = [NonNull::dangling(); 16];
+        for object in objects.iter_mut() {
+            *object = alloc(MAX_SIZE).unwrap();
+            unsafe { object.as_ptr().write_bytes(0xa5, MAX_SIZE) };
+        }
+        // Seven to a slab, after the header.
+        assert!(stats()[CLASSES - 1].slabs >= before.slabs + 2);
+        for object in objects {
+            free(object);
+        }
+        assert_eq!(stats()[CLASSES - 1], before);
+        assert!(alloc(MAX_SIZE + 1).is_err());
+    }
+
+    #[test_case]
+    fn redzones_catch_overflows() {
+        use crate::kassert::{self, Mode};
+
+        let before = stats();
+        let previous = DEBUG.get();
+        DEBUG.set(1).unwrap();
+        let objects = [alloc(24).unwrap(), alloc(24).unwrap()];
+        let large = alloc(MAX_SIZE).unwrap();
+        DEBUG.set(previous).unwrap();
+        let [a, b] = objects.map(|object| object.as_ptr() as usize);
+        assert!(unsafe { (*Slab::of(a)).debug });
+        assert_eq!(a % 32, 0);
+        // Too large for a debugged slot.
+        assert!(!unsafe { (*Slab::of(large.as_ptr() as usize)).debug });
+
+        let mode = kassert::MODE.get();
+        kassert::MODE.set(Mode::Count as usize).unwrap();
+        let failures = kassert::failures();
+        unsafe { objects[0].as_ptr().write_bytes(0xa5, 24) };
+        free(objects[0]);
+        assert_eq!(kassert::failures(), failures);
+        unsafe { objects[1].as_ptr().write_bytes(0xa5, 25) };
+        free(objects[1]);
+        assert_eq!(kassert::failures(), failures + 1);
+        kassert::MODE.set(mode).unwrap();
+        free(large);
+
+        assert_ne!(a, b);
+        assert_eq!(stats(), before);
+    }
+}
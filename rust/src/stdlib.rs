@@ -4,7 +4,13 @@ use core::{
     slice,
 };
 
-use crate::mem::{PhysAddr, buddy_alloc, buddy_free};
+use crate::{
+    mem::{
+        self, PAGE_SIZE, PhysAddr, buddy_alloc, buddy_alloc_aligned, buddy_alloc_uninit,
+        buddy_free, buddy_realloc,
+    },
+    slab,
+};
 
 /// Allocates at least `n` bytes of contiguous physical memory.
 ///
@@ -12,11 +18,43 @@ use crate::mem::{PhysAddr, buddy_alloc, buddy_free};
 /// or an error of type `mem::Error` if the allocation fails.
 /// The returned address is guaranteed to be page-aligned.
 ///
+/// The memory is zeroed, like `phalloc_zeroed()`'s.
 pub fn phalloc(n: usize) -> Result<PhysAddr, crate::mem::Error> {
+    phalloc_zeroed(n)
+}
+
+/// Allocates at least `n` bytes like `phalloc()`, all of them zeroed.
+pub fn phalloc_zeroed(n: usize) -> Result<PhysAddr, mem::Error> {
     buddy_alloc(n)
 }
 
-/// Frees the provided physical memory region (`addr`).
+/// Allocates at least `n` bytes like `phalloc()`, but without zeroing them: they hold
+/// whatever they last did, possibly another owner's data. Only for memory about to be
+/// overwritten in full, such as a copy or a read from a device.
+pub fn phalloc_uninit(n: usize) -> Result<PhysAddr, mem::Error> {
+    buddy_alloc_uninit(n)
+}
+
+/// Allocates at least `n` bytes of contiguous physical memory aligned to `align`, a power of
+/// two, for devices that need more than a page's alignment.
+///
+/// Fails with `BadAlignment` if `align` isn't a power of two or larger than
+/// `mem::max_alignment()`. Freed with `phree()` like any other allocation.
+pub fn phalloc_aligned(n: usize, align: usize) -> Result<PhysAddr, mem::Error> {
+    buddy_alloc_aligned(n, align)
+}
+
+/// Resizes memory `phalloc()` returned to at least `n` bytes, returning where it is now.
+///
+/// It stays in place when it shrinks or the memory after it is free, and is otherwise moved
+/// to a new block, the old one freed. The bytes it held are kept, up to `n`; those it grew by
+/// aren't zeroed. On failure it is left as it was.
+pub fn phrealloc(addr: PhysAddr, n: usize) -> Result<PhysAddr, mem::Error> {
+    buddy_realloc(addr, n)
+}
+
+/// Frees the provided physical memory region (`addr`). Its size may be left out, the
+/// allocator keeps it.
 ///
 /// # Panics
 ///
@@ -26,6 +64,154 @@ pub fn phree(addr: PhysAddr) {
     buddy_free(addr);
 }
 
+/// Allocates `n` bytes, up to `slab::MAX_SIZE`, from the slab caches, for objects too small
+/// to take a page each. The memory is aligned to `n` rounded up to a power of two, and not
+/// zeroed.
+///
+/// Fails with `ZeroSize` if `n` is 0, and with `OutOfMemory` if it is larger or there is no
+/// memory left.
+///
+/// With `slab::DEBUG` set, overflowing the memory or writing to it once freed is reported,
+/// naming the caller. Through the kernel heap (`Box`, `Vec`, ...) that is always `heap.rs`:
+/// `GlobalAlloc` can't pass on where it was called from.
+#[track_caller]
+pub fn kmalloc(n: usize) -> Result<NonNull<u8>, mem::Error> {
+    slab::alloc(n)
+}
+
+/// Frees memory that `kmalloc()` returned.
+pub fn kfree(ptr: NonNull<u8>) {
+    slab::free(ptr);
+}
+
+/// Most blocks a scatter list holds.
+pub const SG_MAX: usize = 16;
+
+/// Physical memory made of up to `SG_MAX` blocks that aren't contiguous, in order, for
+/// callers that can take memory in pieces: a device that does scatter-gather DMA, or
+/// pages mapped contiguously into a virtual address space.
+///
+/// The blocks are freed when the list is dropped.
+#[derive(Debug)]
+pub struct SgList {
+    blocks: [Option<PhysAddr>; SG_MAX],
+    len: usize,
+    size: usize,
+}
+
+impl SgList {
+    fn new() -> Self {
+        Self {
+            blocks: [None; SG_MAX],
+            len: 0,
+            size: 0,
+        }
+    }
+
+    /// Returns the blocks, each of them aligned to its size.
+    pub fn blocks(&self) -> impl Iterator<Item = PhysAddr> + '_ {
+        self.blocks[..self.len].iter().flatten().copied()
+    }
+
+    /// Returns the bytes the list was allocated for, which its blocks may exceed.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the blocks as byte slices, in order, covering `size()` bytes.
+    pub fn slices_mut(&mut self) -> impl Iterator<Item = &mut [u8]> + '_ {
+        self.spans()
+            .map(|(addr, len)| unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) })
+    }
+
+    /// Copies the bytes at `offset` into `buf`, as if the blocks were one.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) {
+        crate::kassert!(
+            offset + buf.len() <= self.size(),
+            "SgList::read_at(): past the end"
+        );
+        let mut skip = offset;
+        let mut copied = 0;
+        for (addr, len) in self.spans() {
+            if copied == buf.len() {
+                break;
+            }
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            let n = (len - skip).min(buf.len() - copied);
+            let src = unsafe { core::slice::from_raw_parts((addr + skip) as *const u8, n) };
+            buf[copied..copied + n].copy_from_slice(src);
+            copied += n;
+            skip = 0;
+        }
+    }
+
+    // The address and length of each block, the last one cut at `size`.
+    fn spans(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut left = self.size;
+        self.blocks().map(move |block| {
+            let len = block.size().unwrap_or(PAGE_SIZE).min(left);
+            left -= len;
+            (block.as_usize(), len)
+        })
+    }
+}
+
+impl Drop for SgList {
+    fn drop(&mut self) {
+        for block in self.blocks() {
+            phree(block);
+        }
+    }
+}
+
+/// Allocates at least `n` bytes of physical memory, in one block if there is a free one
+/// that large and otherwise in as few smaller blocks as fragmentation allows.
+///
+/// Fails if the memory can't be found in `SG_MAX` blocks.
+pub fn phalloc_sg(n: usize) -> Result<SgList, mem::Error> {
+    alloc_sg(n, phalloc)
+}
+
+fn alloc_sg(
+    n: usize,
+    mut alloc: impl FnMut(usize) -> Result<PhysAddr, mem::Error>,
+) -> Result<SgList, mem::Error> {
+    if n == 0 {
+        return Err(mem::Error::ZeroSize);
+    }
+    let mut list = SgList::new();
+    list.size = n;
+    if let Ok(block) = alloc(n) {
+        list.blocks[0] = Some(block);
+        list.len = 1;
+        return Ok(list);
+    }
+
+    // The largest block that can still be found, halved on every failure. The blocks
+    // handed back on failure are freed with the list.
+    let mut left = n.next_multiple_of(PAGE_SIZE);
+    let mut chunk = usize::MAX;
+    while left > 0 {
+        if list.len == SG_MAX {
+            return Err(mem::Error::OutOfMemory);
+        }
+        chunk = chunk.min(1 << left.ilog2());
+        match alloc(chunk) {
+            Ok(block) => {
+                list.blocks[list.len] = Some(block);
+                list.len += 1;
+                left = left.saturating_sub(block.size().unwrap_or(chunk));
+            }
+            Err(_) if chunk > PAGE_SIZE => chunk /= 2,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(list)
+}
+
 // FIXME: Doesn't handle nested types properly. e.g FixedVec<FixedVec<usize>>
 #[derive(Debug)]
 pub struct FixedVec<T> {
@@ -56,11 +242,6 @@ impl<T> FixedVec<T> {
     pub fn cap(&self) -> usize {
         self.cap
     }
-
-    /// Returns a raw pointer to the backing memory for the `FixedVec`.
-    pub fn as_ptr(&self) -> *const T {
-        self.ptr.as_ptr()
-    }
 }
 
 impl<T> Index<usize> for FixedVec<T> {
@@ -102,3 +283,69 @@ impl<T> Drop for FixedVec<T> {
         phree(self.phys_addr);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn fixed_vec_index_and_deref() {
+        let mut v: FixedVec<usize> = FixedVec::new(16);
+        for i in 0..v.cap() {
+            v[i] = i * 2;
+        }
+        assert_eq!(v.len(), 16);
+        assert_eq!(v[7], 14);
+        assert_eq!(v.iter().sum::<usize>(), 240);
+    }
+
+    #[test_case]
+    fn fixed_vec_memory_is_reused_after_drop() {
+        let first: FixedVec<u8> = FixedVec::new(100);
+        let addr = first.as_ptr();
+        drop(first);
+
+        let second: FixedVec<u8> = FixedVec::new(100);
+        assert_eq!(second.as_ptr(), addr);
+    }
+
+    #[test_case]
+    fn scatter_lists_fall_back_to_smaller_blocks() {
+        // Nothing larger than two pages is free.
+        let fragmented = |n| match n {
+            n if n <= 2 * PAGE_SIZE => phalloc(n),
+            _ => Err(mem::Error::OutOfMemory),
+        };
+        let list = alloc_sg(7 * PAGE_SIZE, fragmented).unwrap();
+        let sizes = [2, 2, 2, 1].map(|pages| Some(pages * PAGE_SIZE));
+        assert!(list.blocks().map(|block| block.size()).eq(sizes));
+        assert_eq!(list.size(), 7 * PAGE_SIZE);
+        drop(list);
+
+        let list = phalloc_sg(3 * PAGE_SIZE).unwrap();
+        assert!(
+            list.blocks()
+                .map(|block| block.size())
+                .eq([Some(4 * PAGE_SIZE)])
+        );
+        drop(list);
+
+        // Too many blocks needed: the ones found are handed back.
+        let used = mem::usage().used;
+        let pages = |n| match n {
+            PAGE_SIZE => phalloc(n),
+            _ => Err(mem::Error::OutOfMemory),
+        };
+        assert!(alloc_sg((SG_MAX + 1) * PAGE_SIZE, pages).is_err());
+        assert_eq!(mem::usage().used, used);
+
+        // Bytes are found across blocks.
+        let mut list = alloc_sg(2 * PAGE_SIZE, pages).unwrap();
+        for (i, slice) in list.slices_mut().enumerate() {
+            slice.fill(i as u8 + 1);
+        }
+        let mut buf = [0; 4];
+        list.read_at(PAGE_SIZE - 2, &mut buf);
+        assert_eq!(buf, [1, 1, 2, 2]);
+    }
+}
@@ -0,0 +1,177 @@
+use alloc::{vec, vec::Vec};
+
+use crate::{bootprof, trace};
+
+/// When a subsystem is initialized. Levels run in order, subsystems within one level in
+/// link order unless one names another to wait for (see `Subsystem::after`), so a
+/// subsystem may rely on those of earlier levels and those it names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InitLevel {
+    /// Process table and other state everything else builds on.
+    Core,
+    /// Interrupts and timers.
+    Arch,
+    /// Kernel services built on the core, e.g. IPC or filesystems.
+    Subsys,
+    /// Device drivers.
+    Device,
+    /// Anything that wants to see the rest of the kernel up, e.g. the debugger.
+    Late,
+}
+
+impl InitLevel {
+    const ALL: [InitLevel; 5] = [
+        InitLevel::Core,
+        InitLevel::Arch,
+        InitLevel::Subsys,
+        InitLevel::Device,
+        InitLevel::Late,
+    ];
+}
+
+/// A subsystem compiled into the kernel, collected at link time by `register_subsystem!`.
+///
+/// Subsystems behind disabled features aren't compiled, so they leave no entry behind.
+pub struct Subsystem {
+    pub level: InitLevel,
+    pub name: &'static str,
+    /// Subsystems of the same or an earlier level that must be initialized first. Names
+    /// that no subsystem has count as initialized: those behind a disabled feature, and
+    /// `dtb`, `mem` and `cmdline`, which `kernel_init` sets up before any subsystem.
+    pub after: &'static [&'static str],
+    pub init: fn(),
+}
+
+unsafe extern "C" {
+    // Bounds of the `.subsystems` section, see kernel.ld.
+    static __subsystems: u8;
+    static __subsystems_end: u8;
+}
+
+/// Returns all registered subsystems, in link order.
+pub fn all() -> &'static [Subsystem] {
+    let start = unsafe { &__subsystems } as *const u8 as *const Subsystem;
+    let end = unsafe { &__subsystems_end } as *const u8 as *const Subsystem;
+    unsafe { core::slice::from_raw_parts(start, end.offset_from(start) as usize) }
+}
+
+/// Returns the order to initialize `subsystems` in, as indices into it: level by level,
+/// and within a level the first in link order of those whose `after` are all initialized.
+///
+/// Fails with a subsystem that never gets its turn, because it is part of a cycle or waits
+/// for one of a later level.
+fn order(subsystems: &[Subsystem]) -> Result<Vec<usize>, &Subsystem> {
+    let mut done = vec![false; subsystems.len()];
+    let mut order = Vec::with_capacity(subsystems.len());
+    let is_ready = |subsystem: &Subsystem, done: &[bool]| {
+        subsystem.after.iter().all(|&name| {
+            subsystems
+                .iter()
+                .zip(done)
+                .all(|(other, &done)| other.name != name || done)
+        })
+    };
+
+    for level in InitLevel::ALL {
+        let pending = |i: &usize, done: &[bool]| !done[*i] && subsystems[*i].level == level;
+        while let Some(next) =
+            (0..subsystems.len()).find(|i| pending(i, &done) && is_ready(&subsystems[*i], &done))
+        {
+            done[next] = true;
+            order.push(next);
+        }
+        if let Some(stuck) = (0..subsystems.len()).find(|i| pending(i, &done)) {
+            return Err(&subsystems[stuck]);
+        }
+    }
+    Ok(order)
+}
+
+/// Initializes all registered subsystems level by level, each after those it waits for,
+/// timing each for the boot report.
+///
+/// # Panics
+///
+/// Panics if the subsystems wait for each other in a cycle, or for one of a later level.
+pub fn init_all() {
+    let subsystems = all();
+    let order = order(subsystems).unwrap_or_else(|stuck| {
+        panic!(
+            "subsys: {} ({:?}) waits for {:?}, in a cycle or at a later level.",
+            stuck.name, stuck.level, stuck.after
+        )
+    });
+    for subsystem in order.into_iter().map(|i| &subsystems[i]) {
+        trace!("subsys: init {} ({:?})", subsystem.name, subsystem.level);
+        bootprof::measure(subsystem.name, subsystem.init);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    // Number of test init functions run so far, and when each of them ran (starting at 1).
+    static RUN: AtomicUsize = AtomicUsize::new(0);
+    static EARLY_RAN: AtomicUsize = AtomicUsize::new(0);
+    static LATE_RAN: AtomicUsize = AtomicUsize::new(0);
+
+    fn late() {
+        LATE_RAN.store(RUN.fetch_add(1, Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+
+    fn early() {
+        EARLY_RAN.store(RUN.fetch_add(1, Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+
+    // Registered in reverse, the levels decide the order.
+    crate::register_subsystem!(Late, "subsys-test-late", late, after("subsys-test-early"));
+    crate::register_subsystem!(Core, "subsys-test-early", early);
+
+    #[test_case]
+    fn subsystems_are_initialized_by_level() {
+        assert!(all().iter().any(|s| s.name == "subsys-test-late"));
+        let early = EARLY_RAN.load(Ordering::Relaxed);
+        let late = LATE_RAN.load(Ordering::Relaxed);
+        assert!(early != 0 && late != 0);
+        assert!(early < late);
+    }
+
+    const fn subsystem(
+        level: InitLevel,
+        name: &'static str,
+        after: &'static [&'static str],
+    ) -> Subsystem {
+        Subsystem {
+            level,
+            name,
+            after,
+            init: || {},
+        }
+    }
+
+    #[test_case]
+    fn subsystems_wait_for_those_they_name() {
+        use InitLevel::*;
+
+        let subsystems = [
+            subsystem(Subsys, "c", &["b"]),
+            subsystem(Subsys, "b", &["a", "mem"]),
+            subsystem(Core, "d", &[]),
+            subsystem(Subsys, "a", &["d"]),
+        ];
+        assert_eq!(order(&subsystems).ok(), Some(vec![2, 3, 1, 0]));
+
+        let cycle = [
+            subsystem(Core, "a", &["b"]),
+            subsystem(Core, "b", &["c"]),
+            subsystem(Core, "c", &["a"]),
+        ];
+        assert_eq!(order(&cycle).err().map(|s| s.name), Some("a"));
+
+        let later = [subsystem(Core, "a", &["b"]), subsystem(Late, "b", &[])];
+        assert_eq!(order(&later).err().map(|s| s.name), Some("a"));
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{
+    block::{self, Op, Request, SECTOR_SIZE},
+    cmdline,
+    mem::{self, PAGE_SIZE, PhysAddr},
+    page, println, proc,
+    stdlib::{phalloc_uninit, phree},
+    sync::Mutex,
+    syscall::Errno,
+    vm::{PageTable, WORKING_SET_SCANS},
+};
+
+// Swap: anonymous pages of processes written out to a block device to free their frames
+// when memory runs out. The device, given with `swap=<name>` on the command line, is cut
+// into page-sized slots, a bitmap here telling which hold a page. Its shrinker evicts the
+// pages that went unused the longest according to page aging (see `aging`), leaving in
+// their entry the slot they were written to instead of the frame (see `vm::PageTable::
+// swap_out()`). A process touching such a page faults, and `swap_in()` reads it back
+// into a new frame before it carries on. Kernel accesses to user memory go through
+// `proc::user_accessible()`, which swaps in the pages first.
+//
+// FIXME: Only this hart's TLB is flushed when a page is swapped out, another running the
+// process may keep writing to the frame after it was freed.
+
+/// Most slots a device is used for, past that the rest of it is left alone.
+const MAX_SLOTS: usize = 4096;
+const SLOT_SECTORS: u64 = (PAGE_SIZE / SECTOR_SIZE) as u64;
+// Most pages a shrinker call evicts.
+const BATCH: usize = 16;
+
+struct Swap {
+    dev: usize,
+    slots: usize,
+    used: [u32; MAX_SLOTS / 32],
+}
+
+static SWAP: Mutex<Option<Swap>> = Mutex::new(None);
+
+/// Slots in use and in total, see `stats()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub total: usize,
+    pub used: usize,
+}
+
+/// Starts swapping to the block device at `index`.
+///
+/// Fails with `EBUSY` if swap is on already, and with `EINVAL` if the device can't hold a
+/// single page.
+pub fn enable(index: usize) -> Result<(), Errno> {
+    let slots = (block::device(index)?.sectors() / SLOT_SECTORS).min(MAX_SLOTS as u64);
+    if slots == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let mut swap = SWAP.lock();
+    if swap.is_some() {
+        return Err(Errno::EBUSY);
+    }
+    *swap = Some(Swap {
+        dev: index,
+        slots: slots as usize,
+        used: [0; MAX_SLOTS / 32],
+    });
+    Ok(())
+}
+
+/// Stops swapping. Fails with `EBUSY` while pages are swapped out.
+pub fn disable() -> Result<(), Errno> {
+    let mut swap = SWAP.lock();
+    if swap
+        .as_ref()
+        .is_some_and(|s| s.used.iter().any(|&w| w != 0))
+    {
+        return Err(Errno::EBUSY);
+    }
+    *swap = None;
+    Ok(())
+}
+
+pub fn stats() -> Stats {
+    SWAP.lock()
+        .as_ref()
+        .map_or(Stats { total: 0, used: 0 }, |s| Stats {
+            total: s.slots,
+            used: s.used.iter().map(|w| w.count_ones() as usize).sum(),
+        })
+}
+
+/// Frees `slot`, whose page is no longer needed.
+pub fn free(slot: usize) {
+    if let Some(swap) = SWAP.lock().as_mut() {
+        swap.used[slot / 32] &= !(1 << (slot % 32));
+    }
+}
+
+/// Writes the page in `frame` to a free slot, returning it. Fails with `ENOSPC` if there
+/// is none, and `EAGAIN` if swap is in use.
+fn write(frame: usize) -> Result<usize, Errno> {
+    let (dev, slot) = {
+        let mut guard = SWAP.try_lock().ok_or(Errno::EAGAIN)?;
+        let swap = guard.as_mut().ok_or(Errno::ENOSPC)?;
+        let slot = (0..swap.slots)
+            .find(|&slot| swap.used[slot / 32] & (1 << (slot % 32)) == 0)
+            .ok_or(Errno::ENOSPC)?;
+        swap.used[slot / 32] |= 1 << (slot % 32);
+        (swap.dev, slot)
+    };
+    let page = unsafe { core::slice::from_raw_parts(frame as *const u8, PAGE_SIZE) };
+    block::write(dev, slot as u64 * SLOT_SECTORS, page).inspect_err(|_| free(slot))?;
+    Ok(slot)
+}
+
+/// Reads the page in `slot` into `frame`, keeping the slot.
+pub fn read(slot: usize, frame: usize) -> Result<(), Errno> {
+    let dev = SWAP.lock().as_ref().ok_or(Errno::ENODEV)?.dev;
+    let page = unsafe { core::slice::from_raw_parts_mut(frame as *mut u8, PAGE_SIZE) };
+    let request = Request {
+        sector: slot as u64 * SLOT_SECTORS,
+        op: Op::Read(page),
+    };
+    // Not through `block::read()`: a swapped page is read back once, reading ahead of it
+    // would only evict what is worth keeping in the windows.
+    block::submit(dev, &mut [request])
+}
+
+/// Brings back the page at `vaddr` in `table` if it was swapped out, into a new frame.
+/// Returns whether it was.
+pub fn swap_in(table: &mut PageTable, vaddr: usize) -> Result<bool, Errno> {
+    let Some(slot) = table.swapped(vaddr) else {
+        return Ok(false);
+    };
+    let frame = phalloc_uninit(PAGE_SIZE).map_err(|_| Errno::ENOMEM)?;
+    if let Err(err) = read(slot, frame.as_usize()) {
+        phree(frame);
+        return Err(err);
+    }
+    free(slot);
+    table.swap_in(vaddr, frame.as_usize());
+    Ok(true)
+}
+
+/// Writes out up to `max` of the pages of `table` unused for at least `age` aging scans
+/// and frees their frames, returning how many that was.
+fn evict(table: &mut PageTable, age: usize, max: usize) -> usize {
+    let mut victims = [0; BATCH];
+    let mut count = 0;
+    table.for_each_user_page(|vaddr, paddr| {
+        // Frames of user mappings of devices or shared with another have no descriptor, or
+        // more than one user.
+        let old = page::get(paddr).is_some_and(|page| {
+            page.flags() & page::USER_ANON != 0 && page.refcount() == 1 && page.age() >= age
+        });
+        if old && count < max.min(BATCH) {
+            victims[count] = vaddr;
+            count += 1;
+        }
+    });
+
+    let mut evicted = 0;
+    for &vaddr in &victims[..count] {
+        let Some(paddr) = table.translate(vaddr) else {
+            continue;
+        };
+        let Ok(slot) = write(paddr) else {
+            break;
+        };
+        if let Some(frame) = table.swap_out(vaddr, slot) {
+            phree(PhysAddr::new(frame, Some(PAGE_SIZE)));
+            evicted += 1;
+        }
+    }
+    evicted
+}
+
+/// Evicts up to `BATCH` pages of any process, those out of the working set first, then
+/// those unused for fewer scans, never those used since the last one.
+fn shrink() -> usize {
+    if SWAP.try_lock().is_none_or(|swap| swap.is_none()) {
+        return 0;
+    }
+    let mut evicted = 0;
+    for age in (1..=WORKING_SET_SCANS).rev() {
+        // Whoever holds the process table may be allocating.
+        let done = proc::try_for_each_page_table(|table| {
+            if evicted < BATCH {
+                evicted += evict(table, age, BATCH - evicted);
+            }
+        });
+        if !done || evicted == BATCH {
+            break;
+        }
+    }
+    evicted
+}
+
+crate::register_subsystem!(Late, "swap", init);
+
+fn init() {
+    mem::register_shrinker(shrink);
+    let Some(name) = cmdline::get("swap") else {
+        return;
+    };
+    match block::find(name).ok_or(Errno::ENODEV).and_then(enable) {
+        Ok(()) => println!("swap: {} KiB on {name}", stats().total * PAGE_SIZE / 1024),
+        Err(err) => println!("swap: can't swap to {name}: {err:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block::BlockDevice,
+        stdlib::phalloc,
+        vm::{PAGE_R, PAGE_U, PAGE_W},
+    };
+
+    const DISK_SECTORS: usize = 4 * SLOT_SECTORS as usize;
+
+    struct RamDisk(Mutex<[u8; DISK_SECTORS * SECTOR_SIZE]>);
+
+    impl BlockDevice for RamDisk {
+        fn sectors(&self) -> u64 {
+            DISK_SECTORS as u64
+        }
+
+        fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+            let start = sector as usize * SECTOR_SIZE;
+            buf.copy_from_slice(&self.0.lock()[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+            let start = sector as usize * SECTOR_SIZE;
+            self.0.lock()[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    static DISK: RamDisk = RamDisk(Mutex::new([0; DISK_SECTORS * SECTOR_SIZE]));
+
+    #[test_case]
+    fn old_pages_are_swapped_out_and_back_in() {
+        let index = block::add("swaptest", &DISK).unwrap();
+        assert_eq!(enable(index), Ok(()));
+        assert_eq!(enable(index), Err(Errno::EBUSY));
+        assert_eq!(stats(), Stats { total: 4, used: 0 });
+
+        let mut table = PageTable::new();
+        let vaddr = 0x1000_0000;
+        let frame = phalloc(PAGE_SIZE).unwrap().as_usize();
+        unsafe { (frame as *mut u8).write_bytes(0xa5, PAGE_SIZE) };
+        table.map_page(vaddr, frame, PAGE_U | PAGE_R | PAGE_W);
+
+        // Just mapped, so not unused for a scan yet.
+        assert_eq!(evict(&mut table, 1, BATCH), 0);
+        table.age();
+        assert_eq!(evict(&mut table, 1, BATCH), 1);
+        assert!(table.translate(vaddr).is_none());
+        assert!(!table.allows(vaddr, vaddr + 1, PAGE_R));
+        assert_eq!(table.usage().resident, 0);
+        assert_eq!(table.usage().swapped, 1);
+        assert_eq!(stats().used, 1);
+        assert_eq!(disable(), Err(Errno::EBUSY));
+
+        assert_eq!(swap_in(&mut table, vaddr + 8), Ok(true));
+        assert_eq!(swap_in(&mut table, vaddr), Ok(false));
+        assert!(table.allows(vaddr, vaddr + PAGE_SIZE, PAGE_R | PAGE_W));
+        let frame = table.translate(vaddr).unwrap();
+        let page = unsafe { core::slice::from_raw_parts(frame as *const u8, PAGE_SIZE) };
+        assert!(page.iter().all(|&b| b == 0xa5));
+        assert_eq!(table.usage().resident, 1);
+        assert_eq!(stats().used, 0);
+
+        // Freeing a page table frees the slots of its swapped pages too.
+        table.age();
+        assert_eq!(evict(&mut table, 1, BATCH), 1);
+        table.free_user_pages();
+        assert_eq!(stats().used, 0);
+        assert_eq!(disable(), Ok(()));
+    }
+}
@@ -1,7 +1,13 @@
 use core::cell::UnsafeCell;
 use core::hint::spin_loop;
+use core::mem::MaybeUninit;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicU8, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use crate::{
+    MAX_HARTS, hart_id,
+    proc::{self, WaitQueue},
+};
 
 #[repr(u8)]
 enum OnceState {
@@ -106,6 +112,29 @@ impl<T> OnceCell<T> {
         // Safety: Initialization is complete, so the value is guaranteed to be Some.
         unsafe { (*self.value.get()).as_ref().unwrap() }
     }
+
+    /// Returns the stored value, `None` if it hasn't been initialized yet.
+    pub fn get(&self) -> Option<&T> {
+        if !self.once.is_complete() {
+            return None;
+        }
+        // Safety: Initialization is complete, the value is never written again.
+        unsafe { (*self.value.get()).as_ref() }
+    }
+}
+
+// Number of `Mutex`es held by each hart, and the address of the one it acquired last.
+// Reported by the watchdog when a hart gets stuck.
+static HELD_LOCKS: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+static LAST_LOCK: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+/// Returns the number of locks currently held by `hart`
+/// and the address of the lock it most recently acquired.
+pub fn held_locks(hart: usize) -> (usize, usize) {
+    (
+        HELD_LOCKS[hart].load(Ordering::Relaxed),
+        LAST_LOCK[hart].load(Ordering::Relaxed),
+    )
 }
 
 #[repr(u8)]
@@ -130,7 +159,7 @@ impl<T> Mutex<T> {
     }
 
     /// Acquires the lock, spinning (using `core::hint::spin_loop()`) until it becomes available.
-    pub fn lock(&self) -> MutexGuard<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
         while self
             .lock
             .compare_exchange(
@@ -143,6 +172,28 @@ impl<T> Mutex<T> {
         {
             spin_loop();
         }
+        self.acquired()
+    }
+
+    /// Attempts to acquire the lock without spinning.
+    ///
+    /// Returns `None` if the lock is currently held, e.g. by the code an interrupt handler interrupted.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.lock
+            .compare_exchange(
+                MutexState::Free as u8,
+                MutexState::Locked as u8,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| self.acquired())
+    }
+
+    fn acquired(&self) -> MutexGuard<'_, T> {
+        let hart = hart_id();
+        HELD_LOCKS[hart].fetch_add(1, Ordering::Relaxed);
+        LAST_LOCK[hart].store(self as *const Self as usize, Ordering::Relaxed);
         MutexGuard { mutex: self }
     }
 }
@@ -169,6 +220,7 @@ impl<'a, T> DerefMut for MutexGuard<'a, T> {
 
 impl<'a, T> Drop for MutexGuard<'a, T> {
     fn drop(&mut self) {
+        HELD_LOCKS[hart_id()].fetch_sub(1, Ordering::Relaxed);
         self.mutex
             .lock
             .store(MutexState::Free as u8, Ordering::Release);
@@ -178,3 +230,331 @@ impl<'a, T> Drop for MutexGuard<'a, T> {
 // Safety: Our simple mutex is safe to share between threads as long as T is Send.
 unsafe impl<T: Send> Sync for Mutex<T> {}
 unsafe impl<T: Send> Send for Mutex<T> {}
+
+struct Slot<T> {
+    // The position the slot is due for next, less its index so that all start at 0: to be
+    // written with the value pushed at that position, or one past it while holding it.
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A bounded queue of up to `N` values, which must be a power of two, that any number of
+/// producers push to and one consumer pops from, all without a lock.
+///
+/// Producers may be interrupt handlers: they never wait for the consumer, or for each
+/// other, so nothing an interrupted consumer holds can keep them from pushing.
+pub struct Mpsc<T, const N: usize> {
+    slots: [Slot<T>; N],
+    // Positions of the next value to push and to pop, counting since the start.
+    tail: AtomicUsize,
+    head: AtomicUsize,
+    // Held while popping, a second consumer at the same time finds the queue empty.
+    popping: AtomicBool,
+}
+
+impl<T: Copy, const N: usize> Mpsc<T, N> {
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two(), "queue size not a power of two.");
+        Self {
+            slots: [const {
+                Slot {
+                    stamp: AtomicUsize::new(0),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; N],
+            tail: AtomicUsize::new(0),
+            head: AtomicUsize::new(0),
+            popping: AtomicBool::new(false),
+        }
+    }
+
+    fn stamp(&self, index: usize) -> usize {
+        self.slots[index]
+            .stamp
+            .load(Ordering::Acquire)
+            .wrapping_add(index)
+    }
+
+    fn set_stamp(&self, index: usize, stamp: usize) {
+        self.slots[index]
+            .stamp
+            .store(stamp.wrapping_sub(index), Ordering::Release);
+    }
+
+    /// Adds `value` at the end, handing it back if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let index = pos % N;
+            let ahead = self.stamp(index).wrapping_sub(pos) as isize;
+            if ahead == 0 {
+                match self.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // Safety: taking `pos` makes this producer the only writer of the
+                        // slot, and the consumer reads it only once it is stamped.
+                        unsafe { (*self.slots[index].value.get()).write(value) };
+                        self.set_stamp(index, pos.wrapping_add(1));
+                        return Ok(());
+                    }
+                    Err(tail) => pos = tail,
+                }
+            } else if ahead < 0 {
+                // Still holds the value pushed a lap before.
+                return Err(value);
+            } else {
+                // Another producer took `pos`.
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Removes the value at the front, `None` if there is none or it is still being pushed.
+    pub fn pop(&self) -> Option<T> {
+        if self.popping.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        let pos = self.head.load(Ordering::Relaxed);
+        let index = pos % N;
+        let value = (self.stamp(index) == pos.wrapping_add(1)).then(|| {
+            // Safety: stamped as holding a value, and only the consumer reads it.
+            let value = unsafe { (*self.slots[index].value.get()).assume_init_read() };
+            self.set_stamp(index, pos.wrapping_add(N));
+            self.head.store(pos.wrapping_add(1), Ordering::Relaxed);
+            value
+        });
+        self.popping.store(false, Ordering::Release);
+        value
+    }
+}
+
+// Safety: values are moved in by one side and out by the other, never shared.
+unsafe impl<T: Send, const N: usize> Sync for Mpsc<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for Mpsc<T, N> {}
+
+// Owner of a `PiMutex` that is free.
+const NO_OWNER: usize = usize::MAX;
+
+/// A mutex that blocks waiting processes instead of spinning, and boosts the priority of
+/// the process holding it to that of the highest waiting one, so a process of a lower
+/// priority than both can't keep them waiting (see `proc::priority()`).
+///
+/// The boost ends when the owner unlocks, even if it holds another one still. Not for
+/// interrupt context.
+pub struct PiMutex<T> {
+    // Pid of the process holding it, `NO_OWNER` if none.
+    owner: AtomicUsize,
+    // Processes waiting for it.
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+impl<T> PiMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            owner: AtomicUsize::new(NO_OWNER),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Acquires the lock, blocking while another process holds it.
+    pub fn lock(&self) -> PiMutexGuard<'_, T> {
+        let pid = proc::current();
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            let owner = self.owner.load(Ordering::Relaxed);
+            if owner != NO_OWNER {
+                proc::boost(owner, proc::priority(pid));
+            }
+            if proc::is_scheduling() {
+                // Queued before looking again, so an unlock in between wakes it.
+                self.waiters.add(pid);
+                if let Some(guard) = self.try_lock() {
+                    self.waiters.remove(pid);
+                    return guard;
+                }
+                proc::sleep(None);
+            } else {
+                spin_loop();
+            }
+        }
+    }
+
+    /// Attempts to acquire the lock without blocking.
+    pub fn try_lock(&self) -> Option<PiMutexGuard<'_, T>> {
+        self.owner
+            .compare_exchange(
+                NO_OWNER,
+                proc::current(),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| PiMutexGuard { mutex: self })
+    }
+}
+
+/// A guard that releases a `PiMutex` when dropped, ending the owner's boost.
+pub struct PiMutexGuard<'a, T> {
+    mutex: &'a PiMutex<T>,
+}
+
+impl<T> Deref for PiMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: we hold the lock.
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for PiMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: we hold the lock.
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for PiMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        proc::unboost(proc::current());
+        self.mutex.owner.store(NO_OWNER, Ordering::Release);
+        // All of them, the one of the highest priority runs first.
+        self.mutex.waiters.wake_all();
+    }
+}
+
+unsafe impl<T: Send> Sync for PiMutex<T> {}
+unsafe impl<T: Send> Send for PiMutex<T> {}
+
+/// Lets a fixed number of harts or processes wait for each other, each one going on only
+/// once all of them called `wait()`. Can be used again once they did.
+pub struct Barrier {
+    n: usize,
+    arrived: AtomicUsize,
+    // Counts the times all of them arrived.
+    generation: AtomicUsize,
+    // Processes blocked in `wait()`.
+    waiters: WaitQueue,
+}
+
+impl Barrier {
+    /// Creates a barrier for `n` parties.
+    pub const fn new(n: usize) -> Self {
+        Self {
+            n,
+            arrived: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Waits until all `n` parties called `wait()`, spinning until processes are scheduled
+    /// and blocking once they are. Returns `true` for the last one to arrive only.
+    pub fn wait(&self) -> bool {
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.arrived.fetch_add(1, Ordering::AcqRel) + 1 == self.n {
+            self.arrived.store(0, Ordering::Relaxed);
+            self.generation.fetch_add(1, Ordering::Release);
+            self.waiters.wake_all();
+            return true;
+        }
+
+        let blocking = proc::is_scheduling();
+        while self.generation.load(Ordering::Acquire) == generation {
+            if blocking {
+                // Queued before looking again, so the last one arriving in between wakes it.
+                let pid = proc::current();
+                self.waiters.add(pid);
+                if self.generation.load(Ordering::Acquire) != generation {
+                    self.waiters.remove(pid);
+                    break;
+                }
+                proc::sleep(None);
+            } else {
+                spin_loop();
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Barrier, Mpsc, Mutex, OnceCell, Ordering, PiMutex};
+    use crate::proc;
+
+    #[test_case]
+    fn mutex_try_lock_fails_while_held() {
+        let mutex = Mutex::new(0);
+        let guard = mutex.lock();
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+        assert!(mutex.try_lock().is_some());
+    }
+
+    #[test_case]
+    fn once_cell_initializes_once() {
+        let cell = OnceCell::new();
+        assert_eq!(*cell.get_or_init(|| 1), 1);
+        assert_eq!(*cell.get_or_init(|| 2), 1);
+    }
+
+    #[test_case]
+    fn mpsc_keeps_order_across_laps() {
+        let queue: Mpsc<usize, 4> = Mpsc::new();
+        assert_eq!(queue.pop(), None);
+        for lap in 0..3 {
+            for i in 0..4 {
+                assert_eq!(queue.push(lap * 4 + i), Ok(()));
+            }
+            assert_eq!(queue.push(99), Err(99));
+            for i in 0..4 {
+                assert_eq!(queue.pop(), Some(lap * 4 + i));
+            }
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test_case]
+    fn barrier_of_one_never_waits() {
+        let barrier = Barrier::new(1);
+        assert!(barrier.wait());
+        assert!(barrier.wait());
+        assert_eq!(barrier.generation.load(Ordering::Relaxed), 2);
+
+        // As if another party were waiting already.
+        let barrier = Barrier::new(2);
+        barrier.arrived.store(1, Ordering::Relaxed);
+        barrier.waiters.add(proc::current());
+        assert!(barrier.wait());
+        assert_eq!(barrier.arrived.load(Ordering::Relaxed), 0);
+        // It was woken.
+        assert!(!barrier.waiters.wake_one());
+    }
+
+    #[test_case]
+    fn pi_mutex_boosts_its_owner() {
+        let mutex = PiMutex::new(0);
+        let owner = proc::current();
+        let guard = mutex.lock();
+        assert!(mutex.try_lock().is_none());
+
+        // What a waiting process of priority 5 does before it blocks.
+        proc::boost(owner, 5);
+        mutex.waiters.add(owner);
+        assert_eq!(proc::priority(owner), 5);
+        drop(guard);
+        assert_eq!(proc::priority(owner), 0);
+        // The waiter was woken.
+        assert!(!mutex.waiters.wake_one());
+        *mutex.lock() += 1;
+        assert_eq!(*mutex.lock(), 1);
+    }
+}
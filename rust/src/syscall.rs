@@ -0,0 +1,489 @@
+use core::slice;
+
+use crate::{
+    alarm, cred, cwd, elf, file, futex, icmp, ipc,
+    mem::PAGE_SIZE,
+    mmap,
+    net::{AF_INET, SocketAddr},
+    pipe, poll, proc, random, reboot, rlimit, signal, trace,
+    trap::TrapFrame,
+    tty,
+    uaccess::{self, Access, UserAccess},
+    udp, vfs,
+};
+
+// Syscall numbers, passed in `a7`. Arguments go in `a0` to `a5`, the result comes back
+// in `a0`: zero or positive on success, a negated `Errno` on failure.
+pub const SYS_MQ_CREATE: usize = 1;
+pub const SYS_MQ_DESTROY: usize = 2;
+pub const SYS_MQ_SEND: usize = 3;
+pub const SYS_MQ_RECV: usize = 4;
+pub const SYS_READ: usize = 5;
+pub const SYS_WRITE: usize = 6;
+pub const SYS_CLOSE: usize = 7;
+pub const SYS_PIPE: usize = 8;
+pub const SYS_KILL: usize = 9;
+pub const SYS_SIGACTION: usize = 10;
+pub const SYS_SIGPROCMASK: usize = 11;
+pub const SYS_SIGRETURN: usize = 12;
+pub const SYS_FUTEX_WAIT: usize = 13;
+pub const SYS_FUTEX_WAKE: usize = 14;
+pub const SYS_POLL: usize = 15;
+pub const SYS_SOCKET: usize = 16;
+pub const SYS_BIND: usize = 17;
+pub const SYS_SENDTO: usize = 18;
+pub const SYS_RECVFROM: usize = 19;
+pub const SYS_GETRLIMIT: usize = 20;
+pub const SYS_SETRLIMIT: usize = 21;
+pub const SYS_OPEN: usize = 22;
+pub const SYS_GETDENTS: usize = 23;
+pub const SYS_DUP: usize = 24;
+pub const SYS_DUP2: usize = 25;
+pub const SYS_FCNTL: usize = 26;
+pub const SYS_EXIT: usize = 27;
+pub const SYS_FORK: usize = 28;
+pub const SYS_EXEC: usize = 29;
+pub const SYS_WAIT: usize = 30;
+pub const SYS_GETITIMER: usize = 31;
+pub const SYS_SETITIMER: usize = 32;
+pub const SYS_SETPGID: usize = 33;
+pub const SYS_GETPGID: usize = 34;
+pub const SYS_TCSETPGRP: usize = 35;
+pub const SYS_TCGETPGRP: usize = 36;
+pub const SYS_MPROTECT: usize = 37;
+pub const SYS_FSYNC: usize = 38;
+pub const SYS_GETRANDOM: usize = 39;
+pub const SYS_SETUID: usize = 40;
+pub const SYS_GETUID: usize = 41;
+pub const SYS_SETGID: usize = 42;
+pub const SYS_GETGID: usize = 43;
+pub const SYS_CHMOD: usize = 44;
+pub const SYS_CHOWN: usize = 45;
+pub const SYS_MKDIR: usize = 46;
+pub const SYS_RMDIR: usize = 47;
+pub const SYS_UNLINK: usize = 48;
+pub const SYS_RENAME: usize = 49;
+pub const SYS_LINK: usize = 50;
+pub const SYS_SYMLINK: usize = 51;
+pub const SYS_READLINK: usize = 52;
+pub const SYS_MMAP: usize = 53;
+pub const SYS_MUNMAP: usize = 54;
+pub const SYS_CHDIR: usize = 55;
+pub const SYS_GETCWD: usize = 56;
+pub const SYS_REBOOT: usize = 57;
+pub const SYS_GETPRIORITY: usize = 58;
+pub const SYS_SETPRIORITY: usize = 59;
+pub const SYS_PING: usize = 60;
+
+// Options of `wait()`, with the values Linux uses.
+pub const WUNTRACED: usize = 2;
+
+/// Most arguments, and most environment strings, `exec()` takes.
+pub const EXEC_STRINGS_MAX: usize = 32;
+
+// Socket types and protocols, with the values Linux uses.
+pub const SOCK_DGRAM: usize = 2;
+pub const IPPROTO_UDP: usize = 17;
+
+/// Error numbers returned by syscalls, with the values Linux uses.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(isize)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    ENXIO = 6,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EXDEV = 18,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    ENFILE = 23,
+    EMFILE = 24,
+    ENOTTY = 25,
+    EFBIG = 27,
+    ENOSPC = 28,
+    EROFS = 30,
+    EPIPE = 32,
+    ERANGE = 34,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    ELOOP = 40,
+    ENOTSOCK = 88,
+    EDESTADDRREQ = 89,
+    EMSGSIZE = 90,
+    EPROTONOSUPPORT = 93,
+    EAFNOSUPPORT = 97,
+    EADDRINUSE = 98,
+    ENOBUFS = 105,
+    ENETUNREACH = 101,
+    ETIMEDOUT = 110,
+    EHOSTUNREACH = 113,
+    /// Kernel only: a signal interrupted a blocking syscall, which runs again once the
+    /// signal is handled, or fails with `EINTR` if a handler without `SA_RESTART` ran.
+    ERESTARTSYS = 512,
+}
+
+/// Handles an `ecall` at `pc` from user space described by `tf`.
+///
+/// Returns the pc to return to user space at.
+pub fn dispatch(tf: &mut TrapFrame, pc: usize) -> usize {
+    let args = [0, 1, 2, 3, 4, 5].map(|n| tf.syscall_arg(n));
+
+    let nr = tf.syscall_nr();
+    crate::tracepoint!(SyscallEnter, nr);
+    // Any argument may point into the process's memory.
+    let _user = UserAccess::new();
+    let ret = match nr {
+        // Restores the whole frame, including `a0`, from before the signal handler ran.
+        SYS_SIGRETURN => return signal::sigreturn(tf),
+        SYS_EXIT => proc::exit(proc::exit_status(args[0])),
+        SYS_EXEC => match sys_exec(args[0], args[1], args[2], args[3]) {
+            // The new program starts with all registers zeroed but `sp`.
+            Ok((entry, sp)) => {
+                *tf = TrapFrame::zeroed();
+                tf.set_sp(sp);
+                return entry;
+            }
+            Err(errno) => Err(errno),
+        },
+        SYS_MQ_CREATE => ipc::create(args[0], args[1]).map(|_| 0),
+        SYS_MQ_DESTROY => ipc::destroy(args[0]).map(|_| 0),
+        SYS_MQ_SEND => user_slice(args[1], args[2])
+            .and_then(|msg| ipc::send(args[0], msg, args[3] & ipc::MQ_NONBLOCK != 0))
+            .map(|_| 0),
+        SYS_MQ_RECV => user_slice_mut(args[1], args[2]).and_then(|buf| {
+            ipc::recv(args[0], buf, args[3] & ipc::MQ_NONBLOCK != 0).map(|(len, _)| len)
+        }),
+        SYS_READ => user_slice_mut(args[1], args[2]).and_then(|buf| file::read(args[0], buf)),
+        SYS_WRITE => user_slice(args[1], args[2]).and_then(|buf| file::write(args[0], buf)),
+        SYS_CLOSE => file::close(args[0]).map(|_| 0),
+        SYS_PIPE => sys_pipe(args[0]),
+        SYS_KILL => sys_kill(args[0] as isize, args[1]).map(|_| 0),
+        SYS_SIGACTION => signal::sigaction(args[0], args[1], args[2], args[3]),
+        SYS_SIGPROCMASK => signal::sigprocmask(args[0], args[1] as u32).map(|old| old as usize),
+        SYS_FUTEX_WAIT => futex::wait(args[0], args[1] as u32).map(|_| 0),
+        SYS_FUTEX_WAKE => futex::wake(args[0], args[1]),
+        SYS_POLL => poll::sys_poll(args[0], args[1], args[2] as isize),
+        SYS_SOCKET => sys_socket(args[0], args[1], args[2]),
+        SYS_BIND => user_slice(args[1], args[2])
+            .and_then(SocketAddr::parse)
+            .and_then(|addr| udp::bind(file::socket(args[0])?, addr))
+            .map(|_| 0),
+        SYS_SENDTO => sys_sendto(args[0], args[1], args[2], args[3], args[4], args[5]),
+        SYS_RECVFROM => sys_recvfrom(args[0], args[1], args[2], args[3], args[4], args[5]),
+        SYS_GETRLIMIT => rlimit::sys_getrlimit(args[0], args[1]),
+        SYS_SETRLIMIT => rlimit::sys_setrlimit(args[0], args[1]),
+        SYS_OPEN => user_path(args[0], args[1]).and_then(|path| vfs::open(path, args[2], args[3])),
+        SYS_DUP => file::dup(args[0]),
+        SYS_DUP2 => file::dup2(args[0], args[1]),
+        SYS_FCNTL => file::fcntl(args[0], args[1], args[2]),
+        SYS_FORK => proc::fork(tf, pc + 4),
+        SYS_WAIT => sys_wait(args[0] as isize, args[1], args[2]),
+        SYS_GETITIMER => alarm::sys_getitimer(args[0]),
+        SYS_SETITIMER => alarm::sys_setitimer(args[0], args[1]),
+        SYS_SETPGID => proc::setpgid(args[0], args[1]).map(|_| 0),
+        SYS_GETPGID => sys_getpgid(args[0]),
+        SYS_TCSETPGRP => tty::tcsetpgrp(args[0], args[1]).map(|_| 0),
+        SYS_TCGETPGRP => tty::tcgetpgrp(args[0]),
+        SYS_MPROTECT => proc::mprotect(args[0], args[1], args[2]).map(|_| 0),
+        SYS_MMAP => mmap::mmap(args[0], args[1], args[2], args[3], args[4], args[5]),
+        SYS_MUNMAP => mmap::munmap(args[0], args[1]).map(|_| 0),
+        SYS_FSYNC => file::fsync(args[0]).map(|_| 0),
+        SYS_GETRANDOM => random::sys_getrandom(args[0], args[1], args[2]),
+        SYS_SETUID => cred::setuid(proc::current(), args[0]).map(|_| 0),
+        SYS_GETUID => Ok(cred::get(proc::current()).uid),
+        SYS_SETGID => cred::setgid(proc::current(), args[0]).map(|_| 0),
+        SYS_GETGID => Ok(cred::get(proc::current()).gid),
+        SYS_CHMOD => user_path(args[0], args[1])
+            .and_then(|path| vfs::chmod(path, args[2]))
+            .map(|_| 0),
+        SYS_CHOWN => user_path(args[0], args[1])
+            .and_then(|path| vfs::chown(path, args[2], args[3]))
+            .map(|_| 0),
+        SYS_MKDIR => user_path(args[0], args[1])
+            .and_then(|path| vfs::mkdir(path, args[2]))
+            .map(|_| 0),
+        SYS_RMDIR => user_path(args[0], args[1]).and_then(vfs::rmdir).map(|_| 0),
+        SYS_UNLINK => user_path(args[0], args[1]).and_then(vfs::unlink).map(|_| 0),
+        SYS_RENAME => user_path(args[0], args[1])
+            .and_then(|old| vfs::rename(old, user_path(args[2], args[3])?))
+            .map(|_| 0),
+        SYS_LINK => user_path(args[0], args[1])
+            .and_then(|old| vfs::link(old, user_path(args[2], args[3])?))
+            .map(|_| 0),
+        SYS_SYMLINK => user_path(args[0], args[1])
+            .and_then(|target| vfs::symlink(target, user_path(args[2], args[3])?))
+            .map(|_| 0),
+        SYS_READLINK => user_path(args[0], args[1])
+            .and_then(|path| vfs::readlink(path, user_slice_mut(args[2], args[3])?)),
+        SYS_CHDIR => user_path(args[0], args[1]).and_then(cwd::chdir).map(|_| 0),
+        SYS_GETCWD => user_slice_mut(args[0], args[1]).and_then(cwd::getcwd),
+        SYS_REBOOT => reboot::reboot(args[0]),
+        SYS_GETPRIORITY => sys_getpriority(args[0]),
+        SYS_SETPRIORITY => sys_setpriority(args[0], args[1]).map(|_| 0),
+        SYS_PING => icmp::sys_ping(args[0], args[1], args[2], args[3]),
+        SYS_GETDENTS => {
+            user_slice_mut(args[1], args[2]).and_then(|buf| vfs::getdents(args[0], buf))
+        }
+        _ => Err(Errno::ENOSYS),
+    };
+    trace!("syscall {nr} by pid {}: {ret:?}", proc::current());
+
+    // Back to the `ecall` with the arguments as they were, see `signal::deliver()`.
+    if ret == Err(Errno::ERESTARTSYS) {
+        signal::interrupted_syscall();
+        crate::tracepoint!(SyscallExit, nr, -(Errno::ERESTARTSYS as isize));
+        return pc;
+    }
+
+    let ret = match ret {
+        Ok(value) => value as isize,
+        Err(errno) => -(errno as isize),
+    };
+    crate::tracepoint!(SyscallExit, nr, ret);
+    tf.set_syscall_ret(ret);
+
+    pc + 4
+}
+
+/// Runs the program at `path` with `argv` and `envp`, NULL-terminated arrays of pointers to
+/// NUL-terminated strings (null for an empty one), returning its entry point and stack
+/// pointer.
+fn sys_exec(
+    path: usize,
+    path_len: usize,
+    argv: usize,
+    envp: usize,
+) -> Result<(usize, usize), Errno> {
+    let path = user_path(path, path_len)?;
+    let mut argv_buf = [&[][..]; EXEC_STRINGS_MAX];
+    let argv = user_strings(argv, &mut argv_buf)?;
+    let mut envp_buf = [&[][..]; EXEC_STRINGS_MAX];
+    let envp = user_strings(envp, &mut envp_buf)?;
+    proc::exec(path, argv, envp)
+}
+
+/// Sends `sig` to process `pid` if positive, to the caller's process group if 0, and to
+/// group `-pid` otherwise.
+fn sys_kill(pid: isize, sig: usize) -> Result<(), Errno> {
+    match pid {
+        1.. => {
+            cred::may_signal(proc::current(), pid as usize)?;
+            signal::kill(pid as usize, sig)
+        }
+        0 => signal::kill_group(proc::pgid(proc::current()), sig),
+        // Every process, which isn't supported.
+        -1 => Err(Errno::EINVAL),
+        _ => signal::kill_group(pid.unsigned_abs(), sig),
+    }
+}
+
+/// Returns the process group of `pid`, the caller if 0.
+fn sys_getpgid(pid: usize) -> Result<usize, Errno> {
+    let pid = if pid == 0 { proc::current() } else { pid };
+    if !proc::exists(pid) {
+        return Err(Errno::ESRCH);
+    }
+    Ok(proc::pgid(pid))
+}
+
+/// Returns the priority `pid`, the caller if 0, is scheduled with.
+fn sys_getpriority(pid: usize) -> Result<usize, Errno> {
+    let pid = if pid == 0 { proc::current() } else { pid };
+    if !proc::exists(pid) {
+        return Err(Errno::ESRCH);
+    }
+    Ok(proc::priority(pid))
+}
+
+/// Sets the priority of `pid`, the caller if 0, up to `proc::MAX_PRIORITY`. Only root may
+/// raise it, or change that of another user's process.
+fn sys_setpriority(pid: usize, priority: usize) -> Result<(), Errno> {
+    let pid = if pid == 0 { proc::current() } else { pid };
+    if !proc::exists(pid) {
+        return Err(Errno::ESRCH);
+    }
+    if priority > proc::MAX_PRIORITY {
+        return Err(Errno::EINVAL);
+    }
+    cred::may_signal(proc::current(), pid)?;
+    if priority > proc::priority(pid) {
+        cred::require_root()?;
+    }
+    proc::set_priority(pid, priority);
+    Ok(())
+}
+
+/// Waits for child `pid` (any child if -1) to exit, storing its wait status as an `i32` at
+/// `status` unless it is null.
+fn sys_wait(pid: isize, status: usize, options: usize) -> Result<usize, Errno> {
+    let pid = match pid {
+        -1 => None,
+        0.. => Some(pid as usize),
+        _ => return Err(Errno::EINVAL),
+    };
+    if options & !WUNTRACED != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let (child, wait_status) = proc::wait(pid, options & WUNTRACED != 0)?;
+    if status != 0 {
+        user_slice_mut(status, size_of::<i32>())?
+            .copy_from_slice(&(wait_status as i32).to_ne_bytes());
+    }
+    Ok(child)
+}
+
+/// Creates a pipe and stores the read and write ends' fds in the `[usize; 2]` at `fds`.
+fn sys_pipe(fds: usize) -> Result<usize, Errno> {
+    let out = user_slice_mut(fds, 2 * size_of::<usize>())?;
+    let (read_fd, write_fd) = pipe::open()?;
+
+    out[..size_of::<usize>()].copy_from_slice(&read_fd.to_ne_bytes());
+    out[size_of::<usize>()..].copy_from_slice(&write_fd.to_ne_bytes());
+
+    Ok(0)
+}
+
+/// Creates a socket, only UDP over IPv4 is supported.
+fn sys_socket(domain: usize, ty: usize, protocol: usize) -> Result<usize, Errno> {
+    if domain != AF_INET as usize {
+        return Err(Errno::EAFNOSUPPORT);
+    }
+    if ty != SOCK_DGRAM || (protocol != 0 && protocol != IPPROTO_UDP) {
+        return Err(Errno::EPROTONOSUPPORT);
+    }
+    udp::open()
+}
+
+/// Sends the `len` bytes at `buf` from socket `fd` to the `struct sockaddr_in` at `addr`.
+fn sys_sendto(
+    fd: usize,
+    buf: usize,
+    len: usize,
+    flags: usize,
+    addr: usize,
+    addr_len: usize,
+) -> Result<usize, Errno> {
+    // FIXME: No flags are supported yet.
+    if flags != 0 {
+        return Err(Errno::EINVAL);
+    }
+    if addr == 0 {
+        return Err(Errno::EDESTADDRREQ);
+    }
+    let sock = file::socket(fd)?;
+    let dst = SocketAddr::parse(user_slice(addr, addr_len)?)?;
+    udp::send_to(sock, dst, user_slice(buf, len)?)
+}
+
+/// Receives a datagram on socket `fd` into the `len` bytes at `buf`.
+///
+/// Unless `addr` is null, the sender is stored there as a `struct sockaddr_in`, truncated
+/// to the `u32` length at `addr_len`, which is set to the full length.
+fn sys_recvfrom(
+    fd: usize,
+    buf: usize,
+    len: usize,
+    flags: usize,
+    addr: usize,
+    addr_len: usize,
+) -> Result<usize, Errno> {
+    if flags != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let sock = file::socket(fd)?;
+    let buf = user_slice_mut(buf, len)?;
+    let addr_out = match addr {
+        0 => None,
+        _ => {
+            let addr_len = user_slice_mut(addr_len, size_of::<u32>())?;
+            let max = u32::from_ne_bytes(addr_len[..].try_into().unwrap()) as usize;
+            Some((user_slice_mut(addr, max.min(SocketAddr::LEN))?, addr_len))
+        }
+    };
+
+    let (n, from) = udp::recv_from(sock, buf)?;
+
+    if let Some((addr, addr_len)) = addr_out {
+        addr.copy_from_slice(&from.to_bytes()[..addr.len()]);
+        addr_len.copy_from_slice(&(SocketAddr::LEN as u32).to_ne_bytes());
+    }
+
+    Ok(n)
+}
+
+/// Reads the NULL-terminated array of string pointers at `addr` into `out`, returning the
+/// part of `out` used. Fails with `E2BIG` if it has more than `out` holds.
+fn user_strings<'a, 'b>(
+    addr: usize,
+    out: &'b mut [&'a [u8]; EXEC_STRINGS_MAX],
+) -> Result<&'b [&'a [u8]], Errno> {
+    let word = size_of::<usize>();
+    if addr == 0 {
+        return Ok(&out[..0]);
+    }
+    if !addr.is_multiple_of(word) {
+        return Err(Errno::EFAULT);
+    }
+    let mut n = 0;
+    loop {
+        let pointers = user_slice(addr + n * word, word)?;
+        let string = usize::from_ne_bytes(pointers.try_into().unwrap());
+        if string == 0 {
+            return Ok(&out[..n]);
+        }
+        *out.get_mut(n).ok_or(Errno::E2BIG)? = user_cstr(string)?;
+        n += 1;
+    }
+}
+
+/// Returns the NUL-terminated string at `addr`, without the NUL. Fails with `E2BIG` if it
+/// is longer than `elf::ARG_MAX`.
+fn user_cstr<'a>(addr: usize) -> Result<&'a [u8], Errno> {
+    // Checked a page at a time, as the string may end just before an unmapped one.
+    let mut len = 0;
+    while len < elf::ARG_MAX {
+        let start = addr.checked_add(len).ok_or(Errno::EFAULT)?;
+        let chunk = (PAGE_SIZE - start % PAGE_SIZE).min(elf::ARG_MAX - len);
+        match user_slice(start, chunk)?.iter().position(|&c| c == 0) {
+            Some(end) => return user_slice(addr, len + end),
+            None => len += chunk,
+        }
+    }
+    Err(Errno::E2BIG)
+}
+
+/// Returns the `len` bytes at `addr` in the calling process's memory, if it can read them.
+///
+/// Only valid while the syscall runs, see `uaccess`.
+pub fn user_slice<'a>(addr: usize, len: usize) -> Result<&'a [u8], Errno> {
+    uaccess::check(addr, len, Access::Read)?;
+    Ok(unsafe { slice::from_raw_parts(addr as *const u8, len) })
+}
+
+/// Returns the path of `len` bytes at `addr` in the calling process's memory, if it can
+/// read them. Paths that aren't UTF-8 name no file.
+fn user_path<'a>(addr: usize, len: usize) -> Result<&'a str, Errno> {
+    core::str::from_utf8(user_slice(addr, len)?).map_err(|_| Errno::ENOENT)
+}
+
+/// Returns the `len` bytes at `addr` in the calling process's memory, if it can write them.
+pub fn user_slice_mut<'a>(addr: usize, len: usize) -> Result<&'a mut [u8], Errno> {
+    uaccess::check(addr, len, Access::Write)?;
+    Ok(unsafe { slice::from_raw_parts_mut(addr as *mut u8, len) })
+}
@@ -0,0 +1,123 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    aging, block, cmdline, kassert, macros, println, proc, slab, syscall::Errno, tracepoint,
+};
+
+// Kernel parameters that can be changed while it runs, like Linux's sysctls. Each is a
+// `Tunable` owned by the module it tunes, listed in `TUNABLES`, and shown as a file in
+// `/proc/sys`: reading it gives the value, writing a number sets it, e.g. with the
+// `sysctl` program, or from boot with `sysctl.<name>=<value>` on the command line. Values
+// are checked against the tunable's range, and its callback, if any, runs after a change.
+
+/// A number the kernel can be told to use instead of its default.
+pub struct Tunable {
+    pub name: &'static str,
+    value: AtomicUsize,
+    min: usize,
+    max: usize,
+    on_change: Option<fn(usize)>,
+}
+
+impl Tunable {
+    pub const fn new(name: &'static str, default: usize, min: usize, max: usize) -> Self {
+        Self {
+            name,
+            value: AtomicUsize::new(default),
+            min,
+            max,
+            on_change: None,
+        }
+    }
+
+    /// Has `f` called with the new value whenever it changes.
+    pub const fn on_change(self, f: fn(usize)) -> Self {
+        Self {
+            on_change: Some(f),
+            ..self
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Fails with `EINVAL` if `value` is out of range.
+    pub fn set(&self, value: usize) -> Result<(), Errno> {
+        if !(self.min..=self.max).contains(&value) {
+            return Err(Errno::EINVAL);
+        }
+        if self.value.swap(value, Ordering::Relaxed) != value
+            && let Some(f) = self.on_change
+        {
+            f(value);
+        }
+        Ok(())
+    }
+
+    /// Sets the value written as text, surrounding whitespace (like `echo`'s newline)
+    /// ignored.
+    pub fn parse(&self, s: &str) -> Result<(), Errno> {
+        self.set(s.trim().parse().map_err(|_| Errno::EINVAL)?)
+    }
+}
+
+/// Every tunable, in the order `/proc/sys` lists them.
+pub const TUNABLES: [&Tunable; 7] = [
+    &macros::LOGLEVEL,
+    &proc::TIMESLICE,
+    &block::READAHEAD_SECTORS,
+    &aging::SCAN_MS,
+    &slab::DEBUG,
+    &kassert::MODE,
+    &tracepoint::TRACEPOINTS,
+];
+
+pub fn find(name: &str) -> Option<&'static Tunable> {
+    TUNABLES.iter().copied().find(|t| t.name == name)
+}
+
+crate::register_subsystem!(Core, "sysctl", init, after("cmdline"));
+
+fn init() {
+    for option in cmdline::as_str().split_whitespace() {
+        let Some((name, value)) = option
+            .strip_prefix("sysctl.")
+            .and_then(|o| o.split_once('='))
+        else {
+            continue;
+        };
+        if let Err(err) = find(name).ok_or(Errno::ENOENT).and_then(|t| t.parse(value)) {
+            println!("sysctl: can't set {name} to '{value}': {err:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static CHANGED: AtomicUsize = AtomicUsize::new(0);
+
+    #[test_case]
+    fn values_are_range_checked() {
+        static TUNABLE: Tunable =
+            Tunable::new("test", 5, 1, 10).on_change(|v| CHANGED.store(v, Ordering::Relaxed));
+
+        assert_eq!(TUNABLE.parse("7\n"), Ok(()));
+        assert_eq!(TUNABLE.get(), 7);
+        assert_eq!(CHANGED.load(Ordering::Relaxed), 7);
+
+        assert_eq!(TUNABLE.parse("11"), Err(Errno::EINVAL));
+        assert_eq!(TUNABLE.parse("0"), Err(Errno::EINVAL));
+        assert_eq!(TUNABLE.parse("-1"), Err(Errno::EINVAL));
+        assert_eq!(TUNABLE.parse("seven"), Err(Errno::EINVAL));
+        assert_eq!(TUNABLE.get(), 7);
+    }
+
+    #[test_case]
+    fn tunables_are_found_by_name() {
+        assert_eq!(find("loglevel").map(|t| t.get()), Some(macros::loglevel()));
+        assert!(find("nonexistent").is_none());
+    }
+}
@@ -0,0 +1,364 @@
+use crate::{
+    cred, dtb,
+    mem::PhysAddr,
+    println,
+    stdlib::phalloc_uninit,
+    sync::OnceCell,
+    syscall::Errno,
+    vfs::{self, DirEntry, FileSystem, Metadata, NAME_MAX},
+};
+
+// A read-only filesystem over a tar archive (POSIX ustar) in memory, mounted at `/` from
+// the initrd the boot loader passes (QEMU's `-initrd`). `user/mkinitrd.sh` packs the user
+// programs into one.
+//
+// Files are found by scanning the headers, an inode is the index of a file's header block
+// plus one, 0 being the root. Directories need entries of their own, which `tar` creates
+// when it archives a directory. Files keep the mode and owner they were archived with.
+// Symbolic links are files of their own, hard links are looked up as the file they link to.
+
+const BLOCK_SIZE: usize = 512;
+const ROOT: usize = 0;
+// Longest path kept, including a ustar prefix.
+const PATH_MAX: usize = 256;
+
+// Fields of a header block.
+const NAME: core::ops::Range<usize> = 0..100;
+const MODE: core::ops::Range<usize> = 100..108;
+const UID: core::ops::Range<usize> = 108..116;
+const GID: core::ops::Range<usize> = 116..124;
+const SIZE: core::ops::Range<usize> = 124..136;
+const TYPEFLAG: usize = 156;
+const LINKNAME: core::ops::Range<usize> = 157..257;
+const MAGIC: core::ops::Range<usize> = 257..262;
+const PREFIX: core::ops::Range<usize> = 345..500;
+
+const TYPE_HARDLINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIR: u8 = b'5';
+
+/// A file or directory of the archive.
+struct Entry {
+    ino: usize,
+    path: [u8; PATH_MAX],
+    path_len: usize,
+    is_dir: bool,
+    typeflag: u8,
+    meta: Metadata,
+    // Offset of the contents in the archive.
+    data: usize,
+    size: usize,
+}
+
+impl Entry {
+    /// Returns the path without leading `./` or `/`, or a trailing `/`. The root is "".
+    fn path(&self) -> &str {
+        // Checked to be UTF-8 by `entry()`.
+        normalize(unsafe { core::str::from_utf8_unchecked(&self.path[..self.path_len]) })
+    }
+
+    /// Returns the directory the entry is in and its name.
+    fn split(&self) -> (&str, &str) {
+        self.path().rsplit_once('/').unwrap_or(("", self.path()))
+    }
+}
+
+/// Returns `path` without leading `./` or `/`, or a trailing `/`, as paths of entries and
+/// hard link targets are compared. The root is "".
+fn normalize(path: &str) -> &str {
+    let mut path = path.trim_end_matches('/');
+    loop {
+        match path.strip_prefix("./").or_else(|| path.strip_prefix('/')) {
+            Some(rest) => path = rest,
+            None if path == "." => return "",
+            None => return path,
+        }
+    }
+}
+
+/// Returns the NUL-terminated field, the whole of it if it fills the field.
+fn field(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..len]
+}
+
+fn octal(bytes: &[u8]) -> Option<usize> {
+    let digits = core::str::from_utf8(field(bytes)).ok()?.trim();
+    usize::from_str_radix(digits, 8).ok()
+}
+
+struct TarFs {
+    archive: &'static [u8],
+}
+
+impl TarFs {
+    /// Returns the entry whose header is block `ino - 1`, `None` at the end of the archive.
+    fn entry(&self, ino: usize) -> Option<Entry> {
+        let offset = ino.checked_sub(1)? * BLOCK_SIZE;
+        let header = self.archive.get(offset..offset + BLOCK_SIZE)?;
+        if &header[MAGIC] != b"ustar" {
+            return None;
+        }
+
+        let is_dir = header[TYPEFLAG] == TYPE_DIR;
+        let mut entry = Entry {
+            ino,
+            path: [0; PATH_MAX],
+            path_len: 0,
+            is_dir,
+            typeflag: header[TYPEFLAG],
+            // Archivers that leave them out get the defaults of `FileSystem::metadata()`.
+            meta: Metadata {
+                mode: octal(&header[MODE]).unwrap_or(if is_dir { 0o755 } else { 0o644 }) & 0o7777,
+                uid: octal(&header[UID]).unwrap_or(cred::ROOT),
+                gid: octal(&header[GID]).unwrap_or(cred::ROOT),
+            },
+            data: offset + BLOCK_SIZE,
+            size: octal(&header[SIZE])?,
+        };
+        let (prefix, name) = (field(&header[PREFIX]), field(&header[NAME]));
+        for part in [prefix, b"/".as_slice(), name] {
+            if prefix.is_empty() && part == b"/" {
+                continue;
+            }
+            entry.path[entry.path_len..entry.path_len + part.len()].copy_from_slice(part);
+            entry.path_len += part.len();
+        }
+        core::str::from_utf8(&entry.path[..entry.path_len]).ok()?;
+        Some(entry)
+    }
+
+    /// Calls `f` with every entry of the archive until it returns `Some`.
+    fn find<T>(&self, mut f: impl FnMut(&Entry) -> Option<T>) -> Option<T> {
+        let mut ino = 1;
+        while let Some(entry) = self.entry(ino) {
+            if let Some(found) = f(&entry) {
+                return Some(found);
+            }
+            ino += 1 + entry.size.div_ceil(BLOCK_SIZE);
+        }
+        None
+    }
+
+    /// Returns the link name of entry `ino`, the target of a link.
+    fn link_name(&self, ino: usize) -> Option<&str> {
+        let header = (ino.checked_sub(1)?) * BLOCK_SIZE;
+        core::str::from_utf8(field(&self.archive[header..][LINKNAME])).ok()
+    }
+
+    /// Returns the path of directory `dir`.
+    fn dir_path(&self, dir: usize, out: &mut [u8; PATH_MAX]) -> Result<usize, Errno> {
+        if dir == ROOT {
+            return Ok(0);
+        }
+        let entry = self.entry(dir).ok_or(Errno::ENOENT)?;
+        if !entry.is_dir {
+            return Err(Errno::ENOTDIR);
+        }
+        let path = entry.path().as_bytes();
+        out[..path.len()].copy_from_slice(path);
+        Ok(path.len())
+    }
+}
+
+impl FileSystem for TarFs {
+    fn root(&self) -> usize {
+        ROOT
+    }
+
+    fn lookup(&self, dir: usize, name: &str) -> Result<usize, Errno> {
+        let mut dir_path = [0; PATH_MAX];
+        let len = self.dir_path(dir, &mut dir_path)?;
+        let dir_path = &dir_path[..len];
+
+        let entry = self
+            .find(|entry| {
+                let (parent, entry_name) = entry.split();
+                (parent.as_bytes() == dir_path && entry_name == name && !entry.path().is_empty())
+                    .then_some((entry.ino, entry.typeflag))
+            })
+            .ok_or(Errno::ENOENT)?;
+        match entry {
+            (ino, TYPE_HARDLINK) => {
+                // Always to a file archived before the link.
+                let target = normalize(self.link_name(ino).ok_or(Errno::ENOENT)?);
+                self.find(|entry| (entry.path() == target).then_some(entry.ino))
+                    .ok_or(Errno::ENOENT)
+            }
+            (ino, _) => Ok(ino),
+        }
+    }
+
+    fn is_dir(&self, ino: usize) -> bool {
+        ino == ROOT || self.entry(ino).is_some_and(|e| e.is_dir)
+    }
+
+    fn read(&self, ino: usize, offset: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+        let entry = self.entry(ino).ok_or(Errno::ENOENT)?;
+        let contents = &self.archive[entry.data..entry.data + entry.size];
+        let start = offset.min(contents.len());
+        let n = buf.len().min(contents.len() - start);
+        buf[..n].copy_from_slice(&contents[start..start + n]);
+        Ok(n)
+    }
+
+    fn is_symlink(&self, ino: usize) -> bool {
+        self.entry(ino).is_some_and(|e| e.typeflag == TYPE_SYMLINK)
+    }
+
+    fn readlink(&self, ino: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+        if !self.is_symlink(ino) {
+            return Err(Errno::EINVAL);
+        }
+        let target = self.link_name(ino).ok_or(Errno::EINVAL)?.as_bytes();
+        let out = buf.get_mut(..target.len()).ok_or(Errno::ENAMETOOLONG)?;
+        out.copy_from_slice(target);
+        Ok(target.len())
+    }
+
+    fn metadata(&self, ino: usize) -> Metadata {
+        match self.entry(ino) {
+            Some(entry) => entry.meta,
+            None => Metadata {
+                mode: 0o755,
+                uid: cred::ROOT,
+                gid: cred::ROOT,
+            },
+        }
+    }
+
+    fn readdir(&self, dir: usize, index: usize) -> Result<Option<DirEntry>, Errno> {
+        let mut dir_path = [0; PATH_MAX];
+        let len = self.dir_path(dir, &mut dir_path)?;
+        let dir_path = &dir_path[..len];
+
+        let mut seen = 0;
+        Ok(self.find(|entry| {
+            let (parent, name) = entry.split();
+            // Names too long for a directory entry can only be looked up.
+            if parent.as_bytes() != dir_path || name.is_empty() || name.len() > NAME_MAX {
+                return None;
+            }
+            seen += 1;
+            (seen > index).then(|| DirEntry::new(entry.ino, format_args!("{name}")))
+        }))
+    }
+}
+
+static TARFS: OnceCell<TarFs> = OnceCell::new();
+
+crate::register_subsystem!(Subsys, "tarfs", init, after("dtb", "mem"));
+
+/// Mounts the initrd at `/`, if there is one.
+fn init() {
+    let Some((start, end)) = dtb::initrd() else {
+        println!("tarfs: no initrd");
+        return;
+    };
+
+    // The boot loader's copy may be anywhere in RAM, processes only map memory the
+    // kernel allocated.
+    let len = end.saturating_sub(start);
+    let initrd = match PhysAddr::new_checked(start, Some(len)) {
+        Ok(initrd) if len > 0 => initrd,
+        result => {
+            println!("tarfs: bad initrd at {start:#x}..{end:#x}: {result:?}");
+            return;
+        }
+    };
+    let Ok(copy) = phalloc_uninit(len) else {
+        println!("tarfs: no memory for the {len} byte initrd");
+        return;
+    };
+    let archive = unsafe {
+        copy.as_mut_ptr()
+            .copy_from_nonoverlapping(initrd.as_ptr(), len);
+        core::slice::from_raw_parts(copy.as_ptr(), len)
+    };
+
+    let fs = TARFS.get_or_init(|| TarFs { archive });
+    vfs::mount("/", fs).expect("can't mount the initrd at /.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(block: &mut [u8], name: &str, size: usize, typeflag: u8) {
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        let mut size_field = [b'0'; 11];
+        let mut size = size;
+        for digit in size_field.iter_mut().rev() {
+            *digit = b'0' + (size % 8) as u8;
+            size /= 8;
+        }
+        block[SIZE.start..SIZE.start + 11].copy_from_slice(&size_field);
+        block[TYPEFLAG] = typeflag;
+        block[MAGIC].copy_from_slice(b"ustar");
+    }
+
+    #[test_case]
+    fn finds_files_and_lists_directories() {
+        // ./, ./bin/, ./bin/hi with 3 bytes, then the two zero blocks ending the archive.
+        static mut ARCHIVE: [u8; 7 * BLOCK_SIZE] = [0; 7 * BLOCK_SIZE];
+        let archive = unsafe { &mut *core::ptr::addr_of_mut!(ARCHIVE) };
+        header(&mut archive[..BLOCK_SIZE], "./", 0, TYPE_DIR);
+        header(&mut archive[BLOCK_SIZE..], "./bin/", 0, TYPE_DIR);
+        header(&mut archive[2 * BLOCK_SIZE..], "./bin/hi", 3, b'0');
+        archive[3 * BLOCK_SIZE..3 * BLOCK_SIZE + 3].copy_from_slice(b"hi\n");
+        archive[2 * BLOCK_SIZE + MODE.start..][..7].copy_from_slice(b"0000755");
+        archive[2 * BLOCK_SIZE + UID.start..][..7].copy_from_slice(b"0001750");
+        let fs = TarFs { archive };
+
+        let bin = fs.lookup(ROOT, "bin").unwrap();
+        assert!(fs.is_dir(bin));
+        let hi = fs.lookup(bin, "hi").unwrap();
+        assert!(!fs.is_dir(hi));
+        let meta = fs.metadata(hi);
+        assert_eq!((meta.mode, meta.uid, meta.gid), (0o755, 1000, cred::ROOT));
+        assert_eq!(fs.metadata(bin).mode, 0o755);
+        assert_eq!(fs.lookup(ROOT, "hi"), Err(Errno::ENOENT));
+        assert_eq!(fs.lookup(hi, "x"), Err(Errno::ENOTDIR));
+
+        let mut buf = [0; 8];
+        assert_eq!(fs.read(hi, 1, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"i\n");
+
+        for (dir, name) in [(ROOT, "bin"), (bin, "hi")] {
+            assert_eq!(fs.readdir(dir, 0).unwrap().unwrap().name(), name);
+            assert!(fs.readdir(dir, 1).unwrap().is_none());
+        }
+    }
+
+    #[test_case]
+    fn follows_hard_links_but_not_symlinks() {
+        // ./bin/, ./bin/hi with 3 bytes, ./bin/sh -> hi, ./bin/hi2 linked to ./bin/hi.
+        static mut ARCHIVE: [u8; 7 * BLOCK_SIZE] = [0; 7 * BLOCK_SIZE];
+        let archive = unsafe { &mut *core::ptr::addr_of_mut!(ARCHIVE) };
+        header(&mut archive[..BLOCK_SIZE], "./bin/", 0, TYPE_DIR);
+        header(&mut archive[BLOCK_SIZE..], "./bin/hi", 3, b'0');
+        archive[2 * BLOCK_SIZE..2 * BLOCK_SIZE + 3].copy_from_slice(b"hi\n");
+        header(&mut archive[3 * BLOCK_SIZE..], "./bin/sh", 0, TYPE_SYMLINK);
+        archive[3 * BLOCK_SIZE + LINKNAME.start..][..2].copy_from_slice(b"hi");
+        header(
+            &mut archive[4 * BLOCK_SIZE..],
+            "./bin/hi2",
+            0,
+            TYPE_HARDLINK,
+        );
+        archive[4 * BLOCK_SIZE + LINKNAME.start..][..8].copy_from_slice(b"./bin/hi");
+        let fs = TarFs { archive };
+
+        let bin = fs.lookup(ROOT, "bin").unwrap();
+        let hi = fs.lookup(bin, "hi").unwrap();
+        assert_eq!(fs.lookup(bin, "hi2"), Ok(hi));
+        let sh = fs.lookup(bin, "sh").unwrap();
+        assert!(fs.is_symlink(sh));
+        assert!(!fs.is_symlink(hi));
+
+        let mut buf = [0; 8];
+        assert_eq!(fs.readlink(sh, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"hi");
+        assert_eq!(fs.readlink(hi, &mut buf), Err(Errno::EINVAL));
+    }
+}
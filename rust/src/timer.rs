@@ -0,0 +1,246 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use core::arch::asm;
+
+use crate::{
+    MAX_HARTS, alarm, cmdline, cpu,
+    csr::{self, Sie, Sstatus},
+    hart_id, loadavg, net, println, proc, profile, sbi,
+    sync::OnceCell,
+    tty, watchdog,
+};
+
+/// Frequency of the `time` CSR on QEMU virt.
+// FIXME: Should be read from `/cpus/timebase-frequency` in the dtb
+pub const TIMEBASE_FREQ: u64 = 10_000_000;
+
+/// Number of timer interrupts per second, unless overridden with `hz=` on the command line.
+pub const DEFAULT_HZ: usize = 100;
+const MAX_HZ: usize = 1000;
+/// Longest an idle hart goes without a tick. Typed input and network interfaces are only
+/// polled, so they would wait as long.
+const IDLE_MAX_MS: usize = 100;
+
+// Number of timer ticks on hart 0 since `init()`.
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+static HZ: AtomicUsize = AtomicUsize::new(DEFAULT_HZ);
+// Ticks any hart spent in the idle process.
+static IDLE_TICKS: AtomicUsize = AtomicUsize::new(0);
+// Low half of `time` at each hart's last tick. Ticks are counted from here rather than by
+// interrupt, so none are lost while an idle hart skips them.
+static LAST_TICK: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+// Whether idle harts may skip ticks, unless `nohz=off` is on the command line.
+static NOHZ: AtomicBool = AtomicBool::new(true);
+
+/// Raises a hart's timer interrupt at a given time.
+pub trait ClockEvent: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Makes the calling hart's timer interrupt pending once `time` reaches `deadline`,
+    /// replacing the deadline set before, and clears it until then.
+    fn set_next_event(&self, deadline: u64);
+}
+
+/// The SBI TIME extension, which every firmware has, at the cost of an `ecall` each time.
+struct SbiTimer;
+
+impl ClockEvent for SbiTimer {
+    fn name(&self) -> &'static str {
+        "sbi"
+    }
+
+    fn set_next_event(&self, deadline: u64) {
+        sbi::set_timer(deadline);
+    }
+}
+
+/// The Sstc extension's `stimecmp`, written directly.
+struct Sstc;
+
+impl ClockEvent for Sstc {
+    fn name(&self) -> &'static str {
+        "sstc"
+    }
+
+    fn set_next_event(&self, deadline: u64) {
+        csr::set_stimecmp(deadline);
+    }
+}
+
+// Chosen by `init()` for all harts, from the boot hart's extensions.
+static CLOCK_EVENT: OnceCell<&'static dyn ClockEvent> = OnceCell::new();
+
+/// Returns what programs the timer interrupts: `stimecmp` if the boot hart has Sstc, the
+/// SBI otherwise, or with `clockevent=sbi` on the command line.
+pub fn clock_event() -> &'static dyn ClockEvent {
+    *CLOCK_EVENT.get_or_init(|| {
+        match cpu::current().has_named("sstc") && cmdline::get("clockevent") != Some("sbi") {
+            true => &Sstc,
+            false => &SbiTimer,
+        }
+    })
+}
+
+/// Returns the current value of the `time` CSR.
+#[cfg(target_arch = "riscv32")]
+pub fn now() -> u64 {
+    // `time` is read in two halves on rv32, retry if the low half wrapped in between.
+    loop {
+        let hi = csr::timeh();
+        let lo = csr::time();
+        if hi == csr::timeh() {
+            return ((hi as u64) << 32) | lo as u64;
+        }
+    }
+}
+
+#[cfg(target_arch = "riscv64")]
+pub fn now() -> u64 {
+    csr::time() as u64
+}
+
+/// Returns the number of timer ticks since the timer was initialized.
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of ticks the harts spent idle, summed up over all of them.
+pub fn idle_ticks() -> usize {
+    IDLE_TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of timer interrupts per second.
+pub fn hz() -> usize {
+    HZ.load(Ordering::Relaxed)
+}
+
+/// Converts `ms` milliseconds to timer ticks, rounding up.
+pub fn ms_to_ticks(ms: usize) -> usize {
+    (ms * hz()).div_ceil(1000)
+}
+
+/// Returns the length of a tick in `time` units.
+fn period() -> usize {
+    TIMEBASE_FREQ as usize / hz()
+}
+
+/// Programs the calling hart's timer for `ticks` ticks after its last one, right away if
+/// that has passed.
+fn program_tick(ticks: usize) {
+    let now = now();
+    let due = LAST_TICK[hart_id()].load(Ordering::Relaxed) + ticks * period();
+    let wait = due.wrapping_sub(now as usize) as isize;
+    clock_event().set_next_event(now + wait.max(0) as u64);
+}
+
+/// Returns how many ticks have passed on the calling hart since its last one, at least one,
+/// and moves the last one forward by as many.
+fn elapsed_ticks() -> usize {
+    let last = &LAST_TICK[hart_id()];
+    let since = (now() as usize).wrapping_sub(last.load(Ordering::Relaxed));
+    let ticks = (since / period()).max(1);
+    last.fetch_add(ticks * period(), Ordering::Relaxed);
+    ticks
+}
+
+/// Returns the earliest tick anything waits for.
+fn next_deadline() -> Option<usize> {
+    [
+        proc::next_wakeup(),
+        alarm::next_deadline(),
+        Some(loadavg::next_sample()),
+    ]
+    .into_iter()
+    .flatten()
+    .min()
+}
+
+/// Waits for an interrupt on an idle hart.
+///
+/// Rather than every tick, the timer wakes it when the next deadline is due, if that is
+/// later and `IDLE_MAX_MS` isn't exceeded. The ticks skipped are counted once it runs again.
+pub fn idle() {
+    // Deadlines must not change between looking at them and sleeping.
+    Sstatus::clear_sie();
+
+    let ticks = ticks();
+    let skip = match NOHZ.load(Ordering::Relaxed) {
+        true => next_deadline()
+            .unwrap_or(usize::MAX)
+            .min(ticks + ms_to_ticks(IDLE_MAX_MS))
+            .saturating_sub(ticks)
+            .max(1),
+        false => 1,
+    };
+    program_tick(skip);
+    unsafe { asm!("wfi") };
+
+    // Woken by something else, the tick is due as usual. If it has passed, the interrupt is
+    // taken right away and counts the ticks skipped.
+    program_tick(1);
+    Sstatus::set_sie();
+}
+
+crate::register_subsystem!(Arch, "timer", init, after("cpu", "cmdline"));
+
+/// Starts the periodic timer interrupt on the calling hart.
+fn init() {
+    if let Some(hz) = cmdline::get_usize("hz") {
+        HZ.store(hz.clamp(1, MAX_HZ), Ordering::Relaxed);
+    }
+    if cmdline::get("nohz") == Some("off") {
+        NOHZ.store(false, Ordering::Relaxed);
+    }
+
+    println!("timer: {} Hz, events by {}", hz(), clock_event().name());
+    LAST_TICK[hart_id()].store(now() as usize, Ordering::Relaxed);
+    program_tick(1);
+    Sie::set_stie();
+    Sstatus::set_sie();
+}
+
+/// Handles a supervisor timer interrupt that arrived while executing at `sepc`.
+pub fn on_tick(sepc: usize) {
+    crate::tracepoint!(TimerTick, sepc);
+    profile::sample(sepc);
+    let elapsed = elapsed_ticks();
+    // Hart 0 keeps the global time, other harts only run their periodic work.
+    if hart_id() == 0 {
+        let ticks = TICKS.fetch_add(elapsed, Ordering::Relaxed) + elapsed;
+        proc::wake_sleepers(ticks);
+        alarm::on_tick(ticks);
+        tty::on_tick();
+        loadavg::on_tick(ticks);
+        net::on_tick();
+    }
+    if proc::current() == 0 {
+        IDLE_TICKS.fetch_add(elapsed, Ordering::Relaxed);
+    }
+    program_tick(1);
+    watchdog::on_tick(sepc);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn idle_harts_wake_for_the_next_deadline() {
+        // A pid no process uses while tests run.
+        let pid = proc::PROC_MAX - 1;
+        let tick_ms = 1000 / hz();
+
+        alarm::set(
+            pid,
+            alarm::Itimer {
+                value: 2 * tick_ms,
+                interval: 0,
+            },
+        );
+        assert!(next_deadline().is_some_and(|next| next <= ticks() + 2));
+
+        // Nothing else waits while tests run.
+        alarm::reset(pid);
+        assert_eq!(next_deadline(), Some(loadavg::next_sample()));
+    }
+}
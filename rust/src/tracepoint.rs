@@ -0,0 +1,302 @@
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use crate::{MAX_HARTS, cmdline, hart_id, ksyms, print, println, proc, sysctl::Tunable, timer};
+
+// Tracepoints: `tracepoint!(Event, args...)` call sites that, when their event is enabled,
+// append a small binary record to a ring buffer of the current hart. Nothing is formatted
+// until the buffers are dumped, so tracing the scheduler barely changes its timing.
+//
+// Events are enabled with `tracepoints=` on the command line, a comma separated list of
+// event names or `all`, and with `enable()` and `disable()` at runtime, which writing a mask
+// of them to `/proc/sys/tracepoints` does.
+
+/// Largest number of arguments a record holds.
+pub const MAX_ARGS: usize = 3;
+
+// Records kept per hart, older ones are overwritten.
+const BUFFER_LEN: usize = 256;
+
+/// Something that happened, recorded by `tracepoint!` if enabled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Event {
+    /// The scheduler switched from process `prev` to `next`.
+    SchedSwitch,
+    /// The processes in mask `pids` were made runnable.
+    Wakeup,
+    /// Syscall `nr` was entered.
+    SyscallEnter,
+    /// Syscall `nr` returned `ret`, negative for errors.
+    SyscallExit,
+    /// Timer interrupt at `sepc`.
+    TimerTick,
+}
+
+const EVENTS: [Event; 5] = [
+    Event::SchedSwitch,
+    Event::Wakeup,
+    Event::SyscallEnter,
+    Event::SyscallExit,
+    Event::TimerTick,
+];
+const ALL_EVENTS: usize = (1 << EVENTS.len()) - 1;
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::SchedSwitch => "sched_switch",
+            Event::Wakeup => "wakeup",
+            Event::SyscallEnter => "syscall_enter",
+            Event::SyscallExit => "syscall_exit",
+            Event::TimerTick => "timer_tick",
+        }
+    }
+
+    // Arguments that are kernel addresses, printed as symbols by `dump()`.
+    fn address_args(self) -> &'static [usize] {
+        match self {
+            Event::TimerTick => &[0],
+            _ => &[],
+        }
+    }
+
+    // Names of the arguments, printed by `dump()`.
+    fn arg_names(self) -> &'static [&'static str] {
+        match self {
+            Event::SchedSwitch => &["prev", "next"],
+            Event::Wakeup => &["pids"],
+            Event::SyscallEnter => &["nr"],
+            Event::SyscallExit => &["nr", "ret"],
+            Event::TimerTick => &["sepc"],
+        }
+    }
+
+    /// Returns the event called `name`.
+    pub fn from_name(name: &str) -> Option<Event> {
+        EVENTS.into_iter().find(|e| e.name() == name)
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Record {
+    // Value of the `time` CSR.
+    time: u64,
+    event: Event,
+    pid: u8,
+    nargs: u8,
+    args: [usize; MAX_ARGS],
+}
+
+const EMPTY: Record = Record {
+    time: 0,
+    event: Event::SchedSwitch,
+    pid: 0,
+    nargs: 0,
+    args: [0; MAX_ARGS],
+};
+
+struct Buffer {
+    records: UnsafeCell<[Record; BUFFER_LEN]>,
+    // Records ever written, the next one goes to `written % BUFFER_LEN`.
+    written: AtomicUsize,
+}
+
+// Each buffer is only written by its own hart, which reserves a slot before writing it, so
+// a record written by an interrupt handler never overwrites one being written.
+unsafe impl Sync for Buffer {}
+
+static BUFFERS: [Buffer; MAX_HARTS] = [const {
+    Buffer {
+        records: UnsafeCell::new([EMPTY; BUFFER_LEN]),
+        written: AtomicUsize::new(0),
+    }
+}; MAX_HARTS];
+
+// Enabled events, as a bit mask indexed by `Event as u8`.
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+
+/// Events to record, as a bit mask indexed by `Event as u8`, `/proc/sys/tracepoints`.
+pub static TRACEPOINTS: Tunable = Tunable::new("tracepoints", 0, 0, ALL_EVENTS).on_change(|mask| {
+    for event in EVENTS {
+        match mask & (1 << event as u8) != 0 {
+            true => enable(event),
+            false => disable(event),
+        }
+    }
+});
+
+/// Records `$event` with up to `MAX_ARGS` arguments if it is enabled, doing nothing but a
+/// load and a branch otherwise.
+#[macro_export]
+macro_rules! tracepoint {
+    ($event:ident $(, $arg:expr)* $(,)?) => {{
+        const {
+            assert!(
+                <[&str]>::len(&[$(stringify!($arg)),*]) <= $crate::tracepoint::MAX_ARGS,
+                "too many tracepoint arguments"
+            );
+        }
+        let event = $crate::tracepoint::Event::$event;
+        if $crate::tracepoint::is_enabled(event) {
+            $crate::tracepoint::record(event, &[$($arg as usize),*]);
+        }
+    }};
+}
+
+pub fn is_enabled(event: Event) -> bool {
+    ENABLED.load(Ordering::Relaxed) & (1 << event as u8) != 0
+}
+
+pub fn enable(event: Event) {
+    ENABLED.fetch_or(1 << event as u8, Ordering::Relaxed);
+}
+
+pub fn disable(event: Event) {
+    ENABLED.fetch_and(!(1 << event as u8), Ordering::Relaxed);
+}
+
+/// Appends a record of `event` to the calling hart's buffer, use `tracepoint!` instead.
+pub fn record(event: Event, args: &[usize]) {
+    let mut record = Record {
+        time: timer::now(),
+        event,
+        pid: proc::current() as u8,
+        nargs: args.len() as u8,
+        args: [0; MAX_ARGS],
+    };
+    record.args[..args.len()].copy_from_slice(args);
+
+    let buffer = &BUFFERS[hart_id()];
+    let slot = buffer.written.fetch_add(1, Ordering::Relaxed) % BUFFER_LEN;
+    unsafe {
+        (buffer.records.get() as *mut Record)
+            .add(slot)
+            .write_volatile(record)
+    };
+}
+
+crate::register_subsystem!(Core, "tracepoint", init, after("cmdline"));
+
+/// Enables the events listed with `tracepoints=` on the command line.
+fn init() {
+    let Some(list) = cmdline::get("tracepoints") else {
+        return;
+    };
+
+    let mut mask = 0;
+    for name in list.split(',') {
+        match name {
+            "all" => mask = ALL_EVENTS,
+            _ => match Event::from_name(name) {
+                Some(event) => mask |= 1 << event as u8,
+                None => println!("tracepoint: unknown event {name}"),
+            },
+        }
+    }
+    let _ = TRACEPOINTS.set(mask);
+}
+
+/// Prints the records of every hart, oldest first, and empties the buffers.
+///
+/// Tracing is paused meanwhile, records written by other harts while dumping may still
+/// come out garbled.
+pub fn dump() {
+    let enabled = ENABLED.swap(0, Ordering::Relaxed);
+
+    for (hart, buffer) in BUFFERS.iter().enumerate() {
+        let written = buffer.written.swap(0, Ordering::Relaxed);
+        if written == 0 {
+            continue;
+        }
+
+        let kept = written.min(BUFFER_LEN);
+        println!(
+            "tracepoint: hart {hart}, {kept} records ({} overwritten)",
+            written - kept
+        );
+        for i in written - kept..written {
+            let record = unsafe {
+                (buffer.records.get() as *const Record)
+                    .add(i % BUFFER_LEN)
+                    .read_volatile()
+            };
+            print_record(&record);
+        }
+    }
+
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+fn print_record(record: &Record) {
+    let us = record.time * 1_000_000 / timer::TIMEBASE_FREQ;
+    print!(
+        "[{:5}.{:06}] pid {} {}:",
+        us / 1_000_000,
+        us % 1_000_000,
+        record.pid,
+        record.event.name()
+    );
+    let names = record.event.arg_names();
+    for (i, &arg) in record.args[..record.nargs as usize].iter().enumerate() {
+        if let Some(name) = names.get(i) {
+            print!(" {name}=");
+        } else {
+            print!(" ");
+        }
+        if record.event.address_args().contains(&i) {
+            print!("{}", ksyms::resolve(arg));
+        } else {
+            print!("{arg:#x}");
+        }
+    }
+    println!("");
+}
+
+/// Dumps the buffers if any event is enabled, called when the kernel panics.
+pub fn dump_on_panic() {
+    if ENABLED.load(Ordering::Relaxed) != 0 {
+        dump();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn records_only_enabled_events() {
+        let buffer = &BUFFERS[hart_id()];
+        let was_enabled = is_enabled(Event::Wakeup);
+        let before = buffer.written.load(Ordering::Relaxed);
+
+        disable(Event::Wakeup);
+        crate::tracepoint!(Wakeup, 0b10);
+        assert_eq!(buffer.written.load(Ordering::Relaxed), before);
+
+        enable(Event::Wakeup);
+        crate::tracepoint!(Wakeup, 0b10);
+        // Other enabled events may be recorded meanwhile, e.g. by the timer interrupt.
+        let written = buffer.written.load(Ordering::Relaxed);
+        let records = unsafe { &*buffer.records.get() };
+        let record = (before..written)
+            .map(|i| records[i % BUFFER_LEN])
+            .find(|r| r.event == Event::Wakeup && r.args[0] == 0b10)
+            .unwrap();
+        assert_eq!(record.nargs, 1);
+        assert_eq!(record.pid as usize, proc::current());
+
+        if !was_enabled {
+            disable(Event::Wakeup);
+        }
+    }
+
+    #[test_case]
+    fn finds_events_by_name() {
+        assert_eq!(Event::from_name("sched_switch"), Some(Event::SchedSwitch));
+        assert_eq!(Event::from_name("nope"), None);
+    }
+}
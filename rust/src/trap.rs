@@ -1,7 +1,31 @@
-use core::arch::naked_asm;
+use core::{
+    arch::{asm, naked_asm},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use crate::read_csr;
+use crate::{
+    MAX_HARTS, crash,
+    csr::{self, Privilege, Scause, Sstatus},
+    emulate, extable, hart_id, irq, load_reg, misaligned, println, proc, random, reg_bytes, signal,
+    store_reg, syscall, timer, uaccess, workqueue,
+};
 
+pub const IRQ_S_SOFT: usize = 1;
+pub const IRQ_S_TIMER: usize = 5;
+pub const IRQ_S_EXTERNAL: usize = 9;
+// Interrupt causes counted, the standard ones are below 16.
+pub const IRQ_CAUSES: usize = 16;
+const EXC_ILLEGAL_INSN: usize = 2;
+const EXC_LOAD_MISALIGNED: usize = 4;
+const EXC_STORE_MISALIGNED: usize = 6;
+const EXC_USER_ECALL: usize = 8;
+const EXC_INSN_PAGE_FAULT: usize = 12;
+const EXC_LOAD_PAGE_FAULT: usize = 13;
+const EXC_STORE_PAGE_FAULT: usize = 15;
+#[cfg(feature = "gdb")]
+const EXC_BREAKPOINT: usize = 3;
+
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct TrapFrame {
     ra: usize,
@@ -37,93 +61,410 @@ pub struct TrapFrame {
     sp: usize,
 }
 
+impl TrapFrame {
+    /// Returns a trap frame with all registers set to zero.
+    pub const fn zeroed() -> Self {
+        unsafe { core::mem::zeroed() }
+    }
+
+    /// Returns general-purpose register `x<n>`, `x0` always reads as zero.
+    pub fn reg(&self, n: usize) -> usize {
+        match n {
+            1 => self.ra,
+            2 => self.sp,
+            3 => self.gp,
+            4 => self.tp,
+            5 => self.t0,
+            6 => self.t1,
+            7 => self.t2,
+            8 => self.s0,
+            9 => self.s1,
+            10 => self.a0,
+            11 => self.a1,
+            12 => self.a2,
+            13 => self.a3,
+            14 => self.a4,
+            15 => self.a5,
+            16 => self.a6,
+            17 => self.a7,
+            18 => self.s2,
+            19 => self.s3,
+            20 => self.s4,
+            21 => self.s5,
+            22 => self.s6,
+            23 => self.s7,
+            24 => self.s8,
+            25 => self.s9,
+            26 => self.s10,
+            27 => self.s11,
+            28 => self.t3,
+            29 => self.t4,
+            30 => self.t5,
+            31 => self.t6,
+            _ => 0,
+        }
+    }
+
+    /// Sets the stack pointer.
+    pub fn set_sp(&mut self, sp: usize) {
+        self.sp = sp;
+    }
+
+    /// Returns the syscall number passed in `a7`.
+    pub fn syscall_nr(&self) -> usize {
+        self.a7
+    }
+
+    /// Returns syscall argument `n` (0 to 5), passed in `a0` to `a5`.
+    pub fn syscall_arg(&self, n: usize) -> usize {
+        self.reg(10 + n)
+    }
+
+    /// Sets the value returned to user space in `a0`.
+    pub fn set_syscall_ret(&mut self, value: isize) {
+        self.a0 = value as usize;
+    }
+
+    /// Sets general-purpose register `x<n>`, writes to `x0` are ignored.
+    pub fn set_reg(&mut self, n: usize, value: usize) {
+        match n {
+            1 => self.ra = value,
+            2 => self.sp = value,
+            3 => self.gp = value,
+            4 => self.tp = value,
+            5 => self.t0 = value,
+            6 => self.t1 = value,
+            7 => self.t2 = value,
+            8 => self.s0 = value,
+            9 => self.s1 = value,
+            10 => self.a0 = value,
+            11 => self.a1 = value,
+            12 => self.a2 = value,
+            13 => self.a3 = value,
+            14 => self.a4 = value,
+            15 => self.a5 = value,
+            16 => self.a6 = value,
+            17 => self.a7 = value,
+            18 => self.s2 = value,
+            19 => self.s3 = value,
+            20 => self.s4 = value,
+            21 => self.s5 = value,
+            22 => self.s6 = value,
+            23 => self.s7 = value,
+            24 => self.s8 = value,
+            25 => self.s9 = value,
+            26 => self.s10 = value,
+            27 => self.s11 = value,
+            28 => self.t3 = value,
+            29 => self.t4 = value,
+            30 => self.t5 = value,
+            31 => self.t6 = value,
+            _ => {}
+        }
+    }
+}
+
 #[naked]
 #[unsafe(no_mangle)]
 #[unsafe(link_section = ".text.trap_entry")]
 pub unsafe extern "C" fn trap_entry() {
-    // FIXME: Doesn't save floating-point registers
+    // Floating-point registers aren't saved here, the kernel doesn't use them. `fpu` swaps
+    // them when switching processes.
+    //
+    // A vector table, installed in `TrapMode::Vectored`: exceptions enter at the first entry
+    // and interrupts at the one for their cause, so each kind goes straight to its own
+    // handler without decoding `scause` first. The linker script keeps it 8-byte aligned,
+    // entries must be full-size jumps.
+    //
+    // sscratch holds the kernel stack top while running in user mode and zero
+    // while running in the kernel, in which case the trap frame is pushed onto
+    // the interrupted kernel stack. `ra` is saved first and then carries the handler
+    // to the common part of the path.
     unsafe {
         naked_asm!(
+            ".option push",
+            ".option norvc",
+            "j 3f",
+            ".rept {irq_causes} - 1",
+            "j 4f",
+            ".endr",
+            ".option pop",
+            "3:",
+            "csrrw sp, sscratch, sp",
+            "bnez sp, 1f",
+            "csrr sp, sscratch",
+            "1:",
+            concat!("addi sp, sp, -31 * ", reg_bytes!()),
+            store_reg!(ra, 0),
+            "la ra, exception_handler",
+            "j 5f",
+            "4:",
             "csrrw sp, sscratch, sp",
-            "addi sp, sp, -4 * 31",
-            "sw ra,  4 * 0(sp)",
-            "sw gp,  4 * 1(sp)",
-            "sw tp,  4 * 2(sp)",
-            "sw t0,  4 * 3(sp)",
-            "sw t1,  4 * 4(sp)",
-            "sw t2,  4 * 5(sp)",
-            "sw t3,  4 * 6(sp)",
-            "sw t4,  4 * 7(sp)",
-            "sw t5,  4 * 8(sp)",
-            "sw t6,  4 * 9(sp)",
-            "sw a0,  4 * 10(sp)",
-            "sw a1,  4 * 11(sp)",
-            "sw a2,  4 * 12(sp)",
-            "sw a3,  4 * 13(sp)",
-            "sw a4,  4 * 14(sp)",
-            "sw a5,  4 * 15(sp)",
-            "sw a6,  4 * 16(sp)",
-            "sw a7,  4 * 17(sp)",
-            "sw s0,  4 * 18(sp)",
-            "sw s1,  4 * 19(sp)",
-            "sw s2,  4 * 20(sp)",
-            "sw s3,  4 * 21(sp)",
-            "sw s4,  4 * 22(sp)",
-            "sw s5,  4 * 23(sp)",
-            "sw s6,  4 * 24(sp)",
-            "sw s7,  4 * 25(sp)",
-            "sw s8,  4 * 26(sp)",
-            "sw s9,  4 * 27(sp)",
-            "sw s10, 4 * 28(sp)",
-            "sw s11, 4 * 29(sp)",
+            "bnez sp, 1f",
+            "csrr sp, sscratch",
+            "1:",
+            concat!("addi sp, sp, -31 * ", reg_bytes!()),
+            store_reg!(ra, 0),
+            "la ra, interrupt_handler",
+            "5:",
+            store_reg!(gp, 1),
+            store_reg!(tp, 2),
+            store_reg!(t0, 3),
+            store_reg!(t1, 4),
+            store_reg!(t2, 5),
+            store_reg!(t3, 6),
+            store_reg!(t4, 7),
+            store_reg!(t5, 8),
+            store_reg!(t6, 9),
+            store_reg!(a0, 10),
+            store_reg!(a1, 11),
+            store_reg!(a2, 12),
+            store_reg!(a3, 13),
+            store_reg!(a4, 14),
+            store_reg!(a5, 15),
+            store_reg!(a6, 16),
+            store_reg!(a7, 17),
+            store_reg!(s0, 18),
+            store_reg!(s1, 19),
+            store_reg!(s2, 20),
+            store_reg!(s3, 21),
+            store_reg!(s4, 22),
+            store_reg!(s5, 23),
+            store_reg!(s6, 24),
+            store_reg!(s7, 25),
+            store_reg!(s8, 26),
+            store_reg!(s9, 27),
+            store_reg!(s10, 28),
+            store_reg!(s11, 29),
             "csrr a0, sscratch",
-            "sw a0, 4 * 30(sp)",
-            "addi a0, sp, 4 * 31",
-            "csrw sscratch, a0",
+            store_reg!(a0, 30),
+            "csrw sscratch, zero",
             "mv a0, sp",
-            "call trap_handler",
-            "lw ra,  4 * 0(sp)",
-            "lw gp,  4 * 1(sp)",
-            "lw tp,  4 * 2(sp)",
-            "lw t0,  4 * 3(sp)",
-            "lw t1,  4 * 4(sp)",
-            "lw t2,  4 * 5(sp)",
-            "lw t3,  4 * 6(sp)",
-            "lw t4,  4 * 7(sp)",
-            "lw t5,  4 * 8(sp)",
-            "lw t6,  4 * 9(sp)",
-            "lw a0,  4 * 10(sp)",
-            "lw a1,  4 * 11(sp)",
-            "lw a2,  4 * 12(sp)",
-            "lw a3,  4 * 13(sp)",
-            "lw a4,  4 * 14(sp)",
-            "lw a5,  4 * 15(sp)",
-            "lw a6,  4 * 16(sp)",
-            "lw a7,  4 * 17(sp)",
-            "lw s0,  4 * 18(sp)",
-            "lw s1,  4 * 19(sp)",
-            "lw s2,  4 * 20(sp)",
-            "lw s3,  4 * 21(sp)",
-            "lw s4,  4 * 22(sp)",
-            "lw s5,  4 * 23(sp)",
-            "lw s6,  4 * 24(sp)",
-            "lw s7,  4 * 25(sp)",
-            "lw s8,  4 * 26(sp)",
-            "lw s9,  4 * 27(sp)",
-            "lw s10, 4 * 28(sp)",
-            "lw s11, 4 * 29(sp)",
-            "lw sp,  4 * 30(sp)",
+            "jalr ra",
+            // Entered here by `return_to_user` too, with `sp` pointing at the frame.
+            ".global trap_return",
+            "trap_return:",
+            // When returning to user mode, the next trap starts on top of this kernel stack.
+            "csrr a0, sstatus",
+            "andi a0, a0, 1 << 8", // sstatus.SPP
+            "bnez a0, 2f",
+            concat!("addi a0, sp, 31 * ", reg_bytes!()),
+            "csrw sscratch, a0",
+            "2:",
+            load_reg!(ra, 0),
+            load_reg!(gp, 1),
+            load_reg!(tp, 2),
+            load_reg!(t0, 3),
+            load_reg!(t1, 4),
+            load_reg!(t2, 5),
+            load_reg!(t3, 6),
+            load_reg!(t4, 7),
+            load_reg!(t5, 8),
+            load_reg!(t6, 9),
+            load_reg!(a0, 10),
+            load_reg!(a1, 11),
+            load_reg!(a2, 12),
+            load_reg!(a3, 13),
+            load_reg!(a4, 14),
+            load_reg!(a5, 15),
+            load_reg!(a6, 16),
+            load_reg!(a7, 17),
+            load_reg!(s0, 18),
+            load_reg!(s1, 19),
+            load_reg!(s2, 20),
+            load_reg!(s3, 21),
+            load_reg!(s4, 22),
+            load_reg!(s5, 23),
+            load_reg!(s6, 24),
+            load_reg!(s7, 25),
+            load_reg!(s8, 26),
+            load_reg!(s9, 27),
+            load_reg!(s10, 28),
+            load_reg!(s11, 29),
+            load_reg!(sp, 30),
             "sret",
+            irq_causes = const IRQ_CAUSES,
+        )
+    }
+}
+
+/// Enters user mode at `pc` with the registers in `frame`, which must be at the top of the
+/// calling process's kernel stack, where the next trap from user mode pushes its frame.
+///
+/// # Safety
+///
+/// Whatever runs on the kernel stack is abandoned, and `pc` must be mapped for user mode.
+pub unsafe fn return_to_user(frame: *const TrapFrame, pc: usize) -> ! {
+    unsafe {
+        asm!(
+            // Interrupts stay off until `sret` turns them on along with user mode.
+            "csrci sstatus, 1 << 1",
+            "csrw sepc, a1",
+            "li t0, 1 << 8", // sstatus.SPP
+            "csrc sstatus, t0",
+            "li t0, 1 << 5", // sstatus.SPIE
+            "csrs sstatus, t0",
+            "mv sp, a0",
+            "j trap_return",
+            in("a0") frame,
+            in("a1") pc,
+            options(noreturn),
         )
     }
 }
 
+// Interrupts taken by each hart, indexed by hart and then by cause.
+static INTERRUPTS: [[AtomicUsize; IRQ_CAUSES]; MAX_HARTS] =
+    [const { [const { AtomicUsize::new(0) }; IRQ_CAUSES] }; MAX_HARTS];
+
+/// Returns how many interrupts with `cause` hart `hart` has taken.
+pub fn interrupt_count(hart: usize, cause: usize) -> usize {
+    INTERRUPTS[hart][cause].load(Ordering::Relaxed)
+}
+
+/// Done first on every trap, with the frame just pushed.
+fn enter() {
+    if Sstatus::read().spp() == Privilege::User {
+        uaccess::deny();
+    }
+    // The likeliest moment to run off the stack.
+    proc::check_stack(proc::current());
+}
+
+/// Handles an interrupt, given the trap frame and the interrupted pc (`sepc`).
+type InterruptHandler = fn(&mut TrapFrame, usize);
+
+// Handlers of the interrupts the kernel enables, indexed by cause. Any other is a bug.
+const INTERRUPT_HANDLERS: [Option<InterruptHandler>; IRQ_CAUSES] = {
+    let mut handlers: [Option<InterruptHandler>; IRQ_CAUSES] = [None; IRQ_CAUSES];
+    handlers[IRQ_S_TIMER] = Some(on_timer);
+    handlers[IRQ_S_EXTERNAL] = Some(|_, _| irq::on_external());
+    handlers
+};
+
+#[unsafe(no_mangle)]
+unsafe fn interrupt_handler(tf: *mut TrapFrame) {
+    let cause = Scause::read().code();
+    let user_pc = csr::sepc();
+    let tf = unsafe { &mut *tf };
+    enter();
+
+    // Only causes enabled in `sie` are taken, all of them below `IRQ_CAUSES`.
+    INTERRUPTS[hart_id()][cause].fetch_add(1, Ordering::Relaxed);
+    random::add_interrupt(cause, user_pc);
+    match INTERRUPT_HANDLERS[cause] {
+        Some(handler) => handler(tf, user_pc),
+        None => panic!("unexpected interrupt {cause}, sepc=0x{user_pc:x}"),
+    }
+}
+
+fn on_timer(tf: &mut TrapFrame, user_pc: usize) {
+    timer::on_tick(user_pc);
+
+    #[cfg(feature = "gdb")]
+    if let Some(pc) = crate::gdb::poll_interrupt(tf, user_pc) {
+        csr::set_sepc(pc);
+        return;
+    }
+
+    let sstatus = Sstatus::read();
+    if sstatus.spp() == Privilege::User {
+        // User code keeps the hart until its next syscall unless preempted here, once its
+        // time slice is used up.
+        if proc::tick_slice() {
+            proc::give_up();
+            sstatus.write();
+        }
+        workqueue::run_softirqs();
+        let pc = signal::deliver(tf, user_pc);
+        csr::set_sepc(pc);
+    }
+}
+
 #[unsafe(no_mangle)]
-pub unsafe fn trap_handler(_tf: *const TrapFrame) {
-    let scause = read_csr!("scause");
-    let stval = read_csr!("stval");
-    let user_pc = read_csr!("sepc");
+unsafe fn exception_handler(tf: *mut TrapFrame) {
+    let scause = Scause::read().code();
+    let stval = csr::stval();
+    let user_pc = csr::sepc();
+    let tf = unsafe { &mut *tf };
+    enter();
+
+    // A page of the process that was swapped out, or the zero page written to, touched by
+    // it or by the kernel on its behalf.
+    if matches!(
+        scause,
+        EXC_INSN_PAGE_FAULT | EXC_LOAD_PAGE_FAULT | EXC_STORE_PAGE_FAULT
+    ) && proc::on_page_fault(stval, scause == EXC_STORE_PAGE_FAULT)
+    {
+        return;
+    }
+
+    // The kernel touched an address that may fault on purpose, see `extable`.
+    if Sstatus::read().spp() == Privilege::Supervisor
+        && let Some(pc) = extable::fixup(user_pc)
+    {
+        csr::set_sepc(pc);
+        return;
+    }
+
+    if scause == EXC_USER_ECALL {
+        // A blocking syscall may run other processes, which overwrite these.
+        let sstatus = Sstatus::read();
+        let pc = syscall::dispatch(tf, user_pc);
+        workqueue::run_softirqs();
+        let pc = signal::deliver(tf, pc);
+        sstatus.write();
+        csr::set_sepc(pc);
+        return;
+    }
+
+    if (scause == EXC_LOAD_MISALIGNED || scause == EXC_STORE_MISALIGNED)
+        && let Some(pc) = misaligned::on_misaligned(tf, user_pc, stval)
+    {
+        csr::set_sepc(pc);
+        return;
+    }
+
+    if scause == EXC_ILLEGAL_INSN
+        && let Some(pc) = emulate::on_illegal_insn(tf, user_pc, stval)
+    {
+        csr::set_sepc(pc);
+        return;
+    }
+
+    // A breakpoint the debugger set, in user mode or in the kernel.
+    #[cfg(feature = "gdb")]
+    if scause == EXC_BREAKPOINT
+        && let Some(pc) = crate::gdb::on_breakpoint(tf, user_pc)
+    {
+        csr::set_sepc(pc);
+        return;
+    }
+
+    // A process causing a fault nothing above handled in user mode gets a signal. A fault
+    // in the kernel, even on a process's behalf or in a kernel thread, panics: the process
+    // can't exit midway through kernel code, maybe holding locks.
+    let pid = proc::current();
+    if Sstatus::read().spp() == Privilege::User
+        && pid != 0
+        && let Some(sig) = crash::fault_signal(scause)
+    {
+        if scause == EXC_ILLEGAL_INSN {
+            println!(
+                "pid {pid}: illegal instruction 0x{:08x} at 0x{user_pc:x}",
+                emulate::fetch(user_pc, stval)
+            );
+        }
+        if !signal::is_caught(sig) {
+            crash::report(pid, tf, scause, stval, user_pc);
+        }
+        signal::force(sig);
+        let pc = signal::deliver(tf, user_pc);
+        csr::set_sepc(pc);
+        return;
+    }
 
+    crash::print_csrs();
     panic!(
         "Oops...I'm trapped!\nscause={:x}, stval={:x}, sepc=0x{:x}\n",
         scause, stval, user_pc
@@ -0,0 +1,399 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    console,
+    file::{self, FileKind},
+    poll,
+    proc::{self, PROC_MAX},
+    sbi,
+    signal::{self, SIGHUP, SIGINT, SIGQUIT, SIGTSTP},
+    sync::{Mpsc, Mutex, OnceCell},
+    syscall::Errno,
+    timer,
+};
+
+// Terminals, what user programs read from and write to when they use the console, all
+// working the same whatever device is behind them. A tty is a driver, moving characters
+// to and from a device (tty 0 is the SBI console, drivers register others, see
+// `register()`), and the state kept here for it:
+//
+// - A line discipline: typed characters are echoed and collected until Enter, Backspace
+//   erases the last one, and only complete lines are handed to readers, one per read.
+//   Ctrl-D on an empty line reads as end of file. Ctrl-C, Ctrl-\ and Ctrl-Z discard the
+//   line being typed and send `SIGINT`, `SIGQUIT` and `SIGTSTP` to the foreground process
+//   group, the one `tcsetpgrp()` picked. Input is also taken on every tick, so they work while nobody
+//   reads.
+// - The process it is the controlling terminal of, which opened it as its stdio, and
+//   whose children inherit it. Only they can change its foreground group, and when that
+//   process exits the foreground group gets `SIGHUP`.
+
+pub const MAX_TTYS: usize = 8;
+
+// Bytes of typed input kept, complete lines and the one being typed.
+const LINE_MAX: usize = 256;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const EOF: u8 = 0x04;
+const INTR: u8 = 0x03;
+const QUIT: u8 = 0x1c;
+const SUSP: u8 = 0x1a;
+
+// No tty, or no process, in `CTTY` and `SESSION`.
+const NONE: usize = usize::MAX;
+
+/// A device characters can be written to and read from, passed `arg` to tell its ports
+/// apart.
+#[derive(Debug, Clone, Copy)]
+pub struct Driver {
+    pub name: &'static str,
+    /// Writes the bytes, returning `false` if it can't now (the SBI console is used then).
+    /// Called with any lock held, from interrupt handlers too.
+    pub write: fn(usize, &[u8]) -> bool,
+    pub getchar: fn(usize) -> Option<u8>,
+    pub arg: usize,
+}
+
+const SBI: Driver = Driver {
+    name: "sbi",
+    write: |_, buf| {
+        for &c in buf {
+            sbi::putchar(c as char);
+        }
+        true
+    },
+    getchar: |_| sbi::getchar(),
+    arg: 0,
+};
+
+// Drivers of the ttys after the SBI console. Never removed, so they can be used without a
+// lock while printing.
+static DRIVERS: [OnceCell<Driver>; MAX_TTYS - 1] = [const { OnceCell::new() }; MAX_TTYS - 1];
+static COUNT: AtomicUsize = AtomicUsize::new(1);
+
+struct Line {
+    buf: [u8; LINE_MAX],
+    len: usize,
+    // Ctrl-D was typed on an empty line, the next read returns 0.
+    eof: bool,
+}
+
+static LINES: [Mutex<Line>; MAX_TTYS] = [const {
+    Mutex::new(Line {
+        buf: [0; LINE_MAX],
+        len: 0,
+        eof: false,
+    })
+}; MAX_TTYS];
+
+// Input arriving other than from the device (see `feed()`), until the line discipline
+// takes it. Pushed to without a lock, so also from interrupt context.
+static INPUT: [Mpsc<u8, LINE_MAX>; MAX_TTYS] = [const { Mpsc::new() }; MAX_TTYS];
+
+// Process group of each tty that typed signals go to, 0 for none.
+static FOREGROUND: [AtomicUsize; MAX_TTYS] = [const { AtomicUsize::new(0) }; MAX_TTYS];
+// Process each tty is the controlling terminal of.
+static SESSION: [AtomicUsize; MAX_TTYS] = [const { AtomicUsize::new(NONE) }; MAX_TTYS];
+// Controlling terminal of each process, indexed by pid.
+static CTTY: [AtomicUsize; PROC_MAX] = [const { AtomicUsize::new(NONE) }; PROC_MAX];
+
+// MARK - DRIVERS
+
+/// Adds a tty for `driver`, returning its number. It becomes the console if the command
+/// line names it (see `console`).
+///
+/// # Panics
+///
+/// This function panics if there are `MAX_TTYS` already.
+pub fn register(driver: Driver) -> usize {
+    let tty = COUNT.fetch_add(1, Ordering::Relaxed);
+    assert!(tty < MAX_TTYS, "too many ttys.");
+    DRIVERS[tty - 1].get_or_init(|| driver);
+    console::select(tty, driver.name);
+    tty
+}
+
+fn driver(tty: usize) -> Option<&'static Driver> {
+    match tty {
+        0 => Some(&SBI),
+        _ => DRIVERS.get(tty - 1).and_then(OnceCell::get),
+    }
+}
+
+/// Writes `buf` to `tty`'s device, or to the SBI console if it can't take it now.
+pub fn output(tty: usize, buf: &[u8]) {
+    if driver(tty).is_some_and(|driver| (driver.write)(driver.arg, buf)) {
+        return;
+    }
+    (SBI.write)(0, buf);
+}
+
+fn getchar(tty: usize) -> Option<u8> {
+    driver(tty).and_then(|driver| (driver.getchar)(driver.arg))
+}
+
+// MARK - LINE DISCIPLINE
+
+impl Line {
+    /// Returns where the line being typed starts, after the complete ones.
+    fn typing(&self) -> usize {
+        self.buf[..self.len]
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(0, |i| i + 1)
+    }
+
+    /// Adds a typed character, passing what to show for it to `echo`. Returns the signal
+    /// it stands for, if any.
+    fn input(&mut self, c: u8, echo: &mut impl FnMut(&[u8])) -> Option<usize> {
+        match c {
+            b'\r' | b'\n' if self.len < LINE_MAX => {
+                self.buf[self.len] = b'\n';
+                self.len += 1;
+                echo(b"\n");
+            }
+            BACKSPACE | DELETE if self.len > self.typing() => {
+                self.len -= 1;
+                echo(b"\x08 \x08");
+            }
+            EOF if self.len == 0 => self.eof = true,
+            INTR | QUIT | SUSP => {
+                self.len = self.typing();
+                let (shown, sig) = match c {
+                    INTR => (b"^C\n", SIGINT),
+                    QUIT => (b"^\\\n", SIGQUIT),
+                    _ => (b"^Z\n", SIGTSTP),
+                };
+                echo(shown);
+                return Some(sig);
+            }
+            // Keeps room for the newline.
+            b' '..DELETE if self.len < LINE_MAX - 1 => {
+                self.buf[self.len] = c;
+                self.len += 1;
+                echo(&[c]);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Returns the length of the first complete line, with its newline.
+    fn complete(&self) -> Option<usize> {
+        self.buf[..self.len]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map(|i| i + 1)
+    }
+
+    /// Moves up to `buf.len()` bytes of the first complete line into `buf`.
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.complete().unwrap_or(0));
+        buf[..n].copy_from_slice(&self.buf[..n]);
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+        n
+    }
+
+    /// Returns whether a read would return right away.
+    fn is_ready(&self) -> bool {
+        self.complete().is_some() || self.eof
+    }
+}
+
+/// Feeds a character typed on `tty` into its line discipline, sending the signal it stands
+/// for.
+fn input(tty: usize, line: &mut Line, c: u8) {
+    let sig = line.input(c, &mut |echo| {
+        write(tty, echo);
+    });
+    if let Some(sig) = sig {
+        // Also called from the timer interrupt.
+        signal::kill_group_deferred(FOREGROUND[tty].load(Ordering::Relaxed), sig);
+    }
+}
+
+/// Feeds all characters waiting for `tty`, fed or on its device, into its line discipline.
+fn pump(tty: usize, line: &mut Line) {
+    while let Some(c) = INPUT[tty].pop().or_else(|| getchar(tty)) {
+        input(tty, line, c);
+    }
+}
+
+/// Takes `bytes` as if they were typed on `tty`, for input arriving some other way (see
+/// `console::feed()`).
+///
+/// Never blocks, so it may be called from interrupt context. What doesn't fit while
+/// nobody takes input is dropped.
+pub fn feed(tty: usize, bytes: &[u8]) {
+    for &c in bytes {
+        let _ = INPUT[tty].push(c);
+    }
+    // Otherwise whoever holds it takes the input, or the next tick.
+    if let Some(mut line) = LINES[tty].try_lock() {
+        pump(tty, &mut line);
+    }
+}
+
+/// Takes typed input on every tick, so that typed signals arrive while nobody reads.
+pub fn on_tick() {
+    on_input();
+}
+
+/// Takes the input a driver's interrupt handler says arrived, without waiting for the next
+/// tick. Never blocks, like `feed()`.
+pub fn on_input() {
+    let count = COUNT.load(Ordering::Relaxed);
+    for (tty, line) in LINES.iter().enumerate().take(count) {
+        // Whoever holds it is taking input already.
+        if let Some(mut line) = line.try_lock() {
+            pump(tty, &mut line);
+        }
+    }
+}
+
+// MARK - FILES
+
+/// Reads from `tty`'s current line, blocking until it is complete.
+pub fn read(tty: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    loop {
+        let mut line = LINES[tty].lock();
+        pump(tty, &mut line);
+        if line.eof {
+            line.eof = false;
+            return Ok(0);
+        }
+        if line.is_ready() {
+            return Ok(line.take(buf));
+        }
+        drop(line);
+
+        // FIXME: Polled every tick, there is no console interrupt yet.
+        proc::sleep(Some(timer::ticks() + 1));
+        signal::interrupted()?;
+    }
+}
+
+/// Writes `buf` to `tty`, returning how many bytes were written.
+pub fn write(tty: usize, buf: &[u8]) -> usize {
+    if tty == console::tty() {
+        console::mirror(buf);
+    }
+    output(tty, buf);
+    buf.len()
+}
+
+/// Returns `tty`'s readiness as `poll::POLL*` flags.
+pub fn poll(tty: usize) -> u16 {
+    let mut line = LINES[tty].lock();
+    pump(tty, &mut line);
+    if line.is_ready() {
+        poll::POLLIN | poll::POLLOUT
+    } else {
+        poll::POLLOUT
+    }
+}
+
+/// Opens `tty` as fds 0, 1 and 2 of the calling process, which has no fds yet, making it
+/// the process's controlling terminal and its process group the foreground one.
+pub fn open_stdio(tty: usize) -> Result<(), Errno> {
+    let fd = file::open(FileKind::Tty(tty))?;
+    file::dup(fd)?;
+    file::dup(fd)?;
+    let pid = proc::current();
+    CTTY[pid].store(tty, Ordering::Relaxed);
+    SESSION[tty].store(pid, Ordering::Relaxed);
+    FOREGROUND[tty].store(proc::pgid(pid), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the tty `fd` is, if it is the calling process's controlling terminal.
+///
+/// Fails with `ENOTTY` otherwise.
+fn controlling(fd: usize) -> Result<usize, Errno> {
+    match file::kind(fd)? {
+        FileKind::Tty(tty) if CTTY[proc::current()].load(Ordering::Relaxed) == tty => Ok(tty),
+        _ => Err(Errno::ENOTTY),
+    }
+}
+
+/// Makes process group `pgid` the foreground one of the tty open as `fd`.
+///
+/// Fails with `ENOTTY` if `fd` isn't the caller's controlling terminal, and with `EPERM` if
+/// group `pgid` doesn't exist.
+pub fn tcsetpgrp(fd: usize, pgid: usize) -> Result<(), Errno> {
+    let tty = controlling(fd)?;
+    if proc::group_members(pgid) == 0 {
+        return Err(Errno::EPERM);
+    }
+    FOREGROUND[tty].store(pgid, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the foreground process group of the tty open as `fd`.
+pub fn tcgetpgrp(fd: usize) -> Result<usize, Errno> {
+    Ok(FOREGROUND[controlling(fd)?].load(Ordering::Relaxed))
+}
+
+/// Gives `child` the controlling terminal of `parent`.
+pub fn inherit(parent: usize, child: usize) {
+    CTTY[child].store(CTTY[parent].load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Forgets the controlling terminal of exiting `pid`, which has left its process group. If
+/// `pid` is the process that made it so, the terminal hangs up: its foreground group gets
+/// `SIGHUP`.
+pub fn reset(pid: usize) {
+    let tty = CTTY[pid].swap(NONE, Ordering::Relaxed);
+    if tty == NONE {
+        return;
+    }
+    if SESSION[tty]
+        .compare_exchange(pid, NONE, Ordering::Relaxed, Ordering::Relaxed)
+        .is_ok()
+    {
+        signal::kill_group_deferred(FOREGROUND[tty].swap(0, Ordering::Relaxed), SIGHUP);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn line_discipline_edits_and_completes_lines() {
+        let mut line = Line {
+            buf: [0; LINE_MAX],
+            len: 0,
+            eof: false,
+        };
+        let mut echoed = [0; 16];
+        let mut len = 0;
+        // A complete line, then one being typed that Backspace can't erase past.
+        for &c in b"\x7flx\x7fs\rp\x7f\x7fw" {
+            line.input(c, &mut |echo| {
+                echoed[len..len + echo.len()].copy_from_slice(echo);
+                len += echo.len();
+            });
+        }
+        assert_eq!(&echoed[..len], b"lx\x08 \x08s\np\x08 \x08w");
+        assert!(line.is_ready());
+
+        let mut buf = [0; 4];
+        assert_eq!(line.take(&mut buf[..2]), 2);
+        assert_eq!(&buf[..2], b"ls");
+        assert_eq!(line.take(&mut buf), 1);
+        assert_eq!(buf[0], b'\n');
+        assert!(!line.is_ready());
+
+        // Ctrl-C throws the typed "w" away.
+        assert_eq!(line.input(INTR, &mut |_| {}), Some(SIGINT));
+        assert_eq!(line.len, 0);
+        assert_eq!(line.input(QUIT, &mut |_| {}), Some(SIGQUIT));
+        line.input(EOF, &mut |_| {});
+        assert!(line.eof);
+    }
+}
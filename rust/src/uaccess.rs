@@ -0,0 +1,99 @@
+use crate::{
+    csr::Sstatus,
+    elf, proc,
+    syscall::Errno,
+    vm::{PAGE_R, PAGE_W, PAGE_X},
+};
+
+// The rules for when the kernel may touch user memory. The MMU faults on kernel accesses
+// to user pages (`PAGE_U`) while `sstatus.SUM` is clear, which it is except inside a
+// `UserAccess` scope, so a stray user pointer can't be followed by accident. Every range
+// is passed to `check()` first, which faults it with `EFAULT` unless the calling process
+// has it mapped as user memory with the access needed; a scope alone doesn't make a
+// pointer safe.
+//
+// Syscalls run in a scope from dispatch to return, as they are all passed user pointers.
+// Blocking inside one keeps it, since `sstatus` is switched along with the process.
+
+/// How user memory is going to be accessed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Exec,
+}
+
+/// Lets the kernel access user pages until dropped, when the previous state is restored.
+pub struct UserAccess {
+    was_set: bool,
+}
+
+impl UserAccess {
+    pub fn new() -> Self {
+        let was_set = Sstatus::read().sum();
+        Sstatus::set_sum();
+        Self { was_set }
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if !self.was_set {
+            Sstatus::clear_sum();
+        }
+    }
+}
+
+/// Denies the kernel access to user pages, called on every trap from user mode.
+pub fn deny() {
+    Sstatus::clear_sum();
+}
+
+/// Returns whether `addr` is in the part of an address space processes own.
+pub fn is_user(addr: usize) -> bool {
+    addr < elf::STACK_TOP
+}
+
+/// Checks that the `len` bytes at `addr` are user memory of the calling process that it
+/// can access with `access`.
+pub fn check(addr: usize, len: usize, access: Access) -> Result<(), Errno> {
+    let end = addr.checked_add(len).ok_or(Errno::EFAULT)?;
+    if addr == 0 {
+        return Err(Errno::EFAULT);
+    }
+    if len == 0 {
+        return Ok(());
+    }
+
+    let flags = match access {
+        Access::Read => PAGE_R,
+        Access::Write => PAGE_W,
+        Access::Exec => PAGE_X,
+    };
+    match proc::user_accessible(addr, end, flags) {
+        true => Ok(()),
+        false => Err(Errno::EFAULT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn bad_user_ranges_fault() {
+        assert_eq!(check(0, 4, Access::Read), Err(Errno::EFAULT));
+        assert_eq!(check(usize::MAX, 2, Access::Write), Err(Errno::EFAULT));
+        // Tests run before any process exists, with paging off.
+        let word = 0u32;
+        assert_eq!(check(&word as *const _ as usize, 4, Access::Read), Ok(()));
+
+        let before = Sstatus::read().sum();
+        {
+            let _outer = UserAccess::new();
+            drop(UserAccess::new());
+            assert!(Sstatus::read().sum());
+        }
+        assert_eq!(Sstatus::read().sum(), before);
+    }
+}
@@ -0,0 +1,370 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    file::{self, FileKind},
+    ipv4::{self, Ipv4Header, MAX_PAYLOAD, PROTO_UDP},
+    net::{self, Ipv4Addr, MacAddr, SocketAddr},
+    poll::{POLLIN, POLLOUT, PollTable},
+    proc::WaitQueue,
+    signal,
+    sync::Mutex,
+    syscall::Errno,
+    trace,
+};
+
+// User Datagram Protocol (RFC 768) and the sockets user space sends and receives with.
+
+const HEADER_LEN: usize = 8;
+
+const MAX_SOCKETS: usize = 8;
+// Datagrams queued per socket before new ones are dropped.
+const QUEUE_LEN: usize = 4;
+// Longer datagrams are truncated when received, the rest is lost.
+const MAX_DATAGRAM: usize = 512;
+
+// Ports picked for sockets that send without binding first.
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+static NEXT_EPHEMERAL: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+struct Datagram {
+    from: SocketAddr,
+    len: usize,
+    data: [u8; MAX_DATAGRAM],
+}
+
+struct Socket {
+    // Local port, 0 until bound.
+    port: u16,
+    queue: [Datagram; QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+static SOCKETS: Mutex<[Option<Socket>; MAX_SOCKETS]> = Mutex::new([const { None }; MAX_SOCKETS]);
+
+// Processes waiting for a datagram on the socket in the same slot of `SOCKETS`.
+static READERS: [WaitQueue; MAX_SOCKETS] = [const { WaitQueue::new() }; MAX_SOCKETS];
+
+/// Creates an unbound UDP socket for the kernel's own use, returning its index.
+pub fn create() -> Result<usize, Errno> {
+    let mut sockets = SOCKETS.lock();
+    let sock = sockets
+        .iter()
+        .position(|s| s.is_none())
+        .ok_or(Errno::ENFILE)?;
+
+    sockets[sock] = Some(Socket {
+        port: 0,
+        queue: [Datagram {
+            from: SocketAddr {
+                ip: Ipv4Addr::UNSPECIFIED,
+                port: 0,
+            },
+            len: 0,
+            data: [0; MAX_DATAGRAM],
+        }; QUEUE_LEN],
+        head: 0,
+        len: 0,
+    });
+    Ok(sock)
+}
+
+/// Creates an unbound UDP socket, returning its fd.
+pub fn open() -> Result<usize, Errno> {
+    let sock = create()?;
+    file::open(FileKind::Socket(sock)).inspect_err(|_| close(sock))
+}
+
+/// Returns a free ephemeral port, going round the range so ports aren't reused right away.
+fn ephemeral_port(sockets: &[Option<Socket>]) -> Result<u16, Errno> {
+    let count = EPHEMERAL_PORTS.len();
+    (0..count)
+        .map(|_| {
+            let n = NEXT_EPHEMERAL.fetch_add(1, Ordering::Relaxed) % count;
+            EPHEMERAL_PORTS.start() + n as u16
+        })
+        .find(|&port| !sockets.iter().flatten().any(|s| s.port == port))
+        .ok_or(Errno::EADDRINUSE)
+}
+
+/// Binds `sock` to the port of `addr`, or an ephemeral one if it is 0.
+///
+/// Sockets receive on all interfaces, so the address itself must be unspecified.
+pub fn bind(sock: usize, addr: SocketAddr) -> Result<(), Errno> {
+    if addr.ip != Ipv4Addr::UNSPECIFIED {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut sockets = SOCKETS.lock();
+    if sockets[sock].as_ref().unwrap().port != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let port = match addr.port {
+        0 => ephemeral_port(&*sockets)?,
+        port if sockets.iter().flatten().any(|s| s.port == port) => {
+            return Err(Errno::EADDRINUSE);
+        }
+        port => port,
+    };
+
+    sockets[sock].as_mut().unwrap().port = port;
+    Ok(())
+}
+
+/// Returns the port `sock` is bound to, binding it to an ephemeral one first if needed.
+fn local_port(sock: usize) -> Result<u16, Errno> {
+    let mut sockets = SOCKETS.lock();
+    match sockets[sock].as_ref().unwrap().port {
+        0 => {
+            let port = ephemeral_port(&*sockets)?;
+            sockets[sock].as_mut().unwrap().port = port;
+            Ok(port)
+        }
+        port => Ok(port),
+    }
+}
+
+/// Writes a datagram carrying `data` from `src` to `dst` to `buf`, returning its length.
+fn write_datagram(buf: &mut [u8], src: SocketAddr, dst: SocketAddr, data: &[u8]) -> usize {
+    let len = HEADER_LEN + data.len();
+    buf[0..2].copy_from_slice(&src.port.to_be_bytes());
+    buf[2..4].copy_from_slice(&dst.port.to_be_bytes());
+    buf[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+    buf[6..8].fill(0);
+    buf[HEADER_LEN..len].copy_from_slice(data);
+
+    let sum = ipv4::checksum_with(
+        ipv4::pseudo_header_sum(src.ip, dst.ip, PROTO_UDP, len),
+        &buf[..len],
+    );
+    // An all-zero checksum means none was computed.
+    let sum = if sum == 0 { 0xffff } else { sum };
+    buf[6..8].copy_from_slice(&sum.to_be_bytes());
+    len
+}
+
+/// Sends `data` in a datagram from `sock` to `dst`, binding the socket to an ephemeral port
+/// if it isn't bound yet.
+pub fn send_to(sock: usize, dst: SocketAddr, data: &[u8]) -> Result<usize, Errno> {
+    if HEADER_LEN + data.len() > MAX_PAYLOAD {
+        return Err(Errno::EMSGSIZE);
+    }
+
+    let (iface, _) = ipv4::route(dst.ip)?;
+    let src = SocketAddr {
+        ip: net::ip_config(iface)?.addr,
+        port: local_port(sock)?,
+    };
+
+    let mut datagram = [0; MAX_PAYLOAD];
+    let len = write_datagram(&mut datagram, src, dst, data);
+    ipv4::send(dst.ip, PROTO_UDP, &datagram[..len])?;
+    Ok(data.len())
+}
+
+/// Broadcasts `data` in a datagram from `sock` to port `dst_port` on `iface`'s link, which
+/// works before the interface has an address.
+pub fn send_broadcast(sock: usize, iface: usize, dst_port: u16, data: &[u8]) -> Result<(), Errno> {
+    if HEADER_LEN + data.len() > MAX_PAYLOAD {
+        return Err(Errno::EMSGSIZE);
+    }
+
+    let src = SocketAddr {
+        ip: net::ip_config(iface)?.addr,
+        port: local_port(sock)?,
+    };
+    let dst = SocketAddr {
+        ip: Ipv4Addr::BROADCAST,
+        port: dst_port,
+    };
+
+    let mut datagram = [0; MAX_PAYLOAD];
+    let len = write_datagram(&mut datagram, src, dst, data);
+    ipv4::send_via(
+        iface,
+        MacAddr::BROADCAST,
+        dst.ip,
+        PROTO_UDP,
+        &datagram[..len],
+    )
+}
+
+/// Moves the oldest datagram queued on `s` into `buf`, see `recv_from()`.
+fn dequeue(s: &mut Socket, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+    if s.len == 0 {
+        return None;
+    }
+
+    let datagram = &s.queue[s.head];
+    let n = datagram.len.min(buf.len());
+    buf[..n].copy_from_slice(&datagram.data[..n]);
+    let from = datagram.from;
+    s.head = (s.head + 1) % QUEUE_LEN;
+    s.len -= 1;
+
+    Some((n, from))
+}
+
+/// Receives the next datagram on `sock` into `buf`, blocking until one arrives.
+///
+/// Returns the number of bytes copied, the rest of a longer datagram is discarded, and the
+/// address it came from.
+pub fn recv_from(sock: usize, buf: &mut [u8]) -> Result<(usize, SocketAddr), Errno> {
+    loop {
+        let mut sockets = SOCKETS.lock();
+        if let Some(received) = dequeue(sockets[sock].as_mut().unwrap(), buf) {
+            return Ok(received);
+        }
+        READERS[sock].wait(sockets);
+        signal::interrupted()?;
+    }
+}
+
+/// Like `recv_from()`, but returns `None` instead of blocking if nothing was received.
+pub fn try_recv_from(sock: usize, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+    dequeue(SOCKETS.lock()[sock].as_mut().unwrap(), buf)
+}
+
+/// Returns the readiness of `sock` as `poll::POLL*` flags, queueing the caller on it
+/// through `table` if given.
+pub fn poll(sock: usize, table: Option<&mut PollTable>) -> u16 {
+    let sockets = SOCKETS.lock();
+    let s = sockets[sock].as_ref().unwrap();
+
+    if let Some(table) = table {
+        table.add(&READERS[sock]);
+    }
+
+    // Sending never blocks for lack of room.
+    POLLOUT | if s.len > 0 { POLLIN } else { 0 }
+}
+
+/// Releases `sock`, called once the last file referring to it is closed.
+pub fn close(sock: usize) {
+    SOCKETS.lock()[sock] = None;
+}
+
+/// Handles a UDP datagram in an IPv4 datagram described by `header`, queueing it on the
+/// socket bound to its destination port.
+pub fn receive(header: &Ipv4Header, datagram: &[u8]) {
+    if datagram.len() < HEADER_LEN {
+        return;
+    }
+    let src_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let dst_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    let sum = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if len < HEADER_LEN || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    let pseudo = ipv4::pseudo_header_sum(header.src, header.dst, PROTO_UDP, len);
+    if sum != 0 && ipv4::checksum_with(pseudo, datagram) != 0 {
+        return;
+    }
+
+    let mut sockets = SOCKETS.lock();
+    let Some(sock) = sockets
+        .iter()
+        .position(|s| s.as_ref().is_some_and(|s| s.port == dst_port))
+    else {
+        trace!("udp: dropped datagram for port {dst_port}");
+        return;
+    };
+    let s = sockets[sock].as_mut().unwrap();
+    if s.len == QUEUE_LEN {
+        trace!("udp: dropped datagram for port {dst_port}, queue full");
+        return;
+    }
+
+    let payload = &datagram[HEADER_LEN..];
+    let entry = &mut s.queue[(s.head + s.len) % QUEUE_LEN];
+    entry.from = SocketAddr {
+        ip: header.src,
+        port: src_port,
+    };
+    entry.len = payload.len().min(MAX_DATAGRAM);
+    entry.data[..entry.len].copy_from_slice(&payload[..entry.len]);
+    s.len += 1;
+    drop(sockets);
+
+    READERS[sock].wake_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        eth::{self, ETHERTYPE_IPV4, EthHeader},
+        net::tests::{MockDevice, mock_interface},
+    };
+
+    #[test_case]
+    fn sends_and_receives_datagrams() {
+        let (iface, dev) = mock_interface();
+        let fd = open().unwrap();
+        let sock = file::socket(fd).unwrap();
+        let any = |port| SocketAddr {
+            ip: Ipv4Addr::UNSPECIFIED,
+            port,
+        };
+        bind(sock, any(7)).unwrap();
+        assert_eq!(bind(sock, any(8)), Err(Errno::EINVAL));
+
+        // A broadcast needs no ARP, so the reply goes out right away.
+        let peer = SocketAddr {
+            ip: Ipv4Addr::BROADCAST,
+            port: 4000,
+        };
+        assert_eq!(send_to(sock, peer, b"ping"), Ok(4));
+
+        let frame = {
+            let sent = dev.sent.lock();
+            let mut frame = [0; eth::HEADER_LEN + ipv4::HEADER_LEN + HEADER_LEN + 4];
+            frame.copy_from_slice(&sent.0[..sent.1]);
+            frame
+        };
+        let (_, datagram) = eth::parse(&frame).unwrap();
+        let (header, udp) = ipv4::parse(datagram).unwrap();
+        assert_eq!(header.protocol, PROTO_UDP);
+        assert_eq!(&udp[..4], &[0, 7, 0x0f, 0xa0]);
+        assert_eq!(&udp[HEADER_LEN..], b"ping");
+
+        // Loop the datagram back, as if it came from the peer.
+        let mut looped = frame;
+        eth::write_header(
+            &mut looped,
+            &EthHeader {
+                dst: MockDevice::MAC,
+                src: MacAddr([2, 0, 0, 0, 0, 1]),
+                ethertype: ETHERTYPE_IPV4,
+            },
+        );
+        let header = Ipv4Header {
+            src: Ipv4Addr([10, 0, 0, 1]),
+            dst: Ipv4Addr([10, 0, 0, 2]),
+            ..header
+        };
+        let (_, datagram) = looped.split_at_mut(eth::HEADER_LEN);
+        ipv4::write_header(datagram, &header, HEADER_LEN + 4);
+        // Swap the ports and leave the checksum out.
+        datagram[ipv4::HEADER_LEN..ipv4::HEADER_LEN + 4].copy_from_slice(&[0x0f, 0xa0, 0, 7]);
+        datagram[ipv4::HEADER_LEN + 6..ipv4::HEADER_LEN + 8].fill(0);
+        eth::receive(iface, &looped);
+
+        let mut buf = [0; 2];
+        let (n, from) = recv_from(sock, &mut buf).unwrap();
+        assert_eq!((n, &buf), (2, b"pi"));
+        assert_eq!(
+            from,
+            SocketAddr {
+                ip: Ipv4Addr([10, 0, 0, 1]),
+                port: 4000
+            }
+        );
+        assert_eq!(poll(sock, None), POLLOUT);
+
+        file::close(fd).unwrap();
+    }
+}
@@ -0,0 +1,794 @@
+use crate::{
+    cred::{self, MAY_EXEC, MAY_READ, MAY_WRITE},
+    cwd, dcache,
+    file::{self, FileKind},
+    pagecache, proc,
+    rcu::Rcu,
+    syscall::Errno,
+};
+
+// The virtual filesystem: filesystems are mounted at absolute paths, and paths are resolved
+// to an inode of the filesystem mounted at their longest matching prefix, relative ones
+// from the working directory of the calling process (see `cwd`). Open files keep
+// the mount and inode, see `FileKind::Inode`.
+
+const MAX_MOUNTS: usize = 8;
+/// Longest name of a directory entry.
+pub const NAME_MAX: usize = 32;
+/// Longest path resolved, once symbolic links in it are replaced by their targets.
+pub const PATH_MAX: usize = 256;
+/// Most symbolic links followed resolving one path.
+pub const SYMLOOP_MAX: usize = 8;
+
+// Flags of `open()`, with the values Linux uses.
+pub const O_ACCMODE: usize = 0o3;
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_CREAT: usize = 0o100;
+pub const O_EXCL: usize = 0o200;
+pub const O_DIRECTORY: usize = 0o200000;
+pub const O_CLOEXEC: usize = 0o2000000;
+
+/// The sticky bit of a mode: in a directory others may write to, only the owner of an
+/// entry (or of the directory) may remove or rename it.
+pub const S_ISVTX: usize = 0o1000;
+
+/// An entry of a directory, as returned by `FileSystem::readdir()`.
+#[derive(Debug, Clone, Copy)]
+pub struct DirEntry {
+    pub ino: usize,
+    name: [u8; NAME_MAX],
+    len: usize,
+}
+
+impl DirEntry {
+    /// Creates an entry for `ino` named `name`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name is longer than `NAME_MAX`.
+    pub fn new(ino: usize, name: core::fmt::Arguments) -> Self {
+        let mut entry = DirEntry {
+            ino,
+            name: [0; NAME_MAX],
+            len: 0,
+        };
+        let mut out = BufWriter::new(&mut entry.name);
+        core::fmt::write(&mut out, name).expect("directory entry name too long.");
+        entry.len = out.len();
+        entry
+    }
+
+    pub fn name(&self) -> &str {
+        // Only ever written from a `str`.
+        unsafe { core::str::from_utf8_unchecked(&self.name[..self.len]) }
+    }
+}
+
+/// Who owns a file, and what its permission bits let whom do with it (see
+/// `cred::permission()`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metadata {
+    pub mode: usize,
+    pub uid: usize,
+    pub gid: usize,
+}
+
+/// A filesystem that can be mounted, identifying files by inode numbers of its choosing.
+pub trait FileSystem: Sync {
+    /// Returns the inode of the filesystem's root directory.
+    fn root(&self) -> usize;
+
+    /// Returns the inode of `name` in directory `dir`.
+    fn lookup(&self, dir: usize, name: &str) -> Result<usize, Errno>;
+
+    fn is_dir(&self, ino: usize) -> bool;
+
+    /// Reads from file `ino` at `offset` into `buf`, returning how many bytes were read.
+    fn read(&self, ino: usize, offset: usize, buf: &mut [u8]) -> Result<usize, Errno>;
+
+    /// Returns entry `index` of directory `dir`, `None` past the last one.
+    fn readdir(&self, dir: usize, index: usize) -> Result<Option<DirEntry>, Errno>;
+
+    /// Writes `buf` to file `ino` at `offset`, returning how many bytes were written.
+    fn write(&self, _ino: usize, _offset: usize, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Returns the owner and permission bits of file `ino`. Unless the filesystem keeps
+    /// them, everything belongs to root, which alone may write.
+    fn metadata(&self, ino: usize) -> Metadata {
+        Metadata {
+            mode: if self.is_dir(ino) { 0o755 } else { 0o644 },
+            uid: cred::ROOT,
+            gid: cred::ROOT,
+        }
+    }
+
+    /// Changes the owner and permission bits of file `ino`.
+    fn set_metadata(&self, _ino: usize, _meta: Metadata) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Creates an empty file named `name` in directory `dir` with `meta`, returning its
+    /// inode.
+    ///
+    /// Fails with `EEXIST` if the directory has an entry of that name already.
+    fn create(&self, _dir: usize, _name: &str, _meta: Metadata) -> Result<usize, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Creates an empty directory named `name` in directory `dir` with `meta`, returning
+    /// its inode.
+    ///
+    /// Fails with `EEXIST` if the directory has an entry of that name already.
+    fn mkdir(&self, _dir: usize, _name: &str, _meta: Metadata) -> Result<usize, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Removes entry `name`, which isn't a directory, from directory `dir`.
+    fn unlink(&self, _dir: usize, _name: &str) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Removes entry `name`, an empty directory, from directory `dir`.
+    ///
+    /// Fails with `ENOTEMPTY` if it isn't empty.
+    fn rmdir(&self, _dir: usize, _name: &str) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Moves entry `old_name` of directory `old_dir` to `new_name` in `new_dir`, replacing
+    /// what is there if it is of the same kind, an empty directory for a directory.
+    ///
+    /// Fails with `EINVAL` if that would move a directory below itself.
+    fn rename(
+        &self,
+        _old_dir: usize,
+        _old_name: &str,
+        _new_dir: usize,
+        _new_name: &str,
+    ) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Names file `ino`, which isn't a directory, `name` in directory `dir` as well.
+    fn link(&self, _dir: usize, _name: &str, _ino: usize) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    /// Creates a symbolic link named `name` in directory `dir` to `target`, with `meta`,
+    /// returning its inode.
+    fn symlink(
+        &self,
+        _dir: usize,
+        _name: &str,
+        _target: &str,
+        _meta: Metadata,
+    ) -> Result<usize, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    fn is_symlink(&self, _ino: usize) -> bool {
+        false
+    }
+
+    /// Stores the target of symbolic link `ino` into `buf`, returning its length.
+    ///
+    /// Fails with `EINVAL` if it isn't a symbolic link, and with `ENAMETOOLONG` if `buf`
+    /// can't hold the target.
+    fn readlink(&self, _ino: usize, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    /// Called when a file opens inode `ino`, and when one that did is closed, for
+    /// filesystems that keep removed inodes until nothing refers to them.
+    fn opened(&self, _ino: usize) {}
+    fn released(&self, _ino: usize) {}
+
+    /// Returns whether what `lookup()` and `is_dir()` return may be cached, see `dcache`.
+    /// Filesystems whose files come and go on their own say no.
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    /// Writes back whatever the filesystem holds of file `ino` in memory, then flushes its
+    /// device, so the file survives the machine going away. Written back in order: data
+    /// first, then a flush, then the metadata pointing at it (FAT entries, directory
+    /// entries, sizes), then another flush, so the metadata on disk never refers to data
+    /// that isn't there.
+    ///
+    /// Filesystems that hold nothing back, read-only ones included, have nothing to do.
+    fn fsync(&self, _ino: usize) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Writes back whatever the filesystem holds of any file in memory, like `fsync()` for
+    /// all of them at once.
+    fn sync(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Mount {
+    path: &'static str,
+    fs: &'static dyn FileSystem,
+}
+
+// Read on every path lookup, changed about never.
+static MOUNTS: Rcu<[Option<Mount>; MAX_MOUNTS]> = Rcu::new([None; MAX_MOUNTS]);
+
+/// Mounts `fs` at the absolute path `path`, which needn't exist.
+///
+/// Fails with `EPERM` unless the calling process is root, with `EBUSY` if something is
+/// mounted there already, and with `ENOSPC` if all mount slots are taken.
+pub fn mount(path: &'static str, fs: &'static dyn FileSystem) -> Result<(), Errno> {
+    cred::require_root()?;
+    if !path.starts_with('/') {
+        return Err(Errno::EINVAL);
+    }
+    let path = match path.trim_end_matches('/') {
+        "" => "/",
+        path => path,
+    };
+
+    MOUNTS.update(|mounts| {
+        if mounts.iter().flatten().any(|m| m.path == path) {
+            return Err(Errno::EBUSY);
+        }
+        let slot = mounts
+            .iter_mut()
+            .find(|m| m.is_none())
+            .ok_or(Errno::ENOSPC)?;
+        *slot = Some(Mount { path, fs });
+        Ok(())
+    })
+}
+
+// Returns `path` relative to `mount`, `None` if it's outside of it.
+fn strip_mount<'a>(path: &'a str, mount: &str) -> Option<&'a str> {
+    if mount == "/" {
+        return Some(path);
+    }
+    let rest = path.strip_prefix(mount)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+/// Resolves `path`, taken from the calling process's working directory if relative, to a
+/// mount and an inode on it, following symbolic links.
+pub fn resolve(path: &str) -> Result<(usize, usize), Errno> {
+    resolve_links(path, true)
+}
+
+/// Resolves `path` like `resolve()`, but if it names a symbolic link, to the link itself.
+pub fn resolve_nofollow(path: &str) -> Result<(usize, usize), Errno> {
+    resolve_links(path, false)
+}
+
+/// How far `walk()` got.
+enum Walked {
+    Inode(usize, usize),
+    /// A symbolic link, inode `ino` of `mount`, named by `path[start..end]`.
+    Link {
+        mount: usize,
+        ino: usize,
+        start: usize,
+        end: usize,
+    },
+}
+
+/// Resolves `path`, following symbolic links on the way, and the one it ends with too if
+/// `follow`.
+///
+/// Fails with `ELOOP` if that takes more than `SYMLOOP_MAX` links.
+fn resolve_links(path: &str, follow: bool) -> Result<(usize, usize), Errno> {
+    let (mut absolute, mut buf) = ([0; PATH_MAX], [0; PATH_MAX]);
+    let mut path = cwd::absolute(path, &mut absolute)?;
+    for _ in 0..=SYMLOOP_MAX {
+        let (mount, ino, start, end) = match walk(path, follow)? {
+            Walked::Inode(mount, ino) => return Ok((mount, ino)),
+            Walked::Link {
+                mount,
+                ino,
+                start,
+                end,
+            } => (mount, ino, start, end),
+        };
+
+        // The path goes on from the link's target, relative to the link's directory.
+        let mut next = [0; PATH_MAX];
+        let dir = &path.as_bytes()[..start];
+        next[..dir.len()].copy_from_slice(dir);
+        let target_len = fs(mount).readlink(ino, &mut next[dir.len()..])?;
+        let target = &next[dir.len()..dir.len() + target_len];
+        let (target_start, len) = match target.first() {
+            Some(b'/') => (dir.len(), target_len),
+            _ => (0, dir.len() + target_len),
+        };
+        let rest = &path.as_bytes()[end..];
+        if target_start + len + rest.len() > PATH_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        next[target_start + len..target_start + len + rest.len()].copy_from_slice(rest);
+        let len = len + rest.len();
+        buf[..len].copy_from_slice(&next[target_start..target_start + len]);
+        path = core::str::from_utf8(&buf[..len]).map_err(|_| Errno::ENOENT)?;
+    }
+    Err(Errno::ELOOP)
+}
+
+/// Resolves `path` until the first symbolic link to follow, see `resolve_links()`.
+fn walk(path: &str, follow: bool) -> Result<Walked, Errno> {
+    if !path.starts_with('/') {
+        return Err(Errno::ENOENT);
+    }
+
+    let (index, mount, rest) = MOUNTS
+        .read(|mounts| {
+            mounts
+                .iter()
+                .enumerate()
+                .filter_map(|(i, m)| {
+                    let m = (*m)?;
+                    Some((i, m, strip_mount(path, m.path)?))
+                })
+                .max_by_key(|(_, m, _)| m.path.len())
+        })
+        .ok_or(Errno::ENOENT)?;
+
+    let mut ino = mount.fs.root();
+    let mut start = path.len() - rest.len();
+    while start < path.len() {
+        let end = path[start..].find('/').map_or(path.len(), |i| start + i);
+        let name = &path[start..end];
+        if name.is_empty() || name == "." {
+            start = end + 1;
+            continue;
+        }
+        if !dcache::is_dir(index, mount.fs, ino) {
+            return Err(Errno::ENOTDIR);
+        }
+        permission(mount.fs, ino, MAY_EXEC)?;
+        // FIXME: `..` doesn't leave the mount yet.
+        ino = dcache::lookup(index, mount.fs, ino, name)?;
+
+        let last = path[end..].split('/').all(|n| n.is_empty() || n == ".");
+        if (follow || !last) && mount.fs.is_symlink(ino) {
+            return Ok(Walked::Link {
+                mount: index,
+                ino,
+                start,
+                end,
+            });
+        }
+        start = end + 1;
+    }
+    Ok(Walked::Inode(index, ino))
+}
+
+/// Checks that the calling process may access file `ino` of `fs` in the ways `want`, see
+/// `cred::permission()`.
+fn permission(fs: &dyn FileSystem, ino: usize, want: usize) -> Result<(), Errno> {
+    let meta = fs.metadata(ino);
+    cred::permission(proc::current(), meta.uid, meta.gid, meta.mode, want)
+}
+
+fn fs(mount: usize) -> &'static dyn FileSystem {
+    MOUNTS
+        .read(|mounts| mounts[mount])
+        .expect("inode of a filesystem that isn't mounted.")
+        .fs
+}
+
+/// Opens the file at `path` for the calling process, returning its fd.
+///
+/// With `O_CREAT`, a file that doesn't exist is created, owned by the caller and with
+/// permission bits `mode`; with `O_EXCL` as well, one that does exist is an error
+/// (`EEXIST`).
+pub fn open(path: &str, flags: usize, mode: usize) -> Result<usize, Errno> {
+    let (mount, ino, created) = match resolve(path) {
+        Ok(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => return Err(Errno::EEXIST),
+        Ok((mount, ino)) => (mount, ino, false),
+        Err(Errno::ENOENT) if flags & O_CREAT != 0 => {
+            let (mount, ino) = create(path, mode)?;
+            (mount, ino, true)
+        }
+        Err(errno) => return Err(errno),
+    };
+    let is_dir = is_dir(mount, ino);
+    if flags & O_DIRECTORY != 0 && !is_dir {
+        return Err(Errno::ENOTDIR);
+    }
+    if flags & O_ACCMODE != O_RDONLY && is_dir {
+        return Err(Errno::EISDIR);
+    }
+    let want = match flags & O_ACCMODE {
+        O_RDONLY => MAY_READ,
+        O_WRONLY => MAY_WRITE,
+        _ => MAY_READ | MAY_WRITE,
+    };
+    // Whoever creates a file may open it, whatever its mode.
+    if !created {
+        permission(fs(mount), ino, want)?;
+    }
+    let fd = file::open_as(FileKind::Inode { mount, ino }, flags & O_ACCMODE)?;
+    dcache::get(mount, ino);
+    fs(mount).opened(ino);
+    if flags & O_CLOEXEC != 0 {
+        file::fcntl(fd, file::F_SETFD, file::FD_CLOEXEC)?;
+    }
+    Ok(fd)
+}
+
+/// Resolves the directory `path` is in, returning its mount and inode and the last
+/// component of `path`.
+fn resolve_parent(path: &str) -> Result<(usize, usize, &str), Errno> {
+    let path = path.trim_end_matches('/');
+    let (dir, name) = match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((dir, name)) => (dir, name),
+        None if path.is_empty() => return Err(Errno::ENOENT),
+        // In the working directory.
+        None => (".", path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Errno::EINVAL);
+    }
+    let (mount, ino) = resolve(dir)?;
+    if !is_dir(mount, ino) {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok((mount, ino, name))
+}
+
+/// Returns the metadata for a file created by the calling process with permission bits
+/// `mode`.
+fn new_metadata(mode: usize) -> Metadata {
+    let cred = cred::get(proc::current());
+    Metadata {
+        mode: mode & 0o7777,
+        uid: cred.uid,
+        gid: cred.gid,
+    }
+}
+
+/// Creates the file at `path`, owned by the calling process, for `open()`.
+fn create(path: &str, mode: usize) -> Result<(usize, usize), Errno> {
+    let (mount, dir, name) = resolve_parent(path)?;
+    let fs = fs(mount);
+    permission(fs, dir, MAY_WRITE | MAY_EXEC)?;
+    let ino = fs.create(dir, name, new_metadata(mode))?;
+    Ok((mount, ino))
+}
+
+/// Creates the directory at `path`, owned by the calling process and with permission bits
+/// `mode`.
+pub fn mkdir(path: &str, mode: usize) -> Result<(), Errno> {
+    if is_mount_point(path) {
+        return Err(Errno::EEXIST);
+    }
+    let (mount, dir, name) = resolve_parent(path)?;
+    let fs = fs(mount);
+    permission(fs, dir, MAY_WRITE | MAY_EXEC)?;
+    fs.mkdir(dir, name, new_metadata(mode)).map(|_| ())
+}
+
+/// Checks that the calling process may remove or rename entry `name` of directory `dir`:
+/// it may write to the directory, and if the directory is sticky, owns either.
+fn may_delete(fs: &dyn FileSystem, dir: usize, name: &str) -> Result<(), Errno> {
+    permission(fs, dir, MAY_WRITE | MAY_EXEC)?;
+    let dir_meta = fs.metadata(dir);
+    let pid = proc::current();
+    if dir_meta.mode & S_ISVTX == 0 || cred::is_root(pid) {
+        return Ok(());
+    }
+    let uid = cred::get(pid).uid;
+    let owner = fs.metadata(fs.lookup(dir, name)?).uid;
+    match uid == owner || uid == dir_meta.uid {
+        true => Ok(()),
+        false => Err(Errno::EPERM),
+    }
+}
+
+/// Returns whether a filesystem is mounted at `path`, which then can't be removed.
+fn is_mount_point(path: &str) -> bool {
+    let mut buf = [0; PATH_MAX];
+    let Ok(path) = cwd::absolute(path, &mut buf) else {
+        return false;
+    };
+    let path = path.trim_end_matches('/');
+    MOUNTS.read(|mounts| mounts.iter().flatten().any(|m| m.path == path))
+}
+
+/// Removes the file at `path`, which isn't a directory.
+pub fn unlink(path: &str) -> Result<(), Errno> {
+    let (mount, dir, name) = resolve_parent(path)?;
+    let fs = fs(mount);
+    may_delete(fs, dir, name)?;
+    fs.unlink(dir, name)?;
+    dcache::forget(mount, dir, name);
+    Ok(())
+}
+
+/// Removes the empty directory at `path`.
+pub fn rmdir(path: &str) -> Result<(), Errno> {
+    if is_mount_point(path) {
+        return Err(Errno::EBUSY);
+    }
+    let (mount, dir, name) = resolve_parent(path)?;
+    let fs = fs(mount);
+    may_delete(fs, dir, name)?;
+    fs.rmdir(dir, name)?;
+    dcache::forget(mount, dir, name);
+    Ok(())
+}
+
+/// Moves the file at `old` to `new`, replacing what is there if it is of the same kind.
+///
+/// Fails with `EXDEV` if they are on different filesystems.
+pub fn rename(old: &str, new: &str) -> Result<(), Errno> {
+    if is_mount_point(old) || is_mount_point(new) {
+        return Err(Errno::EBUSY);
+    }
+    let (mount, old_dir, old_name) = resolve_parent(old)?;
+    let (new_mount, new_dir, new_name) = resolve_parent(new)?;
+    if mount != new_mount {
+        return Err(Errno::EXDEV);
+    }
+    let fs = fs(mount);
+    may_delete(fs, old_dir, old_name)?;
+    match may_delete(fs, new_dir, new_name) {
+        // Nothing to replace, but the directory must still be writable.
+        Err(Errno::ENOENT) => permission(fs, new_dir, MAY_WRITE | MAY_EXEC)?,
+        result => result?,
+    }
+    fs.rename(old_dir, old_name, new_dir, new_name)?;
+    dcache::forget(mount, old_dir, old_name);
+    dcache::forget(mount, new_dir, new_name);
+    Ok(())
+}
+
+/// Gives the file at `old`, which isn't a directory, the name `new` as well.
+///
+/// Fails with `EXDEV` if they are on different filesystems.
+pub fn link(old: &str, new: &str) -> Result<(), Errno> {
+    let (mount, ino) = resolve_nofollow(old)?;
+    if is_dir(mount, ino) {
+        return Err(Errno::EPERM);
+    }
+    let (new_mount, dir, name) = resolve_parent(new)?;
+    if mount != new_mount {
+        return Err(Errno::EXDEV);
+    }
+    let fs = fs(mount);
+    permission(fs, dir, MAY_WRITE | MAY_EXEC)?;
+    fs.link(dir, name, ino)
+}
+
+/// Creates a symbolic link at `path` to `target`, which needn't exist. A relative target
+/// is taken from the directory of the link.
+pub fn symlink(target: &str, path: &str) -> Result<(), Errno> {
+    if target.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    if target.len() > PATH_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    let (mount, dir, name) = resolve_parent(path)?;
+    let fs = fs(mount);
+    permission(fs, dir, MAY_WRITE | MAY_EXEC)?;
+    fs.symlink(dir, name, target, new_metadata(0o777))
+        .map(|_| ())
+}
+
+/// Stores the target of the symbolic link at `path` into `buf`, returning its length.
+pub fn readlink(path: &str, buf: &mut [u8]) -> Result<usize, Errno> {
+    let (mount, ino) = resolve_nofollow(path)?;
+    fs(mount).readlink(ino, buf)
+}
+
+/// Sets the permission bits of the file at `path` to `mode`.
+///
+/// Fails with `EPERM` unless the calling process owns the file or is root.
+pub fn chmod(path: &str, mode: usize) -> Result<(), Errno> {
+    let (mount, ino) = resolve(path)?;
+    let fs = fs(mount);
+    let meta = fs.metadata(ino);
+    let pid = proc::current();
+    if meta.uid != cred::get(pid).uid && !cred::is_root(pid) {
+        return Err(Errno::EPERM);
+    }
+    fs.set_metadata(
+        ino,
+        Metadata {
+            mode: mode & 0o7777,
+            ..meta
+        },
+    )
+}
+
+/// Gives the file at `path` to user `uid` and group `gid`, either left as is if
+/// `usize::MAX`.
+///
+/// Fails with `EPERM` unless the calling process is root.
+pub fn chown(path: &str, uid: usize, gid: usize) -> Result<(), Errno> {
+    cred::require_root()?;
+    let (mount, ino) = resolve(path)?;
+    let fs = fs(mount);
+    let meta = fs.metadata(ino);
+    let keep = |new, old| if new == usize::MAX { old } else { new };
+    fs.set_metadata(
+        ino,
+        Metadata {
+            uid: keep(uid, meta.uid),
+            gid: keep(gid, meta.gid),
+            ..meta
+        },
+    )
+}
+
+/// Checks that the calling process may access inode `ino` of `mount` in the ways `want`,
+/// see `cred::permission()`.
+pub fn access(mount: usize, ino: usize, want: usize) -> Result<(), Errno> {
+    permission(fs(mount), ino, want)
+}
+
+/// Returns whether inode `ino` of `mount` is a directory.
+pub fn is_dir(mount: usize, ino: usize) -> bool {
+    dcache::is_dir(mount, fs(mount), ino)
+}
+
+/// Lets go of inode `ino` of `mount` once no open file refers to it, for `file::close()`.
+pub fn release(mount: usize, ino: usize) {
+    dcache::put(mount, ino);
+    fs(mount).released(ino);
+}
+
+/// Reads from inode `ino` of `mount` at `offset`, for `file::read()`.
+pub fn read(mount: usize, ino: usize, offset: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+    if is_dir(mount, ino) {
+        return Err(Errno::EISDIR);
+    }
+    // What shared mappings wrote is only in the page cache until then.
+    pagecache::writeback(mount, ino)?;
+    let fs = fs(mount);
+    fs.read(ino, offset, buf)
+}
+
+/// Writes to inode `ino` of `mount` at `offset`, for `file::write()`.
+pub fn write(mount: usize, ino: usize, offset: usize, buf: &[u8]) -> Result<usize, Errno> {
+    if is_dir(mount, ino) {
+        return Err(Errno::EISDIR);
+    }
+    let fs = fs(mount);
+    let written = fs.write(ino, offset, buf)?;
+    pagecache::write_through(mount, ino, offset, &buf[..written]);
+    Ok(written)
+}
+
+/// Writes back inode `ino` of `mount`, for `file::fsync()`.
+pub fn fsync(mount: usize, ino: usize) -> Result<(), Errno> {
+    pagecache::writeback(mount, ino)?;
+    fs(mount).fsync(ino)
+}
+
+/// Writes back every file of every mount, the page cache first, returning the last error
+/// but going on past it.
+pub fn sync() -> Result<(), Errno> {
+    let mut result = pagecache::writeback_all();
+    let mounts = MOUNTS.read(|mounts| *mounts);
+    for mount in mounts.iter().flatten() {
+        if let Err(errno) = mount.fs.sync() {
+            result = Err(errno);
+        }
+    }
+    result
+}
+
+/// Returns the frame holding page `index` of inode `ino` of `mount` in the page cache, see
+/// `pagecache::map()`.
+pub fn map_page(mount: usize, ino: usize, index: usize) -> Result<usize, Errno> {
+    pagecache::map(mount, fs(mount), ino, index)
+}
+
+/// Stores the names of the entries of directory `fd` into `buf`, each followed by a NUL,
+/// continuing where the last call stopped. Returns the bytes stored, 0 after the last entry.
+///
+/// The entries are cached, names listed are often opened next.
+///
+/// Fails with `EINVAL` if `buf` can't hold the next name.
+pub fn getdents(fd: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+    let FileKind::Inode { mount, ino } = file::kind(fd)? else {
+        return Err(Errno::ENOTDIR);
+    };
+    if !is_dir(mount, ino) {
+        return Err(Errno::ENOTDIR);
+    }
+    let fs = fs(mount);
+
+    let mut index = file::offset(fd)?;
+    let mut len = 0;
+    while let Some(entry) = fs.readdir(ino, index)? {
+        let name = entry.name().as_bytes();
+        if len + name.len() + 1 > buf.len() {
+            if len == 0 {
+                return Err(Errno::EINVAL);
+            }
+            break;
+        }
+        buf[len..len + name.len()].copy_from_slice(name);
+        buf[len + name.len()] = 0;
+        dcache::insert(mount, fs, ino, entry.name(), entry.ino);
+        len += name.len() + 1;
+        index += 1;
+    }
+
+    file::set_offset(fd, index)?;
+    Ok(len)
+}
+
+/// Formats into a byte buffer, failing once it is full.
+pub struct BufWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> BufWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl core::fmt::Write for BufWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn paths_resolve_below_their_mount() {
+        assert_eq!(strip_mount("/proc/1/maps", "/proc"), Some("/1/maps"));
+        assert_eq!(strip_mount("/proc", "/proc"), Some(""));
+        assert_eq!(strip_mount("/procfs", "/proc"), None);
+        assert_eq!(strip_mount("/etc", "/"), Some("/etc"));
+        assert_eq!(resolve("relative"), Err(Errno::ENOENT));
+    }
+
+    #[test_case]
+    fn symlinks_resolve_until_they_loop() {
+        assert_eq!(mkdir("/tmp/vfs-dir", 0o755), Ok(()));
+        assert_eq!(symlink("vfs-dir", "/tmp/vfs-rel"), Ok(()));
+        assert_eq!(symlink("/tmp/vfs-rel/", "/tmp/vfs-abs"), Ok(()));
+        assert_eq!(symlink("/tmp/vfs-loop", "/tmp/vfs-loop"), Ok(()));
+
+        let dir = resolve("/tmp/vfs-dir").unwrap();
+        assert_eq!(resolve("/tmp/vfs-rel"), Ok(dir));
+        assert_eq!(resolve("/tmp/./vfs-abs/."), Ok(dir));
+        assert_ne!(resolve_nofollow("/tmp/vfs-abs"), Ok(dir));
+        assert_eq!(resolve("/tmp/vfs-loop"), Err(Errno::ELOOP));
+        let mut buf = [0; 16];
+        assert_eq!(readlink("/tmp/vfs-rel", &mut buf), Ok(7));
+        assert_eq!(&buf[..7], b"vfs-dir");
+
+        for link in ["/tmp/vfs-rel", "/tmp/vfs-abs", "/tmp/vfs-loop"] {
+            assert_eq!(unlink(link), Ok(()));
+        }
+        assert_eq!(rmdir("/tmp/vfs-dir"), Ok(()));
+    }
+}
@@ -0,0 +1,384 @@
+use core::sync::atomic::{Ordering, fence};
+
+use crate::{
+    dtb, faultpoint,
+    irq::{self, Irq},
+    mem::{self, PAGE_SIZE, RegionKind},
+    stdlib::phalloc_aligned,
+    syscall::Errno,
+};
+
+// The virtio MMIO transport (virtio 1.2, section 4.2), shared by the virtio drivers: finding
+// devices in the device tree, negotiating features, and split virtqueues (section 2.7) to
+// exchange buffers with them. Both the legacy interface (version 1, QEMU's default) and
+// the modern one (version 2) are driven, with the legacy memory layout for queues that
+// works for both.
+//
+// Drivers poll their queues, or request the interrupt line a device's node names (see
+// `Device::irq()`), their handler acknowledging it with `Device::ack_interrupt()`. Buffers are handed to devices by physical address, which is the
+// kernel's virtual address too.
+
+// Registers, as offsets from the base.
+const MAGIC: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c;
+const QUEUE_PFN: usize = 0x040;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC: usize = 0x080;
+const QUEUE_DRIVER: usize = 0x090;
+const QUEUE_DEVICE: usize = 0x0a0;
+const CONFIG: usize = 0x100;
+
+const MAGIC_VALUE: u32 = 0x7472_6976;
+
+// Device status bits.
+const ACKNOWLEDGE: u32 = 1;
+const DRIVER: u32 = 2;
+const DRIVER_OK: u32 = 4;
+const FEATURES_OK: u32 = 8;
+
+/// Required of modern devices, which legacy ones don't know.
+const F_VERSION_1: u64 = 1 << 32;
+
+/// Most buffers a queue holds, the size of the free descriptor mask.
+pub const MAX_QUEUE_SIZE: u16 = 32;
+
+// Descriptor flags.
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// A virtio device, by the base address of its registers.
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    base: usize,
+    version: u32,
+    irq: Option<Irq>,
+}
+
+/// Calls `f` with every device with id `device_id` (e.g. 3 for a console) in the device
+/// tree.
+pub fn for_each_device(device_id: u32, mut f: impl FnMut(Device)) {
+    dtb::for_each_compatible("virtio,mmio", |node| {
+        let dev = Device {
+            base: node.start,
+            version: 0,
+            irq: irq::of_node(&node),
+        };
+        if dev.read(MAGIC) != MAGIC_VALUE || dev.read(DEVICE_ID) != device_id {
+            return;
+        }
+        let (start, end) = (node.start, node.end);
+        mem::add_region(RegionKind::Mmio, start, end.max(start + PAGE_SIZE));
+        f(Device {
+            version: dev.read(VERSION),
+            ..dev
+        });
+    });
+}
+
+impl Device {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { ((self.base + reg) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { ((self.base + reg) as *mut u32).write_volatile(value) }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Returns the interrupt line the device raises, `None` if it has none the PLIC knows.
+    pub fn irq(&self) -> Option<Irq> {
+        self.irq
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    /// Resets the device and agrees on the features of `wanted` it offers, returning them.
+    ///
+    /// Fails with `ENODEV` if the device doesn't accept them.
+    pub fn init(&self, wanted: u64) -> Result<u64, Errno> {
+        self.write(STATUS, 0);
+        self.write(STATUS, ACKNOWLEDGE);
+        self.write(STATUS, ACKNOWLEDGE | DRIVER);
+
+        let mut offered = 0;
+        for half in 0..2 {
+            self.write(DEVICE_FEATURES_SEL, half);
+            offered |= (self.read(DEVICE_FEATURES) as u64) << (32 * half);
+        }
+        let wanted = match self.is_legacy() {
+            true => wanted,
+            false => wanted | F_VERSION_1,
+        };
+        let features = offered & wanted;
+        for half in 0..2 {
+            self.write(DRIVER_FEATURES_SEL, half);
+            self.write(DRIVER_FEATURES, (features >> (32 * half)) as u32);
+        }
+        if self.is_legacy() {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            return Ok(features);
+        }
+
+        self.write(STATUS, ACKNOWLEDGE | DRIVER | FEATURES_OK);
+        if self.read(STATUS) & FEATURES_OK == 0 {
+            return Err(Errno::ENODEV);
+        }
+        Ok(features & !F_VERSION_1)
+    }
+
+    /// Lets the device start using its queues, once they are set up.
+    pub fn ready(&self) {
+        let status = self.read(STATUS);
+        self.write(STATUS, status | DRIVER_OK);
+    }
+
+    /// Reads the `u32` at `offset` of the device-specific configuration.
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        self.read(CONFIG + offset)
+    }
+
+    /// Reads the `u64` at `offset` of the device-specific configuration, low half first.
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32
+    }
+
+    /// Acknowledges the device's interrupt, returning whether it had raised one.
+    pub fn ack_interrupt(&self) -> bool {
+        let status = self.read(INTERRUPT_STATUS);
+        self.write(INTERRUPT_ACK, status);
+        status != 0
+    }
+
+    /// Tells the device queue `index` has new buffers.
+    fn notify(&self, index: u16) {
+        fence(Ordering::SeqCst);
+        self.write(QUEUE_NOTIFY, index as u32);
+    }
+}
+
+// MARK - VIRTQUEUES
+
+#[repr(C)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A split virtqueue, of single buffers or chains of them.
+pub struct Queue {
+    dev: Device,
+    index: u16,
+    size: u16,
+    // Descriptor table, then the available ring, then on the next page the used ring.
+    mem: usize,
+    // Descriptors not handed to the device, as a bit mask.
+    free: u32,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+impl Queue {
+    /// Sets up queue `index` of `dev` with up to `size` buffers, fewer if the device has a
+    /// smaller limit.
+    ///
+    /// Fails with `ENOENT` if the device has no such queue, and with `ENOMEM` if there is
+    /// no memory for it.
+    pub fn new(dev: Device, index: u16, size: u16) -> Result<Self, Errno> {
+        dev.write(QUEUE_SEL, index as u32);
+        let max = dev.read(QUEUE_NUM_MAX);
+        if max == 0 {
+            return Err(Errno::ENOENT);
+        }
+        let size = size.min(MAX_QUEUE_SIZE).min(max as u16);
+
+        // The legacy interface takes the queue by page number, the used ring on the page
+        // after the descriptors (see `QUEUE_ALIGN`).
+        let mem = phalloc_aligned(2 * PAGE_SIZE, PAGE_SIZE).map_err(|_| Errno::ENOMEM)?;
+        unsafe { mem.as_mut_ptr().write_bytes(0, 2 * PAGE_SIZE) };
+        let queue = Self {
+            dev,
+            index,
+            size,
+            mem: mem.as_usize(),
+            free: u32::MAX >> (32 - size as u32),
+            avail_idx: 0,
+            last_used: 0,
+        };
+
+        dev.write(QUEUE_NUM, size as u32);
+        if dev.is_legacy() {
+            dev.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            dev.write(QUEUE_PFN, (queue.mem / PAGE_SIZE) as u32);
+        } else {
+            for (reg, addr) in [
+                (QUEUE_DESC, queue.mem),
+                (QUEUE_DRIVER, queue.avail()),
+                (QUEUE_DEVICE, queue.used()),
+            ] {
+                dev.write(reg, addr as u32);
+                dev.write(reg + 4, (addr as u64 >> 32) as u32);
+            }
+            dev.write(QUEUE_READY, 1);
+        }
+        Ok(queue)
+    }
+
+    fn avail(&self) -> usize {
+        self.mem + size_of::<Desc>() * self.size as usize
+    }
+
+    fn used(&self) -> usize {
+        self.mem + PAGE_SIZE
+    }
+
+    fn desc(&self, id: u16) -> *mut Desc {
+        (self.mem as *mut Desc).wrapping_add(id as usize)
+    }
+
+    /// Hands the `len` bytes at `addr` to the device, to fill if `device_writes`, to read
+    /// otherwise, returning the buffer's id. `None` if the queue is full.
+    pub fn push(&mut self, addr: usize, len: usize, device_writes: bool) -> Option<u16> {
+        self.push_chain(&[(addr, len, device_writes)])
+    }
+
+    /// Hands `bufs` to the device as one request, each the address and length of a buffer
+    /// and whether the device fills it, returning the id of the first. Devices expect the
+    /// buffers they read before those they fill. `None` if there aren't enough free
+    /// descriptors, or if the `virtio-tx` fault point fails the request.
+    pub fn push_chain(&mut self, bufs: &[(usize, usize, bool)]) -> Option<u16> {
+        if faultpoint!("virtio-tx") {
+            return None;
+        }
+        if bufs.is_empty() || (self.free.count_ones() as usize) < bufs.len() {
+            return None;
+        }
+        // Taken from the last buffer to the first, so each knows the one after it.
+        let mut next = None;
+        for &(addr, len, device_writes) in bufs.iter().rev() {
+            let id = self.free.trailing_zeros() as u16;
+            self.free &= !(1 << id);
+            let mut flags = if device_writes { DESC_F_WRITE } else { 0 };
+            if next.is_some() {
+                flags |= DESC_F_NEXT;
+            }
+            unsafe {
+                self.desc(id).write_volatile(Desc {
+                    addr: addr as u64,
+                    len: len as u32,
+                    flags,
+                    next: next.unwrap_or(0),
+                });
+            }
+            next = Some(id);
+        }
+        let id = next?;
+        unsafe {
+            // `flags`, `idx`, then the ring.
+            let ring = (self.avail() as *mut u16).add(2);
+            ring.add((self.avail_idx % self.size) as usize)
+                .write_volatile(id);
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            (self.avail() as *mut u16)
+                .add(1)
+                .write_volatile(self.avail_idx);
+        }
+        self.dev.notify(self.index);
+        Some(id)
+    }
+
+    /// Takes back a buffer the device is done with, returning its id and how many bytes the
+    /// device wrote to it.
+    pub fn pop(&mut self) -> Option<(u16, usize)> {
+        fence(Ordering::SeqCst);
+        let used_idx = unsafe { (self.used() as *const u16).add(1).read_volatile() };
+        if used_idx == self.last_used {
+            return None;
+        }
+        // `flags`, `idx`, then the ring of `id` and `len` pairs.
+        let elem = unsafe {
+            (self.used() as *const u32).add(1 + 2 * (self.last_used % self.size) as usize)
+        };
+        let (id, len) = unsafe { (elem.read_volatile(), elem.add(1).read_volatile()) };
+        self.last_used = self.last_used.wrapping_add(1);
+        let mut desc = id as u16;
+        loop {
+            self.free |= 1 << desc;
+            let Desc { flags, next, .. } = unsafe { self.desc(desc).read_volatile() };
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            desc = next;
+        }
+        Some((id as u16, len as usize))
+    }
+
+    /// Returns the address of the buffer with id `id`.
+    pub fn addr(&self, id: u16) -> usize {
+        unsafe { (*self.desc(id)).addr as usize }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{
+        fault::{self, Fault},
+        stdlib::phalloc,
+    };
+
+    /// Returns a modern device whose registers are a page of RAM, for testing drivers up to
+    /// the point they wait for the device. `config` is written to its configuration.
+    pub fn mock_device(config: &[u32]) -> Device {
+        let base = phalloc(PAGE_SIZE).unwrap().as_usize();
+        let dev = Device {
+            base,
+            version: 2,
+            irq: None,
+        };
+        dev.write(QUEUE_NUM_MAX, MAX_QUEUE_SIZE as u32);
+        for (i, &value) in config.iter().enumerate() {
+            dev.write(CONFIG + 4 * i, value);
+        }
+        dev
+    }
+
+    #[test_case]
+    fn failed_pushes_keep_their_descriptors() {
+        let mut queue = Queue::new(mock_device(&[]), 0, 4).unwrap();
+        fault::inject("virtio-tx", Fault::Fail { skip: 0, count: 1 }).unwrap();
+        assert_eq!(
+            queue.push_chain(&[(0x1000, 16, false), (0x2000, 1, true)]),
+            None
+        );
+        assert_eq!(fault::clear("virtio-tx"), 1);
+        assert_eq!(queue.free.count_ones(), 4);
+        assert!(
+            queue
+                .push_chain(&[(0x1000, 16, false), (0x2000, 1, true)])
+                .is_some()
+        );
+        assert_eq!(queue.free.count_ones(), 2);
+    }
+}
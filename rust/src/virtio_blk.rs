@@ -0,0 +1,221 @@
+use core::hint::spin_loop;
+
+use crate::{
+    block::{self, BlockDevice, MAX_SEGMENTS, SECTOR_SIZE},
+    mem::PAGE_SIZE,
+    println,
+    stdlib::phalloc,
+    sync::{Mutex, OnceCell},
+    syscall::Errno,
+    timer,
+    virtio::{self, Device, Queue},
+};
+
+// Virtio block devices (virtio 1.2, section 5.2), QEMU's `virtio-blk-device`: each becomes
+// a disk of the block layer named `vda`, `vdb` and so on, in device tree order, whose
+// partitions `block::register()` then adds.
+//
+// A request is a chain of descriptors: a header saying what to do and where, the buffers
+// of the data, and a status byte the device fills in once done. A vectored read or write
+// goes to the device as one request, its buffers each a descriptor of the chain. Devices
+// with a volatile write cache offer the FLUSH command, which `BlockDevice::flush()` sends.
+//
+// One request is in flight per disk at a time, and its completion is polled for.
+
+const DEVICE_ID: u32 = 2;
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+// Offset of `capacity`, in sectors, in the configuration.
+const CONFIG_CAPACITY: usize = 0;
+
+// Request types.
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+// Status of a completed request.
+const S_OK: u8 = 0;
+
+const MAX_DISKS: usize = 4;
+const NAMES: [&str; MAX_DISKS] = ["vda", "vdb", "vdc", "vdd"];
+
+// The header, the data, and the status.
+const QUEUE_SIZE: u16 = MAX_SEGMENTS as u16 + 2;
+const HEADER_SIZE: usize = 16;
+// Microseconds to wait for the device to complete a request.
+const TIMEOUT_US: u64 = 5_000_000;
+
+struct VirtioBlk {
+    queue: Mutex<Queue>,
+    // Page the header and the status are in.
+    buf: usize,
+    sectors: u64,
+    read_only: bool,
+    flush: bool,
+}
+
+static DISKS: [OnceCell<VirtioBlk>; MAX_DISKS] = [const { OnceCell::new() }; MAX_DISKS];
+
+impl VirtioBlk {
+    /// Sends request `kind` for the sectors starting at `sector`, with the buffers of
+    /// `data`, which the device fills if `device_writes`, and waits for it to complete.
+    ///
+    /// Fails with `EIO` if the device reports an error or doesn't complete it in time.
+    fn request(
+        &self,
+        kind: u32,
+        sector: u64,
+        data: &[(usize, usize)],
+        device_writes: bool,
+    ) -> Result<(), Errno> {
+        let mut queue = self.queue.lock();
+        let status = self.buf + HEADER_SIZE;
+        unsafe {
+            (self.buf as *mut u32).write_volatile(kind);
+            ((self.buf + 4) as *mut u32).write_volatile(0);
+            ((self.buf + 8) as *mut u64).write_volatile(sector);
+            (status as *mut u8).write_volatile(u8::MAX);
+        }
+
+        let mut chain = [(0, 0, false); QUEUE_SIZE as usize];
+        chain[0] = (self.buf, HEADER_SIZE, false);
+        for (desc, &(addr, len)) in chain[1..].iter_mut().zip(data) {
+            *desc = (addr, len, device_writes);
+        }
+        chain[data.len() + 1] = (status, 1, true);
+        queue
+            .push_chain(&chain[..data.len() + 2])
+            .ok_or(Errno::EBUSY)?;
+
+        let deadline = timer::now() + TIMEOUT_US * timer::TIMEBASE_FREQ / 1_000_000;
+        while queue.pop().is_none() {
+            if timer::now() >= deadline {
+                return Err(Errno::EIO);
+            }
+            spin_loop();
+        }
+        match unsafe { (status as *const u8).read_volatile() } {
+            S_OK => Ok(()),
+            _ => Err(Errno::EIO),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        self.read_vectored(sector, &mut [buf])
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        self.write_vectored(sector, &[buf])
+    }
+
+    fn read_vectored(&self, sector: u64, bufs: &mut [&mut [u8]]) -> Result<(), Errno> {
+        if bufs.len() > MAX_SEGMENTS {
+            return Err(Errno::EINVAL);
+        }
+        let mut data = [(0, 0); MAX_SEGMENTS];
+        let mut len = 0;
+        for (desc, buf) in data.iter_mut().zip(bufs.iter_mut()) {
+            *desc = (buf.as_mut_ptr() as usize, buf.len());
+            len += buf.len();
+        }
+        block::check_range(self.sectors, sector, len)?;
+        self.request(T_IN, sector, &data[..bufs.len()], true)
+    }
+
+    fn write_vectored(&self, sector: u64, bufs: &[&[u8]]) -> Result<(), Errno> {
+        if self.read_only {
+            return Err(Errno::EROFS);
+        }
+        if bufs.len() > MAX_SEGMENTS {
+            return Err(Errno::EINVAL);
+        }
+        let mut data = [(0, 0); MAX_SEGMENTS];
+        let mut len = 0;
+        for (desc, buf) in data.iter_mut().zip(bufs) {
+            *desc = (buf.as_ptr() as usize, buf.len());
+            len += buf.len();
+        }
+        block::check_range(self.sectors, sector, len)?;
+        self.request(T_OUT, sector, &data[..bufs.len()], false)
+    }
+
+    fn flush(&self) -> Result<(), Errno> {
+        match self.flush {
+            true => self.request(T_FLUSH, 0, &[], false),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Sets up the request queue of `dev` and lets it start.
+fn probe(dev: Device) -> Result<VirtioBlk, Errno> {
+    let features = dev.init(F_RO | F_FLUSH)?;
+    let queue = Queue::new(dev, 0, QUEUE_SIZE)?;
+    let buf = phalloc(PAGE_SIZE).map_err(|_| Errno::ENOMEM)?.as_usize();
+    dev.ready();
+    Ok(VirtioBlk {
+        queue: Mutex::new(queue),
+        buf,
+        sectors: dev.config_u64(CONFIG_CAPACITY),
+        read_only: features & F_RO != 0,
+        flush: features & F_FLUSH != 0,
+    })
+}
+
+crate::register_subsystem!(Device, "virtio-blk", init);
+
+fn init() {
+    let mut count = 0;
+    virtio::for_each_device(DEVICE_ID, |dev| {
+        if count == MAX_DISKS {
+            return;
+        }
+        match probe(dev) {
+            Ok(disk) => {
+                DISKS[count].get_or_init(|| disk);
+                count += 1;
+            }
+            Err(err) => println!("virtio-blk: can't set up {:#x}: {err:?}", dev.base()),
+        }
+    });
+
+    for (name, disk) in NAMES.iter().zip(&DISKS[..count]) {
+        let Some(disk) = disk.get() else {
+            continue;
+        };
+        match block::register(name, disk) {
+            Ok(_) => println!(
+                "virtio-blk: {name}, {} KiB",
+                disk.sectors * SECTOR_SIZE as u64 / 1024
+            ),
+            Err(err) => println!("virtio-blk: can't register {name}: {err:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fault::{self, Fault},
+        virtio::tests::mock_device,
+    };
+
+    #[test_case]
+    fn requests_the_queue_refuses_fail() {
+        // Eight sectors.
+        let disk = probe(mock_device(&[8, 0])).unwrap();
+        assert_eq!(disk.sectors(), 8);
+        fault::inject("virtio-tx", Fault::Fail { skip: 0, count: 2 }).unwrap();
+        assert_eq!(disk.write(0, &[0; SECTOR_SIZE]), Err(Errno::EBUSY));
+        assert_eq!(disk.read(7, &mut [0; SECTOR_SIZE]), Err(Errno::EBUSY));
+        assert_eq!(fault::clear("virtio-tx"), 2);
+        assert_eq!(disk.read(8, &mut [0; SECTOR_SIZE]), Err(Errno::ENXIO));
+    }
+}
@@ -0,0 +1,335 @@
+use core::hint::spin_loop;
+
+use crate::{
+    executor::{self, Notify},
+    hart_id,
+    irq::{self, IrqReturn},
+    mem::PAGE_SIZE,
+    println,
+    stdlib::phalloc,
+    sync::{Mutex, OnceCell},
+    syscall::Errno,
+    timer,
+    tty::{self, Driver},
+    virtio::{self, Device, Queue},
+};
+
+// A virtio console (virtio 1.2, section 5.3), QEMU's `virtio-serial-device`. Each of its
+// ports becomes a tty named `hvc<n>` (see `tty::register()`), so with
+// `-device virtconsole,chardev=...` per port, `log=hvc0 console=hvc1` on the command line
+// sends kernel messages to one and runs the shell on the other.
+//
+// With the multiport feature, ports come and go through messages on the control queues:
+// the device announces each one, which is then opened. Their queues are all set up when
+// the device is, as there is no way to add some later, for up to `MAX_PORTS` ports.
+//
+// Only the first console device is used. Input is taken when the device interrupts, and
+// polled for when the console reads and on every tick, which is all there is for a device
+// without an interrupt line. Control messages are taken by a task the interrupt wakes up,
+// or which polls for them without one.
+
+const DEVICE_ID: u32 = 3;
+const F_MULTIPORT: u64 = 1 << 1;
+// Offset of `max_nr_ports` in the configuration.
+const CONFIG_MAX_NR_PORTS: usize = 4;
+
+const MAX_PORTS: usize = 4;
+const NAMES: [&str; MAX_PORTS] = ["hvc0", "hvc1", "hvc2", "hvc3"];
+
+// Control message events.
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const PORT_READY: u16 = 3;
+const PORT_OPEN: u16 = 6;
+
+// One page of buffers: those input arrives in for each port, those control messages
+// arrive in, the one control messages are sent from, and the one each port sends from.
+const RX_BUF: usize = 64;
+const RX_BUFS: u16 = 4;
+const CONTROL_RX: usize = MAX_PORTS * RX_BUFS as usize * RX_BUF;
+const CONTROL_BUFS: u16 = 4;
+const CONTROL_TX: usize = CONTROL_RX + CONTROL_BUFS as usize * RX_BUF;
+const TX: usize = CONTROL_TX + RX_BUF;
+const TX_BUF: usize = (PAGE_SIZE - TX) / MAX_PORTS;
+
+// Microseconds to wait for the device to take output or a control message.
+const TX_TIMEOUT_US: u64 = 10_000;
+// Milliseconds given to the device to announce its ports at boot, and between looks at the
+// control queue after.
+const ANNOUNCE_MS: u64 = 100;
+const POLL_MS: usize = 10;
+
+struct Port {
+    rx: Queue,
+    tx: Queue,
+    // Announced by the device, and opened.
+    open: bool,
+    // Buffer input is being read from: its id, length, and the bytes read.
+    input: Option<(u16, usize, usize)>,
+    // Output the device hadn't taken when `write()` gave up waiting.
+    tx_busy: bool,
+}
+
+struct VirtioConsole {
+    buf: usize,
+    // Receive and transmit control queues, with the multiport feature only.
+    control: Option<(Queue, Queue)>,
+    ports: [Option<Port>; MAX_PORTS],
+}
+
+static CONSOLE: Mutex<Option<VirtioConsole>> = Mutex::new(None);
+// For the interrupt handler, which can't wait for `CONSOLE`.
+static DEVICE: OnceCell<Device> = OnceCell::new();
+// Signaled on interrupts, which may come with control messages.
+static CONTROL: Notify = Notify::new();
+
+/// Returns the index of the receive queue of `port`, its transmit queue being the next.
+fn rx_queue(port: usize) -> u16 {
+    match port {
+        0 => 0,
+        _ => 2 * port as u16 + 2,
+    }
+}
+
+/// Waits for `queue` to hand back a buffer, returning whether it did in time.
+fn wait(queue: &mut Queue) -> bool {
+    let deadline = timer::now() + TX_TIMEOUT_US * timer::TIMEBASE_FREQ / 1_000_000;
+    while timer::now() < deadline {
+        if queue.pop().is_some() {
+            return true;
+        }
+        spin_loop();
+    }
+    false
+}
+
+impl VirtioConsole {
+    /// Sends a control message about port `id`.
+    fn send(&mut self, id: usize, event: u16, value: u16) {
+        let Some((_, tx)) = self.control.as_mut() else {
+            return;
+        };
+        let addr = self.buf + CONTROL_TX;
+        unsafe {
+            (addr as *mut u32).write_volatile(id as u32);
+            ((addr + 4) as *mut u16).write_volatile(event);
+            ((addr + 6) as *mut u16).write_volatile(value);
+        }
+        if tx.push(addr, 8, false).is_some() {
+            wait(tx);
+        }
+    }
+
+    /// Handles the control messages the device sent, returning the ports it announced as
+    /// a bit mask.
+    fn poll_control(&mut self) -> usize {
+        let mut added = 0;
+        while let Some((id, len)) = self.control.as_mut().and_then(|(rx, _)| rx.pop()) {
+            let rx = &mut self.control.as_mut().unwrap().0;
+            let addr = rx.addr(id);
+            let (port, event) = unsafe {
+                (
+                    (addr as *const u32).read_volatile() as usize,
+                    ((addr + 4) as *const u16).read_volatile(),
+                )
+            };
+            rx.push(addr, RX_BUF, true);
+            if len < 8 || event != DEVICE_ADD || port >= MAX_PORTS {
+                continue;
+            }
+            let Some(p) = self.ports[port].as_mut() else {
+                continue;
+            };
+            if !p.open {
+                p.open = true;
+                added |= 1 << port;
+                self.send(port, PORT_READY, 1);
+                self.send(port, PORT_OPEN, 1);
+            }
+        }
+        added
+    }
+}
+
+fn write(port: usize, buf: &[u8]) -> bool {
+    // Printing while the console is in use here would deadlock.
+    let Some(mut guard) = CONSOLE.try_lock() else {
+        return false;
+    };
+    let Some(console) = guard.as_mut() else {
+        return false;
+    };
+    let addr = console.buf + TX + port * TX_BUF;
+    let Some(p) = console.ports[port].as_mut().filter(|p| p.open) else {
+        return false;
+    };
+    if p.tx_busy {
+        if p.tx.pop().is_none() {
+            return false;
+        }
+        p.tx_busy = false;
+    }
+    for chunk in buf.chunks(TX_BUF) {
+        unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), addr as *mut u8, chunk.len()) };
+        p.tx.push(addr, chunk.len(), false);
+        if !wait(&mut p.tx) {
+            // Dropping the rest, as the buffer is still the device's.
+            p.tx_busy = true;
+            break;
+        }
+    }
+    true
+}
+
+fn getchar(port: usize) -> Option<u8> {
+    let mut guard = CONSOLE.try_lock()?;
+    let p = guard.as_mut()?.ports[port].as_mut()?;
+    loop {
+        let (id, len, read) = match p.input {
+            Some(input) => input,
+            None => {
+                let (id, len) = p.rx.pop()?;
+                (id, len, 0)
+            }
+        };
+        let addr = p.rx.addr(id);
+        if read < len {
+            p.input = Some((id, len, read + 1));
+            return Some(unsafe { ((addr + read) as *const u8).read_volatile() });
+        }
+        p.input = None;
+        p.rx.push(addr, RX_BUF, true);
+    }
+}
+
+/// Makes the ports in the bit mask `ports` ttys.
+fn register(ports: usize) {
+    for port in (0..MAX_PORTS).filter(|port| ports & (1 << port) != 0) {
+        tty::register(Driver {
+            name: NAMES[port],
+            write,
+            getchar,
+            arg: port,
+        });
+        println!("virtio-console: port {}", NAMES[port]);
+    }
+}
+
+/// Sets up the queues of `dev` and lets it start, returning whether it has the multiport
+/// feature.
+fn probe(dev: Device) -> Result<bool, Errno> {
+    let multiport = dev.init(F_MULTIPORT)? & F_MULTIPORT != 0;
+    let nr_ports = match multiport {
+        true => (dev.config_u32(CONFIG_MAX_NR_PORTS) as usize).clamp(1, MAX_PORTS),
+        false => 1,
+    };
+
+    let mut ports = [const { None }; MAX_PORTS];
+    for (port, slot) in ports.iter_mut().enumerate().take(nr_ports) {
+        *slot = Some(Port {
+            rx: Queue::new(dev, rx_queue(port), RX_BUFS)?,
+            tx: Queue::new(dev, rx_queue(port) + 1, 1)?,
+            // Without the multiport feature the one port is there from the start.
+            open: !multiport,
+            input: None,
+            tx_busy: false,
+        });
+    }
+    let control = match multiport {
+        true => Some((Queue::new(dev, 2, CONTROL_BUFS)?, Queue::new(dev, 3, 1)?)),
+        false => None,
+    };
+    let buf = phalloc(PAGE_SIZE).map_err(|_| Errno::ENOMEM)?.as_usize();
+    dev.ready();
+
+    let mut console = VirtioConsole {
+        buf,
+        control,
+        ports,
+    };
+    for (port, p) in console.ports.iter_mut().flatten().enumerate() {
+        for i in 0..RX_BUFS as usize {
+            p.rx.push(buf + (port * RX_BUFS as usize + i) * RX_BUF, RX_BUF, true);
+        }
+    }
+    if let Some((rx, _)) = console.control.as_mut() {
+        for i in 0..CONTROL_BUFS as usize {
+            rx.push(buf + CONTROL_RX + i * RX_BUF, RX_BUF, true);
+        }
+    }
+    console.send(0, DEVICE_READY, 1);
+    *CONSOLE.lock() = Some(console);
+    Ok(multiport)
+}
+
+/// Takes the input that arrived and wakes up the control task, in interrupt context.
+fn on_irq(_irq: usize) -> IrqReturn {
+    if !DEVICE.get().is_some_and(Device::ack_interrupt) {
+        return IrqReturn::None;
+    }
+    tty::on_input();
+    CONTROL.notify();
+    IrqReturn::Handled
+}
+
+/// Has the device's interrupts go to the calling hart, the boot hart, the only one that
+/// takes device interrupts.
+fn request_irq(dev: Device) -> Result<(), Errno> {
+    let irq = dev.irq().ok_or(Errno::ENODEV)?;
+    DEVICE.get_or_init(|| dev);
+    irq::request_irq(irq, "virtio-console", on_irq)?;
+    irq::set_affinity(irq, 1 << hart_id())
+}
+
+/// Handles control messages, making the ports announced since ttys.
+fn poll() {
+    let added = CONSOLE.lock().as_mut().map_or(0, |c| c.poll_control());
+    register(added);
+}
+
+crate::register_subsystem!(Device, "virtio-console", init);
+
+fn init() {
+    let mut found = None;
+    virtio::for_each_device(DEVICE_ID, |dev| {
+        found.get_or_insert(dev);
+    });
+    let Some(dev) = found else {
+        return;
+    };
+    let multiport = match probe(dev) {
+        Ok(multiport) => multiport,
+        Err(err) => {
+            println!("virtio-console: can't set up {:#x}: {err:?}", dev.base());
+            return;
+        }
+    };
+    // Without one, input is only polled for.
+    let irq = request_irq(dev)
+        .inspect_err(|err| println!("virtio-console: no interrupt: {err:?}"))
+        .is_ok();
+    if !multiport {
+        register(1);
+        return;
+    }
+
+    // Gives the device time to announce its ports, so one `console=` names is there for
+    // the first process.
+    let deadline = timer::now() + ANNOUNCE_MS * timer::TIMEBASE_FREQ / 1000;
+    while timer::now() < deadline {
+        poll();
+        spin_loop();
+    }
+    let task = async move {
+        loop {
+            match irq {
+                true => CONTROL.notified().await,
+                false => executor::sleep_ms(POLL_MS).await,
+            }
+            poll();
+        }
+    };
+    if let Err(err) = executor::spawn(task) {
+        println!("virtio-console: can't poll for ports: {err:?}");
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{
+    console, executor, hart_id,
+    irq::{self, IrqReturn},
+    mem::PAGE_SIZE,
+    println,
+    stdlib::phalloc,
+    sync::{Mutex, OnceCell},
+    syscall::Errno,
+    virtio::{self, Device, Queue},
+};
+
+// Virtio input devices (virtio 1.2, section 5.8), QEMU's `virtio-keyboard-device`: key
+// presses are turned into the characters a US keyboard types and fed to the console as if
+// they came over the serial line (see `console::feed()`), so the shell can be used from
+// QEMU's window. Ctrl with a letter types its control character, Ctrl-C and Ctrl-D
+// included. Keys without a character, arrows and function keys among them, are ignored.
+//
+// Devices send events in Linux's `input_event` encoding, with its key codes. Pointing
+// devices send some too, for buttons, which have no character and are ignored as well.
+// Events are taken by the thread part of the device's interrupt handler, in `irqd`, as the
+// console may echo them. A task polls for those of devices without an interrupt line.
+
+const DEVICE_ID: u32 = 18;
+const MAX_DEVICES: usize = 2;
+// Buffers events arrive in, one event each.
+const EVENT_BUFS: u16 = 16;
+const EVENT_SIZE: usize = 8;
+// Milliseconds between looks at the event queues.
+const POLL_MS: usize = 10;
+
+// Event types and values.
+const EV_KEY: u16 = 1;
+const KEY_RELEASED: u32 = 0;
+
+// Key codes of the modifiers.
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_RIGHTCTRL: u16 = 97;
+
+// Characters of key codes up to Space on a US keyboard, 0 for none, without and with Shift.
+const KEYMAP: &[u8; 58] =
+    b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\r\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const SHIFTED: &[u8; 58] =
+    b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\r\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+/// State of the modifier keys.
+#[derive(Default)]
+struct Keyboard {
+    shift: bool,
+    ctrl: bool,
+    caps_lock: bool,
+}
+
+impl Keyboard {
+    /// Takes a key event, returning the character it types, if any.
+    fn key(&mut self, code: u16, value: u32) -> Option<u8> {
+        let pressed = value != KEY_RELEASED;
+        match code {
+            KEY_LEFTSHIFT | KEY_RIGHTSHIFT => self.shift = pressed,
+            KEY_LEFTCTRL | KEY_RIGHTCTRL => self.ctrl = pressed,
+            // Not on repeats.
+            KEY_CAPSLOCK if value == 1 => self.caps_lock = !self.caps_lock,
+            _ if pressed => {
+                let keymap = if self.shift { SHIFTED } else { KEYMAP };
+                let c = *keymap.get(code as usize).filter(|&&c| c != 0)?;
+                return Some(match c {
+                    b'a'..=b'z' | b'A'..=b'Z' if self.ctrl => c & 0x1f,
+                    b'a'..=b'z' | b'A'..=b'Z' if self.caps_lock => c ^ 0x20,
+                    _ => c,
+                });
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+struct Input {
+    events: Queue,
+    keyboard: Keyboard,
+}
+
+static DEVICES: Mutex<[Option<Input>; MAX_DEVICES]> = Mutex::new([const { None }; MAX_DEVICES]);
+// The devices again, for the hard interrupt handler, which can't wait for `DEVICES`.
+static IRQ_DEVICES: [OnceCell<Device>; MAX_DEVICES] = [const { OnceCell::new() }; MAX_DEVICES];
+
+/// Sets up the event queue of `dev`, filling it with buffers.
+fn probe(dev: Device) -> Result<Input, Errno> {
+    dev.init(0)?;
+    let mut events = Queue::new(dev, 0, EVENT_BUFS)?;
+    let buf = phalloc(PAGE_SIZE).map_err(|_| Errno::ENOMEM)?.as_usize();
+    dev.ready();
+    for i in 0..EVENT_BUFS as usize {
+        events.push(buf + i * EVENT_SIZE, EVENT_SIZE, true);
+    }
+    Ok(Input {
+        events,
+        keyboard: Keyboard::default(),
+    })
+}
+
+/// Feeds the characters of the key events devices sent to the console.
+fn poll() {
+    let mut typed = [0; EVENT_BUFS as usize];
+    let mut len = 0;
+    for input in DEVICES.lock().iter_mut().flatten() {
+        while let Some((id, written)) = input.events.pop() {
+            let addr = input.events.addr(id);
+            let (kind, code, value) = unsafe {
+                (
+                    (addr as *const u16).read_volatile(),
+                    ((addr + 2) as *const u16).read_volatile(),
+                    ((addr + 4) as *const u32).read_volatile(),
+                )
+            };
+            input.events.push(addr, EVENT_SIZE, true);
+            if written < EVENT_SIZE || kind != EV_KEY || len == typed.len() {
+                continue;
+            }
+            if let Some(c) = input.keyboard.key(code, value) {
+                typed[len] = c;
+                len += 1;
+            }
+        }
+    }
+    // Not with the devices locked, the console may print its echo.
+    console::feed(&typed[..len]);
+}
+
+/// Acknowledges the interrupt of the device on line `irq`, leaving its events to `poll()`.
+fn on_irq(irq: usize) -> IrqReturn {
+    // All of those on the line, if it is shared.
+    let mut raised = false;
+    for dev in IRQ_DEVICES.iter().filter_map(OnceCell::get) {
+        if dev.irq().is_some_and(|line| line.line() == irq) {
+            raised |= dev.ack_interrupt();
+        }
+    }
+    match raised {
+        true => IrqReturn::WakeThread,
+        false => IrqReturn::None,
+    }
+}
+
+/// Has `irqd` take the events of device `index` whenever it interrupts, which goes to the
+/// calling hart, the boot hart, the only one that takes device interrupts.
+fn request_irq(index: usize, dev: Device) -> Result<(), Errno> {
+    let irq = dev.irq().ok_or(Errno::ENODEV)?;
+    IRQ_DEVICES[index].get_or_init(|| dev);
+    irq::request_threaded_irq(irq, "virtio-input", on_irq, |_| poll())?;
+    irq::set_affinity(irq, 1 << hart_id())
+}
+
+crate::register_subsystem!(Device, "virtio-input", init);
+
+fn init() {
+    let mut devices = DEVICES.lock();
+    let mut count = 0;
+    let mut polled = false;
+    virtio::for_each_device(DEVICE_ID, |dev| {
+        if count == MAX_DEVICES {
+            return;
+        }
+        match probe(dev) {
+            Ok(input) => {
+                devices[count] = Some(input);
+                polled |= request_irq(count, dev).is_err();
+                count += 1;
+            }
+            Err(err) => println!("virtio-input: can't set up {:#x}: {err:?}", dev.base()),
+        }
+    });
+    drop(devices);
+    if !polled {
+        return;
+    }
+
+    let task = async {
+        loop {
+            executor::sleep_ms(POLL_MS).await;
+            poll();
+        }
+    };
+    if let Err(err) = executor::spawn(task) {
+        println!("virtio-input: can't poll for events: {err:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn key_events_type_characters() {
+        let mut keyboard = Keyboard::default();
+        let mut typed = [0; 8];
+        let mut len = 0;
+        // "Hi", Enter, then Ctrl-C, with a repeat and releases in between.
+        for (code, value) in [
+            (KEY_LEFTSHIFT, 1),
+            (35, 1),
+            (35, 0),
+            (KEY_LEFTSHIFT, 0),
+            (23, 1),
+            (23, 2),
+            (23, 0),
+            (28, 1),
+            (KEY_LEFTCTRL, 1),
+            (46, 1),
+            (KEY_LEFTCTRL, 0),
+            (103, 1),
+        ] {
+            if let Some(c) = keyboard.key(code, value) {
+                typed[len] = c;
+                len += 1;
+            }
+        }
+        assert_eq!(&typed[..len], b"Hii\r\x03");
+
+        keyboard.key(KEY_CAPSLOCK, 1);
+        assert_eq!(keyboard.key(30, 1), Some(b'A'));
+        assert_eq!(keyboard.key(2, 1), Some(b'1'));
+    }
+}
@@ -1,55 +1,1068 @@
-use crate::{mem::PAGE_SIZE, panic, stdlib::FixedVec};
+use crate::{
+    csr::SatpMode,
+    mem::{PAGE_SIZE, PhysAddr},
+    page, pagecache, panic,
+    stdlib::{phalloc, phalloc_uninit, phalloc_zeroed, phree},
+    swap,
+    sync::OnceCell,
+    syscall::Errno,
+};
+
+// Paging mode of the target: Sv32 (two levels of 1024 entries) on rv32,
+// Sv39 (three levels of 512 entries) on rv64.
+#[cfg(target_arch = "riscv32")]
+pub const SATP_MODE: SatpMode = SatpMode::Sv32;
+#[cfg(target_arch = "riscv32")]
+const LEVELS: usize = 2;
+#[cfg(target_arch = "riscv64")]
+pub const SATP_MODE: SatpMode = SatpMode::Sv39;
+#[cfg(target_arch = "riscv64")]
+const LEVELS: usize = 3;
+
+// Each table fills one page with `usize` entries.
+const ENTRIES: usize = PAGE_SIZE / size_of::<usize>();
+const VPN_BITS: u32 = ENTRIES.trailing_zeros();
 
-// SATP: Supervisor Address Translation and Protection
-pub const SATP_SV32: usize = 1 << 31;
 pub const PAGE_V: usize = 1 << 0;
 pub const PAGE_R: usize = 1 << 1;
 pub const PAGE_W: usize = 1 << 2;
 pub const PAGE_X: usize = 1 << 3;
 pub const PAGE_U: usize = 1 << 4;
+// Set by the MMU when the page is accessed, and written.
+const PAGE_A: usize = 1 << 6;
+const PAGE_D: usize = 1 << 7;
+// A software bit: a user page that is mapped but inaccessible (`PROT_NONE`), kept with its
+// frame but without `PAGE_V`, which with no R, W or X would mean a pointer to a table.
+const PAGE_PROT_NONE: usize = 1 << 8;
+// A software bit: a user page written out to swap, with the slot where the frame number
+// would be and its `PAGE_R`, `PAGE_W`, `PAGE_X` and `PAGE_U` kept, but not `PAGE_V`.
+const PAGE_SWAP: usize = 1 << 9;
+
+// Protections `mprotect()` takes, as Linux numbers them.
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+/// Most VMAs an address space has.
+pub const VMA_MAX: usize = 16;
+
+/// Aging scans in a row a page can go unused and still be in the working set.
+pub const WORKING_SET_SCANS: usize = 4;
+
+/// A run of consecutive pages mapped to consecutive frames with the same permissions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub paddr: usize,
+    // `PAGE_R`, `PAGE_W`, `PAGE_X` and `PAGE_U`.
+    pub flags: usize,
+}
+
+impl core::fmt::Display for Region {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let perm = |flag, c| if self.flags & flag != 0 { c } else { '-' };
+        write!(
+            f,
+            "0x{:08x}-0x{:08x} {}{}{}{} -> 0x{:08x}",
+            self.start,
+            self.end,
+            perm(PAGE_R, 'r'),
+            perm(PAGE_W, 'w'),
+            perm(PAGE_X, 'x'),
+            perm(PAGE_U, 'u'),
+            self.paddr,
+        )
+    }
+}
+
+/// A virtual memory area: a range of the user address space, whole pages, and the access
+/// the process asked for to it, which its pages are mapped with.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    // `PAGE_R`, `PAGE_W` and `PAGE_X`.
+    pub prot: usize,
+    pub backing: Backing,
+}
+
+/// What the pages of a VMA hold until they are written.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Backing {
+    /// Zeros, or what the kernel put there, like a program's segments.
+    #[default]
+    Anon,
+    /// Inode `ino` of `mount`, from `offset` at the start of the VMA, mapped from the page
+    /// cache (see `mmap`). Writes go to the file if `shared`, to a copy of the page if not.
+    File {
+        mount: usize,
+        ino: usize,
+        offset: usize,
+        shared: bool,
+    },
+}
+
+impl Vma {
+    /// Returns the part of the VMA in `start..end`, empty if there is none.
+    pub fn clip(self, start: usize, end: usize) -> Vma {
+        let (start, end) = (start.max(self.start), end.min(self.end));
+        let backing = match self.backing {
+            Backing::File {
+                mount,
+                ino,
+                offset,
+                shared,
+            } => Backing::File {
+                mount,
+                ino,
+                offset: offset + start.saturating_sub(self.start),
+                shared,
+            },
+            Backing::Anon => Backing::Anon,
+        };
+        Vma {
+            start,
+            end,
+            backing,
+            ..self
+        }
+    }
+
+    /// Returns whether `next`, starting where the VMA ends, may be merged into it.
+    fn continues_into(&self, next: &Vma) -> bool {
+        self.end == next.start
+            && self.prot == next.prot
+            // The file continuing at the same offset, if it is one.
+            && self.clip(self.end, self.end).backing == next.backing
+    }
+}
+
+/// Memory an address space uses, in pages, counted as pages are mapped and unmapped.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MemUsage {
+    /// User pages mapped.
+    pub resident: usize,
+    /// Most user pages mapped at once.
+    pub peak: usize,
+    /// Pages of the table itself, at every level.
+    pub tables: usize,
+    /// User pages used recently, as of the last `PageTable::age()`.
+    pub working_set: usize,
+    /// User pages written since they were mapped, as of the last `PageTable::age()`.
+    pub dirty: usize,
+    /// User pages written out to swap.
+    pub swapped: usize,
+}
 
 #[derive(Debug)]
 pub struct PageTable {
-    root_pt: FixedVec<usize>,
-    second_pts: FixedVec<FixedVec<usize>>,
+    // Physical address of the root table, tables of the lower levels are only reachable through it.
+    root: usize,
+    // The user address space's VMAs, in address order, not overlapping.
+    vmas: [Vma; VMA_MAX],
+    vma_len: usize,
+    usage: MemUsage,
+}
+
+/// Returns the index into the table at `level` (0 being the leaf level) that `vaddr` uses.
+fn vpn(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + level as u32 * VPN_BITS)) & (ENTRIES - 1)
+}
+
+fn pte_addr(pte: usize) -> usize {
+    (pte >> 10) * PAGE_SIZE
+}
+
+/// Returns the leaf entry mapping `paddr` with `flags`: an inaccessible page if they have
+/// none of R, W and X.
+fn leaf(paddr: usize, flags: usize) -> usize {
+    let ppn = (paddr / PAGE_SIZE) << 10;
+    match flags & (PAGE_R | PAGE_W | PAGE_X) {
+        0 => ppn | flags | PAGE_PROT_NONE,
+        // Sv32 and Sv39 reserve writable pages that aren't readable.
+        PAGE_W => ppn | flags | PAGE_R | PAGE_V,
+        _ => ppn | flags | PAGE_V,
+    }
+}
+
+/// Returns the swap slot of `pte`, if it is of a page written out to swap.
+fn swap_slot(pte: usize) -> Option<usize> {
+    (pte & (PAGE_V | PAGE_SWAP) == PAGE_SWAP).then_some(pte >> 10)
+}
+
+/// Returns whether `pte` maps a page, rather than pointing to the next level or nothing.
+fn is_leaf(pte: usize) -> bool {
+    match pte & PAGE_V {
+        0 => pte & PAGE_PROT_NONE != 0,
+        _ => pte & (PAGE_R | PAGE_W | PAGE_X) != 0,
+    }
+}
+
+fn table<'a>(addr: usize) -> &'a mut [usize; ENTRIES] {
+    unsafe { &mut *(addr as *mut [usize; ENTRIES]) }
+}
+
+fn alloc_table() -> usize {
+    let addr = phalloc(PAGE_SIZE).expect("out of memory for page tables.");
+    unsafe { addr.as_mut_ptr().write_bytes(0, PAGE_SIZE) };
+    if let Some(page) = page::get(addr.as_usize()) {
+        page.set_owner(page::Owner::PageTable);
+    }
+    addr.as_usize()
+}
+
+/// Calls `f` with the virtual address, physical address, size and flags of every leaf
+/// entry of the table at `addr` on `level`, whose first entry maps `base`.
+fn walk(addr: usize, level: usize, base: usize, f: &mut impl FnMut(usize, usize, usize, usize)) {
+    let shift = 12 + level as u32 * VPN_BITS;
+
+    for (i, &pte) in table(addr).iter().enumerate() {
+        let vaddr = base | (i << shift);
+        if pte & PAGE_V != 0 && !is_leaf(pte) {
+            walk(pte_addr(pte), level - 1, vaddr, f);
+        } else if is_leaf(pte) {
+            let flags = pte & (PAGE_R | PAGE_W | PAGE_X | PAGE_U);
+            f(vaddr, pte_addr(pte), 1 << shift, flags);
+        }
+    }
+}
+
+/// Unmaps the user pages (`PAGE_U`) below the table at `addr` on `level` and frees their
+/// frames, and the swap slots of those written out, returning how many pages were mapped.
+fn free_user(addr: usize, level: usize) -> usize {
+    let mut freed = 0;
+    for pte in table(addr).iter_mut() {
+        if *pte & PAGE_V != 0 && !is_leaf(*pte) {
+            freed += free_user(pte_addr(*pte), level - 1);
+        } else {
+            let size = PAGE_SIZE << (level as u32 * VPN_BITS);
+            freed += unmap_user(pte, size);
+        }
+    }
+    freed
+}
+
+/// Unmaps the user page of leaf entry `pte`, of `size` bytes, freeing its frame or swap
+/// slot, and returns how many pages were mapped.
+fn unmap_user(pte: &mut usize, size: usize) -> usize {
+    let mut freed = 0;
+    if let Some(slot) = swap_slot(*pte) {
+        swap::free(slot);
+        *pte = 0;
+    } else if is_leaf(*pte) && *pte & PAGE_U != 0 {
+        let frame = pte_addr(*pte);
+        // Pages of the cache are only lent.
+        if pagecache::is_cached(frame) {
+            pagecache::unmap(frame, *pte & PAGE_W != 0);
+            freed = 1;
+        } else if !is_zero_page(frame) {
+            phree(PhysAddr::new(frame, Some(size)));
+            freed = size / PAGE_SIZE;
+        }
+        *pte = 0;
+    }
+    freed
+}
+
+/// Calls `f` with every user leaf entry below the table at `addr` on `level` that maps a
+/// frame of its own, not the zero page.
+fn for_each_user_pte(addr: usize, level: usize, f: &mut impl FnMut(&mut usize)) {
+    for pte in table(addr).iter_mut() {
+        // Tables end at level 0, an entry there without R, W or X isn't followed.
+        if level > 0 && *pte & PAGE_V != 0 && !is_leaf(*pte) {
+            for_each_user_pte(pte_addr(*pte), level - 1, f);
+        } else if is_leaf(*pte) && *pte & PAGE_U != 0 && !is_zero_page(pte_addr(*pte)) {
+            f(pte);
+        }
+    }
+}
+
+fn free_table(addr: usize, level: usize) {
+    if level > 0 {
+        for &pte in table(addr).iter() {
+            // Entries without R, W, or X point to the next level.
+            if pte & PAGE_V != 0 && pte & (PAGE_R | PAGE_W | PAGE_X) == 0 {
+                free_table(pte_addr(pte), level - 1);
+            }
+        }
+    }
+    phree(PhysAddr::new(addr, Some(PAGE_SIZE)));
 }
 
 impl PageTable {
     pub fn new() -> Self {
         Self {
-            // each page table level has 2^10 entries.
-            // each entry is 32 bits wide, hense
-            // each level fits into one page.
-            root_pt: FixedVec::new(1024),
-            second_pts: FixedVec::new(1024),
+            root: alloc_table(),
+            vmas: [Vma::default(); VMA_MAX],
+            vma_len: 0,
+            usage: MemUsage {
+                tables: 1,
+                ..MemUsage::default()
+            },
         }
     }
 
+    pub fn usage(&self) -> MemUsage {
+        self.usage
+    }
+
     pub fn root_pt_addr(&self) -> usize {
-        self.root_pt.as_ptr() as usize
+        self.root
     }
 
     pub fn map_page(&mut self, vaddr: usize, paddr: usize, flags: usize) {
-        if vaddr % PAGE_SIZE != 0 {
+        if !vaddr.is_multiple_of(PAGE_SIZE) {
             panic!("unaligned vaddr {vaddr:x}");
         }
-        if paddr % PAGE_SIZE != 0 {
+        if !paddr.is_multiple_of(PAGE_SIZE) {
             panic!("unaligned paddr {paddr:x}");
         }
 
-        let vpn1 = vaddr >> 22 & 0x3ff;
+        let mut pt = self.root;
+        for level in (1..LEVELS).rev() {
+            let pte = &mut table(pt)[vpn(vaddr, level)];
+            if (*pte & PAGE_V) == 0 {
+                // PTE is not valid,
+                // lets create the non-existing next level page table
+                *pte = ((alloc_table() / PAGE_SIZE) << 10) | PAGE_V;
+                self.usage.tables += 1;
+            }
+            pt = pte_addr(*pte);
+        }
+
+        // The zero page isn't the memory of any one process.
+        let pte = &mut table(pt)[vpn(vaddr, 0)];
+        let was_user = is_leaf(*pte) && *pte & PAGE_U != 0 && !is_zero_page(pte_addr(*pte));
+        let is_user = flags & PAGE_U != 0 && !is_zero_page(paddr);
+        *pte = leaf(paddr, flags);
+        match (was_user, is_user) {
+            (false, true) => {
+                self.usage.resident += 1;
+                self.usage.peak = self.usage.peak.max(self.usage.resident);
+            }
+            (true, false) => self.usage.resident -= 1,
+            _ => {}
+        }
+        // User pages are the process's own memory until they are unmapped and freed, unless
+        // the page cache lent them.
+        if is_user
+            && !pagecache::is_cached(paddr)
+            && let Some(page) = page::get(paddr)
+        {
+            page.set_owner(page::Owner::User);
+            page.set_flags(page::USER_ANON);
+        }
+    }
+
+    /// Unmaps all user pages, which the table owns, and frees them.
+    pub fn free_user_pages(&mut self) {
+        self.usage.resident -= free_user(self.root, LEVELS - 1);
+        self.usage.swapped = 0;
+        unsafe { core::arch::asm!("sfence.vma") };
+    }
+
+    /// Ages the user pages: those the MMU marked accessed since the last scan are young
+    /// again, with the mark cleared for the next one, the others a scan older. Updates the
+    /// working set and dirty pages in `usage()`, and returns how many pages were accessed.
+    ///
+    /// FIXME: Only this hart's TLB is flushed, another may keep using a cached entry
+    /// without marking the page accessed again, which then looks unused.
+    pub fn age(&mut self) -> usize {
+        let (mut accessed, mut working_set, mut dirty) = (0, 0, 0);
+        for_each_user_pte(self.root, LEVELS - 1, &mut |pte| {
+            let young = *pte & PAGE_A != 0;
+            *pte &= !PAGE_A;
+            accessed += young as usize;
+            dirty += (*pte & PAGE_D != 0) as usize;
+            let age = page::get(pte_addr(*pte)).map_or(0, |page| page.on_scan(young));
+            working_set += (age < WORKING_SET_SCANS) as usize;
+        });
+        unsafe { core::arch::asm!("sfence.vma") };
+        self.usage.working_set = working_set;
+        self.usage.dirty = dirty;
+        accessed
+    }
+
+    /// Returns the physical address `vaddr` is mapped to, or `None` if it isn't mapped.
+    pub fn translate(&self, vaddr: usize) -> Option<usize> {
+        let mut pt = self.root;
+        for level in (0..LEVELS).rev() {
+            let pte = table(pt)[vpn(vaddr, level)];
+            if (pte & PAGE_V) == 0 {
+                return None;
+            }
+            pt = pte_addr(pte);
+        }
+
+        Some(pt + vaddr % PAGE_SIZE)
+    }
+
+    /// Returns the leaf entry of the 4 KiB page at `vaddr`, if its tables exist.
+    fn pte_mut(&mut self, vaddr: usize) -> Option<&'static mut usize> {
+        let mut pt = self.root;
+        for level in (1..LEVELS).rev() {
+            let pte = table(pt)[vpn(vaddr, level)];
+            if pte & PAGE_V == 0 || is_leaf(pte) {
+                return None;
+            }
+            pt = pte_addr(pte);
+        }
+        Some(&mut table(pt)[vpn(vaddr, 0)])
+    }
+
+    /// Returns whether every page in `start..end` is a user page (`PAGE_U`) mapped with all
+    /// of `flags`.
+    pub fn allows(&self, start: usize, end: usize, flags: usize) -> bool {
+        let required = PAGE_V | PAGE_U | flags;
+        (start & !(PAGE_SIZE - 1)..end)
+            .step_by(PAGE_SIZE)
+            .all(|vaddr| {
+                self.pte(vaddr)
+                    .is_some_and(|pte| pte & required == required)
+            })
+    }
+
+    /// Returns the leaf entry of the 4 KiB page at `vaddr`, if its tables exist.
+    fn pte(&self, vaddr: usize) -> Option<usize> {
+        let mut pt = self.root;
+        for level in (1..LEVELS).rev() {
+            let pte = table(pt)[vpn(vaddr, level)];
+            if pte & PAGE_V == 0 || is_leaf(pte) {
+                return None;
+            }
+            pt = pte_addr(pte);
+        }
+        Some(table(pt)[vpn(vaddr, 0)])
+    }
+
+    /// Changes the access to the user pages mapped in `start..end` to `prot` (`PAGE_R`,
+    /// `PAGE_W` and `PAGE_X`), leaving other pages alone.
+    pub fn protect_range(&mut self, start: usize, end: usize, prot: usize) {
+        for vaddr in (start..end).step_by(PAGE_SIZE) {
+            let Some(pte) = self.pte_mut(vaddr) else {
+                continue;
+            };
+            let frame = pte_addr(*pte);
+            if is_leaf(*pte) && is_zero_page(frame) {
+                *pte = leaf(frame, PAGE_U | read_only(prot));
+            } else if is_leaf(*pte) && pagecache::is_cached(frame) {
+                // Written to the file, or copied, only once a write faults, see `mmap`.
+                if *pte & PAGE_W != 0 {
+                    pagecache::make_read_only(frame);
+                }
+                *pte = leaf(frame, PAGE_U | read_only(prot));
+            } else if is_leaf(*pte) && *pte & PAGE_U != 0 {
+                *pte = leaf(pte_addr(*pte), PAGE_U | prot);
+            } else if swap_slot(*pte).is_some() {
+                *pte = (*pte & !(PAGE_R | PAGE_W | PAGE_X)) | prot;
+            }
+        }
+        unsafe { core::arch::asm!("sfence.vma") };
+    }
+
+    /// Unmaps the user pages in `start..end`, freeing their frames or swap slots.
+    pub fn unmap_range(&mut self, start: usize, end: usize) {
+        for vaddr in (start..end).step_by(PAGE_SIZE) {
+            if let Some(pte) = self.pte_mut(vaddr) {
+                let resident = is_leaf(*pte) && *pte & PAGE_U != 0 && !is_zero_page(pte_addr(*pte));
+                let swapped = swap_slot(*pte).is_some();
+                unmap_user(pte, PAGE_SIZE);
+                self.usage.resident -= resident as usize;
+                self.usage.swapped -= swapped as usize;
+            }
+        }
+        unsafe { core::arch::asm!("sfence.vma") };
+    }
+
+    /// Returns the frame the user page at `vaddr` maps, and whether it is writable.
+    pub fn user_page(&self, vaddr: usize) -> Option<(usize, bool)> {
+        self.pte(vaddr)
+            .filter(|&pte| is_leaf(pte) && pte & PAGE_U != 0)
+            .map(|pte| (pte_addr(pte), pte & PAGE_W != 0))
+    }
+
+    /// Maps page `frame` of the page cache at `vaddr` as a user page with `prot`, read-only
+    /// unless `writable`, in which case writes go to the cache's page, see `mmap`. The
+    /// caller hands over a reference to it.
+    pub fn map_cache_page(&mut self, vaddr: usize, frame: usize, prot: usize, writable: bool) {
+        let flags = match writable {
+            true => prot,
+            false => read_only(prot),
+        };
+        self.map_page(vaddr, frame, PAGE_U | flags);
+        if writable {
+            pagecache::make_writable(frame);
+        }
+        // A read-only entry may be cached.
+        unsafe { core::arch::asm!("sfence.vma") };
+    }
+
+    /// Gives the user page at `vaddr` a copy of the frame it maps, mapped with `flags`,
+    /// letting go of the old one, which the page cache lent. Returns the copy.
+    ///
+    /// Fails with `ENOMEM` if there is no memory for it.
+    pub fn copy_cache_page(&mut self, vaddr: usize, flags: usize) -> Result<usize, Errno> {
+        let vaddr = vaddr & !(PAGE_SIZE - 1);
+        let Some((old, writable)) = self.user_page(vaddr) else {
+            return Err(Errno::EFAULT);
+        };
+        let frame = phalloc_uninit(PAGE_SIZE).map_err(|_| Errno::ENOMEM)?;
+        unsafe {
+            frame
+                .as_mut_ptr()
+                .copy_from_nonoverlapping(old as *const u8, PAGE_SIZE)
+        };
+        self.map_page(vaddr, frame.as_usize(), flags);
+        pagecache::unmap(old, writable);
+        unsafe { core::arch::asm!("sfence.vma") };
+        Ok(frame.as_usize())
+    }
+}
+
+// MARK - SWAP
+
+impl PageTable {
+    /// Calls `f` with the virtual and physical address of every user page mapped, in
+    /// address order.
+    pub fn for_each_user_page(&self, mut f: impl FnMut(usize, usize)) {
+        walk(
+            self.root,
+            LEVELS - 1,
+            0,
+            &mut |vaddr, paddr, size, flags| {
+                if flags & PAGE_U != 0 && size == PAGE_SIZE {
+                    f(vaddr, paddr);
+                }
+            },
+        );
+    }
+
+    /// Calls `f` with the virtual address, swap slot and flags of every page written out to
+    /// swap.
+    pub fn for_each_swapped(&self, f: impl FnMut(usize, usize, usize)) {
+        fn visit(addr: usize, level: usize, base: usize, f: &mut impl FnMut(usize, usize, usize)) {
+            let shift = 12 + level as u32 * VPN_BITS;
+            for (i, &pte) in table(addr).iter().enumerate() {
+                let vaddr = base | (i << shift);
+                if let Some(slot) = swap_slot(pte) {
+                    f(vaddr, slot, pte & (PAGE_R | PAGE_W | PAGE_X | PAGE_U));
+                } else if pte & PAGE_V != 0 && !is_leaf(pte) {
+                    visit(pte_addr(pte), level - 1, vaddr, f);
+                }
+            }
+        }
+        visit(self.root, LEVELS - 1, 0, &mut { f });
+    }
+
+    /// Replaces the user page at `vaddr` with a reference to swap slot `slot`, which must
+    /// hold its contents, returning its frame for the caller to free. `None` if there is
+    /// no user page there.
+    pub fn swap_out(&mut self, vaddr: usize, slot: usize) -> Option<usize> {
+        let pte = self.pte_mut(vaddr)?;
+        if !is_leaf(*pte) || *pte & PAGE_U == 0 {
+            return None;
+        }
+        let frame = pte_addr(*pte);
+        *pte = (slot << 10) | (*pte & (PAGE_R | PAGE_W | PAGE_X | PAGE_U)) | PAGE_SWAP;
+        unsafe { core::arch::asm!("sfence.vma") };
+        self.usage.resident -= 1;
+        self.usage.swapped += 1;
+        Some(frame)
+    }
+
+    /// Returns the swap slot of the page at `vaddr`, if it was written out to swap.
+    pub fn swapped(&self, vaddr: usize) -> Option<usize> {
+        swap_slot(self.pte(vaddr & !(PAGE_SIZE - 1))?)
+    }
+
+    /// Maps `frame`, holding the contents read back from swap, where the page at `vaddr`
+    /// was swapped out, with the access it had.
+    pub fn swap_in(&mut self, vaddr: usize, frame: usize) {
+        let vaddr = vaddr & !(PAGE_SIZE - 1);
+        let Some(pte) = self.pte_mut(vaddr).filter(|pte| swap_slot(**pte).is_some()) else {
+            return;
+        };
+        let flags = *pte & (PAGE_R | PAGE_W | PAGE_X | PAGE_U);
+        *pte = 0;
+        self.usage.swapped -= 1;
+        self.map_page(vaddr, frame, flags);
+        unsafe { core::arch::asm!("sfence.vma") };
+    }
+}
+
+// MARK - ZERO PAGE
+
+// A frame of zeros shared read-only by every page of user memory that was never written,
+// like the zeroed part of a program's data or its stack. Writing to one faults, and the
+// page gets a frame of its own (see `PageTable::unshare_zero_page()`).
+static ZERO_PAGE: OnceCell<usize> = OnceCell::new();
+
+/// Returns the physical address of the zero page, allocated on first use.
+pub fn zero_page() -> usize {
+    *ZERO_PAGE.get_or_init(|| {
+        let addr = phalloc(PAGE_SIZE).expect("out of memory for the zero page.");
+        unsafe { addr.as_mut_ptr().write_bytes(0, PAGE_SIZE) };
+        addr.as_usize()
+    })
+}
+
+pub fn is_zero_page(paddr: usize) -> bool {
+    ZERO_PAGE.get() == Some(&paddr)
+}
+
+/// Returns `flags` without `PAGE_W`, readable if they were writable.
+fn read_only(flags: usize) -> usize {
+    match flags & PAGE_W {
+        0 => flags,
+        _ => (flags & !PAGE_W) | PAGE_R,
+    }
+}
+
+impl PageTable {
+    /// Maps the zero page at `vaddr` as a user page, read-only whatever `flags` ask for:
+    /// it gets a frame of its own on the first write, if its VMA allows writing.
+    pub fn map_zero_page(&mut self, vaddr: usize, flags: usize) {
+        self.map_page(vaddr, zero_page(), PAGE_U | read_only(flags));
+    }
+
+    /// Gives the page at `vaddr` a zeroed frame of its own, mapped with the protection of
+    /// its VMA, if it maps the zero page and the VMA allows writing. Returns whether it
+    /// did.
+    ///
+    /// Fails with `ENOMEM` if there is no memory for the frame.
+    pub fn unshare_zero_page(&mut self, vaddr: usize) -> Result<bool, Errno> {
+        let vaddr = vaddr & !(PAGE_SIZE - 1);
+        let Some(vma) = self
+            .vmas()
+            .iter()
+            .find(|vma| vma.start <= vaddr && vaddr < vma.end && vma.prot & PAGE_W != 0)
+            .copied()
+        else {
+            return Ok(false);
+        };
+        if !self
+            .pte(vaddr)
+            .is_some_and(|pte| is_leaf(pte) && is_zero_page(pte_addr(pte)))
+        {
+            return Ok(false);
+        }
+
+        let frame = phalloc_zeroed(PAGE_SIZE).map_err(|_| Errno::ENOMEM)?;
+        self.map_page(vaddr, frame.as_usize(), PAGE_U | vma.prot);
+        // The read-only entry may be cached.
+        unsafe { core::arch::asm!("sfence.vma") };
+        Ok(true)
+    }
+}
+
+// MARK - VMAS
+
+/// Returns the page flags for `PROT_*` bits, `None` if there are others.
+pub fn prot_flags(prot: usize) -> Option<usize> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return None;
+    }
+    let flag = |bit, flag| if prot & bit != 0 { flag } else { 0 };
+    Some(flag(PROT_READ, PAGE_R) | flag(PROT_WRITE, PAGE_W) | flag(PROT_EXEC, PAGE_X))
+}
+
+impl PageTable {
+    pub fn vmas(&self) -> &[Vma] {
+        &self.vmas[..self.vma_len]
+    }
+
+    /// Returns the VMA `vaddr` is in.
+    pub fn vma_at(&self, vaddr: usize) -> Option<Vma> {
+        self.vmas()
+            .iter()
+            .find(|vma| vma.start <= vaddr && vaddr < vma.end)
+            .copied()
+    }
+
+    /// Returns the highest address below `top`, above the first page, where `len` bytes fit
+    /// between the VMAs.
+    pub fn find_gap(&self, len: usize, top: usize) -> Option<usize> {
+        let mut end = top;
+        for vma in self.vmas().iter().rev() {
+            if vma.end <= end && end - vma.end >= len {
+                break;
+            }
+            end = end.min(vma.start);
+        }
+        end.checked_sub(len).filter(|&start| start >= PAGE_SIZE)
+    }
+
+    /// Makes `start..end` one anonymous VMA with `prot`, see `insert_vma()`.
+    pub fn set_vma(&mut self, start: usize, end: usize, prot: usize) -> Result<(), Errno> {
+        self.insert_vma(Vma {
+            start,
+            end,
+            prot,
+            backing: Backing::Anon,
+        })
+    }
+
+    /// Adds `vma`, replacing what VMAs covered its range, and merging it with neighbours
+    /// that have the same protection and backing.
+    ///
+    /// Fails with `ENOMEM` if that takes more than `VMA_MAX` VMAs.
+    pub fn insert_vma(&mut self, vma: Vma) -> Result<(), Errno> {
+        self.splice_vmas(vma.start, vma.end, Some(vma), |_| None)
+    }
+
+    /// Removes the VMAs in `start..end`, cutting those partly in it.
+    ///
+    /// Fails with `ENOMEM` if that takes more than `VMA_MAX` VMAs.
+    pub fn remove_vmas(&mut self, start: usize, end: usize) -> Result<(), Errno> {
+        self.splice_vmas(start, end, None, |_| None)
+    }
+
+    /// Replaces the parts of the VMAs in `start..end` with `new` and what `keep` makes of
+    /// each of them, in address order, merging what may be merged.
+    fn splice_vmas(
+        &mut self,
+        start: usize,
+        end: usize,
+        new: Option<Vma>,
+        keep: impl Fn(Vma) -> Option<Vma>,
+    ) -> Result<(), Errno> {
+        // What the existing VMAs have below the range, the new one or what is kept of them
+        // in it, then what they have above it. Cutting one in two takes one more.
+        let mut vmas = [Vma::default(); VMA_MAX + 1];
+        let mut len: usize = 0;
+        let mut push = |vma: Vma| {
+            if vma.start >= vma.end {
+                return;
+            }
+            if let Some(last) = len.checked_sub(1).and_then(|last| vmas.get_mut(last))
+                && last.continues_into(&vma)
+            {
+                last.end = vma.end;
+                return;
+            }
+            if let Some(slot) = vmas.get_mut(len) {
+                *slot = vma;
+            }
+            len += 1;
+        };
+        for &vma in self.vmas() {
+            push(vma.clip(vma.start, start));
+        }
+        if let Some(vma) = new {
+            push(vma);
+        }
+        for &vma in self.vmas() {
+            let part = vma.clip(start, end);
+            if part.start < part.end
+                && let Some(vma) = keep(part)
+            {
+                push(vma);
+            }
+        }
+        for &vma in self.vmas() {
+            push(vma.clip(end, vma.end));
+        }
 
-        if (self.root_pt[vpn1] & PAGE_V) == 0 {
-            // PTE is not valid,
-            // lets create the non-existing 2nd level page table
-            let second_pt: FixedVec<usize> = FixedVec::new(1024);
-            let second_pt_phys_addr = second_pt.as_ptr() as usize;
-            self.second_pts[vpn1] = second_pt;
-            self.root_pt[vpn1] = ((second_pt_phys_addr / PAGE_SIZE) << 10) | PAGE_V;
+        if len > VMA_MAX {
+            return Err(Errno::ENOMEM);
         }
+        self.vmas[..len].copy_from_slice(&vmas[..len]);
+        self.vma_len = len;
+        Ok(())
+    }
+
+    /// Changes the protection of `start..end`, which must all be in VMAs, to `prot`, for
+    /// the VMAs and the pages mapped there.
+    ///
+    /// Fails with `ENOMEM` if part of the range isn't in a VMA, or the VMAs would be too
+    /// many.
+    pub fn mprotect(&mut self, start: usize, end: usize, prot: usize) -> Result<(), Errno> {
+        let mut covered = start;
+        for vma in self.vmas() {
+            if vma.start <= covered && covered < vma.end {
+                covered = vma.end;
+            }
+        }
+        if covered < end {
+            return Err(Errno::ENOMEM);
+        }
+        self.splice_vmas(start, end, None, |vma| Some(Vma { prot, ..vma }))?;
+        self.protect_range(start, end, prot);
+        Ok(())
+    }
+
+    /// Gives this address space the VMAs of `other`, whose pages it has copies of.
+    pub fn copy_vmas(&mut self, other: &PageTable) {
+        self.vmas = other.vmas;
+        self.vma_len = other.vma_len;
+    }
+}
+
+// MARK - END
+
+impl PageTable {
+    /// Calls `f` for every mapped region, in address order.
+    pub fn for_each_region(&self, mut f: impl FnMut(Region)) {
+        let mut current: Option<Region> = None;
+
+        walk(
+            self.root,
+            LEVELS - 1,
+            0,
+            &mut |vaddr, paddr, size, flags| {
+                if let Some(r) = &mut current
+                    && r.end == vaddr
+                    && r.paddr + (r.end - r.start) == paddr
+                    && r.flags == flags
+                {
+                    r.end += size;
+                    return;
+                }
+
+                let next = Region {
+                    start: vaddr,
+                    end: vaddr + size,
+                    paddr,
+                    flags,
+                };
+                if let Some(r) = current.replace(next) {
+                    f(r);
+                }
+            },
+        );
+
+        if let Some(r) = current {
+            f(r);
+        }
+    }
+}
+
+impl Drop for PageTable {
+    fn drop(&mut self) {
+        // Process slots start out zeroed, without a table.
+        if self.root != 0 {
+            free_user(self.root, LEVELS - 1);
+            free_table(self.root, LEVELS - 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn merges_consecutive_mappings_into_regions() {
+        let mut pt = PageTable::new();
+        for i in 0..3 {
+            pt.map_page(
+                0x1000_0000 + i * PAGE_SIZE,
+                0x8100_0000 + i * PAGE_SIZE,
+                PAGE_R | PAGE_W,
+            );
+        }
+        // Same permissions, but not backed by the next frame.
+        pt.map_page(0x1000_3000, 0x8200_0000, PAGE_R | PAGE_W);
+
+        let mut regions = [None; 3];
+        let mut count = 0;
+        pt.for_each_region(|r| {
+            regions[count] = Some(r);
+            count += 1;
+        });
+
+        assert_eq!(count, 2);
+        assert_eq!(
+            regions[0],
+            Some(Region {
+                start: 0x1000_0000,
+                end: 0x1000_3000,
+                paddr: 0x8100_0000,
+                flags: PAGE_R | PAGE_W,
+            })
+        );
+        assert_eq!(regions[1].map(|r| r.paddr), Some(0x8200_0000));
+    }
+
+    #[test_case]
+    fn counts_pages_as_they_are_mapped() {
+        let base = 0x1000_0000;
+        let mut pt = PageTable::new();
+        for i in 0..2 {
+            let frame = phalloc(PAGE_SIZE).unwrap();
+            pt.map_page(base + i * PAGE_SIZE, frame.as_usize(), PAGE_R | PAGE_U);
+        }
+        // Kernel mappings aren't the process's memory.
+        pt.map_page(0x8100_0000, 0x8100_0000, PAGE_R);
+        let usage = pt.usage();
+        assert_eq!((usage.resident, usage.peak), (2, 2));
+        assert_eq!(usage.tables, 1 + 2 * (LEVELS - 1));
+
+        pt.free_user_pages();
+        let usage = pt.usage();
+        assert_eq!((usage.resident, usage.peak), (0, 2));
+    }
+
+    #[test_case]
+    fn unused_pages_age_out_of_the_working_set() {
+        let base = 0x1000_0000;
+        let mut pt = PageTable::new();
+        for i in 0..2 {
+            let frame = phalloc(PAGE_SIZE).unwrap();
+            pt.map_page(
+                base + i * PAGE_SIZE,
+                frame.as_usize(),
+                PAGE_R | PAGE_W | PAGE_U,
+            );
+        }
+        // As the MMU would on a write to the first page, paging being off in tests.
+        let touch = |pt: &mut PageTable| *pt.pte_mut(base).unwrap() |= PAGE_A | PAGE_D;
+
+        touch(&mut pt);
+        assert_eq!(pt.age(), 1);
+        assert_eq!((pt.usage().working_set, pt.usage().dirty), (2, 1));
+        for _ in 0..WORKING_SET_SCANS {
+            touch(&mut pt);
+            assert_eq!(pt.age(), 1);
+        }
+        assert_eq!(pt.usage().working_set, 1);
+        pt.free_user_pages();
+    }
+
+    #[test_case]
+    fn mprotect_splits_vmas_and_remaps_pages() {
+        let used = crate::mem::usage().used;
+        let base = 0x1000_0000;
+        let mut pt = PageTable::new();
+        for i in 0..3 {
+            let frame = phalloc(PAGE_SIZE).unwrap();
+            pt.map_page(
+                base + i * PAGE_SIZE,
+                frame.as_usize(),
+                PAGE_R | PAGE_W | PAGE_U,
+            );
+        }
+        pt.set_vma(base, base + 3 * PAGE_SIZE, PAGE_R | PAGE_W)
+            .unwrap();
+
+        let guard = base + PAGE_SIZE;
+        pt.mprotect(guard, guard + PAGE_SIZE, 0).unwrap();
+        let prots = [PAGE_R | PAGE_W, 0, PAGE_R | PAGE_W];
+        assert!(pt.vmas().iter().map(|vma| vma.prot).eq(prots));
+        assert_eq!(pt.translate(guard), None);
+        let mut flags = [0; 3];
+        let mut count = 0;
+        pt.for_each_region(|r| {
+            flags[count] = r.flags;
+            count += 1;
+        });
+        assert_eq!(flags, prots.map(|prot| prot | PAGE_U));
+        assert!(pt.allows(base + 8, base + PAGE_SIZE, PAGE_R | PAGE_W));
+        assert!(!pt.allows(base, base + 3 * PAGE_SIZE, PAGE_R));
+        assert!(!pt.allows(base + 3 * PAGE_SIZE, base + 4 * PAGE_SIZE, 0));
+
+        // Writable only is writable and readable, as the MMU has it.
+        pt.mprotect(guard, guard + PAGE_SIZE, PAGE_W).unwrap();
+        assert!(pt.translate(guard).is_some());
+        pt.mprotect(guard, guard + PAGE_SIZE, PAGE_R | PAGE_W)
+            .unwrap();
+        assert_eq!(pt.vmas().len(), 1);
+        assert_eq!(
+            pt.mprotect(base, base + 4 * PAGE_SIZE, PAGE_R),
+            Err(Errno::ENOMEM)
+        );
+
+        drop(pt);
+        assert_eq!(crate::mem::usage().used, used);
+    }
+
+    #[test_case]
+    fn file_vmas_keep_their_offsets() {
+        let base = 0x1000_0000;
+        let file = |offset| Backing::File {
+            mount: 0,
+            ino: 1,
+            offset,
+            shared: false,
+        };
+        let mut pt = PageTable::new();
+        pt.insert_vma(Vma {
+            start: base,
+            end: base + 3 * PAGE_SIZE,
+            prot: PAGE_R,
+            backing: file(PAGE_SIZE),
+        })
+        .unwrap();
+
+        let middle = base + PAGE_SIZE;
+        pt.mprotect(middle, middle + PAGE_SIZE, PAGE_R | PAGE_W)
+            .unwrap();
+        assert_eq!(pt.vmas().len(), 3);
+        assert_eq!(pt.vma_at(middle).unwrap().backing, file(2 * PAGE_SIZE));
+        assert_eq!(
+            pt.vma_at(middle + PAGE_SIZE).unwrap().backing,
+            file(3 * PAGE_SIZE)
+        );
+        // Merged again, the file continuing where it left off.
+        pt.mprotect(middle, middle + PAGE_SIZE, PAGE_R).unwrap();
+        assert_eq!(pt.vmas().len(), 1);
+
+        // Not with anonymous memory, or another part of the file.
+        pt.set_vma(base - PAGE_SIZE, base, PAGE_R).unwrap();
+        pt.remove_vmas(middle, middle + PAGE_SIZE).unwrap();
+        pt.insert_vma(Vma {
+            start: middle,
+            end: middle + PAGE_SIZE,
+            prot: PAGE_R,
+            backing: file(0),
+        })
+        .unwrap();
+        assert_eq!(pt.vmas().len(), 4);
+        assert_eq!(
+            pt.find_gap(PAGE_SIZE, base + 4 * PAGE_SIZE),
+            Some(base + 3 * PAGE_SIZE)
+        );
+        assert_eq!(
+            pt.find_gap(2 * PAGE_SIZE, base + 4 * PAGE_SIZE),
+            Some(base - 3 * PAGE_SIZE)
+        );
+    }
+
+    #[test_case]
+    fn zero_pages_are_shared_until_written() {
+        let zero = zero_page();
+        let used = crate::mem::usage().used;
+        let base = 0x1000_0000;
+        let mut pt = PageTable::new();
+        for i in 0..2 {
+            pt.map_zero_page(base + i * PAGE_SIZE, PAGE_R | PAGE_W);
+        }
+        pt.set_vma(base, base + 2 * PAGE_SIZE, PAGE_R | PAGE_W)
+            .unwrap();
+        assert_eq!(pt.translate(base + PAGE_SIZE), Some(zero));
+        assert_eq!(pt.usage().resident, 0);
+        assert!(pt.allows(base, base + 2 * PAGE_SIZE, PAGE_R));
+        assert!(!pt.allows(base, base + 1, PAGE_W));
+
+        // Still read-only once made writable, until it is written.
+        pt.mprotect(base, base + 2 * PAGE_SIZE, PAGE_R | PAGE_W | PAGE_X)
+            .unwrap();
+        assert!(!pt.allows(base, base + 1, PAGE_W));
+        assert_eq!(pt.unshare_zero_page(base + 8), Ok(true));
+        assert_eq!(pt.unshare_zero_page(base + 8), Ok(false));
+        let frame = pt.translate(base).unwrap();
+        assert_ne!(frame, zero);
+        assert!(pt.allows(base, base + 1, PAGE_R | PAGE_W | PAGE_X));
+        let page = unsafe { core::slice::from_raw_parts(frame as *const u8, PAGE_SIZE) };
+        assert!(page.iter().all(|&b| b == 0));
+        assert_eq!(pt.usage().resident, 1);
+
+        pt.mprotect(base + PAGE_SIZE, base + 2 * PAGE_SIZE, PAGE_R)
+            .unwrap();
+        assert_eq!(pt.unshare_zero_page(base + PAGE_SIZE), Ok(false));
 
-        let vpn0 = vaddr >> 12 & 0x3ff;
-        let second_pt = &mut self.second_pts[vpn1];
-        second_pt[vpn0] = ((paddr / PAGE_SIZE) << 10) | flags | PAGE_V;
+        drop(pt);
+        assert_eq!(crate::mem::usage().used, used);
     }
 }
@@ -0,0 +1,139 @@
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use crate::{
+    MAX_HARTS, cmdline, hart_id, panic, println, proc,
+    sbi::{self, ResetReason, ResetType},
+    sync::{self, Mutex},
+    timer,
+};
+
+/// Time a hart may run without passing through the scheduler.
+const HART_TIMEOUT_MS: usize = 5000;
+const MAX_WATCHDOGS: usize = 8;
+const NOT_WATCHED: usize = usize::MAX;
+
+/// What happens once a watchdog misses its deadline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Action {
+    Panic = 0,
+    /// Cold-reboots the machine via SBI SRST (which exits QEMU when run with `--no-reboot`).
+    Reset = 1,
+}
+
+/// Handle to a watchdog returned by `register()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogId(usize);
+
+struct Watchdog {
+    name: &'static str,
+    timeout_ticks: usize,
+    last_pet: usize,
+    hart: usize,
+}
+
+static ACTION: AtomicU8 = AtomicU8::new(Action::Panic as u8);
+
+static WATCHDOGS: Mutex<[Option<Watchdog>; MAX_WATCHDOGS]> =
+    Mutex::new([const { None }; MAX_WATCHDOGS]);
+
+// Tick at which each hart last went through the scheduler.
+static HART_LAST_PET: [AtomicUsize; MAX_HARTS] =
+    [const { AtomicUsize::new(NOT_WATCHED) }; MAX_HARTS];
+
+// Interrupted pc of each hart at its most recent timer tick.
+static HART_LAST_PC: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+/// Selects what happens when a deadline is missed.
+pub fn set_action(action: Action) {
+    ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+crate::register_subsystem!(Core, "watchdog", init);
+
+fn init() {
+    if cmdline::get("watchdog") == Some("reset") {
+        set_action(Action::Reset);
+    }
+}
+
+/// Registers a subsystem watchdog that must be petted at least every `timeout_ms` milliseconds.
+///
+/// # Panics
+///
+/// This function panics if all watchdog slots are in use.
+pub fn register(name: &'static str, timeout_ms: usize) -> WatchdogId {
+    let mut watchdogs = WATCHDOGS.lock();
+    let slot = watchdogs
+        .iter()
+        .position(|w| w.is_none())
+        .expect("no free watchdog slots.");
+
+    watchdogs[slot] = Some(Watchdog {
+        name,
+        timeout_ticks: timer::ms_to_ticks(timeout_ms),
+        last_pet: timer::ticks(),
+        hart: hart_id(),
+    });
+
+    WatchdogId(slot)
+}
+
+/// Resets the deadline of the subsystem watchdog `id`.
+pub fn pet(id: WatchdogId) {
+    if let Some(watchdog) = WATCHDOGS.lock()[id.0].as_mut() {
+        watchdog.last_pet = timer::ticks();
+        watchdog.hart = hart_id();
+    }
+}
+
+/// Resets the deadline of the calling hart. Called by the scheduler on every switch.
+pub fn pet_hart() {
+    HART_LAST_PET[hart_id()].store(timer::ticks(), Ordering::Relaxed);
+}
+
+/// Checks all deadlines. Called from the timer interrupt with the interrupted `sepc`.
+///
+/// Runs in interrupt context, so it never blocks on a lock.
+pub fn on_tick(sepc: usize) {
+    HART_LAST_PC[hart_id()].store(sepc, Ordering::Relaxed);
+
+    let now = timer::ticks();
+    let hart_timeout = timer::ms_to_ticks(HART_TIMEOUT_MS);
+
+    for (hart, last_pet) in HART_LAST_PET.iter().enumerate() {
+        let last_pet = last_pet.load(Ordering::Relaxed);
+        if last_pet != NOT_WATCHED && now.wrapping_sub(last_pet) > hart_timeout {
+            expire("scheduler", hart);
+        }
+    }
+
+    // The interrupted code may hold the lock, try again on the next tick.
+    if let Some(watchdogs) = WATCHDOGS.try_lock() {
+        for watchdog in watchdogs.iter().flatten() {
+            if now.wrapping_sub(watchdog.last_pet) > watchdog.timeout_ticks {
+                expire(watchdog.name, watchdog.hart);
+            }
+        }
+    }
+}
+
+fn expire(name: &str, hart: usize) -> ! {
+    let (held_locks, last_lock) = sync::held_locks(hart);
+
+    println!("\nwatchdog: '{name}' missed its deadline on hart {hart}");
+    println!(
+        "watchdog: pid={} pc=0x{:x} held_locks={} last_lock=0x{:x}",
+        proc::current_pid(hart),
+        HART_LAST_PC[hart].load(Ordering::Relaxed),
+        held_locks,
+        last_lock
+    );
+
+    if ACTION.load(Ordering::Relaxed) == Action::Reset as u8 {
+        let err = sbi::system_reset(ResetType::ColdReboot, ResetReason::SystemFailure);
+        println!("watchdog: SRST failed with error {err}");
+    }
+
+    panic!("watchdog '{name}' expired");
+}
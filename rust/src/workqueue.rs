@@ -0,0 +1,210 @@
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use crate::{
+    MAX_HARTS, hart_id, proc,
+    sync::{Mutex, OnceCell},
+    syscall::Errno,
+    timer, watchdog,
+};
+
+// Deferred work, for what an interrupt handler or a timer shouldn't do with interrupts off
+// but also needn't do right away.
+//
+// `queue_work()` hands a function and its argument to the worker of the calling hart, a
+// kernel process that calls it later, scheduled like any other, so it may take locks and
+// block. Queueing never takes a lock, it is safe from interrupt context. Work items run in
+// no particular order.
+//
+// Softirqs are lighter: a handler registered with `open_softirq()` runs once a hart
+// raised it with `raise_softirq()` and is about to return to user mode, with interrupts
+// still off. Raising one that is pending already does nothing, it runs once.
+//
+// FIXME: Only the boot hart has a worker, the others are parked.
+
+pub const QUEUE_LEN: usize = 16;
+pub const NR_SOFTIRQS: usize = 8;
+
+// Softirqs, by number.
+/// Polls the network interfaces, see `net`.
+pub const NET_RX_SOFTIRQ: usize = 0;
+
+// Longest a work item may keep the worker from coming back for the next one, blocked or
+// not, in milliseconds.
+const WORK_TIMEOUT_MS: usize = 10_000;
+
+// States of a slot of a work queue.
+const FREE: u8 = 0;
+// Being filled in by `queue_work()`.
+const CLAIMED: u8 = 1;
+const QUEUED: u8 = 2;
+
+struct Slot {
+    state: AtomicU8,
+    func: AtomicUsize,
+    data: AtomicUsize,
+}
+
+static QUEUES: [[Slot; QUEUE_LEN]; MAX_HARTS] = [const {
+    [const {
+        Slot {
+            state: AtomicU8::new(FREE),
+            func: AtomicUsize::new(0),
+            data: AtomicUsize::new(0),
+        }
+    }; QUEUE_LEN]
+}; MAX_HARTS];
+// Pid of each hart's worker as a bit mask, 0 until it runs.
+static WORKERS: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+// Never removed, so they can be used without a lock in interrupt context.
+static SOFTIRQS: [OnceCell<fn()>; NR_SOFTIRQS] = [const { OnceCell::new() }; NR_SOFTIRQS];
+// Taken by `open_softirq()`, before `SOFTIRQS` is set.
+static OPENED: Mutex<[bool; NR_SOFTIRQS]> = Mutex::new([false; NR_SOFTIRQS]);
+// Softirqs raised on each hart, as a bit mask.
+static PENDING: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+// MARK - Work queues
+
+/// Has the worker of the calling hart call `f(data)`.
+///
+/// Fails with `ENOSPC` if its queue is full.
+pub fn queue_work(f: fn(usize), data: usize) -> Result<(), Errno> {
+    let hart = hart_id();
+    let slot = QUEUES[hart]
+        .iter()
+        .find(|slot| {
+            slot.state
+                .compare_exchange(FREE, CLAIMED, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        })
+        .ok_or(Errno::ENOSPC)?;
+    slot.func.store(f as usize, Ordering::Relaxed);
+    slot.data.store(data, Ordering::Relaxed);
+    slot.state.store(QUEUED, Ordering::Release);
+    proc::wake_deferred(WORKERS[hart].load(Ordering::Relaxed));
+    Ok(())
+}
+
+/// Runs the work queued on `hart`, returning whether there was any.
+fn run_work(hart: usize) -> bool {
+    let mut ran = false;
+    for slot in &QUEUES[hart] {
+        if slot.state.load(Ordering::Acquire) != QUEUED {
+            continue;
+        }
+        let f = slot.func.load(Ordering::Relaxed);
+        let data = slot.data.load(Ordering::Relaxed);
+        slot.state.store(FREE, Ordering::Release);
+        // SAFETY: Only `queue_work()` queues, with a `fn(usize)`.
+        let f: fn(usize) = unsafe { core::mem::transmute(f) };
+        f(data);
+        ran = true;
+    }
+    ran
+}
+
+/// `kworker`, the kernel process running the work queued on its hart.
+fn worker() -> ! {
+    let hart = hart_id();
+    WORKERS[hart].store(1 << proc::current(), Ordering::Relaxed);
+    // Asleep it comes back every second at least, so only a stuck work item misses it.
+    let watchdog = watchdog::register("kworker", WORK_TIMEOUT_MS);
+    loop {
+        watchdog::pet(watchdog);
+        if !run_work(hart) {
+            // A wakeup just before it blocks may be missed, it then looks again in a second.
+            proc::sleep(Some(timer::ticks() + timer::hz()));
+        }
+    }
+}
+
+/// Starts the worker of the boot hart, once there are processes.
+pub fn start_workers() {
+    proc::new(worker as *const () as usize);
+}
+
+// MARK - Softirqs
+
+/// Calls `handler` whenever softirq `nr` was raised, before returning to user mode.
+///
+/// Fails with `EINVAL` if there is no such softirq, and with `EBUSY` if it has a handler
+/// already.
+pub fn open_softirq(nr: usize, handler: fn()) -> Result<(), Errno> {
+    if nr >= NR_SOFTIRQS {
+        return Err(Errno::EINVAL);
+    }
+    let mut opened = OPENED.lock();
+    if opened[nr] {
+        return Err(Errno::EBUSY);
+    }
+    opened[nr] = true;
+    drop(opened);
+
+    SOFTIRQS[nr].get_or_init(|| handler);
+    Ok(())
+}
+
+/// Has the calling hart run the handler of softirq `nr` before it returns to user mode.
+pub fn raise_softirq(nr: usize) {
+    if nr < NR_SOFTIRQS {
+        PENDING[hart_id()].fetch_or(1 << nr, Ordering::Release);
+    }
+}
+
+/// Runs the softirqs raised on the calling hart, on the way back to user mode.
+pub fn run_softirqs() {
+    let pending = PENDING[hart_id()].swap(0, Ordering::Acquire);
+    for (nr, handler) in SOFTIRQS.iter().enumerate() {
+        if pending & (1 << nr) != 0
+            && let Some(handler) = handler.get()
+        {
+            handler();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static WORK_DONE: AtomicUsize = AtomicUsize::new(0);
+    static SOFTIRQ_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    #[test_case]
+    fn queued_work_runs_once() {
+        let hart = hart_id();
+        let add = |n| {
+            WORK_DONE.fetch_add(n, Ordering::Relaxed);
+        };
+        assert_eq!(queue_work(add, 1), Ok(()));
+        assert_eq!(queue_work(add, 2), Ok(()));
+        assert_eq!(WORK_DONE.load(Ordering::Relaxed), 0);
+        assert!(run_work(hart));
+        assert_eq!(WORK_DONE.load(Ordering::Relaxed), 3);
+        assert!(!run_work(hart));
+
+        for _ in 0..QUEUE_LEN {
+            assert_eq!(queue_work(add, 0), Ok(()));
+        }
+        assert_eq!(queue_work(add, 0), Err(Errno::ENOSPC));
+        run_work(hart);
+    }
+
+    #[test_case]
+    fn raised_softirqs_run_once() {
+        let nr = NR_SOFTIRQS - 1;
+        let handler = || {
+            SOFTIRQ_RUNS.fetch_add(1, Ordering::Relaxed);
+        };
+        assert_eq!(open_softirq(nr, handler), Ok(()));
+        assert_eq!(open_softirq(nr, handler), Err(Errno::EBUSY));
+        assert_eq!(open_softirq(NR_SOFTIRQS, handler), Err(Errno::EINVAL));
+
+        raise_softirq(nr);
+        raise_softirq(nr);
+        run_softirqs();
+        assert_eq!(SOFTIRQ_RUNS.load(Ordering::Relaxed), 1);
+        run_softirqs();
+        assert_eq!(SOFTIRQ_RUNS.load(Ordering::Relaxed), 1);
+    }
+}
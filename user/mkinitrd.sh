@@ -0,0 +1,18 @@
+#!/bin/sh
+# Builds the user programs and packs them into target/initrd.tar, a ustar archive with
+# every program under /bin, for QEMU's -initrd. Arguments are passed to `cargo build`.
+set -eu
+
+cd "$(dirname "$0")"
+cargo build --release "$@"
+
+out=target/riscv32imac-unknown-none-elf/release
+root=target/initrd
+rm -rf "$root"
+mkdir -p "$root/bin"
+for src in src/bin/*.rs; do
+    name=$(basename "$src" .rs)
+    cp "$out/$name" "$root/bin/$name"
+done
+tar --format=ustar --owner=0 --group=0 -cf target/initrd.tar -C "$root" .
+echo "target/initrd.tar: $(ls "$root/bin" | tr '\n' ' ')"
@@ -0,0 +1,164 @@
+#![no_std]
+#![no_main]
+
+use os1k_user::{
+    env, eprintln, println,
+    sys::{self, Errno},
+    time,
+};
+
+// Microbenchmarks of the paths the scheduler and trap code are on: a syscall round trip,
+// a context switch between two processes, and moving data through a pipe. Timed with the
+// `time` and `cycle` counters, and printed one line per benchmark as `name key=value ...`,
+// so runs before and after a change can be diffed.
+//
+// `bench [iterations]` runs every benchmark that many times, 1000 by default.
+
+os1k_user::entry!(main);
+
+const DEFAULT_ITERATIONS: usize = 1000;
+const CHUNK: usize = 512;
+
+/// Runs a benchmark the given number of times and prints its results.
+type Bench = fn(usize) -> Result<(), Errno>;
+
+/// Time and cycles spent on a run of a benchmark.
+struct Sample {
+    time: u64,
+    cycles: u64,
+}
+
+fn measure(f: impl FnOnce() -> Result<(), Errno>) -> Result<Sample, Errno> {
+    let (time, cycles) = (time::rdtime(), time::rdcycle());
+    f()?;
+    Ok(Sample {
+        time: time::rdtime() - time,
+        cycles: time::rdcycle() - cycles,
+    })
+}
+
+fn report(name: &str, ops: usize, sample: &Sample) {
+    let ops = ops.max(1) as u64;
+    println!(
+        "{name} ops={ops} ns/op={} cycles/op={}",
+        time::to_ns(sample.time) / ops,
+        sample.cycles / ops
+    );
+}
+
+fn main() -> i32 {
+    let iterations = match env::args().nth(1).map(str::parse) {
+        None => DEFAULT_ITERATIONS,
+        Some(Ok(n)) if n > 0 => n,
+        Some(_) => {
+            eprintln!("usage: bench [iterations]");
+            return 2;
+        }
+    };
+
+    let benchmarks: [(&str, Bench); 3] = [
+        ("syscall", syscall),
+        ("context_switch", context_switch),
+        ("pipe_throughput", pipe_throughput),
+    ];
+    let mut status = 0;
+    for (name, bench) in benchmarks {
+        if let Err(errno) = bench(iterations) {
+            eprintln!("bench: {name}: {errno}");
+            status = 1;
+        }
+    }
+    status
+}
+
+/// Round trips into the kernel with about the cheapest syscall there is.
+fn syscall(iterations: usize) -> Result<(), Errno> {
+    let sample = measure(|| {
+        for _ in 0..iterations {
+            sys::getpgid(0)?;
+        }
+        Ok(())
+    })?;
+    report("syscall", iterations, &sample);
+    Ok(())
+}
+
+/// Passes a byte back and forth between two processes over a pair of pipes, each pass
+/// blocking one and waking the other.
+fn context_switch(iterations: usize) -> Result<(), Errno> {
+    let (ping_read, ping_write) = sys::pipe()?;
+    let (pong_read, pong_write) = sys::pipe()?;
+    let mut byte = [0];
+
+    if sys::fork()? == 0 {
+        for _ in 0..iterations {
+            if sys::read(ping_read, &mut byte) != Ok(1) || sys::write(pong_write, &byte).is_err() {
+                sys::exit(1);
+            }
+        }
+        sys::exit(0);
+    }
+
+    let sample = measure(|| {
+        for _ in 0..iterations {
+            sys::write(ping_write, &byte)?;
+            sys::read(pong_read, &mut byte)?;
+        }
+        Ok(())
+    });
+    finish(&[ping_read, ping_write, pong_read, pong_write])?;
+    // Two switches per round trip.
+    report("context_switch", 2 * iterations, &sample?);
+    Ok(())
+}
+
+/// Streams `iterations` chunks from a child process through a pipe.
+fn pipe_throughput(iterations: usize) -> Result<(), Errno> {
+    let (read_end, write_end) = sys::pipe()?;
+    let mut buf = [0; CHUNK];
+
+    if sys::fork()? == 0 {
+        let _ = sys::close(read_end);
+        for _ in 0..iterations {
+            let mut chunk = &buf[..];
+            while !chunk.is_empty() {
+                match sys::write(write_end, chunk) {
+                    Ok(n) => chunk = &chunk[n..],
+                    Err(_) => sys::exit(1),
+                }
+            }
+        }
+        sys::exit(0);
+    }
+    let _ = sys::close(write_end);
+
+    let mut bytes = 0;
+    let sample = measure(|| {
+        loop {
+            match sys::read(read_end, &mut buf)? {
+                0 => return Ok(()),
+                n => bytes += n,
+            }
+        }
+    });
+    finish(&[read_end])?;
+    let sample = sample?;
+    let ns = time::to_ns(sample.time).max(1);
+    println!(
+        "pipe_throughput bytes={bytes} ns={ns} KiB/s={}",
+        bytes as u64 * 1_000_000_000 / 1024 / ns
+    );
+    Ok(())
+}
+
+/// Closes `fds` and reaps the child, failing if it didn't finish its part.
+fn finish(fds: &[usize]) -> Result<(), Errno> {
+    for &fd in fds {
+        let _ = sys::close(fd);
+    }
+    let (_, status) = sys::wait(None, 0)?;
+    match sys::exit_code(status) {
+        Some(0) => Ok(()),
+        _ => Err(Errno::ECHILD),
+    }
+}
@@ -0,0 +1,19 @@
+#![no_std]
+#![no_main]
+
+use os1k_user::{env, print, println};
+
+// Prints its arguments, separated by spaces.
+
+os1k_user::entry!(main);
+
+fn main() -> i32 {
+    for (i, arg) in env::args().skip(1).enumerate() {
+        if i > 0 {
+            print!(" ");
+        }
+        print!("{arg}");
+    }
+    println!();
+    0
+}
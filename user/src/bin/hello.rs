@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+use os1k_user::{println, sys};
+
+os1k_user::entry!(main);
+
+fn main() -> i32 {
+    println!("Hello from user space!");
+
+    let (read_fd, write_fd) = sys::pipe().expect("pipe failed.");
+    sys::write(write_fd, b"ping").unwrap();
+    let mut buf = [0; 4];
+    let n = sys::read(read_fd, &mut buf).unwrap();
+    println!(
+        "read {:?} back from a pipe",
+        core::str::from_utf8(&buf[..n])
+    );
+
+    0
+}
@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+
+use core::fmt::{self, Write};
+
+use os1k_user::{
+    eprintln, println,
+    sys::{self, Errno},
+};
+
+// Lists the processes, one line each with its state and the memory it uses in kB, from the
+// `status` files of the pid directories in `/proc`.
+
+os1k_user::entry!(main);
+
+// Fields of `/proc/<pid>/status` shown, and their column headers.
+const COLUMNS: [(&str, &str); 7] = [
+    ("Pid", "PID"),
+    ("State", "STATE"),
+    ("VmRSS", "RSS"),
+    ("VmHWM", "PEAK"),
+    ("VmWSS", "WSS"),
+    ("VmPTE", "PTE"),
+    ("VmSwap", "SWAP"),
+];
+
+/// A path built in place.
+struct Path {
+    buf: [u8; 32],
+    len: usize,
+}
+
+impl Write for Path {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+fn main() -> i32 {
+    match list() {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("ps: {err}");
+            1
+        }
+    }
+}
+
+fn list() -> Result<(), Errno> {
+    let dir = sys::open("/proc", sys::O_DIRECTORY)?;
+    let mut names = [0; 256];
+    let len = sys::getdents(dir, &mut names);
+    sys::close(dir)?;
+
+    let [pid, state, rss, peak, wss, pte, swap] = COLUMNS.map(|(_, header)| header);
+    println!("{pid:>5} {state:<8} {rss:>8} {peak:>8} {wss:>8} {pte:>8} {swap:>8}");
+    for name in names[..len?].split(|&b| b == 0) {
+        let Ok(name) = core::str::from_utf8(name) else {
+            continue;
+        };
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        // Exited since the directory was read.
+        let _ = show(name);
+    }
+    Ok(())
+}
+
+fn show(pid: &str) -> Result<(), Errno> {
+    let mut path = Path {
+        buf: [0; 32],
+        len: 0,
+    };
+    write!(path, "/proc/{pid}/status").map_err(|_| Errno::ENOENT)?;
+    let path = core::str::from_utf8(&path.buf[..path.len]).map_err(|_| Errno::ENOENT)?;
+
+    let fd = sys::open(path, sys::O_RDONLY)?;
+    let mut status = [0; 512];
+    let len = sys::read(fd, &mut status);
+    sys::close(fd)?;
+    let status = core::str::from_utf8(&status[..len?]).map_err(|_| Errno::EINVAL)?;
+
+    let mut values = [""; COLUMNS.len()];
+    for line in status.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        if let Some(i) = COLUMNS.iter().position(|&(k, _)| k == key) {
+            // Sizes without their unit, always kB.
+            values[i] = value.trim().trim_end_matches(" kB").trim();
+        }
+    }
+    let [pid, state, rss, peak, wss, pte, swap] = values;
+    println!("{pid:>5} {state:<8} {rss:>8} {peak:>8} {wss:>8} {pte:>8} {swap:>8}");
+    Ok(())
+}
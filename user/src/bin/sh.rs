@@ -0,0 +1,398 @@
+#![no_std]
+#![no_main]
+
+use os1k_user::{
+    env, eprintln,
+    io::STDIN,
+    print, println,
+    sys::{self, SigHandler},
+    time,
+};
+
+// A minimal shell, run by the kernel as the first process: reads a line, runs the program
+// it names in a child process with the other words as its arguments, and waits for it.
+// Programs are looked up in the `:`-separated directories of `$PATH` unless the name
+// contains a `/`, and get the shell's environment and working directory, which `cd`
+// changes. `mkdir`, `rmdir`, `rm`, `mv` and `ln` are built in, `perf` runs a program
+// and reports the time, cycles and instructions it took, and `shutdown [-r]` powers off
+// or reboots the machine.
+//
+// Every program runs in a process group of its own, made the console's foreground group
+// while the shell waits for it, so Ctrl-C and Ctrl-Z reach the program but not the shell.
+// A stopped program is kept as a job: `jobs` lists them and `fg [pid]` continues one in
+// the foreground.
+
+os1k_user::entry!(main);
+
+const LINE_MAX: usize = 256;
+const PATH_MAX: usize = 256;
+const JOBS_MAX: usize = 8;
+// Milliseconds `ping` waits for each reply.
+const PING_TIMEOUT_MS: usize = 1000;
+
+/// Stopped programs, by pid, the most recently stopped last.
+struct Jobs {
+    pids: [usize; JOBS_MAX],
+    len: usize,
+}
+
+impl Jobs {
+    fn push(&mut self, pid: usize) {
+        if self.len == JOBS_MAX {
+            // Forgotten, but still stopped; it can be continued with `kill`.
+            self.pids.copy_within(1.., 0);
+            self.len -= 1;
+        }
+        self.pids[self.len] = pid;
+        self.len += 1;
+    }
+
+    /// Removes `pid`, or the most recent job if `None`, and returns it.
+    fn take(&mut self, pid: Option<usize>) -> Option<usize> {
+        let index = match pid {
+            Some(pid) => self.pids[..self.len].iter().position(|&p| p == pid)?,
+            None => self.len.checked_sub(1)?,
+        };
+        let pid = self.pids[index];
+        self.pids.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        Some(pid)
+    }
+}
+
+fn main() -> i32 {
+    let mut jobs = Jobs {
+        pids: [0; JOBS_MAX],
+        len: 0,
+    };
+    let mut line = [0; LINE_MAX];
+    let mut status = 0;
+
+    // Typed while the shell is in the foreground, they would end or stop it.
+    for sig in [sys::SIGINT, sys::SIGTSTP] {
+        let _ = sys::sigaction(sig, SigHandler::Ignore, 0);
+    }
+
+    loop {
+        let mut cwd = [0; PATH_MAX];
+        print!("{}$ ", sys::getcwd(&mut cwd).unwrap_or("?"));
+        let n = match sys::read(STDIN, &mut line) {
+            Ok(0) => return status,
+            Ok(n) => n,
+            Err(errno) => {
+                eprintln!("sh: can't read input: {errno}");
+                return 1;
+            }
+        };
+        let Ok(line) = core::str::from_utf8(&line[..n]) else {
+            eprintln!("sh: input isn't UTF-8");
+            continue;
+        };
+
+        let mut words = line.split_ascii_whitespace();
+        let Some(name) = words.next() else {
+            continue;
+        };
+        status = match name {
+            "cd" => cd(words.next().unwrap_or("/")),
+            "exit" => return words.next().and_then(|s| s.parse().ok()).unwrap_or(status),
+            "jobs" => {
+                for pid in &jobs.pids[..jobs.len] {
+                    println!("[{pid}] stopped");
+                }
+                0
+            }
+            "fg" => fg(&mut jobs, words.next()),
+            "mkdir" => each_path(name, words, |path| sys::mkdir(path, 0o755)),
+            "rmdir" => each_path(name, words, sys::rmdir),
+            "rm" => each_path(name, words, sys::unlink),
+            "mv" => mv(words.next(), words.next()),
+            "ln" => ln(words),
+            "perf" => perf(&mut jobs, words),
+            "shutdown" => shutdown(words.next()),
+            "ping" => ping(words.next(), words.next()),
+            _ => run_words(&mut jobs, line.split_ascii_whitespace()),
+        };
+    }
+}
+
+fn cd(dir: &str) -> i32 {
+    match sys::chdir(dir) {
+        Ok(()) => 0,
+        Err(errno) => {
+            eprintln!("cd: {dir}: {errno}");
+            1
+        }
+    }
+}
+
+/// Calls `f` with every path of `args`, returning 1 if it failed for any.
+fn each_path<'a>(
+    cmd: &str,
+    args: impl Iterator<Item = &'a str>,
+    f: impl Fn(&str) -> Result<(), sys::Errno>,
+) -> i32 {
+    let mut status = 0;
+    for arg in args {
+        if let Err(errno) = f(arg) {
+            eprintln!("{cmd}: {arg}: {errno}");
+            status = 1;
+        }
+    }
+    status
+}
+
+/// Moves `old` to `new`.
+fn mv(old: Option<&str>, new: Option<&str>) -> i32 {
+    let (Some(old), Some(new)) = (old, new) else {
+        eprintln!("mv: usage: mv <old> <new>");
+        return 1;
+    };
+    match sys::rename(old, new) {
+        Ok(()) => 0,
+        Err(errno) => {
+            eprintln!("mv: {old} -> {new}: {errno}");
+            1
+        }
+    }
+}
+
+/// `ln [-s] <target> <name>`: names the file `target` `name` as well, or with `-s` makes
+/// `name` a symbolic link to `target`, which is kept as given.
+fn ln<'a>(mut args: impl Iterator<Item = &'a str>) -> i32 {
+    let mut target = args.next();
+    let symbolic = target == Some("-s");
+    if symbolic {
+        target = args.next();
+    }
+    let (Some(target), Some(name)) = (target, args.next()) else {
+        eprintln!("ln: usage: ln [-s] <target> <name>");
+        return 1;
+    };
+    let result = match symbolic {
+        true => sys::symlink(target, name),
+        false => sys::link(target, name),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(errno) => {
+            eprintln!("ln: {name}: {errno}");
+            1
+        }
+    }
+}
+
+/// Continues job `pid`, or the most recently stopped one, in the foreground.
+fn fg(jobs: &mut Jobs, pid: Option<&str>) -> i32 {
+    let pid = match pid.map(str::parse) {
+        Some(Ok(pid)) => Some(pid),
+        Some(Err(_)) => {
+            eprintln!("fg: not a pid");
+            return 1;
+        }
+        None => None,
+    };
+    let Some(job) = jobs.take(pid) else {
+        eprintln!("fg: no such job");
+        return 1;
+    };
+    if let Err(errno) = sys::kill(-(job as isize), sys::SIGCONT) {
+        eprintln!("fg: {errno}");
+        return 1;
+    }
+    foreground(jobs, job)
+}
+
+/// `perf <command> [args...]`: runs a program like `run_words()`, then prints the time,
+/// cycles and instructions it took.
+///
+/// The counters are the hart's, so they include the kernel and anything else that ran on
+/// it meanwhile.
+fn perf<'a>(jobs: &mut Jobs, args: impl Iterator<Item = &'a str>) -> i32 {
+    let mut args = args.peekable();
+    if args.peek().is_none() {
+        eprintln!("perf: usage: perf <command> [args...]");
+        return 1;
+    }
+    let (time, cycles, instret) = (time::rdtime(), time::rdcycle(), time::rdinstret());
+    let status = run_words(jobs, args);
+    let us = time::to_ns(time::rdtime() - time) / 1000;
+    eprintln!(
+        "perf: {}.{:03} ms, {} cycles, {} instructions",
+        us / 1000,
+        us % 1000,
+        time::rdcycle().wrapping_sub(cycles),
+        time::rdinstret().wrapping_sub(instret)
+    );
+    status
+}
+
+/// Powers off the machine, or reboots it with `-r`.
+fn shutdown(option: Option<&str>) -> i32 {
+    let cmd = match option {
+        None => sys::REBOOT_CMD_POWER_OFF,
+        Some("-r") => sys::REBOOT_CMD_RESTART,
+        Some(_) => {
+            eprintln!("shutdown: usage: shutdown [-r]");
+            return 1;
+        }
+    };
+    eprintln!("shutdown: {}", sys::reboot(cmd));
+    1
+}
+
+/// Pings `host`, an IPv4 address, `count` times (4 by default), returning 1 if no reply came.
+fn ping(host: Option<&str>, count: Option<&str>) -> i32 {
+    let addr = host.and_then(parse_ipv4);
+    let count = count.map_or(Some(4), |count| count.parse::<u16>().ok());
+    let (Some(host), Some(addr), Some(count)) = (host, addr, count) else {
+        eprintln!("ping: usage: ping <a.b.c.d> [count]");
+        return 1;
+    };
+    let mut received = 0;
+    for seq in 1..=count {
+        match sys::ping(addr, seq, PING_TIMEOUT_MS) {
+            Ok(reply) => {
+                received += 1;
+                println!(
+                    "reply from {host}: seq={seq} ttl={} time={}.{:03} ms",
+                    reply.ttl,
+                    reply.rtt_us / 1000,
+                    reply.rtt_us % 1000
+                );
+            }
+            Err(errno) => println!("no reply from {host}: seq={seq}: {errno}"),
+        }
+    }
+    println!("{count} sent, {received} received");
+    (received == 0) as i32
+}
+
+/// Parses an IPv4 address in dotted decimal.
+fn parse_ipv4(s: &str) -> Option<[u8; 4]> {
+    let mut addr = [0; 4];
+    let mut parts = s.split('.');
+    for byte in &mut addr {
+        *byte = parts.next()?.parse().ok()?;
+    }
+    parts.next().is_none().then_some(addr)
+}
+
+/// Runs the program named by the first of `words`, with all of them as its arguments.
+fn run_words<'a>(jobs: &mut Jobs, words: impl Iterator<Item = &'a str>) -> i32 {
+    let mut argv = [""; sys::EXEC_STRINGS_MAX];
+    let mut argc = 0;
+    for word in words {
+        if argc == argv.len() {
+            break;
+        }
+        argv[argc] = word;
+        argc += 1;
+    }
+    run(jobs, &argv[..argc])
+}
+
+/// Runs the program `argv[0]` with `argv` and waits for it, returning its exit code.
+fn run(jobs: &mut Jobs, argv: &[&str]) -> i32 {
+    let child = match sys::fork() {
+        Ok(0) => {
+            let _ = sys::setpgid(0, 0);
+            for sig in [sys::SIGINT, sys::SIGTSTP] {
+                let _ = sys::sigaction(sig, SigHandler::Default, 0);
+            }
+            sys::exit(exec(argv))
+        }
+        Ok(child) => child,
+        Err(errno) => {
+            eprintln!("sh: can't fork: {errno}");
+            return 1;
+        }
+    };
+    // Also done by the child, whichever runs first; the group must exist before it's
+    // made the foreground one.
+    let _ = sys::setpgid(child, child);
+    foreground(jobs, child)
+}
+
+/// Makes job `pid` the foreground one and waits until it exits or stops, returning its
+/// exit code.
+fn foreground(jobs: &mut Jobs, pid: usize) -> i32 {
+    let _ = sys::tcsetpgrp(STDIN, pid);
+    let result = sys::wait(Some(pid), sys::WUNTRACED);
+    if let Ok(shell) = sys::getpgid(0) {
+        let _ = sys::tcsetpgrp(STDIN, shell);
+    }
+
+    let status = match result {
+        Ok((_, status)) => status,
+        Err(errno) => {
+            eprintln!("sh: can't wait for {pid}: {errno}");
+            return 1;
+        }
+    };
+    if let Some(sig) = sys::stop_signal(status) {
+        println!("[{pid}] stopped");
+        jobs.push(pid);
+        return 128 + sig as i32;
+    }
+    match sys::term_signal(status) {
+        Some(sig) => {
+            eprintln!("sh: {pid} killed by signal {sig}");
+            128 + sig as i32
+        }
+        None => sys::exit_code(status).unwrap_or(0),
+    }
+}
+
+/// Replaces the shell with the program `argv[0]`, returning the exit code to end with if it
+/// can't be run.
+fn exec(argv: &[&str]) -> i32 {
+    let name = argv[0];
+    let mut envp = [""; sys::EXEC_STRINGS_MAX];
+    let mut envc = 0;
+    for (var, slot) in env::vars().zip(&mut envp) {
+        *slot = var;
+        envc += 1;
+    }
+    let envp = &envp[..envc];
+
+    let search = if name.contains('/') {
+        ""
+    } else {
+        env::var("PATH").unwrap_or("/bin")
+    };
+    let mut errno = sys::Errno::ENOENT;
+    for dir in search.split(':') {
+        let mut path = [0; PATH_MAX];
+        let path = match dir {
+            "" => Some(name),
+            dir => join(dir, name, &mut path),
+        };
+        let Some(path) = path else {
+            continue;
+        };
+        errno = sys::exec(path, argv, envp);
+        // Keep looking unless the program exists but can't run.
+        if errno != sys::Errno::ENOENT {
+            break;
+        }
+    }
+    eprintln!("sh: {name}: {errno}");
+    match errno {
+        sys::Errno::ENOENT => 127,
+        _ => 126,
+    }
+}
+
+/// Writes `dir/name` into `out`, `None` if it doesn't fit.
+fn join<'a>(dir: &str, name: &str, out: &'a mut [u8; PATH_MAX]) -> Option<&'a str> {
+    let dir = dir.trim_end_matches('/');
+    let len = dir.len() + 1 + name.len();
+    if len > PATH_MAX {
+        return None;
+    }
+    out[..dir.len()].copy_from_slice(dir.as_bytes());
+    out[dir.len()] = b'/';
+    out[dir.len() + 1..len].copy_from_slice(name.as_bytes());
+    core::str::from_utf8(&out[..len]).ok()
+}
@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+
+use os1k_user::{
+    env, eprintln, print,
+    sys::{self, Errno},
+};
+
+// Shows and sets the kernel's tunables, the files in `/proc/sys`.
+//
+// `sysctl` prints every tunable as `name = value`, `sysctl name` prints one, and
+// `sysctl name=value` sets it.
+
+os1k_user::entry!(main);
+
+const SYS: &str = "/proc/sys";
+
+fn main() -> i32 {
+    let result = match env::args().nth(1) {
+        None => show_all(),
+        Some(arg) => match arg.split_once('=') {
+            Some((name, value)) => set(name, value),
+            None => show(arg),
+        },
+    };
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("sysctl: {err}");
+            1
+        }
+    }
+}
+
+/// Builds the path of the file of tunable `name` in `buf`.
+fn path<'a>(buf: &'a mut [u8; 64], name: &str) -> Result<&'a str, Errno> {
+    let len = SYS.len() + 1 + name.len();
+    if len > buf.len() || name.contains('/') {
+        return Err(Errno::ENOENT);
+    }
+    buf[..SYS.len()].copy_from_slice(SYS.as_bytes());
+    buf[SYS.len()] = b'/';
+    buf[SYS.len() + 1..len].copy_from_slice(name.as_bytes());
+    core::str::from_utf8(&buf[..len]).map_err(|_| Errno::ENOENT)
+}
+
+fn show(name: &str) -> Result<(), Errno> {
+    let mut buf = [0; 64];
+    let fd = sys::open(path(&mut buf, name)?, sys::O_RDONLY)?;
+    let mut value = [0; 32];
+    let len = sys::read(fd, &mut value);
+    sys::close(fd)?;
+    let value = core::str::from_utf8(&value[..len?]).map_err(|_| Errno::EINVAL)?;
+    print!("{name} = {value}");
+    Ok(())
+}
+
+fn show_all() -> Result<(), Errno> {
+    let dir = sys::open(SYS, sys::O_DIRECTORY)?;
+    let mut names = [0; 256];
+    let len = sys::getdents(dir, &mut names);
+    sys::close(dir)?;
+    for name in names[..len?].split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        show(core::str::from_utf8(name).map_err(|_| Errno::EINVAL)?)?;
+    }
+    Ok(())
+}
+
+fn set(name: &str, value: &str) -> Result<(), Errno> {
+    let mut buf = [0; 64];
+    let fd = sys::open(path(&mut buf, name)?, sys::O_WRONLY)?;
+    let written = sys::write(fd, value.as_bytes());
+    sys::close(fd)?;
+    written?;
+    show(name)
+}
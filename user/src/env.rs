@@ -0,0 +1,68 @@
+use core::{
+    ffi::CStr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+// The program's arguments and environment. The kernel starts a program with them on its
+// stack as the System V ABI lays them out: `argc`, then `argv` and `envp`, arrays of
+// pointers to NUL-terminated strings each ending with a null pointer.
+
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+static ENVP: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Finds `argv` and `envp` from the stack pointer the program started with.
+///
+/// # Safety
+///
+/// `sp` must point at `argc` as the kernel laid it out.
+pub(crate) unsafe fn init(sp: *const usize) {
+    unsafe {
+        let argc = *sp;
+        let argv = sp.add(1) as *mut *const u8;
+        ARGV.store(argv, Ordering::Relaxed);
+        ENVP.store(argv.add(argc + 1), Ordering::Relaxed);
+    }
+}
+
+/// Iterates over a null-terminated array of string pointers, panicking at a string that
+/// isn't UTF-8.
+#[derive(Clone)]
+pub struct Strings {
+    next: *const *const u8,
+}
+
+impl Iterator for Strings {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        if self.next.is_null() {
+            return None;
+        }
+        let string = unsafe { *self.next };
+        if string.is_null() {
+            return None;
+        }
+        self.next = unsafe { self.next.add(1) };
+        let string = unsafe { CStr::from_ptr(string.cast()) };
+        Some(string.to_str().expect("argument isn't UTF-8."))
+    }
+}
+
+/// Returns the program's arguments, starting with the name it was run as.
+pub fn args() -> Strings {
+    Strings {
+        next: ARGV.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns the program's environment, as `NAME=value` strings.
+pub fn vars() -> Strings {
+    Strings {
+        next: ENVP.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns the value of environment variable `name`.
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find_map(|var| var.strip_prefix(name)?.strip_prefix('='))
+}
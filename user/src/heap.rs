@@ -0,0 +1,65 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// The program's heap: a bump allocator over a static array, which only gets memory back
+// when the most recent allocation is freed. Enough for short-lived programs; link `alloc`
+// to use it.
+
+const HEAP_SIZE: usize = 64 * 1024;
+
+#[repr(C, align(16))]
+struct Heap {
+    memory: UnsafeCell<[u8; HEAP_SIZE]>,
+    // Offset of the first free byte.
+    next: AtomicUsize,
+}
+
+// Allocations hand out disjoint parts of `memory`, claimed atomically through `next`.
+unsafe impl Sync for Heap {}
+
+#[global_allocator]
+static HEAP: Heap = Heap {
+    memory: UnsafeCell::new([0; HEAP_SIZE]),
+    next: AtomicUsize::new(0),
+};
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let base = self.memory.get() as usize;
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            let start = (base + next).next_multiple_of(layout.align()) - base;
+            let end = match start.checked_add(layout.size()) {
+                Some(end) if end <= HEAP_SIZE => end,
+                _ => return ptr::null_mut(),
+            };
+            match self
+                .next
+                .compare_exchange(next, end, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return (base + start) as *mut u8,
+                Err(current) => next = current,
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let start = ptr as usize - self.memory.get() as usize;
+        // Fails, leaking the memory, unless this is the last allocation.
+        let _ = self.next.compare_exchange(
+            start + layout.size(),
+            start,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Returns how many bytes of the heap are in use, counting what is leaked.
+pub fn used() -> usize {
+    HEAP.next.load(Ordering::Relaxed)
+}
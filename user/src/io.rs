@@ -0,0 +1,56 @@
+use core::fmt::{self, Write};
+
+use crate::sys;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+/// Writes to an fd with `write()`, retrying until everything is written.
+pub struct FdWriter(pub usize);
+
+impl Write for FdWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut buf = s.as_bytes();
+        while !buf.is_empty() {
+            match sys::write(self.0, buf) {
+                Ok(0) | Err(_) => return Err(fmt::Error),
+                Ok(n) => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: usize, args: fmt::Arguments) {
+    // There is nowhere left to report a failing stdout to.
+    let _ = FdWriter(fd).write_fmt(args);
+}
+
+/// Prints to stdout.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::io::_print($crate::io::STDOUT, format_args!($($arg)*))
+    };
+}
+
+/// Prints to stdout, followed by a newline.
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::print!("{}\n", format_args!($($arg)*))
+    };
+}
+
+/// Prints to stderr, followed by a newline.
+#[macro_export]
+macro_rules! eprintln {
+    ($($arg:tt)*) => {
+        $crate::io::_print($crate::io::STDERR, format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
@@ -0,0 +1,78 @@
+#![no_std]
+
+// Runtime for programs running on the OS1K kernel: start code, a heap, syscall wrappers and
+// `print!`. A program is a binary of this crate, or of a crate depending on it:
+//
+//   #![no_std]
+//   #![no_main]
+//
+//   os1k_user::entry!(main);
+//
+//   fn main() -> i32 {
+//       os1k_user::println!("Hello from user space!");
+//       0
+//   }
+//
+// `user.ld` links it at the address the kernel loads programs at. The kernel provides the
+// stack, with the program's arguments and environment on it (see `env`). Build with
+// `cargo build --release` from this directory.
+
+use core::{arch::global_asm, panic::PanicInfo};
+
+pub mod env;
+pub mod heap;
+pub mod io;
+pub mod random;
+pub mod sys;
+pub mod time;
+
+// The program's entry point: zeroes the bss and enters Rust with the stack pointer the
+// kernel started it with, which points at the arguments.
+global_asm!(
+    r#"
+    .section .text.start
+    .global _start
+_start:
+    mv a0, sp
+    la t0, __bss
+    la t1, __bss_end
+1:
+    bgeu t0, t1, 2f
+    sb zero, 0(t0)
+    addi t0, t0, 1
+    j 1b
+2:
+    call __os1k_start
+"#
+);
+
+unsafe extern "Rust" {
+    // Defined by `entry!`.
+    fn __os1k_main() -> i32;
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn __os1k_start(sp: *const usize) -> ! {
+    unsafe { env::init(sp) };
+    let status = unsafe { __os1k_main() };
+    sys::exit(status)
+}
+
+/// Makes `$main`, a `fn() -> i32`, the program's main function. The process exits with the
+/// status it returns.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[unsafe(no_mangle)]
+        fn __os1k_main() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("panic: {info}");
+    sys::exit(101)
+}
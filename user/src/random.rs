@@ -0,0 +1,41 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::sys;
+
+// Random numbers for programs: `u64()` draws from a fast generator (wyrand) seeded from the
+// kernel's entropy pool the first time it is used, so every program run starts from its own
+// seed, e.g. for the keys of a hash map. A forked child goes on with the sequence of its
+// parent. Where numbers must not be predictable, use `sys::getrandom()` directly.
+
+// The generator's state, in halves as 64-bit atomics aren't there on rv32. Programs are
+// single-threaded.
+static STATE: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+fn state() -> u64 {
+    let [hi, lo] = STATE
+        .each_ref()
+        .map(|half| half.load(Ordering::Relaxed) as u64);
+    (hi << 32) | lo
+}
+
+fn set_state(state: u64) {
+    STATE[0].store((state >> 32) as u32, Ordering::Relaxed);
+    STATE[1].store(state as u32, Ordering::Relaxed);
+}
+
+/// Returns a random number.
+pub fn u64() -> u64 {
+    let mut state = state();
+    if state == 0 {
+        let mut seed = [0; 8];
+        // Only fails if the kernel has no `getrandom()`, the time is a poor seed but a seed.
+        if sys::getrandom(&mut seed, 0).is_err() {
+            seed = crate::time::rdtime().to_ne_bytes();
+        }
+        state = u64::from_ne_bytes(seed);
+    }
+    state = state.wrapping_add(0xa076_1d64_78bd_642f);
+    set_state(state);
+    let t = state as u128 * (state ^ 0xe703_7ed1_a0b4_28db) as u128;
+    (t >> 64) as u64 ^ t as u64
+}
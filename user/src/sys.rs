@@ -0,0 +1,710 @@
+use core::{
+    arch::{asm, global_asm},
+    sync::atomic::AtomicU32,
+};
+
+// Typed wrappers of the kernel's syscalls. The numbers, flags and error values must match
+// `rust/src/syscall.rs` and the modules it calls into.
+
+pub const SYS_MQ_CREATE: usize = 1;
+pub const SYS_MQ_DESTROY: usize = 2;
+pub const SYS_MQ_SEND: usize = 3;
+pub const SYS_MQ_RECV: usize = 4;
+pub const SYS_READ: usize = 5;
+pub const SYS_WRITE: usize = 6;
+pub const SYS_CLOSE: usize = 7;
+pub const SYS_PIPE: usize = 8;
+pub const SYS_KILL: usize = 9;
+pub const SYS_SIGACTION: usize = 10;
+pub const SYS_SIGPROCMASK: usize = 11;
+pub const SYS_SIGRETURN: usize = 12;
+pub const SYS_FUTEX_WAIT: usize = 13;
+pub const SYS_FUTEX_WAKE: usize = 14;
+pub const SYS_POLL: usize = 15;
+pub const SYS_GETRLIMIT: usize = 20;
+pub const SYS_SETRLIMIT: usize = 21;
+pub const SYS_OPEN: usize = 22;
+pub const SYS_GETDENTS: usize = 23;
+pub const SYS_DUP: usize = 24;
+pub const SYS_DUP2: usize = 25;
+pub const SYS_FCNTL: usize = 26;
+pub const SYS_EXIT: usize = 27;
+pub const SYS_FORK: usize = 28;
+pub const SYS_EXEC: usize = 29;
+pub const SYS_WAIT: usize = 30;
+pub const SYS_GETITIMER: usize = 31;
+pub const SYS_SETITIMER: usize = 32;
+pub const SYS_SETPGID: usize = 33;
+pub const SYS_GETPGID: usize = 34;
+pub const SYS_TCSETPGRP: usize = 35;
+pub const SYS_TCGETPGRP: usize = 36;
+pub const SYS_MPROTECT: usize = 37;
+pub const SYS_FSYNC: usize = 38;
+pub const SYS_GETRANDOM: usize = 39;
+pub const SYS_SETUID: usize = 40;
+pub const SYS_GETUID: usize = 41;
+pub const SYS_SETGID: usize = 42;
+pub const SYS_GETGID: usize = 43;
+pub const SYS_CHMOD: usize = 44;
+pub const SYS_CHOWN: usize = 45;
+pub const SYS_MKDIR: usize = 46;
+pub const SYS_RMDIR: usize = 47;
+pub const SYS_UNLINK: usize = 48;
+pub const SYS_RENAME: usize = 49;
+pub const SYS_LINK: usize = 50;
+pub const SYS_SYMLINK: usize = 51;
+pub const SYS_READLINK: usize = 52;
+pub const SYS_MMAP: usize = 53;
+pub const SYS_MUNMAP: usize = 54;
+pub const SYS_CHDIR: usize = 55;
+pub const SYS_GETCWD: usize = 56;
+pub const SYS_REBOOT: usize = 57;
+pub const SYS_GETPRIORITY: usize = 58;
+pub const SYS_SETPRIORITY: usize = 59;
+pub const SYS_PING: usize = 60;
+
+// Limits of `exec()`: most bytes of arguments and environment, and most strings in either.
+pub const ARG_MAX: usize = 4096;
+pub const EXEC_STRINGS_MAX: usize = 32;
+
+// Protections of `mprotect()`, none of them making pages inaccessible.
+pub const PROT_NONE: usize = 0;
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+// Flags of `mmap()`.
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+// Flags of `open()`.
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_CREAT: usize = 0o100;
+pub const O_EXCL: usize = 0o200;
+pub const O_DIRECTORY: usize = 0o200000;
+pub const O_CLOEXEC: usize = 0o2000000;
+
+// Commands and flags of `fcntl()`.
+pub const F_GETFD: usize = 1;
+pub const F_SETFD: usize = 2;
+pub const FD_CLOEXEC: usize = 1;
+
+// Flags of `getrandom()`: fail with `EAGAIN` rather than wait for the entropy pool to be
+// ready, or don't wait for it at all.
+pub const GRND_NONBLOCK: usize = 1 << 0;
+pub const GRND_INSECURE: usize = 1 << 2;
+
+// Events of `poll()`.
+pub const POLLIN: u16 = 0x1;
+pub const POLLOUT: u16 = 0x4;
+pub const POLLERR: u16 = 0x8;
+pub const POLLHUP: u16 = 0x10;
+
+// Permissions and flags of message queues.
+pub const MQ_OTHERS_SEND: usize = 1 << 0;
+pub const MQ_OTHERS_RECV: usize = 1 << 1;
+pub const MQ_NONBLOCK: usize = 1 << 0;
+
+// Signals, with the values Linux uses.
+pub const SIGINT: usize = 2;
+pub const SIGKILL: usize = 9;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+
+// Options of `wait()`.
+pub const WUNTRACED: usize = 2;
+
+// Commands of `reboot()`.
+pub const REBOOT_CMD_RESTART: usize = 0x0123_4567;
+pub const REBOOT_CMD_POWER_OFF: usize = 0x4321_fedc;
+
+// How `sigprocmask()` changes the mask.
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+// Flags of `sigaction()`.
+pub const SA_RESTART: usize = 0x1000_0000;
+
+/// An error number returned by a syscall, with the values Linux uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub usize);
+
+impl Errno {
+    pub const EPERM: Self = Self(1);
+    pub const ENOENT: Self = Self(2);
+    pub const ESRCH: Self = Self(3);
+    pub const EINTR: Self = Self(4);
+    pub const E2BIG: Self = Self(7);
+    pub const ENOEXEC: Self = Self(8);
+    pub const EBADF: Self = Self(9);
+    pub const ECHILD: Self = Self(10);
+    pub const EAGAIN: Self = Self(11);
+    pub const ENOMEM: Self = Self(12);
+    pub const EACCES: Self = Self(13);
+    pub const EFAULT: Self = Self(14);
+    pub const EBUSY: Self = Self(16);
+    pub const EEXIST: Self = Self(17);
+    pub const EXDEV: Self = Self(18);
+    pub const ENODEV: Self = Self(19);
+    pub const ENOTDIR: Self = Self(20);
+    pub const EISDIR: Self = Self(21);
+    pub const EINVAL: Self = Self(22);
+    pub const EMFILE: Self = Self(24);
+    pub const ENOTTY: Self = Self(25);
+    pub const ENOSPC: Self = Self(28);
+    pub const EROFS: Self = Self(30);
+    pub const EPIPE: Self = Self(32);
+    pub const ERANGE: Self = Self(34);
+    pub const ENAMETOOLONG: Self = Self(36);
+    pub const ENOSYS: Self = Self(38);
+    pub const ENOTEMPTY: Self = Self(39);
+    pub const ELOOP: Self = Self(40);
+    pub const ETIMEDOUT: Self = Self(110);
+}
+
+impl core::fmt::Display for Errno {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let message = match *self {
+            Self::EPERM => "operation not permitted",
+            Self::ENOENT => "no such file or directory",
+            Self::EINTR => "interrupted system call",
+            Self::E2BIG => "argument list too long",
+            Self::ENOEXEC => "exec format error",
+            Self::EBADF => "bad file descriptor",
+            Self::ECHILD => "no child processes",
+            Self::EAGAIN => "resource temporarily unavailable",
+            Self::ENOMEM => "out of memory",
+            Self::EACCES => "permission denied",
+            Self::EBUSY => "device or resource busy",
+            Self::EEXIST => "file exists",
+            Self::EXDEV => "cross-device link",
+            Self::ENODEV => "no such device",
+            Self::ENOTDIR => "not a directory",
+            Self::EISDIR => "is a directory",
+            Self::EINVAL => "invalid argument",
+            Self::ENOTTY => "not a terminal",
+            Self::ENOSPC => "no space left on device",
+            Self::EROFS => "read-only file system",
+            Self::ENAMETOOLONG => "file name too long",
+            Self::ENOSYS => "function not implemented",
+            Self::ENOTEMPTY => "directory not empty",
+            Self::ELOOP => "too many levels of symbolic links",
+            Self(n) => return write!(f, "error {n}"),
+        };
+        f.write_str(message)
+    }
+}
+
+/// Makes syscall `nr` with `args`, returning what the kernel left in `a0`.
+///
+/// # Safety
+///
+/// Pointers among `args` must be valid for what syscall `nr` does with them.
+pub unsafe fn syscall(nr: usize, args: [usize; 6]) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => ret,
+            in("a1") args[1],
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a5") args[5],
+            in("a7") nr,
+        );
+    }
+    ret
+}
+
+fn result(ret: isize) -> Result<usize, Errno> {
+    match ret {
+        0.. => Ok(ret as usize),
+        _ => Err(Errno(ret.unsigned_abs())),
+    }
+}
+
+macro_rules! syscall {
+    ($nr:expr $(, $arg:expr)* $(,)?) => {{
+        let mut args = [0usize; 6];
+        let values: &[usize] = &[$($arg as usize),*];
+        args[..values.len()].copy_from_slice(values);
+        result(unsafe { syscall($nr, args) })
+    }};
+}
+
+/// Ends the calling process.
+pub fn exit(status: i32) -> ! {
+    let _ = syscall!(SYS_EXIT, status);
+    unreachable!("exit() returned.");
+}
+
+/// Creates a copy of the calling process, returning the copy's pid, and 0 in the copy.
+pub fn fork() -> Result<usize, Errno> {
+    syscall!(SYS_FORK)
+}
+
+/// Replaces the program of the calling process with the executable at `path`, started with
+/// arguments `argv` and environment `envp` (`NAME=value` strings). Only returns if that
+/// fails.
+pub fn exec(path: &str, argv: &[&str], envp: &[&str]) -> Errno {
+    if argv.len() > EXEC_STRINGS_MAX || envp.len() > EXEC_STRINGS_MAX {
+        return Errno::E2BIG;
+    }
+    // NUL-terminated copies of the strings, and the two null-terminated arrays of pointers
+    // to them the kernel takes.
+    let mut strings = [0u8; ARG_MAX];
+    let mut pointers = [0usize; 2 * (EXEC_STRINGS_MAX + 1)];
+    let mut len = 0;
+    let (argv_ptrs, envp_ptrs) = pointers.split_at_mut(EXEC_STRINGS_MAX + 1);
+    for (array, ptrs) in [(argv, argv_ptrs), (envp, envp_ptrs)] {
+        for (s, ptr) in array.iter().zip(ptrs) {
+            let end = len + s.len() + 1;
+            if end > ARG_MAX {
+                return Errno::E2BIG;
+            }
+            strings[len..end - 1].copy_from_slice(s.as_bytes());
+            *ptr = strings.as_ptr() as usize + len;
+            len = end;
+        }
+    }
+
+    let envp_ptrs = &pointers[EXEC_STRINGS_MAX + 1..];
+    match syscall!(
+        SYS_EXEC,
+        path.as_ptr(),
+        path.len(),
+        pointers.as_ptr(),
+        envp_ptrs.as_ptr()
+    ) {
+        Ok(_) => unreachable!("exec() returned."),
+        Err(errno) => errno,
+    }
+}
+
+/// Waits for child `pid` (any child if `None`) to exit, or also to stop with `WUNTRACED`
+/// in `options`, returning its pid and wait status.
+pub fn wait(pid: Option<usize>, options: usize) -> Result<(usize, i32), Errno> {
+    let pid = pid.map_or(-1, |pid| pid as isize);
+    let mut status = 0i32;
+    let child = syscall!(SYS_WAIT, pid, &mut status as *mut i32, options)?;
+    Ok((child, status))
+}
+
+/// Returns the exit code of a process that exited, `None` if it didn't.
+pub fn exit_code(status: i32) -> Option<i32> {
+    (status & 0x7f == 0).then_some((status >> 8) & 0xff)
+}
+
+/// Returns the signal that terminated a process, `None` if it wasn't terminated.
+pub fn term_signal(status: i32) -> Option<usize> {
+    let sig = status & 0x7f;
+    (sig != 0 && sig != 0x7f).then_some(sig as usize)
+}
+
+/// Returns the signal that stopped a process, `None` if it isn't stopped.
+pub fn stop_signal(status: i32) -> Option<usize> {
+    (status & 0xff == 0x7f).then_some(((status >> 8) & 0xff) as usize)
+}
+
+/// Moves process `pid` (the caller if 0) into process group `pgid`, a new one that `pid`
+/// leads if `pgid` is 0.
+pub fn setpgid(pid: usize, pgid: usize) -> Result<(), Errno> {
+    syscall!(SYS_SETPGID, pid, pgid).map(|_| ())
+}
+
+/// Returns the process group of `pid`, the caller if 0.
+pub fn getpgid(pid: usize) -> Result<usize, Errno> {
+    syscall!(SYS_GETPGID, pid)
+}
+
+/// Returns the priority process `pid`, the caller if 0, is scheduled with. Runnable
+/// processes of a higher priority run first.
+pub fn getpriority(pid: usize) -> Result<usize, Errno> {
+    syscall!(SYS_GETPRIORITY, pid)
+}
+
+/// Sets the priority of process `pid`, the caller if 0, up to 39. Only root may raise it,
+/// or change that of another user's process.
+pub fn setpriority(pid: usize, priority: usize) -> Result<(), Errno> {
+    syscall!(SYS_SETPRIORITY, pid, priority).map(|_| ())
+}
+
+/// Makes process group `pgid` the foreground one of the console open as `fd`, the one
+/// Ctrl-C and Ctrl-Z send signals to.
+pub fn tcsetpgrp(fd: usize, pgid: usize) -> Result<(), Errno> {
+    syscall!(SYS_TCSETPGRP, fd, pgid).map(|_| ())
+}
+
+pub fn tcgetpgrp(fd: usize) -> Result<usize, Errno> {
+    syscall!(SYS_TCGETPGRP, fd)
+}
+
+/// Changes the access to the pages in `addr..addr + len`, with `addr` page-aligned, to
+/// `prot`: a guard page with `PROT_NONE`, or code made read-only and executable.
+pub fn mprotect(addr: usize, len: usize, prot: usize) -> Result<(), Errno> {
+    syscall!(SYS_MPROTECT, addr, len, prot).map(|_| ())
+}
+
+/// Maps `len` bytes of the file open as `fd` from `offset`, page-aligned, or zeroed memory
+/// with `MAP_ANONYMOUS`, with `prot`, returning where. With `MAP_SHARED` writes go to the
+/// file, with `MAP_PRIVATE` to a copy only the caller sees.
+pub fn mmap(
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> Result<usize, Errno> {
+    syscall!(SYS_MMAP, addr, len, prot, flags, fd, offset)
+}
+
+pub fn munmap(addr: usize, len: usize) -> Result<(), Errno> {
+    syscall!(SYS_MUNMAP, addr, len).map(|_| ())
+}
+
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+    syscall!(SYS_READ, fd, buf.as_mut_ptr(), buf.len())
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, Errno> {
+    syscall!(SYS_WRITE, fd, buf.as_ptr(), buf.len())
+}
+
+pub fn open(path: &str, flags: usize) -> Result<usize, Errno> {
+    open_mode(path, flags, 0)
+}
+
+/// Opens `path` like `open()`, creating it with permission bits `mode` if `flags` has
+/// `O_CREAT` and it doesn't exist.
+pub fn open_mode(path: &str, flags: usize, mode: usize) -> Result<usize, Errno> {
+    syscall!(SYS_OPEN, path.as_ptr(), path.len(), flags, mode)
+}
+
+pub fn close(fd: usize) -> Result<(), Errno> {
+    syscall!(SYS_CLOSE, fd).map(|_| ())
+}
+
+/// Waits until what was written to file `fd` is on stable storage.
+pub fn fsync(fd: usize) -> Result<(), Errno> {
+    syscall!(SYS_FSYNC, fd).map(|_| ())
+}
+
+/// Fills `buf` with random bytes from the kernel's entropy pool, waiting until it is ready
+/// unless `flags` say otherwise.
+pub fn getrandom(buf: &mut [u8], flags: usize) -> Result<usize, Errno> {
+    syscall!(SYS_GETRANDOM, buf.as_mut_ptr(), buf.len(), flags)
+}
+
+/// Makes the calling process run as user `uid`. Only root (uid 0) may change to another
+/// user, and can't get back.
+pub fn setuid(uid: usize) -> Result<(), Errno> {
+    syscall!(SYS_SETUID, uid).map(|_| ())
+}
+
+pub fn getuid() -> usize {
+    // Can't fail.
+    syscall!(SYS_GETUID).unwrap_or(0)
+}
+
+/// Makes the calling process run as group `gid`, which only root may change to another.
+pub fn setgid(gid: usize) -> Result<(), Errno> {
+    syscall!(SYS_SETGID, gid).map(|_| ())
+}
+
+pub fn getgid() -> usize {
+    // Can't fail.
+    syscall!(SYS_GETGID).unwrap_or(0)
+}
+
+/// Sets the permission bits of `path` to `mode`, which only its owner and root may.
+pub fn chmod(path: &str, mode: usize) -> Result<(), Errno> {
+    syscall!(SYS_CHMOD, path.as_ptr(), path.len(), mode).map(|_| ())
+}
+
+/// Gives `path` to user `uid` and group `gid`, `usize::MAX` leaving either as is. Only
+/// root may.
+pub fn chown(path: &str, uid: usize, gid: usize) -> Result<(), Errno> {
+    syscall!(SYS_CHOWN, path.as_ptr(), path.len(), uid, gid).map(|_| ())
+}
+
+/// Creates the directory `path` with permission bits `mode`.
+pub fn mkdir(path: &str, mode: usize) -> Result<(), Errno> {
+    syscall!(SYS_MKDIR, path.as_ptr(), path.len(), mode).map(|_| ())
+}
+
+/// Removes the empty directory `path`.
+pub fn rmdir(path: &str) -> Result<(), Errno> {
+    syscall!(SYS_RMDIR, path.as_ptr(), path.len()).map(|_| ())
+}
+
+/// Removes the file `path`, which isn't a directory.
+pub fn unlink(path: &str) -> Result<(), Errno> {
+    syscall!(SYS_UNLINK, path.as_ptr(), path.len()).map(|_| ())
+}
+
+/// Moves the file `old` to `new`, replacing what is there.
+pub fn rename(old: &str, new: &str) -> Result<(), Errno> {
+    syscall!(SYS_RENAME, old.as_ptr(), old.len(), new.as_ptr(), new.len()).map(|_| ())
+}
+
+/// Gives the file `old` the name `new` as well.
+pub fn link(old: &str, new: &str) -> Result<(), Errno> {
+    syscall!(SYS_LINK, old.as_ptr(), old.len(), new.as_ptr(), new.len()).map(|_| ())
+}
+
+/// Creates a symbolic link `path` to `target`, which needn't exist.
+pub fn symlink(target: &str, path: &str) -> Result<(), Errno> {
+    syscall!(
+        SYS_SYMLINK,
+        target.as_ptr(),
+        target.len(),
+        path.as_ptr(),
+        path.len()
+    )
+    .map(|_| ())
+}
+
+/// Stores the target of the symbolic link `path` into `buf`, returning its length.
+pub fn readlink(path: &str, buf: &mut [u8]) -> Result<usize, Errno> {
+    syscall!(
+        SYS_READLINK,
+        path.as_ptr(),
+        path.len(),
+        buf.as_mut_ptr(),
+        buf.len()
+    )
+}
+
+/// Makes `path` the working directory, which relative paths are taken from.
+pub fn chdir(path: &str) -> Result<(), Errno> {
+    syscall!(SYS_CHDIR, path.as_ptr(), path.len()).map(|_| ())
+}
+
+/// Stores the working directory into `buf`, returning it.
+pub fn getcwd(buf: &mut [u8]) -> Result<&str, Errno> {
+    let len = syscall!(SYS_GETCWD, buf.as_mut_ptr(), buf.len())?;
+    core::str::from_utf8(&buf[..len]).map_err(|_| Errno::ERANGE)
+}
+
+/// Powers off the machine for `REBOOT_CMD_POWER_OFF`, or resets it for
+/// `REBOOT_CMD_RESTART`, once the other processes were signalled to exit and the
+/// filesystems written back. Only root may, and it only returns on failure.
+pub fn reboot(cmd: usize) -> Errno {
+    match syscall!(SYS_REBOOT, cmd) {
+        Ok(_) => Errno::ENOSYS,
+        Err(errno) => errno,
+    }
+}
+
+/// Stores the NUL-terminated names of the next entries of directory `fd` into `buf`,
+/// returning the bytes stored, 0 after the last entry.
+pub fn getdents(fd: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+    syscall!(SYS_GETDENTS, fd, buf.as_mut_ptr(), buf.len())
+}
+
+/// Creates a pipe, returning its read and write ends.
+pub fn pipe() -> Result<(usize, usize), Errno> {
+    let mut fds = [0usize; 2];
+    syscall!(SYS_PIPE, fds.as_mut_ptr())?;
+    Ok((fds[0], fds[1]))
+}
+
+pub fn dup(fd: usize) -> Result<usize, Errno> {
+    syscall!(SYS_DUP, fd)
+}
+
+pub fn dup2(old_fd: usize, new_fd: usize) -> Result<usize, Errno> {
+    syscall!(SYS_DUP2, old_fd, new_fd)
+}
+
+pub fn fcntl(fd: usize, cmd: usize, arg: usize) -> Result<usize, Errno> {
+    syscall!(SYS_FCNTL, fd, cmd, arg)
+}
+
+/// An entry of the array passed to `poll()`, laid out like Linux's `struct pollfd`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: u16,
+    pub revents: u16,
+}
+
+/// Waits up to `timeout_ms` (forever if negative) for an event of `fds`, returning how many
+/// have `revents` set.
+pub fn poll(fds: &mut [PollFd], timeout_ms: isize) -> Result<usize, Errno> {
+    syscall!(SYS_POLL, fds.as_mut_ptr(), fds.len(), timeout_ms)
+}
+
+/// Sends `sig` to process `pid` if positive, to the caller's process group if 0, and to
+/// group `-pid` if below -1.
+pub fn kill(pid: isize, sig: usize) -> Result<(), Errno> {
+    syscall!(SYS_KILL, pid, sig).map(|_| ())
+}
+
+// Where signal handlers return to, restoring what the signal interrupted.
+global_asm!(
+    r#"
+    .section .text
+    .global __os1k_sigreturn
+__os1k_sigreturn:
+    li a7, {nr}
+    ecall
+"#,
+    nr = const SYS_SIGRETURN,
+);
+
+unsafe extern "C" {
+    fn __os1k_sigreturn();
+}
+
+/// A signal's disposition.
+#[derive(Debug, Clone, Copy)]
+pub enum SigHandler {
+    Default,
+    Ignore,
+    Handler(extern "C" fn(usize)),
+}
+
+/// Sets the handler of `sig`, returning the address of the previous one (0 for the default
+/// action, 1 for ignoring it).
+///
+/// Blocking syscalls a handler interrupts fail with `EINTR`, unless `flags` has
+/// `SA_RESTART`, which runs them again.
+pub fn sigaction(sig: usize, handler: SigHandler, flags: usize) -> Result<usize, Errno> {
+    let handler = match handler {
+        SigHandler::Default => 0,
+        SigHandler::Ignore => 1,
+        SigHandler::Handler(f) => f as usize,
+    };
+    syscall!(
+        SYS_SIGACTION,
+        sig,
+        handler,
+        __os1k_sigreturn as *const () as usize,
+        flags
+    )
+}
+
+/// Changes the blocked signals as `how` says, returning the previous mask.
+pub fn sigprocmask(how: usize, set: u32) -> Result<u32, Errno> {
+    syscall!(SYS_SIGPROCMASK, how, set).map(|old| old as u32)
+}
+
+/// Sleeps until `futex_wake()` is called on `futex`, unless it no longer holds `expected`.
+pub fn futex_wait(futex: &AtomicU32, expected: u32) -> Result<(), Errno> {
+    syscall!(SYS_FUTEX_WAIT, futex.as_ptr(), expected).map(|_| ())
+}
+
+/// Wakes up to `count` processes waiting on `futex`, returning how many were woken.
+pub fn futex_wake(futex: &AtomicU32, count: usize) -> Result<usize, Errno> {
+    syscall!(SYS_FUTEX_WAKE, futex.as_ptr(), count)
+}
+
+/// A resource limit, laid out like Linux's `struct rlimit`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[repr(C)]
+pub struct Rlimit {
+    pub cur: usize,
+    pub max: usize,
+}
+
+pub fn getrlimit(resource: usize) -> Result<Rlimit, Errno> {
+    let mut limit = Rlimit::default();
+    syscall!(SYS_GETRLIMIT, resource, &mut limit as *mut Rlimit)?;
+    Ok(limit)
+}
+
+pub fn setrlimit(resource: usize, limit: Rlimit) -> Result<(), Errno> {
+    syscall!(SYS_SETRLIMIT, resource, &limit as *const Rlimit).map(|_| ())
+}
+
+/// An alarm timer: milliseconds until `SIGALRM` is sent, and the interval after which it is
+/// sent again (0 for once). A `value` of 0 is a timer that isn't set.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[repr(C)]
+pub struct Itimer {
+    pub value: usize,
+    pub interval: usize,
+}
+
+pub fn getitimer() -> Result<Itimer, Errno> {
+    let mut timer = Itimer::default();
+    syscall!(SYS_GETITIMER, &mut timer as *mut Itimer)?;
+    Ok(timer)
+}
+
+/// Sets the alarm timer, returning the previous one.
+pub fn setitimer(timer: Itimer) -> Result<Itimer, Errno> {
+    let mut old = Itimer::default();
+    syscall!(
+        SYS_SETITIMER,
+        &timer as *const Itimer,
+        &mut old as *mut Itimer
+    )?;
+    Ok(old)
+}
+
+/// Sends `SIGALRM` once after `ms` milliseconds, or never if 0, replacing the alarm timer.
+/// Returns the milliseconds that were left until the previous one.
+pub fn alarm(ms: usize) -> usize {
+    let timer = Itimer {
+        value: ms,
+        interval: 0,
+    };
+    // Can't fail with valid pointers.
+    setitimer(timer).map_or(0, |old| old.value)
+}
+
+/// The reply to a `ping()`: its TTL, and the microseconds it took to arrive.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[repr(C)]
+pub struct PingReply {
+    pub ttl: usize,
+    pub rtt_us: usize,
+}
+
+/// Has the kernel send an ICMP echo request with sequence number `seq` to `addr` and wait up
+/// to `timeout_ms` milliseconds for the reply. Fails with `ETIMEDOUT` if none arrived.
+pub fn ping(addr: [u8; 4], seq: u16, timeout_ms: usize) -> Result<PingReply, Errno> {
+    let mut reply = PingReply::default();
+    syscall!(
+        SYS_PING,
+        u32::from_be_bytes(addr),
+        seq,
+        timeout_ms,
+        &mut reply as *mut PingReply
+    )?;
+    Ok(reply)
+}
+
+/// Creates message queue `id`, owned by the calling process. `perms` is a combination of
+/// `MQ_OTHERS_SEND` and `MQ_OTHERS_RECV`.
+pub fn mq_create(id: usize, perms: usize) -> Result<(), Errno> {
+    syscall!(SYS_MQ_CREATE, id, perms).map(|_| ())
+}
+
+pub fn mq_destroy(id: usize) -> Result<(), Errno> {
+    syscall!(SYS_MQ_DESTROY, id).map(|_| ())
+}
+
+/// Appends `msg` to queue `id`, blocking while it is full unless `flags` has `MQ_NONBLOCK`.
+pub fn mq_send(id: usize, msg: &[u8], flags: usize) -> Result<(), Errno> {
+    syscall!(SYS_MQ_SEND, id, msg.as_ptr(), msg.len(), flags).map(|_| ())
+}
+
+/// Receives a message from queue `id` into `buf`, returning its length.
+pub fn mq_recv(id: usize, buf: &mut [u8], flags: usize) -> Result<usize, Errno> {
+    syscall!(SYS_MQ_RECV, id, buf.as_mut_ptr(), buf.len(), flags)
+}
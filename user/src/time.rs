@@ -0,0 +1,59 @@
+use core::arch::asm;
+
+// The hardware counters, read directly from user mode: `time`, a wall clock at a fixed
+// frequency, `cycle`, the hart's clock cycles, and `instret`, the instructions it retired.
+// The kernel lets programs read them (and emulates `time` where the platform doesn't).
+
+/// Frequency of `time` on QEMU virt, as the kernel assumes too.
+pub const TIMEBASE_FREQ: u64 = 10_000_000;
+
+macro_rules! counter {
+    ($low:literal, $high:literal) => {{
+        #[cfg(target_arch = "riscv32")]
+        {
+            // Read in two halves, retried if the low half wrapped in between.
+            loop {
+                let (hi, lo, hi2): (usize, usize, usize);
+                unsafe {
+                    asm!(
+                        concat!("csrr {0}, ", $high),
+                        concat!("csrr {1}, ", $low),
+                        concat!("csrr {2}, ", $high),
+                        out(reg) hi,
+                        out(reg) lo,
+                        out(reg) hi2,
+                    )
+                };
+                if hi == hi2 {
+                    break (hi as u64) << 32 | lo as u64;
+                }
+            }
+        }
+        #[cfg(target_arch = "riscv64")]
+        {
+            let value: usize;
+            unsafe { asm!(concat!("csrr {0}, ", $low), out(reg) value) };
+            value as u64
+        }
+    }};
+}
+
+/// Returns the `time` counter.
+pub fn rdtime() -> u64 {
+    counter!("time", "timeh")
+}
+
+/// Returns the `cycle` counter.
+pub fn rdcycle() -> u64 {
+    counter!("cycle", "cycleh")
+}
+
+/// Returns the `instret` counter.
+pub fn rdinstret() -> u64 {
+    counter!("instret", "instreth")
+}
+
+/// Converts a difference of `time` values to nanoseconds.
+pub fn to_ns(time: u64) -> u64 {
+    time * (1_000_000_000 / TIMEBASE_FREQ)
+}